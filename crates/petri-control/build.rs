@@ -0,0 +1,74 @@
+//! Embeds the build metadata shown by `petri --version` and `petri status`.
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+use chrono::{DateTime, Utc};
+
+fn main() {
+    let git_sha = git(&["rev-parse", "--short=12", "HEAD"]);
+    println!(
+        "cargo:rustc-env=PETRI_GIT_SHA={}",
+        git_sha.as_deref().unwrap_or("unknown")
+    );
+    println!("cargo:rustc-env=PETRI_BUILD_DATE={}", build_date());
+    println!(
+        "cargo:rustc-env=PETRI_TARGET={}",
+        env::var("TARGET").expect("cargo sets the target")
+    );
+
+    let mut features: Vec<_> = env::vars()
+        .filter_map(|(key, _)| {
+            let feature = key.strip_prefix("CARGO_FEATURE_")?;
+            Some(feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    let features = if features.is_empty() {
+        "none".to_owned()
+    } else {
+        features.join(",")
+    };
+    println!("cargo:rustc-env=PETRI_FEATURES={features}");
+
+    // Build again when another commit is checked out.
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        let git_dir = Path::new(&git_dir);
+        println!("cargo:rerun-if-changed={}", git_dir.join("HEAD").display());
+        if let Some(head_ref) = git(&["symbolic-ref", "-q", "HEAD"]) {
+            // Refs may be packed instead.
+            let ref_path = git_dir.join(head_ref);
+            let ref_path = if ref_path.exists() {
+                ref_path
+            } else {
+                git_dir.join("packed-refs")
+            };
+            println!("cargo:rerun-if-changed={}", ref_path.display());
+        }
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+/// Returns the trimmed output of the git command, `None` if the sources
+/// are not in a repository or git is not installed.
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_owned())
+}
+
+/// Returns the date of the build, taken from `SOURCE_DATE_EPOCH` if it's
+/// set for reproducible builds.
+fn build_date() -> String {
+    let date = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .and_then(|epoch| DateTime::<Utc>::from_timestamp(epoch, 0))
+        .unwrap_or_else(Utc::now);
+    date.format("%Y-%m-%d").to_string()
+}
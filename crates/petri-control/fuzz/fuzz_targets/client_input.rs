@@ -0,0 +1,10 @@
+//! What a server (or whatever listens at the address) can send to a
+//! client: the handshake, followed by the packets.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    petri_control::cli::fuzzing::client_input(data);
+});
@@ -0,0 +1,10 @@
+//! What an untrusted local user can send to the server: the request line,
+//! followed by framed requests on multiplexed connections.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    petri_control::cli::fuzzing::server_input(data);
+});
@@ -0,0 +1,212 @@
+//! Token-based authorization of control connections.
+//!
+//! Connections from the user running the server are trusted. Other
+//! connections must present a token, and the role of the token decides
+//! which commands they can run.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind as IoErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use chrono::Local;
+use parking_lot::RwLock;
+use petri_utils::time::in_zone;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+/// The environment variable that the client reads the token from.
+pub const TOKEN_ENV: &str = "PETRI_TOKEN";
+
+/// Roles of tokens, from the least privileged to the most.
+#[derive(
+    Serialize, Deserialize, clap::ValueEnum, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Can only inspect processes, jobs and the server.
+    ReadOnly,
+    /// Can also start and stop processes and jobs.
+    Operator,
+    /// Can also manage the server and the tokens.
+    Admin,
+}
+
+/// A token without its secret.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TokenInfo {
+    pub id: String,
+    pub name: Option<String>,
+    pub role: Role,
+    pub created_at: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct TokenEntry {
+    #[serde(flatten)]
+    info: TokenInfo,
+    /// The hex-encoded SHA-1 digest of the token.
+    digest: String,
+}
+
+/// The persisted tokens. Only the digests of the tokens are stored.
+pub struct TokenStore {
+    path: Option<PathBuf>,
+    entries: RwLock<Vec<TokenEntry>>,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::ReadOnly => "read-only",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl TokenStore {
+    /// Creates a store that keeps the tokens in memory only.
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            entries: Default::default(),
+        }
+    }
+
+    /// Loads the tokens from the file, or creates an empty store if the
+    /// file doesn't exist.
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let entries = match File::open(&path) {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                file.read_to_string(&mut contents)?;
+                serde_json::from_str(&contents)?
+            }
+            Err(err) if err.kind() == IoErrorKind::NotFound => vec![],
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            path: Some(path),
+            entries: RwLock::new(entries),
+        })
+    }
+
+    /// Returns the role of the token, `None` if it's not valid.
+    pub fn role_of(&self, token: &str) -> Option<Role> {
+        let digest = digest(token);
+        let entries = self.entries.read();
+        entries
+            .iter()
+            .find(|entry| entry.digest == digest)
+            .map(|entry| entry.info.role)
+    }
+
+    /// Creates a new token, and returns it along with its info.
+    ///
+    /// The token itself is not stored, so it can't be retrieved again.
+    pub fn create(&self, role: Role, name: Option<String>) -> Result<(String, TokenInfo)> {
+        let mut secret = [0; 16];
+        File::open("/dev/urandom")?.read_exact(&mut secret)?;
+        let secret = hex(&secret);
+        let info = TokenInfo {
+            id: secret[..8].to_owned(),
+            name,
+            role,
+            created_at: in_zone(&Local::now()).format("%Y-%m-%d %T").to_string(),
+        };
+        let token = format!("petri_{secret}");
+
+        let mut entries = self.entries.write();
+        entries.push(TokenEntry {
+            info: info.clone(),
+            digest: digest(&token),
+        });
+        if let Err(err) = self.save(&entries) {
+            entries.pop();
+            return Err(err);
+        }
+
+        Ok((token, info))
+    }
+
+    /// Revokes the token with the given id, and returns its info.
+    pub fn revoke(&self, id: &str) -> Result<TokenInfo> {
+        let mut entries = self.entries.write();
+        let Some(idx) = entries.iter().position(|entry| entry.info.id == id) else {
+            return Err(anyhow!("token `{id}` is not found"));
+        };
+        let entry = entries.remove(idx);
+        if let Err(err) = self.save(&entries) {
+            entries.insert(idx, entry);
+            return Err(err);
+        }
+
+        Ok(entry.info)
+    }
+
+    pub fn tokens(&self) -> Vec<TokenInfo> {
+        let entries = self.entries.read();
+        entries.iter().map(|entry| entry.info.clone()).collect()
+    }
+
+    fn save(&self, entries: &[TokenEntry]) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Write to a temporary file first, so the tokens are not lost if
+        // the server crashes in the middle.
+        let tmp_path = path.with_extension("tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        file.write_all(serde_json::to_string_pretty(entries)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+}
+
+fn digest(token: &str) -> String {
+    hex(&Sha1::digest(token.as_bytes()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, octet| {
+            hex.push_str(&format!("{octet:02x}"));
+            hex
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Role, TokenStore};
+
+    #[test]
+    fn test_create_and_revoke() {
+        let store = TokenStore::in_memory();
+        let (token, info) = store.create(Role::Operator, None).unwrap();
+        assert_eq!(store.role_of(&token), Some(Role::Operator));
+        assert_eq!(store.role_of("petri_invalid"), None);
+
+        store.revoke(&info.id).unwrap();
+        assert_eq!(store.role_of(&token), None);
+        assert!(store.revoke(&info.id).is_err());
+    }
+
+    #[test]
+    fn test_role_order() {
+        assert!(Role::ReadOnly < Role::Operator);
+        assert!(Role::Operator < Role::Admin);
+    }
+}
@@ -0,0 +1,138 @@
+//! The store of checkpoints made by `petri checkpoint`.
+//!
+//! Each checkpoint is a directory named by its id, holding the images made
+//! by CRIU and a `checkpoint.json` file describing the process.
+
+use std::fs;
+use std::io::{self, ErrorKind as IoErrorKind};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use petri_core::checkpoint::OutputPipes;
+use serde::{Deserialize, Serialize};
+
+const INFO_FILE: &str = "checkpoint.json";
+const IMAGES_DIR: &str = "images";
+
+/// A checkpointed process.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CheckpointInfo {
+    pub id: String,
+    pub pid: u32,
+    /// The job that the process belonged to.
+    pub jid: Option<String>,
+    pub cmd: String,
+    pub cwd: String,
+    /// The directory of the log files of the process, if it logged.
+    pub log_path: Option<PathBuf>,
+    pub stdout_pipe: String,
+    pub stderr_pipe: String,
+    /// Whether the process kept running after the checkpoint.
+    pub left_running: bool,
+    /// When the checkpoint was made, as a Unix timestamp.
+    pub created_at_ts: i64,
+}
+
+pub struct CheckpointStore {
+    dir: PathBuf,
+}
+
+impl CheckpointInfo {
+    /// Returns an id for the checkpoint of the process made now, like
+    /// `web-20240501-153000`.
+    pub fn new_id(name: &str, now: &DateTime<Local>) -> String {
+        let name: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        format!("{name}-{}", now.format("%Y%m%d-%H%M%S"))
+    }
+
+    pub fn output_pipes(&self) -> OutputPipes {
+        OutputPipes {
+            stdout: self.stdout_pipe.clone(),
+            stderr: self.stderr_pipe.clone(),
+        }
+    }
+}
+
+impl CheckpointStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    #[inline]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the directory of the CRIU images of the checkpoint.
+    pub fn images_dir(&self, id: &str) -> PathBuf {
+        self.dir.join(id).join(IMAGES_DIR)
+    }
+
+    pub fn save(&self, info: &CheckpointInfo) -> Result<()> {
+        let path = self.dir.join(&info.id).join(INFO_FILE);
+        fs::create_dir_all(path.parent().expect("the path should have a parent"))?;
+        fs::write(path, serde_json::to_vec_pretty(info)?)?;
+        Ok(())
+    }
+
+    pub fn load(&self, id: &str) -> Result<CheckpointInfo> {
+        if id.is_empty() || id.contains(['/', '\0']) || id.starts_with('.') {
+            return Err(anyhow!("invalid checkpoint id `{id}`"));
+        }
+        let path = self.dir.join(id).join(INFO_FILE);
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == IoErrorKind::NotFound => {
+                return Err(anyhow!("checkpoint `{id}` is not found"));
+            }
+            Err(err) => return Err(err.into()),
+        };
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    /// Returns the checkpoints from the oldest, skipping the unreadable ones.
+    pub fn list(&self) -> io::Result<Vec<CheckpointInfo>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == IoErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        let mut checkpoints = vec![];
+        for entry in entries {
+            let Some(id) = entry?.file_name().to_str().map(str::to_owned) else {
+                continue;
+            };
+            match self.load(&id) {
+                Ok(info) => checkpoints.push(info),
+                Err(err) => debug!("skipped checkpoint `{id}`: {err:?}"),
+            }
+        }
+        checkpoints.sort_by_key(|info| info.created_at_ts);
+        Ok(checkpoints)
+    }
+
+    pub fn remove(&self, id: &str) -> io::Result<()> {
+        fs::remove_dir_all(self.dir.join(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Local, TimeZone};
+
+    use super::CheckpointInfo;
+
+    #[test]
+    fn test_new_id() {
+        let now = Local.with_ymd_and_hms(2024, 5, 1, 15, 30, 0).unwrap();
+        assert_eq!(CheckpointInfo::new_id("web", &now), "web-20240501-153000");
+        assert_eq!(
+            CheckpointInfo::new_id("../my app", &now),
+            "---my-app-20240501-153000"
+        );
+    }
+}
@@ -1,23 +1,96 @@
+pub mod bytes;
+mod codec;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+pub mod mux;
+
+use std::any::Any;
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::fs;
+use std::error::Error as StdError;
+use std::ffi::OsString;
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::io::{self, IsTerminal};
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
 use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{env, mem, process, thread};
 
 use anyhow::Result;
+use clap::CommandFactory;
+use petri_utils::console_table;
+use petri_utils::time::FormattedUptime;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use tokio::io::{self as tokio_io, AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{UnixListener, UnixStream};
-use tokio::sync::RwLock;
-use tokio::task;
+use tokio::io::{
+    self as tokio_io, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+    BufReader, ReadHalf, WriteHalf,
+};
+use tokio::sync::{mpsc, watch, Mutex as AsyncMutex, RwLock};
+use tokio::{task, time};
+
+use super::auth::{Role, TOKEN_ENV};
+use super::history::{CommandResult, HistoryEntry};
+use super::transport::{IpcListener, IpcStream};
+use super::{command, Context, LONG_VERSION, VERSION};
+
+use bytes::Base64Bytes;
+pub use codec::{Codec, Compression, Encoding, Handshake};
 
-use super::{command, env, Context};
+/// Requests longer than this are rejected, to not buffer unbounded
+/// garbage from a client.
+const MAX_REQUEST_LEN: usize = 4 * 1024 * 1024;
+
+/// How long to wait for the client to send the request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Serialize, Deserialize)]
 pub struct OwnedIpcRequestPacket {
     pub cmd: command::Command,
     pub cwd: String,
     pub env: HashMap<String, String>,
+    /// The variables that are not valid UTF-8, see [`bytes::split_env`].
+    #[serde(default)]
+    pub env_bytes: Vec<(Base64Bytes, Base64Bytes)>,
+    /// The token to authorize the request, see [`auth`](crate::auth).
+    #[serde(default)]
+    pub token: Option<String>,
+    /// The arguments the client was invoked with, for the history.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Encodings of the packets that the client accepts, in the order of
+    /// preference. The server replies in JSON lines if it's empty.
+    #[serde(default)]
+    pub encodings: Vec<Encoding>,
+    /// The compression that the client requests, only applied to binary
+    /// encodings. Meant for sockets forwarded from remote hosts, since
+    /// local connections don't benefit from it.
+    #[serde(default)]
+    pub compression: Compression,
+    /// The version of the client, `None` for clients older than this
+    /// field.
+    #[serde(default)]
+    pub version: Option<String>,
+    /// Whether to multiplex commands over the connection, see [`mux`].
+    /// Only honored with an encoding.
+    #[serde(default)]
+    pub multiplex: bool,
+    #[serde(default)]
+    pub render: RenderHints,
+}
+
+/// How the client shows the output, so what the server renders fits it.
+/// Clients that don't send them get no colors and no truncation.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct RenderHints {
+    /// The width of the terminal, `None` if the output is not a terminal.
+    pub width: Option<u16>,
+    /// Whether the output can be colored with ANSI escapes.
+    pub color: bool,
 }
 
 #[derive(Serialize)]
@@ -25,12 +98,121 @@ pub struct IpcRequestPacket<'c> {
     pub cmd: &'c command::Command,
     pub cwd: String,
     pub env: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub env_bytes: Vec<(Base64Bytes, Base64Bytes)>,
+    pub token: Option<String>,
+    pub args: Vec<String>,
+    pub encodings: Vec<Encoding>,
+    pub compression: Compression,
+    pub version: &'c str,
+    pub multiplex: bool,
+    pub render: RenderHints,
+}
+
+/// A packet of a command on a multiplexed connection, see [`mux`].
+#[derive(Serialize)]
+pub struct IpcMuxPacket<'p, T> {
+    /// The id of the command, given by the client.
+    pub id: u64,
+    /// `None` once the command is done.
+    pub packet: Option<&'p OwnedIpcMessagePacket<T>>,
+    /// The id of the request, sent along with the end of the command.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct OwnedIpcMuxPacket<T> {
+    pub id: u64,
+    pub packet: Option<OwnedIpcMessagePacket<T>>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// A request of the client on a multiplexed connection, see [`mux`].
+#[derive(Serialize)]
+pub struct IpcMuxRequest<'r, 'c> {
+    /// The id of the command, unique among the running ones.
+    pub id: u64,
+    /// `None` to cancel the running command with the id.
+    pub request: Option<&'r IpcRequestPacket<'c>>,
+}
+
+#[derive(Deserialize)]
+struct OwnedIpcMuxRequest {
+    id: u64,
+    /// Kept as a value to be parsed like the first request.
+    request: Option<serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum OwnedIpcMessagePacket<T> {
     Output(String),
     Response(T),
+    EndOfStream(EndOfStream),
+    PermissionDenied(PermissionDenied),
+    Failed(CommandFailed),
+    InternalError(InternalError),
+    BadRequest(BadRequest),
+    UnknownCommand(UnknownCommand),
+}
+
+/// The final packet of a stream, sent when the server ends it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EndOfStream {
+    pub reason: EndOfStreamReason,
+}
+
+/// Sent instead of the response when the client is not allowed to run
+/// the command.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PermissionDenied {
+    /// The role the command requires.
+    pub required: Role,
+    /// The role of the client, `None` if it's not authorized at all.
+    pub granted: Option<Role>,
+    pub reason: String,
+}
+
+/// The final packet when the command failed, only sent to clients that
+/// negotiated the encoding, since older clients can't decode it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CommandFailed {
+    pub reason: String,
+}
+
+/// The final packet when the command panicked on the server, sent
+/// instead of [`CommandFailed`].
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InternalError {
+    pub message: String,
+}
+
+/// The only packet when the request can't be read or parsed, always in
+/// JSON.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BadRequest {
+    pub reason: String,
+    /// The usage of commands, if the command is the part that can't be
+    /// parsed (e.g. it's sent by a newer client).
+    pub help: Option<String>,
+}
+
+/// The only packet when the server doesn't know the command, which is
+/// likely sent by a newer client.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UnknownCommand {
+    pub name: String,
+    pub server_version: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum EndOfStreamReason {
+    /// The process being streamed has exited.
+    ProcessExited,
+    /// The requested streaming duration has elapsed.
+    DeadlineReached,
 }
 
 impl<T> OwnedIpcMessagePacket<T> {
@@ -40,6 +222,89 @@ impl<T> OwnedIpcMessagePacket<T> {
             _ => None,
         }
     }
+
+    pub fn to_end_of_stream(&self) -> Option<&EndOfStream> {
+        match self {
+            OwnedIpcMessagePacket::EndOfStream(eos) => Some(eos),
+            _ => None,
+        }
+    }
+
+    pub fn to_permission_denied(&self) -> Option<&PermissionDenied> {
+        match self {
+            OwnedIpcMessagePacket::PermissionDenied(denied) => Some(denied),
+            _ => None,
+        }
+    }
+
+    pub fn to_failed(&self) -> Option<&CommandFailed> {
+        match self {
+            OwnedIpcMessagePacket::Failed(failed) => Some(failed),
+            _ => None,
+        }
+    }
+
+    pub fn to_internal_error(&self) -> Option<&InternalError> {
+        match self {
+            OwnedIpcMessagePacket::InternalError(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    pub fn to_bad_request(&self) -> Option<&BadRequest> {
+        match self {
+            OwnedIpcMessagePacket::BadRequest(bad_request) => Some(bad_request),
+            _ => None,
+        }
+    }
+
+    pub fn to_unknown_command(&self) -> Option<&UnknownCommand> {
+        match self {
+            OwnedIpcMessagePacket::UnknownCommand(unknown) => Some(unknown),
+            _ => None,
+        }
+    }
+}
+
+impl RenderHints {
+    /// Detects the hints of the standard output of the client.
+    pub fn detect() -> Self {
+        if !io::stdout().is_terminal() {
+            return Self::default();
+        }
+        let mut size: libc::winsize = unsafe { mem::zeroed() };
+        let res = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+        Self {
+            width: (res == 0 && size.ws_col > 0).then_some(size.ws_col),
+            color: env::var_os("NO_COLOR").is_none(),
+        }
+    }
+
+    /// Shortens a command to the width of the terminal, which no column can
+    /// be wider than, so long ones are not sent in full. Clients fit it to
+    /// the width left by the other columns, see
+    /// [`DynamicBuilder::fit_column`](petri_utils::console_table::DynamicBuilder::fit_column).
+    pub fn truncate_cmd<'a>(&self, cmd: &'a str) -> Cow<'a, str> {
+        match self.width {
+            Some(width) => console_table::truncate(cmd, width as usize),
+            None => Cow::Borrowed(cmd),
+        }
+    }
+}
+
+impl BadRequest {
+    fn packet(reason: String, help: Option<String>) -> OwnedIpcMessagePacket<()> {
+        OwnedIpcMessagePacket::BadRequest(BadRequest { reason, help })
+    }
+}
+
+impl Display for EndOfStreamReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EndOfStreamReason::ProcessExited => "the process exited",
+            EndOfStreamReason::DeadlineReached => "the duration elapsed",
+        })
+    }
 }
 
 impl OwnedIpcMessagePacket<serde_json::Value> {
@@ -54,8 +319,44 @@ impl OwnedIpcMessagePacket<serde_json::Value> {
     }
 }
 
+/// The id of a command the server receives, which is written in the
+/// server logs of the command and told to the client, so the logs of a
+/// failed command can be found.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RequestId(u64);
+
+impl RequestId {
+    /// Returns the id of the current command, if any.
+    pub fn current() -> Option<Self> {
+        REQUEST_ID.try_with(|id| *id).ok()
+    }
+
+    /// Scrambles the sequence number, so ids of different server runs are
+    /// unlikely to collide.
+    fn new(seed: u64, seq: u64) -> Self {
+        // SplitMix64.
+        let mut z = seed.wrapping_add(seq.wrapping_mul(0x9e3779b97f4a7c15));
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        Self((z ^ (z >> 31)) & 0xffff_ffff_ffff)
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:012x}", self.0)
+    }
+}
+
+/// The [`LogContext`](petri_logger::LogContext) of the server, which
+/// tags the log lines with the id of the current command.
+pub fn log_context() -> Option<String> {
+    RequestId::current().map(|id| format!("req {id}"))
+}
+
 struct Inner {
     id_seed: AtomicU64,
+    request_id_seed: u64,
     pairs: RwLock<HashMap<u64, ControlPair>>,
 
     ctx: Arc<Context>,
@@ -64,12 +365,44 @@ struct Inner {
 struct ControlPair;
 
 pub(super) struct IpcChannel {
-    stream: UnixStream,
+    sink: PacketSink,
+    codec: Codec,
+}
+
+/// Where the packets of a command go.
+enum PacketSink {
+    /// The connection serves the command only.
+    Stream(IpcStream),
+    /// The command shares the connection with others.
+    Mux(MuxSink),
+}
+
+struct MuxSink {
+    id: u64,
+    writer: Arc<AsyncMutex<WriteHalf<IpcStream>>>,
+    /// Set when the client cancels the command or closes the connection.
+    cancelled: watch::Receiver<bool>,
 }
 
 impl IpcChannel {
-    pub fn stream_mut(&mut self) -> &mut UnixStream {
-        &mut self.stream
+    /// Resolves once the client closes the connection, or cancels the
+    /// command on a multiplexed connection. Clients don't send anything
+    /// while a command is running.
+    pub async fn closed(&mut self) {
+        match &mut self.sink {
+            PacketSink::Stream(stream) => {
+                // We don't expect to read any bytes here, so we only use a
+                // small buffer to check if the remote peer is closed.
+                let mut buf = [0; 1];
+                while stream.read(&mut buf).await.unwrap_or(0) != 0 {
+                    warn!("unexpected byte received: {}", buf[0]);
+                }
+            }
+            PacketSink::Mux(sink) => {
+                // The sender is dropped once the connection is gone.
+                _ = sink.cancelled.wait_for(|cancelled| *cancelled).await;
+            }
+        }
     }
 
     pub async fn write_response<T>(&mut self, resp: T) -> tokio_io::Result<()>
@@ -85,63 +418,92 @@ impl IpcChannel {
         self.write_packet(&msg).await
     }
 
+    pub async fn write_permission_denied(
+        &mut self,
+        denied: PermissionDenied,
+    ) -> tokio_io::Result<()> {
+        let msg = OwnedIpcMessagePacket::<()>::PermissionDenied(denied);
+        self.write_packet(&msg).await
+    }
+
+    pub async fn write_failed(&mut self, reason: String) -> tokio_io::Result<()> {
+        let msg = OwnedIpcMessagePacket::<()>::Failed(CommandFailed { reason });
+        self.write_packet(&msg).await
+    }
+
+    pub async fn write_internal_error(&mut self, message: String) -> tokio_io::Result<()> {
+        let msg = OwnedIpcMessagePacket::<()>::InternalError(InternalError { message });
+        self.write_packet(&msg).await
+    }
+
     async fn write_packet<'a, T>(&mut self, pkt: &OwnedIpcMessagePacket<T>) -> tokio_io::Result<()>
     where
         T: Serialize + Send + Sync + 'static,
     {
-        let mut json_string = match serde_json::to_string(pkt) {
-            Ok(s) => s,
-            Err(err) => return Err(tokio_io::Error::new(tokio_io::ErrorKind::Other, err)),
-        };
-        json_string.push('\n');
+        match &mut self.sink {
+            PacketSink::Stream(stream) => {
+                let frame = self.codec.encode(pkt)?;
+                stream.write_all(&frame).await?;
+                stream.flush().await
+            }
+            PacketSink::Mux(sink) => {
+                if *sink.cancelled.borrow() {
+                    return Err(tokio_io::Error::new(
+                        tokio_io::ErrorKind::BrokenPipe,
+                        "the command is cancelled",
+                    ));
+                }
+                let frame = self.codec.encode(&IpcMuxPacket {
+                    id: sink.id,
+                    packet: Some(pkt),
+                    request_id: None,
+                })?;
+                mux::write_frame(&sink.writer, &frame).await
+            }
+        }
+    }
 
-        self.stream.write_all(json_string.as_bytes()).await?;
-        self.stream.flush().await?;
-        Ok(())
+    /// Tells the client that the command is done, only needed on
+    /// multiplexed connections where closing the connection can't.
+    async fn finish(&mut self) -> tokio_io::Result<()> {
+        let PacketSink::Mux(sink) = &self.sink else {
+            return Ok(());
+        };
+        let frame = self.codec.encode(&IpcMuxPacket::<()> {
+            id: sink.id,
+            packet: None,
+            request_id: RequestId::current().map(|id| id.to_string()),
+        })?;
+        mux::write_frame(&sink.writer, &frame).await
     }
 }
 
 pub(super) async fn serve_cli(ctx: Arc<Context>) -> Result<()> {
-    let sock_path = env::socket_path()?;
-    let listener = UnixListener::bind(sock_path)?;
+    // The socket file is removed when the listener is dropped, so it's
+    // also cleaned up if the future is early cancelled.
+    let listener = IpcListener::bind(&ctx.listen_address)?;
+    info!("listening on {}", ctx.listen_address);
 
     let inner = Arc::new(Inner {
         id_seed: Default::default(),
+        request_id_seed: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64),
         pairs: Default::default(),
         ctx,
     });
 
-    // Make sure the socket file is removed when the future is done
-    // or early cancelled.
-    struct DropGuard<'a>(&'a UnixListener);
-
-    impl<'a> Drop for DropGuard<'a> {
-        #[rustfmt::skip]
-        fn drop(&mut self) {
-            let sock_addr = self.0
-                .local_addr()
-                .expect("could not get local address");
-            let sock_file_path = sock_addr
-                .as_pathname()
-                .expect("the socket should have a path name");
-            if let Err(err) = fs::remove_file(sock_file_path) {
-                error!("failed to cleanup the socket: {err:?}");
-            }
-        }
-    }
-
-    let _drop_guard = DropGuard(&listener);
     inner.accept_loop(&listener).await;
 
     Ok(())
 }
 
 impl Inner {
-    async fn accept_loop(self: &Arc<Self>, listener: &UnixListener) {
+    async fn accept_loop(self: &Arc<Self>, listener: &IpcListener) {
         loop {
             match listener.accept().await {
                 Ok((stream, addr)) => {
-                    debug!("new connection from: {:?}", addr);
+                    debug!("new connection from: {addr}");
                     self.serve_connection(stream).await;
                 }
                 Err(e) => {
@@ -153,56 +515,583 @@ impl Inner {
         }
     }
 
-    async fn serve_connection(self: &Arc<Self>, stream: UnixStream) {
+    async fn serve_connection(self: &Arc<Self>, stream: IpcStream) {
         let id = self.id_seed.fetch_add(1, AtomicOrdering::Relaxed);
 
         let pair = ControlPair;
         self.pairs.write().await.insert(id, pair);
 
+        let request_id = RequestId::new(self.request_id_seed, id);
         let inner = Arc::clone(self);
-        task::spawn(async move {
-            let (read_half, write_half) = stream.into_split();
-            let mut reader = BufReader::new(read_half);
-
-            let mut line = String::new();
-            if reader.read_line(&mut line).await.is_ok() {
-                let stream = reader
-                    .into_inner()
-                    .reunite(write_half)
-                    .expect("should reunite into stream");
-                if let Err(err) = inner.run_command(&line, stream).await {
-                    error!("failed to run command: {:?}", err);
+        let task = REQUEST_ID.scope(request_id, async move {
+            inner.serve_requests(stream).await;
+            inner.pairs.write().await.remove(&id);
+        });
+        task::spawn(task);
+    }
+
+    async fn serve_requests(self: &Arc<Self>, stream: IpcStream) {
+        let peer_uid = stream.peer_uid();
+        let (read_half, write_half) = tokio_io::split(stream);
+        let mut reader = BufReader::new(read_half);
+
+        let line = time::timeout(REQUEST_TIMEOUT, read_request_line(&mut reader)).await;
+        let request = match line {
+            Ok(Ok(RequestLine::Line(line))) => parse_request(&line),
+            Ok(Ok(RequestLine::TooLarge)) => {
+                let reason = format!("the request exceeds {MAX_REQUEST_LEN} bytes");
+                Err(BadRequest::packet(reason, None))
+            }
+            Ok(Ok(RequestLine::Eof)) => return,
+            Ok(Err(err)) => {
+                error!("failed to read from the stream: {err:?}");
+                return;
+            }
+            Err(_) => {
+                let reason = "timed out waiting for the request".to_owned();
+                Err(BadRequest::packet(reason, None))
+            }
+        };
+
+        let request = match request {
+            Ok(request) => request,
+            Err(rejection) => {
+                log_rejection(&rejection);
+                // Clients that sent a rejected request can't have
+                // negotiated the encoding, so reply in JSON.
+                let mut ipc_channel = IpcChannel {
+                    sink: PacketSink::Stream(reader.into_inner().unsplit(write_half)),
+                    codec: Codec::JSON,
+                };
+                _ = ipc_channel.write_packet(&rejection).await;
+                return;
+            }
+        };
+
+        if request.multiplex && !request.encodings.is_empty() {
+            self.serve_multiplexed(request, reader, write_half, peer_uid)
+                .await;
+            return;
+        }
+
+        // Reply the negotiated encoding if the client supports any.
+        let mut stream = reader.into_inner().unsplit(write_half);
+        let negotiated = !request.encodings.is_empty();
+        let mut codec = Codec::JSON;
+        if let Some(encoding) = request.encodings.first() {
+            codec = Codec::new(*encoding, request.compression);
+            if let Err(err) = write_handshake(&mut stream, codec, false).await {
+                error!("failed to write the handshake: {err:?}");
+                return;
+            }
+        }
+
+        let mut ipc_channel = IpcChannel {
+            sink: PacketSink::Stream(stream),
+            codec,
+        };
+        if let Err(err) = self
+            .run_command(request, &mut ipc_channel, peer_uid, negotiated)
+            .await
+        {
+            error!("failed to run command: {:?}", err);
+        }
+    }
+
+    /// Serves the commands on a multiplexed connection until the client
+    /// closes it, see [`mux`] for the protocol.
+    async fn serve_multiplexed(
+        self: &Arc<Self>,
+        first: OwnedIpcRequestPacket,
+        mut reader: BufReader<ReadHalf<IpcStream>>,
+        mut writer: WriteHalf<IpcStream>,
+        peer_uid: Option<u32>,
+    ) {
+        let codec = Codec::new(first.encodings[0], first.compression);
+        if let Err(err) = write_handshake(&mut writer, codec, true).await {
+            error!("failed to write the handshake: {err:?}");
+            return;
+        }
+        debug!("the connection is multiplexed");
+
+        let writer = Arc::new(AsyncMutex::new(writer));
+        let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+        let mut running = HashMap::new();
+        let spawn = |id, request| {
+            let (cancel_tx, cancel_rx) = watch::channel(false);
+            let sink = MuxSink {
+                id,
+                writer: Arc::clone(&writer),
+                cancelled: cancel_rx,
+            };
+            self.spawn_mux_command(request, sink, codec, peer_uid, done_tx.clone());
+            cancel_tx
+        };
+        running.insert(0, spawn(0, Ok(first)));
+
+        loop {
+            let payload = tokio::select! {
+                payload = codec.read_frame(&mut reader) => payload,
+                Some(id) = done_rx.recv() => {
+                    running.remove(&id);
+                    continue;
                 }
-            } else {
-                error!("failed to read from the stream");
+            };
+            let payload = match payload {
+                Ok(Some(payload)) => payload,
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("failed to read from the multiplexed connection: {err:?}");
+                    break;
+                }
+            };
+            let mux_request: OwnedIpcMuxRequest = match codec.decode(&payload) {
+                Ok(mux_request) => mux_request,
+                Err(err) => {
+                    warn!("bad request on the multiplexed connection: {err}");
+                    break;
+                }
+            };
+
+            let id = mux_request.id;
+            let Some(request) = mux_request.request else {
+                if let Some(cancel_tx) = running.get(&id) {
+                    debug!("command {id} is cancelled by the client");
+                    _ = cancel_tx.send(true);
+                }
+                continue;
+            };
+            // Packets of both commands would be tagged with the id, so the
+            // client can't tell them apart.
+            if running.contains_key(&id) {
+                warn!("command {id} is requested while it's still running");
+                break;
             }
+            running.insert(id, spawn(id, parse_request_value(request)));
+        }
 
-            inner.pairs.write().await.remove(&id);
+        // Stop the commands still streaming to the client.
+        for cancel_tx in running.into_values() {
+            _ = cancel_tx.send(true);
+        }
+    }
+
+    fn spawn_mux_command(
+        self: &Arc<Self>,
+        request: Result<OwnedIpcRequestPacket, OwnedIpcMessagePacket<()>>,
+        sink: MuxSink,
+        codec: Codec,
+        peer_uid: Option<u32>,
+        done_tx: mpsc::UnboundedSender<u64>,
+    ) {
+        let seq = self.id_seed.fetch_add(1, AtomicOrdering::Relaxed);
+        let request_id = RequestId::new(self.request_id_seed, seq);
+        let inner = Arc::clone(self);
+        let task = REQUEST_ID.scope(request_id, async move {
+            let id = sink.id;
+            let mut ipc_channel = IpcChannel {
+                sink: PacketSink::Mux(sink),
+                codec,
+            };
+            match request {
+                Ok(request) => {
+                    if let Err(err) = inner
+                        .run_command(request, &mut ipc_channel, peer_uid, true)
+                        .await
+                    {
+                        error!("failed to run command: {:?}", err);
+                    }
+                }
+                Err(rejection) => {
+                    log_rejection(&rejection);
+                    _ = ipc_channel.write_packet(&rejection).await;
+                }
+            }
+            _ = ipc_channel.finish().await;
+            _ = done_tx.send(id);
         });
+        task::spawn(task);
     }
 
-    async fn run_command(self: &Arc<Self>, payload: &str, stream: UnixStream) -> Result<()> {
-        let mut ipc_channel = IpcChannel { stream };
-        let request: OwnedIpcRequestPacket = serde_json::from_str(payload)?;
+    async fn run_command(
+        self: &Arc<Self>,
+        request: OwnedIpcRequestPacket,
+        ipc_channel: &mut IpcChannel,
+        peer_uid: Option<u32>,
+        negotiated: bool,
+    ) -> Result<()> {
         let cmd = request.cmd;
 
+        let required = cmd.required_role();
+        let denied = match self.authorize(request.token.as_deref(), peer_uid) {
+            Ok(role) if role >= required => None,
+            Ok(role) => Some(PermissionDenied {
+                required,
+                granted: Some(role),
+                reason: format!(
+                    "the command requires the {} role, but the client is {}",
+                    required.as_str(),
+                    role.as_str()
+                ),
+            }),
+            Err(err) => Some(PermissionDenied {
+                required,
+                granted: None,
+                reason: err.to_string(),
+            }),
+        };
+        if let Some(denied) = denied {
+            let err = anyhow!("permission denied: {}", denied.reason);
+            self.ctx.history.record(HistoryEntry::new(
+                peer_uid,
+                request.args,
+                CommandResult::Denied,
+                Some(denied.reason.clone()),
+            ));
+            ipc_channel.write_permission_denied(denied).await?;
+            return Err(err);
+        }
+
         let client_env = ClientEnv {
             cwd: request.cwd,
-            env: request.env,
+            env: self
+                .ctx
+                .env_policy
+                .read()
+                .apply(bytes::join_env(request.env, request.env_bytes)),
+            render: request.render,
         };
 
-        CLIENT_ENV
-            .scope(client_env, async move {
-                cmd.run(&self.ctx, &mut ipc_channel).await
-            })
-            .await
+        let cmd_line = request.args.join(" ");
+        let lane = cmd.lane();
+        if self.ctx.lanes.is_full(lane) {
+            debug!("command `{cmd_line}` is queued in the {lane} lane");
+        }
+        let _permit = tokio::select! {
+            permit = self.ctx.lanes.acquire(lane) => permit,
+            _ = ipc_channel.closed() => {
+                debug!("the client left while `{cmd_line}` was queued");
+                return Ok(());
+            }
+        };
+        let _active = self.ctx.active_commands.begin(cmd_line.clone());
+        let streaming = cmd.is_streaming();
+        let probe = cmd.is_probe();
+        let channel = &mut *ipc_channel;
+        let run = CLIENT_ENV.scope(client_env, async move {
+            // Keep serving other clients if the command panics.
+            match CatchUnwind::new(cmd.run(&self.ctx, channel)).await {
+                Ok(res) => res,
+                Err(payload) => Err(CommandPanicked::from_payload(payload).into()),
+            }
+        });
+        let res = if streaming {
+            run.await
+        } else {
+            match self.run_with_limits(run, &cmd_line).await {
+                Some(res) => res,
+                None => {
+                    let timeout = FormattedUptime::new(self.ctx.command_limits.timeout);
+                    _ = ipc_channel
+                        .write_output(&format!("the command timed out after {timeout}\n"))
+                        .await;
+                    Err(anyhow!("timed out after {timeout}"))
+                }
+            }
+        };
+        let (result, error) = match &res {
+            Ok(_) => (CommandResult::Ok, None),
+            Err(err) => (CommandResult::Failed, Some(format!("{err:#}"))),
+        };
+        let panicked = res
+            .as_ref()
+            .err()
+            .and_then(|err| err.downcast_ref::<CommandPanicked>());
+        if let Some(panicked) = panicked {
+            self.ctx
+                .command_panics
+                .fetch_add(1, AtomicOrdering::Relaxed);
+            error!("command `{cmd_line}` panicked: {}", panicked.0);
+        }
+        if let (true, Some(error)) = (negotiated, &error) {
+            // The client may be gone already.
+            _ = match panicked {
+                Some(panicked) => ipc_channel.write_internal_error(panicked.0.clone()).await,
+                None => ipc_channel.write_failed(error.clone()).await,
+            };
+        }
+        // Probes run from cron would crowd out everything else.
+        if !probe || error.is_some() {
+            self.ctx
+                .history
+                .record(HistoryEntry::new(peer_uid, request.args, result, error));
+        }
+        res
     }
 }
 
+impl Inner {
+    /// Runs the command, and warns if it's slow. Returns `None` if it's
+    /// cancelled for running too long.
+    async fn run_with_limits<F>(&self, run: F, cmd_line: &str) -> Option<Result<()>>
+    where
+        F: Future<Output = Result<()>>,
+    {
+        let limits = self.ctx.command_limits;
+        tokio::pin!(run);
+
+        tokio::select! {
+            res = &mut run => return Some(res),
+            _ = time::sleep(limits.slow_threshold) => {}
+        }
+        warn!(
+            "command `{cmd_line}` has been running for {}, \
+            the stacks can be sampled with `sample {}` if it hangs",
+            FormattedUptime::new(limits.slow_threshold),
+            process::id()
+        );
+
+        let remaining = limits.timeout.saturating_sub(limits.slow_threshold);
+        match time::timeout(remaining, run).await {
+            Ok(res) => Some(res),
+            Err(_) => {
+                error!(
+                    "command `{cmd_line}` is cancelled after running for {}",
+                    FormattedUptime::new(limits.timeout)
+                );
+                None
+            }
+        }
+    }
+
+    /// Returns the role of the client.
+    fn authorize(&self, token: Option<&str>, peer_uid: Option<u32>) -> Result<Role> {
+        if let Some(token) = token {
+            return self
+                .ctx
+                .token_store
+                .role_of(token)
+                .ok_or_else(|| anyhow!("the token is invalid or revoked"));
+        }
+
+        // Clients of the same user are trusted, as they can access the
+        // server anyway.
+        let Some(peer_uid) = peer_uid else {
+            return Err(anyhow!(
+                "failed to identify the client, provide a token with `{TOKEN_ENV}`"
+            ));
+        };
+        let server_uid = unsafe { libc::geteuid() };
+        if peer_uid == server_uid || peer_uid == 0 {
+            return Ok(Role::Admin);
+        }
+        if self.ctx.observer_uids.contains(&peer_uid) {
+            return Ok(Role::ReadOnly);
+        }
+        Err(anyhow!(
+            "clients of other users must provide a token with `{TOKEN_ENV}`"
+        ))
+    }
+}
+
+enum RequestLine {
+    Line(Vec<u8>),
+    /// The line is longer than [`MAX_REQUEST_LEN`].
+    TooLarge,
+    Eof,
+}
+
+/// Reads the request line without buffering more than
+/// [`MAX_REQUEST_LEN`] bytes of it.
+async fn read_request_line<R>(reader: &mut R) -> tokio_io::Result<RequestLine>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = vec![];
+    let len = reader
+        .take(MAX_REQUEST_LEN as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if len == 0 {
+        return Ok(RequestLine::Eof);
+    }
+    if !line.ends_with(b"\n") && line.len() > MAX_REQUEST_LEN {
+        return Ok(RequestLine::TooLarge);
+    }
+    Ok(RequestLine::Line(line))
+}
+
+/// Tells the client the negotiated encoding, and the id of the request.
+async fn write_handshake<W>(writer: &mut W, codec: Codec, multiplex: bool) -> tokio_io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut handshake = codec.handshake();
+    handshake.request_id = RequestId::current().map(|id| id.to_string());
+    handshake.multiplex = multiplex;
+    handshake.server_version = Some(LONG_VERSION.to_owned());
+    let mut handshake = serde_json::to_vec(&handshake)?;
+    handshake.push(b'\n');
+    writer.write_all(&handshake).await?;
+    writer.flush().await
+}
+
+/// Reads the handshake of the server. Servers that don't support encodings
+/// reply in JSON lines right away, so the line read is returned instead to
+/// be decoded as the first packet.
+pub async fn read_handshake<R>(
+    reader: &mut R,
+) -> tokio_io::Result<(Option<Handshake>, Option<Vec<u8>>)>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut first_line = vec![];
+    reader.read_until(b'\n', &mut first_line).await?;
+    match serde_json::from_slice::<Handshake>(&first_line) {
+        Ok(handshake) => Ok((Some(handshake), None)),
+        Err(_) => Ok((None, Some(first_line).filter(|line| !line.is_empty()))),
+    }
+}
+
+fn log_rejection(rejection: &OwnedIpcMessagePacket<()>) {
+    match rejection {
+        OwnedIpcMessagePacket::UnknownCommand(unknown) => {
+            warn!("unknown command `{}`", unknown.name);
+        }
+        OwnedIpcMessagePacket::BadRequest(bad_request) => {
+            warn!("bad request: {}", bad_request.reason);
+        }
+        _ => {}
+    }
+}
+
+/// Parses the request, or returns the packet to reject it.
+fn parse_request(line: &[u8]) -> Result<OwnedIpcRequestPacket, OwnedIpcMessagePacket<()>> {
+    let value: serde_json::Value = serde_json::from_slice(line)
+        .map_err(|err| BadRequest::packet(format!("the request is not valid JSON: {err}"), None))?;
+    parse_request_value(value)
+}
+
+/// Parses the request decoded from any encoding, or returns the packet to
+/// reject it.
+fn parse_request_value(
+    value: serde_json::Value,
+) -> Result<OwnedIpcRequestPacket, OwnedIpcMessagePacket<()>> {
+    if let Some(name) = value.get("cmd").and_then(unknown_command) {
+        return Err(OwnedIpcMessagePacket::UnknownCommand(UnknownCommand {
+            name,
+            server_version: VERSION.to_owned(),
+        }));
+    }
+
+    let cmd_is_invalid = value
+        .get("cmd")
+        .is_some_and(|cmd| command::Command::deserialize(cmd).is_err());
+    let client_version = value
+        .get("version")
+        .and_then(|version| version.as_str())
+        .map(str::to_owned);
+    let render = value
+        .get("render")
+        .and_then(|render| RenderHints::deserialize(render).ok())
+        .unwrap_or_default();
+    serde_json::from_value(value).map_err(|err| {
+        let mut reason = format!("the request is invalid: {err}");
+        if cmd_is_invalid && client_version.as_deref() != Some(VERSION) {
+            let client_version = client_version.as_deref().unwrap_or("unknown");
+            reason.push_str(&format!(
+                " (client version {client_version}, server version {VERSION})"
+            ));
+        }
+        let help = cmd_is_invalid.then(|| {
+            let help = command::Command::command().render_help();
+            if render.color {
+                help.ansi().to_string()
+            } else {
+                help.to_string()
+            }
+        });
+        BadRequest::packet(reason, help)
+    })
+}
+
+/// Returns the name of the command (like `job edit` for subcommands) if
+/// the server doesn't know it.
+fn unknown_command(cmd: &serde_json::Value) -> Option<String> {
+    let mut clap_command = command::Command::command();
+    let mut cmd = cmd;
+    let mut names = vec![];
+    loop {
+        let name = cmd.get("name")?.as_str()?;
+        names.push(name);
+        let Some(subcommand) = clap_command.find_subcommand(name) else {
+            return Some(names.join(" "));
+        };
+        if !subcommand.has_subcommands() {
+            return None;
+        }
+        clap_command = subcommand.clone();
+        cmd = cmd.get("args")?;
+    }
+}
+
+/// The error of a command that panicked, with the panic message.
+#[derive(Debug)]
+struct CommandPanicked(String);
+
+impl CommandPanicked {
+    fn from_payload(payload: Box<dyn Any + Send>) -> Self {
+        let msg = match payload.downcast::<String>() {
+            Ok(msg) => *msg,
+            Err(payload) => match payload.downcast::<&'static str>() {
+                Ok(msg) => (*msg).to_owned(),
+                Err(_) => "<message is not displayable>".to_owned(),
+            },
+        };
+        Self(msg)
+    }
+}
+
+impl Display for CommandPanicked {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "the command panicked: {}", self.0)
+    }
+}
+
+impl StdError for CommandPanicked {}
+
+/// Resolves to the error if the future panics, like `catch_unwind` of
+/// the `futures` crate.
+struct CatchUnwind<F>(Pin<Box<F>>);
+
+impl<F: Future> CatchUnwind<F> {
+    fn new(fut: F) -> Self {
+        Self(Box::pin(fut))
+    }
+}
+
+impl<F: Future> Future for CatchUnwind<F> {
+    type Output = thread::Result<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+        let fut = self.0.as_mut();
+        match panic::catch_unwind(AssertUnwindSafe(|| fut.poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    }
+}
+
+/// The working directory and the environment of the client, with the
+/// environment filtered by the [`EnvPolicy`] of the server.
+///
+/// [`EnvPolicy`]: crate::env::EnvPolicy
 #[derive(Debug, Clone)]
 pub struct ClientEnv {
     cwd: String,
-    env: HashMap<String, String>,
+    env: HashMap<OsString, OsString>,
+    render: RenderHints,
 }
 
 impl ClientEnv {
@@ -212,11 +1101,245 @@ impl ClientEnv {
     }
 
     #[inline(always)]
-    pub fn env(&self) -> &HashMap<String, String> {
+    pub fn env(&self) -> &HashMap<OsString, OsString> {
         &self.env
     }
+
+    #[inline(always)]
+    pub fn render(&self) -> RenderHints {
+        self.render
+    }
 }
 
 tokio::task_local! {
     pub static CLIENT_ENV: ClientEnv;
+    static REQUEST_ID: RequestId;
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::{
+        parse_request, read_handshake, read_request_line, CatchUnwind, Codec, CommandPanicked,
+        Compression, Encoding, OwnedIpcMessagePacket, RequestLine, MAX_REQUEST_LEN,
+    };
+
+    fn block_on<F: std::future::Future>(fut: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(fut)
+    }
+
+    /// JSON values shaped like requests, with commands that may or may not
+    /// exist.
+    fn requests() -> impl Strategy<Value = serde_json::Value> {
+        let leaf = prop_oneof![
+            Just(serde_json::Value::Null),
+            any::<bool>().prop_map(serde_json::Value::from),
+            any::<i64>().prop_map(serde_json::Value::from),
+            ".{0,8}".prop_map(serde_json::Value::from),
+        ];
+        let value = leaf.prop_recursive(3, 16, 4, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..4).prop_map(serde_json::Value::from),
+                prop::collection::hash_map("[a-z_]{1,8}", inner, 0..4)
+                    .prop_map(|map| serde_json::Value::Object(map.into_iter().collect())),
+            ]
+        });
+        let name = prop::sample::select(vec!["ps", "run", "job", "history", "launch"]);
+        let subcommand = prop::sample::select(vec!["ls", "start", "history", "rename"]);
+        let cmd = (name, value.clone(), proptest::option::of(subcommand)).prop_map(
+            |(name, args, sub)| match sub {
+                Some(sub) => serde_json::json!({"name": name, "args": {"name": sub, "args": args}}),
+                None => serde_json::json!({"name": name, "args": args}),
+            },
+        );
+        (cmd, value, any::<bool>()).prop_map(|(cmd, extra, multiplex)| {
+            serde_json::json!({
+                "cmd": cmd,
+                "cwd": "/",
+                "env": {},
+                "encodings": ["message_pack"],
+                "multiplex": multiplex,
+                "extra": extra,
+            })
+        })
+    }
+
+    #[tokio::test]
+    async fn test_read_request_line() {
+        let mut reader = &b"{}\nrest"[..];
+        assert!(matches!(
+            read_request_line(&mut reader).await.unwrap(),
+            RequestLine::Line(line) if line == b"{}\n"
+        ));
+
+        let garbage = vec![b'x'; MAX_REQUEST_LEN + 1];
+        let mut reader = &garbage[..];
+        assert!(matches!(
+            read_request_line(&mut reader).await.unwrap(),
+            RequestLine::TooLarge
+        ));
+
+        let mut line = vec![b'x'; MAX_REQUEST_LEN - 1];
+        line.push(b'\n');
+        let mut reader = &line[..];
+        assert!(matches!(
+            read_request_line(&mut reader).await.unwrap(),
+            RequestLine::Line(_)
+        ));
+
+        let mut reader = &b""[..];
+        assert!(matches!(
+            read_request_line(&mut reader).await.unwrap(),
+            RequestLine::Eof
+        ));
+    }
+
+    #[test]
+    fn test_parse_request() {
+        // The request is not `Debug`, since it has the token.
+        let parse_err = |line: &[u8]| match parse_request(line).err().unwrap() {
+            OwnedIpcMessagePacket::BadRequest(bad_request) => bad_request,
+            _ => panic!("expected a bad request"),
+        };
+        let bad_request = parse_err(b"\x00garbage\n");
+        assert!(bad_request.reason.contains("not valid JSON"));
+        assert!(bad_request.help.is_none());
+
+        let bad_request = parse_err(b"{\"cwd\": \"/\", \"env\": {}}\n");
+        assert!(bad_request.reason.contains("invalid"));
+        assert!(bad_request.help.is_none());
+
+        let line =
+            br#"{"cmd": {"name": "history", "args": {"limit": "1"}}, "cwd": "/", "env": {}}"#;
+        let bad_request = parse_err(line);
+        assert!(bad_request.reason.contains("client version unknown"));
+        let help = bad_request.help.unwrap();
+        assert!(help.contains("Usage:") && !help.contains('\x1b'));
+
+        // The help is colored for clients that render colors.
+        let line = br#"{"cmd": {"name": "history", "args": {"limit": "1"}}, "cwd": "/", "env": {}, "render": {"width": 80, "color": true}}"#;
+        assert!(parse_err(line).help.unwrap().contains('\x1b'));
+
+        let line = br#"{"cmd": {"name": "history", "args": {"limit": 1, "failed": false}}, "cwd": "/", "env": {}}"#;
+        assert!(parse_request(line).is_ok());
+    }
+
+    #[test]
+    fn test_unknown_command() {
+        let unknown = |line: &[u8]| match parse_request(line).err().unwrap() {
+            OwnedIpcMessagePacket::UnknownCommand(unknown) => unknown.name,
+            _ => panic!("expected an unknown command"),
+        };
+        assert_eq!(
+            unknown(br#"{"cmd": {"name": "launch", "args": {}}, "cwd": "/", "env": {}}"#),
+            "launch"
+        );
+        assert_eq!(
+            unknown(
+                br#"{"cmd": {"name": "job", "args": {"name": "rename"}}, "cwd": "/", "env": {}}"#
+            ),
+            "job rename"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_catch_unwind() {
+        assert_eq!(CatchUnwind::new(async { 42 }).await.unwrap(), 42);
+
+        let payload = CatchUnwind::new(async { panic!("boom {}", 42) })
+            .await
+            .unwrap_err();
+        assert_eq!(CommandPanicked::from_payload(payload).0, "boom 42");
+        let payload = CatchUnwind::new(async { panic!("boom") })
+            .await
+            .unwrap_err();
+        assert_eq!(CommandPanicked::from_payload(payload).0, "boom");
+    }
+
+    proptest! {
+        #[test]
+        fn prop_parse_arbitrary_requests(line in prop::collection::vec(any::<u8>(), 0..256)) {
+            if let Err(rejection) = parse_request(&line) {
+                prop_assert!(matches!(
+                    rejection,
+                    OwnedIpcMessagePacket::BadRequest(_) | OwnedIpcMessagePacket::UnknownCommand(_)
+                ));
+            }
+        }
+
+        #[test]
+        fn prop_parse_request_values(request in requests()) {
+            let line = serde_json::to_vec(&request).unwrap();
+            if let Err(rejection) = parse_request(&line) {
+                prop_assert!(matches!(
+                    rejection,
+                    OwnedIpcMessagePacket::BadRequest(_) | OwnedIpcMessagePacket::UnknownCommand(_)
+                ));
+            }
+        }
+
+        #[test]
+        fn prop_read_request_lines(data in prop::collection::vec(prop_oneof![Just(b'\n'), any::<u8>()], 0..256)) {
+            let mut reader = &data[..];
+            let mut read = vec![];
+            loop {
+                match block_on(read_request_line(&mut reader)).unwrap() {
+                    RequestLine::Line(line) => {
+                        // Only the last line may be cut off by EOF.
+                        prop_assert!(line.ends_with(b"\n") || reader.is_empty());
+                        prop_assert_eq!(line.iter().filter(|b| **b == b'\n').count(), usize::from(line.ends_with(b"\n")));
+                        read.extend(line);
+                    }
+                    RequestLine::TooLarge => prop_assert!(false, "the line is not too large"),
+                    RequestLine::Eof => break,
+                }
+            }
+            prop_assert_eq!(read, data);
+        }
+
+        #[test]
+        fn prop_read_handshake(
+            encoding in prop::sample::select(Encoding::PREFERRED.to_vec()),
+            zstd in any::<bool>(),
+            request_id in proptest::option::of("[0-9a-f]{16}"),
+            multiplex in any::<bool>(),
+            rest in prop::collection::vec(any::<u8>(), 0..64),
+        ) {
+            let compression = if zstd { Compression::Zstd } else { Compression::None };
+            let mut handshake = Codec::new(encoding, compression).handshake();
+            handshake.request_id = request_id.clone();
+            handshake.multiplex = multiplex;
+            let mut data = serde_json::to_vec(&handshake).unwrap();
+            data.push(b'\n');
+            data.extend(&rest);
+
+            let mut reader = &data[..];
+            let (parsed, pending) = block_on(read_handshake(&mut reader)).unwrap();
+            let parsed = parsed.unwrap();
+            prop_assert_eq!(parsed.encoding, encoding);
+            prop_assert_eq!(parsed.compression, Codec::new(encoding, compression).handshake().compression);
+            prop_assert_eq!(parsed.request_id, request_id);
+            prop_assert_eq!(parsed.multiplex, multiplex);
+            prop_assert!(pending.is_none());
+            // The packets after the handshake are left to the codec.
+            prop_assert_eq!(reader, &rest[..]);
+        }
+
+        #[test]
+        fn prop_read_arbitrary_handshakes(data in prop::collection::vec(any::<u8>(), 0..128)) {
+            let mut reader = &data[..];
+            let (handshake, pending) = block_on(read_handshake(&mut reader)).unwrap();
+            // Lines that are not handshakes are returned to be decoded as
+            // packets.
+            let consumed = &data[..data.len() - reader.len()];
+            match handshake {
+                Some(_) => prop_assert!(pending.is_none()),
+                None => prop_assert_eq!(pending.unwrap_or_default(), consumed),
+            }
+        }
+    }
 }
@@ -0,0 +1,114 @@
+//! Bytes that may not be valid UTF-8, like environment variables and the
+//! output of processes, which are base64-encoded in packets so they are
+//! sent as they are in every encoding.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt::{self, Formatter};
+use std::os::unix::ffi::OsStringExt;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Bytes sent as a base64 string.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Base64Bytes(pub Vec<u8>);
+
+impl Serialize for Base64Bytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64.encode(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Base64Bytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Base64Visitor;
+
+        impl Visitor<'_> for Base64Visitor {
+            type Value = Base64Bytes;
+
+            fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                f.write_str("a base64 string")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                BASE64
+                    .decode(v)
+                    .map(Base64Bytes)
+                    .map_err(|err| E::custom(format!("invalid base64: {err}")))
+            }
+        }
+
+        deserializer.deserialize_str(Base64Visitor)
+    }
+}
+
+/// Splits the variables into the ones that are valid UTF-8, sent as they
+/// are, and the others, which are base64-encoded.
+pub fn split_env<I>(vars: I) -> (HashMap<String, String>, Vec<(Base64Bytes, Base64Bytes)>)
+where
+    I: IntoIterator<Item = (OsString, OsString)>,
+{
+    let mut env = HashMap::new();
+    let mut env_bytes = vec![];
+    for (name, value) in vars {
+        match (name.into_string(), value.into_string()) {
+            (Ok(name), Ok(value)) => {
+                env.insert(name, value);
+            }
+            (name, value) => {
+                let bytes = |s: Result<String, OsString>| match s {
+                    Ok(s) => s.into_bytes(),
+                    Err(s) => s.into_vec(),
+                };
+                env_bytes.push((Base64Bytes(bytes(name)), Base64Bytes(bytes(value))));
+            }
+        }
+    }
+    (env, env_bytes)
+}
+
+/// Joins the variables split by [`split_env`].
+pub fn join_env(
+    env: HashMap<String, String>,
+    env_bytes: Vec<(Base64Bytes, Base64Bytes)>,
+) -> HashMap<OsString, OsString> {
+    let env = env
+        .into_iter()
+        .map(|(name, value)| (name.into(), value.into()));
+    let env_bytes = env_bytes
+        .into_iter()
+        .map(|(name, value)| (OsString::from_vec(name.0), OsString::from_vec(value.0)));
+    env.chain(env_bytes).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+
+    use super::{join_env, split_env, Base64Bytes};
+
+    #[test]
+    fn test_env_roundtrip() {
+        let vars = [
+            (OsString::from("LANG"), OsString::from("C.UTF-8")),
+            (
+                OsString::from("LATIN1"),
+                OsString::from_vec(b"caf\xe9".to_vec()),
+            ),
+            (OsString::from_vec(b"N\xffME".to_vec()), OsString::from("x")),
+        ];
+        let (env, env_bytes) = split_env(vars.clone());
+        assert_eq!(env.len(), 1);
+        assert_eq!(env_bytes.len(), 2);
+
+        let json = serde_json::to_string(&env_bytes).unwrap();
+        let env_bytes: Vec<(Base64Bytes, Base64Bytes)> = serde_json::from_str(&json).unwrap();
+        assert_eq!(join_env(env, env_bytes), vars.into_iter().collect());
+
+        assert!(serde_json::from_str::<Base64Bytes>("\"not base64!\"").is_err());
+    }
+}
@@ -0,0 +1,317 @@
+//! Encodings of the message packets.
+//!
+//! JSON packets are delimited by newlines, so they can be read and
+//! written by hand. Binary packets are prefixed with their length as a
+//! big-endian `u32` instead, and can be compressed.
+
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+/// Packets larger than this are rejected, to not allocate unbounded
+/// memory for a corrupted length prefix.
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// Packets smaller than this are not worth compressing.
+const COMPRESSION_THRESHOLD: usize = 256;
+
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+/// Compression of binary packets. JSON packets are never compressed.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+/// The first packet of the server if the client requested encodings, in
+/// JSON, to tell the encoding of the following packets.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Handshake {
+    pub encoding: Encoding,
+    #[serde(default)]
+    pub compression: Compression,
+    /// The id of the request, see [`RequestId`](super::RequestId).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Whether the connection is multiplexed, see [`mux`](super::mux).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub multiplex: bool,
+    /// The long version of the server, to tell version skews.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_version: Option<String>,
+}
+
+/// Reads and writes packets in the negotiated encoding.
+#[derive(Clone, Copy, Debug)]
+pub struct Codec {
+    encoding: Encoding,
+    compression: Compression,
+}
+
+impl Encoding {
+    /// Encodings in the order that clients prefer.
+    pub const PREFERRED: [Encoding; 3] = [Encoding::MessagePack, Encoding::Cbor, Encoding::Json];
+}
+
+impl Codec {
+    pub const JSON: Codec = Codec {
+        encoding: Encoding::Json,
+        compression: Compression::None,
+    };
+
+    /// Creates the codec, compression is ignored for JSON.
+    pub fn new(encoding: Encoding, compression: Compression) -> Self {
+        let compression = if encoding == Encoding::Json {
+            Compression::None
+        } else {
+            compression
+        };
+        Self {
+            encoding,
+            compression,
+        }
+    }
+
+    pub fn from_handshake(handshake: &Handshake) -> Self {
+        Self::new(handshake.encoding, handshake.compression)
+    }
+
+    pub fn handshake(&self) -> Handshake {
+        Handshake {
+            encoding: self.encoding,
+            compression: self.compression,
+            request_id: None,
+            multiplex: false,
+            server_version: None,
+        }
+    }
+
+    /// Encodes the value into a frame.
+    pub fn encode<T: Serialize>(&self, value: &T) -> IoResult<Vec<u8>> {
+        let mut payload = match self.encoding {
+            Encoding::Json => {
+                let mut payload = serde_json::to_vec(value).map_err(invalid_data)?;
+                payload.push(b'\n');
+                return Ok(payload);
+            }
+            // Keep the field names, since responses are decoded into JSON
+            // values first, which can't be read as struct variants from
+            // arrays.
+            Encoding::MessagePack => rmp_serde::to_vec_named(value).map_err(invalid_data)?,
+            Encoding::Cbor => {
+                let mut payload = vec![];
+                ciborium::into_writer(value, &mut payload).map_err(invalid_data)?;
+                payload
+            }
+        };
+
+        // Compressed payloads start with a flag telling whether the rest
+        // is actually compressed.
+        if self.compression == Compression::Zstd {
+            payload = if payload.len() >= COMPRESSION_THRESHOLD {
+                let mut compressed = vec![1];
+                compressed.extend(zstd::bulk::compress(&payload, ZSTD_LEVEL)?);
+                compressed
+            } else {
+                let mut raw = Vec::with_capacity(1 + payload.len());
+                raw.push(0);
+                raw.extend(payload);
+                raw
+            };
+        }
+
+        let len = u32::try_from(payload.len())
+            .ok()
+            .filter(|len| *len as usize <= MAX_FRAME_LEN)
+            .ok_or_else(|| invalid_data("packet is too large"))?;
+        let mut frame = Vec::with_capacity(4 + payload.len());
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+
+    /// Reads the payload of the next frame, or returns `None` on EOF.
+    pub async fn read_frame<R>(&self, reader: &mut R) -> IoResult<Option<Vec<u8>>>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        if self.encoding == Encoding::Json {
+            let mut line = vec![];
+            let read = (&mut *reader)
+                .take(MAX_FRAME_LEN as u64 + 1)
+                .read_until(b'\n', &mut line)
+                .await?;
+            if read == 0 {
+                return Ok(None);
+            }
+            if line.len() > MAX_FRAME_LEN {
+                return Err(invalid_data("packet is too large"));
+            }
+            return Ok(Some(line));
+        }
+
+        let mut len = [0; 4];
+        match reader.read_exact(&mut len).await {
+            Ok(_) => {}
+            Err(err) if err.kind() == IoErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(invalid_data("packet is too large"));
+        }
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload).await?;
+
+        if self.compression == Compression::Zstd {
+            payload = match payload.split_first() {
+                Some((0, raw)) => raw.to_vec(),
+                Some((1, compressed)) => zstd::bulk::decompress(compressed, MAX_FRAME_LEN)?,
+                _ => return Err(invalid_data("invalid compressed packet")),
+            };
+        }
+        Ok(Some(payload))
+    }
+
+    /// Decodes the payload of a frame.
+    pub fn decode<T: DeserializeOwned>(&self, payload: &[u8]) -> IoResult<T> {
+        match self.encoding {
+            Encoding::Json => serde_json::from_slice(payload).map_err(invalid_data),
+            Encoding::MessagePack => rmp_serde::from_slice(payload).map_err(invalid_data),
+            Encoding::Cbor => ciborium::from_reader(payload).map_err(invalid_data),
+        }
+    }
+}
+
+fn invalid_data<E>(err: E) -> IoError
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    IoError::new(IoErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::{Codec, Compression, Encoding, MAX_FRAME_LEN};
+    use crate::cli::{OwnedIpcMessagePacket, OwnedIpcMuxRequest};
+
+    fn codecs() -> impl Strategy<Value = Codec> {
+        let encoding = prop::sample::select(Encoding::PREFERRED.to_vec());
+        let compression = prop::sample::select(vec![Compression::None, Compression::Zstd]);
+        (encoding, compression)
+            .prop_map(|(encoding, compression)| Codec::new(encoding, compression))
+    }
+
+    fn block_on<F: std::future::Future>(fut: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(fut)
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let codecs = Encoding::PREFERRED.into_iter().flat_map(|encoding| {
+            [Compression::None, Compression::Zstd].map(|c| Codec::new(encoding, c))
+        });
+        for codec in codecs {
+            let short = OwnedIpcMessagePacket::<()>::Output("hello\n".to_owned());
+            let long = OwnedIpcMessagePacket::<()>::Output("hello\n".repeat(100));
+            let mut frames = codec.encode(&short).unwrap();
+            frames.extend(codec.encode(&long).unwrap());
+
+            let mut reader = &frames[..];
+            for expected in [short, long] {
+                let payload = codec.read_frame(&mut reader).await.unwrap().unwrap();
+                let pkt: OwnedIpcMessagePacket<serde_json::Value> = codec.decode(&payload).unwrap();
+                assert_eq!(pkt.to_output(), expected.to_output());
+            }
+            assert!(codec.read_frame(&mut reader).await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reject_large_frames() {
+        // JSON lines are capped too, without waiting for a newline.
+        let codec = Codec::JSON;
+        let line = vec![b'x'; MAX_FRAME_LEN + 1];
+        let err = codec.read_frame(&mut &line[..]).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let mut line = vec![b'x'; MAX_FRAME_LEN - 1];
+        line.push(b'\n');
+        assert!(codec.read_frame(&mut &line[..]).await.unwrap().is_some());
+
+        let codec = Codec::new(Encoding::MessagePack, Compression::None);
+        let frame = u32::MAX.to_be_bytes();
+        let err = codec.read_frame(&mut &frame[..]).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // Compressed payloads can't expand beyond the limit either.
+        let codec = Codec::new(Encoding::MessagePack, Compression::Zstd);
+        let mut payload = vec![1];
+        payload.extend(zstd::bulk::compress(&vec![0; MAX_FRAME_LEN + 1], 3).unwrap());
+        let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+        frame.extend(payload);
+        assert!(codec.read_frame(&mut &frame[..]).await.is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_round_trip(codec in codecs(), outputs in prop::collection::vec(".*", 0..8)) {
+            let mut frames = vec![];
+            for output in &outputs {
+                let pkt = OwnedIpcMessagePacket::<()>::Output(output.clone());
+                frames.extend(codec.encode(&pkt).unwrap());
+            }
+
+            let mut reader = &frames[..];
+            for output in &outputs {
+                let payload = block_on(codec.read_frame(&mut reader)).unwrap().unwrap();
+                let pkt: OwnedIpcMessagePacket<serde_json::Value> = codec.decode(&payload).unwrap();
+                prop_assert_eq!(pkt.to_output(), Some(output.as_str()));
+            }
+            prop_assert!(block_on(codec.read_frame(&mut reader)).unwrap().is_none());
+        }
+
+        #[test]
+        fn prop_read_arbitrary_frames(codec in codecs(), data in prop::collection::vec(any::<u8>(), 0..512)) {
+            let mut reader = &data[..];
+            let mut consumed = 0;
+            while let Ok(Some(payload)) = block_on(codec.read_frame(&mut reader)) {
+                prop_assert!(payload.len() <= MAX_FRAME_LEN);
+                // Every frame takes some input, so reading ends.
+                prop_assert!(data.len() - reader.len() > consumed);
+                consumed = data.len() - reader.len();
+                _ = codec.decode::<OwnedIpcMessagePacket<serde_json::Value>>(&payload);
+                _ = codec.decode::<OwnedIpcMuxRequest>(&payload);
+            }
+        }
+
+        #[test]
+        fn prop_read_truncated_frames(
+            codec in codecs().prop_filter("binary", |codec| codec.encoding != Encoding::Json),
+            output in ".{1,64}",
+            cut in any::<prop::sample::Index>(),
+        ) {
+            let frame = codec.encode(&OwnedIpcMessagePacket::<()>::Output(output)).unwrap();
+            let mut reader = &frame[..cut.index(frame.len())];
+            let res = block_on(codec.read_frame(&mut reader));
+            prop_assert!(!matches!(res, Ok(Some(_))));
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! Entry points of the fuzz targets in `fuzz/`, which run the input from
+//! untrusted peers through the same parsing as the server and clients do.
+//! Only built with the `fuzzing` feature.
+
+use std::future::Future;
+
+use tokio::runtime::{Builder as RuntimeBuilder, Runtime};
+
+use super::{
+    parse_request, parse_request_value, read_handshake, read_request_line, Codec,
+    OwnedIpcMessagePacket, OwnedIpcMuxPacket, OwnedIpcMuxRequest, RequestLine,
+};
+
+thread_local! {
+    static RUNTIME: Runtime = RuntimeBuilder::new_current_thread()
+        .build()
+        .expect("failed to create the runtime");
+}
+
+/// Reads the input like the server reads a connection: the request line,
+/// and then the framed requests if the connection is multiplexed.
+pub fn server_input(data: &[u8]) {
+    block_on(async {
+        let mut reader = data;
+        let Ok(RequestLine::Line(line)) = read_request_line(&mut reader).await else {
+            return;
+        };
+        let Ok(request) = parse_request(&line) else {
+            return;
+        };
+        let Some(encoding) = request.encodings.first() else {
+            return;
+        };
+        let codec = Codec::new(*encoding, request.compression);
+        while let Ok(Some(payload)) = codec.read_frame(&mut reader).await {
+            let Ok(mux_request) = codec.decode::<OwnedIpcMuxRequest>(&payload) else {
+                return;
+            };
+            if let Some(request) = mux_request.request {
+                _ = parse_request_value(request);
+            }
+        }
+    });
+}
+
+/// Reads the input like a client reads a connection: the handshake, and
+/// then the packets in the negotiated encoding.
+pub fn client_input(data: &[u8]) {
+    type Packet = OwnedIpcMessagePacket<serde_json::Value>;
+
+    block_on(async {
+        let mut reader = data;
+        let Ok((handshake, pending)) = read_handshake(&mut reader).await else {
+            return;
+        };
+        let codec = handshake
+            .as_ref()
+            .map_or(Codec::JSON, Codec::from_handshake);
+        let multiplex = handshake.is_some_and(|handshake| handshake.multiplex);
+        if let Some(pending) = pending {
+            _ = codec.decode::<Packet>(&pending);
+        }
+        while let Ok(Some(payload)) = codec.read_frame(&mut reader).await {
+            if multiplex {
+                _ = codec.decode::<OwnedIpcMuxPacket<serde_json::Value>>(&payload);
+            } else {
+                _ = codec.decode::<Packet>(&payload);
+            }
+        }
+    });
+}
+
+fn block_on<F: Future>(fut: F) -> F::Output {
+    RUNTIME.with(|runtime| runtime.block_on(fut))
+}
@@ -0,0 +1,494 @@
+//! Multiplexed connections, where commands share one connection.
+//!
+//! A client asks for it by setting `multiplex` in the first request, which
+//! runs as the command with id 0. If the server supports it, `multiplex` is
+//! also set in the handshake, and then in the negotiated encoding:
+//!
+//! - The client sends [`IpcMuxRequest`]s to run more commands, or to cancel
+//!   the running ones. Reusing the id of a running command is a protocol
+//!   error that closes the connection.
+//! - The server sends [`IpcMuxPacket`]s tagged with the ids of the commands,
+//!   and one without a packet when a command is done.
+//!
+//! Servers that don't support it run the first request as usual, and
+//! [`MuxClient`] falls back to a connection per command.
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
+use tokio::runtime::Handle as RuntimeHandle;
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+use tokio::task::{self, JoinHandle};
+
+use super::{
+    read_handshake, Codec, IpcMuxRequest, IpcRequestPacket, OwnedIpcMessagePacket,
+    OwnedIpcMuxPacket,
+};
+use crate::env::ListenAddress;
+use crate::transport::IpcStream;
+
+/// A packet of a command, with the response left undecoded.
+pub type Packet = OwnedIpcMessagePacket<serde_json::Value>;
+
+type Reader = BufReader<ReadHalf<BufReader<IpcStream>>>;
+type Writer = WriteHalf<BufReader<IpcStream>>;
+
+/// Runs commands over one connection if the server supports it, or over a
+/// connection per command otherwise. Dropping it closes the connection.
+pub struct MuxClient {
+    address: ListenAddress,
+    /// `None` if the server doesn't support multiplexing.
+    conn: Option<Arc<Connection>>,
+    reader: Option<JoinHandle<()>>,
+}
+
+struct Connection {
+    writer: AsyncMutex<Writer>,
+    codec: Codec,
+    server_version: Option<String>,
+    /// Senders of the running commands, `None` once the connection is
+    /// closed.
+    streams: Mutex<Option<HashMap<u64, mpsc::UnboundedSender<Event>>>>,
+    next_id: AtomicU64,
+}
+
+enum Event {
+    Packet(Packet),
+    /// The command is done, with the id of the request.
+    Done(Option<String>),
+}
+
+/// The packets of a command. Dropping it before the command is done
+/// cancels the command.
+pub struct MuxStream {
+    rx: mpsc::UnboundedReceiver<Event>,
+    kind: StreamKind,
+    request_id: Option<String>,
+    server_version: Option<String>,
+    done: bool,
+}
+
+enum StreamKind {
+    Mux {
+        id: u64,
+        conn: Arc<Connection>,
+    },
+    /// The command has a connection of its own, read by the task.
+    Own(JoinHandle<()>),
+}
+
+impl MuxClient {
+    /// Connects to the server and runs the first command.
+    pub async fn connect(
+        address: ListenAddress,
+        request: IpcRequestPacket<'_>,
+    ) -> io::Result<(Self, MuxStream)> {
+        let stream = IpcStream::connect(&address).await?;
+        Self::with_stream(address, stream, request).await
+    }
+
+    /// Runs the first command over a connected stream, so callers can tell
+    /// connection errors from the others.
+    pub async fn with_stream(
+        address: ListenAddress,
+        stream: IpcStream,
+        mut request: IpcRequestPacket<'_>,
+    ) -> io::Result<(Self, MuxStream)> {
+        request.multiplex = true;
+        let mut reader = send_request(stream, &request).await?;
+        let (handshake, pending) = read_handshake(&mut reader).await?;
+        let handshake = match handshake {
+            Some(handshake) if handshake.multiplex => handshake,
+            handshake => {
+                let stream = MuxStream::own(reader, handshake, pending);
+                let client = Self {
+                    address,
+                    conn: None,
+                    reader: None,
+                };
+                return Ok((client, stream));
+            }
+        };
+
+        let (read_half, write_half) = tokio::io::split(reader);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let conn = Arc::new(Connection {
+            writer: AsyncMutex::new(write_half),
+            codec: Codec::from_handshake(&handshake),
+            server_version: handshake.server_version.clone(),
+            streams: Mutex::new(Some(HashMap::from([(0, tx)]))),
+            next_id: AtomicU64::new(1),
+        });
+        let reader = task::spawn(read_mux(Arc::clone(&conn), BufReader::new(read_half)));
+        let stream = MuxStream {
+            rx,
+            kind: StreamKind::Mux {
+                id: 0,
+                conn: Arc::clone(&conn),
+            },
+            request_id: handshake.request_id,
+            server_version: handshake.server_version,
+            done: false,
+        };
+        let client = Self {
+            address,
+            conn: Some(conn),
+            reader: Some(reader),
+        };
+        Ok((client, stream))
+    }
+
+    /// Returns whether the commands share the connection.
+    pub fn is_multiplexed(&self) -> bool {
+        self.conn.is_some()
+    }
+
+    /// Runs another command.
+    pub async fn send(&self, request: IpcRequestPacket<'_>) -> io::Result<MuxStream> {
+        let Some(conn) = &self.conn else {
+            let stream = IpcStream::connect(&self.address).await?;
+            let mut reader = send_request(stream, &request).await?;
+            let (handshake, pending) = read_handshake(&mut reader).await?;
+            return Ok(MuxStream::own(reader, handshake, pending));
+        };
+
+        let id = conn.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::unbounded_channel();
+        match conn.streams.lock().as_mut() {
+            Some(streams) => streams.insert(id, tx),
+            None => return Err(connection_closed()),
+        };
+        // Created first, so the command is forgotten if writing fails.
+        let stream = MuxStream {
+            rx,
+            kind: StreamKind::Mux {
+                id,
+                conn: Arc::clone(conn),
+            },
+            request_id: None,
+            server_version: conn.server_version.clone(),
+            done: false,
+        };
+        let frame = conn.codec.encode(&IpcMuxRequest {
+            id,
+            request: Some(&request),
+        })?;
+        write_frame(&conn.writer, &frame).await?;
+        Ok(stream)
+    }
+}
+
+impl Drop for MuxClient {
+    fn drop(&mut self) {
+        if let Some(reader) = &self.reader {
+            reader.abort();
+        }
+    }
+}
+
+impl MuxStream {
+    fn own(
+        reader: BufReader<IpcStream>,
+        handshake: Option<super::Handshake>,
+        pending: Option<Vec<u8>>,
+    ) -> Self {
+        let (codec, request_id, server_version) = match handshake {
+            Some(handshake) => (
+                Codec::from_handshake(&handshake),
+                handshake.request_id,
+                handshake.server_version,
+            ),
+            None => (Codec::JSON, None, None),
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        let reader = task::spawn(read_own(reader, codec, pending, request_id.clone(), tx));
+        Self {
+            rx,
+            kind: StreamKind::Own(reader),
+            request_id,
+            server_version,
+            done: false,
+        }
+    }
+
+    /// Returns the next packet, or `None` once the command is done.
+    pub async fn next(&mut self) -> io::Result<Option<Packet>> {
+        if self.done {
+            return Ok(None);
+        }
+        match self.rx.recv().await {
+            Some(Event::Packet(packet)) => Ok(Some(packet)),
+            Some(Event::Done(request_id)) => {
+                self.done = true;
+                self.request_id = request_id.or(self.request_id.take());
+                Ok(None)
+            }
+            None => {
+                self.done = true;
+                Err(connection_closed())
+            }
+        }
+    }
+
+    /// Returns the id of the request to find its server logs, which may
+    /// only be known once the command is done.
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    /// Returns the long version of the server, `None` for servers older
+    /// than it.
+    pub fn server_version(&self) -> Option<&str> {
+        self.server_version.as_deref()
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let (id, conn) = match &self.kind {
+            StreamKind::Own(reader) => {
+                // Closing the connection stops the command.
+                reader.abort();
+                return;
+            }
+            StreamKind::Mux { id, conn } => (*id, Arc::clone(conn)),
+        };
+        if self.done {
+            return;
+        }
+        match conn.streams.lock().as_mut() {
+            Some(streams) => streams.remove(&id),
+            None => return,
+        };
+
+        // Streams may be dropped after the runtime is gone.
+        let Ok(runtime) = RuntimeHandle::try_current() else {
+            return;
+        };
+        runtime.spawn(async move {
+            if let Ok(frame) = conn.codec.encode(&IpcMuxRequest { id, request: None }) {
+                _ = write_frame(&conn.writer, &frame).await;
+            }
+        });
+    }
+}
+
+/// Writes a frame to the connection shared by commands.
+pub(super) async fn write_frame<W>(writer: &AsyncMutex<W>, frame: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = writer.lock().await;
+    writer.write_all(frame).await?;
+    writer.flush().await
+}
+
+async fn send_request(
+    mut stream: IpcStream,
+    request: &IpcRequestPacket<'_>,
+) -> io::Result<BufReader<IpcStream>> {
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    stream.write_all(&line).await?;
+    Ok(BufReader::new(stream))
+}
+
+async fn read_mux(conn: Arc<Connection>, mut reader: Reader) {
+    loop {
+        let payload = match conn.codec.read_frame(&mut reader).await {
+            Ok(Some(payload)) => payload,
+            Ok(None) => break,
+            Err(err) => {
+                debug!("failed to read from the multiplexed connection: {err:?}");
+                break;
+            }
+        };
+        let pkt: OwnedIpcMuxPacket<serde_json::Value> = match conn.codec.decode(&payload) {
+            Ok(pkt) => pkt,
+            Err(err) => {
+                debug!("invalid packet on the multiplexed connection: {err:?}");
+                break;
+            }
+        };
+
+        let mut streams = conn.streams.lock();
+        let Some(streams) = streams.as_mut() else {
+            break;
+        };
+        match pkt.packet {
+            Some(packet) => {
+                if let Some(tx) = streams.get(&pkt.id) {
+                    _ = tx.send(Event::Packet(packet));
+                }
+            }
+            None => {
+                if let Some(tx) = streams.remove(&pkt.id) {
+                    _ = tx.send(Event::Done(pkt.request_id));
+                }
+            }
+        }
+    }
+
+    // The running commands end with an error.
+    conn.streams.lock().take();
+}
+
+async fn read_own(
+    mut reader: BufReader<IpcStream>,
+    codec: Codec,
+    mut pending: Option<Vec<u8>>,
+    request_id: Option<String>,
+    tx: mpsc::UnboundedSender<Event>,
+) {
+    loop {
+        let payload = match pending.take() {
+            Some(payload) => payload,
+            None => match codec.read_frame(&mut reader).await {
+                Ok(Some(payload)) => payload,
+                Ok(None) => break,
+                Err(_) => return,
+            },
+        };
+        let Ok(packet) = codec.decode(&payload) else {
+            return;
+        };
+        if tx.send(Event::Packet(packet)).is_err() {
+            return;
+        }
+    }
+    _ = tx.send(Event::Done(request_id));
+}
+
+fn connection_closed() -> io::Error {
+    io::Error::new(ErrorKind::ConnectionAborted, "the connection is closed")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use clap::Parser;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+
+    use super::MuxClient;
+    use crate::cli::{Compression, Encoding, IpcRequestPacket};
+    use crate::env::ListenAddress;
+    use crate::transport::IpcStream;
+    use crate::Command;
+
+    fn request(cmd: &Command) -> IpcRequestPacket<'_> {
+        IpcRequestPacket {
+            cmd,
+            cwd: "/".to_owned(),
+            env: Default::default(),
+            env_bytes: vec![],
+            token: None,
+            args: vec![],
+            encodings: vec![Encoding::Json],
+            compression: Compression::None,
+            version: "test",
+            multiplex: false,
+            render: Default::default(),
+        }
+    }
+
+    async fn read_json(reader: &mut BufReader<UnixStream>) -> serde_json::Value {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_multiplexed() {
+        let (client_end, server_end) = UnixStream::pair().unwrap();
+        let server = tokio::spawn(async move {
+            let mut server_end = BufReader::new(server_end);
+            let first = read_json(&mut server_end).await;
+            assert_eq!(first["multiplex"], true);
+            server_end
+                .write_all(b"{\"encoding\":\"json\",\"multiplex\":true}\n")
+                .await
+                .unwrap();
+
+            let second = read_json(&mut server_end).await;
+            assert_eq!(second["id"], 1);
+            assert_eq!(second["request"]["cmd"]["name"], "status");
+            // Packets of the commands interleave.
+            server_end
+                .write_all(
+                    b"{\"id\":1,\"packet\":{\"Output\":\"b\\n\"}}\n\
+                    {\"id\":0,\"packet\":{\"Output\":\"a\\n\"}}\n\
+                    {\"id\":0,\"packet\":null,\"request_id\":\"r0\"}\n\
+                    {\"id\":1,\"packet\":null,\"request_id\":\"r1\"}\n",
+                )
+                .await
+                .unwrap();
+
+            let third = read_json(&mut server_end).await;
+            assert_eq!(third["id"], 2);
+            let cancel = read_json(&mut server_end).await;
+            assert_eq!(cancel["id"], 2);
+            assert!(cancel["request"].is_null());
+        });
+
+        let ps = Command::parse_from(["petri", "ps"]);
+        let status = Command::parse_from(["petri", "status"]);
+        let address = ListenAddress::Path(PathBuf::from("/nonexistent"));
+        let (client, mut first) =
+            MuxClient::with_stream(address, IpcStream::Unix(client_end), request(&ps))
+                .await
+                .unwrap();
+        assert!(client.is_multiplexed());
+        let mut second = client.send(request(&status)).await.unwrap();
+
+        let packet = first.next().await.unwrap().unwrap();
+        assert_eq!(packet.to_output(), Some("a\n"));
+        assert!(first.next().await.unwrap().is_none());
+        assert_eq!(first.request_id(), Some("r0"));
+
+        let packet = second.next().await.unwrap().unwrap();
+        assert_eq!(packet.to_output(), Some("b\n"));
+        assert!(second.next().await.unwrap().is_none());
+        assert_eq!(second.request_id(), Some("r1"));
+
+        // Dropping a running command cancels it.
+        let third = client.send(request(&ps)).await.unwrap();
+        drop(third);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fallback() {
+        let (client_end, server_end) = UnixStream::pair().unwrap();
+        let server = tokio::spawn(async move {
+            let mut server_end = BufReader::new(server_end);
+            read_json(&mut server_end).await;
+            // Older servers reply without multiplexing.
+            server_end
+                .write_all(
+                    b"{\"encoding\":\"json\",\"request_id\":\"r0\"}\n{\"Output\":\"a\\n\"}\n",
+                )
+                .await
+                .unwrap();
+        });
+
+        let ps = Command::parse_from(["petri", "ps"]);
+        let address = ListenAddress::Path(PathBuf::from("/nonexistent"));
+        let (client, mut first) =
+            MuxClient::with_stream(address, IpcStream::Unix(client_end), request(&ps))
+                .await
+                .unwrap();
+        assert!(!client.is_multiplexed());
+        server.await.unwrap();
+
+        let packet = first.next().await.unwrap().unwrap();
+        assert_eq!(packet.to_output(), Some("a\n"));
+        assert!(first.next().await.unwrap().is_none());
+        assert_eq!(first.request_id(), Some("r0"));
+    }
+}
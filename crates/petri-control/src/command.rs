@@ -1,40 +1,148 @@
+mod apply;
+mod batch;
+mod bench;
+mod checkpoint;
+mod down;
+mod env;
+mod gc;
+mod healthz;
+mod history;
 mod job;
+mod job_file;
 mod log;
+mod logs;
+mod namespace;
+mod notify;
+mod on_exit;
+mod paths;
+mod pause;
+mod ping;
 mod ps;
+mod restore;
+mod resume;
 mod run;
+mod server;
+mod stats;
+mod status;
 mod stop;
 mod stop_server;
+mod stream;
+mod tag;
+mod token;
+mod top;
+mod tree;
+mod up;
+mod wait;
+
+use std::pin::pin;
+use std::time::Duration;
 
 use anyhow::Result;
 use async_trait::async_trait;
+use chrono::{DateTime, Local, NaiveDateTime, NaiveTime};
 use clap::Parser;
+use petri_core::spawn_queue::SpawnPermit;
+use petri_utils::time::parse_duration;
 use serde::{Deserialize, Serialize};
 
+use super::auth::Role;
 use super::cli::{IpcChannel, OwnedIpcMessagePacket};
+use super::lanes::Lane;
 use super::Context as ControlContext;
+use super::{LONG_VERSION, VERSION};
+
+pub use bench::{run_bench_child, BENCH_CHILD_ARG};
+pub use stream::{
+    ItemReceiver, OutputEnd, Progress, ResponseStream, StreamConsumer, StreamEnd, StreamHandler,
+    StreamPacket,
+};
 
 const AFTER_HELP: &str = color_print::cstr!(
     "Run '<bold>petri help <<command>></bold>' for more information on a specific command."
 );
 
+/// How often to tell the client what a starting job is waiting for.
+const PRECONDITION_REPORT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Commands are tagged with their names on the wire, so servers can tell
+/// the ones they don't know from malformed ones.
 #[derive(Parser, Serialize, Deserialize, Debug)]
+#[serde(tag = "name", content = "args", rename_all = "kebab-case")]
 #[command(name = "petri")]
 #[command(about = "A minimalist process manager")]
+#[command(version = VERSION, long_version = LONG_VERSION)]
 #[command(after_help = AFTER_HELP)]
 pub enum Command {
     /// Run an arbitrary command.
-    Run(run::RunSubcommand),
+    Run(Box<run::RunSubcommand>),
     /// Stop a currently running process.
     Stop(stop::StopSubcommand),
+    /// Pause a running process with `SIGSTOP` until it's resumed.
+    Pause(pause::PauseSubcommand),
+    /// Resume a paused process.
+    Resume(resume::ResumeSubcommand),
+    /// Snapshot a process and its descendants to disk with CRIU
+    /// (experimental, Linux only).
+    Checkpoint(checkpoint::CheckpointSubcommand),
+    /// Restore a process from its checkpoint (experimental, Linux only).
+    Restore(restore::RestoreSubcommand),
+    /// Annotate a process with tags and a note, like why it's running.
+    Tag(tag::TagSubcommand),
     /// Stream logs of a process.
     Log(log::LogSubcommand),
+    /// Manage log files of processes and jobs.
+    #[command(subcommand)]
+    Logs(logs::LogsSubcommand),
     /// List processes.
     Ps(ps::PsSubcommand),
+    /// Show historical resource usage of a process.
+    Stats(stats::StatsSubcommand),
+    /// Show the current resource usage of the running processes, refreshing
+    /// until stopped.
+    Top(top::TopSubcommand),
+    /// Show managed processes with their descendants.
+    Tree(tree::TreeSubcommand),
+    /// Show the status of the server.
+    Status(status::StatusSubcommand),
+    /// Check whether the server responds, printing `pong` if it does.
+    Ping(ping::PingSubcommand),
+    /// Check whether the server is healthy, exiting with 1 if it isn't or
+    /// doesn't respond, for cron jobs and external watchdogs.
+    Healthz(healthz::HealthzSubcommand),
+    /// Show the environment the server runs with, to debug why processes
+    /// behave differently than in a shell.
+    Env(env::EnvSubcommand),
     /// Manage jobs.
     #[command(subcommand)]
     Job(job::JobSubcommand),
+    /// Start autostart jobs (or the selected ones) with their dependencies.
+    Up(up::UpSubcommand),
+    /// Stop autostart jobs (or the selected ones).
+    Down(down::DownSubcommand),
+    /// Create or update jobs from a Procfile or a compose file.
+    Apply(apply::ApplySubcommand),
+    /// Wait for a process or job to exit.
+    Wait(wait::WaitSubcommand),
+    /// Run a command when a process exits.
+    OnExit(on_exit::OnExitSubcommand),
+    /// Notify the server from a managed process.
+    #[command(subcommand)]
+    Notify(notify::NotifySubcommand),
+    /// Remove exited jobs past the retention and their log files.
+    Gc(gc::GcSubcommand),
+    /// Show the commands run against the server.
+    History(history::HistorySubcommand),
+    /// Inspect the server.
+    #[command(subcommand)]
+    Server(server::ServerSubcommand),
+    /// Manage tokens of clients.
+    #[command(subcommand)]
+    Token(token::TokenSubcommand),
     /// Request the server to stop.
     StopServer(stop_server::StopServerSubcommand),
+    /// Measure the throughput and latency of the output of processes.
+    #[command(hide = true)]
+    Bench(bench::BenchSubcommand),
 }
 
 macro_rules! dispatch_command {
@@ -42,12 +150,59 @@ macro_rules! dispatch_command {
         match $c_var {
             Command::Run($s_var) => $handler,
             Command::Stop($s_var) => $handler,
+            Command::Pause($s_var) => $handler,
+            Command::Resume($s_var) => $handler,
+            Command::Checkpoint($s_var) => $handler,
+            Command::Restore($s_var) => $handler,
+            Command::Tag($s_var) => $handler,
             Command::Log($s_var) => $handler,
+            Command::Logs(logs_subcommand) => match logs_subcommand {
+                logs::LogsSubcommand::Fetch($s_var) => $handler,
+                logs::LogsSubcommand::Remote(remote_subcommand) => match remote_subcommand {
+                    logs::RemoteSubcommand::Ls($s_var) => $handler,
+                },
+            },
             Command::Ps($s_var) => $handler,
+            Command::Stats($s_var) => $handler,
+            Command::Top($s_var) => $handler,
+            Command::Tree($s_var) => $handler,
+            Command::Status($s_var) => $handler,
+            Command::Ping($s_var) => $handler,
+            Command::Healthz($s_var) => $handler,
+            Command::Env($s_var) => $handler,
             Command::Job(job_subcommand) => match job_subcommand {
                 job::JobSubcommand::Ls($s_var) => $handler,
+                job::JobSubcommand::Start($s_var) => $handler,
+                job::JobSubcommand::Deploy($s_var) => $handler,
+                job::JobSubcommand::Undo($s_var) => $handler,
+                job::JobSubcommand::ExportSystemd($s_var) => $handler,
+                job::JobSubcommand::Inspect($s_var) => $handler,
+                job::JobSubcommand::Edit($s_var) => $handler,
+                job::JobSubcommand::History($s_var) => $handler,
+            },
+            Command::Up($s_var) => $handler,
+            Command::Down($s_var) => $handler,
+            Command::Apply($s_var) => $handler,
+            Command::Wait($s_var) => $handler,
+            Command::OnExit($s_var) => $handler,
+            Command::Notify(notify_subcommand) => match notify_subcommand {
+                notify::NotifySubcommand::Ready($s_var) => $handler,
+            },
+            Command::Gc($s_var) => $handler,
+            Command::History($s_var) => $handler,
+            Command::Server(server_subcommand) => match server_subcommand {
+                server::ServerSubcommand::Logs($s_var) => $handler,
+                server::ServerSubcommand::Metrics($s_var) => $handler,
+                server::ServerSubcommand::Set($s_var) => $handler,
+                server::ServerSubcommand::Reload($s_var) => $handler,
+            },
+            Command::Token(token_subcommand) => match token_subcommand {
+                token::TokenSubcommand::Create($s_var) => $handler,
+                token::TokenSubcommand::Ls($s_var) => $handler,
+                token::TokenSubcommand::Revoke($s_var) => $handler,
             },
             Command::StopServer($s_var) => $handler,
+            Command::Bench($s_var) => $handler,
         }
     };
 }
@@ -60,6 +215,12 @@ pub trait CommandClient {
     /// run in stream mode, which directly writes the contents server
     /// sends to stdout.
     fn handler(&self) -> Option<Box<dyn ResponseHandler>>;
+
+    /// Prepares the command on the client before it's sent to the
+    /// server, like collecting the local state that the server needs.
+    fn prepare(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -68,9 +229,227 @@ pub trait ResponseHandler {
         &mut self,
         resp: OwnedIpcMessagePacket<serde_json::Value>,
     ) -> Result<()>;
+
+    /// Returns the status that the client should exit with after the
+    /// response is handled.
+    fn exit_status(&self) -> i32 {
+        0
+    }
+
+    /// Returns whether the server sends more response packets after the
+    /// ones handled so far.
+    fn expects_more(&self) -> bool {
+        false
+    }
+
+    /// Returns the command to send after the response is handled, for
+    /// commands that take more than one request (like `job edit`).
+    fn follow_up(&mut self) -> Option<Command> {
+        None
+    }
+}
+
+/// Waits until the preconditions of the job hold, telling the client what
+/// it's waiting for whenever that changes.
+pub(crate) async fn wait_preconditions(
+    ctx: &ControlContext,
+    channel: &mut IpcChannel,
+    jid: &str,
+) -> Result<()> {
+    let wait = ctx.job_mgr_handle.wait_preconditions(jid);
+    let mut wait = pin!(wait);
+    let mut reported = None;
+    loop {
+        tokio::select! {
+            res = &mut wait => return res,
+            _ = tokio::time::sleep(PRECONDITION_REPORT_INTERVAL) => {}
+        }
+        let waiting_for = ctx.job_mgr_handle.waiting_for(jid);
+        if waiting_for != reported {
+            if let Some(target) = &waiting_for {
+                channel
+                    .write_output(&format!("waiting for {target}\n"))
+                    .await?;
+            }
+            reported = waiting_for;
+        }
+    }
+}
+
+/// Waits for a slot in the spawn queue of the server, telling the client
+/// its position while it's queued.
+pub(crate) async fn wait_spawn_slot(
+    ctx: &ControlContext,
+    channel: &mut IpcChannel,
+) -> Result<SpawnPermit> {
+    let mut ticket = ctx.proc_mgr_handle.queue_spawn();
+    while let Some(position) = ticket.position() {
+        channel
+            .write_output(&format!(
+                "waiting to start, position {position} in the spawn queue\n"
+            ))
+            .await?;
+        ticket.changed().await;
+    }
+    Ok(ticket.wait().await)
+}
+
+/// Parses a `KEY=VALUE` pair from the command line.
+pub(crate) fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!("expected `KEY=VALUE`, got `{s}`")),
+    }
+}
+
+/// Parses a deadline from the command line, either a duration from now
+/// like `30m`, or a local time like `18:30`, `18:30:15` or
+/// `2024-05-01 18:30`. A time of day that has passed means tomorrow.
+///
+/// Returns the deadline as a Unix timestamp in seconds.
+pub(crate) fn parse_deadline(s: &str) -> Result<i64, String> {
+    parse_time_from(s, Local::now(), true).map(|deadline| deadline.timestamp())
+}
+
+/// Parses a time in the past from the command line, either a duration
+/// ago like `30m`, or a local time like [`parse_deadline`]. A time of day
+/// that hasn't come yet means yesterday.
+///
+/// Returns the time as a Unix timestamp in seconds.
+pub(crate) fn parse_since(s: &str) -> Result<i64, String> {
+    parse_time_from(s, Local::now(), false).map(|since| since.timestamp())
+}
+
+/// Converts a deadline sent by the client back to a local time, which
+/// fails if it's out of range.
+fn deadline_from_timestamp(ts: i64) -> Result<DateTime<Local>> {
+    DateTime::from_timestamp(ts, 0)
+        .map(|deadline| deadline.with_timezone(&Local))
+        .ok_or_else(|| anyhow!("deadline `{ts}` is out of range"))
+}
+
+/// Parses a time after `now` if `ahead`, or before it otherwise.
+fn parse_time_from(s: &str, now: DateTime<Local>, ahead: bool) -> Result<DateTime<Local>, String> {
+    if let Ok(duration) = parse_duration(s) {
+        let duration = chrono::Duration::from_std(duration).map_err(|err| err.to_string())?;
+        let time = if ahead {
+            now.checked_add_signed(duration)
+        } else {
+            now.checked_sub_signed(duration)
+        };
+        return time.ok_or_else(|| format!("duration `{s}` is too long"));
+    }
+
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(s, fmt).ok());
+    let naive = match naive {
+        Some(naive) => naive,
+        None => {
+            let Some(time) = ["%H:%M:%S", "%H:%M"]
+                .iter()
+                .find_map(|fmt| NaiveTime::parse_from_str(s, fmt).ok())
+            else {
+                return Err(format!(
+                    "expected a duration like `30m` or a time like `18:30`, got `{s}`"
+                ));
+            };
+            let today = now.date_naive().and_time(time);
+            match (ahead, today > now.naive_local()) {
+                (true, false) => today + chrono::Duration::days(1),
+                (false, true) => today - chrono::Duration::days(1),
+                _ => today,
+            }
+        }
+    };
+    naive
+        .and_local_timezone(Local)
+        .earliest()
+        .ok_or_else(|| format!("time `{s}` doesn't exist in the local time zone"))
 }
 
 impl Command {
+    /// Returns the role that a client needs to run the command.
+    pub fn required_role(&self) -> Role {
+        match self {
+            Command::Ps(_)
+            | Command::Log(_)
+            | Command::Logs(_)
+            | Command::Stats(_)
+            | Command::Top(_)
+            | Command::Tree(_)
+            | Command::Status(_)
+            | Command::Ping(_)
+            | Command::Healthz(_)
+            | Command::Env(_)
+            | Command::Wait(_)
+            | Command::History(_)
+            | Command::Job(job::JobSubcommand::Ls(_))
+            | Command::Job(job::JobSubcommand::History(_))
+            | Command::Server(server::ServerSubcommand::Logs(_))
+            | Command::Server(server::ServerSubcommand::Metrics(_)) => Role::ReadOnly,
+            Command::Run(_)
+            | Command::Stop(_)
+            | Command::Pause(_)
+            | Command::Resume(_)
+            | Command::Checkpoint(_)
+            | Command::Restore(_)
+            | Command::Tag(_)
+            | Command::OnExit(_)
+            | Command::Up(_)
+            | Command::Down(_)
+            | Command::Apply(_)
+            | Command::Gc(_)
+            | Command::Notify(_)
+            | Command::Bench(_)
+            | Command::Job(_) => Role::Operator,
+            Command::Server(_) | Command::Token(_) | Command::StopServer(_) => Role::Admin,
+        }
+    }
+
+    /// Returns whether the command streams until the client, the process
+    /// being watched or the server ends it, so it can run for arbitrarily
+    /// long.
+    pub fn is_streaming(&self) -> bool {
+        matches!(
+            self,
+            Command::Log(_)
+                | Command::Logs(logs::LogsSubcommand::Fetch(_))
+                | Command::Wait(_)
+                | Command::Top(_)
+                | Command::Server(server::ServerSubcommand::Logs(_))
+                | Command::StopServer(_)
+        ) || matches!(self, Command::Run(run) if run.is_unbounded())
+            || matches!(self, Command::OnExit(on_exit) if on_exit.is_unbounded())
+    }
+
+    /// Returns the lane that the command is served in. `stop-server` is
+    /// served with the quick ones, so it gets through a busy server.
+    pub fn lane(&self) -> Lane {
+        match self {
+            Command::StopServer(_) => Lane::Quick,
+            _ if self.is_streaming() => Lane::Streaming,
+            _ if self.required_role() == Role::ReadOnly => Lane::Quick,
+            _ => Lane::Batch,
+        }
+    }
+
+    /// Returns whether the command only checks that the server is alive.
+    /// These don't start the server and aren't recorded in the history
+    /// unless they fail.
+    pub fn is_probe(&self) -> bool {
+        matches!(self, Command::Ping(_) | Command::Healthz(_))
+    }
+
+    /// Returns how long the client waits for the server to respond before
+    /// giving up, if the command has a limit.
+    pub fn client_timeout(&self) -> Option<Duration> {
+        match self {
+            Command::Healthz(healthz) => Some(healthz.timeout),
+            _ => None,
+        }
+    }
+
     pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
         dispatch_command!(self, subcommand => subcommand.run(ctx, channel).await?);
 
@@ -82,4 +461,57 @@ impl CommandClient for Command {
     fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
         dispatch_command!(self, subcommand => subcommand.handler())
     }
+
+    fn prepare(&mut self) -> Result<()> {
+        dispatch_command!(self, subcommand => subcommand.prepare())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Local, TimeZone};
+
+    use super::parse_time_from;
+
+    #[test]
+    fn test_parse_deadline() {
+        let now = Local.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let parse = |s| parse_time_from(s, now, true).map(|deadline| deadline.to_string());
+        let at = |h, m| {
+            Local
+                .with_ymd_and_hms(2024, 5, 1, h, m, 0)
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(parse("30m"), Ok(at(12, 30)));
+        assert_eq!(parse("18:30"), Ok(at(18, 30)));
+        assert_eq!(
+            parse("08:00:00"),
+            Ok(Local
+                .with_ymd_and_hms(2024, 5, 2, 8, 0, 0)
+                .unwrap()
+                .to_string())
+        );
+        assert_eq!(parse("2024-05-01 13:15"), Ok(at(13, 15)));
+        assert!(parse("tomorrow").is_err());
+        assert!(parse("9000000000000").is_err());
+    }
+
+    #[test]
+    fn test_parse_since() {
+        let now = Local.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let parse = |s| parse_time_from(s, now, false).map(|since| since.to_string());
+        let at = |month, day, h, m| {
+            Local
+                .with_ymd_and_hms(2024, month, day, h, m, 0)
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(parse("30m"), Ok(at(5, 1, 11, 30)));
+        assert_eq!(parse("08:00"), Ok(at(5, 1, 8, 0)));
+        assert_eq!(parse("18:30"), Ok(at(4, 30, 18, 30)));
+        assert_eq!(parse("2024-05-01 13:15"), Ok(at(5, 1, 13, 15)));
+    }
 }
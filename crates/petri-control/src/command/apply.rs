@@ -0,0 +1,559 @@
+mod compose;
+mod procfile;
+mod validate;
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use clap::{Args, ValueEnum};
+use petri_core::job_mgr::{Job, JobDescription};
+use petri_core::process::StartInfo;
+use serde::{Deserialize, Serialize};
+
+use super::batch::{BatchConsumer, BatchReport};
+use super::job_file::JobFile;
+use super::namespace::namespace_of;
+use super::{CommandClient, IpcChannel, OwnedIpcMessagePacket, ResponseHandler, StreamHandler};
+use crate::cli::CLIENT_ENV;
+use crate::diff::{diff, Hunk, LineChange};
+use crate::Context as ControlContext;
+use validate::{check_job, find_cycles, Diagnostic, Problem};
+
+/// The unchanged lines shown around the changed ones.
+const DIFF_CONTEXT: usize = 3;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct ApplySubcommand {
+    /// The format of the file, inferred from its name if omitted.
+    #[arg(long, value_enum)]
+    format: Option<ApplyFormat>,
+    /// The file to import jobs from.
+    file: PathBuf,
+    /// The jobs read from the file on the client.
+    #[arg(skip)]
+    jobs: Vec<JobSpec>,
+    /// The directory of the file, which jobs run in.
+    #[arg(skip)]
+    cwd: String,
+    /// The namespace of the jobs, defaults to the name of the directory of
+    /// the file.
+    #[arg(short = 'n', long)]
+    namespace: Option<String>,
+    /// Show what would be created or updated as a diff of the job settings
+    /// (see `petri job edit`), without applying it.
+    #[arg(long)]
+    diff: bool,
+    /// Check the jobs for problems on the server, like missing programs and
+    /// dependency cycles, without applying them.
+    #[arg(long, conflicts_with = "diff")]
+    validate_only: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum, Serialize, Deserialize, Debug)]
+enum ApplyFormat {
+    /// `name: command` lines, as used by foreman and Heroku.
+    Procfile,
+    /// Services of a `docker-compose.yml`, with only `command`,
+    /// `environment` and `depends_on` used.
+    Compose,
+}
+
+/// A job converted from an entry of the file.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+struct JobSpec {
+    name: String,
+    cmd_line: Vec<String>,
+    env: Vec<(String, String)>,
+    depends_on: Vec<String>,
+}
+
+/// What applying a job does.
+enum Change {
+    Create(Box<JobDescription>),
+    Update(Box<Job>, Box<JobDescription>),
+    Unchanged(Box<Job>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum ChangeKind {
+    Create,
+    Update,
+    Unchanged,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct DiffResponse {
+    jobs: Vec<JobDiff>,
+}
+
+/// The changes to the settings of a job. Applying never removes jobs, so
+/// only the settings of updated jobs may be removed.
+#[derive(Serialize, Deserialize, Debug)]
+struct JobDiff {
+    name: String,
+    kind: ChangeKind,
+    hunks: Vec<Hunk>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ValidateResponse {
+    jobs: usize,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl ApplyFormat {
+    fn infer(path: &Path) -> Option<Self> {
+        let file_name = path.file_name()?.to_str()?;
+        if file_name.starts_with("Procfile") {
+            Some(ApplyFormat::Procfile)
+        } else if file_name.ends_with(".yml") || file_name.ends_with(".yaml") {
+            Some(ApplyFormat::Compose)
+        } else {
+            None
+        }
+    }
+}
+
+impl ApplySubcommand {
+    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        let env_vars = CLIENT_ENV
+            .try_with(|env| env.env().clone())
+            .expect("no `ClientEnv` set in the calling context");
+
+        let namespace = self
+            .namespace
+            .clone()
+            .unwrap_or_else(|| namespace_of(&self.cwd));
+        if self.validate_only {
+            let jobs = self.jobs.len();
+            let diagnostics = validate_jobs(ctx, self.jobs, &self.cwd, &namespace, env_vars).await;
+            channel
+                .write_response(ValidateResponse { jobs, diagnostics })
+                .await?;
+            return Ok(());
+        }
+
+        let existing_jobs = ctx.job_mgr_handle.jobs().await;
+        let mut changes = Vec::with_capacity(self.jobs.len());
+        for spec in self.jobs {
+            let existing = find_by_name(&existing_jobs, &spec.name);
+            let name = spec.name.clone();
+            changes.push((
+                name,
+                plan_change(spec, &self.cwd, &namespace, env_vars.clone(), existing),
+            ));
+        }
+
+        if self.diff {
+            let mut jobs = Vec::with_capacity(changes.len());
+            for (name, change) in changes {
+                match change {
+                    Ok(change) => jobs.push(JobDiff::new(name, &change)),
+                    Err(err) => {
+                        channel
+                            .write_output(&format!("invalid job {name}: {err}\n"))
+                            .await?;
+                        return Err(err.context("apply"));
+                    }
+                }
+            }
+            channel.write_response(DiffResponse { jobs }).await?;
+            return Ok(());
+        }
+
+        let mut report = BatchReport::default();
+        for (name, change) in changes {
+            let change = match change {
+                Ok(change) => change,
+                Err(err) => {
+                    report
+                        .failed(channel, &name, format!("invalid job {name}: {err}"))
+                        .await?;
+                    continue;
+                }
+            };
+            match change {
+                Change::Create(desc) => match ctx.job_mgr_handle.add_job(*desc).await {
+                    Ok(_) => {
+                        report
+                            .succeeded(channel, &name, format!("created job {name}"))
+                            .await?;
+                    }
+                    Err(err) => {
+                        report
+                            .failed(
+                                channel,
+                                &name,
+                                format!("failed to create job {name}: {err}"),
+                            )
+                            .await?;
+                    }
+                },
+                Change::Update(job, desc) => {
+                    if let Err(err) = ctx.job_mgr_handle.update_job(job.id(), *desc).await {
+                        report
+                            .failed(
+                                channel,
+                                &name,
+                                format!("failed to update job {name}: {err}"),
+                            )
+                            .await?;
+                        continue;
+                    }
+                    let note = if job.pid().is_some() {
+                        " (restart it to take effect)"
+                    } else {
+                        ""
+                    };
+                    report
+                        .succeeded(channel, &name, format!("updated job {name}{note}"))
+                        .await?;
+                }
+                Change::Unchanged(_) => {
+                    report
+                        .skipped(channel, &name, format!("job {name} is unchanged"))
+                        .await?;
+                }
+            }
+        }
+
+        if report.succeeded_count() > 0 {
+            channel
+                .write_output("run `petri up` to start the jobs\n")
+                .await?;
+        }
+        report
+            .finish(channel)
+            .await
+            .map_err(|err| err.context("apply"))
+    }
+}
+
+/// Checks the jobs and their dependencies, including the existing jobs
+/// they depend on, returning all the problems found.
+async fn validate_jobs(
+    ctx: &ControlContext,
+    specs: Vec<JobSpec>,
+    cwd: &str,
+    namespace: &str,
+    env_vars: HashMap<OsString, OsString>,
+) -> Vec<Diagnostic> {
+    let existing_jobs = ctx.job_mgr_handle.jobs().await;
+    let names: Vec<_> = specs.iter().map(|spec| spec.name.clone()).collect();
+    // Dependencies are referred to by the names of jobs, or their ids.
+    let resolve = |dep: &str| {
+        if names.iter().any(|name| name == dep) {
+            return Some(dep.to_owned());
+        }
+        existing_jobs
+            .iter()
+            .find(|job| job.id() == dep || job.description().name.as_deref() == Some(dep))
+            .map(|job| job.display_name().to_owned())
+    };
+
+    let mut graph = BTreeMap::new();
+    for job in &existing_jobs {
+        let deps = job
+            .description()
+            .depends_on
+            .iter()
+            .filter_map(|dep| resolve(dep))
+            .collect();
+        graph.insert(job.display_name().to_owned(), deps);
+    }
+
+    let mut diagnostics = vec![];
+    for spec in specs {
+        let name = spec.name.clone();
+        let existing = find_by_name(&existing_jobs, &name);
+        let change = match plan_change(spec, cwd, namespace, env_vars.clone(), existing) {
+            Ok(change) => change,
+            Err(err) => {
+                let message = err.to_string();
+                diagnostics.push(Diagnostic::new(&name, Problem::Invalid { message }));
+                continue;
+            }
+        };
+        let desc = match &change {
+            Change::Create(desc) | Change::Update(_, desc) => desc,
+            Change::Unchanged(job) => job.description(),
+        };
+
+        for problem in check_job(desc) {
+            diagnostics.push(Diagnostic::new(&name, problem));
+        }
+        let mut deps = vec![];
+        for dep in &desc.depends_on {
+            match resolve(dep) {
+                Some(dep) => deps.push(dep),
+                None => {
+                    let dependency = dep.clone();
+                    diagnostics.push(Diagnostic::new(
+                        &name,
+                        Problem::UnknownDependency { dependency },
+                    ));
+                }
+            }
+        }
+        graph.insert(name, deps);
+    }
+
+    // Cycles only among the existing jobs are not caused by the file.
+    for cycle in find_cycles(&graph) {
+        if cycle.iter().any(|job| names.contains(job)) {
+            let job = cycle[0].clone();
+            diagnostics.push(Diagnostic::new(&job, Problem::DependencyCycle { cycle }));
+        }
+    }
+    diagnostics
+}
+
+/// Returns the job with exactly the name. Ids and their prefixes are not
+/// matched, so a job in the file never replaces one with another name.
+fn find_by_name(jobs: &[Job], name: &str) -> Option<Job> {
+    jobs.iter()
+        .find(|job| job.description().name.as_deref() == Some(name))
+        .cloned()
+}
+
+/// Returns what applying the job does to the existing one with the same
+/// name, if any.
+fn plan_change(
+    spec: JobSpec,
+    cwd: &str,
+    namespace: &str,
+    mut env: HashMap<OsString, OsString>,
+    existing: Option<Job>,
+) -> Result<Change> {
+    env.extend(
+        spec.env
+            .into_iter()
+            .map(|(name, value)| (name.into(), value.into())),
+    );
+    let mut cmd_line = spec.cmd_line.into_iter();
+    // Files are parsed by the client, which may be of another version.
+    let Some(program) = cmd_line.next() else {
+        return Err(anyhow!("the command must not be empty"));
+    };
+    let args: Vec<_> = cmd_line.collect();
+    let args = (!args.is_empty()).then_some(args);
+
+    let Some(job) = existing else {
+        let start_info = StartInfo::builder(program, cwd.to_owned())
+            .args(args.unwrap_or_default())
+            .envs(env)
+            .build()?;
+        let desc = JobDescription::builder(start_info)
+            .name(spec.name)
+            .namespace(namespace)
+            .depends_on(spec.depends_on)
+            .build();
+        return Ok(Change::Create(Box::new(desc)));
+    };
+
+    // Keep the settings that the file doesn't have, like labels and log
+    // files.
+    let mut desc = job.description().clone();
+    let start_info = &desc.start_info;
+    if start_info.program == program
+        && start_info.args == args
+        && start_info.cwd == cwd
+        && start_info.env == env
+        && desc.depends_on == spec.depends_on
+        && desc.namespace == namespace
+    {
+        return Ok(Change::Unchanged(Box::new(job)));
+    }
+
+    desc.start_info.program = program;
+    desc.start_info.args = args;
+    desc.start_info.cwd = cwd.to_owned();
+    desc.start_info.env = env;
+    desc.depends_on = spec.depends_on;
+    desc.namespace = namespace.to_owned();
+    Ok(Change::Update(Box::new(job), Box::new(desc)))
+}
+
+impl JobDiff {
+    fn new(name: String, change: &Change) -> Self {
+        let render = |desc| JobFile::from_description(desc).to_toml();
+        let (kind, hunks) = match change {
+            Change::Create(desc) => (ChangeKind::Create, diff("", &render(desc), DIFF_CONTEXT)),
+            Change::Update(job, desc) => (
+                ChangeKind::Update,
+                diff(&render(job.description()), &render(desc), DIFF_CONTEXT),
+            ),
+            Change::Unchanged(_) => (ChangeKind::Unchanged, vec![]),
+        };
+        Self { name, kind, hunks }
+    }
+}
+
+impl CommandClient for ApplySubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        if self.validate_only {
+            return Some(Box::new(ValidateResponseHandler { exit_status: 0 }));
+        }
+        if !self.diff {
+            return Some(Box::new(StreamHandler::new(BatchConsumer)));
+        }
+        let color = std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+        Some(Box::new(DiffResponseHandler { color }))
+    }
+
+    fn prepare(&mut self) -> Result<()> {
+        let Some(format) = self.format.or_else(|| ApplyFormat::infer(&self.file)) else {
+            return Err(anyhow!(
+                "can't tell the format of `{}`, specify it with `--format`",
+                self.file.display()
+            ));
+        };
+        let path = fs::canonicalize(&self.file)
+            .map_err(|err| anyhow!("failed to read `{}`: {err}", self.file.display()))?;
+        let content = fs::read_to_string(&path)
+            .map_err(|err| anyhow!("failed to read `{}`: {err}", self.file.display()))?;
+
+        self.jobs = match format {
+            ApplyFormat::Procfile => procfile::parse(&content),
+            ApplyFormat::Compose => compose::parse(&content),
+        }
+        .map_err(|err| err.context(format!("failed to parse `{}`", self.file.display())))?;
+        if self.jobs.is_empty() {
+            return Err(anyhow!("no jobs are found in `{}`", self.file.display()));
+        }
+
+        let dir = path
+            .parent()
+            .expect("a file should have a parent directory");
+        let Some(cwd) = dir.to_str() else {
+            return Err(anyhow!("path `{}` is not valid UTF-8", dir.display()));
+        };
+        self.cwd = cwd.to_owned();
+        Ok(())
+    }
+}
+
+struct DiffResponseHandler {
+    color: bool,
+}
+
+impl DiffResponseHandler {
+    fn paint(&self, text: &str, style: &str) -> String {
+        if self.color {
+            format!("\x1b[{style}m{text}\x1b[0m")
+        } else {
+            text.to_owned()
+        }
+    }
+}
+
+#[async_trait]
+impl ResponseHandler for DiffResponseHandler {
+    async fn handle_response(
+        &mut self,
+        resp: OwnedIpcMessagePacket<serde_json::Value>,
+    ) -> Result<()> {
+        let resp: DiffResponse = resp.into_response().expect("expected a response")?;
+
+        let (mut created, mut updated, mut unchanged) = (0, 0, 0);
+        for job in &resp.jobs {
+            let header = match job.kind {
+                ChangeKind::Create => {
+                    created += 1;
+                    format!("job {} will be created", job.name)
+                }
+                ChangeKind::Update => {
+                    updated += 1;
+                    format!("job {} will be updated", job.name)
+                }
+                ChangeKind::Unchanged => {
+                    unchanged += 1;
+                    continue;
+                }
+            };
+            println!("{}", self.paint(&header, "1"));
+            for hunk in &job.hunks {
+                println!("{}", self.paint(&hunk.header(), "36"));
+                for line in &hunk.lines {
+                    let text = format!("{}{}", line.prefix(), line.text);
+                    match line.change {
+                        LineChange::Kept => println!("{text}"),
+                        LineChange::Removed => println!("{}", self.paint(&text, "31")),
+                        LineChange::Added => println!("{}", self.paint(&text, "32")),
+                    }
+                }
+            }
+            println!();
+        }
+
+        if created + updated == 0 {
+            println!("no changes ({unchanged} jobs unchanged)");
+        } else {
+            println!("{created} to create, {updated} to update, {unchanged} unchanged");
+        }
+        Ok(())
+    }
+}
+
+struct ValidateResponseHandler {
+    exit_status: i32,
+}
+
+#[async_trait]
+impl ResponseHandler for ValidateResponseHandler {
+    async fn handle_response(
+        &mut self,
+        resp: OwnedIpcMessagePacket<serde_json::Value>,
+    ) -> Result<()> {
+        let resp: ValidateResponse = resp.into_response().expect("expected a response")?;
+
+        for diagnostic in &resp.diagnostics {
+            println!("{}: {}", diagnostic.job, diagnostic.problem);
+        }
+        if resp.diagnostics.is_empty() {
+            println!("{} jobs are valid", resp.jobs);
+        } else {
+            println!("found {} problems", resp.diagnostics.len());
+            self.exit_status = 1;
+        }
+        Ok(())
+    }
+
+    fn exit_status(&self) -> i32 {
+        self.exit_status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{plan_change, Change, JobSpec};
+
+    #[test]
+    fn test_plan_change() {
+        let spec = |cmd_line: &[&str]| JobSpec {
+            name: "web".to_owned(),
+            cmd_line: cmd_line.iter().map(|arg| arg.to_string()).collect(),
+            env: vec![],
+            depends_on: vec![],
+        };
+
+        let change = plan_change(spec(&["sleep", "60"]), "/", "default", HashMap::new(), None);
+        let Ok(Change::Create(desc)) = change else {
+            panic!("the job should be created");
+        };
+        assert_eq!(desc.start_info.program, "sleep");
+        assert_eq!(desc.start_info.args, Some(vec!["60".to_owned()]));
+
+        let Err(err) = plan_change(spec(&[]), "/", "default", HashMap::new(), None) else {
+            panic!("an empty command should be rejected");
+        };
+        assert_eq!(err.to_string(), "the command must not be empty");
+    }
+}
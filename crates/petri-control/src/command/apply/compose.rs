@@ -0,0 +1,213 @@
+use anyhow::Result;
+use serde_norway::Value;
+
+use super::JobSpec;
+
+/// Converts the services of a compose file. Only `command`, `environment`
+/// and `depends_on` are used, other keys like `image` are ignored.
+pub(super) fn parse(s: &str) -> Result<Vec<JobSpec>> {
+    let mut doc: Value = serde_norway::from_str(s).map_err(|err| anyhow!("invalid YAML: {err}"))?;
+    // Compose files often share settings with anchors and `<<` keys.
+    doc.apply_merge()
+        .map_err(|err| anyhow!("invalid YAML: {err}"))?;
+    let Some(Value::Mapping(services)) = doc.get("services") else {
+        return Err(anyhow!("`services` is missing or not a mapping"));
+    };
+
+    let mut jobs = vec![];
+    for (name, service) in services {
+        let name = scalar(name, "services")?;
+        let spec = convert_service(&name, service)
+            .map_err(|err| err.context(format!("invalid service `{name}`")))?;
+        jobs.push(spec);
+    }
+
+    for job in &jobs {
+        if let Some(dep) = job
+            .depends_on
+            .iter()
+            .find(|dep| !jobs.iter().any(|job| job.name == **dep))
+        {
+            return Err(anyhow!(
+                "service `{}` depends on `{dep}`, which is not defined",
+                job.name
+            ));
+        }
+    }
+    Ok(jobs)
+}
+
+fn convert_service(name: &str, service: &Value) -> Result<JobSpec> {
+    let cmd_line = match service.get("command") {
+        Some(Value::Sequence(items)) => items
+            .iter()
+            .map(|item| scalar(item, "command"))
+            .collect::<Result<_>>()?,
+        Some(Value::Null) | None => {
+            return Err(anyhow!(
+                "`command` is required, images can't be run without a container"
+            ))
+        }
+        Some(command) => {
+            let command = scalar(command, "command")
+                .map_err(|_| anyhow!("`command` must be a string or a list"))?;
+            match shlex::split(&command) {
+                Some(cmd_line) => cmd_line,
+                None => return Err(anyhow!("invalid quoting in `command`")),
+            }
+        }
+    };
+    if cmd_line.is_empty() {
+        return Err(anyhow!("`command` must not be empty"));
+    }
+
+    let env = match service.get("environment") {
+        Some(Value::Mapping(entries)) => entries
+            .iter()
+            .map(|(key, value)| {
+                let key = scalar(key, "environment")?;
+                match value {
+                    // Unlike compose, the value is not taken from the shell.
+                    Value::Null => Ok((key, String::new())),
+                    value => Ok((key, scalar(value, "environment")?)),
+                }
+            })
+            .collect::<Result<_>>()?,
+        Some(Value::Sequence(items)) => {
+            let mut env = vec![];
+            for item in items {
+                let item = scalar(item, "environment")?;
+                // A bare `KEY` passes the variable from the shell, which the
+                // job gets from the client env anyway.
+                if let Some((key, value)) = item.split_once('=') {
+                    env.push((key.to_owned(), value.to_owned()));
+                }
+            }
+            env
+        }
+        Some(Value::Null) | None => vec![],
+        Some(_) => return Err(anyhow!("`environment` must be a mapping or a list")),
+    };
+
+    let depends_on = match service.get("depends_on") {
+        Some(Value::Sequence(items)) => items
+            .iter()
+            .map(|item| scalar(item, "depends_on"))
+            .collect::<Result<_>>()?,
+        // The long syntax, conditions are not supported.
+        Some(Value::Mapping(entries)) => entries
+            .keys()
+            .map(|key| scalar(key, "depends_on"))
+            .collect::<Result<_>>()?,
+        Some(Value::Null) | None => vec![],
+        Some(_) => return Err(anyhow!("`depends_on` must be a list or a mapping")),
+    };
+
+    Ok(JobSpec {
+        name: name.to_owned(),
+        cmd_line,
+        env,
+        depends_on,
+    })
+}
+
+/// Returns the scalar as it's written, like `8000` and `true`.
+fn scalar(value: &Value, key: &str) -> Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err(anyhow!("items of `{key}` must be strings")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn test_parse() {
+        let jobs = parse(
+            r#"
+services:
+  db:
+    image: postgres
+    command: postgres -c 'max_connections=200'
+  web:
+    command: ["python", "-m", "http.server", "8000"]
+    environment:
+      DEBUG: "1"
+      EMPTY:
+    depends_on:
+      db:
+        condition: service_healthy
+  worker:
+    command: ./worker
+    environment:
+      - QUEUE=default
+      - HOME
+    depends_on: [db, web]
+"#,
+        )
+        .unwrap();
+        assert_eq!(jobs.len(), 3);
+
+        assert_eq!(jobs[0].name, "db");
+        assert_eq!(jobs[0].cmd_line, ["postgres", "-c", "max_connections=200"]);
+        assert!(jobs[0].env.is_empty());
+
+        assert_eq!(jobs[1].cmd_line, ["python", "-m", "http.server", "8000"]);
+        assert_eq!(
+            jobs[1].env,
+            [
+                ("DEBUG".to_owned(), "1".to_owned()),
+                ("EMPTY".to_owned(), String::new())
+            ]
+        );
+        assert_eq!(jobs[1].depends_on, ["db"]);
+
+        assert_eq!(jobs[2].env, [("QUEUE".to_owned(), "default".to_owned())]);
+        assert_eq!(jobs[2].depends_on, ["db", "web"]);
+    }
+
+    #[test]
+    fn test_parse_merge_keys() {
+        let jobs = parse(
+            r#"
+x-env: &env
+  LEVEL: info
+services:
+  web:
+    command: ./web
+    environment:
+      <<: *env
+      PORT: 8000
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            jobs[0].env,
+            [
+                ("PORT".to_owned(), "8000".to_owned()),
+                ("LEVEL".to_owned(), "info".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |s| format!("{:#}", parse(s).unwrap_err());
+        assert_eq!(
+            error("version: '3'"),
+            "`services` is missing or not a mapping"
+        );
+        assert_eq!(
+            error("services:\n  db:\n    image: postgres"),
+            "invalid service `db`: `command` is required, images can't be run without a container"
+        );
+        assert_eq!(
+            error("services:\n  web:\n    command: a\n    depends_on: [db]"),
+            "service `web` depends on `db`, which is not defined"
+        );
+    }
+}
@@ -0,0 +1,72 @@
+use anyhow::Result;
+
+use super::JobSpec;
+
+/// Converts the `name: command` lines of a Procfile. Commands are run by
+/// `sh`, like foreman and Heroku do.
+pub(super) fn parse(s: &str) -> Result<Vec<JobSpec>> {
+    let mut jobs: Vec<JobSpec> = vec![];
+    for (idx, line) in s.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let number = idx + 1;
+        let Some((name, command)) = line.split_once(':') else {
+            return Err(anyhow!("line {number}: expected `name: command`"));
+        };
+        let (name, command) = (name.trim(), command.trim());
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(anyhow!("line {number}: invalid process type `{name}`"));
+        }
+        if command.is_empty() {
+            return Err(anyhow!("line {number}: command of `{name}` is empty"));
+        }
+        if jobs.iter().any(|job| job.name == name) {
+            return Err(anyhow!("line {number}: duplicate process type `{name}`"));
+        }
+
+        jobs.push(JobSpec {
+            name: name.to_owned(),
+            cmd_line: vec!["sh".to_owned(), "-c".to_owned(), command.to_owned()],
+            env: vec![],
+            depends_on: vec![],
+        });
+    }
+    Ok(jobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn test_parse() {
+        let jobs = parse(
+            "# Processes of the app.\n\
+             web: bundle exec puma -p $PORT\n\
+             \n\
+             worker_1:bin/worker --queue a:b\n",
+        )
+        .unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].name, "web");
+        assert_eq!(jobs[0].cmd_line, ["sh", "-c", "bundle exec puma -p $PORT"]);
+        assert_eq!(jobs[1].name, "worker_1");
+        assert_eq!(jobs[1].cmd_line, ["sh", "-c", "bin/worker --queue a:b"]);
+
+        let error = |s| parse(s).unwrap_err().to_string();
+        assert_eq!(error("web"), "line 1: expected `name: command`");
+        assert_eq!(error("a b: c"), "line 1: invalid process type `a b`");
+        assert_eq!(error("web:"), "line 1: command of `web` is empty");
+        assert_eq!(
+            error("web: a\nweb: b"),
+            "line 2: duplicate process type `web`"
+        );
+    }
+}
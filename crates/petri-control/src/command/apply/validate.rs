@@ -0,0 +1,208 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::ffi::OsStr;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use petri_core::container::Runtime;
+use petri_core::job_mgr::JobDescription;
+use serde::{Deserialize, Serialize};
+
+/// A problem found in a job of the file.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub(super) struct Diagnostic {
+    pub(super) job: String,
+    pub(super) problem: Problem,
+}
+
+/// A problem of a job that would fail it when it's applied or started.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub(super) enum Problem {
+    /// The job can't be converted, like having an invalid variable.
+    Invalid {
+        message: String,
+    },
+    ProgramNotFound {
+        program: String,
+    },
+    CwdNotFound {
+        cwd: String,
+    },
+    UnknownDependency {
+        dependency: String,
+    },
+    /// The jobs depend on each other, starting and ending with the job.
+    DependencyCycle {
+        cycle: Vec<String>,
+    },
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Invalid { message } => f.write_str(message),
+            Problem::ProgramNotFound { program } if program.contains('/') => {
+                write!(f, "program `{program}` is not found or not executable")
+            }
+            Problem::ProgramNotFound { program } => {
+                write!(f, "program `{program}` is not found in PATH")
+            }
+            Problem::CwdNotFound { cwd } => write!(f, "directory `{cwd}` is not found"),
+            Problem::UnknownDependency { dependency } => {
+                write!(f, "depends on `{dependency}`, which is not defined")
+            }
+            Problem::DependencyCycle { cycle } => {
+                write!(f, "dependency cycle: {}", cycle.join(" -> "))
+            }
+        }
+    }
+}
+
+impl Diagnostic {
+    pub(super) fn new(job: &str, problem: Problem) -> Self {
+        Self {
+            job: job.to_owned(),
+            problem,
+        }
+    }
+}
+
+/// Checks what the job needs on the server to start, returning all the
+/// problems found.
+pub(super) fn check_job(desc: &JobDescription) -> Vec<Problem> {
+    let start_info = &desc.start_info;
+    let mut problems = vec![];
+
+    let cwd = Path::new(&start_info.cwd);
+    if !cwd.is_dir() {
+        problems.push(Problem::CwdNotFound {
+            cwd: start_info.cwd.clone(),
+        });
+    }
+    // Containers run programs of their images.
+    if matches!(start_info.runtime, Runtime::Native) {
+        let path = start_info
+            .env
+            .get(OsStr::new("PATH"))
+            .cloned()
+            .or_else(|| env::var_os("PATH"))
+            .unwrap_or_default();
+        if !find_program(&start_info.program, cwd, &path) {
+            problems.push(Problem::ProgramNotFound {
+                program: start_info.program.clone(),
+            });
+        }
+    }
+    problems
+}
+
+/// Returns whether the program can be executed, looking it up in `path`
+/// like the shell does if it has no slashes.
+fn find_program(program: &str, cwd: &Path, path: impl AsRef<OsStr>) -> bool {
+    let is_executable = |path: &Path| {
+        fs::metadata(path)
+            .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    };
+    if program.contains('/') {
+        return is_executable(&cwd.join(program));
+    }
+    env::split_paths(&path)
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .any(|dir| is_executable(&dir.join(program)))
+}
+
+/// Returns the dependency cycles in the graph of jobs, each starting and
+/// ending with the same job. Dependencies not in the graph are ignored.
+pub(super) fn find_cycles(graph: &BTreeMap<String, Vec<String>>) -> Vec<Vec<String>> {
+    enum Mark {
+        Visiting,
+        Visited,
+    }
+
+    fn visit<'a>(
+        name: &'a str,
+        graph: &'a BTreeMap<String, Vec<String>>,
+        marks: &mut HashMap<&'a str, Mark>,
+        stack: &mut Vec<&'a str>,
+        cycles: &mut Vec<Vec<String>>,
+    ) {
+        match marks.get(name) {
+            Some(Mark::Visited) => return,
+            Some(Mark::Visiting) => {
+                let start = stack
+                    .iter()
+                    .position(|visiting| *visiting == name)
+                    .expect("visiting jobs should be on the stack");
+                let mut cycle: Vec<_> = stack[start..].iter().map(|&n| n.to_owned()).collect();
+                cycle.push(name.to_owned());
+                cycles.push(cycle);
+                return;
+            }
+            None => {}
+        }
+
+        marks.insert(name, Mark::Visiting);
+        stack.push(name);
+        for dep in graph.get(name).into_iter().flatten() {
+            if graph.contains_key(dep) {
+                visit(dep, graph, marks, stack, cycles);
+            }
+        }
+        stack.pop();
+        marks.insert(name, Mark::Visited);
+    }
+
+    let mut marks = HashMap::new();
+    let mut cycles = vec![];
+    for name in graph.keys() {
+        visit(name, graph, &mut marks, &mut vec![], &mut cycles);
+    }
+    cycles
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    use super::{find_cycles, find_program};
+
+    #[test]
+    fn test_find_cycles() {
+        let graph = |edges: &[(&str, &[&str])]| -> BTreeMap<String, Vec<String>> {
+            edges
+                .iter()
+                .map(|(name, deps)| {
+                    let deps = deps.iter().map(|dep| dep.to_string()).collect();
+                    (name.to_string(), deps)
+                })
+                .collect()
+        };
+
+        let acyclic = graph(&[("web", &["api", "db"]), ("api", &["db"]), ("db", &["ext"])]);
+        assert!(find_cycles(&acyclic).is_empty());
+
+        let cyclic = graph(&[
+            ("api", &["db"]),
+            ("db", &["web"]),
+            ("web", &["api"]),
+            ("worker", &["worker"]),
+        ]);
+        assert_eq!(
+            find_cycles(&cyclic),
+            [vec!["api", "db", "web", "api"], vec!["worker", "worker"]]
+        );
+    }
+
+    #[test]
+    fn test_find_program() {
+        let cwd = Path::new("/");
+        assert!(find_program("sh", cwd, "/nonexistent:/bin"));
+        assert!(!find_program("sh", cwd, "/nonexistent"));
+        assert!(find_program("bin/sh", cwd, ""));
+        assert!(!find_program("/etc/passwd", cwd, ""));
+    }
+}
@@ -0,0 +1,145 @@
+//! Results of commands that act on many jobs or processes, like `up` and
+//! `stop --all`. They are streamed item by item with a summary at the end,
+//! and the client exits with 1 if any item failed.
+
+use std::fmt::{self, Display, Formatter};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::{IpcChannel, ResponseStream, StreamConsumer};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Succeeded,
+    Failed,
+    /// Nothing is done, like starting a job that is already running.
+    Skipped,
+}
+
+/// The result of an item, like a job started by `up`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchItem {
+    pub name: String,
+    pub outcome: Outcome,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BatchSummary {
+    pub succeeded: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
+
+/// Sends the results of the items to the client, and counts them for the
+/// summary.
+#[derive(Default)]
+pub(super) struct BatchReport {
+    summary: BatchSummary,
+}
+
+/// Prints the results of a [`BatchReport`] on the client.
+pub(super) struct BatchConsumer;
+
+impl Display for BatchSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} succeeded, {} failed, {} skipped",
+            self.succeeded, self.failed, self.skipped
+        )
+    }
+}
+
+impl BatchReport {
+    pub(super) async fn succeeded(
+        &mut self,
+        channel: &mut IpcChannel,
+        name: &str,
+        message: String,
+    ) -> Result<()> {
+        self.summary.succeeded += 1;
+        self.send(channel, name, Outcome::Succeeded, message).await
+    }
+
+    pub(super) async fn failed(
+        &mut self,
+        channel: &mut IpcChannel,
+        name: &str,
+        message: String,
+    ) -> Result<()> {
+        self.summary.failed += 1;
+        self.send(channel, name, Outcome::Failed, message).await
+    }
+
+    pub(super) async fn skipped(
+        &mut self,
+        channel: &mut IpcChannel,
+        name: &str,
+        message: String,
+    ) -> Result<()> {
+        self.summary.skipped += 1;
+        self.send(channel, name, Outcome::Skipped, message).await
+    }
+
+    pub(super) fn succeeded_count(&self) -> usize {
+        self.summary.succeeded
+    }
+
+    /// Sends the summary. The command fails if any item failed.
+    pub(super) async fn finish(self, channel: &mut IpcChannel) -> Result<()> {
+        let summary = self.summary;
+        ResponseStream::<BatchItem, BatchSummary>::new(channel)
+            .finish(Ok(summary))
+            .await?;
+        if summary.failed > 0 {
+            let total = summary.succeeded + summary.failed + summary.skipped;
+            return Err(anyhow!("{} of {total} failed", summary.failed));
+        }
+        Ok(())
+    }
+
+    async fn send(
+        &self,
+        channel: &mut IpcChannel,
+        name: &str,
+        outcome: Outcome,
+        message: String,
+    ) -> Result<()> {
+        let item = BatchItem {
+            name: name.to_owned(),
+            outcome,
+            message,
+        };
+        ResponseStream::<BatchItem, BatchSummary>::new(channel)
+            .send_item(item)
+            .await?;
+        Ok(())
+    }
+}
+
+impl StreamConsumer for BatchConsumer {
+    type Item = BatchItem;
+    type Result = BatchSummary;
+
+    fn on_item(&mut self, item: BatchItem) -> Result<()> {
+        println!("{}", item.message);
+        Ok(())
+    }
+
+    fn on_finish(&mut self, result: Result<BatchSummary, String>) -> Result<i32> {
+        let summary = match result {
+            Ok(summary) => summary,
+            Err(reason) => {
+                eprintln!("{reason}");
+                return Ok(1);
+            }
+        };
+        if summary != BatchSummary::default() {
+            println!("{summary}");
+        }
+        Ok((summary.failed > 0) as i32)
+    }
+}
@@ -0,0 +1,242 @@
+//! Measures the output path of the server (pipe read, `LogBuffer`,
+//! subscribers and the log file) with a synthetic chatty child, which is
+//! `petri` itself run with [`BENCH_CHILD_ARG`].
+//!
+//! Each line of the child starts with the wall time it's written at, so
+//! the latency is how long the line took to reach a subscriber.
+
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use clap::Args;
+use petri_core::process::{LogOptions, StartInfo};
+use petri_utils::{parse_bytes, FormattedBytes};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::time;
+
+use super::run::parse_read_buffer_size;
+use super::{wait_spawn_slot, CommandClient, IpcChannel, OwnedIpcMessagePacket, ResponseHandler};
+use crate::Context as ControlContext;
+
+/// The argument that runs `petri` as the child of `bench`, followed by the
+/// number of bytes to write and the length of lines.
+pub const BENCH_CHILD_ARG: &str = "--bench-child";
+
+/// The width of the timestamp that lines start with.
+const TIMESTAMP_LEN: usize = 20;
+
+/// How long to wait for the rest of the output after the child exits.
+const EXIT_GRACE: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BenchResponse {
+    exit_code: i32,
+    bytes: u64,
+    lines: u64,
+    elapsed_us: u64,
+    /// Percentiles of the latency of lines, `None` if no line is received.
+    latency_us: Option<Latency>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Latency {
+    p50: u64,
+    p99: u64,
+    max: u64,
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct BenchSubcommand {
+    /// How much output the child writes (e.g. `64M`).
+    #[arg(long, value_parser = parse_bytes, default_value = "64M")]
+    bytes: u64,
+    /// The length of each line, including the newline.
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u64).range(TIMESTAMP_LEN as u64 + 1..),
+        default_value = "128"
+    )]
+    line_size: u64,
+    /// Don't write the output to a log file, to measure the rest of the path.
+    #[arg(long)]
+    no_log: bool,
+    /// The size of the buffer that the output is read into at first.
+    #[arg(long, value_parser = parse_read_buffer_size)]
+    read_buffer_size: Option<usize>,
+}
+
+impl BenchSubcommand {
+    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        let program = env::current_exe()?;
+        let log_dir = env::temp_dir().join(format!("petri-bench-{}", std::process::id()));
+        let log = LogOptions {
+            path: (!self.no_log).then(|| log_dir.clone()),
+            ..Default::default()
+        };
+        let start_info = StartInfo::builder(program.to_string_lossy(), "/")
+            .args([
+                BENCH_CHILD_ARG.to_owned(),
+                self.bytes.to_string(),
+                self.line_size.to_string(),
+            ])
+            .log(log)
+            .read_buffer_size(self.read_buffer_size)
+            .build()?;
+
+        let permit = wait_spawn_slot(ctx, channel).await?;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (process, subscription) = match ctx
+            .proc_mgr_handle
+            .add_attached_process_with_permit(&start_info, permit, tx)
+            .await
+        {
+            Ok(attached) => attached,
+            Err(err) => {
+                channel
+                    .write_output(&format!("failed to start the child: {err}\n"))
+                    .await?;
+                return Err(err.context("bench"));
+            }
+        };
+
+        let started_at = Instant::now();
+        let mut received = 0;
+        let mut latencies = vec![];
+        let mut pending = vec![];
+        let exit = process.wait();
+        tokio::pin!(exit);
+        let mut exit_code = None;
+        while received < self.bytes {
+            let chunk = tokio::select! {
+                chunk = rx.recv() => chunk,
+                code = &mut exit, if exit_code.is_none() => {
+                    exit_code = Some(code);
+                    continue;
+                }
+                _ = time::sleep(EXIT_GRACE), if exit_code.is_some() => None,
+            };
+            let Some(chunk) = chunk else {
+                break;
+            };
+            let now = unix_micros();
+            received += chunk.len() as u64;
+            pending.extend_from_slice(&chunk);
+            let mut start = 0;
+            while let Some(len) = pending[start..].iter().position(|b| *b == b'\n') {
+                let line = &pending[start..start + len];
+                if let Some(written_at) = line
+                    .get(..TIMESTAMP_LEN)
+                    .and_then(|ts| std::str::from_utf8(ts).ok())
+                    .and_then(|ts| ts.parse::<u64>().ok())
+                {
+                    latencies.push(now.saturating_sub(written_at));
+                }
+                start += len + 1;
+            }
+            pending.drain(..start);
+        }
+        let elapsed = started_at.elapsed();
+        drop(subscription);
+        let exit_code = match exit_code {
+            Some(code) => code,
+            None => exit.await,
+        };
+        if !self.no_log {
+            _ = fs::remove_dir_all(&log_dir);
+        }
+
+        latencies.sort_unstable();
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        let resp = BenchResponse {
+            exit_code,
+            bytes: received,
+            lines: latencies.len() as u64,
+            elapsed_us: elapsed.as_micros() as u64,
+            latency_us: (!latencies.is_empty()).then(|| Latency {
+                p50: percentile(50),
+                p99: percentile(99),
+                max: percentile(100),
+            }),
+        };
+        channel.write_response(resp).await?;
+        Ok(())
+    }
+}
+
+impl CommandClient for BenchSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        Some(Box::new(BenchResponseHandler))
+    }
+}
+
+struct BenchResponseHandler;
+
+#[async_trait]
+impl ResponseHandler for BenchResponseHandler {
+    async fn handle_response(
+        &mut self,
+        resp: OwnedIpcMessagePacket<serde_json::Value>,
+    ) -> Result<()> {
+        let resp: BenchResponse = resp.into_response().expect("expected a response")?;
+        if resp.exit_code != 0 {
+            println!("the child exited with code {}", resp.exit_code);
+        }
+
+        let secs = resp.elapsed_us as f64 / 1_000_000.0;
+        let per_sec = (resp.bytes as f64 / secs.max(f64::EPSILON)) as u64;
+        println!(
+            "received {} in {secs:.3}s ({}/s), {} lines",
+            FormattedBytes::new(resp.bytes),
+            FormattedBytes::new(per_sec),
+            resp.lines
+        );
+        if let Some(latency) = resp.latency_us {
+            println!(
+                "latency: p50 {}us, p99 {}us, max {}us",
+                latency.p50, latency.p99, latency.max
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Runs as the child of `bench` with the arguments after
+/// [`BENCH_CHILD_ARG`], writing lines that start with their wall time.
+pub fn run_bench_child(args: &[String]) -> io::Result<()> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid arguments");
+    let parse = |i: usize| -> io::Result<u64> {
+        let arg = args.get(i).ok_or_else(invalid)?;
+        arg.parse().map_err(|_| invalid())
+    };
+    let bytes = parse(0)?;
+    let line_size = parse(1)?.max(TIMESTAMP_LEN as u64 + 1);
+
+    // Standard output is line buffered, so every line is a write like the
+    // ones of chatty processes.
+    let mut stdout = io::stdout().lock();
+    let padding = "x".repeat(line_size as usize - TIMESTAMP_LEN - 1);
+    let mut written = 0;
+    while written < bytes {
+        let line = format!(
+            "{:0width$}{padding}\n",
+            unix_micros(),
+            width = TIMESTAMP_LEN
+        );
+        let len = (bytes - written).min(line_size) as usize;
+        stdout.write_all(&line.as_bytes()[..len])?;
+        written += len as u64;
+    }
+    stdout.flush()
+}
+
+fn unix_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
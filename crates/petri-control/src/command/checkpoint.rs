@@ -0,0 +1,222 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use clap::Args;
+use petri_core::checkpoint;
+use petri_utils::console_table::{self, ColumnCollection};
+use petri_utils::id::short_id;
+use serde::{Deserialize, Serialize};
+
+use super::{CommandClient, IpcChannel, OwnedIpcMessagePacket, ResponseHandler};
+use crate::checkpoint::{CheckpointInfo, CheckpointStore};
+use crate::Context as ControlContext;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CheckpointListResponse {
+    checkpoints: Vec<CheckpointInfo>,
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct CheckpointSubcommand {
+    /// Checkpoint the process with the given pid, along with its
+    /// descendants.
+    #[arg(required_unless_present_any = ["list", "remove"])]
+    pid: Option<u32>,
+    /// Keep the process running after the checkpoint, instead of stopping
+    /// it.
+    #[arg(long)]
+    leave_running: bool,
+    /// List the checkpoints.
+    #[arg(long, conflicts_with_all = ["pid", "leave_running", "remove"])]
+    list: bool,
+    /// Remove the checkpoint with the given id.
+    #[arg(long, value_name = "CHECKPOINT", conflicts_with_all = ["pid", "leave_running"])]
+    remove: Option<String>,
+}
+
+impl CheckpointSubcommand {
+    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        let Some(store) = &ctx.checkpoints else {
+            channel
+                .write_output("checkpoints are not enabled on the server\n")
+                .await?;
+            return Err(anyhow!("checkpoints are not enabled").context("checkpoint"));
+        };
+
+        if self.list {
+            let mut checkpoints = match store.list() {
+                Ok(checkpoints) => checkpoints,
+                Err(err) => {
+                    channel
+                        .write_output(&format!("failed to list the checkpoints: {err}\n"))
+                        .await?;
+                    return Err(anyhow!(err).context("checkpoint"));
+                }
+            };
+            for checkpoint in &mut checkpoints {
+                checkpoint.jid = checkpoint.jid.as_deref().map(short_id);
+            }
+            channel
+                .write_response(CheckpointListResponse { checkpoints })
+                .await?;
+            return Ok(());
+        }
+        if let Some(id) = &self.remove {
+            return remove(store, channel, id).await;
+        }
+
+        let pid = self.pid.expect("pid is required");
+        if let Err(err) = self.checkpoint(ctx, channel, store, pid).await {
+            channel
+                .write_output(&format!("failed to checkpoint the process: {err:#}\n"))
+                .await?;
+            return Err(err.context("checkpoint"));
+        }
+        Ok(())
+    }
+
+    async fn checkpoint(
+        &self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+        store: &CheckpointStore,
+        pid: u32,
+    ) -> Result<()> {
+        let Some(process) = ctx.proc_mgr_handle.process_with_id(pid).await else {
+            return Err(anyhow!("process with id `{pid}` is not found"));
+        };
+        let job = ctx
+            .job_mgr_handle
+            .jobs()
+            .await
+            .into_iter()
+            .find(|job| job.pid() == Some(pid));
+
+        let program = process.argv().first().map_or("", String::as_str);
+        let name = match &job {
+            Some(job) => job.display_name(),
+            None => Path::new(program)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or(program),
+        };
+        let now = Local::now();
+        let id = CheckpointInfo::new_id(name, &now);
+        let images_dir = store.images_dir(&id);
+
+        let cwd = fs::read_link(format!("/proc/{pid}/cwd"))
+            .map(|cwd| cwd.display().to_string())
+            .unwrap_or_else(|_| "/".to_owned());
+        let pipes = match checkpoint::dump(&process, &images_dir, self.leave_running).await {
+            Ok(pipes) => pipes,
+            Err(err) => {
+                // The error has the end of the log, which is all that's
+                // useful in the images of a failed dump.
+                _ = store.remove(&id);
+                return Err(err);
+            }
+        };
+        let info = CheckpointInfo {
+            id: id.clone(),
+            pid,
+            jid: job.as_ref().map(|job| job.id().to_owned()),
+            cmd: process.cmd().to_owned(),
+            cwd,
+            log_path: process.log_path().map(Path::to_owned),
+            stdout_pipe: pipes.stdout,
+            stderr_pipe: pipes.stderr,
+            left_running: self.leave_running,
+            created_at_ts: now.timestamp(),
+        };
+        store.save(&info)?;
+
+        let state = if self.leave_running {
+            "left running"
+        } else {
+            "stopped"
+        };
+        channel
+            .write_output(&format!(
+                "process {pid} checkpointed as `{id}` ({state}), restore it with `petri restore {id}`\n"
+            ))
+            .await?;
+        Ok(())
+    }
+}
+
+async fn remove(store: &CheckpointStore, channel: &mut IpcChannel, id: &str) -> Result<()> {
+    let result = store
+        .load(id)
+        .and_then(|_| store.remove(id).map_err(Into::into));
+    match result {
+        Ok(()) => {
+            channel
+                .write_output(&format!("checkpoint `{id}` removed\n"))
+                .await?;
+            Ok(())
+        }
+        Err(err) => {
+            channel
+                .write_output(&format!("failed to remove the checkpoint: {err:#}\n"))
+                .await?;
+            Err(err.context("checkpoint"))
+        }
+    }
+}
+
+impl CommandClient for CheckpointSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        if self.list {
+            return Some(Box::new(CheckpointListResponseHandler));
+        }
+        None
+    }
+}
+
+struct CheckpointListResponseHandler;
+
+#[async_trait]
+impl ResponseHandler for CheckpointListResponseHandler {
+    async fn handle_response(
+        &mut self,
+        resp: OwnedIpcMessagePacket<serde_json::Value>,
+    ) -> Result<()> {
+        let resp: CheckpointListResponse = resp.into_response().expect("expected a response")?;
+
+        let id_column = console_table::ColumnOptions::new("CHECKPOINT").spacing(2);
+        let created_column = console_table::ColumnOptions::new("CREATED").spacing(2);
+        let pid_column = console_table::ColumnOptions::new("PID")
+            .alignment(console_table::Alignment::Right)
+            .spacing(2);
+        let jid_column = console_table::ColumnOptions::new("JID").spacing(2);
+        let cmd_column = console_table::ColumnOptions::new("CMD");
+
+        let mut table_builder = (
+            id_column,
+            created_column,
+            pid_column,
+            jid_column,
+            cmd_column,
+        )
+            .into_table_builder();
+        for checkpoint in resp.checkpoints {
+            let created_at = DateTime::from_timestamp(checkpoint.created_at_ts, 0)
+                .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %T").to_string())
+                .unwrap_or_default();
+            table_builder.push_row(
+                checkpoint.id,
+                created_at,
+                checkpoint.pid.to_string(),
+                checkpoint.jid.unwrap_or_default(),
+                checkpoint.cmd,
+            );
+        }
+
+        println!("{table_builder}");
+
+        Ok(())
+    }
+}
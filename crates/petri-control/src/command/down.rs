@@ -0,0 +1,96 @@
+use anyhow::Result;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+
+use super::batch::{BatchConsumer, BatchReport};
+use super::namespace::NamespaceArgs;
+use super::up::select_jobs;
+use super::{parse_key_value, CommandClient, IpcChannel, ResponseHandler, StreamHandler};
+use crate::Context as ControlContext;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct DownSubcommand {
+    /// Select jobs having the label instead of autostart jobs.
+    #[arg(short = 'l', long = "selector", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    selector: Vec<(String, String)>,
+    #[command(flatten)]
+    namespace: NamespaceArgs,
+}
+
+impl DownSubcommand {
+    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        let jids: Vec<_> = select_jobs(ctx, &self.namespace, &self.selector)
+            .await
+            .iter()
+            .map(|job| job.id().to_owned())
+            .collect();
+        if jids.is_empty() {
+            channel.write_output("no jobs to stop\n").await?;
+            return BatchReport::default().finish(channel).await;
+        }
+
+        // Stop dependents before the jobs they depend on. Only the selected
+        // jobs are stopped, their dependencies are left running.
+        let order: Vec<_> = match ctx.job_mgr_handle.startup_order(&jids).await {
+            Ok(order) => order
+                .into_iter()
+                .rev()
+                .filter(|jid| jids.contains(jid))
+                .collect(),
+            Err(err) => {
+                channel
+                    .write_output(&format!(
+                        "failed to resolve dependencies ({err:#}), stopping in reverse creation order\n"
+                    ))
+                    .await?;
+                jids.into_iter().rev().collect()
+            }
+        };
+
+        let mut report = BatchReport::default();
+        for jid in &order {
+            let Some(job) = ctx.job_mgr_handle.job_with_id(jid).await else {
+                continue;
+            };
+            let name = job.display_name();
+            if job.pid().is_some() {
+                channel
+                    .write_output(&format!("stopping {name}...\n"))
+                    .await?;
+            }
+
+            match ctx.job_mgr_handle.stop_job(jid).await {
+                Ok(Some(exit_code)) => {
+                    report
+                        .succeeded(
+                            channel,
+                            name,
+                            format!("{name} stopped with exit code {exit_code}"),
+                        )
+                        .await?;
+                }
+                Ok(None) => {
+                    report
+                        .skipped(channel, name, format!("{name} is not running"))
+                        .await?;
+                }
+                Err(err) => {
+                    report
+                        .failed(channel, name, format!("failed to stop {name}: {err:#}"))
+                        .await?;
+                }
+            }
+        }
+
+        report
+            .finish(channel)
+            .await
+            .map_err(|err| err.context("down"))
+    }
+}
+
+impl CommandClient for DownSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        Some(Box::new(StreamHandler::new(BatchConsumer)))
+    }
+}
@@ -0,0 +1,141 @@
+use std::env;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use clap::Args;
+use petri_core::platform::Capabilities;
+use serde::{Deserialize, Serialize};
+
+use super::{CommandClient, IpcChannel, OwnedIpcMessagePacket, ResponseHandler};
+use crate::Context as ControlContext;
+
+/// The variables of the server that commonly change how programs behave.
+/// Other variables are not shown, since they may hold secrets.
+const SHOWN_VARS: &[&str] = &[
+    "PATH",
+    "HOME",
+    "USER",
+    "SHELL",
+    "LANG",
+    "LANGUAGE",
+    "TZ",
+    "TMPDIR",
+    "XDG_RUNTIME_DIR",
+];
+
+#[derive(Serialize, Deserialize, Debug)]
+struct EnvResponse {
+    pid: u32,
+    uid: u32,
+    os: String,
+    arch: String,
+    cwd: Option<String>,
+    /// The variables by their names, `LC_*` included.
+    vars: Vec<(String, String)>,
+    listen_address: String,
+    paths: Vec<(String, String)>,
+    capabilities: CapabilitiesResponse,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CapabilitiesResponse {
+    cgroup_version: Option<u8>,
+    user_namespaces: bool,
+    seccomp: bool,
+    pty: bool,
+    child_subreaper: bool,
+    #[serde(default)]
+    criu: bool,
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct EnvSubcommand;
+
+impl EnvSubcommand {
+    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        let mut vars: Vec<_> = env::vars()
+            .filter(|(name, _)| SHOWN_VARS.contains(&name.as_str()) || name.starts_with("LC_"))
+            .collect();
+        vars.sort();
+
+        let capabilities = Capabilities::detect();
+        let resp = EnvResponse {
+            pid: std::process::id(),
+            uid: unsafe { libc::getuid() },
+            os: env::consts::OS.to_owned(),
+            arch: env::consts::ARCH.to_owned(),
+            cwd: env::current_dir().ok().map(|cwd| cwd.display().to_string()),
+            vars,
+            listen_address: ctx.listen_address.to_string(),
+            paths: ctx
+                .paths
+                .iter()
+                .map(|(name, path)| ((*name).to_owned(), path.display().to_string()))
+                .collect(),
+            capabilities: CapabilitiesResponse {
+                cgroup_version: capabilities.cgroup_version,
+                user_namespaces: capabilities.user_namespaces,
+                seccomp: capabilities.seccomp,
+                pty: capabilities.pty,
+                child_subreaper: capabilities.child_subreaper,
+                criu: capabilities.criu,
+            },
+        };
+        channel.write_response(resp).await?;
+        Ok(())
+    }
+}
+
+impl CommandClient for EnvSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        Some(Box::new(EnvResponseHandler))
+    }
+}
+
+struct EnvResponseHandler;
+
+#[async_trait]
+impl ResponseHandler for EnvResponseHandler {
+    async fn handle_response(
+        &mut self,
+        resp: OwnedIpcMessagePacket<serde_json::Value>,
+    ) -> Result<()> {
+        let resp: EnvResponse = resp.into_response().expect("expected a response")?;
+
+        println!("server:   pid {}, uid {}", resp.pid, resp.uid);
+        println!("platform: {} ({})", resp.os, resp.arch);
+        println!("cwd:      {}", resp.cwd.as_deref().unwrap_or("-"));
+        println!("address:  {}", resp.listen_address);
+
+        println!();
+        println!("environment:");
+        for (name, value) in &resp.vars {
+            println!("  {name}={value}");
+        }
+
+        if !resp.paths.is_empty() {
+            println!();
+            println!("paths:");
+            let width = resp.paths.iter().map(|(name, _)| name.len()).max();
+            for (name, path) in &resp.paths {
+                println!("  {name:width$}  {path}", width = width.unwrap_or_default());
+            }
+        }
+
+        let caps = &resp.capabilities;
+        let yes_no = |available: bool| if available { "yes" } else { "no" };
+        println!();
+        println!("capabilities:");
+        match caps.cgroup_version {
+            Some(version) => println!("  cgroups          v{version}"),
+            None => println!("  cgroups          no"),
+        }
+        println!("  user namespaces  {}", yes_no(caps.user_namespaces));
+        println!("  seccomp          {}", yes_no(caps.seccomp));
+        println!("  pty              {}", yes_no(caps.pty));
+        println!("  child subreaper  {}", yes_no(caps.child_subreaper));
+        println!("  criu             {}", yes_no(caps.criu));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::Args;
+use petri_utils::time::parse_duration;
+use petri_utils::FormattedBytes;
+use serde::{Deserialize, Serialize};
+
+use super::{CommandClient, IpcChannel, ResponseHandler};
+use crate::Context as ControlContext;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct GcSubcommand {
+    /// Remove jobs that exited longer ago than this, instead of the
+    /// retention in the server config.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    retention: Option<Duration>,
+}
+
+impl GcSubcommand {
+    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        let retention = self.retention.unwrap_or(ctx.job_retention);
+        let report = ctx.job_mgr_handle.gc(retention).await;
+
+        for name in &report.jobs {
+            channel
+                .write_output(&format!("removed job {name}\n"))
+                .await?;
+        }
+        channel
+            .write_output(&format!(
+                "reclaimed {} jobs, {} log files ({}) and {} stale pids\n",
+                report.jobs.len(),
+                report.log_files,
+                FormattedBytes::new(report.log_bytes),
+                report.stale_pids
+            ))
+            .await?;
+
+        Ok(())
+    }
+}
+
+impl CommandClient for GcSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        None
+    }
+}
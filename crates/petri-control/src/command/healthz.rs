@@ -0,0 +1,84 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use clap::Args;
+use petri_core::fds;
+use petri_core::process_mgr::SPAWN_FD_RESERVE;
+use petri_utils::time::parse_duration;
+use serde::{Deserialize, Serialize};
+
+use super::{CommandClient, IpcChannel, OwnedIpcMessagePacket, ResponseHandler};
+use crate::Context as ControlContext;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct HealthzSubcommand {
+    /// Consider the server unhealthy if it doesn't respond in time.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "5s")]
+    pub(super) timeout: Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct HealthzResponse {
+    /// Why the server is unhealthy, empty if it's healthy.
+    problems: Vec<String>,
+}
+
+impl HealthzSubcommand {
+    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        // Both managers are locked by most commands, so a deadlock in either
+        // makes this time out.
+        ctx.job_mgr_handle.jobs().await;
+        ctx.proc_mgr_handle.processes().await;
+
+        let mut problems = vec![];
+        if ctx.proc_mgr_handle.is_shutting_down() {
+            problems.push("the server is shutting down".to_owned());
+        }
+        if let Some(usage) = fds::Usage::current() {
+            if usage.available() < SPAWN_FD_RESERVE {
+                problems.push(format!(
+                    "{} of {} file descriptors are open, too few to start processes",
+                    usage.open, usage.limit
+                ));
+            }
+        }
+
+        channel.write_response(HealthzResponse { problems }).await?;
+        Ok(())
+    }
+}
+
+impl CommandClient for HealthzSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        Some(Box::new(HealthzResponseHandler { healthy: false }))
+    }
+}
+
+struct HealthzResponseHandler {
+    healthy: bool,
+}
+
+#[async_trait]
+impl ResponseHandler for HealthzResponseHandler {
+    async fn handle_response(
+        &mut self,
+        resp: OwnedIpcMessagePacket<serde_json::Value>,
+    ) -> Result<()> {
+        let resp: HealthzResponse = resp.into_response().expect("expected a response")?;
+        // Quiet when healthy, so it can run from cron.
+        for problem in &resp.problems {
+            eprintln!("unhealthy: {problem}");
+        }
+        self.healthy = resp.problems.is_empty();
+        Ok(())
+    }
+
+    fn exit_status(&self) -> i32 {
+        if self.healthy {
+            0
+        } else {
+            1
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use clap::Args;
+use petri_utils::console_table::{self, ColumnCollection};
+use serde::{Deserialize, Serialize};
+
+use super::{CommandClient, IpcChannel, OwnedIpcMessagePacket, ResponseHandler};
+use crate::history::{CommandResult, HistoryEntry};
+use crate::Context as ControlContext;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct HistoryResponse {
+    entries: Vec<HistoryEntry>,
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct HistorySubcommand {
+    /// Number of the latest commands to show
+    #[arg(short = 'n', long, default_value_t = 20)]
+    limit: usize,
+    /// Only show the commands that failed or were denied
+    #[arg(long)]
+    failed: bool,
+}
+
+impl HistorySubcommand {
+    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        let mut entries = ctx.history.entries();
+        if self.failed {
+            entries.retain(|entry| entry.result != CommandResult::Ok);
+        }
+        let skipped = entries.len().saturating_sub(self.limit);
+        entries.drain(..skipped);
+
+        channel.write_response(HistoryResponse { entries }).await?;
+        Ok(())
+    }
+}
+
+impl CommandClient for HistorySubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        Some(Box::new(HistoryResponseHandler))
+    }
+}
+
+struct HistoryResponseHandler;
+
+#[async_trait]
+impl ResponseHandler for HistoryResponseHandler {
+    async fn handle_response(
+        &mut self,
+        resp: OwnedIpcMessagePacket<serde_json::Value>,
+    ) -> Result<()> {
+        let resp: HistoryResponse = resp.into_response().expect("expected a response")?;
+
+        let time_column = console_table::ColumnOptions::new("TIME");
+        let uid_column = console_table::ColumnOptions::new("UID").spacing(2);
+        let result_column = console_table::ColumnOptions::new("RESULT").spacing(2);
+        let cmd_column = console_table::ColumnOptions::new("CMD").spacing(2);
+
+        let mut table_builder =
+            (time_column, uid_column, result_column, cmd_column).into_table_builder();
+        for entry in resp.entries {
+            let cmd = shlex::try_join(entry.args.iter().map(String::as_str))
+                .unwrap_or_else(|_| entry.args.join(" "));
+            table_builder.push_row(
+                entry.time,
+                entry
+                    .uid
+                    .map(|uid| uid.to_string())
+                    .unwrap_or_else(|| "-".to_owned()),
+                entry.result.as_str().to_owned(),
+                format!("petri {cmd}"),
+            );
+        }
+
+        println!("{table_builder}");
+        Ok(())
+    }
+}
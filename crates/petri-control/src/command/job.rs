@@ -1,10 +1,32 @@
+mod deploy;
+mod edit;
+mod export_systemd;
+mod history;
+mod inspect;
 mod ls;
+mod start;
+mod undo;
 
 use clap::Subcommand;
 use serde::{Deserialize, Serialize};
 
 #[derive(Subcommand, Serialize, Deserialize, Debug)]
+#[serde(tag = "name", content = "args", rename_all = "kebab-case")]
 pub enum JobSubcommand {
     /// List jobs
     Ls(ls::ListSubcommand),
+    /// Start a job, or an instance of a job template
+    Start(start::StartSubcommand),
+    /// Replace the command of a running job without downtime
+    Deploy(deploy::DeploySubcommand),
+    /// Revert the latest deploy of the jobs
+    Undo(undo::UndoSubcommand),
+    /// Print a systemd user unit that runs the job like petri does
+    ExportSystemd(export_systemd::ExportSystemdSubcommand),
+    /// Show how a job is configured, including the environment of its processes
+    Inspect(inspect::InspectSubcommand),
+    /// Edit the settings of a job in $EDITOR
+    Edit(edit::EditSubcommand),
+    /// Show when the processes of a job started and exited
+    History(history::HistorySubcommand),
 }
@@ -0,0 +1,196 @@
+use anyhow::Result;
+use clap::Args;
+use petri_core::job_mgr::{Job, JobRevision};
+use petri_core::process::StartInfo;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::IpcChannel;
+use crate::command::{wait_preconditions, wait_spawn_slot, CommandClient, ResponseHandler};
+use crate::Context as ControlContext;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct DeploySubcommand {
+    /// The id, name or id prefix of the job.
+    job: String,
+    /// The new command line, split like a shell does.
+    #[arg(long = "cmd", value_name = "COMMAND", value_parser = parse_cmd_line)]
+    cmd_line: CmdLine,
+}
+
+/// A non-empty command line.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct CmdLine(Vec<String>);
+
+fn parse_cmd_line(s: &str) -> Result<CmdLine, String> {
+    match shlex::split(s) {
+        Some(cmd_line) if !cmd_line.is_empty() => Ok(CmdLine(cmd_line)),
+        Some(_) => Err("command must not be empty".to_owned()),
+        None => Err(format!("invalid quoting in command `{s}`")),
+    }
+}
+
+impl DeploySubcommand {
+    pub(in crate::command) async fn run(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+    ) -> Result<()> {
+        let job = match ctx.job_mgr_handle.resolve_job_id(&self.job).await {
+            Ok(jid) => ctx.job_mgr_handle.job_with_id(&jid).await,
+            Err(err) => {
+                channel.write_output(&format!("{err}\n")).await?;
+                return Err(err.context("job deploy"));
+            }
+        };
+        let Some(job) = job else {
+            channel.write_output("job is not found\n").await?;
+            return Err(anyhow!("job is removed while deploying").context("job deploy"));
+        };
+        if job.description().template {
+            channel
+                .write_output("job templates cannot be deployed\n")
+                .await?;
+            return Err(anyhow!("deploying a template").context("job deploy"));
+        }
+
+        let mut start_info = job.description().start_info.clone();
+        let CmdLine(mut cmd_line) = self.cmd_line;
+        if cmd_line.is_empty() {
+            channel.write_output("command must not be empty\n").await?;
+            return Err(anyhow!("empty command").context("job deploy"));
+        }
+        let args = cmd_line.split_off(1);
+        start_info.program = cmd_line.remove(0);
+        start_info.args = if args.is_empty() { None } else { Some(args) };
+
+        roll_out(ctx, channel, &job, start_info, None)
+            .await
+            .map_err(|err| err.context("job deploy"))
+    }
+}
+
+/// Starts the job with the start info and switches to it once it's ready,
+/// then stops the previous process. The job is unchanged if the new
+/// process fails to become ready.
+///
+/// If `revision` is given, it's reverted instead of recording a new one.
+pub(super) async fn roll_out(
+    ctx: &ControlContext,
+    channel: &mut IpcChannel,
+    job: &Job,
+    start_info: StartInfo,
+    revision: Option<&JobRevision>,
+) -> Result<()> {
+    // Phase 1: start the new version alongside the old one.
+    channel
+        .write_output("starting the new version...\n")
+        .await?;
+    if let Err(err) = wait_preconditions(ctx, channel, job.id()).await {
+        channel
+            .write_output(&format!("failed to start the new version: {err:#}\n"))
+            .await?;
+        return Err(err);
+    }
+    let permit = wait_spawn_slot(ctx, channel).await?;
+    let spawned = match ctx.job_mgr_handle.spawn_info(job.id(), &start_info).await {
+        Ok(spawn_info) => {
+            ctx.proc_mgr_handle
+                .add_process_with_permit(&spawn_info, permit)
+                .await
+        }
+        Err(err) => Err(err),
+    };
+    let pid = match spawned {
+        Ok(pid) => pid,
+        Err(err) => {
+            channel
+                .write_output(&format!("failed to start the new version: {err:#}\n"))
+                .await?;
+            return Err(err);
+        }
+    };
+
+    // Phase 2: wait for the new version to become ready.
+    channel
+        .write_output(&format!(
+            "waiting for the new version (pid: {pid}) to become ready...\n"
+        ))
+        .await?;
+    let ready = match ctx.proc_mgr_handle.process_with_id(pid).await {
+        Some(process) => job.description().readiness.wait(&process).await,
+        None => Err(anyhow!("process exited before becoming ready")),
+    };
+
+    // Phase 3: switch the job to the new version.
+    let swapped = match ready {
+        Ok(_) => {
+            channel
+                .write_output("switching the job to the new version...\n")
+                .await?;
+            match revision {
+                Some(revision) => ctx.job_mgr_handle.revert_job_process(revision, pid).await,
+                None => {
+                    ctx.job_mgr_handle
+                        .swap_job_process(job.id(), start_info, pid)
+                        .await
+                }
+            }
+        }
+        Err(err) => Err(err),
+    };
+    let old_pid = match swapped {
+        Ok(old_pid) => old_pid,
+        Err(err) => {
+            channel
+                .write_output(&format!(
+                    "the new version failed: {err:#}, rolling back...\n"
+                ))
+                .await?;
+            // The process may have already exited, which is fine.
+            _ = ctx.proc_mgr_handle.stop_process(pid).await;
+            channel
+                .write_output("rolled back, the job is unchanged\n")
+                .await?;
+            return Err(err);
+        }
+    };
+
+    // Phase 4: stop the old version.
+    if let Some(old_pid) = old_pid {
+        channel
+            .write_output(&format!("stopping the old version (pid: {old_pid})...\n"))
+            .await?;
+        match ctx.proc_mgr_handle.stop_process(old_pid).await {
+            Ok(exit_code) => {
+                channel
+                    .write_output(&format!("old version stopped with exit code {exit_code}\n"))
+                    .await?;
+            }
+            Err(err) => {
+                channel
+                    .write_output(&format!("failed to stop the old version: {err:#}\n"))
+                    .await?;
+            }
+        }
+    }
+
+    let action = if revision.is_some() {
+        "reverted"
+    } else {
+        "deployed"
+    };
+    channel
+        .write_output(&format!(
+            "job {} {action} (pid: {pid})\n",
+            job.display_name()
+        ))
+        .await?;
+
+    Ok(())
+}
+
+impl CommandClient for DeploySubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        None
+    }
+}
@@ -0,0 +1,258 @@
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process::Command as ProcessCommand;
+
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+
+use super::JobSubcommand;
+use crate::cli::{IpcChannel, OwnedIpcMessagePacket};
+use crate::command::job_file::JobFile;
+use crate::command::{
+    wait_preconditions, wait_spawn_slot, Command, CommandClient, ResponseHandler,
+};
+use crate::Context as ControlContext;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct EditSubcommand {
+    /// The id, name or id prefix of the job.
+    job: String,
+    /// Restart the job if it's running and the changes apply to its process.
+    #[arg(long)]
+    restart: bool,
+    /// The edited job, sent by the client once the editor exits.
+    #[arg(skip)]
+    edited: Option<EditedJob>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct EditedJob {
+    /// The file as the server rendered it, to tell whether the job was
+    /// changed by others while it was being edited.
+    original: String,
+    file: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct EditResponse {
+    jid: String,
+    short_jid: String,
+    file: String,
+}
+
+impl EditSubcommand {
+    pub(in crate::command) async fn run(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+    ) -> Result<()> {
+        let job = match ctx.job_mgr_handle.resolve_job_id(&self.job).await {
+            Ok(jid) => ctx.job_mgr_handle.job_with_id(&jid).await,
+            Err(err) => {
+                channel.write_output(&format!("{err}\n")).await?;
+                return Err(err.context("job edit"));
+            }
+        };
+        let Some(job) = job else {
+            channel.write_output("job is not found\n").await?;
+            return Err(anyhow!("job is removed").context("job edit"));
+        };
+
+        let current = JobFile::from_description(job.description());
+        let Some(edited) = self.edited else {
+            let resp = EditResponse {
+                jid: job.id().to_owned(),
+                short_jid: job.short_id().to_owned(),
+                file: current.render(job.display_name()),
+            };
+            channel.write_response(resp).await?;
+            return Ok(());
+        };
+
+        if current.render(job.display_name()) != edited.original {
+            channel
+                .write_output("the job was changed while it was being edited, edit it again\n")
+                .await?;
+            return Err(anyhow!("conflicting changes").context("job edit"));
+        }
+        let res = JobFile::parse(&edited.file).and_then(|file| {
+            let desc = file.apply(job.description())?;
+            Ok((file, desc))
+        });
+        let (file, desc) = match res {
+            Ok(res) => res,
+            Err(err) => {
+                channel
+                    .write_output(&format!("invalid job file: {err:#}\n"))
+                    .await?;
+                return Err(err.context("job edit"));
+            }
+        };
+        if let Err(err) = ctx.job_mgr_handle.update_job(job.id(), desc).await {
+            channel
+                .write_output(&format!("failed to update the job: {err}\n"))
+                .await?;
+            return Err(err.context("job edit"));
+        }
+        let name = file.name.as_deref().unwrap_or(job.short_id());
+        channel
+            .write_output(&format!("job {name} updated\n"))
+            .await?;
+
+        if job.pid().is_none() || !file.changes_process(&current) {
+            return Ok(());
+        }
+        if !self.restart {
+            channel
+                .write_output(
+                    "the running process is unchanged, edit with `--restart` to restart it\n",
+                )
+                .await?;
+            return Ok(());
+        }
+
+        channel
+            .write_output(&format!("restarting {name}...\n"))
+            .await?;
+        if let Err(err) = ctx.job_mgr_handle.stop_job(job.id()).await {
+            channel
+                .write_output(&format!("failed to stop the job: {err}\n"))
+                .await?;
+            return Err(err.context("job edit"));
+        }
+        if let Err(err) = wait_preconditions(ctx, channel, job.id()).await {
+            channel
+                .write_output(&format!("failed to start the job: {err}\n"))
+                .await?;
+            return Err(err.context("job edit"));
+        }
+        let permit = wait_spawn_slot(ctx, channel).await?;
+        match ctx
+            .job_mgr_handle
+            .start_job_with_permit(job.id(), permit)
+            .await
+        {
+            Ok(pid) => {
+                channel
+                    .write_output(&format!("job {name} restarted (pid: {pid})\n"))
+                    .await?;
+                Ok(())
+            }
+            Err(err) => {
+                channel
+                    .write_output(&format!("failed to start the job: {err}\n"))
+                    .await?;
+                Err(err.context("job edit"))
+            }
+        }
+    }
+}
+
+impl CommandClient for EditSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        Some(Box::new(EditResponseHandler {
+            restart: self.restart,
+            follow_up: None,
+            exit_status: 0,
+        }))
+    }
+}
+
+struct EditResponseHandler {
+    restart: bool,
+    follow_up: Option<Command>,
+    exit_status: i32,
+}
+
+#[async_trait]
+impl ResponseHandler for EditResponseHandler {
+    async fn handle_response(
+        &mut self,
+        resp: OwnedIpcMessagePacket<serde_json::Value>,
+    ) -> Result<()> {
+        let resp: EditResponse = resp.into_response().expect("expected a response")?;
+
+        let path = std::env::temp_dir().join(format!(
+            "petri-job-{}-{}.toml",
+            resp.short_jid,
+            std::process::id()
+        ));
+        // The file may hold secrets from the job's environment, and the temp
+        // directory is shared, so don't follow a file planted there.
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .and_then(|mut f| f.write_all(resp.file.as_bytes()))
+            .with_context(|| format!("failed to write `{}`", path.display()))?;
+        let res = run_editor(&path).and_then(|_| {
+            fs::read_to_string(&path)
+                .with_context(|| format!("failed to read `{}`", path.display()))
+        });
+        let file = match res {
+            Ok(file) => file,
+            Err(err) => {
+                _ = fs::remove_file(&path);
+                return Err(err);
+            }
+        };
+
+        if file == resp.file {
+            _ = fs::remove_file(&path);
+            println!("no changes");
+            return Ok(());
+        }
+        if let Err(err) = JobFile::parse(&file) {
+            // Keep the file, so the changes are not lost.
+            eprintln!("invalid job file: {err:#}");
+            eprintln!("the edited file is kept at {}", path.display());
+            self.exit_status = 1;
+            return Ok(());
+        }
+        _ = fs::remove_file(&path);
+
+        self.follow_up = Some(Command::Job(JobSubcommand::Edit(EditSubcommand {
+            job: resp.jid,
+            restart: self.restart,
+            edited: Some(EditedJob {
+                original: resp.file,
+                file,
+            }),
+        })));
+        Ok(())
+    }
+
+    fn exit_status(&self) -> i32 {
+        self.exit_status
+    }
+
+    fn follow_up(&mut self) -> Option<Command> {
+        self.follow_up.take()
+    }
+}
+
+/// Opens the file with `$VISUAL` or `$EDITOR`, which may have arguments
+/// like `code --wait`, and waits for it to exit.
+fn run_editor(path: &Path) -> Result<()> {
+    let editor = ["VISUAL", "EDITOR"]
+        .into_iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|editor| !editor.is_empty())
+        .unwrap_or_else(|| "vi".to_owned());
+    let status = ProcessCommand::new("/bin/sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$1\""))
+        .arg("sh")
+        .arg(path)
+        .status()
+        .with_context(|| format!("failed to run the editor `{editor}`"))?;
+    if !status.success() {
+        return Err(anyhow!("the editor `{editor}` exited with {status}"));
+    }
+    Ok(())
+}
@@ -0,0 +1,319 @@
+use std::ffi::OsStr;
+use std::fmt::Write as _;
+use std::path::Path;
+
+use anyhow::Result;
+use clap::Args;
+use petri_core::job_mgr::{JobDescription, Readiness, RestartMode};
+use serde::{Deserialize, Serialize};
+
+use crate::cli::IpcChannel;
+use crate::command::{CommandClient, ResponseHandler};
+use crate::Context as ControlContext;
+
+/// Variables of the shell session the job was created in, which make no
+/// sense in a unit.
+const SESSION_ENV_VARS: &[&str] = &["_", "OLDPWD", "PWD", "SHLVL"];
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct ExportSystemdSubcommand {
+    /// Id or name of the job.
+    job: String,
+}
+
+impl ExportSystemdSubcommand {
+    pub(in crate::command) async fn run(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+    ) -> Result<()> {
+        let job = match ctx.job_mgr_handle.resolve_job_id(&self.job).await {
+            Ok(jid) => ctx.job_mgr_handle.job_with_id(&jid).await,
+            Err(err) => {
+                channel.write_output(&format!("{err}\n")).await?;
+                return Err(err.context("job export-systemd"));
+            }
+        };
+        let Some(job) = job else {
+            channel.write_output("job is not found\n").await?;
+            return Err(anyhow!("job is removed").context("job export-systemd"));
+        };
+        if job.description().template {
+            channel
+                .write_output("job templates cannot be exported\n")
+                .await?;
+            return Err(anyhow!("exporting a template").context("job export-systemd"));
+        }
+
+        // Units are named after the jobs, so refer to the dependencies by
+        // their names too.
+        let mut deps = vec![];
+        for dep in &job.description().depends_on {
+            let name = match ctx.job_mgr_handle.resolve_job_id(dep).await {
+                Ok(jid) => ctx.job_mgr_handle.job_with_id(&jid).await,
+                Err(_) => None,
+            }
+            .map_or_else(|| dep.clone(), |dep| dep.display_name().to_owned());
+            deps.push(name);
+        }
+
+        let unit = render_unit(job.display_name(), job.description(), &deps);
+        channel.write_output(&unit).await?;
+        Ok(())
+    }
+}
+
+impl CommandClient for ExportSystemdSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        None
+    }
+}
+
+/// Renders a systemd user unit that runs the job like petri does. The
+/// unit of a dependency is expected to be named `<dep>.service`.
+fn render_unit(name: &str, desc: &JobDescription, deps: &[String]) -> String {
+    let start_info = &desc.start_info;
+    let mut unit = String::new();
+
+    unit.push_str("[Unit]\n");
+    _ = writeln!(unit, "Description=petri job {name}");
+    for dep in deps {
+        _ = writeln!(unit, "Wants={dep}.service");
+        _ = writeln!(unit, "After={dep}.service");
+    }
+
+    unit.push_str("\n[Service]\n");
+    unit.push_str("Type=simple\n");
+    _ = writeln!(unit, "WorkingDirectory={}", quote(&start_info.cwd));
+    let mut env: Vec<_> = start_info
+        .env
+        .iter()
+        .filter(|(key, _)| !SESSION_ENV_VARS.iter().any(|name| *key == name))
+        .collect();
+    env.sort();
+    for (key, value) in env {
+        // Unit files are text, so the variables that are not valid UTF-8
+        // are left to the user.
+        let (Some(key), Some(value)) = (key.to_str(), value.to_str()) else {
+            _ = writeln!(
+                unit,
+                "# `{}` is not valid UTF-8 and must be set by hand",
+                key.to_string_lossy()
+            );
+            continue;
+        };
+        _ = writeln!(unit, "Environment={}", quote(&format!("{key}={value}")));
+    }
+
+    let program = resolve_program(
+        &start_info.program,
+        &start_info.cwd,
+        start_info
+            .env
+            .get(OsStr::new("PATH"))
+            .and_then(|path| path.to_str()),
+    );
+    let mut exec_start = quote(&program);
+    for arg in start_info.args.iter().flatten() {
+        exec_start.push(' ');
+        exec_start.push_str(&quote(arg));
+    }
+    _ = writeln!(unit, "ExecStart={exec_start}");
+
+    let restart = match desc.restart.mode {
+        RestartMode::Never => "no",
+        RestartMode::OnFailure => "on-failure",
+        RestartMode::Always | RestartMode::UnlessStopped => "always",
+    };
+    _ = writeln!(unit, "Restart={restart}");
+    if !desc.restart.clean_exit_codes.is_empty() {
+        let codes: Vec<_> = desc
+            .restart
+            .clean_exit_codes
+            .iter()
+            .map(i32::to_string)
+            .collect();
+        _ = writeln!(unit, "SuccessExitStatus={}", codes.join(" "));
+    }
+
+    // Leave notes for what systemd does differently.
+    match desc.readiness {
+        Readiness::Immediate => {}
+        Readiness::Delay(delay) => {
+            _ = writeln!(
+                unit,
+                "# petri considered the job ready after {}s, systemd does once it's started.",
+                delay.as_secs()
+            );
+        }
+        Readiness::Notify => unit
+            .push_str("# petri waited for the job to run `petri notify ready`, systemd doesn't.\n"),
+    }
+    if let Some(log_path) = &start_info.log.path {
+        _ = writeln!(
+            unit,
+            "# petri logged to {}, systemd logs to the journal.",
+            log_path.display()
+        );
+    }
+    if let Some(sampling) = &start_info.log.sampling {
+        _ = writeln!(
+            unit,
+            "# petri only logged {sampling} lines, the journal limits by `LogRateLimitBurst=`."
+        );
+    }
+    if desc.idle.is_some() {
+        unit.push_str("# petri stopped the job when idle, which systemd can't do.\n");
+    }
+    for precondition in &desc.preconditions {
+        _ = writeln!(
+            unit,
+            "# petri waited for {} before starting, systemd doesn't.",
+            precondition.target()
+        );
+    }
+    if let Some(jitter) = &desc.restart.jitter {
+        _ = writeln!(
+            unit,
+            "# petri delayed restarts randomly by {jitter}, systemd waits for `RestartSec`."
+        );
+    }
+    if !desc.restart.windows.is_empty() {
+        let windows: Vec<_> = desc
+            .restart
+            .windows
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        _ = writeln!(
+            unit,
+            "# petri only restarted the job within {}, which systemd can't do.",
+            windows.join(", ")
+        );
+    }
+    if let Some(watchdog) = &desc.memory_watchdog {
+        _ = writeln!(
+            unit,
+            "# petri watched the memory of the job, systemd can limit it with `MemoryMax={}`.",
+            watchdog.max_rss_bytes
+        );
+    }
+
+    unit.push_str("\n[Install]\n");
+    if desc.autostart {
+        unit.push_str("WantedBy=default.target\n");
+    } else {
+        unit.push_str("# The job was not autostarted, uncomment to start it on login.\n");
+        unit.push_str("# WantedBy=default.target\n");
+    }
+    unit
+}
+
+/// Makes the program an absolute path, which `ExecStart` requires on older
+/// systemd versions.
+fn resolve_program(program: &str, cwd: &str, path_var: Option<&str>) -> String {
+    if program.starts_with('/') {
+        return program.to_owned();
+    }
+    if program.contains('/') {
+        return Path::new(cwd).join(program).to_string_lossy().into_owned();
+    }
+    path_var
+        .into_iter()
+        .flat_map(|path_var| path_var.split(':'))
+        .filter(|dir| dir.starts_with('/'))
+        .map(|dir| Path::new(dir).join(program))
+        .find(|path| path.is_file())
+        .map_or_else(
+            || program.to_owned(),
+            |path| path.to_string_lossy().into_owned(),
+        )
+}
+
+/// Quotes a word for unit files if needed, escaping specifiers (`%`) and
+/// variable expansions (`$`).
+fn quote(word: &str) -> String {
+    let escaped = word.replace('%', "%%").replace('$', "$$");
+    if !word.is_empty()
+        && !word
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';'))
+    {
+        return escaped;
+    }
+
+    let mut quoted = String::from("\"");
+    for c in escaped.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use petri_core::job_mgr::{JobDescription, Readiness, RestartMode, RestartPolicy};
+    use petri_core::process::StartInfo;
+
+    use super::{quote, render_unit};
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("plain"), "plain");
+        assert_eq!(quote(""), "\"\"");
+        assert_eq!(quote("a b"), "\"a b\"");
+        assert_eq!(quote("say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(quote("$HOME/100%"), "$$HOME/100%%");
+        assert_eq!(quote("; rm"), "\"; rm\"");
+    }
+
+    #[test]
+    fn test_render_unit() {
+        let start_info = StartInfo::builder("/bin/sh", "/srv/app")
+            .args(["-c", "echo $NAME"])
+            .env("NAME", "web app")
+            .env("SHLVL", "2")
+            .build()
+            .unwrap();
+        let desc = JobDescription::builder(start_info)
+            .restart(RestartPolicy {
+                mode: RestartMode::OnFailure,
+                clean_exit_codes: vec![143],
+                ..Default::default()
+            })
+            .name("web".to_owned())
+            .depends_on(["db".to_owned()])
+            .readiness(Readiness::Delay(Duration::from_secs(5)))
+            .build();
+
+        assert_eq!(
+            render_unit("web", &desc, &["db".to_owned()]),
+            "[Unit]\n\
+             Description=petri job web\n\
+             Wants=db.service\n\
+             After=db.service\n\
+             \n\
+             [Service]\n\
+             Type=simple\n\
+             WorkingDirectory=/srv/app\n\
+             Environment=\"NAME=web app\"\n\
+             ExecStart=/bin/sh -c \"echo $$NAME\"\n\
+             Restart=on-failure\n\
+             SuccessExitStatus=143\n\
+             # petri considered the job ready after 5s, systemd does once it's started.\n\
+             \n\
+             [Install]\n\
+             WantedBy=default.target\n"
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use clap::Args;
+use petri_core::job_history::{JobEvent, JobEventKind};
+use petri_core::process::ExitReason;
+use petri_utils::console_table::{self, ColumnCollection};
+use petri_utils::time::in_zone;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::{IpcChannel, OwnedIpcMessagePacket};
+use crate::command::{CommandClient, ResponseHandler};
+use crate::Context as ControlContext;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct HistoryResponse {
+    events: Vec<Event>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Event {
+    /// Formatted by the server, in the time zone of its logs.
+    time: String,
+    event: String,
+    pid: u32,
+    detail: String,
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct HistorySubcommand {
+    /// Id or name of the job.
+    job: String,
+    /// Only show the latest events.
+    #[arg(long)]
+    limit: Option<usize>,
+}
+
+impl HistorySubcommand {
+    pub(in crate::command) async fn run(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+    ) -> Result<()> {
+        let events = match ctx.job_mgr_handle.resolve_job_id(&self.job).await {
+            Ok(jid) => ctx
+                .job_mgr_handle
+                .history(&jid)
+                .await
+                .map_err(|err| anyhow!(err).context("failed to read the history of the job")),
+            Err(err) => Err(err),
+        };
+        let mut events = match events {
+            Ok(events) => events,
+            Err(err) => {
+                channel.write_output(&format!("{err:#}\n")).await?;
+                return Err(err.context("job history"));
+            }
+        };
+        if let Some(limit) = self.limit {
+            events.drain(..events.len().saturating_sub(limit));
+        }
+
+        let resp = HistoryResponse {
+            events: events.iter().map(Event::from).collect(),
+        };
+        channel.write_response(resp).await?;
+        Ok(())
+    }
+}
+
+impl From<&JobEvent> for Event {
+    fn from(event: &JobEvent) -> Self {
+        let (name, detail) = match event.kind {
+            JobEventKind::Started => ("started", String::new()),
+            JobEventKind::Restarted => ("restarted", String::new()),
+            JobEventKind::Restored => ("restored", String::new()),
+            JobEventKind::Exited { exit_code, reason } => {
+                let reason = match reason {
+                    ExitReason::Normal => "",
+                    ExitReason::Oom => " (killed for using too much memory)",
+                    ExitReason::Stopped => " (stopped)",
+                    ExitReason::Idle => " (stopped for being idle)",
+                    ExitReason::MemoryWatchdog => " (stopped by the memory watchdog)",
+                    ExitReason::Checkpointed => " (checkpointed)",
+                    ExitReason::LogQuota => " (stopped for exceeding its log quota)",
+                };
+                ("exited", format!("exit code {exit_code}{reason}"))
+            }
+        };
+        Self {
+            time: in_zone(&event.time).format("%Y-%m-%d %T").to_string(),
+            event: name.to_owned(),
+            pid: event.pid,
+            detail,
+        }
+    }
+}
+
+impl CommandClient for HistorySubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        Some(Box::new(HistoryResponseHandler))
+    }
+}
+
+struct HistoryResponseHandler;
+
+#[async_trait]
+impl ResponseHandler for HistoryResponseHandler {
+    async fn handle_response(
+        &mut self,
+        resp: OwnedIpcMessagePacket<serde_json::Value>,
+    ) -> Result<()> {
+        let resp: HistoryResponse = resp.into_response().expect("expected a response")?;
+
+        let time_column = console_table::ColumnOptions::new("TIME").spacing(2);
+        let event_column = console_table::ColumnOptions::new("EVENT").spacing(2);
+        let pid_column = console_table::ColumnOptions::new("PID")
+            .alignment(console_table::Alignment::Right)
+            .spacing(2);
+        let detail_column = console_table::ColumnOptions::new("DETAIL");
+
+        let mut table_builder =
+            (time_column, event_column, pid_column, detail_column).into_table_builder();
+        for event in resp.events {
+            table_builder.push_row(event.time, event.event, event.pid.to_string(), event.detail);
+        }
+
+        println!("{table_builder}");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt::Write as _;
+
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use clap::Args;
+use petri_core::job_mgr::{Job, RestartMode};
+use petri_core::process::Process;
+use petri_utils::time::in_zone;
+use petri_utils::FormattedBytes;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::IpcChannel;
+use crate::command::{CommandClient, ResponseHandler};
+use crate::env::EnvPolicy;
+use crate::Context as ControlContext;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct InspectSubcommand {
+    /// Id or name of the job.
+    job: String,
+    /// Only print the program and its arguments, exactly as they are passed
+    /// to the process, as a JSON array.
+    #[arg(long)]
+    raw_args: bool,
+}
+
+impl InspectSubcommand {
+    pub(in crate::command) async fn run(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+    ) -> Result<()> {
+        let job = match ctx.job_mgr_handle.resolve_job_id(&self.job).await {
+            Ok(jid) => ctx.job_mgr_handle.job_with_id(&jid).await,
+            Err(err) => {
+                channel.write_output(&format!("{err}\n")).await?;
+                return Err(err.context("job inspect"));
+            }
+        };
+        let Some(job) = job else {
+            channel.write_output("job is not found\n").await?;
+            return Err(anyhow!("job is removed").context("job inspect"));
+        };
+
+        if self.raw_args {
+            let argv = job.description().start_info.argv();
+            let argv = serde_json::to_string(&argv).expect("strings should be serializable");
+            channel.write_output(&format!("{argv}\n")).await?;
+            return Ok(());
+        }
+
+        let process = match job.pid() {
+            Some(pid) => ctx.proc_mgr_handle.process_with_id(pid).await,
+            None => None,
+        };
+        let restart_at = ctx.job_mgr_handle.scheduled_restart(job.id());
+        let details = render_details(&job, process.as_ref(), restart_at, &ctx.env_policy.read());
+        channel.write_output(&details).await?;
+        Ok(())
+    }
+}
+
+impl CommandClient for InspectSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        None
+    }
+}
+
+/// Renders what the job runs and how it's managed, with the environment
+/// that its processes get. The annotations and the log usage are the ones
+/// of the running process.
+fn render_details(
+    job: &Job,
+    process: Option<&Process>,
+    restart_at: Option<DateTime<Local>>,
+    env_policy: &EnvPolicy,
+) -> String {
+    let desc = job.description();
+    let start_info = &desc.start_info;
+    let mut details = String::new();
+
+    _ = writeln!(details, "id:            {}", job.short_id());
+    _ = writeln!(details, "digest:        {}", job.id());
+    _ = writeln!(details, "namespace:     {}", desc.namespace);
+    if let Some(name) = &desc.name {
+        _ = writeln!(details, "name:          {name}");
+    }
+    _ = writeln!(details, "command:       {}", start_info.cmd());
+    _ = writeln!(details, "cwd:           {}", start_info.cwd);
+    match job.pid() {
+        Some(pid) => _ = writeln!(details, "pid:           {pid}"),
+        None => details.push_str("pid:           (not running)\n"),
+    }
+    let restart = match desc.restart.mode {
+        RestartMode::Never => "never",
+        RestartMode::OnFailure => "on-failure",
+        RestartMode::Always => "always",
+        RestartMode::UnlessStopped => "unless-stopped",
+    };
+    _ = write!(details, "restart:       {restart}");
+    if let Some(jitter) = &desc.restart.jitter {
+        _ = write!(details, ", jitter {jitter}");
+    }
+    if !desc.restart.windows.is_empty() {
+        let windows: Vec<_> = desc
+            .restart
+            .windows
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        _ = write!(details, ", within {}", windows.join(", "));
+    }
+    details.push('\n');
+    if let Some(restart_at) = restart_at {
+        _ = writeln!(
+            details,
+            "restarts at:   {}",
+            in_zone(&restart_at).format("%Y-%m-%d %T")
+        );
+    }
+    if !desc.depends_on.is_empty() {
+        _ = writeln!(details, "depends on:    {}", desc.depends_on.join(", "));
+    }
+    for precondition in &desc.preconditions {
+        _ = writeln!(
+            details,
+            "wait for:      {precondition} (up to {:?})",
+            desc.precondition_timeout
+        );
+    }
+    _ = writeln!(details, "stop priority: {}", desc.stop_priority);
+    if let Some(watchdog) = &desc.memory_watchdog {
+        _ = writeln!(
+            details,
+            "max rss:       {} for {} samples every {:?}, then {}",
+            FormattedBytes::new(watchdog.max_rss_bytes),
+            watchdog.samples,
+            watchdog.interval,
+            watchdog.action.as_str()
+        );
+    }
+    if let Some(quota) = &start_info.log.quota {
+        _ = writeln!(
+            details,
+            "log quota:     {} ({})",
+            FormattedBytes::new(quota.max_bytes),
+            quota.policy.as_str()
+        );
+    }
+    if let Some(sampling) = &start_info.log.sampling {
+        _ = writeln!(details, "log sampling:  {sampling}");
+    }
+    if let Some(usage) = process.and_then(Process::log_usage) {
+        _ = write!(
+            details,
+            "log usage:     {}, quota exceeded {} times",
+            FormattedBytes::new(usage.bytes),
+            usage.exceeded
+        );
+        if usage.stopped {
+            details.push_str(", logging stopped");
+        }
+        details.push('\n');
+    }
+    for (key, value) in &desc.labels {
+        _ = writeln!(details, "label:         {key}={value}");
+    }
+
+    if let Some(process) = process {
+        let annotations = process.annotations();
+        for (key, value) in &annotations.tags {
+            _ = writeln!(details, "tag:           {key}={value}");
+        }
+        if let Some(note) = &annotations.note {
+            _ = writeln!(details, "note:          {note}");
+        }
+    }
+
+    // The env file is read as the next start will, so its values may
+    // differ from the ones of the running process.
+    let mut file_env = HashMap::new();
+    if let Some(path) = desc.env_file_path() {
+        match desc.file_env() {
+            Ok(vars) => {
+                _ = writeln!(details, "env file:      {}", path.display());
+                file_env.extend(
+                    vars.into_iter()
+                        .map(|(name, value)| (OsString::from(name), OsString::from(value))),
+                );
+            }
+            Err(err) => _ = writeln!(details, "env file:      {err:#}"),
+        }
+    }
+
+    let mut names: Vec<_> = start_info.env.keys().chain(file_env.keys()).collect();
+    names.sort();
+    names.dedup();
+    details.push_str("environment:\n");
+    for name in names {
+        let (value, note) = match (file_env.get(name), start_info.env.contains_key(name)) {
+            (Some(value), false) => (value, "  (env file)"),
+            (Some(value), true) => (value, "  (env file, overrides the job)"),
+            (None, _) => (&start_info.env[name], ""),
+        };
+        _ = writeln!(
+            details,
+            "  {}={}{note}",
+            name.to_string_lossy(),
+            value.to_string_lossy()
+        );
+    }
+    _ = writeln!(
+        details,
+        "(client variables are accepted by the policy: {env_policy})"
+    );
+    if !start_info.petri_env {
+        details.push_str("(the `PETRI_*` variables are not set)\n");
+    }
+    details
+}
@@ -3,9 +3,12 @@ use async_trait::async_trait;
 use chrono::DateTime;
 use clap::Args;
 use petri_utils::console_table::{self, ColumnCollection};
+use petri_utils::id::short_id;
 use serde::{Deserialize, Serialize};
 
+use crate::cli::{ClientEnv, RenderHints, CLIENT_ENV};
 use crate::cli::{IpcChannel, OwnedIpcMessagePacket};
+use crate::command::namespace::NamespaceArgs;
 use crate::command::{CommandClient, ResponseHandler};
 use crate::Context as ControlContext;
 
@@ -17,13 +20,19 @@ struct ListResponse {
 #[derive(Serialize, Deserialize, Debug)]
 struct Job {
     jid: String,
+    name: Option<String>,
+    #[serde(default)]
+    namespace: String,
     pid: Option<u32>,
     cmd: String,
     created_at_ts: (i64, u32),
 }
 
 #[derive(Args, Serialize, Deserialize, Debug)]
-pub struct ListSubcommand;
+pub struct ListSubcommand {
+    #[command(flatten)]
+    namespace: NamespaceArgs,
+}
 
 impl ListSubcommand {
     pub(in crate::command) async fn run(
@@ -31,15 +40,38 @@ impl ListSubcommand {
         ctx: &ControlContext,
         channel: &mut IpcChannel,
     ) -> Result<()> {
-        let real_jobs = ctx.job_mgr_handle.jobs().await;
-
+        let real_jobs = self.namespace.jobs(ctx).await;
+        let all_jobs = ctx.job_mgr_handle.jobs().await;
+        // Templates may be in other namespaces than their instances.
+        let template_name = |jid: &str| {
+            all_jobs
+                .iter()
+                .find(|job| job.id() == jid)
+                .map(|job| job.display_name().to_owned())
+                .unwrap_or_else(|| short_id(jid))
+        };
+
+        let render = CLIENT_ENV.with(ClientEnv::render);
         let mut jobs = vec![];
-        for job in real_jobs {
+        for job in &real_jobs {
             let created_at = job.created_at();
+            let desc = job.description();
+            let name = if desc.template {
+                desc.name.as_ref().map(|name| format!("{name} (template)"))
+            } else if let Some(template_id) = job.template_id() {
+                let name = template_name(template_id);
+                Some(format!("{name} (instance)"))
+            } else {
+                desc.name.clone()
+            };
             jobs.push(Job {
-                jid: job.id().to_owned(),
+                jid: job.short_id().to_owned(),
+                name,
+                namespace: desc.namespace.clone(),
                 pid: job.pid(),
-                cmd: job.description().start_info.cmd(),
+                cmd: render
+                    .truncate_cmd(&job.description().start_info.cmd())
+                    .into_owned(),
                 created_at_ts: (created_at.timestamp(), created_at.timestamp_subsec_nanos()),
             });
         }
@@ -71,16 +103,34 @@ impl ResponseHandler for ListResponseHandler {
         jobs.sort_by_key(|job| DateTime::from_timestamp(job.created_at_ts.0, job.created_at_ts.1));
 
         let jid_column = console_table::ColumnOptions::new("JID");
+        let name_column = console_table::ColumnOptions::new("NAME").spacing(2);
+        let namespace_column = console_table::ColumnOptions::new("NAMESPACE").spacing(2);
         let pid_column = console_table::ColumnOptions::new("PID")
             .alignment(console_table::Alignment::Right)
             .spacing(2);
         let cmd_column = console_table::ColumnOptions::new("CMD");
 
-        let mut table_builder = (jid_column, pid_column, cmd_column).into_table_builder();
+        let mut table_builder = (
+            jid_column,
+            name_column,
+            namespace_column,
+            pid_column,
+            cmd_column,
+        )
+            .into_table_builder();
 
         for job in jobs {
             let pid_string = job.pid.map(|pid| pid.to_string()).unwrap_or_default();
-            table_builder.push_row(job.jid, pid_string, job.cmd);
+            table_builder.push_row(
+                job.jid,
+                job.name.unwrap_or_default(),
+                job.namespace,
+                pid_string,
+                job.cmd,
+            );
+        }
+        if let Some(width) = RenderHints::detect().width {
+            table_builder.fit_column(4, width.into());
         }
 
         println!("{table_builder}");
@@ -0,0 +1,99 @@
+use anyhow::Result;
+use clap::Args;
+use petri_core::job_mgr::JobOverrides;
+use petri_utils::id::short_id;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::IpcChannel;
+use crate::command::{
+    parse_key_value, wait_preconditions, wait_spawn_slot, CommandClient, ResponseHandler,
+};
+use crate::Context as ControlContext;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct StartSubcommand {
+    /// The id, name or id prefix of the job.
+    job: String,
+    /// Set an environment variable for the instance (templates only).
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    env: Vec<(String, String)>,
+    /// Append an argument to the command line of the instance (templates only).
+    #[arg(long = "set-arg", value_name = "ARG", allow_hyphen_values = true)]
+    extra_args: Vec<String>,
+}
+
+impl StartSubcommand {
+    pub(in crate::command) async fn run(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+    ) -> Result<()> {
+        let jid = match ctx.job_mgr_handle.resolve_job_id(&self.job).await {
+            Ok(jid) => jid,
+            Err(err) => {
+                channel.write_output(&format!("{err}\n")).await?;
+                return Err(err.context("job start"));
+            }
+        };
+
+        let is_template = ctx
+            .job_mgr_handle
+            .job_with_id(&jid)
+            .await
+            .is_some_and(|job| job.description().template);
+
+        let overrides = JobOverrides {
+            env: self.env,
+            extra_args: self.extra_args,
+        };
+        if !is_template && !overrides.is_empty() {
+            channel
+                .write_output("overrides can only be used with job templates\n")
+                .await?;
+            return Err(anyhow!("overrides used with a regular job").context("job start"));
+        }
+
+        if let Err(err) = wait_preconditions(ctx, channel, &jid).await {
+            channel
+                .write_output(&format!("failed to start the job: {err}\n"))
+                .await?;
+            return Err(err.context("job start"));
+        }
+        let permit = wait_spawn_slot(ctx, channel).await?;
+        let res = if is_template {
+            ctx.job_mgr_handle
+                .start_instance_with_permit(&jid, &overrides, permit)
+                .await
+                .map(|(instance_jid, pid)| {
+                    format!(
+                        "instance {} started (pid: {pid})\n",
+                        short_id(&instance_jid)
+                    )
+                })
+        } else {
+            ctx.job_mgr_handle
+                .start_job_with_permit(&jid, permit)
+                .await
+                .map(|pid| format!("process started (pid: {pid})\n"))
+        };
+
+        match res {
+            Ok(msg) => {
+                channel.write_output(&msg).await?;
+                Ok(())
+            }
+            Err(err) => {
+                channel
+                    .write_output(&format!("failed to start the job: {err}\n"))
+                    .await?;
+                Err(err.context("job start"))
+            }
+        }
+    }
+}
+
+impl CommandClient for StartSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        None
+    }
+}
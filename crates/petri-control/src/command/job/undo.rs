@@ -0,0 +1,53 @@
+use anyhow::Result;
+use clap::Args;
+use petri_utils::time::in_zone;
+use serde::{Deserialize, Serialize};
+
+use super::deploy::roll_out;
+use crate::cli::IpcChannel;
+use crate::command::{CommandClient, ResponseHandler};
+use crate::Context as ControlContext;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct UndoSubcommand;
+
+impl UndoSubcommand {
+    pub(in crate::command) async fn run(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+    ) -> Result<()> {
+        let Some(revision) = ctx.job_mgr_handle.last_revision().await else {
+            channel.write_output("nothing to undo\n").await?;
+            return Err(anyhow!("no revisions").context("job undo"));
+        };
+        let Some(job) = ctx.job_mgr_handle.job_with_id(revision.jid()).await else {
+            channel.write_output("job is not found\n").await?;
+            return Err(anyhow!("job is removed").context("job undo"));
+        };
+
+        let start_info = revision.before().clone();
+        let mut cmd_line = vec![start_info.program.as_str()];
+        if let Some(args) = &start_info.args {
+            cmd_line.extend(args.iter().map(String::as_str));
+        }
+        channel
+            .write_output(&format!(
+                "reverting the change of job {} at {}, restoring `{}`\n",
+                job.display_name(),
+                in_zone(revision.created_at()).format("%Y-%m-%d %T"),
+                shlex::try_join(cmd_line).unwrap_or_default()
+            ))
+            .await?;
+
+        roll_out(ctx, channel, &job, start_info, Some(&revision))
+            .await
+            .map_err(|err| err.context("job undo"))
+    }
+}
+
+impl CommandClient for UndoSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        None
+    }
+}
@@ -0,0 +1,340 @@
+//! The settings of jobs as TOML files, which are edited by `job edit`
+//! and compared by `apply --diff`.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Result;
+use petri_core::job_mgr::{
+    JobDescription, Readiness, RestartMode, RestartPolicy, RestartWindow,
+    DEFAULT_PRECONDITION_TIMEOUT,
+};
+use petri_core::precondition::Precondition;
+use petri_core::process::StartInfo;
+use petri_utils::time::parse_duration;
+use serde::{Deserialize, Serialize};
+
+/// The settings of a job that can be edited. Others, like log files and
+/// the sandbox, are kept.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(deny_unknown_fields)]
+pub(super) struct JobFile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) name: Option<String>,
+    namespace: String,
+    command: Vec<String>,
+    cwd: String,
+    autostart: bool,
+    restart: RestartSetting,
+    #[serde(default)]
+    clean_exit_codes: Vec<i32>,
+    /// A range like `5s..30s`, or `30s` for up to 30 seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    restart_jitter: Option<String>,
+    /// Times of day like `02:00-04:00`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    restart_windows: Vec<String>,
+    #[serde(default)]
+    depends_on: Vec<String>,
+    /// Conditions like `tcp:postgres:5432`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    wait_for: Vec<String>,
+    /// How long to wait for `wait_for`, like `1m`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    wait_timeout: Option<String>,
+    /// `immediate`, `notify` or a duration like `5s`.
+    readiness: String,
+    #[serde(default)]
+    stop_priority: i32,
+    petri_env: bool,
+    /// A `.env` file read whenever the job starts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    env_file: Option<PathBuf>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    /// The variables that are valid UTF-8. The others can't be written in
+    /// TOML, so they are kept as they are.
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+enum RestartSetting {
+    Never,
+    OnFailure,
+    Always,
+    UnlessStopped,
+}
+
+impl JobFile {
+    pub(super) fn from_description(desc: &JobDescription) -> Self {
+        let start_info = &desc.start_info;
+        let readiness = match desc.readiness {
+            Readiness::Immediate => "immediate".to_owned(),
+            Readiness::Notify => "notify".to_owned(),
+            Readiness::Delay(delay) => format_duration(delay),
+        };
+        Self {
+            name: desc.name.clone(),
+            namespace: desc.namespace.clone(),
+            command: [start_info.program.clone()]
+                .into_iter()
+                .chain(start_info.args.iter().flatten().cloned())
+                .collect(),
+            cwd: start_info.cwd.clone(),
+            autostart: desc.autostart,
+            restart: desc.restart.mode.into(),
+            clean_exit_codes: desc.restart.clean_exit_codes.clone(),
+            restart_jitter: desc.restart.jitter.map(|jitter| jitter.to_string()),
+            restart_windows: desc
+                .restart
+                .windows
+                .iter()
+                .map(RestartWindow::to_string)
+                .collect(),
+            depends_on: desc.depends_on.clone(),
+            wait_for: desc
+                .preconditions
+                .iter()
+                .map(Precondition::to_string)
+                .collect(),
+            wait_timeout: (!desc.preconditions.is_empty())
+                .then(|| format_duration(desc.precondition_timeout)),
+            readiness,
+            stop_priority: desc.stop_priority,
+            petri_env: start_info.petri_env,
+            env_file: desc.env_file.clone(),
+            labels: desc.labels.clone(),
+            env: start_info
+                .env
+                .iter()
+                .filter_map(|(key, value)| {
+                    Some((key.to_str()?.to_owned(), value.to_str()?.to_owned()))
+                })
+                .collect(),
+        }
+    }
+
+    /// Renders the settings, one line for each field (or each variable of
+    /// tables like `env`).
+    pub(super) fn to_toml(&self) -> String {
+        toml::to_string(self).expect("job file should be serializable")
+    }
+
+    /// Renders the file to be edited, with notes for the user.
+    pub(super) fn render(&self, name: &str) -> String {
+        let file = self.to_toml();
+        format!(
+            "# Settings of job {name}. Others, like log files and the sandbox, are kept.\n\
+             # `readiness` is `immediate`, `notify` or a duration like `5s`.\n\
+             # `restart_jitter` is a range like `5s..30s`, and `restart_windows` are times of\n\
+             # day like `02:00-04:00`.\n\
+             # `wait_for` are conditions like `tcp:postgres:5432`, `file:PATH` or `cmd:COMMAND`.\n\
+             \n\
+             {file}"
+        )
+    }
+
+    /// Parses the edited file, checking what can be checked without the
+    /// job.
+    pub(super) fn parse(s: &str) -> Result<Self> {
+        let file: Self = toml::from_str(s)?;
+        let Some((program, args)) = file.command.split_first() else {
+            return Err(anyhow!("`command` must not be empty"));
+        };
+        file.readiness()?;
+        file.restart()?;
+        file.preconditions()?;
+        StartInfo::builder(program.clone(), file.cwd.clone())
+            .args(args.iter().cloned())
+            .envs(file.env.clone())
+            .build()?;
+        Ok(file)
+    }
+
+    fn readiness(&self) -> Result<Readiness> {
+        match self.readiness.as_str() {
+            "immediate" => Ok(Readiness::Immediate),
+            "notify" => Ok(Readiness::Notify),
+            delay => parse_duration(delay)
+                .map(Readiness::Delay)
+                .map_err(|err| anyhow!("invalid `readiness`: {err}")),
+        }
+    }
+
+    fn restart(&self) -> Result<RestartPolicy> {
+        let jitter = self
+            .restart_jitter
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .map_err(|err| anyhow!("invalid `restart_jitter`: {err}"))?;
+        let windows = self
+            .restart_windows
+            .iter()
+            .map(|window| window.parse())
+            .collect::<Result<_>>()
+            .map_err(|err| anyhow!("invalid `restart_windows`: {err}"))?;
+        Ok(RestartPolicy {
+            mode: self.restart.into(),
+            clean_exit_codes: self.clean_exit_codes.clone(),
+            jitter,
+            windows,
+        })
+    }
+
+    fn preconditions(&self) -> Result<(Vec<Precondition>, Duration)> {
+        let preconditions = self
+            .wait_for
+            .iter()
+            .map(|precondition| precondition.parse())
+            .collect::<Result<_>>()
+            .map_err(|err| anyhow!("invalid `wait_for`: {err}"))?;
+        let timeout = match &self.wait_timeout {
+            Some(timeout) => {
+                parse_duration(timeout).map_err(|err| anyhow!("invalid `wait_timeout`: {err}"))?
+            }
+            None => DEFAULT_PRECONDITION_TIMEOUT,
+        };
+        Ok((preconditions, timeout))
+    }
+
+    /// Returns the description of the job with the settings of the file
+    /// applied.
+    pub(super) fn apply(&self, desc: &JobDescription) -> Result<JobDescription> {
+        let mut desc = desc.clone();
+        let Some((program, args)) = self.command.split_first() else {
+            return Err(anyhow!("`command` must not be empty"));
+        };
+        let start_info = &mut desc.start_info;
+        start_info.program = program.clone();
+        start_info.args = (!args.is_empty()).then(|| args.to_vec());
+        start_info.cwd = self.cwd.clone();
+        start_info
+            .env
+            .retain(|key, value| key.to_str().is_none() || value.to_str().is_none());
+        start_info.env.extend(
+            self.env
+                .iter()
+                .map(|(key, value)| (key.into(), value.into())),
+        );
+        start_info.petri_env = self.petri_env;
+        start_info.validate()?;
+
+        desc.name = self.name.clone();
+        desc.namespace = self.namespace.clone();
+        desc.autostart = self.autostart;
+        desc.restart = self.restart()?;
+        desc.depends_on = self.depends_on.clone();
+        (desc.preconditions, desc.precondition_timeout) = self.preconditions()?;
+        desc.readiness = self.readiness()?;
+        desc.stop_priority = self.stop_priority;
+        desc.labels = self.labels.clone();
+        desc.env_file = self.env_file.clone();
+        Ok(desc)
+    }
+
+    /// Returns whether the changes apply to the process of the job, which
+    /// takes a restart.
+    pub(super) fn changes_process(&self, before: &Self) -> bool {
+        self.command != before.command
+            || self.cwd != before.cwd
+            || self.env != before.env
+            || self.env_file != before.env_file
+            || self.petri_env != before.petri_env
+    }
+}
+
+/// Formats the duration so it's parsed back by [`parse_duration`].
+fn format_duration(duration: Duration) -> String {
+    if duration.subsec_millis() == 0 {
+        format!("{}s", duration.as_secs())
+    } else {
+        format!("{}ms", duration.as_millis())
+    }
+}
+
+impl From<RestartMode> for RestartSetting {
+    fn from(value: RestartMode) -> Self {
+        match value {
+            RestartMode::Never => RestartSetting::Never,
+            RestartMode::OnFailure => RestartSetting::OnFailure,
+            RestartMode::Always => RestartSetting::Always,
+            RestartMode::UnlessStopped => RestartSetting::UnlessStopped,
+        }
+    }
+}
+
+impl From<RestartSetting> for RestartMode {
+    fn from(value: RestartSetting) -> Self {
+        match value {
+            RestartSetting::Never => RestartMode::Never,
+            RestartSetting::OnFailure => RestartMode::OnFailure,
+            RestartSetting::Always => RestartMode::Always,
+            RestartSetting::UnlessStopped => RestartMode::UnlessStopped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use petri_core::job_mgr::{JobDescription, Readiness, RestartMode, RestartPolicy};
+    use petri_core::process::StartInfo;
+
+    use super::JobFile;
+
+    #[test]
+    fn test_job_file() {
+        let start_info = StartInfo::builder("/bin/sh", "/srv/app")
+            .args(["-c", "echo $NAME"])
+            .env("NAME", "web app")
+            .build()
+            .unwrap();
+        let desc = JobDescription::builder(start_info)
+            .restart(RestartPolicy {
+                mode: RestartMode::OnFailure,
+                clean_exit_codes: vec![143],
+                jitter: Some("5s..30s".parse().unwrap()),
+                windows: vec!["02:00-04:00".parse().unwrap()],
+            })
+            .name("web".to_owned())
+            .depends_on(["db".to_owned()])
+            .preconditions(["tcp:db:5432".parse().unwrap()])
+            .readiness(Readiness::Delay(Duration::from_millis(1500)))
+            .label("tier", "frontend")
+            .env_file(PathBuf::from(".env"))
+            .build();
+
+        let file = JobFile::from_description(&desc);
+        let rendered = file.render("web");
+        assert_eq!(JobFile::parse(&rendered).unwrap(), file);
+
+        let edited = rendered
+            .replace("echo $NAME", "echo hi")
+            .replace("\"1500ms\"", "\"notify\"");
+        let edited = JobFile::parse(&edited).unwrap();
+        assert!(edited.changes_process(&file));
+        let applied = edited.apply(&desc).unwrap();
+        assert_eq!(
+            applied.start_info.args,
+            Some(vec!["-c".to_owned(), "echo hi".to_owned()])
+        );
+        assert_eq!(applied.readiness, Readiness::Notify);
+        assert_eq!(applied.restart, desc.restart);
+        assert_eq!(applied.preconditions, desc.preconditions);
+        assert_eq!(applied.precondition_timeout, desc.precondition_timeout);
+        assert_eq!(applied.labels, desc.labels);
+        assert_eq!(applied.env_file, desc.env_file);
+
+        assert!(JobFile::parse(&rendered.replace("\"/srv/app\"", "\"srv\"")).is_err());
+        assert!(JobFile::parse(&rendered.replace("autostart", "autostarts")).is_err());
+        assert!(JobFile::parse(&rendered.replace("1500ms", "soon")).is_err());
+        assert!(JobFile::parse(&rendered.replace("02:00-04:00", "02:00")).is_err());
+        assert!(JobFile::parse(&rendered.replace("tcp:db:5432", "tcp:db")).is_err());
+    }
+}
@@ -1,80 +1,259 @@
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
 use anyhow::Result;
-use clap::Args;
+use clap::{ArgGroup, Args};
+use petri_logger::reader::HistoryReader;
+use petri_utils::time::parse_duration;
 use serde::{Deserialize, Serialize};
-use tokio::io::AsyncReadExt;
 use tokio::sync::mpsc;
+use tokio::task;
+use tokio::time::{self, Instant};
 
-use super::{CommandClient, IpcChannel, ResponseHandler};
+use super::run::OUTPUT_DRAIN_TIMEOUT;
+use super::stream::{OutputChunk, OutputCounter};
+use super::{
+    logs, parse_since, CommandClient, IpcChannel, OutputEnd, ResponseHandler, ResponseStream,
+    StreamConsumer, StreamEnd, StreamHandler,
+};
+use crate::cli::EndOfStreamReason;
 use crate::Context as ControlContext;
 
+/// The maximum size of the chunks that log files are sent in.
+const HISTORY_CHUNK_SIZE: usize = 64 * 1024;
+
 #[derive(Args, Serialize, Deserialize, Debug)]
+#[command(group(ArgGroup::new("target").required(true).args(["pid", "jid"])))]
 pub struct LogSubcommand {
     /// Stream logs of a currently running process with the given pid.
-    #[arg(short, long, required = true)]
-    pid: u32,
+    #[arg(short, long)]
+    pid: Option<u32>,
+    /// Print the log files of all the processes of the job with the given
+    /// id or name, with `--history`.
+    #[arg(short, long, requires = "history")]
+    #[serde(default)]
+    jid: Option<String>,
+    /// Stop streaming after the given duration (e.g. `30s`, `5m`).
+    #[arg(short, long, value_parser = parse_duration, conflicts_with_all = ["until_exit", "history"])]
+    duration: Option<Duration>,
+    /// Stream until the process exits (the default).
+    #[arg(long, conflicts_with = "history")]
+    until_exit: bool,
+    /// Print what was logged to the log files, including the rotated ones,
+    /// instead of streaming.
+    #[arg(long)]
+    #[serde(default)]
+    history: bool,
+    /// Only print the last N lines of the history.
+    #[arg(short = 'n', long, value_name = "N", requires = "history")]
+    #[serde(default)]
+    tail: Option<usize>,
+    /// Only print the history from the time, either a duration ago like
+    /// `30m`, or a local time like `18:30`. The lines must start with
+    /// timestamps, like `2024-05-01T18:30:15Z`.
+    #[arg(long, value_name = "TIME", value_parser = parse_since, requires = "history", conflicts_with = "tail")]
+    #[serde(default)]
+    since: Option<i64>,
 }
 
 impl LogSubcommand {
     pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        if self.history {
+            return self.run_history(ctx, channel).await;
+        }
+
+        let pid = self.pid.expect("either pid or jid is required");
+        let mut stream = ResponseStream::<OutputChunk, OutputEnd>::new(channel);
         let (tx, mut rx) = mpsc::unbounded_channel();
-        let Some(cancel_token) = ctx
-            .proc_mgr_handle
-            .attach_output_channel(self.pid, tx)
-            .await
-        else {
-            channel
-                .write_output("failed to stream logs from the process (is it running?)\n")
-                .await?;
-            return Err(anyhow!("failed to stream logs").context("log"));
+        let Some(process) = ctx.proc_mgr_handle.process_with_id(pid).await else {
+            let reason = "failed to stream logs from the process (is it running?)".to_owned();
+            return stream
+                .finish(Err(reason))
+                .await
+                .map_err(|err| err.context("log"));
+        };
+        let cancel_token = process.attach_output_channel(tx).await;
+
+        let deadline = self.duration.map(|duration| Instant::now() + duration);
+        let mut counter = OutputCounter::default();
+        // The stream ends by the exit of the process instead of the end of
+        // its output, which is not closed while its descendants are running.
+        let mut exit_code = None;
+        let end = stream
+            .forward(&mut rx, |contents| counter.item(contents), async {
+                tokio::select! {
+                    _ = sleep_until(deadline) => {}
+                    code = process.wait() => exit_code = Some(code),
+                }
+            })
+            .await;
+
+        let reason = match (end, exit_code) {
+            (StreamEnd::Disconnected, _) => {
+                debug!("ended streaming logs from process {pid} because the peer is closed");
+                return Ok(());
+            }
+            (StreamEnd::Stopped, None) => EndOfStreamReason::DeadlineReached,
+            _ => EndOfStreamReason::ProcessExited,
+        };
+        if reason == EndOfStreamReason::ProcessExited {
+            let end = stream
+                .forward(
+                    &mut rx,
+                    |contents| counter.item(contents),
+                    time::sleep(OUTPUT_DRAIN_TIMEOUT),
+                )
+                .await;
+            if end == StreamEnd::Disconnected {
+                return Ok(());
+            }
+        }
+        drop(cancel_token);
+        debug!("ended streaming logs from process {pid} because {reason}");
+        stream.finish(Ok(counter.finish(reason, exit_code))).await
+    }
+
+    async fn run_history(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        let mut stream = ResponseStream::<OutputChunk, ()>::new(channel);
+        let log_files = match logs::find_log_files(ctx, self.pid, self.jid.as_deref()).await {
+            Ok((_, log_files)) if log_files.is_empty() => {
+                return stream
+                    .finish(Err("no log files are found".to_owned()))
+                    .await;
+            }
+            Ok((_, log_files)) => log_files,
+            Err(err) => {
+                let reason = format!("failed to find log files: {err:#}");
+                return stream.finish(Err(reason)).await;
+            }
+        };
+
+        // Reading the files may block on the disk.
+        let (tail, since) = (self.tail, self.since);
+        let opened = task::spawn_blocking(move || open_history(&log_files, tail, since)).await?;
+        let mut reader = match opened {
+            Ok(reader) => reader,
+            Err(reason) => return stream.finish(Err(reason)).await,
         };
 
-        let mut peer_closed = false;
         loop {
-            // We don't expect to read any bytes here, so we only use a small
-            // buffer to check if the remote peer is closed.
-            let mut buf = [0; 1];
-            let Some(contents) = tokio::select! {
-                contents = rx.recv() => { contents },
-                read_res = channel.stream_mut().read(&mut buf) => {
-                    if read_res.unwrap_or(0) == 0 {
-                        peer_closed = true;
-                        break;
-                    }
-                    warn!("unexpected byte received: {}", buf[0]);
-                    continue;
+            let (returned, chunk) = task::spawn_blocking(move || {
+                let chunk = reader
+                    .next_chunk(HISTORY_CHUNK_SIZE)
+                    .map(|chunk| chunk.map(OutputChunk::new));
+                (reader, chunk)
+            })
+            .await?;
+            reader = returned;
+            let chunk = match chunk {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(err) => {
+                    let reason = format!("failed to read log files: {err}");
+                    return stream.finish(Err(reason)).await;
                 }
-            } else {
-                break;
             };
-
-            // TODO: support transferring of raw buffer.
-            let s = String::from_utf8_lossy(&contents);
-            if channel.write_output(&s).await.is_err() {
-                peer_closed = true;
-                break;
+            if stream.send_item(chunk).await.is_err() {
+                debug!("ended sending log history because the peer is closed");
+                return Ok(());
             }
         }
+        stream.finish(Ok(())).await
+    }
+}
 
-        drop(cancel_token);
+/// Opens the log files and moves to where the history starts.
+fn open_history(
+    log_files: &[PathBuf],
+    tail: Option<usize>,
+    since: Option<i64>,
+) -> Result<HistoryReader, String> {
+    let mut reader =
+        HistoryReader::open(log_files).map_err(|err| format!("failed to open log files: {err}"))?;
+    if let Some(lines) = tail {
+        reader
+            .seek_tail(lines)
+            .map_err(|err| format!("failed to read log files: {err}"))?;
+    }
+    if let Some(since) = since {
+        let found = reader
+            .seek_time(since)
+            .map_err(|err| format!("failed to read log files: {err}"))?;
+        if !found {
+            return Err("the lines of the log files don't start with timestamps".to_owned());
+        }
+    }
+    Ok(reader)
+}
+
+/// Sleeps until the deadline, or forever if there is no deadline.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
 
-        if peer_closed {
-            debug!(
-                "ended streaming logs from process {} because the peer is closed",
-                self.pid
-            );
+impl CommandClient for LogSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        if self.history {
+            Some(Box::new(StreamHandler::new(HistoryConsumer)))
         } else {
-            debug!(
-                "ended streaming logs from process {} because it exited",
-                self.pid
-            );
+            Some(Box::new(StreamHandler::new(LogConsumer)))
         }
+    }
+}
+
+struct LogConsumer;
+
+impl StreamConsumer for LogConsumer {
+    type Item = OutputChunk;
+    type Result = OutputEnd;
 
+    fn on_item(&mut self, item: OutputChunk) -> Result<()> {
+        item.write_to(io::stdout().lock())?;
         Ok(())
     }
+
+    fn on_finish(&mut self, result: Result<OutputEnd, String>) -> Result<i32> {
+        match result {
+            Ok(end) => {
+                match end.exit_code {
+                    Some(code) => eprintln!(
+                        "stream ended because {} with code {code} ({end})",
+                        end.reason
+                    ),
+                    None => eprintln!("stream ended because {} ({end})", end.reason),
+                }
+                Ok(0)
+            }
+            Err(reason) => {
+                println!("{reason}");
+                Ok(1)
+            }
+        }
+    }
 }
 
-impl CommandClient for LogSubcommand {
-    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
-        None
+struct HistoryConsumer;
+
+impl StreamConsumer for HistoryConsumer {
+    type Item = OutputChunk;
+    type Result = ();
+
+    fn on_item(&mut self, item: OutputChunk) -> Result<()> {
+        item.write_to(io::stdout().lock())?;
+        Ok(())
+    }
+
+    fn on_finish(&mut self, result: Result<(), String>) -> Result<i32> {
+        match result {
+            Ok(()) => Ok(0),
+            Err(reason) => {
+                println!("{reason}");
+                Ok(1)
+            }
+        }
     }
 }
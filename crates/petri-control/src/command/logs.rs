@@ -0,0 +1,50 @@
+mod fetch;
+mod remote;
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+
+use crate::Context as ControlContext;
+
+pub use remote::RemoteSubcommand;
+
+#[derive(Subcommand, Serialize, Deserialize, Debug)]
+#[serde(tag = "name", content = "args", rename_all = "kebab-case")]
+pub enum LogsSubcommand {
+    /// Download log files of a process or job, resuming partial downloads
+    Fetch(fetch::FetchSubcommand),
+    /// Browse log files archived to the object storage
+    #[command(subcommand)]
+    Remote(RemoteSubcommand),
+}
+
+/// Returns the log directory and the log files in it of the running
+/// process with the pid, or of all the processes of the job.
+pub(super) async fn find_log_files(
+    ctx: &ControlContext,
+    pid: Option<u32>,
+    jid: Option<&str>,
+) -> Result<(PathBuf, Vec<PathBuf>)> {
+    if let Some(pid) = pid {
+        let Some(process) = ctx.proc_mgr_handle.process_with_id(pid).await else {
+            return Err(anyhow!("process {pid} is not running"));
+        };
+        let Some(log_path) = process.log_path() else {
+            return Err(anyhow!("process {pid} is not logging to files"));
+        };
+        return Ok((log_path.to_owned(), process.log_files()?));
+    }
+
+    let jid = jid.expect("either pid or jid is required");
+    let jid = ctx.job_mgr_handle.resolve_job_id(jid).await?;
+    let Some(job) = ctx.job_mgr_handle.job_with_id(&jid).await else {
+        return Err(anyhow!("job `{jid}` is not found"));
+    };
+    let Some(log_path) = job.description().start_info.log.path.clone() else {
+        return Err(anyhow!("job `{jid}` is not logging to files"));
+    };
+    Ok((log_path, job.log_files()?))
+}
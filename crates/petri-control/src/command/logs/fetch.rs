@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind as IoErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use clap::{ArgGroup, Args};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio::fs::File as AsyncFile;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::cli::IpcChannel;
+use crate::command::{
+    CommandClient, ResponseHandler, ResponseStream, StreamConsumer, StreamHandler,
+};
+use crate::Context as ControlContext;
+
+/// The size of the chunks that files are sent in.
+const CHUNK_SIZE: u64 = 256 * 1024;
+
+#[derive(Serialize, Deserialize, Debug)]
+enum FetchItem {
+    /// Starts sending a file from `offset`, followed by its chunks.
+    File {
+        name: String,
+        size: u64,
+        offset: u64,
+        /// The hex-encoded SHA-1 digest of the first `size` bytes.
+        digest: String,
+    },
+    /// A base64-encoded chunk of the current file.
+    Chunk(String),
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+#[command(group(ArgGroup::new("target").required(true).args(["pid", "jid"])))]
+pub struct FetchSubcommand {
+    /// Fetch log files of the running process with the given pid.
+    #[arg(short, long)]
+    pid: Option<u32>,
+    /// Fetch log files of all the processes of the job with the given id
+    /// or name.
+    #[arg(short, long)]
+    jid: Option<String>,
+    /// The local directory to save the files to.
+    #[arg(short, long, default_value = ".")]
+    output: PathBuf,
+    /// Download all the files again instead of resuming.
+    #[arg(long)]
+    no_resume: bool,
+    /// Sizes of the files that already exist in the output directory,
+    /// collected by the client.
+    #[arg(skip)]
+    local_sizes: HashMap<String, u64>,
+}
+
+impl FetchSubcommand {
+    pub(in crate::command) async fn run(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+    ) -> Result<()> {
+        let mut stream = ResponseStream::<FetchItem, ()>::new(channel);
+        let found = match super::find_log_files(ctx, self.pid, self.jid.as_deref()).await {
+            Ok((log_path, paths)) if !paths.is_empty() => Ok((log_path, paths)),
+            Ok(_) => Err("no log files are found".to_owned()),
+            Err(err) => Err(format!("failed to find log files: {err:#}")),
+        };
+        let (log_path, paths) = match found {
+            Ok(found) => found,
+            Err(reason) => {
+                return stream
+                    .finish(Err(reason))
+                    .await
+                    .map_err(|err| err.context("logs fetch"));
+            }
+        };
+
+        for path in paths {
+            // Keep the directories created by the template of log names.
+            let Some(name) = path
+                .strip_prefix(&log_path)
+                .ok()
+                .and_then(|name| name.to_str())
+            else {
+                continue;
+            };
+            if let Err(err) = self.send_file(&mut stream, &path, name).await {
+                // The peer may be closed, so don't bother telling it.
+                return Err(err.context(format!("failed to send `{}`", path.display())));
+            }
+        }
+
+        stream.finish(Ok(())).await
+    }
+
+    async fn send_file(
+        &self,
+        stream: &mut ResponseStream<'_, FetchItem, ()>,
+        path: &Path,
+        name: &str,
+    ) -> Result<()> {
+        let mut file = AsyncFile::open(path).await?;
+
+        // The file may still be written, so only send the part that is
+        // hashed here.
+        let size = file.metadata().await?.len();
+        let mut hasher = Sha1::new();
+        let mut buf = vec![0; CHUNK_SIZE as usize];
+        let mut hashed = 0;
+        while hashed < size {
+            let len = buf.len().min((size - hashed) as usize);
+            file.read_exact(&mut buf[..len]).await?;
+            hasher.update(&buf[..len]);
+            hashed += len as u64;
+        }
+
+        // Start over if the local file can't be a part of this one.
+        let offset = self
+            .local_sizes
+            .get(name)
+            .copied()
+            .filter(|local_size| *local_size <= size)
+            .unwrap_or(0);
+        stream
+            .send_item(FetchItem::File {
+                name: name.to_owned(),
+                size,
+                offset,
+                digest: format!("{:x}", hasher.finalize()),
+            })
+            .await?;
+
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut sent = offset;
+        while sent < size {
+            let len = buf.len().min((size - sent) as usize);
+            file.read_exact(&mut buf[..len]).await?;
+            stream
+                .send_item(FetchItem::Chunk(BASE64.encode(&buf[..len])))
+                .await?;
+            sent += len as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Collects the sizes of the files in the directory and its
+    /// subdirectories, by their paths relative to the output directory.
+    fn collect_local_sizes(&mut self, dir: &Path) -> Result<()> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == IoErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                self.collect_local_sizes(&path)?;
+                continue;
+            }
+            if !metadata.is_file() {
+                continue;
+            }
+            if let Some(name) = path.strip_prefix(&self.output).ok().and_then(Path::to_str) {
+                self.local_sizes.insert(name.to_owned(), metadata.len());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CommandClient for FetchSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        Some(Box::new(StreamHandler::new(FetchConsumer {
+            output: self.output.clone(),
+            current: None,
+            fetched: 0,
+            failed: 0,
+        })))
+    }
+
+    fn prepare(&mut self) -> Result<()> {
+        if self.no_resume {
+            return Ok(());
+        }
+
+        let output = self.output.clone();
+        self.collect_local_sizes(&output)
+            .map_err(|err| err.context(format!("failed to read `{}`", output.display())))
+    }
+}
+
+struct FetchConsumer {
+    output: PathBuf,
+    current: Option<Download>,
+    fetched: usize,
+    failed: usize,
+}
+
+struct Download {
+    name: String,
+    path: PathBuf,
+    file: File,
+    digest: String,
+}
+
+impl FetchConsumer {
+    fn start(&mut self, name: String, size: u64, offset: u64, digest: String) -> Result<()> {
+        // Don't let the server write outside the output directory.
+        let relative_path = Path::new(&name);
+        if relative_path
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            return Err(anyhow!("invalid file name `{name}`"));
+        }
+
+        let path = self.output.join(relative_path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(offset == 0)
+            .open(&path)?;
+        if offset == size && size > 0 {
+            println!("{name} is already fetched");
+        } else if offset > 0 {
+            file.set_len(offset)?;
+            file.seek(SeekFrom::End(0))?;
+            println!("resuming {name} from {offset} of {size} bytes");
+        } else {
+            println!("fetching {name} ({size} bytes)");
+        }
+
+        self.current = Some(Download {
+            name,
+            path,
+            file,
+            digest,
+        });
+        Ok(())
+    }
+
+    /// Verifies the file that has been downloaded, and removes it if it's
+    /// corrupted so it will be downloaded from the start next time.
+    fn finish(&mut self) -> Result<()> {
+        let Some(mut download) = self.current.take() else {
+            return Ok(());
+        };
+        download.file.flush()?;
+        drop(download.file);
+
+        if digest_of(&download.path)? == download.digest {
+            self.fetched += 1;
+            return Ok(());
+        }
+
+        self.failed += 1;
+        fs::remove_file(&download.path)?;
+        eprintln!(
+            "{} is corrupted and removed, fetch again to download it from the start",
+            download.name
+        );
+        Ok(())
+    }
+}
+
+impl StreamConsumer for FetchConsumer {
+    type Item = FetchItem;
+    type Result = ();
+
+    fn on_item(&mut self, item: FetchItem) -> Result<()> {
+        match item {
+            FetchItem::File {
+                name,
+                size,
+                offset,
+                digest,
+            } => {
+                self.finish()?;
+                self.start(name, size, offset, digest)?;
+            }
+            FetchItem::Chunk(chunk) => {
+                let Some(download) = self.current.as_mut() else {
+                    return Err(anyhow!("received a chunk before any file"));
+                };
+                download.file.write_all(&BASE64.decode(chunk)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn on_finish(&mut self, result: Result<(), String>) -> Result<i32> {
+        if let Err(reason) = result {
+            println!("{reason}");
+            return Ok(1);
+        }
+
+        self.finish()?;
+        println!(
+            "fetched {} files to {}",
+            self.fetched,
+            self.output.display()
+        );
+        Ok(if self.failed == 0 { 0 } else { 1 })
+    }
+}
+
+fn digest_of(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha1::new();
+    let mut buf = vec![0; CHUNK_SIZE as usize];
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
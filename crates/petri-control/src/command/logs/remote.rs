@@ -0,0 +1,11 @@
+mod ls;
+
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+
+#[derive(Subcommand, Serialize, Deserialize, Debug)]
+#[serde(tag = "name", content = "args", rename_all = "kebab-case")]
+pub enum RemoteSubcommand {
+    /// List the archived log files of a job
+    Ls(ls::ListSubcommand),
+}
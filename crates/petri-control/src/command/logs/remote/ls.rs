@@ -0,0 +1,103 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use clap::Args;
+use petri_utils::console_table::{self, ColumnCollection};
+use petri_utils::FormattedBytes;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::{IpcChannel, OwnedIpcMessagePacket};
+use crate::command::{CommandClient, ResponseHandler};
+use crate::{ArchivedLog, Context as ControlContext, LogArchive};
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ListResponse {
+    logs: Vec<ArchivedLog>,
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct ListSubcommand {
+    /// Id or name of the job. Jobs that have been removed can be given by
+    /// their full ids.
+    job: String,
+}
+
+impl ListSubcommand {
+    pub(in crate::command) async fn run(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+    ) -> Result<()> {
+        let logs = match &ctx.log_archive {
+            Some(archive) => Self::list(archive.as_ref(), ctx, &self.job).await,
+            None => Err(anyhow!("log archiving is not configured")),
+        };
+        let logs = match logs {
+            Ok(logs) => logs,
+            Err(err) => {
+                channel.write_output(&format!("{err:#}\n")).await?;
+                return Err(err.context("logs remote ls"));
+            }
+        };
+
+        channel.write_response(ListResponse { logs }).await?;
+        Ok(())
+    }
+
+    async fn list(
+        archive: &dyn LogArchive,
+        ctx: &ControlContext,
+        query: &str,
+    ) -> Result<Vec<ArchivedLog>> {
+        let list = |jid: String| async move {
+            archive
+                .list(&jid)
+                .await
+                .map_err(|err| err.context("failed to list the archived logs"))
+        };
+        match ctx.job_mgr_handle.resolve_job_id(query).await {
+            Ok(jid) => list(jid).await,
+            // Logs outlive their jobs, so look the query up as an id in the
+            // archive before reporting that the job is not found.
+            Err(err) => match list(query.to_owned()).await {
+                Ok(logs) if !logs.is_empty() => Ok(logs),
+                _ => Err(err),
+            },
+        }
+    }
+}
+
+impl CommandClient for ListSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        Some(Box::new(ListResponseHandler))
+    }
+}
+
+struct ListResponseHandler;
+
+#[async_trait]
+impl ResponseHandler for ListResponseHandler {
+    async fn handle_response(
+        &mut self,
+        resp: OwnedIpcMessagePacket<serde_json::Value>,
+    ) -> Result<()> {
+        let resp: ListResponse = resp.into_response().expect("expected a response")?;
+
+        let modified_column = console_table::ColumnOptions::new("MODIFIED").spacing(2);
+        let size_column = console_table::ColumnOptions::new("SIZE")
+            .alignment(console_table::Alignment::Right)
+            .spacing(2);
+        let key_column = console_table::ColumnOptions::new("KEY");
+
+        let mut table_builder = (modified_column, size_column, key_column).into_table_builder();
+        for log in resp.logs {
+            table_builder.push_row(
+                log.last_modified,
+                FormattedBytes::new(log.size).to_string(),
+                log.key,
+            );
+        }
+
+        println!("{table_builder}");
+        Ok(())
+    }
+}
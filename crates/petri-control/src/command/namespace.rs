@@ -0,0 +1,74 @@
+//! Namespaces group the jobs of a project. Jobs are created in the
+//! namespace named after their directory (the directory of the file for
+//! `apply`), and commands act on the namespace of the client's directory
+//! unless told otherwise.
+
+use std::path::Path;
+
+use clap::Args;
+use petri_core::job_mgr::{Job, DEFAULT_NAMESPACE};
+use serde::{Deserialize, Serialize};
+
+use crate::cli::CLIENT_ENV;
+use crate::Context as ControlContext;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub(super) struct NamespaceArgs {
+    /// The namespace of the jobs, defaults to the one of the current
+    /// directory.
+    #[arg(short = 'n', long)]
+    namespace: Option<String>,
+    /// Act on the jobs of all namespaces.
+    #[arg(long, conflicts_with = "namespace")]
+    all_namespaces: bool,
+}
+
+impl NamespaceArgs {
+    /// Returns the selected namespace, or `None` if all are selected.
+    pub(super) fn selected(&self) -> Option<String> {
+        if self.all_namespaces {
+            return None;
+        }
+        let namespace = self.namespace.clone().unwrap_or_else(|| {
+            let cwd = CLIENT_ENV
+                .try_with(|env| env.cwd().to_owned())
+                .unwrap_or_default();
+            namespace_of(&cwd)
+        });
+        Some(namespace)
+    }
+
+    pub(super) fn is_all(&self) -> bool {
+        self.all_namespaces
+    }
+
+    /// Returns the jobs of the selected namespaces.
+    pub(super) async fn jobs(&self, ctx: &ControlContext) -> Vec<Job> {
+        match self.selected() {
+            Some(namespace) => ctx.job_mgr_handle.jobs_in_namespace(&namespace).await,
+            None => ctx.job_mgr_handle.jobs().await,
+        }
+    }
+}
+
+/// Returns the namespace of the jobs created in the directory, which is
+/// its name.
+pub(super) fn namespace_of(dir: &str) -> String {
+    Path::new(dir)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map_or_else(|| DEFAULT_NAMESPACE.to_owned(), ToOwned::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::namespace_of;
+
+    #[test]
+    fn test_namespace_of() {
+        assert_eq!(namespace_of("/srv/shop"), "shop");
+        assert_eq!(namespace_of("/srv/shop/"), "shop");
+        assert_eq!(namespace_of("/"), "default");
+        assert_eq!(namespace_of(""), "default");
+    }
+}
@@ -0,0 +1,11 @@
+mod ready;
+
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+
+#[derive(Subcommand, Serialize, Deserialize, Debug)]
+#[serde(tag = "name", content = "args", rename_all = "kebab-case")]
+pub enum NotifySubcommand {
+    /// Tell the server that the process running this command is ready.
+    Ready(ready::ReadySubcommand),
+}
@@ -0,0 +1,69 @@
+use anyhow::Result;
+use clap::Args;
+use petri_core::process::SPAWN_ID_ENV;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::IpcChannel;
+use crate::command::{CommandClient, ResponseHandler};
+use crate::Context as ControlContext;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct ReadySubcommand {
+    /// Notify for the process with the given pid, instead of the one running this command.
+    #[arg(short, long)]
+    pid: Option<u32>,
+    /// The spawn id of the process running this command, collected by the
+    /// client from its environment.
+    #[arg(skip)]
+    spawn_id: Option<u64>,
+}
+
+impl ReadySubcommand {
+    pub(in crate::command) async fn run(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+    ) -> Result<()> {
+        let process = match (self.pid, self.spawn_id) {
+            (Some(pid), _) => ctx.proc_mgr_handle.process_with_id(pid).await,
+            (None, Some(spawn_id)) => ctx.proc_mgr_handle.process_with_spawn_id(spawn_id).await,
+            (None, None) => None,
+        };
+        let Some(process) = process else {
+            channel
+                .write_output("the process is not managed by the server\n")
+                .await?;
+            return Err(anyhow!("process is not found").context("notify ready"));
+        };
+
+        process.mark_ready();
+        debug!("process {} notified that it's ready", process.id());
+        Ok(())
+    }
+}
+
+impl CommandClient for ReadySubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        None
+    }
+
+    fn prepare(&mut self) -> Result<()> {
+        if self.pid.is_some() {
+            return Ok(());
+        }
+        // Descendants of the process inherit the id, so scripts run by it
+        // can notify too.
+        match std::env::var(SPAWN_ID_ENV)
+            .ok()
+            .and_then(|id| id.parse().ok())
+        {
+            Some(spawn_id) => {
+                self.spawn_id = Some(spawn_id);
+                Ok(())
+            }
+            None => Err(anyhow!(
+                "not run by a process managed by petri, use `--pid` to notify for one"
+            )),
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::os::unix::process::ExitStatusExt;
+use std::process::{self, Stdio};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use super::{CommandClient, IpcChannel, OwnedIpcMessagePacket, ResponseHandler};
+use crate::cli::CLIENT_ENV;
+use crate::Context as ControlContext;
+
+/// The variables the command is run with, named like the ones of the
+/// `on_process_exit` hook.
+const PID_ENV: &str = "PETRI_PID";
+const EXIT_CODE_ENV: &str = "PETRI_EXIT_CODE";
+
+#[derive(Serialize, Deserialize, Debug)]
+enum OnExitResponse {
+    Exited(i32),
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct OnExitSubcommand {
+    /// Run the command when the process with the given pid exits.
+    #[arg(short, long)]
+    pid: u32,
+    /// Wait for the process to exit and run the command on the client,
+    /// exiting with its exit status, instead of leaving it to the server.
+    #[arg(short, long)]
+    wait: bool,
+    /// The command to run, with `PETRI_PID` and `PETRI_EXIT_CODE` set.
+    #[arg(required = true, last = true)]
+    cmd_line: Vec<String>,
+}
+
+impl OnExitSubcommand {
+    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        let Some(process) = ctx.proc_mgr_handle.process_with_id(self.pid).await else {
+            channel
+                .write_output(&format!("process with id `{}` is not found\n", self.pid))
+                .await?;
+            return Err(anyhow!("process is not found").context("on-exit"));
+        };
+
+        if self.wait {
+            let exit_code = process.wait().await;
+            channel
+                .write_response(OnExitResponse::Exited(exit_code))
+                .await?;
+            return Ok(());
+        }
+
+        let (cwd, env_vars) = CLIENT_ENV
+            .try_with(|env| (env.cwd().to_owned(), env.env().clone()))
+            .expect("no `ClientEnv` set in the calling context");
+        let pid = self.pid;
+        let mut command = Command::new(&self.cmd_line[0]);
+        command
+            .args(&self.cmd_line[1..])
+            .current_dir(cwd)
+            .env_clear()
+            .envs(env_vars)
+            .env(PID_ENV, pid.to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        let cmd_line = self.cmd_line.join(" ");
+        tokio::spawn(async move {
+            let exit_code = process.wait().await;
+            command.env(EXIT_CODE_ENV, exit_code.to_string());
+            match command.status().await {
+                Ok(status) if status.success() => {}
+                Ok(status) => {
+                    warn!("on-exit command `{cmd_line}` of process {pid} exited with {status}")
+                }
+                Err(err) => {
+                    warn!("failed to run on-exit command `{cmd_line}` of process {pid}: {err:?}")
+                }
+            }
+        });
+
+        channel
+            .write_output(&format!("the command will run when process {pid} exits\n"))
+            .await?;
+        Ok(())
+    }
+
+    /// Returns whether the client waits for the process to exit, which can
+    /// take arbitrarily long.
+    pub(super) fn is_unbounded(&self) -> bool {
+        self.wait
+    }
+}
+
+impl CommandClient for OnExitSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        if !self.wait {
+            return None;
+        }
+        Some(Box::new(OnExitResponseHandler {
+            pid: self.pid,
+            cmd_line: self.cmd_line.clone(),
+            exit_status: 0,
+        }))
+    }
+}
+
+/// Runs the command once the server tells the process exited.
+struct OnExitResponseHandler {
+    pid: u32,
+    cmd_line: Vec<String>,
+    exit_status: i32,
+}
+
+#[async_trait]
+impl ResponseHandler for OnExitResponseHandler {
+    async fn handle_response(
+        &mut self,
+        resp: OwnedIpcMessagePacket<serde_json::Value>,
+    ) -> Result<()> {
+        let OnExitResponse::Exited(exit_code) =
+            resp.into_response().expect("expected a response")?;
+
+        let status = process::Command::new(&self.cmd_line[0])
+            .args(&self.cmd_line[1..])
+            .env(PID_ENV, self.pid.to_string())
+            .env(EXIT_CODE_ENV, exit_code.to_string())
+            .status();
+        self.exit_status = match status {
+            // Killed by a signal, reported like shells do.
+            Ok(status) => status
+                .code()
+                .or_else(|| status.signal().map(|signal| 128 + signal))
+                .unwrap_or(1),
+            Err(err) => {
+                eprintln!("failed to run `{}`: {err}", self.cmd_line[0]);
+                1
+            }
+        };
+        Ok(())
+    }
+
+    fn exit_status(&self) -> i32 {
+        self.exit_status
+    }
+}
@@ -0,0 +1,89 @@
+//! Paths given by clients, which are relative to the working directories
+//! of the clients rather than the one of the server.
+
+use std::fs;
+use std::io::ErrorKind as IoErrorKind;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Result;
+
+/// Makes the path absolute against the working directory of the client,
+/// and removes the `.` and `..` components without touching the disk.
+///
+/// Backslashes are kept, since they are valid in file names on Unix.
+pub(super) fn resolve(cwd: &str, path: &Path) -> PathBuf {
+    let mut resolved = PathBuf::new();
+    for component in Path::new(cwd).join(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                // `..` of the root is the root.
+                if resolved.parent().is_some() {
+                    resolved.pop();
+                }
+            }
+            component => resolved.push(component),
+        }
+    }
+    resolved
+}
+
+/// Resolves the directory given by the client with [`resolve`], and checks
+/// that it exists and that the server can write to it if needed. Returns
+/// the canonical path of the directory.
+pub(super) fn resolve_dir(cwd: &str, path: &Path, what: &str, writable: bool) -> Result<PathBuf> {
+    let resolved = resolve(cwd, path);
+    let dir = match fs::canonicalize(&resolved) {
+        Ok(dir) => dir,
+        Err(err) if err.kind() == IoErrorKind::NotFound => {
+            return Err(anyhow!("{what} `{}` does not exist", resolved.display()));
+        }
+        Err(err) => {
+            return Err(anyhow!(
+                "failed to access {what} `{}`: {err}",
+                resolved.display()
+            ));
+        }
+    };
+    if !dir.is_dir() {
+        return Err(anyhow!("{what} `{}` is not a directory", dir.display()));
+    }
+    if writable && !is_writable(&dir) {
+        return Err(anyhow!(
+            "{what} `{}` is not writable by the server",
+            dir.display()
+        ));
+    }
+    Ok(dir)
+}
+
+/// Checks the access of the server itself, which is who writes there.
+fn is_writable(path: &Path) -> bool {
+    let mut bytes = path.as_os_str().as_bytes().to_vec();
+    bytes.push(0);
+    unsafe { libc::access(bytes.as_ptr().cast(), libc::W_OK) == 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::resolve;
+
+    #[test]
+    fn test_resolve() {
+        let resolve = |cwd, path| resolve(cwd, Path::new(path));
+        assert_eq!(
+            resolve("/home/me", "./logs"),
+            PathBuf::from("/home/me/logs")
+        );
+        assert_eq!(
+            resolve("/home/me", "../you/logs/."),
+            PathBuf::from("/home/you/logs")
+        );
+        assert_eq!(resolve("/home/me", "/var/log"), PathBuf::from("/var/log"));
+        assert_eq!(resolve("/home", "../../.."), PathBuf::from("/"));
+        assert_eq!(resolve("/srv", "a\\b"), PathBuf::from("/srv/a\\b"));
+    }
+}
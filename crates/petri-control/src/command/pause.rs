@@ -0,0 +1,42 @@
+use anyhow::Result;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+
+use super::{CommandClient, IpcChannel, ResponseHandler};
+use crate::Context as ControlContext;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct PauseSubcommand {
+    /// Pause the process with the given pid, along with its descendants.
+    #[arg(short, long)]
+    pid: u32,
+}
+
+impl PauseSubcommand {
+    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        match ctx.proc_mgr_handle.pause_process(self.pid).await {
+            Ok(descendants) => {
+                let mut output = format!("process {} paused", self.pid);
+                if descendants > 0 {
+                    output.push_str(&format!(" with {descendants} descendant process(es)"));
+                }
+                output.push('\n');
+                channel.write_output(&output).await?;
+            }
+            Err(err) => {
+                channel
+                    .write_output(&format!("failed to pause the process: {err}\n"))
+                    .await?;
+                return Err(err.context("pause"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl CommandClient for PauseSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        None
+    }
+}
@@ -0,0 +1,25 @@
+use anyhow::Result;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+
+use super::{CommandClient, IpcChannel, ResponseHandler};
+use crate::Context as ControlContext;
+
+/// The response of `ping`, which scripts can match on.
+pub const PONG: &str = "pong";
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct PingSubcommand;
+
+impl PingSubcommand {
+    pub(super) async fn run(self, _ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        channel.write_output(&format!("{PONG}\n")).await?;
+        Ok(())
+    }
+}
+
+impl CommandClient for PingSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        None
+    }
+}
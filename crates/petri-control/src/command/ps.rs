@@ -1,20 +1,34 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use async_trait::async_trait;
-use chrono::DateTime;
-use clap::Args;
-use petri_utils::console_table::{self, ColumnCollection};
+use chrono::{DateTime, Local};
+use clap::{Args, ValueEnum};
+use petri_core::job_mgr::Job;
+use petri_core::process::ExitReason;
+use petri_utils::console_table::{self, ColumnCollection, ColumnOptions, DynamicBuilder};
+use petri_utils::id::short_id;
 use petri_utils::time::FormattedUptime;
 use serde::{Deserialize, Serialize};
 
+use super::namespace::NamespaceArgs;
 use super::{CommandClient, IpcChannel, OwnedIpcMessagePacket, ResponseHandler};
+use crate::cli::{ClientEnv, RenderHints, CLIENT_ENV};
 use crate::Context as ControlContext;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct PsResponse {
     processes: Vec<Process>,
+    #[serde(default)]
+    orphans: Vec<Orphan>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Orphan {
+    pid: u32,
+    owner_pid: Option<u32>,
+    cmd: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -25,6 +39,48 @@ struct Process {
     created_at_ts: (i64, u32),
     uptime_secs: u64,
     last_exit_code: Option<i32>,
+    #[serde(default)]
+    paused: bool,
+    #[serde(default)]
+    oom_killed: bool,
+    /// Whether the job was stopped for being idle.
+    #[serde(default)]
+    stopped_idle: bool,
+    /// Whether the job was stopped by a checkpoint.
+    #[serde(default)]
+    checkpointed: bool,
+    /// When the process is scheduled to stop, as a Unix timestamp.
+    #[serde(default)]
+    expires_at_ts: Option<i64>,
+    /// What the job is waiting for before starting, like `postgres:5432`.
+    #[serde(default)]
+    waiting_for: Option<String>,
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+    #[serde(default)]
+    note: Option<String>,
+}
+
+/// The default columns of the processes table.
+const DEFAULT_COLUMNS: [PsColumn; 5] = [
+    PsColumn::Pid,
+    PsColumn::Jid,
+    PsColumn::Status,
+    PsColumn::Expires,
+    PsColumn::Cmd,
+];
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum PsColumn {
+    Pid,
+    Jid,
+    Status,
+    Expires,
+    Cmd,
+    /// The tags set by `petri tag`.
+    Tags,
+    /// The note set by `petri tag --note`.
+    Note,
 }
 
 #[derive(Args, Serialize, Deserialize, Debug)]
@@ -32,19 +88,45 @@ pub struct PsSubcommand {
     /// Show all jobs (default shows just running)
     #[arg(short = 'a', long = "all")]
     show_all: bool,
+    /// Also show orphaned processes adopted by the server
+    #[arg(long = "orphans")]
+    show_orphans: bool,
+    /// Show the columns in the order, like `pid,status,note`
+    #[arg(long, value_enum, value_name = "COLUMNS", value_delimiter = ',')]
+    format: Option<Vec<PsColumn>>,
+    #[command(flatten)]
+    namespace: NamespaceArgs,
 }
 
 impl PsSubcommand {
     pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
         let now = Instant::now();
 
-        let jobs = ctx.job_mgr_handle.jobs().await;
+        let jobs = self.namespace.jobs(ctx).await;
+        // Processes of jobs in other namespaces are hidden, while the ones
+        // not started for jobs are always shown.
+        let hidden_pids: HashSet<_> = if self.namespace.is_all() {
+            HashSet::new()
+        } else {
+            let jids: HashSet<_> = jobs.iter().map(|job| job.id()).collect();
+            ctx.job_mgr_handle
+                .jobs()
+                .await
+                .iter()
+                .filter(|job| !jids.contains(job.id()))
+                .filter_map(|job| job.pid())
+                .collect()
+        };
         let running_processes = ctx.proc_mgr_handle.processes().await;
 
         let mut processes = vec![];
         let mut pid_index: HashMap<u32, usize> = HashMap::new();
         for proc in running_processes {
+            if hidden_pids.contains(&proc.id()) {
+                continue;
+            }
             let local_started_at = proc.local_started_at();
+            let annotations = proc.annotations();
             pid_index.insert(proc.id(), processes.len());
             processes.push(Process {
                 jid: None,
@@ -56,6 +138,14 @@ impl PsSubcommand {
                 ),
                 uptime_secs: (now - proc.started_at()).as_secs(),
                 last_exit_code: None,
+                paused: proc.is_paused().await,
+                oom_killed: false,
+                stopped_idle: false,
+                checkpointed: false,
+                expires_at_ts: None,
+                waiting_for: None,
+                tags: annotations.tags,
+                note: annotations.note,
             });
         }
 
@@ -68,32 +158,101 @@ impl PsSubcommand {
                 let proc = &mut processes[*idx];
                 proc.jid = jid;
                 proc.created_at_ts = (created_at.timestamp(), created_at.timestamp_subsec_nanos());
+            } else if let Some(waiting_for) = ctx.job_mgr_handle.waiting_for(job.id()) {
+                // Jobs about to start are shown like running ones.
+                processes.push(Process {
+                    waiting_for: Some(waiting_for),
+                    ..Process::not_running(&job)
+                });
             } else if self.show_all {
                 // Also add the non-started jobs if `-a` flags is specified.
-                processes.push(Process {
-                    jid,
-                    pid: None,
-                    cmd: job.description().start_info.cmd(),
-                    created_at_ts: (created_at.timestamp(), created_at.timestamp_subsec_nanos()),
-                    uptime_secs: 0,
-                    last_exit_code: job.last_exit_code(),
-                })
+                processes.push(Process::not_running(&job));
             }
         }
 
-        let resp = PsResponse { processes };
+        let render = CLIENT_ENV.with(ClientEnv::render);
+        for proc in &mut processes {
+            proc.cmd = render.truncate_cmd(&proc.cmd).into_owned();
+            proc.expires_at_ts = ctx
+                .job_mgr_handle
+                .scheduled_stop(proc.pid, proc.jid.as_deref())
+                .map(|deadline| deadline.timestamp());
+            proc.jid = proc.jid.as_deref().map(short_id);
+        }
+
+        let orphans = if self.show_orphans {
+            ctx.proc_mgr_handle
+                .orphans()
+                .into_iter()
+                .map(|orphan| Orphan {
+                    pid: orphan.pid,
+                    owner_pid: orphan.owner_pid,
+                    cmd: render.truncate_cmd(&orphan.cmd).into_owned(),
+                })
+                .collect()
+        } else {
+            vec![]
+        };
+
+        let resp = PsResponse { processes, orphans };
         channel.write_response(resp).await?;
         Ok(())
     }
 }
 
+impl Process {
+    /// Returns the item of a job whose process is not running.
+    fn not_running(job: &Job) -> Self {
+        let created_at = job.created_at();
+        Process {
+            jid: Some(job.id().to_owned()),
+            pid: None,
+            cmd: job.description().start_info.cmd(),
+            created_at_ts: (created_at.timestamp(), created_at.timestamp_subsec_nanos()),
+            uptime_secs: 0,
+            last_exit_code: job.last_exit_code(),
+            paused: false,
+            oom_killed: job.last_exit_reason() == Some(ExitReason::Oom),
+            stopped_idle: job.last_exit_reason() == Some(ExitReason::Idle),
+            checkpointed: job.last_exit_reason() == Some(ExitReason::Checkpointed),
+            expires_at_ts: None,
+            waiting_for: None,
+            tags: BTreeMap::new(),
+            note: None,
+        }
+    }
+}
+
 impl CommandClient for PsSubcommand {
     fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
-        Some(Box::new(PsResponseHandler))
+        Some(Box::new(PsResponseHandler {
+            show_orphans: self.show_orphans,
+            columns: self
+                .format
+                .clone()
+                .unwrap_or_else(|| DEFAULT_COLUMNS.to_vec()),
+        }))
     }
 }
 
-struct PsResponseHandler;
+struct PsResponseHandler {
+    show_orphans: bool,
+    columns: Vec<PsColumn>,
+}
+
+impl PsColumn {
+    fn options(self) -> ColumnOptions {
+        match self {
+            PsColumn::Pid => ColumnOptions::new("PID").alignment(console_table::Alignment::Right),
+            PsColumn::Jid => ColumnOptions::new("JID").spacing(2),
+            PsColumn::Status => ColumnOptions::new("STATUS").spacing(3),
+            PsColumn::Expires => ColumnOptions::new("EXPIRES").spacing(2),
+            PsColumn::Cmd => ColumnOptions::new("CMD").spacing(2),
+            PsColumn::Tags => ColumnOptions::new("TAGS").spacing(2),
+            PsColumn::Note => ColumnOptions::new("NOTE").spacing(2),
+        }
+    }
+}
 
 #[async_trait]
 impl ResponseHandler for PsResponseHandler {
@@ -109,31 +268,84 @@ impl ResponseHandler for PsResponseHandler {
             DateTime::from_timestamp(proc.created_at_ts.0, proc.created_at_ts.1)
         });
 
-        let pid_column =
-            console_table::ColumnOptions::new("PID").alignment(console_table::Alignment::Right);
-        let jid_column = console_table::ColumnOptions::new("JID").spacing(2);
-        let status_column = console_table::ColumnOptions::new("STATUS").spacing(3);
-        let cmd_column = console_table::ColumnOptions::new("CMD");
-
         let mut table_builder =
-            (pid_column, jid_column, status_column, cmd_column).into_table_builder();
+            DynamicBuilder::new(self.columns.iter().map(|column| column.options()).collect());
+        let now = Local::now().timestamp();
 
         for proc in processes {
-            let pid_string = proc.pid.map(|pid| pid.to_string()).unwrap_or_default();
-            let jid_string = proc.jid.map(|jid| jid[0..8].to_owned()).unwrap_or_default();
             let uptime = FormattedUptime::new(Duration::from_secs(proc.uptime_secs));
-            let status_string = if proc.pid.is_some() {
-                format!("Up {uptime}")
-            } else if let Some(last_exit_code) = proc.last_exit_code {
-                format!("Exited with code {last_exit_code}")
-            } else {
-                "Not started".to_owned()
-            };
-            table_builder.push_row(pid_string, jid_string, status_string, proc.cmd);
+            let fields = self.columns.iter().map(|column| match column {
+                PsColumn::Pid => proc.pid.map(|pid| pid.to_string()).unwrap_or_default(),
+                PsColumn::Jid => proc.jid.clone().unwrap_or_default(),
+                PsColumn::Status => {
+                    if proc.paused {
+                        format!("Paused (up {uptime})")
+                    } else if proc.pid.is_some() {
+                        format!("Up {uptime}")
+                    } else if let Some(waiting_for) = &proc.waiting_for {
+                        format!("Waiting for {waiting_for}")
+                    } else if proc.oom_killed {
+                        "Killed (OOM)".to_owned()
+                    } else if proc.stopped_idle {
+                        "Stopped (idle)".to_owned()
+                    } else if proc.checkpointed {
+                        "Checkpointed".to_owned()
+                    } else if let Some(last_exit_code) = proc.last_exit_code {
+                        format!("Exited with code {last_exit_code}")
+                    } else {
+                        "Not started".to_owned()
+                    }
+                }
+                PsColumn::Expires => match proc.expires_at_ts {
+                    Some(ts) => {
+                        let remaining = Duration::from_secs(ts.saturating_sub(now).max(0) as u64);
+                        format!("in {}", FormattedUptime::new(remaining))
+                    }
+                    None => "-".to_owned(),
+                },
+                PsColumn::Cmd => proc.cmd.clone(),
+                PsColumn::Tags if proc.tags.is_empty() => "-".to_owned(),
+                PsColumn::Tags => {
+                    let tags: Vec<_> = proc
+                        .tags
+                        .iter()
+                        .map(|(key, value)| format!("{key}={value}"))
+                        .collect();
+                    tags.join(",")
+                }
+                PsColumn::Note => proc.note.clone().unwrap_or_else(|| "-".to_owned()),
+            });
+            table_builder.push_row(fields.collect());
+        }
+        let width = RenderHints::detect().width;
+        let cmd_idx = self.columns.iter().position(|c| *c == PsColumn::Cmd);
+        if let (Some(width), Some(cmd_idx)) = (width, cmd_idx) {
+            table_builder.fit_column(cmd_idx, width.into());
         }
 
         println!("{table_builder}");
 
+        if self.show_orphans {
+            let pid_column =
+                console_table::ColumnOptions::new("PID").alignment(console_table::Alignment::Right);
+            let owner_column = console_table::ColumnOptions::new("ORPHANED BY").spacing(2);
+            let cmd_column = console_table::ColumnOptions::new("CMD");
+
+            let mut table_builder = (pid_column, owner_column, cmd_column).into_table_builder();
+            for orphan in resp.orphans {
+                let owner_string = orphan
+                    .owner_pid
+                    .map(|pid| pid.to_string())
+                    .unwrap_or_else(|| "-".to_owned());
+                table_builder.push_row(orphan.pid.to_string(), owner_string, orphan.cmd);
+            }
+            if let Some(width) = width {
+                table_builder.fit_column(2, width.into());
+            }
+
+            println!("\n{table_builder}");
+        }
+
         Ok(())
     }
 }
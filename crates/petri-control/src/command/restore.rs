@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::Args;
+use petri_core::checkpoint;
+use petri_core::process::{LogOptions, StartInfo};
+use serde::{Deserialize, Serialize};
+use tokio::time;
+
+use super::{wait_spawn_slot, CommandClient, IpcChannel, ResponseHandler};
+use crate::checkpoint::CheckpointStore;
+use crate::Context as ControlContext;
+
+/// How long the restoring process is watched for failures. CRIU exits
+/// right away if it can't restore, like when the pids are taken.
+const RESTORE_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct RestoreSubcommand {
+    /// Id of the checkpoint, as listed by `petri checkpoint --list`.
+    checkpoint: String,
+}
+
+impl RestoreSubcommand {
+    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        let Some(store) = &ctx.checkpoints else {
+            channel
+                .write_output("checkpoints are not enabled on the server\n")
+                .await?;
+            return Err(anyhow!("checkpoints are not enabled").context("restore"));
+        };
+
+        match self.restore(ctx, channel, store).await {
+            Ok((pid, job_name)) => {
+                let mut output =
+                    format!("checkpoint `{}` restored as process {pid}", self.checkpoint);
+                if let Some(job_name) = job_name {
+                    output.push_str(&format!(" of job {job_name}"));
+                }
+                output.push('\n');
+                channel.write_output(&output).await?;
+            }
+            Err(err) => {
+                channel
+                    .write_output(&format!("failed to restore the checkpoint: {err:#}\n"))
+                    .await?;
+                return Err(err.context("restore"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Restores the checkpoint into its job if the job still exists, or as
+    /// a standalone process otherwise.
+    async fn restore(
+        &self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+        store: &CheckpointStore,
+    ) -> Result<(u32, Option<String>)> {
+        let info = store.load(&self.checkpoint)?;
+        if !checkpoint::is_installed() {
+            return Err(anyhow!("`{}` is not installed", checkpoint::CRIU_PROGRAM));
+        }
+        let images_dir = store.images_dir(&info.id);
+        let pipes = info.output_pipes();
+        let job = match &info.jid {
+            Some(jid) => ctx.job_mgr_handle.job_with_id(jid).await,
+            None => None,
+        };
+
+        let permit = wait_spawn_slot(ctx, channel).await?;
+        let pid = match &job {
+            Some(job) => {
+                let start_info = checkpoint::restore_start_info(
+                    &job.description().start_info,
+                    &images_dir,
+                    &pipes,
+                );
+                ctx.job_mgr_handle
+                    .restore_job_with_permit(job.id(), &start_info, permit)
+                    .await?
+            }
+            None => {
+                let program = shlex::split(&info.cmd)
+                    .and_then(|argv| argv.into_iter().next())
+                    .unwrap_or_default();
+                let log = LogOptions {
+                    path: info.log_path.clone(),
+                    ..Default::default()
+                };
+                let start_info = StartInfo::builder(program, &info.cwd).log(log).build()?;
+                let start_info = checkpoint::restore_start_info(&start_info, &images_dir, &pipes);
+                ctx.proc_mgr_handle
+                    .add_process_with_permit(&start_info, permit)
+                    .await?
+            }
+        };
+
+        let exited =
+            time::timeout(RESTORE_CHECK_TIMEOUT, ctx.proc_mgr_handle.wait_process(pid)).await;
+        if let Ok(Ok(exit_code)) = exited {
+            return Err(checkpoint::restore_error(&images_dir, exit_code));
+        }
+        Ok((pid, job.map(|job| job.display_name().to_owned())))
+    }
+}
+
+impl CommandClient for RestoreSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        None
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::Result;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+
+use super::{CommandClient, IpcChannel, ResponseHandler};
+use crate::Context as ControlContext;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct ResumeSubcommand {
+    /// Resume the paused process with the given pid, along with its descendants.
+    #[arg(short, long)]
+    pid: u32,
+}
+
+impl ResumeSubcommand {
+    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        match ctx.proc_mgr_handle.resume_process(self.pid).await {
+            Ok(descendants) => {
+                let mut output = format!("process {} resumed", self.pid);
+                if descendants > 0 {
+                    output.push_str(&format!(" with {descendants} descendant process(es)"));
+                }
+                output.push('\n');
+                channel.write_output(&output).await?;
+            }
+            Err(err) => {
+                channel
+                    .write_output(&format!("failed to resume the process: {err}\n"))
+                    .await?;
+                return Err(err.context("resume"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl CommandClient for ResumeSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        None
+    }
+}
@@ -1,31 +1,466 @@
-use std::path::PathBuf;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
-use clap::Args;
-use petri_core::job_mgr::JobDescription;
-use petri_core::process::StartInfo;
+use clap::{Args, ValueEnum};
+use petri_core::affinity::CpuSet;
+use petri_core::container::{ContainerOptions, Runtime};
+use petri_core::job_mgr::{
+    IdleActivity, IdlePolicy, JobDescription, MemoryWatchdog, Readiness, RestartJitter,
+    RestartMode, RestartPolicy, RestartWindow, WatchdogAction,
+};
+use petri_core::precondition::Precondition;
+use petri_core::process::{
+    self, LogOptions, LogQuota, LogSampling, OutputSubscriber, Process, QuotaPolicy,
+    RotationCadence, RotationOptions, StartInfo, MAX_READ_BUFFER_SIZE,
+};
+use petri_core::process_mgr::LimitReached;
+use petri_core::sandbox::{SandboxError, SandboxOptions};
+use petri_utils::id::short_id;
+use petri_utils::parse_bytes;
+use petri_utils::subscriber_list::CancellationToken;
+use petri_utils::time::{parse_duration, parse_nonzero_duration};
 use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
 
-use super::{CommandClient, IpcChannel, ResponseHandler};
-use crate::cli::CLIENT_ENV;
+use super::namespace::namespace_of;
+use super::paths;
+use super::stream::{OutputChunk, OutputCounter};
+use super::{
+    deadline_from_timestamp, parse_deadline, parse_key_value, wait_preconditions, wait_spawn_slot,
+    CommandClient, IpcChannel, OutputEnd, ResponseHandler, ResponseStream, StreamConsumer,
+    StreamEnd, StreamHandler,
+};
+use crate::cli::{EndOfStreamReason, CLIENT_ENV};
+use crate::lock::{Holder, LockGuard, LockPolicy};
 use crate::Context as ControlContext;
 
+/// The default log name of jobs that append to their log files.
+const APPEND_JOB_LOG_NAME: &str = "{job}-{date}.log";
+
+/// How long to wait for the output left in the pipes after a streamed
+/// process exits. Its descendants may keep the pipes open.
+pub(super) const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_millis(200);
+
 #[derive(Args, Serialize, Deserialize, Debug)]
 pub struct RunSubcommand {
     /// Redirect stdout & stderr to log files in the given path.
     #[arg(short)]
     log_path: Option<PathBuf>,
+    /// Name the log files after a template like `{job}/{date}/{program}-{instance}.log`.
+    ///
+    /// Available variables are `{job}` (the name or id of the job), `{instance}` (the
+    /// id of the job or template instance), `{program}`, `{pid}` and `{date}`. The
+    /// default is `{program}-{pid}-{date}.log`.
+    #[arg(long, value_name = "TEMPLATE", value_parser = parse_log_name, requires = "log_path")]
+    log_name: Option<String>,
+    /// Append to the existing log file, so a restarted job keeps logging to the same file.
+    ///
+    /// The default log name becomes `{job}-{date}.log` for jobs and
+    /// `{program}-{date}.log` otherwise. Files are still rotated.
+    #[arg(long, requires = "log_path")]
+    log_append: bool,
+    /// How often to rotate the log files.
+    #[arg(long, value_enum, default_value_t = RotateArg::Daily, requires = "log_path")]
+    log_rotate: RotateArg,
+    /// Also rotate a log file once it grows to the size (e.g. `100M`).
+    #[arg(long, value_name = "SIZE", value_parser = parse_nonzero_size, requires = "log_path")]
+    log_max_size: Option<u64>,
+    /// Cap the total size of the log files on the disk (e.g. `1G`), counting
+    /// the files of the previous processes of a job.
+    #[arg(long, value_name = "SIZE", value_parser = parse_nonzero_size, requires = "log_path")]
+    log_quota: Option<u64>,
+    /// What to do once the log files exceed the quota.
+    #[arg(long, value_enum, default_value_t = QuotaPolicyArg::DropOldest, requires = "log_quota")]
+    log_quota_policy: QuotaPolicyArg,
+    /// Only log some lines of a chatty process, every Nth line like `1/10`, or at most
+    /// N lines a second like `100/s`. `petri log` still streams the whole output.
+    #[arg(long, value_name = "RATE", value_parser = parse_log_sampling, requires = "log_path")]
+    log_sample: Option<String>,
     /// Create a job for the command.
     #[arg(short = 'j')]
     create_job: bool,
+    /// Give the job a unique name.
+    #[arg(long, requires = "create_job")]
+    name: Option<String>,
+    /// Put the job in the namespace instead of the one of the current
+    /// directory.
+    #[arg(long, requires = "create_job")]
+    namespace: Option<String>,
+    /// Register the job as a template without starting it.
+    #[arg(long, requires = "create_job")]
+    template: bool,
+    /// Start the job with `petri up` (requires `-j`).
+    #[arg(long, requires = "create_job")]
+    autostart: bool,
+    /// Attach a label to the job (requires `-j`).
+    #[arg(long = "label", value_name = "KEY=VALUE", value_parser = parse_key_value, requires = "create_job")]
+    labels: Vec<(String, String)>,
+    /// Start the job after the given job is ready (requires `-j`).
+    #[arg(long, value_name = "JOB", requires = "create_job")]
+    depends_on: Vec<String>,
+    /// Wait for the condition before starting the job, like `tcp:postgres:5432`,
+    /// `file:/run/app.sock` or `cmd:pg_isready`, can be repeated (requires `-j`).
+    #[arg(long = "wait-for", value_name = "CONDITION", value_parser = parse_precondition, requires = "create_job")]
+    preconditions: Vec<String>,
+    /// How long to wait for `--wait-for` before failing to start.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "1m", requires = "preconditions")]
+    wait_timeout: Duration,
+    /// Consider the job ready if it keeps running for the duration (requires `-j`).
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, requires = "create_job")]
+    ready_after: Option<Duration>,
+    /// Consider the job ready once its process runs `petri notify ready` (requires `-j`).
+    #[arg(long, requires = "create_job", conflicts_with = "ready_after")]
+    ready_on_notify: bool,
+    /// Restart the job after it exits (requires `-j`).
+    #[arg(long, value_enum, default_value_t = RestartArg::Never, requires = "create_job")]
+    restart: RestartArg,
+    /// Treat the exit code as clean for `--restart on-failure`, can be repeated.
+    #[arg(long = "clean-exit-code", value_name = "CODE", requires = "create_job")]
+    clean_exit_codes: Vec<i32>,
+    /// Wait a random delay before each restart, like `5s..30s` or `30s` for up to 30 seconds,
+    /// so jobs failing together don't restart together.
+    #[arg(long, value_name = "RANGE", value_parser = parse_restart_jitter, requires = "create_job")]
+    restart_jitter: Option<String>,
+    /// Only restart the job within the time of day, like `02:00-04:00`, can be repeated.
+    #[arg(long = "restart-window", value_name = "WINDOW", value_parser = parse_restart_window, requires = "create_job")]
+    restart_windows: Vec<String>,
+    /// Stop the job once it stays idle for the duration (requires `-j`).
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, requires = "create_job")]
+    idle_timeout: Option<Duration>,
+    /// What counts as activity for `--idle-timeout`.
+    #[arg(long, value_enum, default_value_t = IdleArg::Any, requires = "idle_timeout")]
+    idle_activity: IdleArg,
+    /// Restart the job once the memory (RSS) of its process stays over the size (e.g. `512M`),
+    /// without relying on cgroups (requires `-j`).
+    #[arg(long, value_name = "SIZE", value_parser = parse_nonzero_size, requires = "create_job")]
+    max_rss: Option<u64>,
+    /// How many consecutive samples must be over `--max-rss`.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 3,
+        value_parser = clap::value_parser!(u32).range(1..),
+        requires = "max_rss"
+    )]
+    max_rss_samples: u32,
+    /// How often to sample the memory for `--max-rss`.
+    #[arg(long, value_name = "DURATION", value_parser = parse_nonzero_duration, default_value = "5s", requires = "max_rss")]
+    max_rss_interval: Duration,
+    /// What to do once the memory stays over `--max-rss`.
+    #[arg(long, value_enum, default_value_t = WatchdogArg::Restart, requires = "max_rss")]
+    max_rss_action: WatchdogArg,
+    /// Stop the job earlier than jobs with higher priorities when the server shuts down,
+    /// but after the jobs depending on it (requires `-j`).
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        allow_negative_numbers = true,
+        requires = "create_job"
+    )]
+    stop_priority: i32,
+    /// Read variables from a `.env` file whenever the job starts, so edits take effect at the
+    /// next restart (requires `-j`).
+    #[arg(long, value_name = "PATH", requires = "create_job")]
+    env_file: Option<PathBuf>,
+    /// Stop the process at a time like `18:30` or after a duration like `30m`.
+    #[arg(long, value_name = "TIME", value_parser = parse_deadline, conflicts_with = "template")]
+    until: Option<i64>,
+    /// Stop the process when the login session of the client ends.
+    #[arg(long, conflicts_with = "template")]
+    bind_to_session: bool,
+    /// The session id of the client, collected by the client.
+    #[arg(skip)]
+    session_id: Option<u32>,
+    /// Set an environment variable, which is not filtered by the env policy of the server.
+    #[arg(short, long, value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    env: Vec<(String, String)>,
+    /// Don't set the `PETRI_*` variables that tell the process it runs under petri.
+    #[arg(long)]
+    no_petri_env: bool,
+    /// Stream the output of the process until it exits, and exit with its
+    /// exit code. The process keeps running if the client is interrupted.
+    #[arg(long, conflicts_with = "create_job")]
+    attach: bool,
+    /// Hold a named lock while the process runs, so runs with the same lock don't overlap.
+    #[arg(long, value_name = "NAME", conflicts_with = "create_job")]
+    lock: Option<String>,
+    /// What to do if the lock is held by another process.
+    #[arg(long, value_enum, default_value_t = LockPolicy::Fail, requires = "lock")]
+    lock_policy: LockPolicy,
+    /// Pin the process to the given CPUs (e.g. `0-3,6`, Linux only).
+    #[arg(long)]
+    cpus: Option<String>,
+    /// The size of the buffer that the output is read into at first (e.g. `64K`), which grows
+    /// while the process is chatty. Defaults to the `read_buffer_size` option of the server.
+    #[arg(long, value_name = "SIZE", value_parser = parse_read_buffer_size)]
+    read_buffer_size: Option<usize>,
+    #[command(flatten)]
+    sandbox: SandboxArgs,
+    #[command(flatten)]
+    runtime: RuntimeArgs,
     #[arg(required = true, last = true)]
     cmd_line: Vec<String>,
 }
 
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum RestartArg {
+    /// Never restart the job.
+    Never,
+    /// Restart the job if it exits with a non-zero exit code.
+    OnFailure,
+    /// Always restart the job, unless it's stopped as a job.
+    Always,
+    /// Always restart the job, unless it's stopped.
+    UnlessStopped,
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum IdleArg {
+    /// The job is active while it writes output.
+    Output,
+    /// The job is active while it uses CPU time.
+    Cpu,
+    /// The job is active while it does either.
+    Any,
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum WatchdogArg {
+    /// Stop the process gracefully and start the job again.
+    Restart,
+    /// Stop the job.
+    Stop,
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum RotateArg {
+    /// Start a new log file every hour.
+    Hourly,
+    /// Start a new log file every day.
+    Daily,
+    /// Start a new log file every week.
+    Weekly,
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum QuotaPolicyArg {
+    /// Rotate the log file and remove the oldest files until they fit.
+    DropOldest,
+    /// Stop logging the output of the process.
+    StopLogging,
+    /// Stop the process.
+    StopProcess,
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+#[command(next_help_heading = "Sandbox options (Linux only)")]
+struct SandboxArgs {
+    /// Prevent the process from gaining new privileges.
+    #[arg(long)]
+    no_new_privs: bool,
+    /// Give the process a private `/tmp`.
+    #[arg(long)]
+    private_tmp: bool,
+    /// Run the process without network access.
+    #[arg(long)]
+    isolate_network: bool,
+    /// Mount the root filesystem read-only for the process.
+    #[arg(long)]
+    read_only_root: bool,
+    /// Change the root directory of the process (requires root or user namespaces).
+    #[arg(long, value_name = "PATH")]
+    rootfs: Option<PathBuf>,
+    /// Bind the cwd into the same path under the rootfs, or in the container.
+    #[arg(long)]
+    bind_cwd: bool,
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+#[command(next_help_heading = "Runtime options")]
+struct RuntimeArgs {
+    /// The runtime to launch the program with.
+    #[arg(long, value_enum, default_value_t = RuntimeKind::Native)]
+    runtime: RuntimeKind,
+    /// The image to run the program with (requires `--runtime container`).
+    #[arg(long, required_if_eq("runtime", "container"))]
+    image: Option<String>,
+    /// The container engine CLI to use.
+    #[arg(long, default_value = "docker")]
+    container_engine: String,
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum RuntimeKind {
+    /// Spawn the program directly on the host.
+    Native,
+    /// Run the program inside an OCI container.
+    Container,
+}
+
+pub(super) fn parse_read_buffer_size(s: &str) -> Result<usize, String> {
+    let size = parse_bytes(s).map_err(|err| err.to_string())?;
+    match usize::try_from(size) {
+        Ok(0) => Err("the size must not be zero".to_owned()),
+        Ok(size) if size > MAX_READ_BUFFER_SIZE => Err(format!(
+            "the size must not exceed {MAX_READ_BUFFER_SIZE} bytes"
+        )),
+        Ok(size) => Ok(size),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn parse_nonzero_size(s: &str) -> Result<u64, String> {
+    match parse_bytes(s) {
+        Ok(0) => Err("the size must not be zero".to_owned()),
+        Ok(size) => Ok(size),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn parse_log_name(s: &str) -> Result<String, String> {
+    process::parse_log_name(s)
+        .map(|_| s.to_owned())
+        .map_err(|err| err.to_string())
+}
+
+fn parse_log_sampling(s: &str) -> Result<String, String> {
+    s.parse::<LogSampling>()
+        .map(|_| s.to_owned())
+        .map_err(|err| err.to_string())
+}
+
+fn parse_precondition(s: &str) -> Result<String, String> {
+    s.parse::<Precondition>()
+        .map(|_| s.to_owned())
+        .map_err(|err| err.to_string())
+}
+
+fn parse_restart_jitter(s: &str) -> Result<String, String> {
+    s.parse::<RestartJitter>()
+        .map(|_| s.to_owned())
+        .map_err(|err| err.to_string())
+}
+
+fn parse_restart_window(s: &str) -> Result<String, String> {
+    s.parse::<RestartWindow>()
+        .map(|_| s.to_owned())
+        .map_err(|err| err.to_string())
+}
+
+impl From<RuntimeArgs> for Runtime {
+    fn from(value: RuntimeArgs) -> Self {
+        match (value.runtime, value.image) {
+            (RuntimeKind::Container, Some(image)) => Runtime::Container(ContainerOptions {
+                engine: value.container_engine,
+                image,
+            }),
+            _ => Runtime::Native,
+        }
+    }
+}
+
+impl From<RestartArg> for RestartMode {
+    fn from(value: RestartArg) -> Self {
+        match value {
+            RestartArg::Never => RestartMode::Never,
+            RestartArg::OnFailure => RestartMode::OnFailure,
+            RestartArg::Always => RestartMode::Always,
+            RestartArg::UnlessStopped => RestartMode::UnlessStopped,
+        }
+    }
+}
+
+impl From<IdleArg> for IdleActivity {
+    fn from(value: IdleArg) -> Self {
+        match value {
+            IdleArg::Output => IdleActivity::Output,
+            IdleArg::Cpu => IdleActivity::Cpu,
+            IdleArg::Any => IdleActivity::Any,
+        }
+    }
+}
+
+impl From<WatchdogArg> for WatchdogAction {
+    fn from(value: WatchdogArg) -> Self {
+        match value {
+            WatchdogArg::Restart => WatchdogAction::Restart,
+            WatchdogArg::Stop => WatchdogAction::Stop,
+        }
+    }
+}
+
+impl From<RotateArg> for RotationCadence {
+    fn from(value: RotateArg) -> Self {
+        match value {
+            RotateArg::Hourly => RotationCadence::Hourly,
+            RotateArg::Daily => RotationCadence::Daily,
+            RotateArg::Weekly => RotationCadence::Weekly,
+        }
+    }
+}
+
+impl From<QuotaPolicyArg> for QuotaPolicy {
+    fn from(value: QuotaPolicyArg) -> Self {
+        match value {
+            QuotaPolicyArg::DropOldest => QuotaPolicy::DropOldest,
+            QuotaPolicyArg::StopLogging => QuotaPolicy::StopLogging,
+            QuotaPolicyArg::StopProcess => QuotaPolicy::StopProcess,
+        }
+    }
+}
+
+impl From<SandboxArgs> for SandboxOptions {
+    fn from(value: SandboxArgs) -> Self {
+        Self {
+            no_new_privs: value.no_new_privs,
+            private_tmp: value.private_tmp,
+            isolate_network: value.isolate_network,
+            read_only_root: value.read_only_root,
+            rootfs: value.rootfs,
+            bind_cwd: value.bind_cwd,
+        }
+    }
+}
+
 impl RunSubcommand {
-    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+    fn restart_policy(&mut self) -> Result<RestartPolicy> {
+        Ok(RestartPolicy {
+            mode: self.restart.into(),
+            clean_exit_codes: mem::take(&mut self.clean_exit_codes),
+            jitter: self.restart_jitter.as_deref().map(str::parse).transpose()?,
+            windows: self
+                .restart_windows
+                .iter()
+                .map(|window| window.parse())
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Resolves the paths against the working directory of the client,
+    /// which is checked as well, since the server may run elsewhere.
+    fn resolve_paths(&mut self, cwd: &str) -> Result<()> {
+        paths::resolve_dir(cwd, Path::new(cwd), "working directory", false)?;
+        if let Some(path) = &self.log_path {
+            self.log_path = Some(paths::resolve_dir(cwd, path, "log directory", true)?);
+        }
+        if let Some(path) = &self.sandbox.rootfs {
+            self.sandbox.rootfs = Some(paths::resolve_dir(cwd, path, "rootfs", false)?);
+        }
+        Ok(())
+    }
+
+    pub(super) async fn run(
+        mut self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+    ) -> Result<()> {
         let (program, args) = {
-            let mut cmd_line = self.cmd_line;
+            let mut cmd_line = mem::take(&mut self.cmd_line);
             let args = cmd_line.split_off(1);
             (cmd_line, if args.is_empty() { None } else { Some(args) })
         };
@@ -35,36 +470,192 @@ impl RunSubcommand {
             return Err(anyhow!("no program is specified").context("run"));
         };
 
+        let cpus = match self.cpus.as_deref().map(str::parse::<CpuSet>).transpose() {
+            Ok(cpus) => cpus,
+            Err(err) => {
+                channel
+                    .write_output(&format!("invalid cpu list: {err}\n"))
+                    .await?;
+                return Err(err.context("run"));
+            }
+        };
+
+        let until = match self.until.map(deadline_from_timestamp).transpose() {
+            Ok(until) => until,
+            Err(err) => {
+                channel.write_output(&format!("{err}\n")).await?;
+                return Err(err.context("run"));
+            }
+        };
+
+        let restart = match self.restart_policy() {
+            Ok(restart) => restart,
+            Err(err) => {
+                channel
+                    .write_output(&format!("invalid restart policy: {err}\n"))
+                    .await?;
+                return Err(err.context("run"));
+            }
+        };
+        let preconditions = self
+            .preconditions
+            .iter()
+            .map(|precondition| precondition.parse())
+            .collect::<Result<Vec<Precondition>>>();
+        let preconditions = match preconditions {
+            Ok(preconditions) => preconditions,
+            Err(err) => {
+                channel
+                    .write_output(&format!("invalid condition: {err}\n"))
+                    .await?;
+                return Err(err.context("run"));
+            }
+        };
+
+        // Name the appended files after the job, so jobs of the same program
+        // don't share them.
+        let log_name = match &self.log_name {
+            None if self.log_append && self.create_job => Some(APPEND_JOB_LOG_NAME.to_owned()),
+            log_name => log_name.clone(),
+        };
+        let log_name = match log_name.as_deref().map(process::parse_log_name) {
+            Some(Ok(template)) => Some(template),
+            Some(Err(err)) => {
+                channel
+                    .write_output(&format!("invalid log name template: {err}\n"))
+                    .await?;
+                return Err(err.context("run"));
+            }
+            None => None,
+        };
+        if let Some(template) = &log_name {
+            if !self.create_job
+                && template
+                    .variables()
+                    .any(|name| name == "job" || name == "instance")
+            {
+                channel
+                    .write_output("`{job}` and `{instance}` can only be used with `-j`\n")
+                    .await?;
+                return Err(anyhow!("log name template requires a job").context("run"));
+            }
+        }
+
         let (cwd, env_vars) = CLIENT_ENV
             .try_with(|env| (env.cwd().to_owned(), env.env().clone()))
             .expect("no `ClientEnv` set in the calling context");
 
-        let start_info = StartInfo {
-            program,
-            args,
-            cwd,
-            env: env_vars,
-            log_path: self.log_path,
+        if let Err(err) = self.resolve_paths(&cwd) {
+            channel.write_output(&format!("{err}\n")).await?;
+            return Err(err.context("run"));
+        }
+
+        let namespace = self.namespace.unwrap_or_else(|| namespace_of(&cwd));
+        let start_info = StartInfo::builder(program, cwd)
+            .args(args.unwrap_or_default())
+            .envs(env_vars)
+            .envs(self.env)
+            .log(LogOptions {
+                path: self.log_path,
+                name: log_name,
+                append: self.log_append,
+                rotation: RotationOptions {
+                    cadence: self.log_rotate.into(),
+                    max_size: self.log_max_size,
+                },
+                quota: self.log_quota.map(|max_bytes| LogQuota {
+                    max_bytes,
+                    policy: self.log_quota_policy.into(),
+                }),
+                sampling: self.log_sample.as_deref().map(str::parse).transpose()?,
+            })
+            .cpus(cpus)
+            .sandbox(self.sandbox.into())
+            .runtime(self.runtime.into())
+            .petri_env(!self.no_petri_env)
+            .read_buffer_size(self.read_buffer_size)
+            .build();
+        let start_info = match start_info {
+            Ok(start_info) => start_info,
+            Err(err) => {
+                channel
+                    .write_output(&format!("invalid command: {err}\n"))
+                    .await?;
+                return Err(anyhow::Error::new(err).context("run"));
+            }
         };
 
+        let mut attached = None;
         let pid = if self.create_job {
-            let job_desc: JobDescription = JobDescription {
-                start_info,
-                auto_restart: false,
-            };
+            let job_desc = JobDescription::builder(start_info)
+                .restart(restart)
+                .name(self.name)
+                .namespace(namespace)
+                .template(self.template)
+                .autostart(self.autostart)
+                .labels(self.labels)
+                .depends_on(self.depends_on)
+                .preconditions(preconditions)
+                .precondition_timeout(self.wait_timeout)
+                .readiness(match self.ready_after {
+                    _ if self.ready_on_notify => Readiness::Notify,
+                    Some(delay) => Readiness::Delay(delay),
+                    None => Readiness::Immediate,
+                })
+                .idle(self.idle_timeout.map(|timeout| IdlePolicy {
+                    timeout,
+                    activity: self.idle_activity.into(),
+                }))
+                .memory_watchdog(self.max_rss.map(|max_rss_bytes| MemoryWatchdog {
+                    max_rss_bytes,
+                    samples: self.max_rss_samples,
+                    interval: self.max_rss_interval,
+                    action: self.max_rss_action.into(),
+                }))
+                .stop_priority(self.stop_priority)
+                .env_file(self.env_file)
+                .build();
             let jid = match ctx.job_mgr_handle.add_job(job_desc).await {
                 Ok(id) => id,
                 Err(err) => {
                     channel
-                        .write_output("failed to create a job for the command\n")
+                        .write_output(&format!("failed to create a job for the command: {err}\n"))
                         .await?;
                     return Err(err.context("run"));
                 }
             };
 
-            match ctx.job_mgr_handle.start_job(&jid).await {
+            if self.template {
+                channel
+                    .write_output(&format!("job template created (jid: {})\n", short_id(&jid)))
+                    .await?;
+                return Ok(());
+            }
+
+            if let Err(err) = wait_preconditions(ctx, channel, &jid).await {
+                channel
+                    .write_output(&format!(
+                        "failed to start the job: {err} (you can run it again later)\n"
+                    ))
+                    .await?;
+                return Err(err.context("run"));
+            }
+            let permit = wait_spawn_slot(ctx, channel).await?;
+            match ctx.job_mgr_handle.start_job_with_permit(&jid, permit).await {
                 Ok(id) => id,
                 Err(err) => {
+                    if let Some(sandbox_err) = err.downcast_ref::<SandboxError>() {
+                        channel
+                            .write_output(&format!("failed to start the job: {sandbox_err}\n"))
+                            .await?;
+                        return Err(err.context("run"));
+                    }
+                    if let Some(limit_err) = err.downcast_ref::<LimitReached>() {
+                        channel
+                            .write_output(&format!("failed to start the job: {limit_err}\n"))
+                            .await?;
+                        return Err(err.context("run"));
+                    }
                     channel
                         .write_output("failed to start the job (you can run it again later)\n")
                         .await?;
@@ -72,9 +663,50 @@ impl RunSubcommand {
                 }
             }
         } else {
-            match ctx.proc_mgr_handle.add_process(&start_info).await {
-                Ok(id) => id,
+            let lock = match &self.lock {
+                Some(name) => Some(acquire_lock(ctx, channel, name, self.lock_policy).await?),
+                None => None,
+            };
+            let permit = wait_spawn_slot(ctx, channel).await?;
+            let res = if self.attach {
+                let (tx, rx) = mpsc::unbounded_channel();
+                ctx.proc_mgr_handle
+                    .add_attached_process_with_permit(&start_info, permit, tx)
+                    .await
+                    .map(|(process, subscription)| {
+                        let pid = process.id();
+                        attached = Some(Attached {
+                            process,
+                            subscription,
+                            output: rx,
+                        });
+                        pid
+                    })
+            } else {
+                ctx.proc_mgr_handle
+                    .add_process_with_permit(&start_info, permit)
+                    .await
+            };
+            match res {
+                Ok(id) => {
+                    if let Some(lock) = lock {
+                        lock.bind(id, ctx.proc_mgr_handle.clone());
+                    }
+                    id
+                }
                 Err(err) => {
+                    if let Some(sandbox_err) = err.downcast_ref::<SandboxError>() {
+                        channel
+                            .write_output(&format!("failed to start the process: {sandbox_err}\n"))
+                            .await?;
+                        return Err(err.context("run"));
+                    }
+                    if let Some(limit_err) = err.downcast_ref::<LimitReached>() {
+                        channel
+                            .write_output(&format!("failed to start the process: {limit_err}\n"))
+                            .await?;
+                        return Err(err.context("run"));
+                    }
                     channel
                         .write_output("failed to start the process (maybe it exited too early)\n")
                         .await?;
@@ -87,12 +719,197 @@ impl RunSubcommand {
             .write_output(&format!("process started (pid: {pid})\n"))
             .await?;
 
-        Ok(())
+        if let Some(deadline) = until {
+            match ctx.job_mgr_handle.schedule_stop(pid, deadline).await {
+                Ok(_) => {
+                    channel
+                        .write_output(&format!(
+                            "it will be stopped at {}\n",
+                            deadline.format("%Y-%m-%d %T")
+                        ))
+                        .await?;
+                }
+                Err(err) => {
+                    channel
+                        .write_output(&format!("failed to schedule the stop: {err}\n"))
+                        .await?;
+                    return Err(err.context("run"));
+                }
+            }
+        }
+
+        if let Some(sid) = self.session_id {
+            match ctx.job_mgr_handle.bind_to_session(pid, sid).await {
+                Ok(_) => {
+                    channel
+                        .write_output(&format!(
+                            "it will be stopped when the session (sid: {sid}) ends\n"
+                        ))
+                        .await?;
+                }
+                Err(err) => {
+                    channel
+                        .write_output(&format!("failed to bind to the session: {err}\n"))
+                        .await?;
+                    return Err(err.context("run"));
+                }
+            }
+        }
+
+        match attached {
+            Some(attached) => attached.stream(channel).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Returns whether the command can run for arbitrarily long, streaming
+    /// the output of the process until it exits or waiting for its lock.
+    pub(super) fn is_unbounded(&self) -> bool {
+        self.attach || (self.lock.is_some() && self.lock_policy == LockPolicy::Wait)
+    }
+}
+
+/// Takes the lock for the process to start, dealing with its holder by
+/// the policy.
+async fn acquire_lock(
+    ctx: &ControlContext,
+    channel: &mut IpcChannel,
+    name: &str,
+    policy: LockPolicy,
+) -> Result<LockGuard> {
+    let mut waiting = false;
+    loop {
+        let released = ctx.run_locks.released();
+        tokio::pin!(released);
+        released.as_mut().enable();
+
+        let holder = match ctx.run_locks.try_acquire(name) {
+            Ok(guard) => return Ok(guard),
+            Err(holder) => holder,
+        };
+        match (policy, holder) {
+            (LockPolicy::Fail, _) => {
+                let held_by = match holder {
+                    Holder::Starting => "a process being started".to_owned(),
+                    Holder::Running(pid) => format!("process {pid}"),
+                };
+                channel
+                    .write_output(&format!("lock `{name}` is held by {held_by}\n"))
+                    .await?;
+                return Err(anyhow!("lock `{name}` is held").context("run"));
+            }
+            (LockPolicy::Replace, Holder::Running(pid)) => {
+                channel
+                    .write_output(&format!("stopping process {pid} holding lock `{name}`\n"))
+                    .await?;
+                // The process may have exited meanwhile, which releases the
+                // lock all the same.
+                _ = ctx.proc_mgr_handle.stop_process(pid).await;
+            }
+            // The holder being started can only be replaced once it's running.
+            (LockPolicy::Wait | LockPolicy::Replace, _) => {
+                if !waiting {
+                    channel
+                        .write_output(&format!("waiting for lock `{name}`...\n"))
+                        .await?;
+                    waiting = true;
+                }
+            }
+        }
+        released.await;
+    }
+}
+
+/// A process started with `--attach`, subscribed to its output since it's
+/// spawned.
+struct Attached {
+    process: Process,
+    subscription: CancellationToken<OutputSubscriber>,
+    output: mpsc::UnboundedReceiver<Arc<[u8]>>,
+}
+
+impl Attached {
+    /// Streams the output until the process exits, and then the exit code.
+    async fn stream(mut self, channel: &mut IpcChannel) -> Result<()> {
+        let pid = self.process.id();
+        let mut stream = ResponseStream::<OutputChunk, OutputEnd>::new(channel);
+        let mut counter = OutputCounter::default();
+
+        // The stream ends by the exit of the process instead of the end of
+        // its output, which is not closed while its descendants are running.
+        let end = stream
+            .forward(&mut self.output, |contents| counter.item(contents), async {
+                self.process.wait().await;
+            })
+            .await;
+        if end == StreamEnd::Disconnected {
+            debug!("detached from process {pid} because the peer is closed");
+            return Ok(());
+        }
+        let exit_code = self.process.wait().await;
+        drop(self.process);
+
+        let end = stream
+            .forward(
+                &mut self.output,
+                |contents| counter.item(contents),
+                tokio::time::sleep(OUTPUT_DRAIN_TIMEOUT),
+            )
+            .await;
+        drop(self.subscription);
+        if end == StreamEnd::Disconnected {
+            return Ok(());
+        }
+        let end = counter.finish(EndOfStreamReason::ProcessExited, Some(exit_code));
+        stream.finish(Ok(end)).await
     }
 }
 
 impl CommandClient for RunSubcommand {
     fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        if self.attach {
+            return Some(Box::new(StreamHandler::new(AttachConsumer)));
+        }
         None
     }
+
+    fn prepare(&mut self) -> Result<()> {
+        if self.bind_to_session {
+            let sid = unsafe { libc::getsid(0) };
+            if sid < 0 {
+                return Err(anyhow!(
+                    "failed to get the session id: {}",
+                    io::Error::last_os_error()
+                ));
+            }
+            self.session_id = Some(sid as u32);
+        }
+        Ok(())
+    }
+}
+
+struct AttachConsumer;
+
+impl StreamConsumer for AttachConsumer {
+    type Item = OutputChunk;
+    type Result = OutputEnd;
+
+    fn on_item(&mut self, item: OutputChunk) -> Result<()> {
+        item.write_to(io::stdout().lock())?;
+        Ok(())
+    }
+
+    fn on_finish(&mut self, result: Result<OutputEnd, String>) -> Result<i32> {
+        match result {
+            Ok(end) => {
+                let exit_code = end.exit_code.unwrap_or_default();
+                eprintln!("process exited with code {exit_code} ({end})");
+                Ok(exit_code)
+            }
+            Err(reason) => {
+                eprintln!("{reason}");
+                Ok(1)
+            }
+        }
+    }
 }
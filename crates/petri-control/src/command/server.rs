@@ -0,0 +1,20 @@
+mod logs;
+mod metrics;
+mod reload;
+mod set;
+
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+
+#[derive(Subcommand, Serialize, Deserialize, Debug)]
+#[serde(tag = "name", content = "args", rename_all = "kebab-case")]
+pub enum ServerSubcommand {
+    /// Show logs of the server
+    Logs(logs::LogsSubcommand),
+    /// Print the metrics of the server in the Prometheus text format
+    Metrics(metrics::MetricsSubcommand),
+    /// Change an option of the running server
+    Set(set::SetSubcommand),
+    /// Reload the config file (also done on `SIGHUP`)
+    Reload(reload::ReloadSubcommand),
+}
@@ -0,0 +1,124 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::cli::IpcChannel;
+use crate::command::{
+    CommandClient, ResponseHandler, ResponseStream, StreamConsumer, StreamEnd, StreamHandler,
+};
+use crate::Context as ControlContext;
+
+/// How many log records can be pending for a following client.
+const FOLLOW_BUFFER_LEN: usize = 1024;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct LogsSubcommand {
+    /// Only show the last N lines of the buffered logs.
+    #[arg(short = 'n', long)]
+    tail: Option<usize>,
+    /// Keep streaming new logs.
+    #[arg(short, long)]
+    follow: bool,
+}
+
+impl LogsSubcommand {
+    pub(in crate::command) async fn run(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+    ) -> Result<()> {
+        let mut stream = ResponseStream::<String, ()>::new(channel);
+        let Some(server_logs) = &ctx.server_logs else {
+            let reason = "the server is not keeping logs in memory".to_owned();
+            return stream
+                .finish(Err(reason))
+                .await
+                .map_err(|err| err.context("server logs"));
+        };
+
+        if !self.follow {
+            let contents = server_logs.contents();
+            stream.send_item(self.tail_of(&contents)).await?;
+            return stream.finish(Ok(())).await;
+        }
+
+        // Drop the logs instead of buffering them without limit when the
+        // client can't keep up, and tell it how many are missed.
+        let (tx, mut rx) = mpsc::channel(FOLLOW_BUFFER_LEN);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let (contents, cancel_token) = server_logs.subscribe(Box::new({
+            let dropped = Arc::clone(&dropped);
+            move |buf| {
+                if let Err(TrySendError::Full(_)) = tx.try_send(buf.to_vec()) {
+                    dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }));
+        stream.send_item(self.tail_of(&contents)).await?;
+
+        // The logs never end, so only the peer ends the stream.
+        let end = stream
+            .forward(
+                &mut rx,
+                |contents| {
+                    let contents = String::from_utf8_lossy(&contents);
+                    match dropped.swap(0, Ordering::Relaxed) {
+                        0 => contents.into_owned(),
+                        count => format!("... {count} log records dropped ...\n{contents}"),
+                    }
+                },
+                std::future::pending(),
+            )
+            .await;
+
+        // Don't log anything before unsubscribing, otherwise the log will
+        // be sent to ourself.
+        drop(cancel_token);
+        if end == StreamEnd::Disconnected {
+            debug!("ended streaming server logs because the peer is closed");
+            return Ok(());
+        }
+        stream.finish(Ok(())).await
+    }
+
+    /// Returns the last lines of the contents as requested.
+    fn tail_of(&self, contents: &[u8]) -> String {
+        let s = String::from_utf8_lossy(contents);
+        let Some(tail) = self.tail else {
+            return s.into_owned();
+        };
+
+        let lines: Vec<_> = s.lines().collect();
+        let start = lines.len().saturating_sub(tail);
+        let mut result = lines[start..].join("\n");
+        if !result.is_empty() {
+            result.push('\n');
+        }
+        result
+    }
+}
+
+impl CommandClient for LogsSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        Some(Box::new(StreamHandler::new(LogsConsumer)))
+    }
+}
+
+struct LogsConsumer;
+
+impl StreamConsumer for LogsConsumer {
+    type Item = String;
+    type Result = ();
+
+    fn on_item(&mut self, item: String) -> Result<()> {
+        let mut stdout = io::stdout();
+        stdout.write_all(item.as_bytes())?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+use std::fmt::Write;
+
+use anyhow::Result;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::IpcChannel;
+use crate::command::{CommandClient, ResponseHandler};
+use crate::lanes::LaneStats;
+use crate::Context as ControlContext;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct MetricsSubcommand;
+
+impl MetricsSubcommand {
+    pub(in crate::command) async fn run(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+    ) -> Result<()> {
+        let proc_stats = ctx.proc_mgr_handle.stats().await;
+        let job_stats = ctx.job_mgr_handle.stats();
+        let jobs = ctx.job_mgr_handle.jobs().await;
+
+        let mut output = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+            _ = writeln!(output, "# HELP petri_{name} {help}");
+            _ = writeln!(output, "# TYPE petri_{name} {kind}");
+            for (labels, value) in samples {
+                _ = writeln!(output, "petri_{name}{labels} {value}");
+            }
+        };
+        let total = |value: u64| [(String::new(), value)];
+        metric(
+            "children_spawned_total",
+            "counter",
+            "Processes spawned since the server started.",
+            &total(proc_stats.children_spawned),
+        );
+        metric(
+            "job_restarts_total",
+            "counter",
+            "Restarts of jobs since the server started.",
+            &total(job_stats.restarts),
+        );
+        metric(
+            "output_bytes_total",
+            "counter",
+            "Bytes read from the output of processes.",
+            &total(proc_stats.output_bytes),
+        );
+        metric(
+            "log_bytes_written_total",
+            "counter",
+            "Bytes written to the log files of processes.",
+            &total(proc_stats.log_bytes_written),
+        );
+        metric(
+            "log_quota_exceeded_total",
+            "counter",
+            "Times the log files of processes exceeded their quotas.",
+            &total(proc_stats.log_quota_exceeded),
+        );
+        metric(
+            "log_lines_skipped_total",
+            "counter",
+            "Lines of the output of processes left out of the log files by sampling.",
+            &total(proc_stats.log_lines_skipped),
+        );
+        let lanes = ctx.lanes.stats();
+        let by_lane = |value: fn(&LaneStats) -> u64| -> Vec<_> {
+            lanes
+                .iter()
+                .map(|stats| (format!("{{lane=\"{}\"}}", stats.lane), value(stats)))
+                .collect()
+        };
+        metric(
+            "control_lane_limit",
+            "gauge",
+            "Commands that a lane runs at once.",
+            &by_lane(|stats| stats.limit as u64),
+        );
+        metric(
+            "control_lane_running",
+            "gauge",
+            "Commands running in a lane.",
+            &by_lane(|stats| stats.running as u64),
+        );
+        metric(
+            "control_lane_queued",
+            "gauge",
+            "Commands waiting for a lane to run them.",
+            &by_lane(|stats| stats.queued as u64),
+        );
+        metric(
+            "control_lane_waits_total",
+            "counter",
+            "Commands that waited for a lane to run them.",
+            &by_lane(|stats| stats.waited),
+        );
+        metric(
+            "control_lane_wait_milliseconds_total",
+            "counter",
+            "Time that commands waited for a lane to run them.",
+            &by_lane(|stats| stats.wait_ms),
+        );
+        metric(
+            "running_jobs",
+            "gauge",
+            "Jobs with a running process.",
+            &total(jobs.iter().filter(|job| job.pid().is_some()).count() as u64),
+        );
+
+        // Only the running processes of jobs with a quota are tracked.
+        let mut log_bytes = vec![];
+        let mut log_quota = vec![];
+        let mut log_exceeded = vec![];
+        for job in &jobs {
+            let Some(pid) = job.pid() else {
+                continue;
+            };
+            let Some(usage) = ctx
+                .proc_mgr_handle
+                .process_with_id(pid)
+                .await
+                .and_then(|process| process.log_usage())
+            else {
+                continue;
+            };
+            let labels = format!(
+                "{{job=\"{}\",jid=\"{}\"}}",
+                escape_label(job.display_name()),
+                escape_label(job.id())
+            );
+            log_bytes.push((labels.clone(), usage.bytes));
+            log_quota.push((labels.clone(), usage.quota.max_bytes));
+            log_exceeded.push((labels, usage.exceeded));
+        }
+        metric(
+            "job_log_bytes",
+            "gauge",
+            "Total size of the log files of jobs with a quota.",
+            &log_bytes,
+        );
+        metric(
+            "job_log_quota_bytes",
+            "gauge",
+            "Quota of the log files of jobs.",
+            &log_quota,
+        );
+        metric(
+            "job_log_quota_exceeded_total",
+            "counter",
+            "Times the log files of the current process of jobs exceeded their quota.",
+            &log_exceeded,
+        );
+
+        channel.write_output(&output).await?;
+        Ok(())
+    }
+}
+
+/// Escapes a label value of the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl CommandClient for MetricsSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::escape_label;
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label("web"), "web");
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
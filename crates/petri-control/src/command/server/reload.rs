@@ -0,0 +1,58 @@
+use anyhow::Result;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::IpcChannel;
+use crate::command::{CommandClient, ResponseHandler};
+use crate::Context as ControlContext;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct ReloadSubcommand;
+
+impl ReloadSubcommand {
+    pub(in crate::command) async fn run(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+    ) -> Result<()> {
+        let Some(reloader) = &ctx.config_reloader else {
+            channel
+                .write_output("the server doesn't support reloading the config\n")
+                .await?;
+            return Err(anyhow!("reloading is not supported").context("server reload"));
+        };
+
+        let summary = match reloader.reload().await {
+            Ok(summary) => summary,
+            Err(err) => {
+                channel
+                    .write_output(&format!("failed to reload the config: {err:#}\n"))
+                    .await?;
+                return Err(err.context("server reload"));
+            }
+        };
+
+        if summary.applied.is_empty() && summary.requires_restart.is_empty() {
+            channel
+                .write_output("config reloaded, nothing changed\n")
+                .await?;
+            return Ok(());
+        }
+        let mut output = String::from("config reloaded\n");
+        for name in &summary.applied {
+            output.push_str(&format!("  applied: {name}\n"));
+        }
+        for name in &summary.requires_restart {
+            output.push_str(&format!("  requires restart: {name}\n"));
+        }
+        channel.write_output(&output).await?;
+
+        Ok(())
+    }
+}
+
+impl CommandClient for ReloadSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        None
+    }
+}
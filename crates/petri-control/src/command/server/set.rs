@@ -0,0 +1,47 @@
+use anyhow::Result;
+use clap::Args;
+use petri_core::runtime_config;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::IpcChannel;
+use crate::command::{CommandClient, ResponseHandler};
+use crate::Context as ControlContext;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct SetSubcommand {
+    /// The option to change, one of `log_level`, `rotation_interval`,
+    /// `kill_timeout`, `output_buffer_size`, `max_concurrent_spawns`,
+    /// `max_processes` and `max_replicas`.
+    #[arg(value_parser = clap::builder::PossibleValuesParser::new(runtime_config::KEYS))]
+    key: String,
+    /// The new value of the option.
+    value: String,
+}
+
+impl SetSubcommand {
+    pub(in crate::command) async fn run(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+    ) -> Result<()> {
+        let runtime_config = ctx.proc_mgr_handle.runtime_config();
+        if let Err(err) = runtime_config.set(&self.key, &self.value) {
+            channel.write_output(&format!("{err}\n")).await?;
+            return Err(err.context("server set"));
+        }
+
+        let value = runtime_config.get().get(&self.key).unwrap_or_default();
+        info!("runtime option `{}` is set to {value}", self.key);
+        channel
+            .write_output(&format!("{} = {value}\n", self.key))
+            .await?;
+
+        Ok(())
+    }
+}
+
+impl CommandClient for SetSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        None
+    }
+}
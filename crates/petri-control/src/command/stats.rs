@@ -0,0 +1,161 @@
+use std::io;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use clap::Args;
+use petri_utils::console_table::{self, ColumnCollection};
+use petri_utils::time::parse_duration;
+use petri_utils::{sparkline, FormattedBytes};
+use serde::{Deserialize, Serialize};
+use tokio::task;
+
+use super::{CommandClient, IpcChannel, OwnedIpcMessagePacket, ResponseHandler};
+use crate::Context as ControlContext;
+
+const SPARKLINE_WIDTH: usize = 60;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct StatsResponse {
+    samples: Vec<Sample>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Sample {
+    timestamp: i64,
+    cpu_percent: f32,
+    rss_bytes: u64,
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct StatsSubcommand {
+    /// Show stats of the process with the given pid (it may have exited).
+    #[arg(short, long)]
+    pid: u32,
+    /// Show stats in the given duration until now (e.g. `30m`, `1h`).
+    #[arg(long, value_parser = parse_duration, default_value = "1h")]
+    last: Duration,
+    /// Print every sample in a table.
+    #[arg(long)]
+    table: bool,
+}
+
+impl StatsSubcommand {
+    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        let Some(store) = &ctx.metrics_store else {
+            channel
+                .write_output("metrics are not enabled on the server\n")
+                .await?;
+            return Err(anyhow!("metrics are not enabled").context("stats"));
+        };
+
+        let since = SystemTime::now()
+            .checked_sub(self.last)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        // Reading the ring files may block.
+        let (store, pid) = (store.clone(), self.pid);
+        let records = task::spawn_blocking(move || store.query(pid, since))
+            .await
+            .unwrap_or_else(|err| Err(io::Error::other(err)));
+        let records = match records {
+            Ok(records) => records,
+            Err(err) => {
+                channel
+                    .write_output(&format!("failed to read metrics: {err}\n"))
+                    .await?;
+                return Err(anyhow::Error::from(err).context("stats"));
+            }
+        };
+
+        let samples = records
+            .into_iter()
+            .map(|record| Sample {
+                timestamp: record.timestamp as i64,
+                cpu_percent: record.cpu_percent,
+                rss_bytes: record.rss_bytes,
+            })
+            .collect();
+        channel.write_response(StatsResponse { samples }).await?;
+        Ok(())
+    }
+}
+
+impl CommandClient for StatsSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        Some(Box::new(StatsResponseHandler {
+            pid: self.pid,
+            table: self.table,
+        }))
+    }
+}
+
+struct StatsResponseHandler {
+    pid: u32,
+    table: bool,
+}
+
+#[async_trait]
+impl ResponseHandler for StatsResponseHandler {
+    async fn handle_response(
+        &mut self,
+        resp: OwnedIpcMessagePacket<serde_json::Value>,
+    ) -> Result<()> {
+        let resp: StatsResponse = resp.into_response().expect("expected a response")?;
+        let samples = resp.samples;
+        let (Some(first), Some(last)) = (samples.first(), samples.last()) else {
+            println!("no samples of process {} in the duration", self.pid);
+            return Ok(());
+        };
+
+        let local_time = |timestamp: i64| {
+            DateTime::from_timestamp(timestamp, 0)
+                .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"))
+        };
+
+        if self.table {
+            let time_column = console_table::ColumnOptions::new("TIME");
+            let cpu_column = console_table::ColumnOptions::new("CPU")
+                .alignment(console_table::Alignment::Right)
+                .spacing(2);
+            let mem_column = console_table::ColumnOptions::new("MEM")
+                .alignment(console_table::Alignment::Right)
+                .spacing(2);
+            let mut table_builder = (time_column, cpu_column, mem_column).into_table_builder();
+            for sample in &samples {
+                table_builder.push_row(
+                    local_time(sample.timestamp)
+                        .map(|time| time.to_string())
+                        .unwrap_or_default(),
+                    format!("{:.1}%", sample.cpu_percent),
+                    FormattedBytes::new(sample.rss_bytes).to_string(),
+                );
+            }
+            println!("{table_builder}");
+            return Ok(());
+        }
+
+        let cpu: Vec<_> = samples.iter().map(|s| s.cpu_percent as f64).collect();
+        let mem: Vec<_> = samples.iter().map(|s| s.rss_bytes as f64).collect();
+        let avg = |values: &[f64]| values.iter().sum::<f64>() / values.len() as f64;
+        let max = |values: &[f64]| values.iter().cloned().fold(0.0, f64::max);
+
+        println!(
+            "CPU  {}  avg {:.1}%  max {:.1}%",
+            sparkline::render(&cpu, SPARKLINE_WIDTH),
+            avg(&cpu),
+            max(&cpu)
+        );
+        println!(
+            "MEM  {}  avg {}  max {}",
+            sparkline::render(&mem, SPARKLINE_WIDTH),
+            FormattedBytes::new(avg(&mem) as u64),
+            FormattedBytes::new(max(&mem) as u64)
+        );
+        if let (Some(from), Some(to)) = (local_time(first.timestamp), local_time(last.timestamp)) {
+            println!("{} samples from {from} to {to}", samples.len());
+        }
+
+        Ok(())
+    }
+}
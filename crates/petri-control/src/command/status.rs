@@ -0,0 +1,187 @@
+use std::sync::atomic::Ordering as AtomicOrdering;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use clap::Args;
+use petri_core::fds;
+use petri_core::process_mgr::SPAWN_FD_RESERVE;
+use petri_utils::time::FormattedUptime;
+use petri_utils::FormattedBytes;
+use serde::{Deserialize, Serialize};
+
+use super::{CommandClient, IpcChannel, OwnedIpcMessagePacket, ResponseHandler};
+use crate::lanes::LaneStats;
+use crate::{Context as ControlContext, LONG_VERSION};
+
+#[derive(Serialize, Deserialize, Debug)]
+struct StatusResponse {
+    pid: u32,
+    /// The long version of the server.
+    #[serde(default)]
+    version: Option<String>,
+    uptime_secs: u64,
+    running_processes: usize,
+    jobs: usize,
+    running_jobs: usize,
+    /// The number of commands that panicked.
+    #[serde(default)]
+    command_panics: u64,
+    /// The open file descriptors of the server, and their limit.
+    #[serde(default)]
+    fds: Option<(usize, u64)>,
+    details: Option<StatusDetails>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct StatusDetails {
+    children_spawned: u64,
+    restarts: u64,
+    output_bytes: u64,
+    log_bytes_written: u64,
+    #[serde(default)]
+    log_quota_exceeded: u64,
+    #[serde(default)]
+    log_lines_skipped: u64,
+    output_subscribers: usize,
+    process_event_handlers: usize,
+    job_event_handlers: usize,
+    pending_job_events: u64,
+    #[serde(default)]
+    queued_spawns: usize,
+    #[serde(default)]
+    lanes: Vec<LaneStats>,
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct StatusSubcommand {
+    /// Also show aggregate numbers since the server started.
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+impl StatusSubcommand {
+    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        let jobs = ctx.job_mgr_handle.jobs().await;
+        let proc_stats = ctx.proc_mgr_handle.stats().await;
+
+        let details = self.verbose.then(|| {
+            let job_stats = ctx.job_mgr_handle.stats();
+            StatusDetails {
+                children_spawned: proc_stats.children_spawned,
+                restarts: job_stats.restarts,
+                output_bytes: proc_stats.output_bytes,
+                log_bytes_written: proc_stats.log_bytes_written,
+                log_quota_exceeded: proc_stats.log_quota_exceeded,
+                log_lines_skipped: proc_stats.log_lines_skipped,
+                output_subscribers: proc_stats.output_subscribers,
+                process_event_handlers: proc_stats.event_handlers,
+                job_event_handlers: job_stats.event_handlers,
+                pending_job_events: job_stats.pending_events,
+                queued_spawns: proc_stats.queued_spawns,
+                lanes: ctx.lanes.stats(),
+            }
+        });
+
+        let resp = StatusResponse {
+            pid: std::process::id(),
+            version: Some(LONG_VERSION.to_owned()),
+            uptime_secs: ctx.started_at.elapsed().as_secs(),
+            running_processes: ctx.proc_mgr_handle.processes().await.len(),
+            jobs: jobs
+                .iter()
+                .filter(|job| !job.description().template)
+                .count(),
+            running_jobs: jobs.iter().filter(|job| job.pid().is_some()).count(),
+            command_panics: ctx.command_panics.load(AtomicOrdering::Relaxed),
+            fds: fds::Usage::current().map(|usage| (usage.open, usage.limit)),
+            details,
+        };
+        channel.write_response(resp).await?;
+        Ok(())
+    }
+}
+
+impl CommandClient for StatusSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        Some(Box::new(StatusResponseHandler))
+    }
+}
+
+struct StatusResponseHandler;
+
+#[async_trait]
+impl ResponseHandler for StatusResponseHandler {
+    async fn handle_response(
+        &mut self,
+        resp: OwnedIpcMessagePacket<serde_json::Value>,
+    ) -> Result<()> {
+        let resp: StatusResponse = resp.into_response().expect("expected a response")?;
+
+        let uptime = FormattedUptime::new(Duration::from_secs(resp.uptime_secs));
+        println!("server:     running (pid: {}), up {uptime}", resp.pid);
+        if let Some(version) = &resp.version {
+            println!("version:    {version}");
+            if version != LONG_VERSION {
+                println!(
+                    "client:     {LONG_VERSION}, restart the server with `petri stop-server` \
+                    to run the same build"
+                );
+            }
+        }
+        println!("processes:  {} running", resp.running_processes);
+        println!("jobs:       {} ({} running)", resp.jobs, resp.running_jobs);
+        if let Some((open, limit)) = resp.fds {
+            let warning = if limit.saturating_sub(open as u64) < SPAWN_FD_RESERVE {
+                ", too few to start processes"
+            } else {
+                ""
+            };
+            println!("fds:        {open} of {limit} open{warning}");
+        }
+        if resp.command_panics > 0 {
+            println!(
+                "panics:     {} commands (see the server logs)",
+                resp.command_panics
+            );
+        }
+
+        let Some(details) = resp.details else {
+            return Ok(());
+        };
+        println!();
+        println!("children spawned:     {}", details.children_spawned);
+        println!("job restarts:         {}", details.restarts);
+        println!(
+            "output processed:     {}",
+            FormattedBytes::new(details.output_bytes)
+        );
+        println!(
+            "log files written:    {}",
+            FormattedBytes::new(details.log_bytes_written)
+        );
+        println!("log quotas exceeded:  {}", details.log_quota_exceeded);
+        println!("log lines skipped:    {}", details.log_lines_skipped);
+        println!("output subscriptions: {}", details.output_subscribers);
+        println!(
+            "event handlers:       {} process, {} job",
+            details.process_event_handlers, details.job_event_handlers
+        );
+        println!("pending job events:   {}", details.pending_job_events);
+        println!("queued spawns:        {}", details.queued_spawns);
+        for stats in &details.lanes {
+            let label = format!("{} commands:", stats.lane);
+            print!(
+                "{label:<21} {} of {} running, {} queued",
+                stats.running, stats.limit, stats.queued
+            );
+            if let Some(average) = stats.wait_ms.checked_div(stats.waited) {
+                let average = Duration::from_millis(average);
+                print!(", {} waited {average:?} on average", stats.waited);
+            }
+            println!();
+        }
+
+        Ok(())
+    }
+}
@@ -1,20 +1,90 @@
+use std::io::{self, IsTerminal, Write};
+
 use anyhow::Result;
+use async_trait::async_trait;
 use clap::Args;
+use petri_utils::console_table::{self, ColumnCollection};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 
-use super::{CommandClient, IpcChannel, ResponseHandler};
+use super::batch::{BatchConsumer, BatchReport};
+use super::{
+    deadline_from_timestamp, parse_deadline, Command, CommandClient, IpcChannel,
+    OwnedIpcMessagePacket, ResponseHandler, StreamHandler,
+};
 use crate::Context as ControlContext;
 
+/// A process whose command line matches the pattern of `stop --match`.
+#[derive(Serialize, Deserialize, Debug)]
+struct MatchedProcess {
+    pid: u32,
+    job: Option<String>,
+    cmd: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct MatchResponse {
+    processes: Vec<MatchedProcess>,
+}
+
 #[derive(Args, Serialize, Deserialize, Debug)]
 pub struct StopSubcommand {
     /// Stop the process with the given pid.
-    #[arg(short, long, required = true)]
-    pid: u32,
+    #[arg(short, long, required_unless_present_any = ["all", "pattern"])]
+    pid: Option<u32>,
+    /// Stop all the running jobs, in the order the server stops them when
+    /// it shuts down, and then the other processes.
+    #[arg(long, conflicts_with_all = ["pid", "tree", "at", "cancel_scheduled"])]
+    all: bool,
+    /// Also kill the descendants of the process and the orphans it left.
+    #[arg(long)]
+    tree: bool,
+    /// Stop the process later, at a time like `18:30` or after a duration like `30m`.
+    #[arg(long, value_name = "TIME", value_parser = parse_deadline, conflicts_with = "tree")]
+    at: Option<i64>,
+    /// Cancel the scheduled stop of the process.
+    #[arg(long, conflicts_with_all = ["tree", "at"])]
+    cancel_scheduled: bool,
+    /// Stop the processes whose command line matches the regex. The
+    /// matches are listed and confirmed before they are stopped.
+    #[arg(
+        long = "match",
+        value_name = "REGEX",
+        value_parser = parse_regex,
+        conflicts_with_all = ["pid", "all", "tree", "at", "cancel_scheduled"]
+    )]
+    pattern: Option<String>,
+    /// Stop the matching processes without confirmation.
+    #[arg(short, long, requires = "pattern", conflicts_with = "dry_run")]
+    yes: bool,
+    /// Only list the matching processes.
+    #[arg(long, requires = "pattern")]
+    dry_run: bool,
+    /// The matching processes confirmed on the client. Others are left
+    /// running, like ones started after they were listed.
+    #[arg(skip)]
+    confirmed: Option<Vec<u32>>,
 }
 
 impl StopSubcommand {
     pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
-        match ctx.proc_mgr_handle.stop_process(self.pid).await {
+        if let Some(pattern) = &self.pattern {
+            return self.stop_matching(ctx, channel, pattern).await;
+        }
+        let Some(pid) = self.pid else {
+            return stop_all(ctx, channel).await;
+        };
+        if self.tree {
+            return self.stop_tree(ctx, channel, pid).await;
+        }
+        if let Some(at) = self.at {
+            return self.schedule(ctx, channel, pid, at).await;
+        }
+        if self.cancel_scheduled {
+            return self.cancel_scheduled(ctx, channel, pid).await;
+        }
+
+        match ctx.proc_mgr_handle.stop_process(pid).await {
             Ok(exit_code) => {
                 channel
                     .write_output(&format!("process stopped with exit code {exit_code}\n"))
@@ -30,10 +100,339 @@ impl StopSubcommand {
 
         Ok(())
     }
+
+    async fn stop_tree(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+        pid: u32,
+    ) -> Result<()> {
+        match ctx.proc_mgr_handle.stop_process_tree(pid).await {
+            Ok((exit_code, killed)) => {
+                if let Some(exit_code) = exit_code {
+                    channel
+                        .write_output(&format!("process stopped with exit code {exit_code}\n"))
+                        .await?;
+                }
+                channel
+                    .write_output(&format!("{killed} descendant process(es) killed\n"))
+                    .await?;
+            }
+            Err(err) => {
+                channel
+                    .write_output("failed to stop the process (is it running?)\n")
+                    .await?;
+                return Err(err.context("stop"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl StopSubcommand {
+    async fn schedule(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+        pid: u32,
+        at: i64,
+    ) -> Result<()> {
+        let deadline = match deadline_from_timestamp(at) {
+            Ok(deadline) => deadline,
+            Err(err) => {
+                channel.write_output(&format!("{err}\n")).await?;
+                return Err(err.context("stop"));
+            }
+        };
+        if let Err(err) = ctx.job_mgr_handle.schedule_stop(pid, deadline).await {
+            channel
+                .write_output("failed to schedule the stop (is it running?)\n")
+                .await?;
+            return Err(err.context("stop"));
+        }
+
+        channel
+            .write_output(&format!(
+                "process {pid} will be stopped at {}\n",
+                deadline.format("%Y-%m-%d %T")
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn cancel_scheduled(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+        pid: u32,
+    ) -> Result<()> {
+        if !ctx.job_mgr_handle.cancel_scheduled_stop(pid).await {
+            channel
+                .write_output("no stop is scheduled for the process\n")
+                .await?;
+            return Err(anyhow!("no scheduled stop").context("stop"));
+        }
+
+        channel
+            .write_output("the scheduled stop is cancelled\n")
+            .await?;
+        Ok(())
+    }
+}
+
+impl StopSubcommand {
+    /// Lists the processes matching the pattern, or stops them once they
+    /// are confirmed. Processes of jobs are stopped as jobs, so they are
+    /// not restarted.
+    async fn stop_matching(
+        &self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+        pattern: &str,
+    ) -> Result<()> {
+        let regex = match Regex::new(pattern) {
+            Ok(regex) => regex,
+            Err(err) => {
+                channel
+                    .write_output(&format!("invalid pattern: {err}\n"))
+                    .await?;
+                return Err(anyhow!(err).context("stop"));
+            }
+        };
+
+        let jobs = ctx.job_mgr_handle.jobs().await;
+        let matched: Vec<_> = ctx
+            .proc_mgr_handle
+            .processes()
+            .await
+            .into_iter()
+            .filter(|process| regex.is_match(process.cmd()))
+            .filter(|process| {
+                self.confirmed
+                    .as_ref()
+                    .is_none_or(|pids| pids.contains(&process.id()))
+            })
+            .map(|process| {
+                let job = jobs.iter().find(|job| job.pid() == Some(process.id()));
+                (process, job)
+            })
+            .collect();
+
+        if !self.yes && self.confirmed.is_none() {
+            let processes = matched
+                .iter()
+                .map(|(process, job)| MatchedProcess {
+                    pid: process.id(),
+                    job: job.map(|job| job.display_name().to_owned()),
+                    cmd: process.cmd().to_owned(),
+                })
+                .collect();
+            channel.write_response(MatchResponse { processes }).await?;
+            return Ok(());
+        }
+
+        let mut report = BatchReport::default();
+        if matched.is_empty() {
+            channel
+                .write_output(&format!("no process matches `{pattern}`\n"))
+                .await?;
+        }
+        for (process, job) in matched {
+            let pid = process.id();
+            let result = match job {
+                Some(job) => ctx.job_mgr_handle.stop_job(job.id()).await,
+                None => ctx.proc_mgr_handle.stop_process(pid).await.map(Some),
+            };
+            let name = match job {
+                Some(job) => format!("{} (pid: {pid})", job.display_name()),
+                None => format!("process {pid}"),
+            };
+            match result {
+                Ok(Some(exit_code)) => {
+                    report
+                        .succeeded(
+                            channel,
+                            &name,
+                            format!("{name} stopped with exit code {exit_code}"),
+                        )
+                        .await?;
+                }
+                Ok(None) => {
+                    report
+                        .skipped(channel, &name, format!("{name} is not running"))
+                        .await?;
+                }
+                Err(err) => {
+                    report
+                        .failed(channel, &name, format!("failed to stop {name}: {err:#}"))
+                        .await?;
+                }
+            }
+        }
+
+        report
+            .finish(channel)
+            .await
+            .map_err(|err| err.context("stop"))
+    }
+}
+
+/// Stops the running jobs as jobs, so they are not restarted, and then the
+/// processes that are not of jobs.
+async fn stop_all(ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+    let mut report = BatchReport::default();
+    for wave in ctx.job_mgr_handle.shutdown_waves().await {
+        for jid in wave {
+            let Some(job) = ctx.job_mgr_handle.job_with_id(&jid).await else {
+                continue;
+            };
+            let name = job.display_name();
+            match ctx.job_mgr_handle.stop_job(&jid).await {
+                Ok(Some(exit_code)) => {
+                    report
+                        .succeeded(
+                            channel,
+                            name,
+                            format!("{name} stopped with exit code {exit_code}"),
+                        )
+                        .await?;
+                }
+                Ok(None) => {
+                    report
+                        .skipped(channel, name, format!("{name} is not running"))
+                        .await?;
+                }
+                Err(err) => {
+                    report
+                        .failed(channel, name, format!("failed to stop {name}: {err:#}"))
+                        .await?;
+                }
+            }
+        }
+    }
+
+    for process in ctx.proc_mgr_handle.processes().await {
+        let pid = process.id();
+        let name = format!("process {pid}");
+        match ctx.proc_mgr_handle.stop_process(pid).await {
+            Ok(exit_code) => {
+                report
+                    .succeeded(
+                        channel,
+                        &name,
+                        format!("{name} stopped with exit code {exit_code}"),
+                    )
+                    .await?;
+            }
+            Err(err) => {
+                report
+                    .failed(channel, &name, format!("failed to stop {name}: {err:#}"))
+                    .await?;
+            }
+        }
+    }
+
+    report
+        .finish(channel)
+        .await
+        .map_err(|err| err.context("stop"))
 }
 
 impl CommandClient for StopSubcommand {
     fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        if self.all || self.yes || self.confirmed.is_some() {
+            return Some(Box::new(StreamHandler::new(BatchConsumer)));
+        }
+        if let Some(pattern) = &self.pattern {
+            return Some(Box::new(MatchResponseHandler {
+                pattern: pattern.clone(),
+                dry_run: self.dry_run,
+                follow_up: None,
+                exit_status: 0,
+            }));
+        }
         None
     }
 }
+
+fn parse_regex(s: &str) -> Result<String> {
+    Regex::new(s)?;
+    Ok(s.to_owned())
+}
+
+/// Lists the matching processes, and asks whether to stop them.
+struct MatchResponseHandler {
+    pattern: String,
+    dry_run: bool,
+    follow_up: Option<Command>,
+    exit_status: i32,
+}
+
+#[async_trait]
+impl ResponseHandler for MatchResponseHandler {
+    async fn handle_response(
+        &mut self,
+        resp: OwnedIpcMessagePacket<serde_json::Value>,
+    ) -> Result<()> {
+        let resp: MatchResponse = resp.into_response().expect("expected a response")?;
+        if resp.processes.is_empty() {
+            println!("no process matches `{}`", self.pattern);
+            return Ok(());
+        }
+
+        let pid_column = console_table::ColumnOptions::new("PID")
+            .alignment(console_table::Alignment::Right)
+            .spacing(2);
+        let job_column = console_table::ColumnOptions::new("JOB").spacing(2);
+        let cmd_column = console_table::ColumnOptions::new("CMD");
+        let mut table_builder = (pid_column, job_column, cmd_column).into_table_builder();
+        for process in &resp.processes {
+            table_builder.push_row(
+                process.pid.to_string(),
+                process.job.clone().unwrap_or_default(),
+                process.cmd.clone(),
+            );
+        }
+        println!("{table_builder}");
+        if self.dry_run {
+            return Ok(());
+        }
+
+        let count = resp.processes.len();
+        if !io::stdin().is_terminal() {
+            eprintln!("refusing to stop {count} process(es) without confirmation, pass `--yes`");
+            self.exit_status = 1;
+            return Ok(());
+        }
+        print!("stop {count} process(es)? [y/N] ");
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            println!("nothing is stopped");
+            return Ok(());
+        }
+
+        self.follow_up = Some(Command::Stop(StopSubcommand {
+            pid: None,
+            all: false,
+            tree: false,
+            at: None,
+            cancel_scheduled: false,
+            pattern: Some(self.pattern.clone()),
+            yes: false,
+            dry_run: false,
+            confirmed: Some(resp.processes.iter().map(|process| process.pid).collect()),
+        }));
+        Ok(())
+    }
+
+    fn exit_status(&self) -> i32 {
+        self.exit_status
+    }
+
+    fn follow_up(&mut self) -> Option<Command> {
+        self.follow_up.take()
+    }
+}
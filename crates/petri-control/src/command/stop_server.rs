@@ -1,26 +1,67 @@
 use anyhow::Result;
 use clap::Args;
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
 
-use super::{CommandClient, IpcChannel, ResponseHandler};
-use crate::Context as ControlContext;
+use super::{
+    CommandClient, IpcChannel, ResponseHandler, ResponseStream, StreamConsumer, StreamHandler,
+};
+use crate::{Context as ControlContext, ShutdownEvent, ShutdownPhase};
 
 #[derive(Args, Serialize, Deserialize, Debug)]
 pub struct StopServerSubcommand;
 
 impl StopServerSubcommand {
     pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        // Subscribe before requesting, so no events are missed. The server
+        // waits for the subscribers before disconnecting the clients.
+        let mut progress = ctx.shutdown_progress.subscribe();
         _ = ctx.shutdown_request.send(true);
-        channel
-            .write_output("requested the server to shutdown\n")
-            .await?;
 
-        Ok(())
+        let mut stream = ResponseStream::<ShutdownEvent, ()>::new(channel);
+        loop {
+            let event = match progress.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+            let is_last = matches!(
+                event,
+                ShutdownEvent::PhaseStarted(ShutdownPhase::StopControl)
+            );
+            if stream.send_item(event).await.is_err() {
+                return Ok(());
+            }
+            if is_last {
+                break;
+            }
+        }
+        stream.finish(Ok(())).await
     }
 }
 
 impl CommandClient for StopServerSubcommand {
     fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
-        None
+        Some(Box::new(StreamHandler::new(ShutdownConsumer)))
+    }
+}
+
+struct ShutdownConsumer;
+
+impl StreamConsumer for ShutdownConsumer {
+    type Item = ShutdownEvent;
+    type Result = ();
+
+    fn on_item(&mut self, event: ShutdownEvent) -> Result<()> {
+        match event {
+            ShutdownEvent::PhaseStarted(phase) => println!("{phase}..."),
+            ShutdownEvent::PhaseTimedOut(phase) => println!("{phase} timed out, moving on"),
+            ShutdownEvent::JobStopped { name, result } => match result {
+                Ok(Some(exit_code)) => println!("  {name} stopped with exit code {exit_code}"),
+                Ok(None) => println!("  {name} exited by itself"),
+                Err(reason) => println!("  failed to stop {name}: {reason}"),
+            },
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,356 @@
+//! Typed streams of responses, for commands that send their results as
+//! they come instead of in a single response.
+
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::io::{self, Write};
+use std::marker::PhantomData;
+use std::str;
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use petri_utils::FormattedBytes;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use super::{IpcChannel, OwnedIpcMessagePacket, ResponseHandler};
+use crate::cli::bytes::Base64Bytes;
+use crate::cli::EndOfStreamReason;
+
+/// A packet of a [`ResponseStream`], sent as a response.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum StreamPacket<T, R> {
+    Item(T),
+    Progress(Progress),
+    /// The last packet, with the result of the command.
+    Finished(Result<R, String>),
+}
+
+/// The progress of a long-running step, shown to the user on the client.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Progress {
+    pub message: String,
+    pub done: u64,
+    pub total: Option<u64>,
+}
+
+/// The result of streaming the output of a process, so clients can tell
+/// its end from a broken connection.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OutputEnd {
+    pub reason: EndOfStreamReason,
+    /// The exit code of the process, `None` if it's still running.
+    pub exit_code: Option<i32>,
+    /// The bytes of output streamed.
+    pub bytes: u64,
+    /// The chunks of output that were not valid UTF-8 (e.g. binary output,
+    /// or cut in the middle of a character), which were streamed
+    /// base64-encoded. Kept for older clients, which expect it.
+    pub truncated_frames: u64,
+}
+
+/// A chunk of the output of a process. It's sent as text if it's valid
+/// UTF-8, which older clients can read, and base64-encoded otherwise, so
+/// any output reaches the client as it is.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum OutputChunk {
+    Text(String),
+    Binary { base64: Base64Bytes },
+}
+
+/// Converts the output of a process to the items of a stream, counting
+/// it for the [`OutputEnd`].
+#[derive(Default)]
+pub(super) struct OutputCounter {
+    bytes: u64,
+    truncated_frames: u64,
+}
+
+/// Why [`ResponseStream::forward`] returned.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StreamEnd {
+    /// All the senders of the items are dropped.
+    Exhausted,
+    /// The future to stop forwarding resolved.
+    Stopped,
+    /// The client closed the connection or cancelled the command, so the
+    /// stream should end without finishing.
+    Disconnected,
+}
+
+/// Receives the items that [`ResponseStream::forward`] sends, from a
+/// bounded or an unbounded channel.
+pub trait ItemReceiver<U> {
+    fn recv_item(&mut self) -> impl Future<Output = Option<U>> + Send;
+}
+
+impl<U: Send> ItemReceiver<U> for mpsc::Receiver<U> {
+    fn recv_item(&mut self) -> impl Future<Output = Option<U>> + Send {
+        self.recv()
+    }
+}
+
+impl<U: Send> ItemReceiver<U> for mpsc::UnboundedReceiver<U> {
+    fn recv_item(&mut self) -> impl Future<Output = Option<U>> + Send {
+        self.recv()
+    }
+}
+
+/// Sends items of type `T` to the client, and then the result of type `R`.
+/// The client handles them with a [`StreamHandler`].
+pub struct ResponseStream<'c, T, R> {
+    channel: &'c mut IpcChannel,
+    _marker: PhantomData<fn(T, R)>,
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.total {
+            Some(total) => write!(f, "{} ({}/{total})", self.message, self.done),
+            None => write!(f, "{} ({})", self.message, self.done),
+        }
+    }
+}
+
+impl Display for OutputEnd {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} streamed", FormattedBytes::new(self.bytes))
+    }
+}
+
+impl OutputCounter {
+    pub(super) fn item(&mut self, contents: Arc<[u8]>) -> OutputChunk {
+        self.bytes += contents.len() as u64;
+        let chunk = OutputChunk::new(&contents);
+        if matches!(chunk, OutputChunk::Binary { .. }) {
+            self.truncated_frames += 1;
+        }
+        chunk
+    }
+
+    pub(super) fn finish(self, reason: EndOfStreamReason, exit_code: Option<i32>) -> OutputEnd {
+        OutputEnd {
+            reason,
+            exit_code,
+            bytes: self.bytes,
+            truncated_frames: self.truncated_frames,
+        }
+    }
+}
+
+impl OutputChunk {
+    /// Sends the contents as text if they are valid UTF-8.
+    pub(super) fn new(contents: &[u8]) -> Self {
+        match str::from_utf8(contents) {
+            Ok(text) => OutputChunk::Text(text.to_owned()),
+            Err(_) => OutputChunk::Binary {
+                base64: Base64Bytes(contents.to_vec()),
+            },
+        }
+    }
+
+    /// Writes the chunk to the output as it was written by the process.
+    pub(super) fn write_to(&self, mut output: impl Write) -> io::Result<()> {
+        match self {
+            OutputChunk::Text(text) => output.write_all(text.as_bytes())?,
+            OutputChunk::Binary { base64 } => output.write_all(&base64.0)?,
+        }
+        output.flush()
+    }
+}
+
+impl<'c, T, R> ResponseStream<'c, T, R>
+where
+    T: Serialize + Send + Sync + 'static,
+    R: Serialize + Send + Sync + 'static,
+{
+    pub(crate) fn new(channel: &'c mut IpcChannel) -> Self {
+        Self {
+            channel,
+            _marker: PhantomData,
+        }
+    }
+
+    pub async fn send_item(&mut self, item: T) -> io::Result<()> {
+        self.send(StreamPacket::Item(item)).await
+    }
+
+    pub async fn send_progress(&mut self, progress: Progress) -> io::Result<()> {
+        self.send(StreamPacket::Progress(progress)).await
+    }
+
+    /// Sends the items received until the senders are dropped, `stop`
+    /// resolves or the client disconnects, whichever comes first.
+    pub async fn forward<U, S>(
+        &mut self,
+        items: &mut impl ItemReceiver<U>,
+        mut to_item: impl FnMut(U) -> T,
+        stop: S,
+    ) -> StreamEnd
+    where
+        S: Future<Output = ()>,
+    {
+        tokio::pin!(stop);
+        loop {
+            tokio::select! {
+                item = items.recv_item() => {
+                    let Some(item) = item else {
+                        return StreamEnd::Exhausted;
+                    };
+                    if self.send_item(to_item(item)).await.is_err() {
+                        return StreamEnd::Disconnected;
+                    }
+                },
+                _ = self.channel.closed() => {
+                    return StreamEnd::Disconnected;
+                },
+                _ = &mut stop => {
+                    return StreamEnd::Stopped;
+                },
+            }
+        }
+    }
+
+    /// Ends the stream with the result. An error result fails the command
+    /// too, after the client is told the reason.
+    pub async fn finish(mut self, result: Result<R, String>) -> Result<()> {
+        let reason = result.as_ref().err().cloned();
+        self.send(StreamPacket::Finished(result)).await?;
+        match reason {
+            Some(reason) => Err(anyhow!(reason)),
+            None => Ok(()),
+        }
+    }
+
+    async fn send(&mut self, packet: StreamPacket<T, R>) -> io::Result<()> {
+        self.channel.write_response(packet).await
+    }
+}
+
+/// Handles the packets of a [`ResponseStream`] on the client.
+pub trait StreamConsumer: Send + 'static {
+    type Item: DeserializeOwned + Send;
+    type Result: DeserializeOwned + Send;
+
+    fn on_item(&mut self, item: Self::Item) -> Result<()>;
+
+    fn on_progress(&mut self, progress: Progress) -> Result<()> {
+        eprintln!("{progress}");
+        Ok(())
+    }
+
+    /// Handles the result, and returns the status that the client should
+    /// exit with.
+    fn on_finish(&mut self, result: Result<Self::Result, String>) -> Result<i32> {
+        match result {
+            Ok(_) => Ok(0),
+            Err(reason) => {
+                eprintln!("{reason}");
+                Ok(1)
+            }
+        }
+    }
+}
+
+/// The [`ResponseHandler`] of commands that respond with a
+/// [`ResponseStream`]. The client fails if the stream ends without the
+/// result.
+pub struct StreamHandler<C> {
+    consumer: C,
+    exit_status: Option<i32>,
+}
+
+impl<C: StreamConsumer> StreamHandler<C> {
+    pub fn new(consumer: C) -> Self {
+        Self {
+            consumer,
+            exit_status: None,
+        }
+    }
+}
+
+#[async_trait]
+impl<C: StreamConsumer> ResponseHandler for StreamHandler<C> {
+    async fn handle_response(
+        &mut self,
+        resp: OwnedIpcMessagePacket<serde_json::Value>,
+    ) -> Result<()> {
+        let packet: StreamPacket<C::Item, C::Result> =
+            resp.into_response().expect("expected a response")?;
+        match packet {
+            StreamPacket::Item(item) => self.consumer.on_item(item),
+            StreamPacket::Progress(progress) => self.consumer.on_progress(progress),
+            StreamPacket::Finished(result) => {
+                self.exit_status = Some(self.consumer.on_finish(result)?);
+                Ok(())
+            }
+        }
+    }
+
+    fn exit_status(&self) -> i32 {
+        self.exit_status.unwrap_or(1)
+    }
+
+    fn expects_more(&self) -> bool {
+        self.exit_status.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use serde_json::json;
+
+    use super::{StreamConsumer, StreamHandler, StreamPacket};
+    use crate::cli::OwnedIpcMessagePacket;
+    use crate::command::ResponseHandler;
+
+    #[derive(Default)]
+    struct Collect(Vec<u32>);
+
+    impl StreamConsumer for Collect {
+        type Item = u32;
+        type Result = ();
+
+        fn on_item(&mut self, item: u32) -> Result<()> {
+            self.0.push(item);
+            Ok(())
+        }
+    }
+
+    fn packet(packet: StreamPacket<u32, ()>) -> OwnedIpcMessagePacket<serde_json::Value> {
+        OwnedIpcMessagePacket::Response(serde_json::to_value(packet).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_stream_handler() {
+        let mut handler = StreamHandler::new(Collect::default());
+        handler
+            .handle_response(packet(StreamPacket::Item(1)))
+            .await
+            .unwrap();
+        handler
+            .handle_response(packet(StreamPacket::Item(2)))
+            .await
+            .unwrap();
+        // Streams cut before the result fail the client.
+        assert!(handler.expects_more());
+        assert_eq!(handler.exit_status(), 1);
+
+        handler
+            .handle_response(packet(StreamPacket::Finished(Ok(()))))
+            .await
+            .unwrap();
+        assert!(!handler.expects_more());
+        assert_eq!(handler.exit_status(), 0);
+        assert_eq!(handler.consumer.0, [1, 2]);
+
+        let mut handler = StreamHandler::new(Collect::default());
+        let failed = OwnedIpcMessagePacket::Response(json!({ "Finished": { "Err": "oops" } }));
+        handler.handle_response(failed).await.unwrap();
+        assert_eq!(handler.exit_status(), 1);
+    }
+}
@@ -0,0 +1,67 @@
+use std::fmt::Write as _;
+
+use anyhow::Result;
+use clap::{ArgGroup, Args};
+use serde::{Deserialize, Serialize};
+
+use super::{parse_key_value, CommandClient, IpcChannel, ResponseHandler};
+use crate::Context as ControlContext;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+#[command(group(ArgGroup::new("changes").required(true).multiple(true)))]
+pub struct TagSubcommand {
+    /// Tag the process with the given pid.
+    #[arg(short, long)]
+    pid: u32,
+    /// Set tags on the process, like `reason=bisect`.
+    #[arg(value_name = "KEY=VALUE", value_parser = parse_key_value, group = "changes")]
+    tags: Vec<(String, String)>,
+    /// Remove the tag with the key.
+    #[arg(long = "remove", value_name = "KEY", group = "changes")]
+    removed: Vec<String>,
+    /// Set a free-text note on the process, or clear it if it's empty.
+    #[arg(long, group = "changes")]
+    note: Option<String>,
+}
+
+impl TagSubcommand {
+    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        let Some(process) = ctx.proc_mgr_handle.process_with_id(self.pid).await else {
+            channel
+                .write_output(&format!("process with id `{}` is not found\n", self.pid))
+                .await?;
+            return Err(anyhow!("process is not found").context("tag"));
+        };
+
+        let annotations = process.annotate(|annotations| {
+            for key in &self.removed {
+                annotations.tags.remove(key);
+            }
+            annotations.tags.extend(self.tags);
+            if let Some(note) = self.note {
+                annotations.note = Some(note).filter(|note| !note.is_empty());
+            }
+            annotations.clone()
+        });
+
+        let mut output = format!("process {} is tagged", self.pid);
+        if annotations.tags.is_empty() {
+            output.push_str(" with nothing");
+        }
+        for (key, value) in &annotations.tags {
+            _ = write!(output, " {key}={value}");
+        }
+        output.push('\n');
+        if let Some(note) = &annotations.note {
+            _ = writeln!(output, "note: {note}");
+        }
+        channel.write_output(&output).await?;
+        Ok(())
+    }
+}
+
+impl CommandClient for TagSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        None
+    }
+}
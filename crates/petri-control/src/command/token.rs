@@ -0,0 +1,17 @@
+mod create;
+mod ls;
+mod revoke;
+
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+
+#[derive(Subcommand, Serialize, Deserialize, Debug)]
+#[serde(tag = "name", content = "args", rename_all = "kebab-case")]
+pub enum TokenSubcommand {
+    /// Create a token for clients of other users
+    Create(create::CreateSubcommand),
+    /// List tokens
+    Ls(ls::ListSubcommand),
+    /// Revoke a token
+    Revoke(revoke::RevokeSubcommand),
+}
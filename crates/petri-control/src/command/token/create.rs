@@ -0,0 +1,55 @@
+use anyhow::Result;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{Role, TOKEN_ENV};
+use crate::cli::IpcChannel;
+use crate::command::{CommandClient, ResponseHandler};
+use crate::Context as ControlContext;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct CreateSubcommand {
+    /// The role of the token.
+    #[arg(short, long, value_enum)]
+    role: Role,
+    /// A name to tell what the token is for.
+    #[arg(short, long)]
+    name: Option<String>,
+}
+
+impl CreateSubcommand {
+    pub(in crate::command) async fn run(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+    ) -> Result<()> {
+        let (token, info) = match ctx.token_store.create(self.role, self.name) {
+            Ok(created) => created,
+            Err(err) => {
+                channel
+                    .write_output(&format!("failed to create the token: {err:#}\n"))
+                    .await?;
+                return Err(err.context("token create"));
+            }
+        };
+
+        info!("created {} token {}", info.role.as_str(), info.id);
+        channel
+            .write_output(&format!(
+                "created {} token {}:\n\n    {token}\n\n\
+                Pass it to the client with the `{TOKEN_ENV}` environment variable, \
+                it will not be shown again.\n",
+                info.role.as_str(),
+                info.id
+            ))
+            .await?;
+
+        Ok(())
+    }
+}
+
+impl CommandClient for CreateSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        None
+    }
+}
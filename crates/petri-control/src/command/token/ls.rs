@@ -0,0 +1,67 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use clap::Args;
+use petri_utils::console_table::{self, ColumnCollection};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::TokenInfo;
+use crate::cli::{IpcChannel, OwnedIpcMessagePacket};
+use crate::command::{CommandClient, ResponseHandler};
+use crate::Context as ControlContext;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ListResponse {
+    tokens: Vec<TokenInfo>,
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct ListSubcommand;
+
+impl ListSubcommand {
+    pub(in crate::command) async fn run(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+    ) -> Result<()> {
+        let tokens = ctx.token_store.tokens();
+        channel.write_response(ListResponse { tokens }).await?;
+        Ok(())
+    }
+}
+
+impl CommandClient for ListSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        Some(Box::new(ListResponseHandler))
+    }
+}
+
+struct ListResponseHandler;
+
+#[async_trait]
+impl ResponseHandler for ListResponseHandler {
+    async fn handle_response(
+        &mut self,
+        resp: OwnedIpcMessagePacket<serde_json::Value>,
+    ) -> Result<()> {
+        let resp: ListResponse = resp.into_response().expect("expected a response")?;
+
+        let id_column = console_table::ColumnOptions::new("ID");
+        let role_column = console_table::ColumnOptions::new("ROLE").spacing(2);
+        let created_column = console_table::ColumnOptions::new("CREATED").spacing(2);
+        let name_column = console_table::ColumnOptions::new("NAME").spacing(2);
+
+        let mut table_builder =
+            (id_column, role_column, created_column, name_column).into_table_builder();
+        for token in resp.tokens {
+            table_builder.push_row(
+                token.id,
+                token.role.as_str().to_owned(),
+                token.created_at,
+                token.name.unwrap_or_default(),
+            );
+        }
+
+        println!("{table_builder}");
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::Result;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::IpcChannel;
+use crate::command::{CommandClient, ResponseHandler};
+use crate::Context as ControlContext;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct RevokeSubcommand {
+    /// The id of the token (shown by `token ls`).
+    id: String,
+}
+
+impl RevokeSubcommand {
+    pub(in crate::command) async fn run(
+        self,
+        ctx: &ControlContext,
+        channel: &mut IpcChannel,
+    ) -> Result<()> {
+        let info = match ctx.token_store.revoke(&self.id) {
+            Ok(info) => info,
+            Err(err) => {
+                channel.write_output(&format!("{err:#}\n")).await?;
+                return Err(err.context("token revoke"));
+            }
+        };
+
+        info!("revoked {} token {}", info.role.as_str(), info.id);
+        channel
+            .write_output(&format!("revoked token {}\n", info.id))
+            .await?;
+
+        Ok(())
+    }
+}
+
+impl CommandClient for RevokeSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        None
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use clap::Args;
+use petri_core::job_mgr::Handle as JobManagerHandle;
+use petri_core::metrics;
+use petri_core::process_mgr::Handle as ProcessManagerHandle;
+use petri_utils::console_table::{self, ColumnCollection};
+use petri_utils::time::parse_nonzero_duration;
+use petri_utils::FormattedBytes;
+use serde::{Deserialize, Serialize};
+use tokio::task;
+use tokio::time::{self, MissedTickBehavior};
+
+use super::{
+    CommandClient, IpcChannel, ResponseHandler, ResponseStream, StreamConsumer, StreamHandler,
+};
+use crate::cli::{ClientEnv, RenderHints, CLIENT_ENV};
+use crate::Context as ControlContext;
+
+/// The whole table is sent every this many updates, so a client can't
+/// drift from the server for long.
+const FULL_SYNC_INTERVAL: u64 = 30;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct TopSubcommand {
+    /// How often to refresh the usage (e.g. `1s`).
+    #[arg(short, long, value_name = "DURATION", value_parser = parse_nonzero_duration, default_value = "2s")]
+    interval: Duration,
+    /// Exit after sampling the given number of times.
+    #[arg(short = 'n', long, value_name = "N")]
+    iterations: Option<u64>,
+}
+
+/// The resource usage of a running process.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+struct TopRow {
+    pid: u32,
+    job: Option<String>,
+    /// The CPU usage since the last update, 100% means one core, rounded
+    /// to a tenth so small changes aren't sent.
+    cpu_percent: f32,
+    rss_bytes: u64,
+    cmd: String,
+}
+
+/// An update of the rows shown by `top`, which are keyed by their pids.
+/// Only the changed rows are sent between full syncs, which keeps the
+/// stream small for servers managing hundreds of processes.
+#[derive(Serialize, Deserialize, Debug)]
+enum TopUpdate {
+    /// All the rows, replacing the ones the client has.
+    Full(Vec<TopRow>),
+    /// The rows that are new or changed, and the pids of the removed ones.
+    Delta {
+        changed: Vec<TopRow>,
+        removed: Vec<u32>,
+    },
+}
+
+/// Samples the running processes, and tells the rows that changed.
+struct TopSampler {
+    proc_mgr_handle: ProcessManagerHandle,
+    job_mgr_handle: JobManagerHandle,
+    render: RenderHints,
+    /// The CPU time of the processes when they were last sampled.
+    cpu_times: HashMap<u32, (Instant, Duration)>,
+    rows: BTreeMap<u32, TopRow>,
+}
+
+impl TopSubcommand {
+    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        let mut stream = ResponseStream::<TopUpdate, ()>::new(channel);
+        let mut sampler = TopSampler {
+            proc_mgr_handle: ctx.proc_mgr_handle.clone(),
+            job_mgr_handle: ctx.job_mgr_handle.clone(),
+            render: CLIENT_ENV.with(ClientEnv::render),
+            cpu_times: HashMap::new(),
+            rows: BTreeMap::new(),
+        };
+
+        // Sampling waits for the updates to be sent, so a slow client only
+        // slows down its own updates instead of piling them up.
+        let mut ticker = time::interval(self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        for updates in 0..self.iterations.unwrap_or(u64::MAX) {
+            ticker.tick().await;
+            let Some(update) = sampler.update(updates % FULL_SYNC_INTERVAL == 0).await else {
+                continue;
+            };
+            if stream.send_item(update).await.is_err() {
+                debug!("ended `top` because the peer is closed");
+                return Ok(());
+            }
+        }
+        stream.finish(Ok(())).await
+    }
+}
+
+impl TopSampler {
+    /// Samples the processes, returning all the rows if `full`, or the
+    /// changed ones if any.
+    async fn update(&mut self, full: bool) -> Option<TopUpdate> {
+        let rows = self.sample().await;
+        let update = if full {
+            Some(TopUpdate::Full(rows.values().cloned().collect()))
+        } else {
+            let (changed, removed) = diff_rows(&self.rows, &rows);
+            (!changed.is_empty() || !removed.is_empty())
+                .then_some(TopUpdate::Delta { changed, removed })
+        };
+        self.rows = rows;
+        update
+    }
+
+    async fn sample(&mut self) -> BTreeMap<u32, TopRow> {
+        let jobs: HashMap<_, _> = self
+            .job_mgr_handle
+            .jobs()
+            .await
+            .iter()
+            .filter_map(|job| Some((job.pid()?, job.display_name().to_owned())))
+            .collect();
+
+        let processes = self.proc_mgr_handle.processes().await;
+        let pids: Vec<_> = processes.iter().map(|process| process.id()).collect();
+        // Sampling reads the kernel for every process, which may block.
+        let samples = task::spawn_blocking(move || {
+            pids.into_iter()
+                .map(|pid| metrics::sample_process(pid).ok())
+                .collect()
+        })
+        .await
+        .unwrap_or_else(|_| vec![None; processes.len()]);
+
+        let now = Instant::now();
+        let mut cpu_times = HashMap::new();
+        let mut rows = BTreeMap::new();
+        for (process, sample) in processes.iter().zip(samples) {
+            let pid = process.id();
+            // The process may just exit.
+            let Some(sample) = sample else {
+                continue;
+            };
+            let cpu_percent = match self.cpu_times.get(&pid) {
+                Some((last_at, last_cpu_time)) => {
+                    let cpu_time = sample.cpu_time.saturating_sub(*last_cpu_time);
+                    let cpu_percent = metrics::cpu_percent(cpu_time, now - *last_at);
+                    (cpu_percent * 10.0).round() / 10.0
+                }
+                None => 0.0,
+            };
+            cpu_times.insert(pid, (now, sample.cpu_time));
+            rows.insert(
+                pid,
+                TopRow {
+                    pid,
+                    job: jobs.get(&pid).cloned(),
+                    cpu_percent,
+                    rss_bytes: sample.rss_bytes,
+                    cmd: self.render.truncate_cmd(process.cmd()).into_owned(),
+                },
+            );
+        }
+        self.cpu_times = cpu_times;
+        rows
+    }
+}
+
+/// Returns the rows in `next` that are new or changed since `prev`, and
+/// the pids of the rows that are gone.
+fn diff_rows(
+    prev: &BTreeMap<u32, TopRow>,
+    next: &BTreeMap<u32, TopRow>,
+) -> (Vec<TopRow>, Vec<u32>) {
+    let changed = next
+        .values()
+        .filter(|row| prev.get(&row.pid) != Some(row))
+        .cloned()
+        .collect();
+    let removed = prev
+        .keys()
+        .filter(|pid| !next.contains_key(pid))
+        .copied()
+        .collect();
+    (changed, removed)
+}
+
+impl TopUpdate {
+    /// Applies the update to the rows of the client.
+    fn apply(self, rows: &mut BTreeMap<u32, TopRow>) {
+        match self {
+            TopUpdate::Full(full) => {
+                *rows = full.into_iter().map(|row| (row.pid, row)).collect();
+            }
+            TopUpdate::Delta { changed, removed } => {
+                for pid in removed {
+                    rows.remove(&pid);
+                }
+                rows.extend(changed.into_iter().map(|row| (row.pid, row)));
+            }
+        }
+    }
+}
+
+impl CommandClient for TopSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        Some(Box::new(StreamHandler::new(TopConsumer {
+            rows: BTreeMap::new(),
+            redraw: io::stdout().is_terminal(),
+        })))
+    }
+}
+
+struct TopConsumer {
+    rows: BTreeMap<u32, TopRow>,
+    /// Whether to redraw the table in place, instead of printing one after
+    /// another.
+    redraw: bool,
+}
+
+impl StreamConsumer for TopConsumer {
+    type Item = TopUpdate;
+    type Result = ();
+
+    fn on_item(&mut self, update: TopUpdate) -> Result<()> {
+        update.apply(&mut self.rows);
+
+        let mut rows: Vec<_> = self.rows.values().collect();
+        rows.sort_by(|a, b| {
+            b.cpu_percent
+                .total_cmp(&a.cpu_percent)
+                .then(a.pid.cmp(&b.pid))
+        });
+
+        let pid_column =
+            console_table::ColumnOptions::new("PID").alignment(console_table::Alignment::Right);
+        let job_column = console_table::ColumnOptions::new("JOB").spacing(2);
+        let cpu_column = console_table::ColumnOptions::new("CPU%")
+            .alignment(console_table::Alignment::Right)
+            .spacing(2);
+        let rss_column = console_table::ColumnOptions::new("RSS")
+            .alignment(console_table::Alignment::Right)
+            .spacing(2);
+        let cmd_column = console_table::ColumnOptions::new("CMD").spacing(2);
+        let mut table_builder =
+            (pid_column, job_column, cpu_column, rss_column, cmd_column).into_table_builder();
+        for row in rows {
+            table_builder.push_row(
+                row.pid.to_string(),
+                row.job.clone().unwrap_or_default(),
+                format!("{:.1}", row.cpu_percent),
+                FormattedBytes::new(row.rss_bytes).to_string(),
+                row.cmd.clone(),
+            );
+        }
+        if let Some(width) = RenderHints::detect().width {
+            table_builder.fit_column(4, width.into());
+        }
+
+        let mut stdout = io::stdout().lock();
+        if self.redraw {
+            // Move to the top left and clear the screen.
+            write!(stdout, "\x1b[H\x1b[2J")?;
+        }
+        writeln!(stdout, "{table_builder}")?;
+        if !self.redraw {
+            writeln!(stdout)?;
+        }
+        stdout.flush()?;
+        Ok(())
+    }
+
+    fn on_finish(&mut self, result: Result<(), String>) -> Result<i32> {
+        match result {
+            Ok(()) => Ok(0),
+            Err(reason) => {
+                println!("{reason}");
+                Ok(1)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{diff_rows, TopRow, TopUpdate};
+
+    fn row(pid: u32, cpu_percent: f32) -> TopRow {
+        TopRow {
+            pid,
+            job: None,
+            cpu_percent,
+            rss_bytes: 1024,
+            cmd: "sleep 60".to_owned(),
+        }
+    }
+
+    fn rows(rows: &[TopRow]) -> BTreeMap<u32, TopRow> {
+        rows.iter().map(|row| (row.pid, row.clone())).collect()
+    }
+
+    #[test]
+    fn test_diff_rows() {
+        let prev = rows(&[row(1, 0.0), row(2, 1.5), row(3, 0.0)]);
+        let next = rows(&[row(1, 0.0), row(2, 2.5), row(4, 0.0)]);
+        let (changed, removed) = diff_rows(&prev, &next);
+        assert_eq!(changed, [row(2, 2.5), row(4, 0.0)]);
+        assert_eq!(removed, [3]);
+
+        // The client ends up with the same rows as the server.
+        let mut client = prev.clone();
+        TopUpdate::Delta { changed, removed }.apply(&mut client);
+        assert_eq!(client, next);
+
+        TopUpdate::Full(vec![row(5, 0.0)]).apply(&mut client);
+        assert_eq!(client, rows(&[row(5, 0.0)]));
+    }
+}
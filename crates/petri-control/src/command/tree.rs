@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use clap::Args;
+use petri_core::metrics::sample_process;
+use petri_core::proc_table::{process_cmd, ProcTable};
+use petri_utils::console_table::{self, ColumnCollection};
+use petri_utils::FormattedBytes;
+use serde::{Deserialize, Serialize};
+use tokio::task;
+
+use super::{CommandClient, IpcChannel, OwnedIpcMessagePacket, ResponseHandler};
+use crate::Context as ControlContext;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TreeResponse {
+    roots: Vec<Node>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Node {
+    pid: u32,
+    /// The job name of a managed process.
+    job: Option<String>,
+    cmd: String,
+    cpu_time_ms: Option<u64>,
+    rss_bytes: Option<u64>,
+    /// Whether the process was orphaned and adopted by the server.
+    orphaned: bool,
+    children: Vec<Node>,
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct TreeSubcommand {
+    /// Only show the tree of the process with the given pid.
+    #[arg(short, long)]
+    pid: Option<u32>,
+}
+
+impl TreeSubcommand {
+    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        let job_names: HashMap<_, _> = ctx
+            .job_mgr_handle
+            .jobs()
+            .await
+            .into_iter()
+            .filter_map(|job| Some((job.pid()?, job.display_name().to_owned())))
+            .collect();
+        let mut orphans: HashMap<_, Vec<_>> = HashMap::new();
+        for orphan in ctx.proc_mgr_handle.orphans() {
+            if let Some(owner_pid) = orphan.owner_pid {
+                orphans.entry(owner_pid).or_default().push(orphan.pid);
+            }
+        }
+
+        let mut processes = ctx.proc_mgr_handle.processes().await;
+        processes.sort_by_key(|proc| proc.started_at());
+        let processes: Vec<_> = processes
+            .iter()
+            .map(|proc| (proc.id(), proc.cmd().to_owned()))
+            .filter(|(pid, _)| self.pid.is_none_or(|target| target == *pid))
+            .collect();
+
+        // Walking the process table and sampling every node reads the
+        // kernel, which may block.
+        let roots = task::spawn_blocking(move || {
+            let table = ProcTable::snapshot()?;
+            Ok::<_, io::Error>(build_roots(&table, processes, job_names, orphans))
+        })
+        .await
+        .unwrap_or_else(|err| Err(io::Error::other(err)));
+        let roots = match roots {
+            Ok(roots) => roots,
+            Err(err) => {
+                channel
+                    .write_output(&format!("failed to read the process table: {err}\n"))
+                    .await?;
+                return Err(anyhow::Error::from(err).context("tree"));
+            }
+        };
+
+        if let Some(pid) = self.pid {
+            if roots.is_empty() {
+                channel
+                    .write_output(&format!("no managed process with pid {pid}\n"))
+                    .await?;
+                return Err(anyhow!("process {pid} is not found").context("tree"));
+            }
+        }
+
+        channel.write_response(TreeResponse { roots }).await?;
+        Ok(())
+    }
+}
+
+fn build_roots(
+    table: &ProcTable,
+    processes: Vec<(u32, String)>,
+    job_names: HashMap<u32, String>,
+    mut orphans: HashMap<u32, Vec<u32>>,
+) -> Vec<Node> {
+    let mut roots = vec![];
+    for (pid, cmd) in processes {
+        let mut root = build_node(table, pid, Some(cmd));
+        root.job = job_names.get(&pid).cloned();
+        for orphan_pid in orphans.remove(&pid).unwrap_or_default() {
+            if !table.contains(orphan_pid) {
+                continue;
+            }
+            let mut node = build_node(table, orphan_pid, None);
+            node.orphaned = true;
+            root.children.push(node);
+        }
+        roots.push(root);
+    }
+    roots
+}
+
+fn build_node(table: &ProcTable, pid: u32, cmd: Option<String>) -> Node {
+    let cmd = cmd
+        .or_else(|| process_cmd(pid))
+        .or_else(|| table.get(pid).map(|entry| format!("[{}]", entry.name)))
+        .unwrap_or_default();
+    let sample = sample_process(pid).ok();
+    Node {
+        pid,
+        job: None,
+        cmd,
+        cpu_time_ms: sample.map(|sample| sample.cpu_time.as_millis() as u64),
+        rss_bytes: sample.map(|sample| sample.rss_bytes),
+        orphaned: false,
+        children: table
+            .children(pid)
+            .iter()
+            .map(|child| build_node(table, *child, None))
+            .collect(),
+    }
+}
+
+impl CommandClient for TreeSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        Some(Box::new(TreeResponseHandler))
+    }
+}
+
+struct TreeResponseHandler;
+
+#[async_trait]
+impl ResponseHandler for TreeResponseHandler {
+    async fn handle_response(
+        &mut self,
+        resp: OwnedIpcMessagePacket<serde_json::Value>,
+    ) -> Result<()> {
+        let resp: TreeResponse = resp.into_response().expect("expected a response")?;
+
+        let pid_column =
+            console_table::ColumnOptions::new("PID").alignment(console_table::Alignment::Right);
+        let job_column = console_table::ColumnOptions::new("JOB").spacing(2);
+        let cpu_column = console_table::ColumnOptions::new("CPU TIME")
+            .alignment(console_table::Alignment::Right)
+            .spacing(2);
+        let mem_column = console_table::ColumnOptions::new("MEM")
+            .alignment(console_table::Alignment::Right)
+            .spacing(2);
+        let cmd_column = console_table::ColumnOptions::new("CMD").spacing(2);
+
+        let mut table_builder =
+            (pid_column, job_column, cpu_column, mem_column, cmd_column).into_table_builder();
+
+        let mut stack: Vec<_> = resp
+            .roots
+            .iter()
+            .rev()
+            .map(|node| (node, String::new(), None))
+            .collect();
+        // Each item carries the prefix of its descendants, and whether the
+        // node is the last child (none for roots).
+        while let Some((node, prefix, is_last)) = stack.pop() {
+            let (branch, child_prefix) = match is_last {
+                None => (String::new(), String::new()),
+                Some(true) => (format!("{prefix}└─ "), format!("{prefix}   ")),
+                Some(false) => (format!("{prefix}├─ "), format!("{prefix}│  ")),
+            };
+            let orphan_mark = if node.orphaned { " (orphaned)" } else { "" };
+
+            table_builder.push_row(
+                node.pid.to_string(),
+                node.job.clone().unwrap_or_default(),
+                node.cpu_time_ms
+                    .map(|ms| format_cpu_time(Duration::from_millis(ms)))
+                    .unwrap_or_else(|| "-".to_owned()),
+                node.rss_bytes
+                    .map(|bytes| FormattedBytes::new(bytes).to_string())
+                    .unwrap_or_else(|| "-".to_owned()),
+                format!("{branch}{}{orphan_mark}", node.cmd),
+            );
+
+            let count = node.children.len();
+            for (idx, child) in node.children.iter().enumerate().rev() {
+                stack.push((child, child_prefix.clone(), Some(idx == count - 1)));
+            }
+        }
+
+        println!("{table_builder}");
+        Ok(())
+    }
+}
+
+/// Formats the CPU time like `ps`, e.g. `1:02.35`.
+fn format_cpu_time(time: Duration) -> String {
+    let secs = time.as_secs();
+    let centis = time.subsec_millis() / 10;
+    format!("{}:{:02}.{centis:02}", secs / 60, secs % 60)
+}
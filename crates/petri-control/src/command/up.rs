@@ -0,0 +1,135 @@
+use anyhow::Result;
+use clap::Args;
+use petri_core::job_mgr::Job;
+use serde::{Deserialize, Serialize};
+
+use super::batch::{BatchConsumer, BatchReport};
+use super::namespace::NamespaceArgs;
+use super::{
+    parse_key_value, wait_preconditions, wait_spawn_slot, CommandClient, IpcChannel,
+    ResponseHandler, StreamHandler,
+};
+use crate::Context as ControlContext;
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct UpSubcommand {
+    /// Select jobs having the label instead of autostart jobs.
+    #[arg(short = 'l', long = "selector", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    selector: Vec<(String, String)>,
+    #[command(flatten)]
+    namespace: NamespaceArgs,
+}
+
+/// Returns the jobs of the namespaces matching all labels in the selector,
+/// or the autostart jobs if the selector is empty. Templates are never
+/// selected.
+pub(super) async fn select_jobs(
+    ctx: &ControlContext,
+    namespace: &NamespaceArgs,
+    selector: &[(String, String)],
+) -> Vec<Job> {
+    let jobs = namespace.jobs(ctx).await;
+    jobs.into_iter()
+        .filter(|job| {
+            let desc = job.description();
+            if desc.template {
+                return false;
+            }
+            if selector.is_empty() {
+                desc.autostart
+            } else {
+                job.matches_labels(selector)
+            }
+        })
+        .collect()
+}
+
+impl UpSubcommand {
+    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        let jids: Vec<_> = select_jobs(ctx, &self.namespace, &self.selector)
+            .await
+            .iter()
+            .map(|job| job.id().to_owned())
+            .collect();
+        if jids.is_empty() {
+            channel.write_output("no jobs to start\n").await?;
+            return BatchReport::default().finish(channel).await;
+        }
+
+        let order = match ctx.job_mgr_handle.startup_order(&jids).await {
+            Ok(order) => order,
+            Err(err) => {
+                channel
+                    .write_output(&format!("failed to resolve dependencies: {err:#}\n"))
+                    .await?;
+                return Err(err.context("up"));
+            }
+        };
+
+        let mut report = BatchReport::default();
+        let mut failed = false;
+        for jid in &order {
+            let Some(job) = ctx.job_mgr_handle.job_with_id(jid).await else {
+                continue;
+            };
+            let name = job.display_name();
+            // Jobs after a failed one may depend on it.
+            if failed {
+                report
+                    .skipped(channel, name, format!("{name} is not started"))
+                    .await?;
+                continue;
+            }
+            if let Some(pid) = job.pid() {
+                report
+                    .skipped(
+                        channel,
+                        name,
+                        format!("{name} is already running (pid: {pid})"),
+                    )
+                    .await?;
+                continue;
+            }
+
+            channel
+                .write_output(&format!("starting {name}...\n"))
+                .await?;
+            let res = async {
+                wait_preconditions(ctx, channel, jid).await?;
+                let permit = wait_spawn_slot(ctx, channel).await?;
+                let pid = ctx
+                    .job_mgr_handle
+                    .start_job_with_permit(jid, permit)
+                    .await?;
+                ctx.job_mgr_handle.wait_job_ready(jid).await?;
+                Ok::<_, anyhow::Error>(pid)
+            }
+            .await;
+
+            match res {
+                Ok(pid) => {
+                    report
+                        .succeeded(channel, name, format!("{name} is ready (pid: {pid})"))
+                        .await?;
+                }
+                Err(err) => {
+                    report
+                        .failed(channel, name, format!("{name} failed to start: {err:#}"))
+                        .await?;
+                    failed = true;
+                }
+            }
+        }
+
+        report
+            .finish(channel)
+            .await
+            .map_err(|err| err.context("up"))
+    }
+}
+
+impl CommandClient for UpSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        Some(Box::new(StreamHandler::new(BatchConsumer)))
+    }
+}
@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use clap::{ArgGroup, Args};
+use petri_utils::time::parse_duration;
+use serde::{Deserialize, Serialize};
+
+use super::{CommandClient, IpcChannel, OwnedIpcMessagePacket, ResponseHandler};
+use crate::Context as ControlContext;
+
+/// The exit status used when waiting timed out, same as `timeout(1)`.
+const TIMED_OUT_EXIT_STATUS: i32 = 124;
+
+#[derive(Serialize, Deserialize, Debug)]
+enum WaitResponse {
+    Exited(i32),
+    TimedOut,
+    NotFound(String),
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+#[command(group(ArgGroup::new("target").required(true).args(["pid", "jid"])))]
+pub struct WaitSubcommand {
+    /// Wait for the process with the given pid.
+    #[arg(short, long)]
+    pid: Option<u32>,
+    /// Wait for the job with the given id or name.
+    #[arg(short, long)]
+    jid: Option<String>,
+    /// Give up waiting after the given duration (e.g. `30s`, `5m`).
+    #[arg(short, long, value_parser = parse_duration)]
+    timeout: Option<Duration>,
+}
+
+impl WaitSubcommand {
+    pub(super) async fn run(self, ctx: &ControlContext, channel: &mut IpcChannel) -> Result<()> {
+        let wait_fut = async {
+            if let Some(pid) = self.pid {
+                return match ctx.proc_mgr_handle.wait_process(pid).await {
+                    Ok(exit_code) => WaitResponse::Exited(exit_code),
+                    Err(err) => WaitResponse::NotFound(format!("{err}")),
+                };
+            }
+
+            let jid = self.jid.as_deref().expect("either pid or jid is required");
+            let resolved_jid = match ctx.job_mgr_handle.resolve_job_id(jid).await {
+                Ok(jid) => jid,
+                Err(err) => return WaitResponse::NotFound(format!("{err}")),
+            };
+            let Some(mut job) = ctx.job_mgr_handle.job_with_id(&resolved_jid).await else {
+                return WaitResponse::NotFound(format!("job `{jid}` is not found"));
+            };
+
+            if let Some(pid) = job.pid() {
+                if let Ok(exit_code) = ctx.proc_mgr_handle.wait_process(pid).await {
+                    return WaitResponse::Exited(exit_code);
+                }
+                // The process exited in the meantime, so fetch the job again
+                // once its exit is recorded.
+                ctx.job_mgr_handle.wait_detached(&resolved_jid, pid).await;
+                let Some(exited_job) = ctx.job_mgr_handle.job_with_id(&resolved_jid).await else {
+                    return WaitResponse::NotFound(format!("job `{jid}` is not found"));
+                };
+                job = exited_job;
+            }
+
+            // The job is not running, report the result of its last run.
+            match job.last_exit_code() {
+                Some(exit_code) => WaitResponse::Exited(exit_code),
+                None => WaitResponse::NotFound(format!("job `{jid}` has never been started")),
+            }
+        };
+
+        let resp = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, wait_fut)
+                .await
+                .unwrap_or(WaitResponse::TimedOut),
+            None => wait_fut.await,
+        };
+
+        channel.write_response(resp).await?;
+        Ok(())
+    }
+}
+
+impl CommandClient for WaitSubcommand {
+    fn handler(&self) -> Option<Box<dyn ResponseHandler>> {
+        Some(Box::new(WaitResponseHandler { exit_status: 0 }))
+    }
+}
+
+struct WaitResponseHandler {
+    exit_status: i32,
+}
+
+#[async_trait]
+impl ResponseHandler for WaitResponseHandler {
+    async fn handle_response(
+        &mut self,
+        resp: OwnedIpcMessagePacket<serde_json::Value>,
+    ) -> Result<()> {
+        let resp: WaitResponse = resp.into_response().expect("expected a response")?;
+        match resp {
+            WaitResponse::Exited(exit_code) => {
+                println!("exited with code {exit_code}");
+                self.exit_status = exit_code;
+            }
+            WaitResponse::TimedOut => {
+                eprintln!("timed out waiting for the target to exit");
+                self.exit_status = TIMED_OUT_EXIT_STATUS;
+            }
+            WaitResponse::NotFound(msg) => {
+                eprintln!("{msg}");
+                self.exit_status = 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn exit_status(&self) -> i32 {
+        self.exit_status
+    }
+}
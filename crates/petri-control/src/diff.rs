@@ -0,0 +1,182 @@
+//! Line-based diffs of texts, like the settings of jobs rendered as TOML.
+
+use std::fmt::{self, Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LineChange {
+    Kept,
+    Removed,
+    Added,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct DiffLine {
+    pub change: LineChange,
+    pub text: String,
+}
+
+/// Changed lines with the unchanged lines around them, like a hunk of a
+/// unified diff. Lines are numbered from 1.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+    pub lines: Vec<DiffLine>,
+}
+
+impl DiffLine {
+    /// Returns the prefix of the line in a unified diff.
+    pub fn prefix(&self) -> char {
+        match self.change {
+            LineChange::Kept => ' ',
+            LineChange::Removed => '-',
+            LineChange::Added => '+',
+        }
+    }
+}
+
+impl Hunk {
+    /// Returns the header of the hunk, like `@@ -1,3 +1,4 @@`.
+    pub fn header(&self) -> String {
+        format!(
+            "@@ -{},{} +{},{} @@",
+            self.old_start, self.old_len, self.new_start, self.new_len
+        )
+    }
+}
+
+impl Display for Hunk {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.header())?;
+        for line in &self.lines {
+            writeln!(f, "{}{}", line.prefix(), line.text)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares the lines of the texts, and groups the changes into hunks with
+/// up to `context` unchanged lines around them. Returns no hunks if the
+/// texts have the same lines.
+pub fn diff(before: &str, after: &str, context: usize) -> Vec<Hunk> {
+    let old: Vec<_> = before.lines().collect();
+    let new: Vec<_> = after.lines().collect();
+    let lines = diff_lines(&old, &new);
+
+    let changed: Vec<_> = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| line.change != LineChange::Kept)
+        .map(|(i, _)| i)
+        .collect();
+    let Some(&first) = changed.first() else {
+        return vec![];
+    };
+
+    // Merge the changes whose context overlaps.
+    let mut ranges = vec![(first.saturating_sub(context), first + context + 1)];
+    for &i in &changed[1..] {
+        let start = i.saturating_sub(context);
+        let last = ranges.last_mut().expect("ranges should not be empty");
+        if start <= last.1 {
+            last.1 = i + context + 1;
+        } else {
+            ranges.push((start, i + context + 1));
+        }
+    }
+
+    ranges
+        .into_iter()
+        .map(|(start, end)| {
+            let end = end.min(lines.len());
+            let count = |lines: &[DiffLine], change| {
+                lines
+                    .iter()
+                    .filter(|line| line.change == LineChange::Kept || line.change == change)
+                    .count()
+            };
+            let old_before = count(&lines[..start], LineChange::Removed);
+            let new_before = count(&lines[..start], LineChange::Added);
+            let hunk_lines = lines[start..end].to_vec();
+            let old_len = count(&hunk_lines, LineChange::Removed);
+            let new_len = count(&hunk_lines, LineChange::Added);
+            Hunk {
+                // Empty sides point at the line before, like `diff -u`.
+                old_start: old_before + (old_len > 0) as usize,
+                old_len,
+                new_start: new_before + (new_len > 0) as usize,
+                new_len,
+                lines: hunk_lines,
+            }
+        })
+        .collect()
+}
+
+/// Returns all the lines of both sides, by their longest common
+/// subsequence.
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<DiffLine> {
+    // `lcs[i][j]` is the length of the LCS of `old[i..]` and `new[j..]`.
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let line = |change, text: &str| DiffLine {
+        change,
+        text: text.to_owned(),
+    };
+    let mut lines = Vec::with_capacity(old.len().max(new.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            lines.push(line(LineChange::Kept, old[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            lines.push(line(LineChange::Removed, old[i]));
+            i += 1;
+        } else {
+            lines.push(line(LineChange::Added, new[j]));
+            j += 1;
+        }
+    }
+    lines.extend(old[i..].iter().map(|text| line(LineChange::Removed, text)));
+    lines.extend(new[j..].iter().map(|text| line(LineChange::Added, text)));
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::diff;
+
+    #[test]
+    fn test_diff() {
+        let render = |before, after| {
+            diff(before, after, 1)
+                .iter()
+                .map(ToString::to_string)
+                .collect::<String>()
+        };
+
+        assert_eq!(render("a\nb\n", "a\nb\n"), "");
+        assert_eq!(render("", "a\nb\n"), "@@ -0,0 +1,2 @@\n+a\n+b\n");
+        assert_eq!(
+            render("a\nb\nc\nd\ne\nf\ng\n", "a\nB\nc\nd\ne\nf\ng\nh\n"),
+            "@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n@@ -7,1 +7,2 @@\n g\n+h\n"
+        );
+        assert_eq!(
+            render("a\nb\nc\n", "a\nc\n"),
+            "@@ -1,3 +1,2 @@\n a\n-b\n c\n"
+        );
+    }
+}
@@ -1,22 +1,350 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fmt::{self, Display, Formatter};
 use std::fs;
 use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
 
-#[cfg(target_os = "macos")]
-pub fn socket_path() -> Result<PathBuf> {
+use petri_core::job_mgr::{INSTANCE_ID_ENV, JID_ENV, JOB_NAME_ENV};
+use petri_core::process::StartInfo;
+use petri_core::process_mgr::SpawnHook;
+use tokio::process::Command;
+
+/// The environment variable to connect to the server at another address,
+/// also used by the server if the config doesn't set one.
+pub const ADDRESS_ENV: &str = "PETRI_ADDRESS";
+
+/// Variables that tell every process the server it runs under, unless it
+/// opts out by [`StartInfo::petri_env`]: the pid of the server, and the
+/// address to reach it in the format of [`ADDRESS_ENV`].
+pub const SERVER_PID_ENV: &str = "PETRI_PID";
+pub const SOCKET_ENV: &str = "PETRI_SOCKET";
+
+/// The environment variable to keep clients from starting the server when
+/// it's not running, set for the processes of a server that disables it.
+pub const NO_AUTOSTART_ENV: &str = "PETRI_NO_AUTOSTART";
+
+/// Variables that the server sets for its processes, which are never
+/// taken from clients (e.g. ones run by a job).
+const PETRI_ENV_VARS: &[&str] = &[
+    SERVER_PID_ENV,
+    SOCKET_ENV,
+    NO_AUTOSTART_ENV,
+    JID_ENV,
+    JOB_NAME_ENV,
+    INSTANCE_ID_ENV,
+];
+
+/// Variables that every process has, the ones of the server are used if
+/// neither the client nor the policy sets them.
+pub const REQUIRED_ENV_VARS: &[&str] = &["PATH", "HOME"];
+
+/// Where the server listens for clients.
+///
+/// It's written as `/path/to/socket` (or `unix:/path/to/socket`),
+/// `@name` for an abstract socket and `tcp:HOST:PORT` for TCP.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ListenAddress {
+    /// A Unix socket file, which is removed when the server stops.
+    Path(PathBuf),
+    /// A Unix socket in the abstract namespace (Linux only), which leaves
+    /// no file to clean up.
+    Abstract(String),
+    /// A TCP socket. Clients must authorize with tokens, since their users
+    /// can't be identified. Binding `[::]` accepts IPv4 clients too.
+    Tcp(SocketAddr),
+}
+
+impl ListenAddress {
+    /// Returns the address of [`ADDRESS_ENV`], or the default one.
+    pub fn from_env() -> Result<Self> {
+        match std::env::var(ADDRESS_ENV) {
+            Ok(address) if !address.is_empty() => address.parse().map_err(|err| {
+                Error::new(ErrorKind::InvalidInput, format!("{ADDRESS_ENV}: {err}"))
+            }),
+            _ => default_address(),
+        }
+    }
+
+    /// Returns the address for clients, which is [`from_env`], except
+    /// that clients run by a process of the server reach the same server
+    /// by [`SOCKET_ENV`] if [`ADDRESS_ENV`] is not set.
+    ///
+    /// [`from_env`]: Self::from_env
+    pub fn for_client() -> Result<Self> {
+        match std::env::var(SOCKET_ENV) {
+            Ok(address) if !address.is_empty() && std::env::var_os(ADDRESS_ENV).is_none() => {
+                address.parse().map_err(|err| {
+                    Error::new(ErrorKind::InvalidInput, format!("{SOCKET_ENV}: {err}"))
+                })
+            }
+            _ => Self::from_env(),
+        }
+    }
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some(addr) = s.strip_prefix("tcp:") {
+            return addr
+                .parse()
+                .map(ListenAddress::Tcp)
+                .map_err(|_| format!("invalid TCP address `{addr}`, expected `HOST:PORT`"));
+        }
+
+        let path = s.strip_prefix("unix:").unwrap_or(s);
+        if let Some(name) = path.strip_prefix('@') {
+            if name.is_empty() {
+                return Err("the abstract socket name is empty".to_owned());
+            }
+            if !cfg!(any(target_os = "linux", target_os = "android")) {
+                return Err("abstract sockets are only supported on Linux".to_owned());
+            }
+            return Ok(ListenAddress::Abstract(name.to_owned()));
+        }
+        if !path.starts_with('/') {
+            return Err(format!(
+                "invalid address `{s}`, expected an absolute path, `@NAME` or `tcp:HOST:PORT`"
+            ));
+        }
+        Ok(ListenAddress::Path(PathBuf::from(path)))
+    }
+}
+
+impl Display for ListenAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddress::Path(path) => write!(f, "{}", path.display()),
+            ListenAddress::Abstract(name) => write!(f, "@{name}"),
+            ListenAddress::Tcp(addr) => write!(f, "tcp:{addr}"),
+        }
+    }
+}
+
+/// Which variables of the client environment are accepted by the server,
+/// applied to the environment of every command. Variables set for a job
+/// explicitly (e.g. by `run --env`) are not filtered.
+///
+/// Patterns match the names of variables, with `*` matching any
+/// characters (e.g. `LC_*` or `*_TOKEN`).
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct EnvPolicy {
+    /// Patterns of the accepted variables, all are accepted if it's empty.
+    pub allow: Vec<String>,
+    /// Patterns of the variables that are never accepted, even if allowed.
+    pub deny: Vec<String>,
+    /// Variables set if the client doesn't set them.
+    pub defaults: BTreeMap<String, String>,
+}
+
+impl EnvPolicy {
+    pub fn accepts(&self, name: &str) -> bool {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|p| matches_pattern(p, name));
+        allowed && !self.deny.iter().any(|p| matches_pattern(p, name))
+    }
+
+    /// Returns the environment that the server accepts from the client,
+    /// with the defaults filled in. Names that are not valid UTF-8 are
+    /// matched against the patterns with replacement characters.
+    pub fn apply(&self, mut env: HashMap<OsString, OsString>) -> HashMap<OsString, OsString> {
+        env.retain(|name, _| {
+            let name = name.to_string_lossy();
+            self.accepts(&name) && !PETRI_ENV_VARS.contains(&&*name)
+        });
+        for (name, value) in &self.defaults {
+            env.entry(name.into()).or_insert_with(|| value.into());
+        }
+        for name in REQUIRED_ENV_VARS {
+            if env.contains_key(OsStr::new(name)) {
+                continue;
+            }
+            if let Some(value) = std::env::var_os(name) {
+                env.insert(name.into(), value);
+            }
+        }
+        env
+    }
+}
+
+/// Sets [`SERVER_PID_ENV`] and [`SOCKET_ENV`] for the processes, and
+/// [`NO_AUTOSTART_ENV`] if clients must not start the server.
+pub struct ServerEnvHook {
+    address: String,
+    no_autostart: bool,
+}
+
+impl ServerEnvHook {
+    pub fn new(address: &ListenAddress) -> Self {
+        Self {
+            address: address.to_string(),
+            no_autostart: false,
+        }
+    }
+
+    pub fn no_autostart(mut self, no_autostart: bool) -> Self {
+        self.no_autostart = no_autostart;
+        self
+    }
+}
+
+impl SpawnHook for ServerEnvHook {
+    fn before_spawn(&self, command: &mut Command, start_info: &StartInfo) -> anyhow::Result<()> {
+        if start_info.petri_env {
+            command
+                .env(SERVER_PID_ENV, std::process::id().to_string())
+                .env(SOCKET_ENV, &self.address);
+            if self.no_autostart {
+                command.env(NO_AUTOSTART_ENV, "1");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for EnvPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let allow = if self.allow.is_empty() {
+            "*".to_owned()
+        } else {
+            self.allow.join(", ")
+        };
+        write!(f, "allow {allow}")?;
+        if !self.deny.is_empty() {
+            write!(f, "; deny {}", self.deny.join(", "))?;
+        }
+        if !self.defaults.is_empty() {
+            let names: Vec<_> = self.defaults.keys().map(String::as_str).collect();
+            write!(f, "; defaults {}", names.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns whether the name matches the pattern, where `*` matches any
+/// characters.
+pub fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<_> = parts.collect();
+    // Without `*`, the whole name must match.
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+pub fn default_address() -> Result<ListenAddress> {
     let base_metadata = fs::metadata("/tmp")?;
     if !base_metadata.is_dir() {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "the location for socket is not available",
-        ));
+        return Err(Error::other("the location for socket is not available"));
     }
-    Ok(PathBuf::from_str("/tmp/petri.sock")
-        .expect("creating path from literal string should success"))
+    Ok(ListenAddress::Path(
+        PathBuf::from_str("/tmp/petri.sock")
+            .expect("creating path from literal string should success"),
+    ))
 }
 
-#[cfg(not(target_os = "macos"))]
-pub fn socket_path() -> Result<PathBuf> {
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+pub fn default_address() -> Result<ListenAddress> {
     compile_error!("target platform not supported")
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::ffi::OsStr;
+    use std::path::PathBuf;
+
+    use super::{matches_pattern, EnvPolicy, ListenAddress};
+
+    #[test]
+    fn test_parse_address() {
+        let parse = |s: &str| s.parse::<ListenAddress>();
+        assert_eq!(
+            parse("/tmp/petri.sock"),
+            Ok(ListenAddress::Path(PathBuf::from("/tmp/petri.sock")))
+        );
+        assert_eq!(
+            parse("unix:/run/petri.sock"),
+            Ok(ListenAddress::Path(PathBuf::from("/run/petri.sock")))
+        );
+        assert_eq!(
+            parse("tcp:[::]:7070"),
+            Ok(ListenAddress::Tcp("[::]:7070".parse().unwrap()))
+        );
+        assert_eq!(
+            parse("tcp:127.0.0.1:7070").unwrap().to_string(),
+            "tcp:127.0.0.1:7070"
+        );
+        assert!(parse("tcp:localhost").is_err());
+        assert!(parse("petri.sock").is_err());
+        assert!(parse("@").is_err());
+
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            parse("@petri").map(|addr| addr.to_string()),
+            Ok("@petri".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("TERM", "TERM"));
+        assert!(!matches_pattern("TERM", "TERMINAL"));
+        assert!(matches_pattern("LC_*", "LC_ALL"));
+        assert!(matches_pattern("*_TOKEN", "GITHUB_TOKEN"));
+        assert!(!matches_pattern("*_TOKEN", "TOKEN"));
+        assert!(matches_pattern("*SECRET*", "MY_SECRET_KEY"));
+        assert!(matches_pattern("A*B*C", "AxxBxxC"));
+        assert!(!matches_pattern("A*B*C", "AxxCxxB"));
+        assert!(matches_pattern("*", "ANY"));
+    }
+
+    #[test]
+    fn test_env_policy() {
+        let policy = EnvPolicy {
+            allow: vec!["LC_*".to_owned(), "APP_*".to_owned(), "PATH".to_owned()],
+            deny: vec!["*_TOKEN".to_owned()],
+            defaults: [("LC_ALL".to_owned(), "C".to_owned())].into(),
+        };
+        let env: HashMap<_, _> = [
+            ("PATH", "/bin"),
+            ("APP_MODE", "dev"),
+            ("APP_TOKEN", "secret"),
+            ("AWS_KEY", "secret"),
+        ]
+        .map(|(name, value)| (name.into(), value.into()))
+        .into();
+
+        let env = policy.apply(env);
+        let get = |name| env.get(OsStr::new(name)).and_then(|value| value.to_str());
+        assert_eq!(get("PATH"), Some("/bin"));
+        assert_eq!(get("APP_MODE"), Some("dev"));
+        assert_eq!(get("LC_ALL"), Some("C"));
+        assert_eq!(get("APP_TOKEN"), None);
+        assert_eq!(get("AWS_KEY"), None);
+        assert_eq!(
+            policy.to_string(),
+            "allow LC_*, APP_*, PATH; deny *_TOKEN; defaults LC_ALL"
+        );
+
+        let env = [("PETRI_JID".into(), "0123abcd".into())].into();
+        assert!(!EnvPolicy::default()
+            .apply(env)
+            .contains_key(OsStr::new("PETRI_JID")));
+    }
+}
@@ -0,0 +1,374 @@
+//! The history of commands run against the server.
+//!
+//! Entries are appended to a JSON lines file as they are recorded, and
+//! the file is compacted to the latest entries once it grows to twice
+//! the capacity. The file is written by a writer task on the blocking
+//! pool, so commands are never held up by the disk.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind as IoErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::Result;
+use chrono::Local;
+use parking_lot::Mutex;
+use petri_utils::time::in_zone;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandResult {
+    Ok,
+    Failed,
+    /// The client was not allowed to run the command.
+    Denied,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryEntry {
+    pub time: String,
+    /// The uid of the client, `None` if it can't be identified.
+    pub uid: Option<u32>,
+    /// The arguments the client was invoked with.
+    pub args: Vec<String>,
+    pub result: CommandResult,
+    pub error: Option<String>,
+}
+
+pub struct CommandHistory {
+    capacity: usize,
+    state: Mutex<State>,
+    /// The queue of the writer task, if the history is kept in a file.
+    writes_tx: Option<mpsc::UnboundedSender<FileWrite>>,
+}
+
+/// A write to the file, done by the writer task.
+enum FileWrite {
+    Append(String),
+    /// Replaces the file with the contents.
+    Rewrite(String),
+    /// Replies once the writes queued before are done.
+    Flush(oneshot::Sender<()>),
+}
+
+/// The commands being run, to tell what the server is busy with when it
+/// stalls. It can be read without waiting for the commands.
+#[derive(Clone, Default)]
+pub struct ActiveCommands {
+    inner: Arc<ActiveCommandsInner>,
+}
+
+#[derive(Default)]
+struct ActiveCommandsInner {
+    next_id: AtomicU64,
+    commands: Mutex<BTreeMap<u64, ActiveCommand>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ActiveCommand {
+    pub cmd_line: String,
+    pub started_at: Instant,
+}
+
+/// Removes the command from [`ActiveCommands`] when it finishes.
+pub struct ActiveCommandGuard {
+    inner: Arc<ActiveCommandsInner>,
+    id: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: VecDeque<HistoryEntry>,
+    /// The number of lines in the file.
+    lines: usize,
+}
+
+impl HistoryEntry {
+    pub fn new(
+        uid: Option<u32>,
+        args: Vec<String>,
+        result: CommandResult,
+        error: Option<String>,
+    ) -> Self {
+        Self {
+            time: in_zone(&Local::now()).format("%Y-%m-%d %T").to_string(),
+            uid,
+            args,
+            result,
+            error,
+        }
+    }
+}
+
+impl ActiveCommands {
+    /// Records that the command starts running until the guard is dropped.
+    pub fn begin(&self, cmd_line: String) -> ActiveCommandGuard {
+        let id = self.inner.next_id.fetch_add(1, AtomicOrdering::Relaxed);
+        let command = ActiveCommand {
+            cmd_line,
+            started_at: Instant::now(),
+        };
+        self.inner.commands.lock().insert(id, command);
+        ActiveCommandGuard {
+            inner: Arc::clone(&self.inner),
+            id,
+        }
+    }
+
+    /// Returns the commands from the earliest started, or `None` if they
+    /// are being updated.
+    pub fn try_snapshot(&self) -> Option<Vec<ActiveCommand>> {
+        let commands = self.inner.commands.try_lock()?;
+        Some(commands.values().cloned().collect())
+    }
+}
+
+impl Drop for ActiveCommandGuard {
+    fn drop(&mut self) {
+        self.inner.commands.lock().remove(&self.id);
+    }
+}
+
+impl CommandResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandResult::Ok => "ok",
+            CommandResult::Failed => "failed",
+            CommandResult::Denied => "denied",
+        }
+    }
+}
+
+impl CommandHistory {
+    /// Creates a history that keeps the entries in memory only.
+    pub fn in_memory(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            state: Default::default(),
+            writes_tx: None,
+        }
+    }
+
+    /// Loads the latest entries from the file, or creates an empty history
+    /// if the file doesn't exist. Lines that can't be parsed are skipped.
+    ///
+    /// It must be called in the runtime, which runs the writer task.
+    pub fn load(path: PathBuf, capacity: usize) -> Result<Self> {
+        let capacity = capacity.max(1);
+        let mut state = State::default();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    state.lines += 1;
+                    let Ok(entry) = serde_json::from_str(&line) else {
+                        continue;
+                    };
+                    if state.entries.len() == capacity {
+                        state.entries.pop_front();
+                    }
+                    state.entries.push_back(entry);
+                }
+            }
+            Err(err) if err.kind() == IoErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        let (writes_tx, writes_rx) = mpsc::unbounded_channel();
+        task::spawn(drain(path, writes_rx));
+        Ok(Self {
+            capacity,
+            state: Mutex::new(state),
+            writes_tx: Some(writes_tx),
+        })
+    }
+
+    pub fn record(&self, entry: HistoryEntry) {
+        let mut state = self.state.lock();
+        if state.entries.len() == self.capacity {
+            state.entries.pop_front();
+        }
+        state.entries.push_back(entry);
+
+        if let Err(err) = self.persist(&mut state) {
+            warn!("failed to persist the command history: {err:?}");
+        }
+    }
+
+    /// Returns the entries from the oldest to the latest.
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        let state = self.state.lock();
+        state.entries.iter().cloned().collect()
+    }
+
+    /// Returns the entries like [`entries`], or `None` if the history is
+    /// being updated.
+    ///
+    /// [`entries`]: Self::entries
+    pub fn try_entries(&self) -> Option<Vec<HistoryEntry>> {
+        let state = self.state.try_lock()?;
+        Some(state.entries.iter().cloned().collect())
+    }
+
+    /// Waits until the recorded entries are written to the file.
+    pub async fn flush(&self) {
+        let Some(writes_tx) = &self.writes_tx else {
+            return;
+        };
+        let (reply, reply_rx) = oneshot::channel();
+        if writes_tx.send(FileWrite::Flush(reply)).is_ok() {
+            _ = reply_rx.await;
+        }
+    }
+
+    /// Queues the write of the latest entry, with the lock held so the
+    /// writes are queued in order.
+    fn persist(&self, state: &mut State) -> Result<()> {
+        let Some(writes_tx) = &self.writes_tx else {
+            return Ok(());
+        };
+
+        let write = if state.lines + 1 < self.capacity * 2 {
+            let entry = state.entries.back().expect("an entry was just pushed");
+            let mut line = serde_json::to_string(entry)?;
+            line.push('\n');
+            state.lines += 1;
+            FileWrite::Append(line)
+        } else {
+            // Rewrite the file with only the entries in memory.
+            let mut contents = String::new();
+            for entry in &state.entries {
+                contents.push_str(&serde_json::to_string(entry)?);
+                contents.push('\n');
+            }
+            state.lines = state.entries.len();
+            FileWrite::Rewrite(contents)
+        };
+        // The task only stops if it panicked, which is already reported.
+        _ = writes_tx.send(write);
+        Ok(())
+    }
+}
+
+/// Does the queued writes in batches, taking a thread of the blocking pool
+/// only while there are writes to do.
+async fn drain(path: PathBuf, mut writes_rx: mpsc::UnboundedReceiver<FileWrite>) {
+    let path = Arc::new(path);
+    while let Some(write) = writes_rx.recv().await {
+        let mut writes = vec![write];
+        while let Ok(write) = writes_rx.try_recv() {
+            writes.push(write);
+        }
+
+        let path = Arc::clone(&path);
+        let written = task::spawn_blocking(move || {
+            for write in writes {
+                if let Err(err) = write.apply(&path) {
+                    warn!("failed to persist the command history: {err:?}");
+                }
+            }
+        })
+        .await;
+        if let Err(err) = written {
+            error!("failed to write the command history: {err}");
+            return;
+        }
+    }
+}
+
+impl FileWrite {
+    fn apply(self, path: &Path) -> io::Result<()> {
+        let (contents, append) = match self {
+            FileWrite::Append(contents) => (contents, true),
+            FileWrite::Rewrite(contents) => (contents, false),
+            FileWrite::Flush(reply) => {
+                _ = reply.send(());
+                return Ok(());
+            }
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        if append {
+            let mut file = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .mode(0o600)
+                .open(path)?;
+            return file.write_all(contents.as_bytes());
+        }
+
+        let tmp_path = path.with_extension("tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        file.write_all(contents.as_bytes())?;
+        fs::rename(tmp_path, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::{CommandHistory, CommandResult, HistoryEntry};
+
+    fn entry(arg: &str) -> HistoryEntry {
+        HistoryEntry::new(Some(0), vec![arg.to_owned()], CommandResult::Ok, None)
+    }
+
+    #[test]
+    fn test_capacity() {
+        let history = CommandHistory::in_memory(2);
+        for arg in ["a", "b", "c"] {
+            history.record(entry(arg));
+        }
+        let args: Vec<_> = history
+            .entries()
+            .into_iter()
+            .map(|entry| entry.args[0].clone())
+            .collect();
+        assert_eq!(args, ["b", "c"]);
+    }
+
+    #[test]
+    fn test_zero_capacity() {
+        let history = CommandHistory::in_memory(0);
+        for arg in ["a", "b"] {
+            history.record(entry(arg));
+        }
+        assert_eq!(history.entries().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_compaction() {
+        let path = env::temp_dir().join(format!("petri-history-test-{}.jsonl", process::id()));
+        let history = CommandHistory::load(path.clone(), 2).unwrap();
+        for arg in ["a", "b", "c", "d", "e"] {
+            history.record(entry(arg));
+        }
+        history.flush().await;
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        assert!(lines < 4);
+
+        let history = CommandHistory::load(path.clone(), 2).unwrap();
+        let args: Vec<_> = history
+            .entries()
+            .into_iter()
+            .map(|entry| entry.args[0].clone())
+            .collect();
+        assert_eq!(args, ["d", "e"]);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+//! Serving the commands of clients in lanes, each with a budget of the
+//! commands it runs at once, so cheap commands like `ps` are served even
+//! while streaming or batch commands use up theirs. Commands over the
+//! budget of their lane wait in its queue.
+
+use std::fmt::{self, Display, Formatter};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering as AtomicOrdering};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum Lane {
+    /// Read-only commands that respond right away, like `ps` and `status`.
+    Quick,
+    /// Commands that stream until they are stopped, like `log`.
+    Streaming,
+    /// Commands that change the state, which may take a while, like `run`.
+    Batch,
+}
+
+/// How many commands each lane runs at once.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LaneLimits {
+    pub quick: usize,
+    pub streaming: usize,
+    pub batch: usize,
+}
+
+pub struct Lanes {
+    lanes: [LaneState; 3],
+}
+
+struct LaneState {
+    semaphore: Semaphore,
+    limit: usize,
+    queued: AtomicUsize,
+    /// How many commands had to wait, and how long they waited in total.
+    waited: AtomicU64,
+    wait_ms: AtomicU64,
+}
+
+/// The numbers of a lane, shown by `status` and the metrics.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct LaneStats {
+    pub lane: Lane,
+    pub limit: usize,
+    pub running: usize,
+    pub queued: usize,
+    /// How many commands had to wait since the server started.
+    pub waited: u64,
+    /// How long they waited in total, in milliseconds.
+    pub wait_ms: u64,
+}
+
+/// Counts a command in the queue until it's dropped, which may be before
+/// it's run if the client goes away.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Lane {
+    pub const ALL: [Lane; 3] = [Lane::Quick, Lane::Streaming, Lane::Batch];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Lane::Quick => "quick",
+            Lane::Streaming => "streaming",
+            Lane::Batch => "batch",
+        }
+    }
+}
+
+impl Display for Lane {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Lanes {
+    pub fn new(limits: LaneLimits) -> Self {
+        let lane = |limit: usize| LaneState {
+            semaphore: Semaphore::new(limit),
+            limit,
+            queued: AtomicUsize::new(0),
+            waited: AtomicU64::new(0),
+            wait_ms: AtomicU64::new(0),
+        };
+        Self {
+            lanes: [
+                lane(limits.quick),
+                lane(limits.streaming),
+                lane(limits.batch),
+            ],
+        }
+    }
+
+    /// Returns whether the lane is running as many commands as it can.
+    pub fn is_full(&self, lane: Lane) -> bool {
+        self.lanes[lane as usize].semaphore.available_permits() == 0
+    }
+
+    /// Waits for the lane to run one more command, which it runs until the
+    /// permit is dropped.
+    pub async fn acquire(&self, lane: Lane) -> SemaphorePermit<'_> {
+        let state = &self.lanes[lane as usize];
+        if let Ok(permit) = state.semaphore.try_acquire() {
+            return permit;
+        }
+
+        let queued = Queued::new(&state.queued);
+        let started_at = Instant::now();
+        let permit = state
+            .semaphore
+            .acquire()
+            .await
+            .expect("the semaphore should never be closed");
+        drop(queued);
+        state.waited.fetch_add(1, AtomicOrdering::Relaxed);
+        state.wait_ms.fetch_add(
+            started_at.elapsed().as_millis() as u64,
+            AtomicOrdering::Relaxed,
+        );
+        permit
+    }
+
+    pub fn stats(&self) -> Vec<LaneStats> {
+        Lane::ALL
+            .into_iter()
+            .map(|lane| {
+                let state = &self.lanes[lane as usize];
+                LaneStats {
+                    lane,
+                    limit: state.limit,
+                    running: state.limit - state.semaphore.available_permits(),
+                    queued: state.queued.load(AtomicOrdering::Relaxed),
+                    waited: state.waited.load(AtomicOrdering::Relaxed),
+                    wait_ms: state.wait_ms.load(AtomicOrdering::Relaxed),
+                }
+            })
+            .collect()
+    }
+}
+
+impl<'a> Queued<'a> {
+    fn new(queued: &'a AtomicUsize) -> Self {
+        queued.fetch_add(1, AtomicOrdering::Relaxed);
+        Self(queued)
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, AtomicOrdering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time;
+
+    use super::{Lane, LaneLimits, Lanes};
+
+    #[tokio::test]
+    async fn test_lanes() {
+        let lanes = Lanes::new(LaneLimits {
+            quick: 1,
+            streaming: 1,
+            batch: 1,
+        });
+        let streaming = lanes.acquire(Lane::Streaming).await;
+        assert!(lanes.is_full(Lane::Streaming));
+
+        // Other lanes are not held up by a full one.
+        drop(lanes.acquire(Lane::Quick).await);
+
+        let waiting = lanes.acquire(Lane::Streaming);
+        tokio::pin!(waiting);
+        tokio::select! {
+            _ = &mut waiting => panic!("the lane should be full"),
+            _ = time::sleep(Duration::from_millis(10)) => {}
+        }
+        let stats = lanes.stats();
+        assert_eq!(
+            (stats[1].running, stats[1].queued, stats[1].waited),
+            (1, 1, 0)
+        );
+
+        drop(streaming);
+        let _permit = waiting.await;
+        let stats = lanes.stats();
+        assert_eq!(
+            (stats[1].running, stats[1].queued, stats[1].waited),
+            (1, 0, 1)
+        );
+        assert_eq!(stats[0].running, 0);
+    }
+}
@@ -4,23 +4,183 @@ extern crate anyhow;
 #[macro_use]
 extern crate log;
 
+pub mod auth;
+pub mod checkpoint;
 pub mod cli;
 pub mod command;
+pub mod diff;
 pub mod env;
+pub mod history;
+pub mod lanes;
+pub mod lock;
+pub mod transport;
 
+use std::fmt::{self, Display, Formatter};
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
+use async_trait::async_trait;
+use auth::TokenStore;
+use checkpoint::CheckpointStore;
+use env::{EnvPolicy, ListenAddress};
+use history::{ActiveCommands, CommandHistory};
+use lanes::Lanes;
+use lock::RunLocks;
+use parking_lot::RwLock;
 use petri_core::job_mgr::Handle as JobManagerHandle;
+use petri_core::metrics::MetricsStore;
 use petri_core::process_mgr::Handle as ProcessManagerHandle;
-use tokio::sync::watch;
+use petri_logger::writers::MemoryWriter;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, watch};
 
 pub use command::Command;
 
+/// The version of the client and the server, to tell version skews.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The version with the metadata of the build, for bug reports.
+pub const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("PETRI_GIT_SHA"),
+    " ",
+    env!("PETRI_BUILD_DATE"),
+    ", ",
+    env!("PETRI_TARGET"),
+    ", features: ",
+    env!("PETRI_FEATURES"),
+    ")"
+);
+
 pub struct Context {
+    /// Where to listen for clients.
+    pub listen_address: ListenAddress,
     pub proc_mgr_handle: ProcessManagerHandle,
     pub job_mgr_handle: JobManagerHandle,
+    /// The store of process metrics, `None` if metrics are disabled.
+    pub metrics_store: Option<MetricsStore>,
+    /// The in-memory copy of the server logs, if enabled.
+    pub server_logs: Option<MemoryWriter>,
     pub shutdown_request: watch::Sender<bool>,
+    /// The progress of the shutdown, streamed to the `stop-server` clients
+    /// subscribing to it. The control server is stopped once they are all
+    /// gone.
+    pub shutdown_progress: broadcast::Sender<ShutdownEvent>,
+    /// When the server started.
+    pub started_at: Instant,
+    /// Tokens that authorize clients of other users.
+    pub token_store: TokenStore,
+    /// Users that can observe the server without a token, with the
+    /// read-only role.
+    pub observer_uids: Vec<u32>,
+    /// The commands run by clients.
+    pub history: Arc<CommandHistory>,
+    /// The commands being run by clients.
+    pub active_commands: ActiveCommands,
+    pub command_limits: CommandLimits,
+    /// The budgets of the commands run at once, by their lanes.
+    pub lanes: Lanes,
+    /// Exited jobs older than this are removed by `petri gc`.
+    pub job_retention: Duration,
+    /// The number of commands that panicked.
+    pub command_panics: AtomicU64,
+    /// Reloads the server config, `None` if reloading is not supported.
+    pub config_reloader: Option<Arc<dyn ConfigReloader>>,
+    /// The storage that log files are archived to, `None` if archiving is
+    /// not configured.
+    pub log_archive: Option<Arc<dyn LogArchive>>,
+    /// The variables accepted from the environment of clients, which can
+    /// be changed while the server is running.
+    pub env_policy: Arc<RwLock<EnvPolicy>>,
+    /// The locks held by processes started with `run --lock`.
+    pub run_locks: RunLocks,
+    /// The checkpoints made by `petri checkpoint`, `None` if checkpoints
+    /// are not enabled.
+    pub checkpoints: Option<CheckpointStore>,
+    /// The files and directories of the server by what they are for,
+    /// shown by `petri env`.
+    pub paths: Vec<(&'static str, PathBuf)>,
+}
+
+/// Limits of the time that commands run for, streaming commands are not
+/// limited.
+#[derive(Clone, Copy, Debug)]
+pub struct CommandLimits {
+    /// Commands running longer than this are cancelled.
+    pub timeout: Duration,
+    /// A warning is logged for commands running longer than this.
+    pub slow_threshold: Duration,
+}
+
+/// The outcome of a config reload.
+#[derive(Default, Debug)]
+pub struct ReloadSummary {
+    /// Settings that were changed and have taken effect.
+    pub applied: Vec<String>,
+    /// Settings that were changed but take effect after a restart.
+    pub requires_restart: Vec<String>,
+}
+
+/// The phases of shutting down the server, in their order.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum ShutdownPhase {
+    /// The running jobs are stopped, dependents first.
+    StopJobs,
+    /// The processes that are not stopped yet are stopped.
+    StopProcesses,
+    FlushLogs,
+    /// The clients are disconnected.
+    StopControl,
+}
+
+/// A step of shutting down the server.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum ShutdownEvent {
+    PhaseStarted(ShutdownPhase),
+    /// The phase didn't finish in its timeout, and is left behind.
+    PhaseTimedOut(ShutdownPhase),
+    /// A job was stopped, with the exit code of its process (`None` if it
+    /// exited by itself in the meantime).
+    JobStopped {
+        name: String,
+        result: Result<Option<i32>, String>,
+    },
+}
+
+impl Display for ShutdownPhase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ShutdownPhase::StopJobs => "stopping jobs",
+            ShutdownPhase::StopProcesses => "stopping the remaining processes",
+            ShutdownPhase::FlushLogs => "flushing logs",
+            ShutdownPhase::StopControl => "disconnecting clients",
+        })
+    }
+}
+
+#[async_trait]
+pub trait ConfigReloader: Send + Sync {
+    async fn reload(&self) -> Result<ReloadSummary>;
+}
+
+/// A log file uploaded to the archive.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ArchivedLog {
+    /// The key of the object in the storage.
+    pub key: String,
+    pub size: u64,
+    /// As reported by the storage, like `2024-05-01T14:00:00.000Z`.
+    pub last_modified: String,
+}
+
+#[async_trait]
+pub trait LogArchive: Send + Sync {
+    /// Lists the archived log files of the job.
+    async fn list(&self, jid: &str) -> Result<Vec<ArchivedLog>>;
 }
 
 pub async fn run_control_server(ctx: Context) -> Result<()> {
@@ -0,0 +1,120 @@
+//! Named locks held by processes started with `petri run --lock`, so runs
+//! that must not overlap (e.g. ones invoked by cron) don't.
+//!
+//! A lock is taken before the process is spawned and released once it
+//! exits, or once the spawn fails.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use petri_core::process_mgr::Handle as ProcessManagerHandle;
+use serde::{Deserialize, Serialize};
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+
+/// What a run does when its lock is held by another process.
+#[derive(clap::ValueEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LockPolicy {
+    /// Fail without starting the process.
+    Fail,
+    /// Wait for the holder to exit.
+    Wait,
+    /// Stop the holder and take over the lock.
+    Replace,
+}
+
+/// The holder of a lock.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Holder {
+    /// The process is being spawned.
+    Starting,
+    Running(u32),
+}
+
+#[derive(Clone, Default)]
+pub struct RunLocks {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    holders: Mutex<HashMap<String, Holder>>,
+    released: Notify,
+}
+
+impl RunLocks {
+    /// Takes the lock for a process about to be spawned, returning the
+    /// current holder if it's held.
+    pub fn try_acquire(&self, name: &str) -> Result<LockGuard, Holder> {
+        let mut holders = self.inner.holders.lock();
+        if let Some(holder) = holders.get(name) {
+            return Err(*holder);
+        }
+        holders.insert(name.to_owned(), Holder::Starting);
+        Ok(LockGuard {
+            locks: self.clone(),
+            name: Some(name.to_owned()),
+        })
+    }
+
+    /// Returns a future that completes once any lock is released. It must
+    /// be enabled before trying to acquire the lock, so no release is missed.
+    pub fn released(&self) -> Notified<'_> {
+        self.inner.released.notified()
+    }
+
+    fn release(&self, name: &str) {
+        self.inner.holders.lock().remove(name);
+        self.inner.released.notify_waiters();
+    }
+}
+
+/// A lock taken for a process being spawned, which is released if it's
+/// dropped before being bound to the process.
+pub struct LockGuard {
+    locks: RunLocks,
+    name: Option<String>,
+}
+
+impl LockGuard {
+    /// Holds the lock until the process exits.
+    pub fn bind(mut self, pid: u32, proc_mgr_handle: ProcessManagerHandle) {
+        let name = self.name.take().expect("the lock should be held");
+        self.locks
+            .inner
+            .holders
+            .lock()
+            .insert(name.clone(), Holder::Running(pid));
+        let locks = self.locks.clone();
+        tokio::spawn(async move {
+            // The process is not found if it has already exited.
+            _ = proc_mgr_handle.wait_process(pid).await;
+            locks.release(&name);
+        });
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if let Some(name) = self.name.take() {
+            self.locks.release(&name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acquire_and_release() {
+        let locks = RunLocks::default();
+        let guard = locks.try_acquire("backup").unwrap();
+        assert!(matches!(locks.try_acquire("backup"), Err(Holder::Starting)));
+        assert!(locks.try_acquire("report").is_ok());
+
+        drop(guard);
+        assert!(locks.try_acquire("backup").is_ok());
+    }
+}
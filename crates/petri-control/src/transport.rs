@@ -0,0 +1,202 @@
+//! Streams between the clients and the server over the transports of
+//! [`ListenAddress`].
+
+use std::fs;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UnixListener, UnixStream};
+
+use crate::env::ListenAddress;
+
+/// A connection between a client and the server.
+#[derive(Debug)]
+pub enum IpcStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl IpcStream {
+    pub async fn connect(address: &ListenAddress) -> io::Result<Self> {
+        match address {
+            ListenAddress::Path(path) => UnixStream::connect(path).await.map(IpcStream::Unix),
+            ListenAddress::Abstract(name) => connect_abstract(name).map(IpcStream::Unix),
+            ListenAddress::Tcp(addr) => {
+                let stream = TcpStream::connect(addr).await?;
+                // Requests and responses are small, send them right away.
+                stream.set_nodelay(true)?;
+                Ok(IpcStream::Tcp(stream))
+            }
+        }
+    }
+
+    /// Returns the uid of the peer, which is only known for Unix sockets.
+    pub fn peer_uid(&self) -> Option<u32> {
+        match self {
+            IpcStream::Unix(stream) => stream.peer_cred().ok().map(|cred| cred.uid()),
+            IpcStream::Tcp(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for IpcStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            IpcStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            IpcStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for IpcStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            IpcStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            IpcStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            IpcStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            IpcStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            IpcStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            IpcStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// The listener of the server. The socket file is removed when it's
+/// dropped, if there is one.
+pub(crate) enum IpcListener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl IpcListener {
+    pub(crate) fn bind(address: &ListenAddress) -> io::Result<Self> {
+        match address {
+            ListenAddress::Path(path) => UnixListener::bind(path).map(IpcListener::Unix),
+            ListenAddress::Abstract(name) => bind_abstract(name).map(IpcListener::Unix),
+            ListenAddress::Tcp(addr) => {
+                let socket = if addr.is_ipv4() {
+                    TcpSocket::new_v4()?
+                } else {
+                    let socket = TcpSocket::new_v6()?;
+                    // Systems differ in the default, make `[::]` dual-stack
+                    // on all of them.
+                    if addr.ip().is_unspecified() {
+                        set_only_v6(&socket, false)?;
+                    }
+                    socket
+                };
+                socket.set_reuseaddr(true)?;
+                socket.bind(*addr)?;
+                socket.listen(128).map(IpcListener::Tcp)
+            }
+        }
+    }
+
+    pub(crate) async fn accept(&self) -> io::Result<(IpcStream, String)> {
+        match self {
+            IpcListener::Unix(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((IpcStream::Unix(stream), format!("{addr:?}")))
+            }
+            IpcListener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                stream.set_nodelay(true)?;
+                Ok((IpcStream::Tcp(stream), addr.to_string()))
+            }
+        }
+    }
+}
+
+impl Drop for IpcListener {
+    fn drop(&mut self) {
+        let IpcListener::Unix(listener) = self else {
+            return;
+        };
+        let Some(path) = listener
+            .local_addr()
+            .ok()
+            .and_then(|addr| addr.as_pathname().map(ToOwned::to_owned))
+        else {
+            return;
+        };
+        if let Err(err) = fs::remove_file(path) {
+            error!("failed to cleanup the socket: {err:?}");
+        }
+    }
+}
+
+fn set_only_v6(socket: &TcpSocket, only_v6: bool) -> io::Result<()> {
+    let value = only_v6 as libc::c_int;
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_V6ONLY,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_abstract(name: &str) -> io::Result<UnixListener> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixListener as StdUnixListener};
+
+    let addr = SocketAddr::from_abstract_name(name)?;
+    let listener = StdUnixListener::bind_addr(&addr)?;
+    listener.set_nonblocking(true)?;
+    UnixListener::from_std(listener)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn connect_abstract(name: &str) -> io::Result<UnixStream> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixStream as StdUnixStream};
+
+    let addr = SocketAddr::from_abstract_name(name)?;
+    let stream = StdUnixStream::connect_addr(&addr)?;
+    stream.set_nonblocking(true)?;
+    UnixStream::from_std(stream)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_abstract(_name: &str) -> io::Result<UnixListener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "abstract sockets are only supported on Linux",
+    ))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn connect_abstract(_name: &str) -> io::Result<UnixStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "abstract sockets are only supported on Linux",
+    ))
+}
@@ -0,0 +1,103 @@
+//! Throughput of the output path of processes: the pipe is read, the
+//! output is appended to the `LogBuffer`, sent to the subscribers and
+//! written to the log file.
+//!
+//! The latency of the path is measured by `petri bench` against a running
+//! server instead, since it's dominated by the scheduling of the server.
+
+use std::hint::black_box;
+use std::path::PathBuf;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use petri_core::process::{LogOptions, StartInfo};
+use petri_core::process_mgr::{Handle as ProcessManagerHandle, ProcessManager};
+use petri_utils::LogBuffer;
+use tokio::runtime::Builder as RuntimeBuilder;
+use tokio::sync::mpsc;
+
+/// A line like the ones of a chatty web server.
+const LINE: &str =
+    "2024-05-01T14:00:00.000Z INFO request handled in 1.2ms path=/api/items status=200";
+
+/// The output of each run of the end-to-end benchmarks.
+const OUTPUT_BYTES: u64 = 8 * 1024 * 1024;
+
+fn log_buffer(c: &mut Criterion) {
+    let chunk: Vec<u8> = format!("{LINE}\n").bytes().cycle().take(1024).collect();
+    let mut group = c.benchmark_group("log_buffer");
+    group.throughput(Throughput::Bytes(chunk.len() as u64));
+    for cap in [4 * 1024, 64 * 1024, 1024 * 1024] {
+        group.bench_with_input(BenchmarkId::new("append", cap), &cap, |b, &cap| {
+            let mut buf = LogBuffer::with_capacity(cap);
+            b.iter(|| buf.append(black_box(&chunk)));
+        });
+    }
+    group.finish();
+}
+
+fn end_to_end(c: &mut Criterion) {
+    let runtime = RuntimeBuilder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to create the runtime");
+    let manager = runtime.block_on(async { ProcessManager::new() });
+    let log_dir = std::env::temp_dir().join(format!("petri-bench-{}", std::process::id()));
+
+    let mut group = c.benchmark_group("end_to_end");
+    group.throughput(Throughput::Bytes(OUTPUT_BYTES));
+    group.sample_size(10);
+    let cases = [
+        ("no_log_file", None, 1),
+        ("log_file", Some(log_dir.clone()), 1),
+        ("log_file_4_subscribers", Some(log_dir.clone()), 4),
+    ];
+    for (name, log_path, subscribers) in cases {
+        group.bench_function(name, |b| {
+            b.iter(|| runtime.block_on(pump(manager.handle(), log_path.clone(), subscribers)));
+        });
+    }
+    group.finish();
+
+    _ = std::fs::remove_dir_all(log_dir);
+}
+
+/// Runs a child that writes [`OUTPUT_BYTES`] of lines, until a subscriber
+/// receives all of them.
+async fn pump(manager: ProcessManagerHandle, log_path: Option<PathBuf>, subscribers: usize) {
+    let script = format!("yes '{LINE}' | head -c {OUTPUT_BYTES}");
+    let log = LogOptions {
+        path: log_path,
+        ..Default::default()
+    };
+    let start_info = StartInfo::builder("sh", "/")
+        .args(["-c", &script])
+        .log(log)
+        .build()
+        .expect("the start info should be valid");
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let permit = manager.queue_spawn().wait().await;
+    let (process, _subscription) = manager
+        .add_attached_process_with_permit(&start_info, permit, tx)
+        .await
+        .expect("failed to spawn the child");
+    // The other subscribers only take their share of the sending.
+    let mut others = vec![];
+    for _ in 1..subscribers {
+        let (tx, rx) = mpsc::unbounded_channel();
+        others.push((manager.attach_output_channel(process.id(), tx).await, rx));
+    }
+
+    let mut received = 0;
+    while received < OUTPUT_BYTES {
+        let Some(chunk) = rx.recv().await else {
+            break;
+        };
+        received += chunk.len() as u64;
+    }
+    assert_eq!(received, OUTPUT_BYTES, "the child exited early");
+    process.wait().await;
+}
+
+criterion_group!(benches, log_buffer, end_to_end);
+criterion_main!(benches);
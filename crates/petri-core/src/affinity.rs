@@ -0,0 +1,137 @@
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use anyhow::{Error, Result};
+
+/// A set of CPUs that a process is allowed to run on.
+///
+/// It can be parsed from a list-style string like `0-3,6`, which is
+/// the same format that `taskset` and cgroups accept.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CpuSet(Vec<usize>);
+
+impl CpuSet {
+    #[inline]
+    pub fn cpus(&self) -> &[usize] {
+        &self.0
+    }
+
+    /// Converts the CPU set into the raw representation used by the
+    /// affinity syscalls.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn to_raw(&self) -> libc::cpu_set_t {
+        let mut raw_set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for cpu in &self.0 {
+            unsafe { libc::CPU_SET(*cpu, &mut raw_set) };
+        }
+        raw_set
+    }
+}
+
+impl FromStr for CpuSet {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut cpus = vec![];
+        for part in s.split(',').map(str::trim) {
+            if part.is_empty() {
+                return Err(anyhow!("empty item in cpu list `{s}`"));
+            }
+
+            let (start, end) = match part.split_once('-') {
+                Some((start, end)) => (start.trim().parse()?, end.trim().parse()?),
+                None => {
+                    let cpu: usize = part.parse()?;
+                    (cpu, cpu)
+                }
+            };
+            if start > end {
+                return Err(anyhow!("invalid cpu range `{part}`"));
+            }
+            if end >= max_cpus() {
+                return Err(anyhow!("cpu {end} is out of range"));
+            }
+            cpus.extend(start..=end);
+        }
+
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(Self(cpus))
+    }
+}
+
+impl Display for CpuSet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut iter = self.0.iter().copied().peekable();
+        let mut first = true;
+        while let Some(start) = iter.next() {
+            let mut end = start;
+            while iter.peek() == Some(&(end + 1)) {
+                end = iter.next().unwrap();
+            }
+
+            if !first {
+                f.write_str(",")?;
+            }
+            first = false;
+
+            if start == end {
+                write!(f, "{start}")?;
+            } else {
+                write!(f, "{start}-{end}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Applies the raw CPU set to the calling process.
+///
+/// This is meant to be called in the child process after forking,
+/// so it only performs a single syscall without allocation.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_current_affinity(raw_set: &libc::cpu_set_t) -> std::io::Result<()> {
+    let res =
+        unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), raw_set) };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[inline]
+fn max_cpus() -> usize {
+    libc::CPU_SETSIZE as usize
+}
+
+#[cfg(not(target_os = "linux"))]
+#[inline]
+fn max_cpus() -> usize {
+    1024
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuSet;
+
+    #[test]
+    fn test_parse() {
+        let set: CpuSet = "0-3,6".parse().unwrap();
+        assert_eq!(set.cpus(), &[0, 1, 2, 3, 6]);
+
+        let set: CpuSet = "5, 1-2, 2".parse().unwrap();
+        assert_eq!(set.cpus(), &[1, 2, 5]);
+
+        assert!("".parse::<CpuSet>().is_err());
+        assert!("3-1".parse::<CpuSet>().is_err());
+        assert!("0,,1".parse::<CpuSet>().is_err());
+        assert!("a-b".parse::<CpuSet>().is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        let set: CpuSet = "6,0-3,4".parse().unwrap();
+        assert_eq!(set.to_string(), "0-4,6");
+    }
+}
@@ -0,0 +1,194 @@
+//! Checkpoints of process trees made with CRIU, which snapshots them into
+//! a directory of images to be restored later, even after a reboot. This
+//! is experimental and Linux only.
+//!
+//! The output pipes of a process are not part of the snapshot. They are
+//! recorded instead, so the restored tree writes to the pipes of the
+//! `criu restore` process that is managed in place of the original one.
+
+use std::env;
+use std::fs;
+use std::io::{self, ErrorKind as IoErrorKind};
+use std::path::Path;
+use std::process::Output;
+
+use anyhow::Result;
+use tokio::process::Command;
+
+use crate::container::Runtime;
+use crate::process::{Process, StartInfo};
+
+/// The program that checkpoints and restores processes.
+pub const CRIU_PROGRAM: &str = "criu";
+
+const DUMP_LOG: &str = "dump.log";
+const RESTORE_LOG: &str = "restore.log";
+
+/// The number of lines of the CRIU log shown when it fails.
+const LOG_TAIL_LINES: usize = 5;
+
+/// The output pipes of a checkpointed process, like `pipe:[1234]`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct OutputPipes {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Returns whether CRIU is found in the `PATH` of the server.
+pub fn is_installed() -> bool {
+    env::var_os("PATH")
+        .is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join(CRIU_PROGRAM).is_file()))
+}
+
+/// Snapshots the process and its descendants into `images_dir`, which
+/// stops them unless `leave_running` is set.
+pub async fn dump(
+    process: &Process,
+    images_dir: &Path,
+    leave_running: bool,
+) -> Result<OutputPipes> {
+    if !cfg!(target_os = "linux") {
+        return Err(anyhow!("checkpoints are only supported on Linux"));
+    }
+    if process.is_containerized() {
+        return Err(anyhow!(
+            "processes in containers cannot be checkpointed, use the container engine instead"
+        ));
+    }
+
+    let pid = process.id();
+    let pipes = OutputPipes {
+        stdout: fd_target(pid, 1)?,
+        stderr: fd_target(pid, 2)?,
+    };
+    fs::create_dir_all(images_dir)?;
+
+    let mut command = Command::new(CRIU_PROGRAM);
+    command
+        .arg("dump")
+        .arg("--tree")
+        .arg(pid.to_string())
+        .arg("--images-dir")
+        .arg(images_dir)
+        .args(["--shell-job", "--file-locks", "--log-file", DUMP_LOG]);
+    if leave_running {
+        command.arg("--leave-running");
+    }
+
+    // Marked beforehand, since the process may be reaped before CRIU exits.
+    process.set_checkpointed(!leave_running);
+    let output = command.output().await;
+    let result = check_output(output, &images_dir.join(DUMP_LOG));
+    if result.is_err() {
+        process.set_checkpointed(false);
+    }
+    result.map(|_| pipes)
+}
+
+/// Returns how to start the process that restores the checkpoint in
+/// `images_dir`. The working directory and logging are kept from the
+/// start info of the checkpointed process.
+pub fn restore_start_info(
+    start_info: &StartInfo,
+    images_dir: &Path,
+    pipes: &OutputPipes,
+) -> StartInfo {
+    let mut args = vec![
+        "restore".to_owned(),
+        "--images-dir".to_owned(),
+        images_dir.display().to_string(),
+        "--shell-job".to_owned(),
+        "--file-locks".to_owned(),
+        "--log-file".to_owned(),
+        RESTORE_LOG.to_owned(),
+        "--inherit-fd".to_owned(),
+        format!("fd[1]:{}", pipes.stdout),
+    ];
+    if pipes.stderr != pipes.stdout {
+        args.push("--inherit-fd".to_owned());
+        args.push(format!("fd[2]:{}", pipes.stderr));
+    }
+
+    let mut info = start_info.clone();
+    // Keep the log files named after the checkpointed program.
+    info.log.name = Some(start_info.log_name_template());
+    info.program = CRIU_PROGRAM.to_owned();
+    info.args = Some(args);
+    info.runtime = Runtime::Native;
+    info.sandbox = Default::default();
+    info
+}
+
+/// Returns what the file descriptor of the process refers to.
+fn fd_target(pid: u32, fd: u32) -> io::Result<String> {
+    let target = fs::read_link(format!("/proc/{pid}/fd/{fd}"))?;
+    Ok(target.display().to_string())
+}
+
+fn check_output(output: io::Result<Output>, log_path: &Path) -> Result<()> {
+    let output = match output {
+        Ok(output) => output,
+        Err(err) if err.kind() == IoErrorKind::NotFound => {
+            return Err(anyhow!("`{CRIU_PROGRAM}` is not installed"));
+        }
+        Err(err) => return Err(err.into()),
+    };
+    if output.status.success() {
+        return Ok(());
+    }
+
+    Err(anyhow!(
+        "`{CRIU_PROGRAM}` failed with {}:\n{}",
+        output.status,
+        log_tail(log_path)
+    ))
+}
+
+/// Returns the error of the restoring process that exited early, with the
+/// end of the CRIU log in `images_dir`.
+pub fn restore_error(images_dir: &Path, exit_code: i32) -> anyhow::Error {
+    let log_path = images_dir.join(RESTORE_LOG);
+    anyhow!(
+        "`{CRIU_PROGRAM}` exited with code {exit_code}, see `{}`:\n{}",
+        log_path.display(),
+        log_tail(&log_path)
+    )
+}
+
+fn log_tail(path: &Path) -> String {
+    let log = fs::read_to_string(path).unwrap_or_default();
+    let lines: Vec<_> = log.lines().collect();
+    lines[lines.len().saturating_sub(LOG_TAIL_LINES)..].join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{restore_start_info, OutputPipes};
+    use crate::process::StartInfo;
+
+    #[test]
+    fn test_restore_start_info() {
+        let start_info = StartInfo::builder("server", "/srv").build().unwrap();
+        let pipes = OutputPipes {
+            stdout: "pipe:[11]".to_owned(),
+            stderr: "pipe:[12]".to_owned(),
+        };
+        let info = restore_start_info(&start_info, Path::new("/ckpt/images"), &pipes);
+        assert_eq!(info.program, "criu");
+        assert_eq!(info.cwd, "/srv");
+        let args = info.args.unwrap();
+        assert_eq!(&args[..3], ["restore", "--images-dir", "/ckpt/images"]);
+        assert!(args.ends_with(&[
+            "--inherit-fd".to_owned(),
+            "fd[1]:pipe:[11]".to_owned(),
+            "--inherit-fd".to_owned(),
+            "fd[2]:pipe:[12]".to_owned(),
+        ]));
+        assert!(info
+            .log
+            .name
+            .is_some_and(|name| name.to_string().starts_with("server-")));
+    }
+}
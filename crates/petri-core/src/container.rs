@@ -0,0 +1,187 @@
+use std::ffi::OsString;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+
+use tokio::process::Command;
+
+use crate::process::StartInfo;
+
+/// The variables that only make sense on the host, which are not passed
+/// into containers.
+const HOST_ENV: [&str; 2] = ["PATH", "HOME"];
+
+/// The runtime that a process is launched with.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub enum Runtime {
+    /// Spawns the program directly on the host.
+    #[default]
+    Native,
+    /// Runs the program inside an OCI container.
+    Container(ContainerOptions),
+}
+
+/// Options for running a program inside an OCI container.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ContainerOptions {
+    /// The CLI of the container engine, like `docker` or `podman`.
+    pub engine: String,
+    /// The image to run the program with.
+    pub image: String,
+}
+
+/// A container launched by the engine CLI in foreground mode.
+///
+/// The engine CLI forwards the container's stdio and exits with the
+/// same code as the container, so it can be monitored just like a
+/// regular process. However, killing the CLI process doesn't stop the
+/// container, which must be done through the engine instead.
+pub(crate) struct Container {
+    engine: String,
+    name: String,
+}
+
+impl Container {
+    pub(crate) fn new(opts: &ContainerOptions) -> Self {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+        let seq = SEQ.fetch_add(1, AtomicOrdering::Relaxed);
+        Self {
+            engine: opts.engine.clone(),
+            name: format!("petri-{}-{seq}", std::process::id()),
+        }
+    }
+
+    /// Makes the engine command, which runs the container once the
+    /// arguments are added by [`add_run_args`].
+    ///
+    /// [`add_run_args`]: Self::add_run_args
+    pub(crate) fn command(&self) -> Command {
+        Command::new(&self.engine)
+    }
+
+    /// Adds the arguments to run the container in foreground. It's done
+    /// after the environment of the command is set, which is passed into
+    /// the container.
+    pub(crate) fn add_run_args(
+        &self,
+        command: &mut Command,
+        opts: &ContainerOptions,
+        start_info: &StartInfo,
+    ) {
+        command.args(["run", "--rm", "--init", "--name", &self.name]);
+
+        // Only the names are passed, so the engine reads the values from
+        // its environment and they are not shown in the process list. The
+        // paths of the host are left to the image.
+        let env_names: Vec<OsString> = command
+            .as_std()
+            .get_envs()
+            .filter(|(name, value)| value.is_some() && !HOST_ENV.iter().any(|host| name == host))
+            .map(|(name, _)| name.to_owned())
+            .collect();
+        for name in env_names {
+            command.arg("--env").arg(name);
+        }
+
+        if start_info.sandbox.bind_cwd {
+            let mut volume = OsString::from(&start_info.cwd);
+            volume.push(":");
+            volume.push(&start_info.cwd);
+            command.arg("--volume").arg(volume);
+        }
+        command.args(["--workdir", &start_info.cwd]);
+
+        // Map the host-level settings to their container equivalents.
+        if let Some(cpus) = &start_info.cpus {
+            command.arg(format!("--cpuset-cpus={cpus}"));
+        }
+        let sandbox = &start_info.sandbox;
+        if sandbox.no_new_privs {
+            command.args(["--security-opt", "no-new-privileges"]);
+        }
+        if sandbox.private_tmp {
+            command.args(["--tmpfs", "/tmp"]);
+        }
+        if sandbox.isolate_network {
+            command.args(["--network", "none"]);
+        }
+        if sandbox.read_only_root {
+            command.arg("--read-only");
+        }
+        if sandbox.rootfs.is_some() {
+            warn!("rootfs is not supported by the container runtime, ignoring it");
+        }
+
+        command.arg(&opts.image).arg(&start_info.program);
+        if let Some(args) = &start_info.args {
+            command.args(args);
+        }
+    }
+
+    /// Asks the engine to kill the container.
+    ///
+    /// Returns `false` if the engine failed to do that, and the caller
+    /// should fall back to killing the engine CLI process.
+    pub(crate) async fn kill(&self) -> bool {
+        let status = Command::new(&self.engine)
+            .args(["kill", &self.name])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await;
+        match status {
+            Ok(status) if status.success() => true,
+            Ok(status) => {
+                warn!("failed to kill container `{}`: {status}", self.name);
+                false
+            }
+            Err(err) => {
+                warn!("failed to kill container `{}`: {err:?}", self.name);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Container, ContainerOptions};
+    use crate::process::StartInfo;
+    use crate::sandbox::SandboxOptions;
+
+    #[test]
+    fn test_run_args() {
+        let opts = ContainerOptions {
+            engine: "docker".to_owned(),
+            image: "alpine".to_owned(),
+        };
+        let start_info = StartInfo::builder("env", "/srv")
+            .sandbox(SandboxOptions {
+                bind_cwd: true,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let container = Container::new(&opts);
+        let mut command = container.command();
+        command.env("FOO", "bar").env("PATH", "/usr/bin");
+        container.add_run_args(&mut command, &opts, &start_info);
+
+        let args: Vec<_> = command
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        let tail = [
+            "--env",
+            "FOO",
+            "--volume",
+            "/srv:/srv",
+            "--workdir",
+            "/srv",
+            "alpine",
+            "env",
+        ];
+        assert!(args.ends_with(&tail.map(str::to_owned)), "{args:?}");
+    }
+}
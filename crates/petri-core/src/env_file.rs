@@ -0,0 +1,147 @@
+//! `.env` files that jobs read their variables from. They are read again
+//! whenever a job starts, so edits take effect at the next restart.
+//!
+//! Lines are `NAME=value`, optionally prefixed by `export`. Values can be
+//! single-quoted (taken literally) or double-quoted (with `\n`, `\"` and
+//! `\\` escapes). Blank lines and lines starting with `#` are ignored, as
+//! are comments after unquoted values.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+/// Reads the variables of the file, in the order they are defined. The
+/// errors tell the path, and the line that fails to parse.
+pub fn load(path: &Path) -> Result<Vec<(String, String)>> {
+    let s = fs::read_to_string(path)
+        .map_err(|err| anyhow!("failed to read env file `{}`: {err}", path.display()))?;
+    parse(&s).map_err(|err| anyhow!("invalid env file `{}`: {err:#}", path.display()))
+}
+
+pub fn parse(s: &str) -> Result<Vec<(String, String)>> {
+    let mut vars = vec![];
+    for (index, line) in s.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let var = parse_line(line).with_context(|| format!("line {}", index + 1))?;
+        vars.push(var);
+    }
+    Ok(vars)
+}
+
+fn parse_line(line: &str) -> Result<(String, String)> {
+    let line = line
+        .strip_prefix("export")
+        .filter(|rest| rest.starts_with([' ', '\t']))
+        .map_or(line, str::trim_start);
+    let Some((name, value)) = line.split_once('=') else {
+        return Err(anyhow!("expected `NAME=value`"));
+    };
+    let name = name.trim_end();
+    let valid_name = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_name {
+        return Err(anyhow!("invalid variable name `{name}`"));
+    }
+    Ok((name.to_owned(), parse_value(value.trim_start())?))
+}
+
+fn parse_value(value: &str) -> Result<String> {
+    if let Some(rest) = value.strip_prefix('\'') {
+        let Some((value, trailing)) = rest.split_once('\'') else {
+            return Err(anyhow!("unterminated single quote"));
+        };
+        check_trailing(trailing)?;
+        return Ok(value.to_owned());
+    }
+
+    if let Some(rest) = value.strip_prefix('"') {
+        let mut unquoted = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((_, c)) = chars.next() {
+            match c {
+                '"' => {
+                    let trailing = chars.as_str();
+                    check_trailing(trailing)?;
+                    return Ok(unquoted);
+                }
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => unquoted.push('\n'),
+                    Some((_, 't')) => unquoted.push('\t'),
+                    Some((_, c @ ('"' | '\\' | '$'))) => unquoted.push(c),
+                    Some((_, c)) => {
+                        unquoted.push('\\');
+                        unquoted.push(c);
+                    }
+                    None => break,
+                },
+                c => unquoted.push(c),
+            }
+        }
+        return Err(anyhow!("unterminated double quote"));
+    }
+
+    // Comments after unquoted values must be separated by whitespace, so
+    // values like `#fff` are kept.
+    let value = match value.find(" #").or_else(|| value.find("\t#")) {
+        Some(end) => &value[..end],
+        None => value,
+    };
+    Ok(value.trim_end().to_owned())
+}
+
+fn check_trailing(trailing: &str) -> Result<()> {
+    let trailing = trailing.trim_start();
+    if trailing.is_empty() || trailing.starts_with('#') {
+        return Ok(());
+    }
+    Err(anyhow!("unexpected `{trailing}` after the quoted value"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn test_parse() {
+        let file = r#"
+# Settings of the app.
+PORT=8080
+export NAME = web app  # the name
+COLOR=#fff
+EMPTY=
+SINGLE='a "b" $c'
+DOUBLE="line\n\"quoted\"" # comment
+"#;
+        let vars = parse(file).unwrap();
+        let pairs: Vec<_> = vars.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(
+            pairs,
+            [
+                ("PORT", "8080"),
+                ("NAME", "web app"),
+                ("COLOR", "#fff"),
+                ("EMPTY", ""),
+                ("SINGLE", "a \"b\" $c"),
+                ("DOUBLE", "line\n\"quoted\""),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = parse("A=1\nB\n").unwrap_err();
+        assert_eq!(format!("{err:#}"), "line 2: expected `NAME=value`");
+        assert!(parse("1A=x").is_err());
+        assert!(parse("A='x").is_err());
+        assert!(parse("A=\"x").is_err());
+        assert!(parse("A='x' y").is_err());
+        assert!(parse("export=1").is_ok());
+    }
+}
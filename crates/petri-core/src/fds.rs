@@ -0,0 +1,133 @@
+//! File descriptors of the server. Every process takes a few of them (its
+//! pipes and log files), so hundreds of processes can exhaust the limit of
+//! the server, which then fails in unrelated places.
+//!
+//! The raised limit is for the server only, processes get the original one
+//! back (see [`restore_limit`]).
+
+use std::fs;
+use std::io;
+use std::mem;
+use std::sync::OnceLock;
+
+use tokio::process::Command;
+
+/// The highest limit macOS accepts, even when the hard limit is unlimited.
+#[cfg(target_os = "macos")]
+const MACOS_OPEN_MAX: u64 = 10240;
+
+/// The soft limit before it was first raised.
+static ORIGINAL_SOFT_LIMIT: OnceLock<libc::rlim_t> = OnceLock::new();
+
+/// The open file descriptors of the server and their limit.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Usage {
+    pub open: usize,
+    pub limit: u64,
+}
+
+impl Usage {
+    /// Returns the current usage, `None` if it can't be told.
+    pub fn current() -> Option<Self> {
+        Some(Self {
+            open: open_count()?,
+            limit: limits().ok()?.rlim_cur,
+        })
+    }
+
+    /// Returns how many more file descriptors can be opened.
+    pub fn available(&self) -> u64 {
+        self.limit.saturating_sub(self.open as u64)
+    }
+}
+
+/// Raises the soft limit of open files to `target`, or to the hard limit
+/// if it's not given, and returns the new limit. The limit is never raised
+/// above the hard limit, nor lowered.
+pub fn raise_limit(target: Option<u64>) -> io::Result<u64> {
+    let mut limit = limits()?;
+    ORIGINAL_SOFT_LIMIT.get_or_init(|| limit.rlim_cur);
+    let hard = limit.rlim_max;
+    let target = target.map_or(hard, |target| target.min(hard));
+    if target <= limit.rlim_cur {
+        return Ok(limit.rlim_cur);
+    }
+
+    #[cfg(target_os = "macos")]
+    let target = target.min(MACOS_OPEN_MAX.max(limit.rlim_cur));
+    limit.rlim_cur = target;
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(target)
+}
+
+/// Makes the command start with the soft limit the server had before it was
+/// raised. Some programs break with descriptors over `FD_SETSIZE`, or size
+/// tables by the limit.
+pub fn restore_limit(command: &mut Command) {
+    let Some(&original) = ORIGINAL_SOFT_LIMIT.get() else {
+        return;
+    };
+    unsafe {
+        command.pre_exec(move || {
+            // Best effort, the process can run with the raised limit.
+            let mut limit = mem::zeroed::<libc::rlimit>();
+            if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) == 0 && original < limit.rlim_cur {
+                limit.rlim_cur = original;
+                libc::setrlimit(libc::RLIMIT_NOFILE, &limit);
+            }
+            Ok(())
+        });
+    }
+}
+
+fn limits() -> io::Result<libc::rlimit> {
+    let mut limit = unsafe { mem::zeroed::<libc::rlimit>() };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(limit)
+}
+
+/// Counts the open file descriptors, without the one used to list them.
+/// It lists a directory, so call it off the runtime.
+fn open_count() -> Option<usize> {
+    let entries = fs::read_dir("/dev/fd").ok()?;
+    Some(entries.count().saturating_sub(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Stdio;
+
+    use tokio::process::Command;
+
+    use super::{limits, raise_limit, restore_limit, Usage};
+
+    #[tokio::test]
+    async fn test_raise_and_restore_limit() {
+        // Leave room to raise the limit, so there's one to restore.
+        let mut before = limits().unwrap();
+        if before.rlim_cur == before.rlim_max {
+            before.rlim_cur -= 1;
+            assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &before) }, 0);
+        }
+        // Never lowered, nor raised beyond the hard limit.
+        assert_eq!(raise_limit(Some(0)).unwrap(), before.rlim_cur);
+        let raised = raise_limit(Some(before.rlim_cur + 1)).unwrap();
+        assert_eq!(raised, before.rlim_cur + 1);
+        assert_eq!(limits().unwrap().rlim_cur, raised);
+
+        let mut command = Command::new("sh");
+        command.args(["-c", "ulimit -n"]).stdout(Stdio::piped());
+        restore_limit(&mut command);
+        let output = command.output().await.unwrap();
+        let limit = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(limit.trim(), before.rlim_cur.to_string());
+
+        let usage = Usage::current().unwrap();
+        assert!(usage.open > 0);
+        assert_eq!(usage.limit, raised);
+    }
+}
@@ -0,0 +1,476 @@
+//! Starts and exits of jobs. The recent events of each job are kept in
+//! memory, and older ones are spilled to an append-only file of the job,
+//! which is pruned by the retention.
+//!
+//! The files are only accessed by a writer task on the blocking pool, in
+//! the order the operations are queued, so the job manager is never held
+//! up by the disk.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind as IoErrorKind, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Local, TimeZone};
+use parking_lot::Mutex;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+
+use crate::process::ExitReason;
+
+/// Something that happened to the process of a job.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct JobEvent {
+    pub time: DateTime<Local>,
+    pub pid: u32,
+    pub kind: JobEventKind,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum JobEventKind {
+    Started,
+    /// Started again after the job exited, by its restart policy or by
+    /// users.
+    Restarted,
+    /// Started from a checkpoint of the job.
+    Restored,
+    Exited {
+        exit_code: i32,
+        reason: ExitReason,
+    },
+}
+
+/// The events of the jobs, see the module docs.
+#[derive(Clone)]
+pub struct JobHistory {
+    inner: Arc<HistoryInner>,
+}
+
+struct HistoryInner {
+    /// Where the spilled events are kept, they are dropped if it's `None`.
+    dir: Option<PathBuf>,
+    /// The number of events of each job kept in memory.
+    capacity: usize,
+    /// How long the spilled events are kept.
+    retention: Duration,
+    recent: Mutex<HashMap<String, VecDeque<JobEvent>>>,
+    /// The queue of the writer task, if there is a dir.
+    ops_tx: Option<mpsc::UnboundedSender<FileOp>>,
+}
+
+/// An operation on the files, done by the writer task.
+enum FileOp {
+    Spill {
+        path: PathBuf,
+        event: JobEvent,
+    },
+    Remove {
+        path: PathBuf,
+    },
+    Read {
+        path: PathBuf,
+        since: Option<DateTime<Local>>,
+        reply: oneshot::Sender<io::Result<Vec<JobEvent>>>,
+    },
+    Prune {
+        dir: PathBuf,
+        since: DateTime<Local>,
+        reply: oneshot::Sender<io::Result<usize>>,
+    },
+    /// Replies once the operations queued before are done.
+    Flush(oneshot::Sender<()>),
+}
+
+/// The number of events of each job kept in memory by default.
+pub const DEFAULT_CAPACITY: usize = 32;
+
+/// How many queued operations the writer task does at a time.
+const BATCH_SIZE: usize = 64;
+
+const FILE_EXTENSION: &str = "history";
+
+impl JobHistory {
+    /// Creates a history keeping up to `capacity` events of each job, and
+    /// dropping the older ones.
+    pub fn in_memory(capacity: usize) -> Self {
+        Self::new(None, capacity, Duration::ZERO)
+    }
+
+    /// Creates a history keeping up to `capacity` events of each job in
+    /// memory, and the older ones in `dir` for `retention`.
+    ///
+    /// It must be called in the runtime, which runs the writer task.
+    pub fn with_dir(dir: PathBuf, capacity: usize, retention: Duration) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self::new(Some(dir), capacity, retention))
+    }
+
+    fn new(dir: Option<PathBuf>, capacity: usize, retention: Duration) -> Self {
+        let ops_tx = dir.as_ref().map(|_| {
+            let (ops_tx, ops_rx) = mpsc::unbounded_channel();
+            task::spawn(drain(ops_rx));
+            ops_tx
+        });
+        Self {
+            inner: Arc::new(HistoryInner {
+                dir,
+                capacity: capacity.max(1),
+                retention,
+                recent: Default::default(),
+                ops_tx,
+            }),
+        }
+    }
+
+    /// Records the event of the job, spilling its oldest event in memory
+    /// if there are too many.
+    pub fn record(&self, jid: &str, event: JobEvent) {
+        let mut recent = self.inner.recent.lock();
+        let events = recent.entry(jid.to_owned()).or_default();
+        events.push_back(event);
+        if events.len() <= self.inner.capacity {
+            return;
+        }
+        let spilled = events.pop_front().expect("events should not be empty");
+        // Queue it with the lock held, so a read that misses it in memory
+        // is queued after it.
+        if let Some(path) = self.file_path(jid) {
+            self.queue(FileOp::Spill {
+                path,
+                event: spilled,
+            });
+        }
+    }
+
+    /// Returns the events of the job from the oldest, including the
+    /// spilled ones within the retention.
+    pub async fn events(&self, jid: &str) -> io::Result<Vec<JobEvent>> {
+        let (spilled_rx, recent) = {
+            let recent = self.inner.recent.lock();
+            // The events spilled before the snapshot are read, and the ones
+            // after are still in it.
+            let spilled_rx = self.file_path(jid).map(|path| {
+                let (reply, reply_rx) = oneshot::channel();
+                self.queue(FileOp::Read {
+                    path,
+                    since: self.since(),
+                    reply,
+                });
+                reply_rx
+            });
+            (spilled_rx, recent.get(jid).cloned().unwrap_or_default())
+        };
+
+        let mut events = match spilled_rx {
+            Some(reply_rx) => reply_rx.await.unwrap_or_else(|_| Err(writer_gone()))?,
+            None => vec![],
+        };
+        events.extend(recent);
+        Ok(events)
+    }
+
+    /// Forgets the job, like when it's removed.
+    pub fn remove(&self, jid: &str) {
+        let mut recent = self.inner.recent.lock();
+        recent.remove(jid);
+        // Queue it with the lock held, so it comes after the spills.
+        if let Some(path) = self.file_path(jid) {
+            self.queue(FileOp::Remove { path });
+        }
+    }
+
+    /// Drops the spilled events older than the retention, removing the
+    /// files left with no events, like the ones of jobs from the previous
+    /// runs of the server. Returns the number of removed files.
+    pub async fn prune(&self) -> io::Result<usize> {
+        let Some(dir) = self.inner.dir.clone() else {
+            return Ok(0);
+        };
+        let Some(since) = self.since() else {
+            return Ok(0);
+        };
+        let (reply, reply_rx) = oneshot::channel();
+        self.queue(FileOp::Prune { dir, since, reply });
+        reply_rx.await.unwrap_or_else(|_| Err(writer_gone()))
+    }
+
+    /// Waits until the spilled events are written to the files.
+    pub async fn flush(&self) {
+        let (reply, reply_rx) = oneshot::channel();
+        self.queue(FileOp::Flush(reply));
+        _ = reply_rx.await;
+    }
+
+    fn queue(&self, op: FileOp) {
+        let Some(ops_tx) = &self.inner.ops_tx else {
+            return;
+        };
+        // The task only stops if it panicked, which is already reported.
+        _ = ops_tx.send(op);
+    }
+
+    fn file_path(&self, jid: &str) -> Option<PathBuf> {
+        let dir = self.inner.dir.as_ref()?;
+        Some(dir.join(format!("{jid}.{FILE_EXTENSION}")))
+    }
+
+    /// Returns the time that the kept events are since, or `None` if all
+    /// are kept.
+    fn since(&self) -> Option<DateTime<Local>> {
+        let retention = chrono::Duration::from_std(self.inner.retention).ok()?;
+        Local::now().checked_sub_signed(retention)
+    }
+}
+
+fn writer_gone() -> io::Error {
+    io::Error::other("the writer of the job history is gone")
+}
+
+/// Does the queued operations in batches, taking a thread of the blocking
+/// pool only while there are operations to do.
+async fn drain(mut ops_rx: mpsc::UnboundedReceiver<FileOp>) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    while let Some(op) = ops_rx.recv().await {
+        batch.push(op);
+        while batch.len() < BATCH_SIZE {
+            let Ok(op) = ops_rx.try_recv() else {
+                break;
+            };
+            batch.push(op);
+        }
+
+        let mut ops = mem::take(&mut batch);
+        let done = task::spawn_blocking(move || {
+            for op in ops.drain(..) {
+                op.run();
+            }
+            ops
+        })
+        .await;
+        match done {
+            Ok(ops) => batch = ops,
+            Err(err) => {
+                error!("failed to write the job history: {err}");
+                return;
+            }
+        }
+    }
+}
+
+impl FileOp {
+    fn run(self) {
+        match self {
+            FileOp::Spill { path, event } => {
+                if let Err(err) = spill(&path, &event) {
+                    warn!("failed to spill to `{}`: {err:?}", path.display());
+                }
+            }
+            FileOp::Remove { path } => {
+                if let Err(err) = fs::remove_file(&path) {
+                    if err.kind() != IoErrorKind::NotFound {
+                        warn!("failed to remove `{}`: {err:?}", path.display());
+                    }
+                }
+            }
+            FileOp::Read { path, since, reply } => {
+                let events = read_events(&path).map(|events| {
+                    events
+                        .into_iter()
+                        .filter(|event| since.is_none_or(|since| event.time >= since))
+                        .collect()
+                });
+                _ = reply.send(events);
+            }
+            FileOp::Prune { dir, since, reply } => {
+                _ = reply.send(prune(&dir, since));
+            }
+            FileOp::Flush(reply) => {
+                _ = reply.send(());
+            }
+        }
+    }
+}
+
+fn spill(path: &Path, event: &JobEvent) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{event}")
+}
+
+fn prune(dir: &Path, since: DateTime<Local>) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(FILE_EXTENSION) {
+            continue;
+        }
+        let events = read_events(&path)?;
+        let kept: Vec<_> = events.iter().filter(|event| event.time >= since).collect();
+        if kept.is_empty() {
+            fs::remove_file(&path)?;
+            removed += 1;
+        } else if kept.len() < events.len() {
+            let mut content = String::new();
+            for event in kept {
+                content.push_str(&format!("{event}\n"));
+            }
+            let tmp_path = path.with_extension("tmp");
+            fs::write(&tmp_path, content)?;
+            fs::rename(&tmp_path, &path)?;
+        }
+    }
+    Ok(removed)
+}
+
+fn read_events(path: &Path) -> io::Result<Vec<JobEvent>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == IoErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+    let mut events = vec![];
+    for line in BufReader::new(file).lines() {
+        // A line may be cut off if the server was killed while writing it.
+        match line?.parse() {
+            Ok(event) => events.push(event),
+            Err(_) => continue,
+        }
+    }
+    Ok(events)
+}
+
+/// Encodes the event as a line of the file, like `1714570200000 1234 exited
+/// 1 oom`.
+impl Display for JobEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} ", self.time.timestamp_millis(), self.pid)?;
+        match self.kind {
+            JobEventKind::Started => f.write_str("started"),
+            JobEventKind::Restarted => f.write_str("restarted"),
+            JobEventKind::Restored => f.write_str("restored"),
+            JobEventKind::Exited { exit_code, reason } => {
+                let reason = match reason {
+                    ExitReason::Normal => "normal",
+                    ExitReason::Oom => "oom",
+                    ExitReason::Stopped => "stopped",
+                    ExitReason::Idle => "idle",
+                    ExitReason::MemoryWatchdog => "memory-watchdog",
+                    ExitReason::Checkpointed => "checkpointed",
+                    ExitReason::LogQuota => "log-quota",
+                };
+                write!(f, "exited {exit_code} {reason}")
+            }
+        }
+    }
+}
+
+impl FromStr for JobEvent {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split(' ');
+        let mut next = || fields.next().ok_or(());
+        let millis: i64 = next()?.parse().map_err(|_| ())?;
+        let time = Local.timestamp_millis_opt(millis).single().ok_or(())?;
+        let pid = next()?.parse().map_err(|_| ())?;
+        let kind = match next()? {
+            "started" => JobEventKind::Started,
+            "restarted" => JobEventKind::Restarted,
+            "restored" => JobEventKind::Restored,
+            "exited" => {
+                let exit_code = next()?.parse().map_err(|_| ())?;
+                let reason = match next()? {
+                    "normal" => ExitReason::Normal,
+                    "oom" => ExitReason::Oom,
+                    "stopped" => ExitReason::Stopped,
+                    "idle" => ExitReason::Idle,
+                    "memory-watchdog" => ExitReason::MemoryWatchdog,
+                    "checkpointed" => ExitReason::Checkpointed,
+                    "log-quota" => ExitReason::LogQuota,
+                    _ => return Err(()),
+                };
+                JobEventKind::Exited { exit_code, reason }
+            }
+            _ => return Err(()),
+        };
+        Ok(Self { time, pid, kind })
+    }
+}
+
+impl JobEvent {
+    pub fn now(pid: u32, kind: JobEventKind) -> Self {
+        Self {
+            time: Local::now(),
+            pid,
+            kind,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Local, TimeZone};
+
+    use super::{JobEvent, JobEventKind, JobHistory};
+    use crate::process::ExitReason;
+
+    #[test]
+    fn test_encode_event() {
+        let time = Local.timestamp_millis_opt(1714570200123).unwrap();
+        let kind = JobEventKind::Exited {
+            exit_code: 137,
+            reason: ExitReason::Oom,
+        };
+        let event = JobEvent {
+            time,
+            pid: 1234,
+            kind,
+        };
+        assert_eq!(event.to_string(), "1714570200123 1234 exited 137 oom");
+        assert_eq!(event.to_string().parse(), Ok(event));
+
+        let event = JobEvent {
+            kind: JobEventKind::Exited {
+                exit_code: 1,
+                reason: ExitReason::MemoryWatchdog,
+            },
+            ..event
+        };
+        assert_eq!(event.to_string().parse(), Ok(event));
+
+        for invalid in ["", "1714570200123 1234", "1714570200123 1234 exited 1"] {
+            assert!(invalid.parse::<JobEvent>().is_err(), "{invalid}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spill_events() {
+        let dir = std::env::temp_dir().join(format!("petri-job-history-{}", std::process::id()));
+        let history = JobHistory::with_dir(dir.clone(), 2, std::time::Duration::MAX).unwrap();
+        let events: Vec<_> = (0..5)
+            .map(|pid| JobEvent::now(pid, JobEventKind::Started))
+            .collect();
+        for event in &events {
+            history.record("job", *event);
+        }
+        let pids: Vec<_> = history
+            .events("job")
+            .await
+            .unwrap()
+            .iter()
+            .map(|e| e.pid)
+            .collect();
+        assert_eq!(pids, [0, 1, 2, 3, 4]);
+        // The spills are written before the read.
+        let spilled = std::fs::read_to_string(dir.join("job.history")).unwrap();
+        assert_eq!(spilled.lines().count(), 3);
+
+        history.remove("job");
+        assert!(history.events("job").await.unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
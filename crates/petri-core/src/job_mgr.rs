@@ -1,34 +1,277 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::ffi::OsString;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
 use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 use std::sync::{Arc, Weak};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
-use chrono::{DateTime, Local};
+use chrono::{DateTime, FixedOffset, Local, NaiveTime, Timelike};
 use indexmap::IndexMap;
-use petri_utils::subscriber_list::CancellationToken;
+use parking_lot::Mutex;
+use petri_logger::writers::file_writer::FileNameTemplate;
+use petri_utils::subscriber_list::{CancellationToken, SubscriberList};
+use petri_utils::time::{in_zone, parse_duration, DelayedTask};
 use petri_utils::Id;
 use sha1::digest::OutputSizeUser;
 use sha1::{Digest, Sha1};
-use tokio::sync::RwLock;
+use tokio::sync::{Notify, RwLock};
 use tokio::task;
 
-use crate::process::StartInfo;
+use crate::container::Runtime;
+use crate::env_file;
+use crate::job_history::{self, JobEvent, JobEventKind, JobHistory};
+use crate::metrics;
+use crate::precondition::Precondition;
+#[cfg(target_os = "linux")]
+use crate::proc_table::ProcStat;
+use crate::process::{ExitReason, LogUsage, Process, StartInfo};
 use crate::process_mgr::{self, Handle as ProcessManagerHandle};
+use crate::spawn_queue::SpawnPermit;
 
+/// Variables that tell the process of a job which job it runs for, unless
+/// it opts out by [`StartInfo::petri_env`]. Like `{job}` and `{instance}`
+/// in log file names, instances of a template have the id and name of the
+/// template, and their own id as the instance id.
+pub const JID_ENV: &str = "PETRI_JID";
+pub const JOB_NAME_ENV: &str = "PETRI_JOB_NAME";
+pub const INSTANCE_ID_ENV: &str = "PETRI_INSTANCE_ID";
+
+/// The namespace of jobs created without one.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// What a job runs and how it's managed, created with
+/// [`JobDescription::builder`].
 #[derive(Clone, Debug)]
+#[non_exhaustive]
 pub struct JobDescription {
     pub start_info: StartInfo,
-    pub auto_restart: bool,
+    /// When to start the job again after its process exits.
+    pub restart: RestartPolicy,
+    /// An optional unique name to refer to the job.
+    pub name: Option<String>,
+    /// The group of the job, like the project it's created for. Commands
+    /// like `up` act on the jobs of a namespace.
+    pub namespace: String,
+    /// Whether the job is a template, which is never started directly
+    /// but used to start parameterized instances.
+    pub template: bool,
+    /// Whether the job is started by `up` when no selector is given.
+    pub autostart: bool,
+    /// Arbitrary labels used to select jobs.
+    pub labels: BTreeMap<String, String>,
+    /// Jobs (by id or name) that must be ready before this job starts.
+    pub depends_on: Vec<String>,
+    /// Conditions outside of petri that must hold before the process of
+    /// the job is spawned, checked in order.
+    pub preconditions: Vec<Precondition>,
+    /// How long to wait for the preconditions before failing to start.
+    pub precondition_timeout: Duration,
+    /// How to decide that the job is ready after it's started.
+    pub readiness: Readiness,
+    /// Stop the job automatically once its process stays idle.
+    pub idle: Option<IdlePolicy>,
+    /// Restart or stop the job once its process uses too much memory.
+    pub memory_watchdog: Option<MemoryWatchdog>,
+    /// Jobs with lower stop priorities are stopped earlier when the server
+    /// shuts down, but always after the jobs depending on them.
+    pub stop_priority: i32,
+    /// A `.env` file read whenever the job starts, relative to the working
+    /// directory. Its variables take precedence over the ones of the start
+    /// info.
+    pub env_file: Option<PathBuf>,
+}
+
+/// Builds a [`JobDescription`]. Jobs are not templates and are started
+/// by `up` unless told otherwise.
+#[derive(Clone, Debug)]
+pub struct JobDescriptionBuilder {
+    desc: JobDescription,
+}
+
+/// The condition for a started job to be considered ready.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Readiness {
+    /// The job is ready as soon as its process is spawned.
+    #[default]
+    Immediate,
+    /// The job is ready if its process keeps running for the duration.
+    Delay(Duration),
+    /// The job is ready once its process notifies that it is, see
+    /// [`Process::mark_ready`].
+    Notify,
+}
+
+/// Stops a job once its process shows no activity for the timeout.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct IdlePolicy {
+    pub timeout: Duration,
+    pub activity: IdleActivity,
+}
+
+/// What counts as activity of a process for [`IdlePolicy`].
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum IdleActivity {
+    /// The process writes to stdout or stderr.
+    Output,
+    /// The CPU time of the process increases.
+    Cpu,
+    /// Either of the above.
+    #[default]
+    Any,
+}
+
+/// Restarts or stops a job once the resident set size of its process
+/// stays above the ceiling for a number of consecutive samples. Unlike
+/// cgroup limits, it works on every platform that processes can be
+/// sampled on, but doesn't count the descendants of the process.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MemoryWatchdog {
+    pub max_rss_bytes: u64,
+    /// The number of consecutive samples above the ceiling to act on,
+    /// so short spikes are tolerated.
+    pub samples: u32,
+    /// How often the process is sampled.
+    pub interval: Duration,
+    pub action: WatchdogAction,
+}
+
+/// What the [`MemoryWatchdog`] does with a job using too much memory.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum WatchdogAction {
+    /// Stop the process gracefully and start the job again, regardless of
+    /// its restart policy.
+    #[default]
+    Restart,
+    /// Stop the job.
+    Stop,
+}
+
+/// When to start a job again after its process exits.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct RestartPolicy {
+    pub mode: RestartMode,
+    /// Exit codes other than 0 that are treated as clean exits by
+    /// [`RestartMode::OnFailure`], like 143 for `SIGTERM`.
+    pub clean_exit_codes: Vec<i32>,
+    /// A random delay added before each restart, so jobs that exit at
+    /// once (e.g. after a shared dependency crashes) don't restart at once.
+    pub jitter: Option<RestartJitter>,
+    /// The times of day the job may be restarted in. A restart outside
+    /// of them waits for the next one to open. Empty means any time.
+    pub windows: Vec<RestartWindow>,
+}
+
+/// A range of random delays, written like `5s..30s`, or `30s` for up to
+/// 30 seconds.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RestartJitter {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+/// A daily range of time in the zone of the server, written like
+/// `02:00-04:00`. A window ending before it starts spans midnight, and one
+/// ending when it starts spans the whole day.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RestartWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum RestartMode {
+    /// The job is never restarted.
+    #[default]
+    Never,
+    /// The job is restarted if it exits with an unclean exit code, or is
+    /// killed for running out of memory.
+    OnFailure,
+    /// The job is restarted whenever it exits, unless it's stopped as a
+    /// job (e.g. by `petri down`).
+    Always,
+    /// The job is restarted whenever it exits, unless it's stopped by the
+    /// server, including stopping its process by pid.
+    UnlessStopped,
+}
+
+/// Parameters that override a job template when starting an instance.
+#[derive(Clone, Default, Debug)]
+pub struct JobOverrides {
+    /// Environment variables to add or replace.
+    pub env: Vec<(String, String)>,
+    /// Arguments to append to the command line.
+    pub extra_args: Vec<String>,
 }
 
 #[derive(Clone, Debug)]
 pub struct Job {
     id: Id,
+    /// The id shown to users.
+    short_id: Id,
     desc: JobDescription,
+    template_id: Option<Id>,
     created_at: DateTime<Local>,
     pid: Option<u32>,
     last_exit_code: Option<i32>,
+    last_exit_reason: Option<ExitReason>,
+    /// When the process of the job last exited.
+    exited_at: Option<DateTime<Local>>,
+    /// Whether the job is being stopped by [`Handle::stop_job`].
+    stop_requested: bool,
+}
+
+/// A change of the start info of a job, recorded so it can be undone.
+#[derive(Clone, Debug)]
+pub struct JobRevision {
+    seq: u64,
+    jid: Id,
+    before: StartInfo,
+    created_at: DateTime<Local>,
+}
+
+/// Handler of process events, with the id of the job that the process
+/// belongs to.
+pub trait EventHandler: Send + Sync {
+    fn handle_process_start(&self, pid: u32, jid: Option<&str>) {
+        _ = pid;
+        _ = jid;
+    }
+
+    fn handle_process_exit(
+        &self,
+        pid: u32,
+        jid: Option<&str>,
+        exit_code: i32,
+        exit_reason: ExitReason,
+    ) {
+        _ = pid;
+        _ = jid;
+        _ = exit_code;
+        _ = exit_reason;
+    }
+
+    /// Called when a log file of the process is rotated, with the path of
+    /// the previous file.
+    fn handle_log_rotated(&self, pid: u32, jid: Option<&str>, path: &Path) {
+        _ = pid;
+        _ = jid;
+        _ = path;
+    }
+
+    /// Called when the log files of the process exceed their quota, after
+    /// its policy is applied.
+    fn handle_log_quota_exceeded(&self, pid: u32, jid: Option<&str>, usage: &LogUsage) {
+        _ = pid;
+        _ = jid;
+        _ = usage;
+    }
 }
 
 pub struct JobManager {
@@ -44,40 +287,313 @@ struct ProcessManagerEventHandler {
     weak_ptr: Weak<Inner>,
 }
 
+/// Aggregate numbers of the job manager since the server started.
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct Stats {
+    /// Number of times the jobs were started again after they exited.
+    pub restarts: u64,
+    /// Process events that are not dispatched to the event handlers yet.
+    pub pending_events: u64,
+    pub event_handlers: usize,
+}
+
+/// What a garbage collection reclaimed, see [`Handle::gc`].
+#[derive(Clone, Default, Debug)]
+#[non_exhaustive]
+pub struct GcReport {
+    /// Names (or ids) of the removed jobs.
+    pub jobs: Vec<String>,
+    /// The number of deleted log files, and their total size in bytes.
+    pub log_files: usize,
+    pub log_bytes: u64,
+    /// The number of pids that were still indexed to jobs not running
+    /// them anymore.
+    pub stale_pids: usize,
+}
+
+/// What a scheduled stop applies to. Stops of jobs survive restarts of
+/// their processes.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum StopTarget {
+    Process(u32),
+    Job(Id),
+}
+
+impl Display for StopTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            StopTarget::Process(pid) => write!(f, "process {pid}"),
+            StopTarget::Job(jid) => write!(f, "job {}", &**jid),
+        }
+    }
+}
+
+struct ScheduledStop {
+    deadline: DateTime<Local>,
+    task: DelayedTask,
+}
+
+/// A restart by the restart policy of a job, which may wait for its
+/// jitter and its windows.
+struct ScheduledRestart {
+    at: DateTime<Local>,
+    task: DelayedTask,
+}
+
+/// Converts the variables to extend the environment of start infos with.
+fn os_env(
+    env: impl IntoIterator<Item = (String, String)>,
+) -> impl Iterator<Item = (OsString, OsString)> {
+    env.into_iter()
+        .map(|(name, value)| (name.into(), value.into()))
+}
+
+/// Hashes the string with its length, so adjacent strings are unambiguous.
+fn update_str(hasher: &mut Sha1, s: impl AsRef<[u8]>) {
+    let s = s.as_ref();
+    hasher.update((s.len() as u64).to_be_bytes());
+    hasher.update(s);
+}
+
+/// Returns the start time of the leader of the session, or `None` if no
+/// running process leads the session with the id. A process that reuses
+/// the pid of the leader starts at another time.
+#[cfg(target_os = "linux")]
+fn session_leader_start_time(sid: u32) -> Option<u64> {
+    leader_start_time(&ProcStat::read(sid).ok()?, sid)
+}
+
+/// Returns the start time of the process if it leads the session with the
+/// id and is still running.
+#[cfg(target_os = "linux")]
+fn leader_start_time(stat: &ProcStat, sid: u32) -> Option<u64> {
+    (!stat.is_zombie() && stat.session == sid).then_some(stat.start_time)
+}
+
+/// Returns whether the session that was led by a process started at
+/// `leader_start_time` has ended, given the start time of its leader now.
+#[inline]
+fn is_session_ended(start_time: Option<u64>, leader_start_time: u64) -> bool {
+    start_time != Some(leader_start_time)
+}
+
+/// Returns the start time of the leader of the session, see the Linux
+/// version. The start time is not checked on other platforms.
+#[cfg(not(target_os = "linux"))]
+fn session_leader_start_time(sid: u32) -> Option<u64> {
+    let session = unsafe { libc::getsid(sid as libc::pid_t) };
+    (session == sid as libc::pid_t).then_some(0)
+}
+
+/// The delay before a job is started again by its restart policy.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// How long to wait for the preconditions of a job by default.
+pub const DEFAULT_PRECONDITION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often to check the preconditions of a job until they hold.
+const PRECONDITION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The maximum number of revisions to keep for undoing.
+const MAX_REVISIONS: usize = 32;
+
+/// The bounds of how often to check whether a process is idle.
+const MIN_IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often to check whether the sessions of bound processes have ended.
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
 struct Inner {
     proc_mgr_handle: ProcessManagerHandle,
     jobs: RwLock<IndexMap<Id, Job>>,
     pid_index: RwLock<HashMap<u32, Id>>,
+    /// Ids of the jobs in each namespace, in the order they were added.
+    namespace_index: RwLock<HashMap<String, Vec<Id>>>,
+    /// Revisions of the jobs, from the oldest to the latest.
+    revisions: RwLock<VecDeque<JobRevision>>,
+    revision_seed: AtomicU64,
+    scheduled_stops: Mutex<HashMap<StopTarget, ScheduledStop>>,
+    scheduled_restarts: Mutex<HashMap<Id, ScheduledRestart>>,
+    /// What the jobs waiting for their preconditions are waiting for.
+    waiting: Mutex<HashMap<Id, String>>,
+    /// Tasks that stop the targets when their login sessions end.
+    session_bindings: Mutex<HashMap<StopTarget, task::JoinHandle<()>>>,
+    event_handlers: SubscriberList<Box<dyn EventHandler>>,
+    restarts: AtomicU64,
+    pending_events: AtomicU64,
+    /// Notified whenever an exited process is detached from its job.
+    detached: Notify,
+    history: JobHistory,
     _cancellation_token: CancellationToken<Box<dyn process_mgr::EventHandler>>,
 }
 
 impl JobDescription {
+    pub fn builder(start_info: StartInfo) -> JobDescriptionBuilder {
+        JobDescriptionBuilder {
+            desc: JobDescription {
+                start_info,
+                restart: RestartPolicy::default(),
+                name: None,
+                namespace: DEFAULT_NAMESPACE.to_owned(),
+                template: false,
+                autostart: true,
+                labels: BTreeMap::new(),
+                depends_on: vec![],
+                preconditions: vec![],
+                precondition_timeout: DEFAULT_PRECONDITION_TIMEOUT,
+                readiness: Readiness::Immediate,
+                idle: None,
+                memory_watchdog: None,
+                stop_priority: 0,
+                env_file: None,
+            },
+        }
+    }
+
+    /// Returns the path of the env file, resolved against the working
+    /// directory.
+    pub fn env_file_path(&self) -> Option<PathBuf> {
+        let env_file = self.env_file.as_ref()?;
+        Some(Path::new(&self.start_info.cwd).join(env_file))
+    }
+
+    /// Reads the variables of the env file, if any.
+    pub fn file_env(&self) -> Result<Vec<(String, String)>> {
+        match self.env_file_path() {
+            Some(path) => env_file::load(&path),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Returns the start info to spawn the process of the job with, which
+    /// has the variables of the env file.
+    fn spawn_start_info(&self) -> Result<StartInfo> {
+        let mut start_info = self.start_info.clone();
+        start_info.env.extend(os_env(self.file_env()?));
+        Ok(start_info)
+    }
+
+    /// Fills in the variable of the log file name template, if any.
+    fn bind_log_name(&mut self, name: &str, value: &str) {
+        if let Some(log_name) = self.start_info.log.name.take() {
+            self.start_info.log.name = Some(log_name.bind(name, value));
+        }
+    }
+
     fn digest(&self, seed: u64) -> String {
         let mut hasher = Sha1::new();
 
         hasher.update(seed.to_be_bytes());
-        hasher.update(self.start_info.program.as_bytes());
-        hasher.update(b"(");
-        if let Some(args) = &self.start_info.args {
-            for arg in args {
-                hasher.update(arg.as_bytes());
-                hasher.update(b",");
-            }
+        // Strings are prefixed by their lengths, so arguments like `a,b`
+        // and `a` `b` are told apart.
+        let argv = self.start_info.argv();
+        hasher.update((argv.len() as u64).to_be_bytes());
+        for arg in &argv {
+            update_str(&mut hasher, arg);
         }
-        hasher.update(b")");
-        hasher.update(self.start_info.cwd.as_bytes());
-        hasher.update(b"{");
-        for env in self.start_info.env.iter() {
-            hasher.update(env.0.as_bytes());
+        update_str(&mut hasher, &self.start_info.cwd);
+        let mut env: Vec<_> = self.start_info.env.iter().collect();
+        env.sort();
+        hasher.update((env.len() as u64).to_be_bytes());
+        for (name, value) in env {
+            update_str(&mut hasher, name.as_bytes());
+            update_str(&mut hasher, value.as_bytes());
+        }
+        let log = &self.start_info.log;
+        if let Some(log_path) = &log.path {
+            hasher.update(log_path.as_os_str().as_bytes());
+        }
+        if let Some(log_name) = &log.name {
+            hasher.update(log_name.to_string().as_bytes());
+        }
+        hasher.update([log.append as u8, log.rotation.cadence as u8]);
+        if let Some(max_size) = log.rotation.max_size {
+            hasher.update(max_size.to_be_bytes());
+        }
+        if let Some(sampling) = &log.sampling {
+            update_str(&mut hasher, sampling.to_string());
+        }
+        if let Some(cpus) = &self.start_info.cpus {
+            hasher.update(cpus.to_string().as_bytes());
+        }
+        let sandbox = &self.start_info.sandbox;
+        hasher.update([
+            sandbox.no_new_privs as u8,
+            sandbox.private_tmp as u8,
+            sandbox.isolate_network as u8,
+            sandbox.read_only_root as u8,
+            sandbox.bind_cwd as u8,
+        ]);
+        if let Some(rootfs) = &sandbox.rootfs {
+            hasher.update(rootfs.as_os_str().as_bytes());
+        }
+        if let Runtime::Container(container) = &self.start_info.runtime {
+            hasher.update(container.engine.as_bytes());
             hasher.update(b":");
-            hasher.update(env.1.as_bytes());
+            hasher.update(container.image.as_bytes());
+        }
+        hasher.update([self.start_info.petri_env as u8]);
+        if let Some(size) = self.start_info.read_buffer_size {
+            hasher.update(size.to_be_bytes());
+        }
+        hasher.update([self.restart.mode as u8]);
+        for code in &self.restart.clean_exit_codes {
+            hasher.update(code.to_be_bytes());
+        }
+        if let Some(jitter) = &self.restart.jitter {
+            hasher.update(b"~");
+            hasher.update(jitter.min.as_millis().to_be_bytes());
+            hasher.update(jitter.max.as_millis().to_be_bytes());
+        }
+        for window in &self.restart.windows {
+            hasher.update(window.to_string().as_bytes());
+            hasher.update(b";");
+        }
+        if let Some(name) = &self.name {
+            hasher.update(name.as_bytes());
+        }
+        hasher.update(self.namespace.as_bytes());
+        hasher.update([self.template as u8, self.autostart as u8]);
+        hasher.update(b"{");
+        for (key, value) in &self.labels {
+            hasher.update(key.as_bytes());
+            hasher.update(b"=");
+            hasher.update(value.as_bytes());
             hasher.update(b",");
         }
-        hasher.update(b"}");
-        if let Some(log_path) = &self.start_info.log_path {
-            hasher.update(log_path.as_os_str().as_bytes());
+        hasher.update(b"}(");
+        for dep in &self.depends_on {
+            hasher.update(dep.as_bytes());
+            hasher.update(b",");
+        }
+        hasher.update(b")");
+        if !self.preconditions.is_empty() {
+            for precondition in &self.preconditions {
+                update_str(&mut hasher, precondition.to_string());
+            }
+            hasher.update(self.precondition_timeout.as_millis().to_be_bytes());
+        }
+        match self.readiness {
+            Readiness::Immediate => {}
+            Readiness::Delay(delay) => hasher.update(delay.as_millis().to_be_bytes()),
+            Readiness::Notify => hasher.update(b"notify"),
+        }
+        if let Some(idle) = &self.idle {
+            hasher.update(idle.timeout.as_millis().to_be_bytes());
+            hasher.update([idle.activity as u8]);
+        }
+        if let Some(watchdog) = &self.memory_watchdog {
+            hasher.update(watchdog.max_rss_bytes.to_be_bytes());
+            hasher.update(watchdog.samples.to_be_bytes());
+            hasher.update(watchdog.interval.as_millis().to_be_bytes());
+            hasher.update([watchdog.action as u8]);
+        }
+        hasher.update(self.stop_priority.to_be_bytes());
+        if let Some(env_file) = &self.env_file {
+            hasher.update(env_file.as_os_str().as_bytes());
         }
-        hasher.update(&[self.auto_restart as u8]);
 
         let digest = hasher.finalize();
         digest.iter().fold(
@@ -90,140 +606,2186 @@ impl JobDescription {
     }
 }
 
-impl Job {
-    #[inline]
-    pub fn id(&self) -> &str {
-        &self.id
+impl JobDescriptionBuilder {
+    pub fn restart(mut self, restart: RestartPolicy) -> Self {
+        self.desc.restart = restart;
+        self
     }
 
-    #[inline]
-    pub fn description(&self) -> &JobDescription {
-        &self.desc
+    pub fn name(mut self, name: impl Into<Option<String>>) -> Self {
+        self.desc.name = name.into();
+        self
     }
 
-    #[inline]
-    pub fn created_at(&self) -> &DateTime<Local> {
-        &self.created_at
+    pub fn namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.desc.namespace = namespace.into();
+        self
     }
 
-    #[inline]
-    pub fn pid(&self) -> Option<u32> {
-        self.pid
+    pub fn template(mut self, template: bool) -> Self {
+        self.desc.template = template;
+        self
     }
 
-    #[inline]
-    pub fn last_exit_code(&self) -> Option<i32> {
-        self.last_exit_code
+    pub fn autostart(mut self, autostart: bool) -> Self {
+        self.desc.autostart = autostart;
+        self
     }
-}
 
-impl JobManager {
-    pub fn new(proc_mgr_handle: ProcessManagerHandle) -> Self {
-        let handle = Handle {
-            inner: Arc::new_cyclic(|me| {
-                let event_handler = ProcessManagerEventHandler {
-                    weak_ptr: me.clone(),
-                };
-                let token = proc_mgr_handle.add_event_handler(event_handler);
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.desc.labels.insert(key.into(), value.into());
+        self
+    }
 
-                Inner {
-                    proc_mgr_handle,
-                    jobs: Default::default(),
-                    pid_index: Default::default(),
-                    _cancellation_token: token,
-                }
-            }),
-        };
+    pub fn labels<I>(mut self, labels: I) -> Self
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        self.desc.labels.extend(labels);
+        self
+    }
 
-        Self { handle }
+    pub fn depends_on<I>(mut self, deps: I) -> Self
+    where
+        I: IntoIterator<Item = String>,
+    {
+        self.desc.depends_on.extend(deps);
+        self
     }
 
-    pub fn handle(&self) -> Handle {
-        self.handle.clone()
+    pub fn preconditions(mut self, preconditions: impl IntoIterator<Item = Precondition>) -> Self {
+        self.desc.preconditions.extend(preconditions);
+        self
+    }
+
+    pub fn precondition_timeout(mut self, timeout: Duration) -> Self {
+        self.desc.precondition_timeout = timeout;
+        self
+    }
+
+    pub fn readiness(mut self, readiness: Readiness) -> Self {
+        self.desc.readiness = readiness;
+        self
+    }
+
+    pub fn idle(mut self, idle: impl Into<Option<IdlePolicy>>) -> Self {
+        self.desc.idle = idle.into();
+        self
+    }
+
+    pub fn memory_watchdog(mut self, watchdog: impl Into<Option<MemoryWatchdog>>) -> Self {
+        self.desc.memory_watchdog = watchdog.into();
+        self
+    }
+
+    pub fn stop_priority(mut self, stop_priority: i32) -> Self {
+        self.desc.stop_priority = stop_priority;
+        self
+    }
+
+    pub fn env_file(mut self, env_file: impl Into<Option<PathBuf>>) -> Self {
+        self.desc.env_file = env_file.into();
+        self
+    }
+
+    pub fn build(self) -> JobDescription {
+        self.desc
     }
 }
 
-impl Handle {
-    pub async fn jobs(&self) -> Vec<Job> {
-        let jobs = self.inner.jobs.read().await;
-        jobs.values().cloned().collect()
+impl Readiness {
+    /// Waits until the process becomes ready, or fails if it exits before
+    /// that.
+    pub async fn wait(&self, process: &Process) -> Result<()> {
+        match *self {
+            Readiness::Immediate => Ok(()),
+            Readiness::Delay(delay) => tokio::select! {
+                exit_code = process.wait() => Err(anyhow!(
+                    "process exited with code {exit_code} before becoming ready"
+                )),
+                _ = tokio::time::sleep(delay) => Ok(()),
+            },
+            Readiness::Notify => tokio::select! {
+                exit_code = process.wait() => Err(anyhow!(
+                    "process exited with code {exit_code} before notifying readiness"
+                )),
+                _ = process.wait_ready() => Ok(()),
+            },
+        }
     }
+}
 
-    pub async fn add_job(&self, job: JobDescription) -> Result<String> {
-        let now_ts = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("current system date is invalid")
-            .as_millis() as u64;
-        let digest = job.digest(now_ts);
-        let job_id = Id::from(&digest);
+impl IdlePolicy {
+    /// Returns when the process was last active, given when it last wrote
+    /// output and when its CPU time last increased.
+    fn last_active_at(&self, output_at: Instant, cpu_at: Instant) -> Instant {
+        match self.activity {
+            IdleActivity::Output => output_at,
+            IdleActivity::Cpu => cpu_at,
+            IdleActivity::Any => output_at.max(cpu_at),
+        }
+    }
 
-        let mut jobs = self.inner.jobs.write().await;
-        if jobs.contains_key(digest.as_str()) {
-            return Err(anyhow!("job id has been already used"));
+    /// How often to check the process, a fraction of the timeout so it's
+    /// stopped soon after becoming idle.
+    fn check_interval(&self) -> Duration {
+        (self.timeout / 4).clamp(MIN_IDLE_CHECK_INTERVAL, MAX_IDLE_CHECK_INTERVAL)
+    }
+}
+
+impl MemoryWatchdog {
+    /// Counts the sample into the streak of samples above the ceiling,
+    /// returning whether the streak is long enough to act on.
+    fn observe(&self, streak: &mut u32, rss_bytes: u64) -> bool {
+        if rss_bytes <= self.max_rss_bytes {
+            *streak = 0;
+            return false;
         }
-        jobs.insert(
-            job_id.clone(),
-            Job {
-                id: job_id.clone(),
-                desc: job,
-                created_at: Local::now(),
-                pid: None,
-                last_exit_code: None,
+        *streak += 1;
+        *streak >= self.samples.max(1)
+    }
+}
+
+impl WatchdogAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WatchdogAction::Restart => "restart",
+            WatchdogAction::Stop => "stop",
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// Returns whether the job should be started again after its process
+    /// exited. `stop_requested` is whether the job was stopped as a job.
+    pub fn should_restart(
+        &self,
+        exit_code: i32,
+        exit_reason: ExitReason,
+        stop_requested: bool,
+    ) -> bool {
+        // The job is started again by restoring the checkpoint, and would
+        // only exceed its log quota again.
+        if stop_requested || matches!(exit_reason, ExitReason::Checkpointed | ExitReason::LogQuota)
+        {
+            return false;
+        }
+        match self.mode {
+            RestartMode::Never => false,
+            RestartMode::OnFailure => match exit_reason {
+                ExitReason::Normal => exit_code != 0 && !self.clean_exit_codes.contains(&exit_code),
+                ExitReason::Oom => true,
+                ExitReason::Stopped
+                | ExitReason::Idle
+                | ExitReason::MemoryWatchdog
+                | ExitReason::Checkpointed
+                | ExitReason::LogQuota => false,
             },
-        );
+            RestartMode::Always => true,
+            RestartMode::UnlessStopped => !matches!(
+                exit_reason,
+                ExitReason::Stopped | ExitReason::Idle | ExitReason::MemoryWatchdog
+            ),
+        }
+    }
 
-        Ok(digest)
+    /// Returns when to restart the job whose process exited at `now`, given
+    /// a jitter sampled by [`RestartJitter::sample`]. The jitter is added
+    /// again when a window opens, so the jobs waiting for it are spread too.
+    fn restart_at(&self, now: DateTime<FixedOffset>, jitter: Duration) -> DateTime<FixedOffset> {
+        let at = now + RESTART_DELAY + jitter;
+        if self.windows.is_empty() || self.windows.iter().any(|window| window.contains(at)) {
+            return at;
+        }
+        let opens_at = self
+            .windows
+            .iter()
+            .map(|window| window.next_start(at))
+            .min()
+            .expect("windows should not be empty");
+        opens_at + jitter
     }
+}
 
-    pub async fn start_job(&self, jid: &str) -> Result<u32> {
-        let mut jobs = self.inner.jobs.write().await;
-        let mut pid_index = self.inner.pid_index.write().await;
+impl RestartJitter {
+    /// The longest jitter, so restarts are not put off indefinitely.
+    pub const MAX: Duration = Duration::from_secs(24 * 60 * 60);
 
-        let Some(job) = jobs.get_mut(jid) else {
-            return Err(anyhow!("job with id `{jid}` is not found"));
+    /// Returns a random delay in the range.
+    fn sample(&self) -> Duration {
+        let max = self.max.as_millis() as u64;
+        let min = (self.min.as_millis() as u64).min(max);
+        Duration::from_millis(fastrand::u64(min..=max))
+    }
+}
+
+impl FromStr for RestartJitter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (min, max) = s.split_once("..").unwrap_or(("0s", s));
+        let parse = |s| parse_duration(s).map_err(|err| anyhow!("invalid jitter `{s}`: {err}"));
+        let jitter = Self {
+            min: parse(min)?,
+            max: parse(max)?,
         };
+        if jitter.min > jitter.max {
+            return Err(anyhow!("jitter `{s}` ends before it starts"));
+        }
+        if jitter.max > Self::MAX {
+            return Err(anyhow!("jitter `{s}` is longer than a day"));
+        }
+        Ok(jitter)
+    }
+}
 
-        if job.pid.is_some() {
-            return Err(anyhow!("job is already started"));
+impl Display for RestartJitter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if !self.min.is_zero() {
+            write!(f, "{}..", FormattedDuration(self.min))?;
         }
+        write!(f, "{}", FormattedDuration(self.max))
+    }
+}
 
-        let pid = self
-            .inner
-            .proc_mgr_handle
-            .add_process(&job.desc.start_info)
-            .await?;
-        job.pid = Some(pid);
-        pid_index.insert(pid, job.id.clone());
+impl RestartWindow {
+    /// Returns whether the time of day of `at` is in the window.
+    fn contains(&self, at: DateTime<FixedOffset>) -> bool {
+        let time = at.time();
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
 
-        Ok(pid)
+    /// Returns when the window opens next after `at`.
+    fn next_start(&self, at: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+        let mut start = at.date_naive().and_time(self.start);
+        if start <= at.naive_local() {
+            start += chrono::Duration::days(1);
+        }
+        start
+            .and_local_timezone(*at.offset())
+            .single()
+            .expect("fixed offsets should be unambiguous")
     }
+}
 
-    async fn handle_process_exit(&self, pid: u32, exit_code: i32) {
-        let mut jobs = self.inner.jobs.write().await;
-        let mut pid_index = self.inner.pid_index.write().await;
+impl FromStr for RestartWindow {
+    type Err = anyhow::Error;
 
-        let Some(jid) = pid_index.get(&pid) else {
-            debug!("no matching job with pid: {pid}");
-            return;
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((start, end)) = s.split_once('-') else {
+            return Err(anyhow!("expected a window like `02:00-04:00`, got `{s}`"));
         };
+        let parse = |time: &str| {
+            ["%H:%M:%S", "%H:%M"]
+                .iter()
+                .find_map(|fmt| NaiveTime::parse_from_str(time.trim(), fmt).ok())
+                .ok_or_else(|| anyhow!("invalid time `{time}` in window `{s}`"))
+        };
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
 
-        let job = jobs.get_mut(jid).expect("internal state is inconsistent");
-        job.pid = None;
-        job.last_exit_code = Some(exit_code);
+impl Display for RestartWindow {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let fmt = |time: NaiveTime| {
+            let fmt = if time.second() == 0 {
+                "%H:%M"
+            } else {
+                "%H:%M:%S"
+            };
+            time.format(fmt)
+        };
+        write!(f, "{}-{}", fmt(self.start), fmt(self.end))
+    }
+}
 
-        pid_index.remove(&pid);
+/// Formats the duration so it's parsed back by [`parse_duration`].
+struct FormattedDuration(Duration);
+
+impl Display for FormattedDuration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.0.subsec_millis() == 0 {
+            write!(f, "{}s", self.0.as_secs())
+        } else {
+            write!(f, "{}ms", self.0.as_millis())
+        }
     }
 }
 
-impl process_mgr::EventHandler for ProcessManagerEventHandler {
-    fn handle_process_exit(&self, pid: u32, exit_code: i32) {
-        let Some(strong_ptr) = self.weak_ptr.upgrade() else {
-            return;
-        };
-        task::spawn(async move {
-            (Handle { inner: strong_ptr })
-                .handle_process_exit(pid, exit_code)
-                .await
-        });
+impl JobOverrides {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.env.is_empty() && self.extra_args.is_empty()
+    }
+
+    /// Makes the description of an instance from the template.
+    fn apply(&self, template: &JobDescription) -> JobDescription {
+        let mut desc = template.clone();
+        desc.name = None;
+        desc.template = false;
+        desc.autostart = false;
+
+        let start_info = &mut desc.start_info;
+        start_info.env.extend(os_env(self.env.iter().cloned()));
+        if !self.extra_args.is_empty() {
+            start_info
+                .args
+                .get_or_insert_with(Vec::new)
+                .extend(self.extra_args.iter().cloned());
+        }
+
+        desc
+    }
+}
+
+impl Job {
+    #[inline]
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the short form of the id, which is shown to users and
+    /// accepted by [`Handle::resolve_job_id`] too.
+    #[inline]
+    pub fn short_id(&self) -> &str {
+        &self.short_id
+    }
+
+    /// Returns the id of the template if the job is an instance of it.
+    #[inline]
+    pub fn template_id(&self) -> Option<&str> {
+        self.template_id.as_deref()
+    }
+
+    /// Returns the name of the job, or its short id if it has no name.
+    #[inline]
+    pub fn display_name(&self) -> &str {
+        self.desc.name.as_deref().unwrap_or(&self.short_id)
+    }
+
+    /// Returns whether the job has all the labels in the selector.
+    pub fn matches_labels(&self, selector: &[(String, String)]) -> bool {
+        selector
+            .iter()
+            .all(|(key, value)| self.desc.labels.get(key) == Some(value))
+    }
+
+    #[inline]
+    pub fn description(&self) -> &JobDescription {
+        &self.desc
+    }
+
+    #[inline]
+    pub fn created_at(&self) -> &DateTime<Local> {
+        &self.created_at
+    }
+
+    #[inline]
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+
+    #[inline]
+    pub fn last_exit_code(&self) -> Option<i32> {
+        self.last_exit_code
+    }
+
+    #[inline]
+    pub fn last_exit_reason(&self) -> Option<ExitReason> {
+        self.last_exit_reason
+    }
+
+    #[inline]
+    pub fn exited_at(&self) -> Option<&DateTime<Local>> {
+        self.exited_at.as_ref()
+    }
+
+    /// Returns whether the job exited longer than `retention` ago and is
+    /// not meant to be started again. Templates and autostart jobs are
+    /// never expired.
+    fn is_expired(&self, now: &DateTime<Local>, retention: Duration) -> bool {
+        if self.pid.is_some() || self.desc.template || self.desc.autostart {
+            return false;
+        }
+        self.exited_at
+            .and_then(|exited_at| (*now - exited_at).to_std().ok())
+            .is_some_and(|age| age >= retention)
+    }
+
+    /// Returns the log files of all the processes the job has run on the
+    /// disk, including the rotated ones.
+    ///
+    /// Unless the template of the log file names includes `{job}` or
+    /// `{instance}`, files of other jobs with the same program and log
+    /// directory are included too.
+    pub fn log_files(&self) -> io::Result<Vec<PathBuf>> {
+        match self.log_location() {
+            Some((log_path, template)) => template.existing_paths(&log_path),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Returns the directory and the template of the log files, so they
+    /// can be listed later without the job.
+    fn log_location(&self) -> Option<(PathBuf, FileNameTemplate)> {
+        let start_info = &self.desc.start_info;
+        let log_path = start_info.log.path.clone()?;
+        Some((log_path, start_info.log_name_template()))
+    }
+}
+
+impl JobRevision {
+    /// Returns the id of the job that was changed.
+    #[inline]
+    pub fn jid(&self) -> &str {
+        &self.jid
+    }
+
+    /// Returns the start info of the job before the change.
+    #[inline]
+    pub fn before(&self) -> &StartInfo {
+        &self.before
+    }
+
+    #[inline]
+    pub fn created_at(&self) -> &DateTime<Local> {
+        &self.created_at
+    }
+}
+
+impl JobManager {
+    pub fn new(proc_mgr_handle: ProcessManagerHandle) -> Self {
+        Self::with_history(
+            proc_mgr_handle,
+            JobHistory::in_memory(job_history::DEFAULT_CAPACITY),
+        )
+    }
+
+    /// Creates the job manager recording the starts and exits of jobs to
+    /// the history.
+    pub fn with_history(proc_mgr_handle: ProcessManagerHandle, history: JobHistory) -> Self {
+        let handle = Handle {
+            inner: Arc::new_cyclic(|me| {
+                let event_handler = ProcessManagerEventHandler {
+                    weak_ptr: me.clone(),
+                };
+                let token = proc_mgr_handle.add_event_handler(event_handler);
+
+                Inner {
+                    proc_mgr_handle,
+                    jobs: Default::default(),
+                    pid_index: Default::default(),
+                    namespace_index: Default::default(),
+                    revisions: Default::default(),
+                    revision_seed: Default::default(),
+                    scheduled_stops: Default::default(),
+                    scheduled_restarts: Default::default(),
+                    waiting: Default::default(),
+                    session_bindings: Default::default(),
+                    event_handlers: Default::default(),
+                    restarts: Default::default(),
+                    pending_events: Default::default(),
+                    detached: Notify::new(),
+                    history,
+                    _cancellation_token: token,
+                }
+            }),
+        };
+
+        Self { handle }
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+}
+
+impl Handle {
+    pub fn add_event_handler<H: EventHandler + 'static>(
+        &self,
+        handler: H,
+    ) -> CancellationToken<Box<dyn EventHandler>> {
+        self.inner.event_handlers.subscribe(Box::new(handler))
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            restarts: self.inner.restarts.load(AtomicOrdering::Relaxed),
+            pending_events: self.inner.pending_events.load(AtomicOrdering::Relaxed),
+            event_handlers: self.inner.event_handlers.len(),
+        }
+    }
+
+    pub async fn jobs(&self) -> Vec<Job> {
+        let jobs = self.inner.jobs.read().await;
+        jobs.values().cloned().collect()
+    }
+
+    /// Returns the jobs in the namespace, in the order they were added.
+    pub async fn jobs_in_namespace(&self, namespace: &str) -> Vec<Job> {
+        let jobs = self.inner.jobs.read().await;
+        let namespace_index = self.inner.namespace_index.read().await;
+        namespace_index
+            .get(namespace)
+            .into_iter()
+            .flatten()
+            .filter_map(|jid| jobs.get(jid).cloned())
+            .collect()
+    }
+
+    /// Returns the starts and exits of the job from the oldest, including
+    /// the ones spilled to disk.
+    pub async fn history(&self, jid: &str) -> io::Result<Vec<JobEvent>> {
+        self.inner.history.events(jid).await
+    }
+
+    pub async fn job_with_id(&self, jid: &str) -> Option<Job> {
+        let jobs = self.inner.jobs.read().await;
+        jobs.get(jid).cloned()
+    }
+
+    pub async fn add_job(&self, mut job: JobDescription) -> Result<String> {
+        job.start_info.validate()?;
+        job.file_env()?;
+        let mut jobs = self.inner.jobs.write().await;
+        if let Some(name) = &job.name {
+            if jobs.values().any(|j| j.desc.name.as_ref() == Some(name)) {
+                return Err(anyhow!("job name `{name}` has been already used"));
+            }
+        }
+        let jid = Self::new_job_id(&jobs, &job);
+        let name = job.name.clone().unwrap_or_else(|| jid.to_string());
+        job.bind_log_name("job", &name);
+        // Instances of templates are bound when they are started.
+        if !job.template {
+            job.bind_log_name("instance", &jid);
+        }
+        let mut namespace_index = self.inner.namespace_index.write().await;
+        Self::insert_job(&mut jobs, &mut namespace_index, jid.clone(), job, None);
+        Ok(jid.to_string())
+    }
+
+    /// Replaces the description of the job. A running process is not
+    /// affected, the new description applies from the next start.
+    pub async fn update_job(&self, jid: &str, mut desc: JobDescription) -> Result<()> {
+        desc.start_info.validate()?;
+        desc.file_env()?;
+        let mut jobs = self.inner.jobs.write().await;
+        if let Some(name) = &desc.name {
+            if jobs
+                .values()
+                .any(|j| &*j.id != jid && j.desc.name.as_ref() == Some(name))
+            {
+                return Err(anyhow!("job name `{name}` has been already used"));
+            }
+        }
+        let Some(job) = jobs.get_mut(jid) else {
+            return Err(anyhow!("job with id `{jid}` is not found"));
+        };
+        if job.desc.template != desc.template {
+            return Err(anyhow!(
+                "jobs and templates cannot be turned into each other"
+            ));
+        }
+
+        let name = desc.name.clone().unwrap_or_else(|| jid.to_owned());
+        desc.bind_log_name("job", &name);
+        if !desc.template {
+            desc.bind_log_name("instance", jid);
+        }
+        if job.desc.namespace != desc.namespace {
+            let mut namespace_index = self.inner.namespace_index.write().await;
+            Self::unindex_namespace(&mut namespace_index, &job.desc.namespace, &job.id);
+            namespace_index
+                .entry(desc.namespace.clone())
+                .or_default()
+                .push(job.id.clone());
+        }
+        job.desc = desc;
+        Ok(())
+    }
+
+    /// Finds the id of a job by its full or short id, name or a unique
+    /// prefix of the ids.
+    pub async fn resolve_job_id(&self, query: &str) -> Result<String> {
+        let jobs = self.inner.jobs.read().await;
+        Self::resolve_job_id_in(&jobs, query)
+    }
+
+    /// Returns the given jobs and all their dependencies, ordered so that
+    /// every job comes after the jobs it depends on.
+    pub async fn startup_order(&self, jids: &[String]) -> Result<Vec<String>> {
+        let jobs = self.inner.jobs.read().await;
+        dependency_order(jids, |jid| {
+            let Some(job) = jobs.get(jid) else {
+                return Err(anyhow!("job with id `{jid}` is not found"));
+            };
+            job.desc
+                .depends_on
+                .iter()
+                .map(|dep| {
+                    Self::resolve_job_id_in(&jobs, dep).map_err(|err| {
+                        err.context(format!(
+                            "failed to resolve dependency of `{}`",
+                            job.display_name()
+                        ))
+                    })
+                })
+                .collect()
+        })
+    }
+
+    /// Returns the running jobs in waves to stop them when the server shuts
+    /// down. Jobs are stopped after the jobs depending on them, and then by
+    /// their stop priorities. Dependencies that can't be resolved are
+    /// ignored.
+    pub async fn shutdown_waves(&self) -> Vec<Vec<String>> {
+        let jobs = self.inner.jobs.read().await;
+        let graph: Vec<_> = jobs
+            .values()
+            .map(|job| {
+                let deps = job
+                    .desc
+                    .depends_on
+                    .iter()
+                    .filter_map(|dep| Self::resolve_job_id_in(&jobs, dep).ok())
+                    .collect();
+                (job.id.to_string(), job.desc.stop_priority, deps)
+            })
+            .collect();
+        // Jobs that are not running are kept in the graph, so the order of
+        // the jobs depending on each other through them is still honored.
+        stop_waves(&graph)
+            .into_iter()
+            .map(|wave| {
+                wave.into_iter()
+                    .filter(|jid| jobs.get(jid.as_str()).is_some_and(|j| j.pid.is_some()))
+                    .collect::<Vec<_>>()
+            })
+            .filter(|wave| !wave.is_empty())
+            .collect()
+    }
+
+    fn resolve_job_id_in(jobs: &IndexMap<Id, Job>, query: &str) -> Result<String> {
+        if jobs.contains_key(query) {
+            return Ok(query.to_owned());
+        }
+        if let Some(job) = jobs
+            .values()
+            .find(|j| j.desc.name.as_deref() == Some(query))
+        {
+            return Ok(job.id.to_string());
+        }
+        // Short ids are cut from digests, so they may collide.
+        let mut same_short_id = jobs.values().filter(|j| &*j.short_id == query);
+        match (same_short_id.next(), same_short_id.next()) {
+            (Some(job), None) => return Ok(job.id.to_string()),
+            (Some(_), Some(_)) => return Err(anyhow!("job id `{query}` is ambiguous")),
+            _ => {}
+        }
+
+        let mut candidates = jobs
+            .values()
+            .filter(|j| j.id.starts_with(query) || j.short_id.starts_with(query));
+        match (candidates.next(), candidates.next()) {
+            (Some(job), None) if !query.is_empty() => Ok(job.id.to_string()),
+            (Some(_), Some(_)) => Err(anyhow!("job id prefix `{query}` is ambiguous")),
+            _ => Err(anyhow!("job `{query}` is not found")),
+        }
+    }
+
+    /// Starts the process of the job once its preconditions hold and there
+    /// is a slot in the spawn queue.
+    pub async fn start_job(&self, jid: &str) -> Result<u32> {
+        self.wait_preconditions(jid).await?;
+        let permit = self.inner.proc_mgr_handle.queue_spawn().wait().await;
+        self.start_job_with_permit(jid, permit).await
+    }
+
+    /// Waits until the preconditions of the job hold, failing once they
+    /// don't for the timeout of the job. Call it before taking a slot in
+    /// the spawn queue, so waiting jobs don't hold slots.
+    pub async fn wait_preconditions(&self, jid: &str) -> Result<()> {
+        let Some(job) = self.job_with_id(jid).await else {
+            return Err(anyhow!("job with id `{jid}` is not found"));
+        };
+        let desc = &job.desc;
+        if desc.preconditions.is_empty() {
+            return Ok(());
+        }
+
+        let deadline = Instant::now() + desc.precondition_timeout;
+        let env = desc.start_info.env.clone();
+        let res = async {
+            let mut waiting = false;
+            for precondition in &desc.preconditions {
+                while !precondition.check(&desc.start_info.cwd, &env).await {
+                    {
+                        let mut waiting_jobs = self.inner.waiting.lock();
+                        // Removed by `stop_job`.
+                        if waiting && !waiting_jobs.contains_key(&job.id) {
+                            return Err(anyhow!(
+                                "stopped while waiting for {}",
+                                precondition.target()
+                            ));
+                        }
+                        waiting_jobs.insert(job.id.clone(), precondition.target());
+                        waiting = true;
+                    }
+                    if Instant::now() >= deadline {
+                        return Err(anyhow!(
+                            "timed out after {:?} waiting for {}",
+                            desc.precondition_timeout,
+                            precondition.target()
+                        ));
+                    }
+                    tokio::time::sleep(PRECONDITION_CHECK_INTERVAL).await;
+                }
+            }
+            Ok(())
+        }
+        .await;
+        self.inner.waiting.lock().remove(&job.id);
+        res
+    }
+
+    /// Returns what the job is waiting for before starting, like
+    /// `postgres:5432`, if it's waiting for its preconditions.
+    pub fn waiting_for(&self, jid: &str) -> Option<String> {
+        self.inner.waiting.lock().get(jid).cloned()
+    }
+
+    /// Starts the process of the job with a slot granted by the spawn
+    /// queue, so the jobs are not locked while waiting in the queue.
+    pub async fn start_job_with_permit(&self, jid: &str, permit: SpawnPermit) -> Result<u32> {
+        self.spawn_job(jid, None, permit).await
+    }
+
+    /// Starts the process of the job from its checkpoint, with the start
+    /// info made by [`checkpoint::restore_start_info`].
+    ///
+    /// [`checkpoint::restore_start_info`]: crate::checkpoint::restore_start_info
+    pub async fn restore_job_with_permit(
+        &self,
+        jid: &str,
+        start_info: &StartInfo,
+        permit: SpawnPermit,
+    ) -> Result<u32> {
+        self.spawn_job(jid, Some(start_info), permit).await
+    }
+
+    async fn spawn_job(
+        &self,
+        jid: &str,
+        restore: Option<&StartInfo>,
+        permit: SpawnPermit,
+    ) -> Result<u32> {
+        let mut jobs = self.inner.jobs.write().await;
+        let mut pid_index = self.inner.pid_index.write().await;
+
+        let Some(job) = jobs.get(jid) else {
+            return Err(anyhow!("job with id `{jid}` is not found"));
+        };
+
+        if job.desc.template {
+            return Err(anyhow!(
+                "job is a template, start an instance of it instead"
+            ));
+        }
+
+        if job.pid.is_some() {
+            return Err(anyhow!("job is already started"));
+        }
+
+        let start_info = match restore {
+            // The environment is restored from the checkpoint.
+            Some(start_info) => start_info.clone(),
+            None => Self::with_job_env(
+                &jobs,
+                &job.id,
+                job.desc.name.as_deref(),
+                job.template_id.as_ref(),
+                &job.desc.spawn_start_info()?,
+            ),
+        };
+        // Instances are the replicas of their template.
+        let group = job.template_id.as_ref().unwrap_or(&job.id).clone();
+        let pid = self
+            .inner
+            .proc_mgr_handle
+            .add_replica_with_permit(&start_info, &group, permit)
+            .await?;
+        let job = jobs.get_mut(jid).expect("job should still exist");
+        job.pid = Some(pid);
+        pid_index.insert(pid, job.id.clone());
+        // Started by hand before its restart.
+        self.cancel_scheduled_restart(jid);
+        let kind = if restore.is_some() {
+            JobEventKind::Restored
+        } else if job.last_exit_code.is_some() {
+            self.inner.restarts.fetch_add(1, AtomicOrdering::Relaxed);
+            JobEventKind::Restarted
+        } else {
+            JobEventKind::Started
+        };
+        self.inner.history.record(jid, JobEvent::now(pid, kind));
+
+        Ok(pid)
+    }
+
+    /// Waits until the started job becomes ready according to its
+    /// readiness condition.
+    pub async fn wait_job_ready(&self, jid: &str) -> Result<()> {
+        let Some(job) = self.job_with_id(jid).await else {
+            return Err(anyhow!("job with id `{jid}` is not found"));
+        };
+        let Some(pid) = job.pid else {
+            return Err(anyhow!("job is not running"));
+        };
+        let Some(process) = self.inner.proc_mgr_handle.process_with_id(pid).await else {
+            return Err(anyhow!("process exited before becoming ready"));
+        };
+
+        job.desc.readiness.wait(&process).await
+    }
+
+    /// Updates the start info of the job and attaches the given process to
+    /// it, returning the pid of the process previously attached.
+    ///
+    /// The previous process is detached from the job and left running, so
+    /// its exit will not affect the job anymore. The change is recorded as
+    /// a revision, which can be reverted by [`revert_job_process`].
+    ///
+    /// [`revert_job_process`]: Self::revert_job_process
+    pub async fn swap_job_process(
+        &self,
+        jid: &str,
+        start_info: StartInfo,
+        pid: u32,
+    ) -> Result<Option<u32>> {
+        let mut jobs = self.inner.jobs.write().await;
+        let mut pid_index = self.inner.pid_index.write().await;
+        let mut revisions = self.inner.revisions.write().await;
+
+        let Some(job) = jobs.get_mut(jid) else {
+            return Err(anyhow!("job with id `{jid}` is not found"));
+        };
+        if job.desc.template {
+            return Err(anyhow!("job is a template"));
+        }
+
+        let before = std::mem::replace(&mut job.desc.start_info, start_info);
+        if revisions.len() == MAX_REVISIONS {
+            revisions.pop_front();
+        }
+        revisions.push_back(JobRevision {
+            seq: self
+                .inner
+                .revision_seed
+                .fetch_add(1, AtomicOrdering::Relaxed),
+            jid: job.id.clone(),
+            before,
+            created_at: Local::now(),
+        });
+
+        Ok(self.attach_process(job, &mut pid_index, pid))
+    }
+
+    /// Returns the latest revision of the jobs.
+    pub async fn last_revision(&self) -> Option<JobRevision> {
+        let revisions = self.inner.revisions.read().await;
+        revisions.back().cloned()
+    }
+
+    /// Restores the start info of the job before the revision, and attaches
+    /// the given process to it like [`swap_job_process`] does.
+    ///
+    /// Only the latest revision can be reverted, and it's removed after
+    /// that, so reverting repeatedly goes back further.
+    ///
+    /// [`swap_job_process`]: Self::swap_job_process
+    pub async fn revert_job_process(
+        &self,
+        revision: &JobRevision,
+        pid: u32,
+    ) -> Result<Option<u32>> {
+        let mut jobs = self.inner.jobs.write().await;
+        let mut pid_index = self.inner.pid_index.write().await;
+        let mut revisions = self.inner.revisions.write().await;
+
+        if revisions.back().map(|r| r.seq) != Some(revision.seq) {
+            return Err(anyhow!("the job was changed again since the revision"));
+        }
+        let Some(job) = jobs.get_mut(&revision.jid) else {
+            return Err(anyhow!("job with id `{}` is not found", revision.jid()));
+        };
+
+        revisions.pop_back();
+        job.desc.start_info = revision.before.clone();
+        Ok(self.attach_process(job, &mut pid_index, pid))
+    }
+
+    fn attach_process(
+        &self,
+        job: &mut Job,
+        pid_index: &mut HashMap<u32, Id>,
+        pid: u32,
+    ) -> Option<u32> {
+        let old_pid = job.pid.replace(pid);
+        if let Some(old_pid) = old_pid {
+            pid_index.remove(&old_pid);
+        }
+        pid_index.insert(pid, job.id.clone());
+        self.inner
+            .history
+            .record(&job.id, JobEvent::now(pid, JobEventKind::Started));
+        old_pid
+    }
+
+    /// Stops the process of the job, returning `None` if it's not running.
+    /// It returns once the process is detached from the job, so the job
+    /// can be started again right away.
+    ///
+    /// The job is not restarted by its restart policy after that, and a
+    /// start waiting for its preconditions fails.
+    pub async fn stop_job(&self, jid: &str) -> Result<Option<i32>> {
+        let pid = {
+            let mut jobs = self.inner.jobs.write().await;
+            let Some(job) = jobs.get_mut(jid) else {
+                return Err(anyhow!("job with id `{jid}` is not found"));
+            };
+            let Some(pid) = job.pid else {
+                self.cancel_scheduled_restart(jid);
+                self.inner.waiting.lock().remove(jid);
+                return Ok(None);
+            };
+            job.stop_requested = true;
+            pid
+        };
+
+        let exit_code = self.inner.proc_mgr_handle.stop_process(pid).await?;
+        self.wait_detached(jid, pid).await;
+        Ok(Some(exit_code))
+    }
+
+    /// Waits until the exited process is detached from the job, when its
+    /// exit code is recorded.
+    pub async fn wait_detached(&self, jid: &str, pid: u32) {
+        loop {
+            let notified = self.inner.detached.notified();
+            let mut notified = pin!(notified);
+            notified.as_mut().enable();
+            let jobs = self.inner.jobs.read().await;
+            if jobs.get(jid).and_then(|job| job.pid) != Some(pid) {
+                return;
+            }
+            drop(jobs);
+            notified.await;
+        }
+    }
+
+    /// Stops the process at the deadline. If the process belongs to a job,
+    /// the job is stopped instead, even if its process restarts before
+    /// that. A previously scheduled stop is replaced.
+    pub async fn schedule_stop(&self, pid: u32, deadline: DateTime<Local>) -> Result<()> {
+        let target = self.stop_target(pid).await?;
+
+        let delay = (deadline - Local::now()).to_std().unwrap_or_default();
+        let handle = self.clone();
+        let target_clone = target.clone();
+        let task = DelayedTask::schedule(
+            move || {
+                task::spawn(async move { handle.run_scheduled_stop(target_clone).await });
+            },
+            delay,
+        );
+
+        let mut scheduled_stops = self.inner.scheduled_stops.lock();
+        if let Some(mut stop) = scheduled_stops.insert(target, ScheduledStop { deadline, task }) {
+            stop.task.cancel();
+        }
+        Ok(())
+    }
+
+    /// Cancels the stop scheduled for the process (or its job), returning
+    /// whether there was one.
+    pub async fn cancel_scheduled_stop(&self, pid: u32) -> bool {
+        let jid = self.inner.pid_index.read().await.get(&pid).cloned();
+        let mut scheduled_stops = self.inner.scheduled_stops.lock();
+        let stop = match jid {
+            Some(jid) => scheduled_stops.remove(&StopTarget::Job(jid)),
+            None => scheduled_stops.remove(&StopTarget::Process(pid)),
+        };
+        match stop {
+            Some(mut stop) => {
+                stop.task.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the deadline of the stop scheduled for the job, or for the
+    /// process if it doesn't belong to a job.
+    pub fn scheduled_stop(&self, pid: Option<u32>, jid: Option<&str>) -> Option<DateTime<Local>> {
+        let target = match (jid, pid) {
+            (Some(jid), _) => StopTarget::Job(Id::from(jid)),
+            (None, Some(pid)) => StopTarget::Process(pid),
+            (None, None) => return None,
+        };
+        let scheduled_stops = self.inner.scheduled_stops.lock();
+        scheduled_stops.get(&target).map(|stop| stop.deadline)
+    }
+
+    /// Returns when the job is restarted by its restart policy, if it's
+    /// waiting for that.
+    pub fn scheduled_restart(&self, jid: &str) -> Option<DateTime<Local>> {
+        let scheduled_restarts = self.inner.scheduled_restarts.lock();
+        scheduled_restarts.get(jid).map(|restart| restart.at)
+    }
+
+    /// Stops the process (or its job) when the login session with the id
+    /// ends, so it doesn't outlive the user that started it. A previous
+    /// binding is replaced.
+    pub async fn bind_to_session(&self, pid: u32, sid: u32) -> Result<()> {
+        let target = self.stop_target(pid).await?;
+        // Reading the process info may block.
+        let leader_start_time = task::spawn_blocking(move || session_leader_start_time(sid))
+            .await
+            .ok()
+            .flatten()
+            .ok_or_else(|| anyhow!("session with id `{sid}` is not found"))?;
+
+        let handle = self.clone();
+        let target_clone = target.clone();
+        let task = task::spawn(async move {
+            loop {
+                tokio::time::sleep(SESSION_CHECK_INTERVAL).await;
+                let start_time = task::spawn_blocking(move || session_leader_start_time(sid)).await;
+                // Keep the binding if the check itself failed.
+                if matches!(start_time, Ok(start_time) if is_session_ended(start_time, leader_start_time))
+                {
+                    break;
+                }
+            }
+            handle.run_session_stop(target_clone, sid).await;
+        });
+
+        let mut session_bindings = self.inner.session_bindings.lock();
+        if let Some(task) = session_bindings.insert(target, task) {
+            task.abort();
+        }
+        Ok(())
+    }
+
+    /// Returns what to stop for the process, its job if it belongs to one.
+    async fn stop_target(&self, pid: u32) -> Result<StopTarget> {
+        if let Some(jid) = self.inner.pid_index.read().await.get(&pid) {
+            return Ok(StopTarget::Job(jid.clone()));
+        }
+        if self
+            .inner
+            .proc_mgr_handle
+            .process_with_id(pid)
+            .await
+            .is_none()
+        {
+            return Err(anyhow!("process with id `{pid}` is not found"));
+        }
+        Ok(StopTarget::Process(pid))
+    }
+
+    async fn stop_target_now(&self, target: &StopTarget) -> Result<Option<i32>> {
+        match target {
+            StopTarget::Process(pid) => self
+                .inner
+                .proc_mgr_handle
+                .stop_process(*pid)
+                .await
+                .map(Some),
+            StopTarget::Job(jid) => self.stop_job(jid).await,
+        }
+    }
+
+    async fn run_session_stop(&self, target: StopTarget, sid: u32) {
+        self.inner.session_bindings.lock().remove(&target);
+        match self.stop_target_now(&target).await {
+            Ok(Some(exit_code)) => {
+                info!("{target} stopped with exit code {exit_code} as session {sid} ended")
+            }
+            Ok(None) => debug!("{target} is not running when session {sid} ended"),
+            Err(err) => warn!("failed to stop {target} as session {sid} ended: {err:?}"),
+        }
+    }
+
+    async fn run_scheduled_stop(&self, target: StopTarget) {
+        self.inner.scheduled_stops.lock().remove(&target);
+        let res = self.stop_target_now(&target).await;
+        match res {
+            Ok(Some(exit_code)) => {
+                info!("{target} stopped as scheduled with exit code {exit_code}")
+            }
+            Ok(None) => debug!("{target} is not running at the scheduled stop"),
+            Err(err) => warn!("failed to stop {target} as scheduled: {err:?}"),
+        }
+    }
+
+    /// Creates an instance of the template with the overrides applied and
+    /// starts it, returning the instance job id and its pid.
+    ///
+    /// The instance is a standalone job referencing the template, so it
+    /// has its own history while the template stays unchanged.
+    pub async fn start_instance(
+        &self,
+        template_jid: &str,
+        overrides: &JobOverrides,
+    ) -> Result<(String, u32)> {
+        self.wait_preconditions(template_jid).await?;
+        let permit = self.inner.proc_mgr_handle.queue_spawn().wait().await;
+        self.start_instance_with_permit(template_jid, overrides, permit)
+            .await
+    }
+
+    /// Like [`start_instance`], but with a slot granted by the spawn queue.
+    ///
+    /// [`start_instance`]: Self::start_instance
+    pub async fn start_instance_with_permit(
+        &self,
+        template_jid: &str,
+        overrides: &JobOverrides,
+        permit: SpawnPermit,
+    ) -> Result<(String, u32)> {
+        let mut jobs = self.inner.jobs.write().await;
+        let mut pid_index = self.inner.pid_index.write().await;
+
+        let Some(template) = jobs.get(template_jid) else {
+            return Err(anyhow!("job with id `{template_jid}` is not found"));
+        };
+        if !template.desc.template {
+            return Err(anyhow!("job is not a template"));
+        }
+
+        let template_id = template.id.clone();
+        let mut desc = overrides.apply(&template.desc);
+        let jid = Self::new_job_id(&jobs, &desc);
+        desc.bind_log_name("instance", &jid);
+        let start_info = Self::with_job_env(
+            &jobs,
+            &jid,
+            None,
+            Some(&template_id),
+            &desc.spawn_start_info()?,
+        );
+        let pid = self
+            .inner
+            .proc_mgr_handle
+            .add_replica_with_permit(&start_info, &template_id, permit)
+            .await?;
+
+        let mut namespace_index = self.inner.namespace_index.write().await;
+        Self::insert_job(
+            &mut jobs,
+            &mut namespace_index,
+            jid.clone(),
+            desc,
+            Some(template_id),
+        );
+        jobs.get_mut(&jid).expect("job was just inserted").pid = Some(pid);
+        pid_index.insert(pid, jid.clone());
+        self.inner
+            .history
+            .record(&jid, JobEvent::now(pid, JobEventKind::Started));
+
+        Ok((jid.to_string(), pid))
+    }
+
+    /// Returns the start info to spawn a process of the job with outside
+    /// of the job manager (e.g. a new version of it), which tells the
+    /// process the job it runs for like the ones started as the job.
+    pub async fn spawn_info(&self, jid: &str, start_info: &StartInfo) -> Result<StartInfo> {
+        let jobs = self.inner.jobs.read().await;
+        let Some(job) = jobs.get(jid) else {
+            return Err(anyhow!("job with id `{jid}` is not found"));
+        };
+        let mut start_info = start_info.clone();
+        start_info.env.extend(os_env(job.desc.file_env()?));
+        Ok(Self::with_job_env(
+            &jobs,
+            &job.id,
+            job.desc.name.as_deref(),
+            job.template_id.as_ref(),
+            &start_info,
+        ))
+    }
+
+    /// Adds the job variables to the start info, unless it opts out.
+    fn with_job_env(
+        jobs: &IndexMap<Id, Job>,
+        jid: &Id,
+        name: Option<&str>,
+        template_id: Option<&Id>,
+        start_info: &StartInfo,
+    ) -> StartInfo {
+        let mut start_info = start_info.clone();
+        if !start_info.petri_env {
+            return start_info;
+        }
+
+        let (job_id, job_name) = match template_id.and_then(|id| jobs.get(id)) {
+            Some(template) => (&*template.id, template.display_name()),
+            None => (&**jid, name.unwrap_or(jid)),
+        };
+        start_info.env.extend([
+            (JID_ENV.into(), job_id.into()),
+            (JOB_NAME_ENV.into(), job_name.into()),
+            (INSTANCE_ID_ENV.into(), jid.to_string().into()),
+        ]);
+        start_info
+    }
+
+    fn new_job_id(jobs: &IndexMap<Id, Job>, desc: &JobDescription) -> Id {
+        let now_ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("current system date is invalid")
+            .as_millis() as u64;
+
+        // Identical descriptions can be added in the same millisecond (like
+        // instances started in a batch), so bump the seed until it's unique.
+        (now_ts..)
+            .map(|seed| Id::from(desc.digest(seed)))
+            .find(|jid| !jobs.contains_key(jid))
+            .expect("should find an unused job id")
+    }
+
+    fn insert_job(
+        jobs: &mut IndexMap<Id, Job>,
+        namespace_index: &mut HashMap<String, Vec<Id>>,
+        job_id: Id,
+        desc: JobDescription,
+        template_id: Option<Id>,
+    ) {
+        namespace_index
+            .entry(desc.namespace.clone())
+            .or_default()
+            .push(job_id.clone());
+        jobs.insert(
+            job_id.clone(),
+            Job {
+                id: job_id.clone(),
+                short_id: job_id.short().into(),
+                desc,
+                template_id,
+                created_at: Local::now(),
+                pid: None,
+                last_exit_code: None,
+                last_exit_reason: None,
+                exited_at: None,
+                stop_requested: false,
+            },
+        );
+    }
+
+    fn unindex_namespace(
+        namespace_index: &mut HashMap<String, Vec<Id>>,
+        namespace: &str,
+        jid: &Id,
+    ) {
+        if let Some(jids) = namespace_index.get_mut(namespace) {
+            jids.retain(|id| id != jid);
+            if jids.is_empty() {
+                namespace_index.remove(namespace);
+            }
+        }
+    }
+
+    async fn handle_process_start(&self, pid: u32) {
+        // Jobs hold the locks while starting processes, so the index is
+        // already updated when we get here.
+        let jid = self.inner.pid_index.read().await.get(&pid).cloned();
+
+        if let Some(jid) = &jid {
+            let jobs = self.inner.jobs.read().await;
+            if let Some(idle) = jobs.get(jid).and_then(|job| job.desc.idle) {
+                self.watch_idle(pid, jid.clone(), idle);
+            }
+            if let Some(watchdog) = jobs.get(jid).and_then(|job| job.desc.memory_watchdog) {
+                self.watch_memory(pid, jid.clone(), watchdog);
+            }
+        }
+
+        self.inner.event_handlers.for_each(|handler| {
+            handler.handle_process_start(pid, jid.as_deref());
+        });
+    }
+
+    async fn handle_log_rotated(&self, pid: u32, path: PathBuf) {
+        let jid = self.inner.pid_index.read().await.get(&pid).cloned();
+        self.inner.event_handlers.for_each(|handler| {
+            handler.handle_log_rotated(pid, jid.as_deref(), &path);
+        });
+    }
+
+    async fn handle_log_quota_exceeded(&self, pid: u32, usage: LogUsage) {
+        let jid = self.inner.pid_index.read().await.get(&pid).cloned();
+        self.inner.event_handlers.for_each(|handler| {
+            handler.handle_log_quota_exceeded(pid, jid.as_deref(), &usage);
+        });
+    }
+
+    /// Stops the job once the process stays idle for the timeout of the
+    /// policy. The watcher ends when the process exits.
+    fn watch_idle(&self, pid: u32, jid: Id, idle: IdlePolicy) {
+        let handle = self.clone();
+        task::spawn(async move {
+            let mut cpu_time = None;
+            let mut cpu_at = Instant::now();
+            // Paused processes are exempt, and the idle time restarts once
+            // they are resumed.
+            let mut paused_at = Instant::now();
+            loop {
+                tokio::time::sleep(idle.check_interval()).await;
+                let Some(process) = handle.inner.proc_mgr_handle.process_with_id(pid).await else {
+                    return;
+                };
+
+                let now = Instant::now();
+                if process.is_paused().await {
+                    paused_at = now;
+                    continue;
+                }
+                if idle.activity != IdleActivity::Output {
+                    // Reading the process info may block.
+                    let sample = task::spawn_blocking(move || metrics::sample_process(pid)).await;
+                    match sample {
+                        Ok(Ok(sample)) if cpu_time == Some(sample.cpu_time) => {}
+                        Ok(Ok(sample)) => {
+                            cpu_time = Some(sample.cpu_time);
+                            cpu_at = now;
+                        }
+                        // Consider the process active if it can't be sampled.
+                        _ => cpu_at = now,
+                    }
+                }
+                let last_active_at = idle
+                    .last_active_at(process.last_output_at(), cpu_at)
+                    .max(paused_at);
+                if now.saturating_duration_since(last_active_at) < idle.timeout {
+                    continue;
+                }
+
+                process.mark_idle();
+                match handle.stop_job(&jid).await {
+                    Ok(_) => info!(
+                        "job {} stopped after being idle for {:?}",
+                        &*jid, idle.timeout
+                    ),
+                    Err(err) => warn!("failed to stop idle job {}: {err:?}", &*jid),
+                }
+                return;
+            }
+        });
+    }
+
+    fn watch_memory(&self, pid: u32, jid: Id, watchdog: MemoryWatchdog) {
+        let handle = self.clone();
+        task::spawn(async move {
+            let mut streak = 0;
+            let rss_bytes = loop {
+                tokio::time::sleep(watchdog.interval).await;
+                let Some(process) = handle.inner.proc_mgr_handle.process_with_id(pid).await else {
+                    return;
+                };
+                // Skip the samples that fail, like ones of a process that
+                // is exiting. Reading the process info may block.
+                let sample = task::spawn_blocking(move || metrics::sample_process(pid)).await;
+                let Ok(Ok(sample)) = sample else {
+                    continue;
+                };
+                if watchdog.observe(&mut streak, sample.rss_bytes) {
+                    process.mark_memory_exceeded();
+                    break sample.rss_bytes;
+                }
+            };
+
+            warn!(
+                "job {} uses {} bytes of memory, over {} for {} samples ({} it)",
+                &*jid,
+                rss_bytes,
+                watchdog.max_rss_bytes,
+                watchdog.samples,
+                watchdog.action.as_str()
+            );
+            if let Err(err) = handle.stop_job(&jid).await {
+                warn!("failed to stop job {} for its memory: {err:?}", &*jid);
+                return;
+            }
+            if watchdog.action == WatchdogAction::Restart {
+                match handle.start_job(&jid).await {
+                    Ok(pid) => info!(
+                        "job {} restarted by the memory watchdog (pid: {pid})",
+                        &*jid
+                    ),
+                    Err(err) => warn!("failed to restart job {}: {err:?}", &*jid),
+                }
+            }
+        });
+    }
+
+    async fn handle_process_exit(&self, pid: u32, exit_code: i32, exit_reason: ExitReason) {
+        let (jid, restart) = match self
+            .detach_exited_process(pid, exit_code, exit_reason)
+            .await
+        {
+            Some((jid, restart)) => (Some(jid), restart),
+            None => (None, None),
+        };
+        if let (Some(jid), Some(restart)) = (&jid, restart) {
+            self.schedule_restart(jid.clone(), &restart);
+        }
+        if jid.is_none() {
+            let stop = self
+                .inner
+                .scheduled_stops
+                .lock()
+                .remove(&StopTarget::Process(pid));
+            if let Some(mut stop) = stop {
+                stop.task.cancel();
+            }
+            let binding = self
+                .inner
+                .session_bindings
+                .lock()
+                .remove(&StopTarget::Process(pid));
+            if let Some(task) = binding {
+                task.abort();
+            }
+        }
+
+        self.inner.event_handlers.for_each(|handler| {
+            handler.handle_process_exit(pid, jid.as_deref(), exit_code, exit_reason);
+        });
+    }
+
+    async fn detach_exited_process(
+        &self,
+        pid: u32,
+        exit_code: i32,
+        exit_reason: ExitReason,
+    ) -> Option<(Id, Option<RestartPolicy>)> {
+        let mut jobs = self.inner.jobs.write().await;
+        let mut pid_index = self.inner.pid_index.write().await;
+
+        let Some(jid) = pid_index.remove(&pid) else {
+            debug!("no matching job with pid: {pid}");
+            return None;
+        };
+
+        let job = jobs.get_mut(&jid).expect("internal state is inconsistent");
+        job.pid = None;
+        job.last_exit_code = Some(exit_code);
+        job.last_exit_reason = Some(exit_reason);
+        job.exited_at = Some(Local::now());
+        self.inner.history.record(
+            &jid,
+            JobEvent::now(
+                pid,
+                JobEventKind::Exited {
+                    exit_code,
+                    reason: exit_reason,
+                },
+            ),
+        );
+        self.inner.detached.notify_waiters();
+        let stop_requested = std::mem::take(&mut job.stop_requested);
+        let restart = !self.inner.proc_mgr_handle.is_shutting_down()
+            && job
+                .desc
+                .restart
+                .should_restart(exit_code, exit_reason, stop_requested);
+
+        Some((jid, restart.then(|| job.desc.restart.clone())))
+    }
+
+    /// Removes the jobs that exited longer than `retention` ago, along with
+    /// their log files, and unindexes the pids that jobs no longer run.
+    ///
+    /// Jobs that others depend on are kept, so are the log files that
+    /// still belong to other jobs or running processes. The history of
+    /// jobs on disk is pruned by its own retention.
+    pub async fn gc(&self, retention: Duration) -> GcReport {
+        let mut report = GcReport::default();
+        if let Err(err) = self.inner.history.prune().await {
+            warn!("failed to prune the job history: {err:?}");
+        }
+
+        let (removed, remaining_logs) = {
+            let mut jobs = self.inner.jobs.write().await;
+            let mut pid_index = self.inner.pid_index.write().await;
+
+            let indexed = pid_index.len();
+            pid_index.retain(|pid, jid| jobs.get(jid).is_some_and(|job| job.pid == Some(*pid)));
+            report.stale_pids = indexed - pid_index.len();
+
+            let now = Local::now();
+            let depended: HashSet<_> = jobs
+                .values()
+                .flat_map(|job| &job.desc.depends_on)
+                .filter_map(|dep| Self::resolve_job_id_in(&jobs, dep).ok())
+                .collect();
+            let expired: Vec<_> = jobs
+                .values()
+                .filter(|job| job.is_expired(&now, retention) && !depended.contains(&*job.id))
+                .map(|job| job.id.clone())
+                .collect();
+            let removed: Vec<_> = expired
+                .iter()
+                .filter_map(|jid| jobs.shift_remove(jid))
+                .collect();
+            let mut namespace_index = self.inner.namespace_index.write().await;
+            for job in &removed {
+                Self::unindex_namespace(&mut namespace_index, &job.desc.namespace, &job.id);
+            }
+
+            // The files are listed after the locks are released.
+            let remaining_logs: Vec<_> = jobs.values().filter_map(Job::log_location).collect();
+            (removed, remaining_logs)
+        };
+        if removed.is_empty() {
+            return report;
+        }
+
+        let removed_jids: HashSet<_> = removed.iter().map(|job| job.id.clone()).collect();
+        self.inner
+            .revisions
+            .write()
+            .await
+            .retain(|revision| !removed_jids.contains(&revision.jid));
+        for jid in &removed_jids {
+            let target = StopTarget::Job(jid.clone());
+            if let Some(mut stop) = self.inner.scheduled_stops.lock().remove(&target) {
+                stop.task.cancel();
+            }
+            self.cancel_scheduled_restart(jid);
+            if let Some(task) = self.inner.session_bindings.lock().remove(&target) {
+                task.abort();
+            }
+            self.inner.history.remove(jid);
+        }
+
+        report.jobs = removed
+            .iter()
+            .map(|job| job.display_name().to_owned())
+            .collect();
+        let processes = self.inner.proc_mgr_handle.processes().await;
+        let collected = task::spawn_blocking(move || {
+            Self::remove_orphaned_logs(&removed, &remaining_logs, &processes)
+        })
+        .await;
+        match collected {
+            Ok((log_files, log_bytes)) => {
+                report.log_files = log_files;
+                report.log_bytes = log_bytes;
+            }
+            Err(err) => warn!("failed to collect log files: {err}"),
+        }
+        report
+    }
+
+    /// Deletes the log files of the removed jobs, returning the number of
+    /// files deleted and their size.
+    ///
+    /// Log files are matched by templates, which may also match the files
+    /// of jobs and processes that are kept.
+    fn remove_orphaned_logs(
+        removed: &[Job],
+        remaining_logs: &[(PathBuf, FileNameTemplate)],
+        processes: &[Process],
+    ) -> (usize, u64) {
+        let mut orphaned = BTreeSet::new();
+        for job in removed {
+            match job.log_files() {
+                Ok(paths) => orphaned.extend(paths),
+                Err(err) => warn!(
+                    "failed to list log files of {}: {err:?}",
+                    job.display_name()
+                ),
+            }
+        }
+        for (log_path, template) in remaining_logs {
+            for path in template.existing_paths(log_path).unwrap_or_default() {
+                orphaned.remove(&path);
+            }
+        }
+        for process in processes {
+            for path in process.log_files().unwrap_or_default() {
+                orphaned.remove(&path);
+            }
+        }
+
+        let (mut log_files, mut log_bytes) = (0, 0);
+        for path in orphaned {
+            let size = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+            match fs::remove_file(&path) {
+                Ok(()) => {
+                    log_files += 1;
+                    log_bytes += size;
+                }
+                Err(err) => warn!("failed to delete log file {}: {err:?}", path.display()),
+            }
+        }
+        (log_files, log_bytes)
+    }
+
+    /// Starts the job again after a short delay, so a job that keeps
+    /// failing doesn't spin, plus the jitter of its restart policy, and
+    /// once one of its windows is open.
+    fn schedule_restart(&self, jid: Id, restart: &RestartPolicy) {
+        let now = Local::now();
+        let jitter = restart
+            .jitter
+            .map_or(Duration::ZERO, |jitter| jitter.sample());
+        let at = restart
+            .restart_at(in_zone(&now), jitter)
+            .with_timezone(&Local);
+        let delay = (at - now).to_std().unwrap_or_default();
+        // Only waiting for a window is worth noting.
+        if delay > RESTART_DELAY + jitter {
+            info!(
+                "job {} will be restarted at {}",
+                &*jid,
+                in_zone(&at).format("%Y-%m-%d %T")
+            );
+        }
+
+        let handle = self.clone();
+        let jid_clone = jid.clone();
+        let task = DelayedTask::schedule(
+            move || {
+                task::spawn(async move { handle.run_scheduled_restart(jid_clone).await });
+            },
+            delay,
+        );
+        let mut scheduled_restarts = self.inner.scheduled_restarts.lock();
+        if let Some(mut restart) = scheduled_restarts.insert(jid, ScheduledRestart { at, task }) {
+            restart.task.cancel();
+        }
+    }
+
+    async fn run_scheduled_restart(&self, jid: Id) {
+        self.inner.scheduled_restarts.lock().remove(&jid);
+        match self.start_job(&jid).await {
+            Ok(pid) => info!("job {} restarted (pid: {pid})", &*jid),
+            Err(err) => warn!("failed to restart job {}: {err:?}", &*jid),
+        }
+    }
+
+    /// Cancels the restart scheduled for the job, e.g. because it's
+    /// stopped or started by hand while waiting for it.
+    fn cancel_scheduled_restart(&self, jid: &str) {
+        let restart = self.inner.scheduled_restarts.lock().remove(jid);
+        if let Some(mut restart) = restart {
+            restart.task.cancel();
+        }
+    }
+}
+
+/// Starts a task that collects garbage of the job manager periodically,
+/// see [`Handle::gc`].
+pub fn spawn_collector(
+    handle: Handle,
+    interval: Duration,
+    retention: Duration,
+) -> task::JoinHandle<()> {
+    task::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let report = handle.gc(retention).await;
+            if !report.jobs.is_empty() || report.stale_pids > 0 {
+                info!(
+                    "collected {} jobs, {} log files ({} bytes) and {} stale pids",
+                    report.jobs.len(),
+                    report.log_files,
+                    report.log_bytes,
+                    report.stale_pids
+                );
+            }
+        }
+    })
+}
+
+/// Sorts the roots and their transitive dependencies topologically.
+fn dependency_order<F>(roots: &[String], mut deps_of: F) -> Result<Vec<String>>
+where
+    F: FnMut(&str) -> Result<Vec<String>>,
+{
+    enum Mark {
+        Visiting,
+        Visited,
+    }
+
+    fn visit<F>(
+        jid: &str,
+        deps_of: &mut F,
+        marks: &mut HashMap<String, Mark>,
+        order: &mut Vec<String>,
+    ) -> Result<()>
+    where
+        F: FnMut(&str) -> Result<Vec<String>>,
+    {
+        match marks.get(jid) {
+            Some(Mark::Visited) => return Ok(()),
+            Some(Mark::Visiting) => {
+                return Err(anyhow!("dependency cycle detected at job `{jid}`"));
+            }
+            None => {}
+        }
+
+        marks.insert(jid.to_owned(), Mark::Visiting);
+        for dep in deps_of(jid)? {
+            visit(&dep, deps_of, marks, order)?;
+        }
+        marks.insert(jid.to_owned(), Mark::Visited);
+        order.push(jid.to_owned());
+
+        Ok(())
+    }
+
+    let mut marks = HashMap::new();
+    let mut order = Vec::new();
+    for jid in roots {
+        visit(jid, &mut deps_of, &mut marks, &mut order)?;
+    }
+    Ok(order)
+}
+
+/// Groups the jobs, given as `(id, stop priority, dependencies)`, into
+/// waves to stop. Each wave has the jobs with the lowest priority among
+/// the ones that no remaining job depends on. Jobs in dependency cycles
+/// are ordered by their priorities only.
+fn stop_waves(graph: &[(String, i32, Vec<String>)]) -> Vec<Vec<String>> {
+    let mut remaining: Vec<_> = graph.iter().collect();
+    let mut waves = Vec::new();
+    while !remaining.is_empty() {
+        let is_depended = |jid: &String| remaining.iter().any(|(_, _, deps)| deps.contains(jid));
+        let mut ready: Vec<_> = remaining
+            .iter()
+            .filter(|(jid, _, _)| !is_depended(jid))
+            .copied()
+            .collect();
+        if ready.is_empty() {
+            ready.clone_from(&remaining);
+        }
+        let priority = ready
+            .iter()
+            .map(|(_, priority, _)| *priority)
+            .min()
+            .expect("there should be remaining jobs");
+        let wave: Vec<_> = ready
+            .into_iter()
+            .filter(|(_, p, _)| *p == priority)
+            .map(|(jid, _, _)| jid.clone())
+            .collect();
+        remaining.retain(|(jid, _, _)| !wave.contains(jid));
+        waves.push(wave);
+    }
+    waves
+}
+
+impl process_mgr::EventHandler for ProcessManagerEventHandler {
+    fn handle_process_start(&self, pid: u32) {
+        let Some(strong_ptr) = self.weak_ptr.upgrade() else {
+            return;
+        };
+        strong_ptr
+            .pending_events
+            .fetch_add(1, AtomicOrdering::Relaxed);
+        task::spawn(async move {
+            let handle = Handle { inner: strong_ptr };
+            handle.handle_process_start(pid).await;
+            handle
+                .inner
+                .pending_events
+                .fetch_sub(1, AtomicOrdering::Relaxed);
+        });
+    }
+
+    fn handle_process_exit(&self, pid: u32, exit_code: i32, exit_reason: ExitReason) {
+        let Some(strong_ptr) = self.weak_ptr.upgrade() else {
+            return;
+        };
+        strong_ptr
+            .pending_events
+            .fetch_add(1, AtomicOrdering::Relaxed);
+        task::spawn(async move {
+            let handle = Handle { inner: strong_ptr };
+            handle
+                .handle_process_exit(pid, exit_code, exit_reason)
+                .await;
+            handle
+                .inner
+                .pending_events
+                .fetch_sub(1, AtomicOrdering::Relaxed);
+        });
+    }
+
+    fn handle_log_rotated(&self, pid: u32, path: &Path) {
+        let Some(strong_ptr) = self.weak_ptr.upgrade() else {
+            return;
+        };
+        strong_ptr
+            .pending_events
+            .fetch_add(1, AtomicOrdering::Relaxed);
+        let path = path.to_owned();
+        task::spawn(async move {
+            let handle = Handle { inner: strong_ptr };
+            handle.handle_log_rotated(pid, path).await;
+            handle
+                .inner
+                .pending_events
+                .fetch_sub(1, AtomicOrdering::Relaxed);
+        });
+    }
+
+    fn handle_log_quota_exceeded(&self, pid: u32, usage: &LogUsage) {
+        let Some(strong_ptr) = self.weak_ptr.upgrade() else {
+            return;
+        };
+        strong_ptr
+            .pending_events
+            .fetch_add(1, AtomicOrdering::Relaxed);
+        let usage = *usage;
+        task::spawn(async move {
+            let handle = Handle { inner: strong_ptr };
+            handle.handle_log_quota_exceeded(pid, usage).await;
+            handle
+                .inner
+                .pending_events
+                .fetch_sub(1, AtomicOrdering::Relaxed);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use chrono::{DateTime, Local};
+    use indexmap::IndexMap;
+
+    use super::{
+        dependency_order, is_session_ended, stop_waves, Handle, IdleActivity, IdlePolicy, Job,
+        JobDescription, MemoryWatchdog, RestartJitter, RestartMode, RestartPolicy, RestartWindow,
+        WatchdogAction,
+    };
+    #[cfg(target_os = "linux")]
+    use super::{leader_start_time, ProcStat};
+    use crate::process::{ExitReason, StartInfo};
+
+    fn order(graph: &[(&str, &[&str])], roots: &[&str]) -> anyhow::Result<Vec<String>> {
+        let graph: HashMap<_, _> = graph.iter().cloned().collect();
+        let roots: Vec<_> = roots.iter().map(|r| r.to_string()).collect();
+        dependency_order(&roots, |jid| {
+            Ok(graph[jid].iter().map(|dep| dep.to_string()).collect())
+        })
+    }
+
+    #[test]
+    fn test_dependency_order() {
+        let graph: &[(&str, &[&str])] = &[
+            ("web", &["api", "db"]),
+            ("api", &["db", "cache"]),
+            ("db", &[]),
+            ("cache", &[]),
+        ];
+        assert_eq!(
+            order(graph, &["web"]).unwrap(),
+            ["db", "cache", "api", "web"]
+        );
+        assert_eq!(
+            order(graph, &["cache", "api"]).unwrap(),
+            ["cache", "db", "api"]
+        );
+    }
+
+    #[test]
+    fn test_dependency_cycle() {
+        let graph: &[(&str, &[&str])] = &[("a", &["b"]), ("b", &["c"]), ("c", &["a"])];
+        assert!(order(graph, &["a"]).is_err());
+    }
+
+    #[test]
+    fn test_stop_waves() {
+        let job = |jid: &str, priority, deps: &[&str]| {
+            let deps = deps.iter().map(|dep| dep.to_string()).collect();
+            (jid.to_owned(), priority, deps)
+        };
+        let graph = [
+            job("web", 0, &["api", "db"]),
+            job("api", 0, &["db"]),
+            job("db", -1, &[]),
+            job("proxy", 5, &[]),
+            job("cron", 0, &[]),
+        ];
+        assert_eq!(
+            stop_waves(&graph),
+            [vec!["web", "cron"], vec!["api"], vec!["db"], vec!["proxy"]]
+        );
+
+        // Dependents are stopped first regardless of the priorities.
+        let graph = [job("app", 10, &["cache"]), job("cache", 0, &[])];
+        assert_eq!(stop_waves(&graph), [["app"], ["cache"]]);
+
+        let graph = [job("a", 1, &["b"]), job("b", 0, &["a"])];
+        assert_eq!(stop_waves(&graph), [["b"], ["a"]]);
+    }
+
+    #[test]
+    fn test_resolve_job_id() {
+        let job = |id: &str, short_id: &str, name: Option<&str>| {
+            let start_info = StartInfo::builder("echo", "/").build().unwrap();
+            let mut desc = JobDescription::builder(start_info).build();
+            desc.name = name.map(str::to_owned);
+            Job {
+                id: id.into(),
+                short_id: short_id.into(),
+                desc,
+                template_id: None,
+                created_at: Local::now(),
+                pid: None,
+                last_exit_code: None,
+                last_exit_reason: None,
+                exited_at: None,
+                stop_requested: false,
+            }
+        };
+        let jobs: IndexMap<_, _> = [
+            job("aaaa1111", "k3", Some("web")),
+            job("bbbb2222", "k3", None),
+            job("cccc3333", "m7", None),
+        ]
+        .into_iter()
+        .map(|job| (job.id.clone(), job))
+        .collect();
+        let resolve = |query| Handle::resolve_job_id_in(&jobs, query);
+
+        assert_eq!(resolve("bbbb2222").unwrap(), "bbbb2222");
+        assert_eq!(resolve("web").unwrap(), "aaaa1111");
+        assert_eq!(resolve("m7").unwrap(), "cccc3333");
+        assert_eq!(resolve("cc").unwrap(), "cccc3333");
+        // A short id shared by two jobs doesn't pick either.
+        assert!(resolve("k3").unwrap_err().to_string().contains("ambiguous"));
+        assert!(resolve("zz").is_err());
+    }
+
+    #[test]
+    fn test_digest() {
+        let desc = |args: &[&str], env: &[(&str, &str)]| {
+            let start_info = StartInfo::builder("echo", "/")
+                .args(args.iter().copied())
+                .envs(
+                    env.iter()
+                        .map(|(name, value)| (name.to_string(), value.to_string())),
+                )
+                .build()
+                .unwrap();
+            JobDescription::builder(start_info).build()
+        };
+
+        let joined = desc(&["a,b"], &[]);
+        let split = desc(&["a", "b"], &[]);
+        assert_ne!(joined.digest(0), split.digest(0));
+        assert_ne!(
+            desc(&["a b"], &[]).digest(0),
+            desc(&["a", "b"], &[]).digest(0)
+        );
+
+        // The digest doesn't depend on the order of the variables.
+        let env = [("A", "1"), ("B", "2"), ("C", "3"), ("D", "4")];
+        let mut reversed = env;
+        reversed.reverse();
+        assert_eq!(desc(&[], &env).digest(0), desc(&[], &reversed).digest(0));
+        assert_ne!(desc(&[], &env).digest(0), desc(&[], &env).digest(1));
+    }
+
+    #[test]
+    fn test_memory_watchdog() {
+        let watchdog = MemoryWatchdog {
+            max_rss_bytes: 100,
+            samples: 3,
+            interval: Duration::from_secs(1),
+            action: WatchdogAction::Restart,
+        };
+        let mut streak = 0;
+        assert!(!watchdog.observe(&mut streak, 200));
+        assert!(!watchdog.observe(&mut streak, 200));
+        // A sample under the ceiling resets the streak.
+        assert!(!watchdog.observe(&mut streak, 100));
+        assert_eq!(streak, 0);
+        assert!(!watchdog.observe(&mut streak, 101));
+        assert!(!watchdog.observe(&mut streak, 101));
+        assert!(watchdog.observe(&mut streak, 101));
+    }
+
+    #[test]
+    fn test_idle_policy() {
+        let output_at = Instant::now();
+        let cpu_at = output_at + Duration::from_secs(5);
+        let policy = |activity, timeout| IdlePolicy { timeout, activity };
+
+        let output = policy(IdleActivity::Output, Duration::from_secs(60));
+        assert_eq!(output.last_active_at(output_at, cpu_at), output_at);
+        assert_eq!(output.check_interval(), Duration::from_secs(10));
+        let cpu = policy(IdleActivity::Cpu, Duration::from_secs(8));
+        assert_eq!(cpu.last_active_at(output_at, cpu_at), cpu_at);
+        assert_eq!(cpu.check_interval(), Duration::from_secs(2));
+        let any = policy(IdleActivity::Any, Duration::from_secs(1));
+        assert_eq!(any.last_active_at(cpu_at, output_at), cpu_at);
+        assert_eq!(any.check_interval(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_restart_policy() {
+        let on_failure = RestartPolicy {
+            mode: RestartMode::OnFailure,
+            clean_exit_codes: vec![143],
+            ..Default::default()
+        };
+        assert!(!on_failure.should_restart(0, ExitReason::Normal, false));
+        assert!(!on_failure.should_restart(143, ExitReason::Normal, false));
+        assert!(on_failure.should_restart(1, ExitReason::Normal, false));
+        assert!(on_failure.should_restart(137, ExitReason::Oom, false));
+        assert!(!on_failure.should_restart(1, ExitReason::Stopped, false));
+        assert!(!on_failure.should_restart(143, ExitReason::Idle, false));
+
+        let always = RestartPolicy {
+            mode: RestartMode::Always,
+            ..Default::default()
+        };
+        assert!(always.should_restart(0, ExitReason::Normal, false));
+        assert!(always.should_restart(1, ExitReason::Stopped, false));
+        assert!(!always.should_restart(1, ExitReason::Stopped, true));
+        assert!(!always.should_restart(137, ExitReason::Checkpointed, false));
+        assert!(!always.should_restart(143, ExitReason::LogQuota, false));
+
+        let unless_stopped = RestartPolicy {
+            mode: RestartMode::UnlessStopped,
+            ..Default::default()
+        };
+        assert!(unless_stopped.should_restart(0, ExitReason::Normal, false));
+        assert!(!unless_stopped.should_restart(1, ExitReason::Stopped, false));
+        assert!(!unless_stopped.should_restart(143, ExitReason::Idle, false));
+        assert!(!RestartPolicy::default().should_restart(1, ExitReason::Normal, false));
+    }
+
+    #[test]
+    fn test_restart_jitter() {
+        let jitter: RestartJitter = "5s..1m".parse().unwrap();
+        assert_eq!(jitter.min, Duration::from_secs(5));
+        assert_eq!(jitter.max, Duration::from_secs(60));
+        assert_eq!(jitter.to_string(), "5s..60s");
+        assert_eq!(
+            "1500ms".parse::<RestartJitter>().unwrap().to_string(),
+            "1500ms"
+        );
+        for _ in 0..100 {
+            let delay = jitter.sample();
+            assert!((jitter.min..=jitter.max).contains(&delay));
+        }
+
+        assert!("1m..5s".parse::<RestartJitter>().is_err());
+        assert!("2d".parse::<RestartJitter>().is_err());
+        assert!("soon".parse::<RestartJitter>().is_err());
+    }
+
+    #[test]
+    fn test_restart_windows() {
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap();
+        let window: RestartWindow = "02:00-04:00".parse().unwrap();
+        assert_eq!(window.to_string(), "02:00-04:00");
+        assert!(window.contains(at("2024-05-01T03:59:59+00:00")));
+        assert!(!window.contains(at("2024-05-01T04:00:00+00:00")));
+        assert_eq!(
+            window.next_start(at("2024-05-01T01:00:00+00:00")),
+            at("2024-05-01T02:00:00+00:00")
+        );
+        assert_eq!(
+            window.next_start(at("2024-05-01T02:00:00+00:00")),
+            at("2024-05-02T02:00:00+00:00")
+        );
+
+        let overnight: RestartWindow = "22:30-01:00".parse().unwrap();
+        assert!(overnight.contains(at("2024-05-01T23:00:00+08:00")));
+        assert!(overnight.contains(at("2024-05-01T00:30:00+08:00")));
+        assert!(!overnight.contains(at("2024-05-01T12:00:00+08:00")));
+        assert!("02:00".parse::<RestartWindow>().is_err());
+        assert!("02:00-25:00".parse::<RestartWindow>().is_err());
+
+        let restart = RestartPolicy {
+            mode: RestartMode::Always,
+            windows: vec![window, overnight],
+            ..Default::default()
+        };
+        let jitter = Duration::from_secs(30);
+        // In a window, only the jitter is waited for.
+        assert_eq!(
+            restart.restart_at(at("2024-05-01T02:10:00+00:00"), jitter),
+            at("2024-05-01T02:10:31+00:00")
+        );
+        // Outside of them, the nearest one is waited for.
+        assert_eq!(
+            restart.restart_at(at("2024-05-01T12:00:00+00:00"), jitter),
+            at("2024-05-01T22:30:30+00:00")
+        );
+        assert_eq!(
+            RestartPolicy::default().restart_at(at("2024-05-01T12:00:00+00:00"), Duration::ZERO),
+            at("2024-05-01T12:00:01+00:00")
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_session_leader() {
+        let leader = ProcStat {
+            name: "bash".to_owned(),
+            state: 'S',
+            ppid: 1,
+            session: 42,
+            utime: 0,
+            stime: 0,
+            start_time: 1000,
+        };
+        let bound = leader_start_time(&leader, 42).unwrap();
+        assert!(!is_session_ended(leader_start_time(&leader, 42), bound));
+
+        // The leader exited but is not reaped yet.
+        let zombie = ProcStat {
+            state: 'Z',
+            ..leader.clone()
+        };
+        assert!(is_session_ended(leader_start_time(&zombie, 42), bound));
+        // The pid is reused by a process in another session.
+        let other = ProcStat {
+            session: 7,
+            start_time: 2000,
+            ..leader.clone()
+        };
+        assert!(is_session_ended(leader_start_time(&other, 42), bound));
+        // The pid is reused by a process that leads a new session with the
+        // same id, which only the start time tells apart.
+        let reused = ProcStat {
+            start_time: 2000,
+            ..leader
+        };
+        assert!(is_session_ended(leader_start_time(&reused, 42), bound));
+        assert!(is_session_ended(None, bound));
     }
 }
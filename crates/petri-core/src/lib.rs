@@ -1,9 +1,77 @@
+//! The supervisor engine of petri, which can be embedded without the
+//! server and the CLI.
+//!
+//! A [`ProcessManager`] runs processes and a [`JobManager`] runs jobs on
+//! top of it, restarting them by their policies. Both are driven through
+//! their cloneable handles, and report events to the
+//! [`ProcessEventHandler`]s and [`JobEventHandler`]s added to them. The
+//! commands of processes can be customized by [`SpawnHook`]s.
+//!
+//! ```no_run
+//! use petri_core::{JobDescription, JobManager, ProcessManager, StartInfo};
+//!
+//! # async fn run() -> petri_core::Result<()> {
+//! let proc_mgr = ProcessManager::new();
+//! let job_mgr = JobManager::new(proc_mgr.handle());
+//!
+//! let start_info = StartInfo::builder("sleep", "/").args(["60"]).build()?;
+//! let desc = JobDescription::builder(start_info)
+//!     .name("sleeper".to_owned())
+//!     .build();
+//! let jid = job_mgr.handle().add_job(desc).await?;
+//! job_mgr.handle().start_job(&jid).await?;
+//!
+//! proc_mgr.shutdown().await;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Stability
+//!
+//! The items re-exported here follow semver. Types that may grow fields
+//! or variants are `#[non_exhaustive]`, and the ones embedders create have
+//! builders. The other public items of the modules are used by the server
+//! and may change in minor versions.
+
 #[macro_use(anyhow)]
 extern crate anyhow;
 
 #[macro_use]
 extern crate log;
 
+pub mod affinity;
+pub mod checkpoint;
+pub mod container;
+pub mod env_file;
+pub mod fds;
+pub mod job_history;
 pub mod job_mgr;
+mod log_writer;
+pub mod metrics;
+mod oom;
+pub mod platform;
+pub mod precondition;
+pub mod proc_table;
 pub mod process;
 pub mod process_mgr;
+mod reaper;
+pub mod runtime_config;
+pub mod sandbox;
+pub mod spawn_queue;
+
+pub use anyhow::{Error, Result};
+pub use job_mgr::{
+    EventHandler as JobEventHandler, Handle as JobManagerHandle, IdleActivity, IdlePolicy, Job,
+    JobDescription, JobDescriptionBuilder, JobManager, JobOverrides, MemoryWatchdog, Readiness,
+    RestartJitter, RestartMode, RestartPolicy, RestartWindow, WatchdogAction,
+};
+pub use process::{
+    wrap_command, ExitReason, InvalidStartInfo, LogOptions, Process, StartInfo, StartInfoBuilder,
+};
+pub use process_mgr::{
+    EventHandler as ProcessEventHandler, Handle as ProcessManagerHandle, LimitReached, Orphan,
+    ProcessManager, SpawnHook,
+};
+pub use runtime_config::{RuntimeConfig, SharedRuntimeConfig};
+pub use sandbox::{SandboxError, SandboxOptions};
+pub use spawn_queue::{SpawnPermit, SpawnTicket};
@@ -0,0 +1,561 @@
+//! Writes the output of processes to their log files on the blocking pool,
+//! so slow disks and rotations don't hold up the reactor while processes
+//! are chatty.
+//!
+//! The total size of the log files is tracked if they have a quota, which
+//! is enforced after each batch is written. Sampled output is cut down to
+//! the kept lines right before it's written.
+
+use std::fs;
+use std::io::Write;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use petri_logger::writers::file_writer::{FileNameTemplate, FileWriter};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::task;
+
+use crate::process::{LogQuota, LogSampling, QuotaPolicy};
+use crate::process_mgr::Handle as ProcessManagerHandle;
+
+/// How many chunks of output can wait for the log file. The pipes of the
+/// process are not read while the queue is full, so no output is lost.
+const QUEUE_CAPACITY: usize = 64;
+
+/// The writer of a log file, which owns a task that drains its queue.
+/// The file is flushed and closed after the writer is dropped and the
+/// queued output is written.
+pub(crate) struct LogWriter {
+    tx: Sender<Arc<[u8]>>,
+}
+
+/// The disk usage of the log files of a process with a quota.
+#[derive(Clone, Copy, Debug)]
+pub struct LogUsage {
+    /// The total size of the log files as of the last write.
+    pub bytes: u64,
+    pub quota: LogQuota,
+    /// How many times the quota was exceeded.
+    pub exceeded: u64,
+    /// Whether the output is no longer logged.
+    pub stopped: bool,
+}
+
+/// The usage of log files shared with the process.
+pub(crate) struct QuotaState {
+    quota: LogQuota,
+    bytes: AtomicU64,
+    exceeded: AtomicU64,
+    stopped: AtomicBool,
+}
+
+/// Keeps the log files of a process within their quota.
+pub(crate) struct QuotaTracker {
+    pid: u32,
+    /// The directory and the template of the files counted.
+    log_path: PathBuf,
+    template: FileNameTemplate,
+    state: Arc<QuotaState>,
+}
+
+/// Picks the lines of the output to log by [`LogSampling`]. A line may be
+/// split across chunks, so whether it's kept is carried over to the next.
+struct LineSampler {
+    sampling: LogSampling,
+    /// Whether the last line is kept, if it's not complete yet.
+    partial_line: Option<bool>,
+    /// The lines seen, for every Nth line.
+    seen: u64,
+    /// When the current second started, and the lines kept in it.
+    second: Option<(Instant, u32)>,
+}
+
+impl QuotaState {
+    pub(crate) fn new(quota: LogQuota) -> Self {
+        Self {
+            quota,
+            bytes: AtomicU64::new(0),
+            exceeded: AtomicU64::new(0),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn usage(&self) -> LogUsage {
+        LogUsage {
+            bytes: self.bytes.load(AtomicOrdering::Relaxed),
+            quota: self.quota,
+            exceeded: self.exceeded.load(AtomicOrdering::Relaxed),
+            stopped: self.stopped.load(AtomicOrdering::Relaxed),
+        }
+    }
+}
+
+impl QuotaTracker {
+    pub(crate) fn new(
+        pid: u32,
+        log_path: PathBuf,
+        template: FileNameTemplate,
+        state: Arc<QuotaState>,
+    ) -> Self {
+        Self {
+            pid,
+            log_path,
+            template,
+            state,
+        }
+    }
+
+    /// Counts the files already on the disk.
+    async fn scan(&self) {
+        let (log_path, template) = (self.log_path.clone(), self.template.clone());
+        let files = task::spawn_blocking(move || existing_files(&log_path, &template)).await;
+        let files = files.ok().and_then(Result::ok).unwrap_or_default();
+        let bytes = files.iter().map(|file| file.1).sum();
+        self.state.bytes.store(bytes, AtomicOrdering::Relaxed);
+    }
+
+    /// Counts the written bytes, returning whether the quota is exceeded.
+    fn add(&self, written: u64) -> bool {
+        let bytes = self.state.bytes.fetch_add(written, AtomicOrdering::Relaxed) + written;
+        bytes > self.state.quota.max_bytes
+    }
+
+    #[inline]
+    fn is_stopped(&self) -> bool {
+        self.state.stopped.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Applies the policy of the exceeded quota, returning the writer to
+    /// keep writing with, or `None` if it's lost.
+    async fn enforce(
+        &self,
+        mut file_writer: FileWriter,
+        mgr_handle: &ProcessManagerHandle,
+    ) -> Option<FileWriter> {
+        let pid = self.pid;
+        let quota = self.state.quota;
+        self.state.exceeded.fetch_add(1, AtomicOrdering::Relaxed);
+        mgr_handle
+            .counters()
+            .log_quota_exceeded
+            .fetch_add(1, AtomicOrdering::Relaxed);
+
+        match quota.policy {
+            QuotaPolicy::DropOldest => {
+                let (log_path, template) = (self.log_path.clone(), self.template.clone());
+                // Other processes may share the template, like other jobs
+                // with the same program when it doesn't include `{job}`.
+                let others: Vec<_> = mgr_handle
+                    .processes()
+                    .await
+                    .into_iter()
+                    .filter(|process| process.id() != pid)
+                    .collect();
+                let dropped = task::spawn_blocking(move || {
+                    if let Err(err) = file_writer.rotate() {
+                        warn!("failed to rotate the log file of process {pid}: {err:?}");
+                    }
+                    let kept: Vec<_> = others
+                        .iter()
+                        .flat_map(|process| process.log_files().unwrap_or_default())
+                        .collect();
+                    let (removed, bytes) = remove_oldest(
+                        &log_path,
+                        &template,
+                        &kept,
+                        file_writer.active_path(),
+                        quota.max_bytes,
+                    );
+                    (file_writer, removed, bytes)
+                })
+                .await;
+                let (writer, removed, bytes) = match dropped {
+                    Ok(dropped) => dropped,
+                    Err(err) => {
+                        error!("failed to enforce the log quota of process {pid}: {err}");
+                        return None;
+                    }
+                };
+                file_writer = writer;
+                self.state.bytes.store(bytes, AtomicOrdering::Relaxed);
+                info!("log files of process {pid} exceeded the quota, removed {removed} file(s)");
+            }
+            QuotaPolicy::StopLogging => {
+                self.state.stopped.store(true, AtomicOrdering::Relaxed);
+                warn!(
+                    "log files of process {pid} exceeded the quota of {} bytes, \
+                    its output is no longer logged",
+                    quota.max_bytes
+                );
+            }
+            QuotaPolicy::StopProcess => {
+                self.state.stopped.store(true, AtomicOrdering::Relaxed);
+                warn!(
+                    "log files of process {pid} exceeded the quota of {} bytes, stopping it",
+                    quota.max_bytes
+                );
+                // The output is read until the process exits, which must
+                // not wait for this writer.
+                let mgr_handle = mgr_handle.clone();
+                task::spawn(async move {
+                    let Some(process) = mgr_handle.process_with_id(pid).await else {
+                        return;
+                    };
+                    // Keeps restart policies from restarting it.
+                    process.mark_log_quota_exceeded();
+                    if let Err(err) = mgr_handle.stop_process(pid).await {
+                        warn!("failed to stop process {pid} over its log quota: {err:?}");
+                    }
+                });
+            }
+        }
+
+        mgr_handle.handle_log_quota_exceeded(pid, &self.state.usage());
+        Some(file_writer)
+    }
+}
+
+impl LogWriter {
+    pub(crate) fn spawn(
+        file_writer: FileWriter,
+        mgr_handle: ProcessManagerHandle,
+        quota: Option<QuotaTracker>,
+        sampling: Option<LogSampling>,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        let sampler = sampling.map(LineSampler::new);
+        task::spawn(drain(file_writer, rx, mgr_handle, quota, sampler));
+        Self { tx }
+    }
+
+    /// Queues the output, waiting while the queue is full.
+    pub(crate) async fn write(&self, buf: Arc<[u8]>) {
+        // The task only stops when the sender is dropped, or when it
+        // panicked and has already been reported.
+        _ = self.tx.send(buf).await;
+    }
+}
+
+/// Writes the queued chunks in batches, taking a thread of the blocking
+/// pool only while there are chunks to write.
+async fn drain(
+    mut file_writer: FileWriter,
+    mut rx: Receiver<Arc<[u8]>>,
+    mgr_handle: ProcessManagerHandle,
+    quota: Option<QuotaTracker>,
+    mut sampler: Option<LineSampler>,
+) {
+    if let Some(quota) = &quota {
+        quota.scan().await;
+    }
+
+    let mut batch = Vec::with_capacity(QUEUE_CAPACITY);
+    while let Some(chunk) = rx.recv().await {
+        batch.push(chunk);
+        while batch.len() < QUEUE_CAPACITY {
+            let Ok(chunk) = rx.try_recv() else {
+                break;
+            };
+            batch.push(chunk);
+        }
+        if quota.as_ref().is_some_and(QuotaTracker::is_stopped) {
+            batch.clear();
+            continue;
+        }
+
+        let mut chunks = mem::take(&mut batch);
+        let written = task::spawn_blocking(move || {
+            let (mut written, mut skipped) = (0, 0);
+            let mut write = |buf: &[u8]| {
+                if file_writer.write_all(buf).is_ok() {
+                    written += buf.len() as u64;
+                }
+            };
+            let now = Instant::now();
+            for chunk in chunks.drain(..) {
+                match &mut sampler {
+                    Some(sampler) => skipped += sampler.sample(&chunk, now, &mut write),
+                    None => write(&chunk),
+                }
+            }
+            (file_writer, chunks, sampler, written, skipped)
+        })
+        .await;
+        let (writer, chunks, returned_sampler, written, skipped) = match written {
+            Ok(written) => written,
+            Err(err) => {
+                error!("failed to write the log file: {err}");
+                return;
+            }
+        };
+        file_writer = writer;
+        batch = chunks;
+        sampler = returned_sampler;
+        let counters = mgr_handle.counters();
+        counters
+            .log_bytes_written
+            .fetch_add(written, AtomicOrdering::Relaxed);
+        counters
+            .log_lines_skipped
+            .fetch_add(skipped, AtomicOrdering::Relaxed);
+
+        if let Some(quota) = quota.as_ref().filter(|quota| quota.add(written)) {
+            match quota.enforce(file_writer, &mgr_handle).await {
+                Some(writer) => file_writer = writer,
+                None => return,
+            }
+        }
+    }
+
+    // Flushing the file may block too.
+    task::spawn_blocking(move || drop(file_writer));
+}
+
+impl LineSampler {
+    fn new(sampling: LogSampling) -> Self {
+        Self {
+            sampling,
+            partial_line: None,
+            seen: 0,
+            second: None,
+        }
+    }
+
+    /// Passes the parts of the chunk to keep to `keep`, returning how many
+    /// lines are skipped. `now` is when the chunk was written.
+    fn sample(&mut self, chunk: &[u8], now: Instant, mut keep: impl FnMut(&[u8])) -> u64 {
+        let mut skipped = 0;
+        // Adjacent kept lines are passed together.
+        let mut kept_from = None;
+        let mut offset = 0;
+        for line in chunk.split_inclusive(|b| *b == b'\n') {
+            let kept = self.partial_line.unwrap_or_else(|| {
+                let kept = self.keep_line(now);
+                skipped += u64::from(!kept);
+                kept
+            });
+            match (kept, kept_from) {
+                (true, None) => kept_from = Some(offset),
+                (false, Some(from)) => {
+                    keep(&chunk[from..offset]);
+                    kept_from = None;
+                }
+                _ => {}
+            }
+            offset += line.len();
+            self.partial_line = (!line.ends_with(b"\n")).then_some(kept);
+        }
+        if let Some(from) = kept_from {
+            keep(&chunk[from..]);
+        }
+        skipped
+    }
+
+    /// Returns whether to keep the line starting now.
+    fn keep_line(&mut self, now: Instant) -> bool {
+        match self.sampling {
+            LogSampling::EveryNth(n) => {
+                let kept = self.seen.is_multiple_of(u64::from(n));
+                self.seen += 1;
+                kept
+            }
+            LogSampling::PerSecond(n) => {
+                let (started_at, kept) = match self.second {
+                    Some((started_at, kept)) if now - started_at < Duration::from_secs(1) => {
+                        (started_at, kept)
+                    }
+                    _ => (now, 0),
+                };
+                let keep = kept < n;
+                self.second = Some((started_at, kept + u32::from(keep)));
+                keep
+            }
+        }
+    }
+}
+
+/// Returns the files matching the template with their sizes and the
+/// times they were modified.
+fn existing_files(
+    log_path: &Path,
+    template: &FileNameTemplate,
+) -> std::io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+    let mut files = vec![];
+    for path in template.existing_paths(log_path)? {
+        // The file may be removed in the meantime.
+        let Ok(metadata) = fs::metadata(&path) else {
+            continue;
+        };
+        let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        files.push((path, metadata.len(), modified));
+    }
+    Ok(files)
+}
+
+/// Removes the oldest files matching the template so they fit in
+/// `max_bytes`, returning how many are removed and the total size of the
+/// rest.
+///
+/// The files belong to the job by its log directory and template, so the
+/// ones of its earlier processes are removed too. Only the files of the
+/// running processes in `kept` and the active file are kept.
+fn remove_oldest(
+    log_path: &Path,
+    template: &FileNameTemplate,
+    kept: &[PathBuf],
+    active_path: Option<&Path>,
+    max_bytes: u64,
+) -> (usize, u64) {
+    let files = existing_files(log_path, template).unwrap_or_default();
+    let (removed, bytes) = oldest_over_quota(files, kept, active_path, max_bytes);
+    for path in &removed {
+        if let Err(err) = fs::remove_file(path) {
+            warn!("failed to remove `{}`: {err}", path.display());
+        }
+    }
+    (removed.len(), bytes)
+}
+
+/// Returns the oldest files to remove so the files fit in `max_bytes`,
+/// and the total size of the rest. The files in `kept` are counted but
+/// never removed, and neither is the active file.
+fn oldest_over_quota(
+    mut files: Vec<(PathBuf, u64, SystemTime)>,
+    kept: &[PathBuf],
+    active_path: Option<&Path>,
+    max_bytes: u64,
+) -> (Vec<PathBuf>, u64) {
+    files.sort_by_key(|file| file.2);
+    let mut bytes: u64 = files.iter().map(|file| file.1).sum();
+    let mut removed = vec![];
+    for (path, len, _) in files {
+        if bytes <= max_bytes {
+            break;
+        }
+        if Some(path.as_path()) == active_path || kept.contains(&path) {
+            continue;
+        }
+        bytes -= len;
+        removed.push(path);
+    }
+    (removed, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant, SystemTime};
+
+    use petri_logger::writers::file_writer::FileNameTemplate;
+
+    use super::{oldest_over_quota, remove_oldest, LineSampler};
+    use crate::process::LogSampling;
+
+    #[test]
+    fn test_oldest_over_quota() {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let files = vec![
+            (PathBuf::from("b.log"), 40, at(2)),
+            (PathBuf::from("a.log"), 50, at(1)),
+            (PathBuf::from("c.log"), 30, at(3)),
+            (PathBuf::from("d.log"), 0, at(4)),
+        ];
+
+        let (removed, bytes) = oldest_over_quota(files.clone(), &[], Some(Path::new("d.log")), 100);
+        assert_eq!(removed, [PathBuf::from("a.log")]);
+        assert_eq!(bytes, 70);
+
+        let (removed, bytes) = oldest_over_quota(files.clone(), &[], Some(Path::new("d.log")), 120);
+        assert!(removed.is_empty());
+        assert_eq!(bytes, 120);
+
+        // The active file is kept even if it's the oldest.
+        let (removed, bytes) = oldest_over_quota(files.clone(), &[], Some(Path::new("a.log")), 60);
+        assert_eq!(removed, [PathBuf::from("b.log"), PathBuf::from("c.log")]);
+        assert_eq!(bytes, 50);
+
+        // Files of other processes are counted but never removed.
+        let kept = [PathBuf::from("a.log"), PathBuf::from("b.log")];
+        let (removed, bytes) = oldest_over_quota(files, &kept, Some(Path::new("d.log")), 60);
+        assert_eq!(removed, [PathBuf::from("c.log")]);
+        assert_eq!(bytes, 90);
+    }
+
+    #[test]
+    fn test_remove_oldest_of_earlier_processes() {
+        let dir = std::env::temp_dir().join(format!("petri-log-quota-{}", std::process::id()));
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, len: usize, secs: u64| {
+            let path = dir.join(name);
+            fs::write(&path, vec![b'x'; len]).unwrap();
+            let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+            path
+        };
+
+        // The files of an earlier process of the job are already over the
+        // quota when the new one starts writing.
+        write("app-1-20261015.log", 60, 1);
+        write("app-1-20261016.log", 60, 2);
+        let other = write("app-2-20261016.log", 30, 3);
+        let active = write("app-3-20261016.log", 10, 4);
+
+        let template = FileNameTemplate::parse("{program}-{pid}-{date}.log")
+            .unwrap()
+            .bind("program", "app");
+        let (removed, bytes) = remove_oldest(
+            &dir,
+            &template,
+            std::slice::from_ref(&other),
+            Some(&active),
+            80,
+        );
+        assert_eq!(removed, 2);
+        assert_eq!(bytes, 40);
+        assert!(other.exists());
+        assert!(active.exists());
+
+        // They stay within the quota afterwards.
+        let (removed, bytes) = remove_oldest(&dir, &template, &[other], Some(&active), 80);
+        assert_eq!(removed, 0);
+        assert_eq!(bytes, 40);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_line_sampler() {
+        let sample = |sampler: &mut LineSampler, chunk: &[u8], now| {
+            let mut kept = vec![];
+            let skipped = sampler.sample(chunk, now, |part| kept.extend_from_slice(part));
+            (String::from_utf8(kept).unwrap(), skipped)
+        };
+        let now = Instant::now();
+
+        let mut sampler = LineSampler::new(LogSampling::EveryNth(2));
+        assert_eq!(
+            sample(&mut sampler, b"a\nb\nc\nd", now),
+            ("a\nc\n".into(), 2)
+        );
+        // The rest of a skipped line is skipped too.
+        assert_eq!(sample(&mut sampler, b"dd\ne\n", now), ("e\n".into(), 0));
+
+        let mut sampler = LineSampler::new(LogSampling::PerSecond(2));
+        assert_eq!(sample(&mut sampler, b"a\nb", now), ("a\nb".into(), 0));
+        assert_eq!(sample(&mut sampler, b"b\nc\nd\n", now), ("b\n".into(), 2));
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            sample(&mut sampler, b"e\nf\ng\n", later),
+            ("e\nf\n".into(), 1)
+        );
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind as IoErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use parking_lot::Mutex;
+use tokio::task::{self, JoinHandle};
+
+#[cfg(target_os = "linux")]
+use crate::proc_table::ProcStat;
+use crate::process_mgr::Handle as ProcessManagerHandle;
+
+/// The resource usage of a process at some moment.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ProcessSample {
+    /// Total CPU time the process has consumed in both user and kernel mode.
+    pub cpu_time: Duration,
+    /// The resident set size in bytes.
+    pub rss_bytes: u64,
+}
+
+/// A persisted metrics record of a process.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MetricsRecord {
+    /// Seconds since the unix epoch.
+    pub timestamp: u64,
+    pub pid: u32,
+    /// CPU usage since the previous record, 100% means one core.
+    pub cpu_percent: f32,
+    pub rss_bytes: u64,
+}
+
+/// An on-disk ring of metrics records.
+///
+/// Records are appended to hourly files, and files older than the
+/// retention are removed when a new hour begins.
+#[derive(Clone)]
+pub struct MetricsStore {
+    inner: Arc<StoreInner>,
+}
+
+struct StoreInner {
+    dir: PathBuf,
+    retention: Mutex<Duration>,
+}
+
+const RECORD_SIZE: usize = 24;
+const FILE_EXTENSION: &str = "metrics";
+const FILE_STEM_FORMAT: &str = "%Y%m%d-%H";
+
+impl MetricsRecord {
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut buf = [0; RECORD_SIZE];
+        buf[0..8].copy_from_slice(&self.timestamp.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.pid.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.cpu_percent.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.rss_bytes.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8; RECORD_SIZE]) -> Self {
+        let field = |range: std::ops::Range<usize>| &buf[range];
+        Self {
+            timestamp: u64::from_le_bytes(field(0..8).try_into().unwrap()),
+            pid: u32::from_le_bytes(field(8..12).try_into().unwrap()),
+            cpu_percent: f32::from_le_bytes(field(12..16).try_into().unwrap()),
+            rss_bytes: u64::from_le_bytes(field(16..24).try_into().unwrap()),
+        }
+    }
+}
+
+impl MetricsStore {
+    pub fn new(dir: PathBuf, retention: Duration) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            inner: Arc::new(StoreInner {
+                dir,
+                retention: Mutex::new(retention),
+            }),
+        })
+    }
+
+    /// Changes the retention, which takes effect when a new hour begins.
+    pub fn set_retention(&self, retention: Duration) {
+        *self.inner.retention.lock() = retention;
+    }
+
+    /// Appends the records into the file of the current hour.
+    pub fn append(&self, records: &[MetricsRecord]) -> io::Result<()> {
+        let now = Utc::now();
+        let path = self.file_path(&now);
+        let is_new_file = !path.exists();
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut writer = BufWriter::new(file);
+        for record in records {
+            writer.write_all(&record.encode())?;
+        }
+        writer.flush()?;
+
+        if is_new_file {
+            self.remove_expired_files(&now);
+        }
+        Ok(())
+    }
+
+    /// Returns the records of the process since the given time.
+    pub fn query(&self, pid: u32, since: SystemTime) -> io::Result<Vec<MetricsRecord>> {
+        let since_ts = since
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        let mut files: Vec<_> = self
+            .hourly_files()?
+            .into_iter()
+            // Keep the file containing `since` by comparing with the end of the hour.
+            .filter(|(hour_ts, _)| hour_ts + 3600 > since_ts)
+            .collect();
+        files.sort_by_key(|(hour_ts, _)| *hour_ts);
+
+        let mut records = vec![];
+        for (_, path) in files {
+            let mut reader = BufReader::new(File::open(path)?);
+            let mut buf = [0; RECORD_SIZE];
+            loop {
+                match reader.read_exact(&mut buf) {
+                    Ok(_) => {}
+                    // A partially written record may be left at the end.
+                    Err(err) if err.kind() == IoErrorKind::UnexpectedEof => break,
+                    Err(err) => return Err(err),
+                }
+                let record = MetricsRecord::decode(&buf);
+                if record.pid == pid && record.timestamp >= since_ts {
+                    records.push(record);
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    fn file_path(&self, time: &DateTime<Utc>) -> PathBuf {
+        let mut path = self.inner.dir.clone();
+        path.push(format!(
+            "{}.{FILE_EXTENSION}",
+            time.format(FILE_STEM_FORMAT)
+        ));
+        path
+    }
+
+    /// Returns the metrics files with the timestamps of their hours.
+    fn hourly_files(&self) -> io::Result<Vec<(u64, PathBuf)>> {
+        let mut files = vec![];
+        for entry in fs::read_dir(&self.inner.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(FILE_EXTENSION) {
+                continue;
+            }
+            let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let Ok(hour) = NaiveDateTime::parse_from_str(
+                &format!("{stem}:00"),
+                &format!("{FILE_STEM_FORMAT}:%M"),
+            ) else {
+                continue;
+            };
+            files.push((hour.and_utc().timestamp() as u64, path));
+        }
+        Ok(files)
+    }
+
+    fn remove_expired_files(&self, now: &DateTime<Utc>) {
+        let now_ts = now.timestamp() as u64;
+        let retention = *self.inner.retention.lock();
+        let Ok(files) = self.hourly_files() else {
+            return;
+        };
+        for (hour_ts, path) in files {
+            if hour_ts + 3600 + retention.as_secs() < now_ts {
+                debug!("removing expired metrics file: {}", path.display());
+                if let Err(err) = fs::remove_file(&path) {
+                    warn!("failed to remove expired metrics file: {err:?}");
+                }
+            }
+        }
+    }
+}
+
+/// Starts a task that samples all the managed processes periodically
+/// and appends the records to the store.
+pub fn spawn_recorder(
+    proc_mgr_handle: ProcessManagerHandle,
+    store: MetricsStore,
+    interval: Duration,
+) -> JoinHandle<()> {
+    task::spawn(async move {
+        // The last CPU time of each process, used to compute the usage.
+        let mut last_samples: HashMap<u32, (Instant, Duration)> = HashMap::new();
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+
+            let now = Instant::now();
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("current system date is invalid")
+                .as_secs();
+
+            // Reading the process info may block, and so does the store.
+            let pids: Vec<_> = proc_mgr_handle
+                .processes()
+                .await
+                .iter()
+                .map(|process| process.id())
+                .collect();
+            let sampled = task::spawn_blocking(move || {
+                pids.into_iter()
+                    .map(|pid| (pid, sample_process(pid)))
+                    .collect::<Vec<_>>()
+            })
+            .await;
+            let Ok(sampled) = sampled else {
+                continue;
+            };
+
+            let mut records = vec![];
+            let mut samples = HashMap::new();
+            for (pid, sample) in sampled {
+                let sample = match sample {
+                    Ok(sample) => sample,
+                    Err(err) => {
+                        // The process may just exit.
+                        debug!("failed to sample process {pid}: {err:?}");
+                        continue;
+                    }
+                };
+
+                let cpu_percent = match last_samples.get(&pid) {
+                    Some((last_at, last_cpu_time)) => {
+                        let cpu_time = sample.cpu_time.saturating_sub(*last_cpu_time);
+                        cpu_percent(cpu_time, now.duration_since(*last_at))
+                    }
+                    None => 0.0,
+                };
+                samples.insert(pid, (now, sample.cpu_time));
+                records.push(MetricsRecord {
+                    timestamp,
+                    pid,
+                    cpu_percent,
+                    rss_bytes: sample.rss_bytes,
+                });
+            }
+            last_samples = samples;
+
+            if records.is_empty() {
+                continue;
+            }
+            let store = store.clone();
+            let appended = task::spawn_blocking(move || store.append(&records)).await;
+            if let Ok(Err(err)) = appended {
+                warn!("failed to write metrics: {err:?}");
+            }
+        }
+    })
+}
+
+/// Returns the CPU usage of a process that consumed `cpu_time` in
+/// `elapsed`, 100% means one core.
+pub fn cpu_percent(cpu_time: Duration, elapsed: Duration) -> f32 {
+    let elapsed = elapsed.as_secs_f32();
+    if elapsed > 0.0 {
+        cpu_time.as_secs_f32() / elapsed * 100.0
+    } else {
+        0.0
+    }
+}
+
+/// Samples the resource usage of the process.
+#[cfg(target_os = "linux")]
+pub fn sample_process(pid: u32) -> io::Result<ProcessSample> {
+    let invalid_data = || io::Error::new(IoErrorKind::InvalidData, "malformed procfs entry");
+
+    let stat = ProcStat::read(pid)?;
+    let ticks = stat.utime + stat.stime;
+
+    let statm = fs::read_to_string(format!("/proc/{pid}/statm"))?;
+    let resident_pages: u64 = statm
+        .split_whitespace()
+        .nth(1)
+        .and_then(|field| field.parse().ok())
+        .ok_or_else(invalid_data)?;
+
+    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+    Ok(ProcessSample {
+        cpu_time: Duration::from_secs_f64(ticks as f64 / ticks_per_sec as f64),
+        rss_bytes: resident_pages * page_size,
+    })
+}
+
+/// Samples the resource usage of the process.
+#[cfg(target_os = "macos")]
+pub fn sample_process(pid: u32) -> io::Result<ProcessSample> {
+    use std::mem;
+
+    let mut info: libc::proc_taskinfo = unsafe { mem::zeroed() };
+    let size = mem::size_of::<libc::proc_taskinfo>() as libc::c_int;
+    let ret = unsafe {
+        libc::proc_pidinfo(
+            pid as libc::c_int,
+            libc::PROC_PIDTASKINFO,
+            0,
+            &mut info as *mut _ as *mut libc::c_void,
+            size,
+        )
+    };
+    if ret != size {
+        return Err(io::Error::last_os_error());
+    }
+
+    // The CPU times are in mach absolute time units.
+    #[allow(deprecated)]
+    let (numer, denom) = {
+        let mut timebase = libc::mach_timebase_info { numer: 0, denom: 0 };
+        unsafe { libc::mach_timebase_info(&mut timebase) };
+        (timebase.numer.max(1) as u128, timebase.denom.max(1) as u128)
+    };
+    let total = (info.pti_total_user + info.pti_total_system) as u128;
+    Ok(ProcessSample {
+        cpu_time: Duration::from_nanos((total * numer / denom) as u64),
+        rss_bytes: info.pti_resident_size,
+    })
+}
+
+/// Samples the resource usage of the process.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn sample_process(_pid: u32) -> io::Result<ProcessSample> {
+    Err(io::Error::new(
+        IoErrorKind::Unsupported,
+        "sampling processes is not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MetricsRecord;
+
+    #[test]
+    fn test_record_codec() {
+        let record = MetricsRecord {
+            timestamp: 1_700_000_000,
+            pid: 4242,
+            cpu_percent: 12.5,
+            rss_bytes: 64 << 20,
+        };
+        assert_eq!(MetricsRecord::decode(&record.encode()), record);
+    }
+}
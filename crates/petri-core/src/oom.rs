@@ -0,0 +1,174 @@
+//! Detection of processes killed by the kernel OOM killer.
+
+/// A probe that tells whether a process was killed by the OOM killer,
+/// either globally or for exceeding the memory limit of its cgroup.
+///
+/// The probe takes a snapshot of the OOM kill counter of the process's
+/// memory cgroup (v1 or v2) when it's spawned, and compares the counter
+/// when it exits.
+/// The kernel log is also checked as a fallback, which is only readable
+/// with enough privileges.
+///
+/// Checking may block, so it should be done on the blocking pool.
+pub(crate) struct OomProbe {
+    #[cfg(target_os = "linux")]
+    memory_events: Option<std::path::PathBuf>,
+    #[cfg(target_os = "linux")]
+    oom_kills: u64,
+    /// When the probe is created, in the clock of the kernel log.
+    #[cfg(target_os = "linux")]
+    started_at_us: u64,
+}
+
+#[cfg(target_os = "linux")]
+impl OomProbe {
+    pub(crate) fn new(pid: u32) -> Self {
+        let started_at_us = monotonic_now_us();
+        let memory_events = cgroup_memory_events(pid);
+        let oom_kills = memory_events
+            .as_deref()
+            .and_then(read_oom_kills)
+            .unwrap_or(0);
+        Self {
+            memory_events,
+            oom_kills,
+            started_at_us,
+        }
+    }
+
+    /// Checks whether the process (or its child) that was killed by
+    /// `SIGKILL` was killed by the OOM killer.
+    ///
+    /// The counter is shared by the cgroup, so any other process in it
+    /// killed by the OOM killer while this process was running makes this
+    /// return a false positive.
+    pub(crate) fn was_oom_killed(&self, pid: u32) -> bool {
+        if let Some(memory_events) = &self.memory_events {
+            if read_oom_kills(memory_events).is_some_and(|count| count > self.oom_kills) {
+                return true;
+            }
+        }
+        kernel_log_reports_oom_kill(pid, self.started_at_us)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl OomProbe {
+    pub(crate) fn new(_pid: u32) -> Self {
+        Self {}
+    }
+
+    pub(crate) fn was_oom_killed(&self, _pid: u32) -> bool {
+        false
+    }
+}
+
+/// Returns the path of the file containing the OOM kill counter of the
+/// process's memory cgroup, which is `memory.events` for cgroup v2 and
+/// `memory.oom_control` for v1.
+#[cfg(target_os = "linux")]
+fn cgroup_memory_events(pid: u32) -> Option<std::path::PathBuf> {
+    let cgroup = std::fs::read_to_string(format!("/proc/{pid}/cgroup")).ok()?;
+
+    let mut v1_path = None;
+    let mut v2_path = None;
+    for line in cgroup.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (_, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+        if controllers.is_empty() {
+            v2_path = Some(path);
+        } else if controllers.split(',').any(|c| c == "memory") {
+            v1_path = Some(path);
+        }
+    }
+
+    let (base, path, file) = match (v1_path, v2_path) {
+        (Some(path), _) => ("/sys/fs/cgroup/memory", path, "memory.oom_control"),
+        (None, Some(path)) => ("/sys/fs/cgroup", path, "memory.events"),
+        (None, None) => return None,
+    };
+    let mut memory_events = std::path::PathBuf::from(base);
+    memory_events.push(path.trim_start_matches('/'));
+    memory_events.push(file);
+    memory_events.exists().then_some(memory_events)
+}
+
+#[cfg(target_os = "linux")]
+fn read_oom_kills(memory_events: &std::path::Path) -> Option<u64> {
+    let contents = std::fs::read_to_string(memory_events).ok()?;
+    contents
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .and_then(|count| count.trim().parse().ok())
+}
+
+/// Returns the time of `CLOCK_MONOTONIC` that the kernel log records are
+/// stamped with, in microseconds.
+#[cfg(target_os = "linux")]
+fn monotonic_now_us() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1_000
+}
+
+/// Returns whether the kernel log reports the process as killed by the
+/// OOM killer since `since_us`. Older records may be of another process
+/// with the same pid.
+#[cfg(target_os = "linux")]
+fn kernel_log_reports_oom_kill(pid: u32, since_us: u64) -> bool {
+    use std::io::Read;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let Ok(mut kmsg) = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open("/dev/kmsg")
+    else {
+        return false;
+    };
+
+    // Every read returns a single record, and fails with `EAGAIN` after
+    // the last one.
+    let needle = format!("Killed process {pid} (");
+    let mut buf = vec![0; 8192];
+    loop {
+        match kmsg.read(&mut buf) {
+            Ok(0) => return false,
+            Ok(cnt) => {
+                let record = String::from_utf8_lossy(&buf[0..cnt]);
+                if record_time_us(&record).is_some_and(|time| time >= since_us)
+                    && record.contains(&needle)
+                {
+                    return true;
+                }
+            }
+            // Records may be overwritten while reading.
+            Err(err) if err.raw_os_error() == Some(libc::EPIPE) => continue,
+            Err(_) => return false,
+        }
+    }
+}
+
+/// Returns the time of the kernel log record, which is formatted like
+/// `6,1234,5678901,-;message`.
+#[cfg(target_os = "linux")]
+fn record_time_us(record: &str) -> Option<u64> {
+    let (prefix, _) = record.split_once(';')?;
+    prefix.split(',').nth(2)?.parse().ok()
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::record_time_us;
+
+    #[test]
+    fn test_record_time() {
+        let record = "3,1024,5678901,-;Out of memory: Killed process 42 (a.out)";
+        assert_eq!(record_time_us(record), Some(5678901));
+        assert_eq!(record_time_us("3,1024;message"), None);
+        assert_eq!(record_time_us("message"), None);
+    }
+}
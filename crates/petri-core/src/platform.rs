@@ -0,0 +1,90 @@
+//! Detects the features of the platform that processes rely on, to tell
+//! why they behave differently under petri than in a shell.
+
+use std::path::Path;
+
+use crate::checkpoint;
+
+/// The features of the platform available to the server.
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    /// The version of the mounted cgroup hierarchy, `None` if cgroups are
+    /// not available. OOM kills are only detected with cgroups.
+    pub cgroup_version: Option<u8>,
+    /// Whether namespaces can be created for sandboxes, which requires
+    /// either root privileges or unprivileged user namespaces.
+    pub user_namespaces: bool,
+    /// Whether the kernel supports seccomp filters.
+    pub seccomp: bool,
+    /// Whether pseudo-terminals can be allocated.
+    pub pty: bool,
+    /// Whether the server can adopt the orphaned descendants of processes.
+    pub child_subreaper: bool,
+    /// Whether CRIU is installed to checkpoint processes.
+    pub criu: bool,
+}
+
+impl Capabilities {
+    pub fn detect() -> Self {
+        Self {
+            cgroup_version: cgroup_version(),
+            user_namespaces: user_namespaces(),
+            seccomp: seccomp(),
+            pty: Path::new("/dev/ptmx").exists(),
+            child_subreaper: cfg!(target_os = "linux"),
+            criu: cfg!(target_os = "linux") && checkpoint::is_installed(),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn cgroup_version() -> Option<u8> {
+    if Path::new("/sys/fs/cgroup/cgroup.controllers").exists() {
+        Some(2)
+    } else if Path::new("/sys/fs/cgroup/memory").exists() {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cgroup_version() -> Option<u8> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn user_namespaces() -> bool {
+    use std::fs;
+
+    if !Path::new("/proc/self/ns/user").exists() {
+        return false;
+    }
+    if unsafe { libc::geteuid() } == 0 {
+        return true;
+    }
+    // Debian-based kernels can disable unprivileged user namespaces.
+    let read = |path: &str| fs::read_to_string(path).ok();
+    let cloning_allowed =
+        read("/proc/sys/kernel/unprivileged_userns_clone").is_none_or(|value| value.trim() != "0");
+    let namespaces_allowed =
+        read("/proc/sys/user/max_user_namespaces").is_none_or(|value| value.trim() != "0");
+    cloning_allowed && namespaces_allowed
+}
+
+#[cfg(not(target_os = "linux"))]
+fn user_namespaces() -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn seccomp() -> bool {
+    // The field is only there if the kernel is built with seccomp.
+    std::fs::read_to_string("/proc/self/status")
+        .is_ok_and(|status| status.lines().any(|line| line.starts_with("Seccomp:")))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn seccomp() -> bool {
+    false
+}
@@ -0,0 +1,179 @@
+//! Conditions outside of petri that a job waits for before its process is
+//! spawned, like the port of a database it connects to, unlike
+//! dependencies which are other jobs.
+//!
+//! They are written like `tcp:postgres:5432`, `file:/run/app.sock` or
+//! `cmd:pg_isready -q`.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Error, Result};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+
+/// How long a single check may take before it counts as failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Precondition {
+    /// The endpoint (`host:port`) accepts TCP connections.
+    Tcp(String),
+    /// The file exists, relative to the working directory of the job.
+    File(PathBuf),
+    /// The command, run by `sh -c` in the working directory and with the
+    /// environment of the job, exits with 0.
+    Command(String),
+}
+
+impl Precondition {
+    /// Returns what is waited for, like `postgres:5432`.
+    pub fn target(&self) -> String {
+        match self {
+            Precondition::Tcp(addr) => addr.clone(),
+            Precondition::File(path) => path.display().to_string(),
+            Precondition::Command(cmd) => format!("`{cmd}`"),
+        }
+    }
+
+    /// Checks the condition once for the job with the working directory
+    /// and environment.
+    pub async fn check(&self, cwd: &str, env: &HashMap<OsString, OsString>) -> bool {
+        match self {
+            Precondition::Tcp(addr) => {
+                let connect = TcpStream::connect(addr.as_str());
+                matches!(
+                    tokio::time::timeout(CHECK_TIMEOUT, connect).await,
+                    Ok(Ok(_))
+                )
+            }
+            Precondition::File(path) => Path::new(cwd).join(path).exists(),
+            Precondition::Command(cmd) => {
+                let status = Command::new("sh")
+                    .arg("-c")
+                    .arg(cmd)
+                    .current_dir(cwd)
+                    .envs(env)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .kill_on_drop(true)
+                    .status();
+                match tokio::time::timeout(CHECK_TIMEOUT, status).await {
+                    Ok(Ok(status)) => status.success(),
+                    Ok(Err(err)) => {
+                        debug!("failed to run precondition `{cmd}`: {err:?}");
+                        false
+                    }
+                    Err(_) => false,
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Precondition {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((kind, target)) = s.split_once(':') else {
+            return Err(anyhow!(
+                "expected a condition like `tcp:HOST:PORT`, `file:PATH` or `cmd:COMMAND`, got `{s}`"
+            ));
+        };
+        if target.trim().is_empty() {
+            return Err(anyhow!("condition `{s}` has nothing to wait for"));
+        }
+        match kind {
+            "tcp" => match target.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                    Ok(Precondition::Tcp(target.to_owned()))
+                }
+                _ => Err(anyhow!("expected `HOST:PORT` in condition `{s}`")),
+            },
+            "file" => Ok(Precondition::File(PathBuf::from(target))),
+            "cmd" => Ok(Precondition::Command(target.to_owned())),
+            _ => Err(anyhow!(
+                "unknown condition `{kind}`, expected `tcp`, `file` or `cmd`"
+            )),
+        }
+    }
+}
+
+impl Display for Precondition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Precondition::Tcp(addr) => write!(f, "tcp:{addr}"),
+            Precondition::File(path) => write!(f, "file:{}", path.display()),
+            Precondition::Command(cmd) => write!(f, "cmd:{cmd}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+
+    use super::Precondition;
+
+    #[test]
+    fn test_parse() {
+        let parse = |s: &str| s.parse::<Precondition>();
+        assert_eq!(
+            parse("tcp:postgres:5432").unwrap(),
+            Precondition::Tcp("postgres:5432".to_owned())
+        );
+        assert_eq!(
+            parse("tcp:[::1]:80").unwrap(),
+            Precondition::Tcp("[::1]:80".to_owned())
+        );
+        assert_eq!(
+            parse("file:run/app.sock").unwrap(),
+            Precondition::File(PathBuf::from("run/app.sock"))
+        );
+        let cmd = parse("cmd:pg_isready -h db").unwrap();
+        assert_eq!(cmd, Precondition::Command("pg_isready -h db".to_owned()));
+        assert_eq!(cmd.to_string(), "cmd:pg_isready -h db");
+        assert_eq!(cmd.target(), "`pg_isready -h db`");
+
+        assert!(parse("postgres:5432").is_err());
+        assert!(parse("tcp:postgres").is_err());
+        assert!(parse("tcp:postgres:http").is_err());
+        assert!(parse("file:").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let env = HashMap::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        assert!(Precondition::Tcp(addr.clone()).check("/", &env).await);
+        drop(listener);
+        assert!(!Precondition::Tcp(addr).check("/", &env).await);
+
+        assert!(Precondition::File("bin/sh".into()).check("/", &env).await);
+        assert!(
+            !Precondition::File("no/such/file".into())
+                .check("/", &env)
+                .await
+        );
+
+        assert!(
+            Precondition::Command("test \"$(pwd)\" = /".to_owned())
+                .check("/", &env)
+                .await
+        );
+        assert!(
+            !Precondition::Command("exit 1".to_owned())
+                .check("/", &env)
+                .await
+        );
+    }
+}
@@ -0,0 +1,276 @@
+//! Snapshots of the system process table.
+
+use std::collections::HashMap;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::str::FromStr;
+
+/// A process in the system process table.
+#[derive(Clone, Debug)]
+pub struct ProcEntry {
+    pub pid: u32,
+    pub ppid: u32,
+    /// The short name of the executable.
+    pub name: String,
+    pub zombie: bool,
+}
+
+/// The fields of `/proc/<pid>/stat` that are used, see proc(5).
+#[cfg(target_os = "linux")]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) struct ProcStat {
+    /// The short name of the executable.
+    pub name: String,
+    pub state: char,
+    pub ppid: u32,
+    /// The id of the session, the pid of its leader.
+    pub session: u32,
+    /// The CPU time in user and kernel mode, in clock ticks.
+    pub utime: u64,
+    pub stime: u64,
+    /// When the process started, in clock ticks since boot. A process that
+    /// reuses the pid of another starts at another time.
+    pub start_time: u64,
+}
+
+/// A snapshot of all the processes in the system.
+pub struct ProcTable {
+    entries: HashMap<u32, ProcEntry>,
+    children: HashMap<u32, Vec<u32>>,
+}
+
+impl ProcTable {
+    pub fn snapshot() -> io::Result<Self> {
+        let entries = read_entries()?;
+
+        let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+        for entry in entries.values() {
+            children.entry(entry.ppid).or_default().push(entry.pid);
+        }
+        for pids in children.values_mut() {
+            pids.sort_unstable();
+        }
+
+        Ok(Self { entries, children })
+    }
+
+    #[inline]
+    pub fn get(&self, pid: u32) -> Option<&ProcEntry> {
+        self.entries.get(&pid)
+    }
+
+    #[inline]
+    pub fn contains(&self, pid: u32) -> bool {
+        self.entries.contains_key(&pid)
+    }
+
+    /// Returns the pids of the direct children of the process.
+    pub fn children(&self, pid: u32) -> &[u32] {
+        self.children
+            .get(&pid)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Returns the pids of all the descendants of the process.
+    pub fn descendants(&self, pid: u32) -> Vec<u32> {
+        let mut result = vec![];
+        let mut stack = vec![pid];
+        while let Some(pid) = stack.pop() {
+            for child in self.children(pid) {
+                result.push(*child);
+                stack.push(*child);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl ProcStat {
+    pub(crate) fn read(pid: u32) -> io::Result<Self> {
+        let stat = std::fs::read_to_string(format!("/proc/{pid}/stat"))?;
+        Self::parse(&stat)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed procfs entry"))
+    }
+
+    fn parse(stat: &str) -> Option<Self> {
+        // The command name may contain spaces and parens, so split at the
+        // first and the last paren.
+        let (name_start, name_end) = (stat.find('(')?, stat.rfind(')')?);
+        let name = stat.get(name_start + 1..name_end)?.to_owned();
+        // The fields are counted from the state, which is the 3rd one.
+        let fields: Vec<_> = stat[name_end + 1..].split_whitespace().collect();
+        fn field<T: FromStr>(fields: &[&str], idx: usize) -> Option<T> {
+            fields.get(idx)?.parse().ok()
+        }
+        let mut state = fields.first()?.chars();
+        Some(Self {
+            name,
+            state: state.next().filter(|_| state.next().is_none())?,
+            ppid: field(&fields, 1)?,
+            session: field(&fields, 3)?,
+            utime: field(&fields, 11)?,
+            stime: field(&fields, 12)?,
+            start_time: field(&fields, 19)?,
+        })
+    }
+
+    #[inline]
+    pub(crate) fn is_zombie(&self) -> bool {
+        self.state == 'Z'
+    }
+}
+
+/// Returns the full command line of the process, if it's readable.
+#[cfg(target_os = "linux")]
+pub fn process_cmd(pid: u32) -> Option<String> {
+    let cmdline = std::fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+    let args: Vec<_> = cmdline
+        .split(|b| *b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(String::from_utf8_lossy)
+        .collect();
+    if args.is_empty() {
+        return None;
+    }
+    Some(args.join(" "))
+}
+
+/// Returns the full command line of the process, if it's readable.
+#[cfg(target_os = "macos")]
+pub fn process_cmd(pid: u32) -> Option<String> {
+    // Reading the arguments requires parsing `KERN_PROCARGS2`, the path
+    // of the executable is good enough for now.
+    let mut buf = vec![0u8; libc::PROC_PIDPATHINFO_MAXSIZE as usize];
+    let len = unsafe {
+        libc::proc_pidpath(
+            pid as libc::c_int,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len() as u32,
+        )
+    };
+    if len <= 0 {
+        return None;
+    }
+    buf.truncate(len as usize);
+    Some(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// Returns the full command line of the process, if it's readable.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn process_cmd(_pid: u32) -> Option<String> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn read_entries() -> io::Result<HashMap<u32, ProcEntry>> {
+    let mut entries = HashMap::new();
+    for dir_entry in std::fs::read_dir("/proc")?.flatten() {
+        let Some(pid) = dir_entry.file_name().to_str().and_then(|s| s.parse().ok()) else {
+            continue;
+        };
+        let Ok(stat) = ProcStat::read(pid) else {
+            // The process may just exit.
+            continue;
+        };
+        entries.insert(
+            pid,
+            ProcEntry {
+                pid,
+                ppid: stat.ppid,
+                zombie: stat.is_zombie(),
+                name: stat.name,
+            },
+        );
+    }
+    Ok(entries)
+}
+
+#[cfg(target_os = "macos")]
+fn read_entries() -> io::Result<HashMap<u32, ProcEntry>> {
+    use std::ffi::CStr;
+    use std::mem;
+
+    // Processes may be created between the two calls, so leave some room.
+    let count = unsafe { libc::proc_listallpids(std::ptr::null_mut(), 0) };
+    if count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut pids = vec![0 as libc::pid_t; count as usize + 64];
+    let size = (pids.len() * mem::size_of::<libc::pid_t>()) as libc::c_int;
+    let count = unsafe { libc::proc_listallpids(pids.as_mut_ptr() as *mut libc::c_void, size) };
+    if count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    pids.truncate(count as usize);
+
+    let mut entries = HashMap::new();
+    for pid in pids {
+        let mut info: libc::proc_bsdinfo = unsafe { mem::zeroed() };
+        let size = mem::size_of::<libc::proc_bsdinfo>() as libc::c_int;
+        let ret = unsafe {
+            libc::proc_pidinfo(
+                pid,
+                libc::PROC_PIDTBSDINFO,
+                0,
+                &mut info as *mut _ as *mut libc::c_void,
+                size,
+            )
+        };
+        if ret != size {
+            // The process may just exit, or is not accessible.
+            continue;
+        }
+
+        let name = unsafe { CStr::from_ptr(info.pbi_comm.as_ptr()) };
+        entries.insert(
+            pid as u32,
+            ProcEntry {
+                pid: pid as u32,
+                ppid: info.pbi_ppid,
+                name: name.to_string_lossy().into_owned(),
+                // `SZOMB` in `sys/proc.h`.
+                zombie: info.pbi_status == 5,
+            },
+        );
+    }
+    Ok(entries)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn read_entries() -> io::Result<HashMap<u32, ProcEntry>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reading the process table is not supported on this platform",
+    ))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::ProcStat;
+
+    #[test]
+    fn test_parse_proc_stat() {
+        let stat = "42 (my (odd) cmd) S 1 42 42 0 -1 4194560 100 0 0 0 7 3 0 0 20 0 1 0 \
+            123456 1000 200 18446744073709551615";
+        assert_eq!(
+            ProcStat::parse(stat),
+            Some(ProcStat {
+                name: "my (odd) cmd".to_owned(),
+                state: 'S',
+                ppid: 1,
+                session: 42,
+                utime: 7,
+                stime: 3,
+                start_time: 123456,
+            })
+        );
+        assert!(ProcStat::parse(&stat.replace(" S ", " Z "))
+            .unwrap()
+            .is_zombie());
+        // Truncated entries are rejected.
+        assert_eq!(ProcStat::parse("42 (cmd) S 1 42 42"), None);
+        assert_eq!(ProcStat::parse("42 cmd S 1"), None);
+    }
+}
@@ -1,30 +1,222 @@
-use std::collections::HashMap;
-use std::io::{ErrorKind as IoErrorKind, Write};
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, ErrorKind as IoErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use chrono::{DateTime, Local};
 use petri_logger::writers::file_writer::{FilePathBuilder, FileWriter};
 use petri_utils::subscriber_list::{self, SubscriberList};
 use petri_utils::LogBuffer;
+use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::process::{Child, ChildStderr, ChildStdout, Command};
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::{oneshot, watch, Mutex, RwLock};
 use tokio::task;
 
+use crate::affinity::CpuSet;
+use crate::container::{Container, Runtime};
+use crate::fds;
+use crate::log_writer::{LogWriter, QuotaState, QuotaTracker};
+
+pub use crate::log_writer::LogUsage;
+use crate::oom::OomProbe;
 use crate::process_mgr::Handle as ProcessManagerHandle;
+use crate::sandbox::{self, SandboxOptions};
+
+pub use petri_logger::writers::file_writer::{FileNameTemplate, RotationCadence, RotationOptions};
 
+/// How to start a process, created with [`StartInfo::builder`].
 #[derive(Clone, Debug)]
+#[non_exhaustive]
 pub struct StartInfo {
     pub program: String,
     pub args: Option<Vec<String>>,
+    /// The working directory, which is an absolute path.
     pub cwd: String,
-    pub env: HashMap<String, String>,
-    pub log_path: Option<PathBuf>,
+    /// The environment variables, which may not be valid UTF-8.
+    pub env: HashMap<OsString, OsString>,
+    pub log: LogOptions,
+    pub cpus: Option<CpuSet>,
+    pub sandbox: SandboxOptions,
+    pub runtime: Runtime,
+    /// Whether the process is told that it runs under petri by the
+    /// `PETRI_*` variables, like [`JID_ENV`] for jobs.
+    ///
+    /// [`JID_ENV`]: crate::job_mgr::JID_ENV
+    pub petri_env: bool,
+    /// The size of the buffer that the output is read into at first,
+    /// which grows while the process is chatty. Defaults to the runtime
+    /// option `read_buffer_size`.
+    pub read_buffer_size: Option<usize>,
+}
+
+/// Where and how the output of a process is logged to files.
+#[derive(Clone, Default, Debug)]
+pub struct LogOptions {
+    /// The directory of the log files, `None` to not log to files.
+    pub path: Option<PathBuf>,
+    /// The template of log file paths relative to `path`, see
+    /// [`DEFAULT_LOG_NAME`] for the default.
+    pub name: Option<FileNameTemplate>,
+    /// Whether to append to the existing log file, so restarted processes
+    /// keep logging to the same file, see [`DEFAULT_APPEND_LOG_NAME`].
+    pub append: bool,
+    /// When the log files are rotated.
+    pub rotation: RotationOptions,
+    /// The cap on the total size of the log files on the disk, counting
+    /// the ones that the template matches, like those of the previous
+    /// processes of a job.
+    pub quota: Option<LogQuota>,
+    /// Keeps only some lines in the log files, for processes too chatty
+    /// to log whole. The output kept in memory for `petri log` is whole.
+    pub sampling: Option<LogSampling>,
+}
+
+/// A cap on the total size of log files, see [`LogOptions::quota`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LogQuota {
+    pub max_bytes: u64,
+    pub policy: QuotaPolicy,
+}
+
+/// What to do once the log files exceed their quota.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum QuotaPolicy {
+    /// Rotate the log file and remove the oldest files until they fit.
+    #[default]
+    DropOldest,
+    /// Stop writing the output to the log files.
+    StopLogging,
+    /// Stop the process.
+    StopProcess,
+}
+
+/// Which lines of the output are kept in the log files, see
+/// [`LogOptions::sampling`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LogSampling {
+    /// Keep every Nth line from the first one, written like `1/10`.
+    EveryNth(u32),
+    /// Keep at most N lines a second, written like `100/s`.
+    PerSecond(u32),
+}
+
+impl LogOptions {
+    /// The number of files that the quota is split into when the oldest
+    /// ones are dropped, so only a part of the output is lost at a time.
+    const QUOTA_PARTS: u64 = 4;
+
+    /// Returns when the log files are rotated, which is at least every
+    /// part of the quota when the oldest files are dropped.
+    fn rotation(&self) -> RotationOptions {
+        let mut rotation = self.rotation;
+        if let Some(quota) = self
+            .quota
+            .filter(|quota| quota.policy == QuotaPolicy::DropOldest)
+        {
+            let part = (quota.max_bytes / Self::QUOTA_PARTS).max(1);
+            rotation.max_size = Some(rotation.max_size.map_or(part, |size| size.min(part)));
+        }
+        rotation
+    }
+}
+
+impl QuotaPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QuotaPolicy::DropOldest => "drop-oldest",
+            QuotaPolicy::StopLogging => "stop-logging",
+            QuotaPolicy::StopProcess => "stop-process",
+        }
+    }
+}
+
+impl FromStr for LogSampling {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let sampling = match s.split_once('/') {
+            Some(("1", n)) => n.parse().ok().map(LogSampling::EveryNth),
+            Some((n, "s")) => n.parse().ok().map(LogSampling::PerSecond),
+            _ => None,
+        };
+        match sampling {
+            Some(LogSampling::EveryNth(0) | LogSampling::PerSecond(0)) | None => Err(anyhow!(
+                "invalid sampling `{s}`, expected `1/N` for every Nth line or `N/s` for N lines a second"
+            )),
+            Some(sampling) => Ok(sampling),
+        }
+    }
+}
+
+impl Display for LogSampling {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LogSampling::EveryNth(n) => write!(f, "1/{n}"),
+            LogSampling::PerSecond(n) => write!(f, "{n}/s"),
+        }
+    }
+}
+
+/// Builds a [`StartInfo`], which is validated once it's built.
+#[derive(Clone, Debug)]
+pub struct StartInfoBuilder {
+    info: StartInfo,
+}
+
+/// Why a [`StartInfo`] is invalid.
+#[derive(Error, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub enum InvalidStartInfo {
+    #[error("the program is empty")]
+    EmptyProgram,
+    #[error("the working directory `{0}` is not an absolute path")]
+    RelativeCwd(String),
+    #[error("invalid environment variable name `{0}`")]
+    InvalidEnvName(String),
+    #[error("the value of environment variable `{0}` contains a NUL byte")]
+    InvalidEnvValue(String),
+    #[error("the read buffer size must not be zero")]
+    ZeroReadBufferSize,
+    #[error("the read buffer size must not exceed {MAX_READ_BUFFER_SIZE} bytes")]
+    ReadBufferSizeTooLarge,
+}
+
+/// Why a process exited, in addition to its exit code.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum ExitReason {
+    /// The process exited by itself, or was stopped by a signal.
+    #[default]
+    Normal,
+    /// The process was killed by the OOM killer, or for exceeding the
+    /// memory limit of its cgroup (Linux only).
+    Oom,
+    /// The process was stopped by the server, like `petri stop` does.
+    Stopped,
+    /// The process was stopped by the server for being idle longer than
+    /// the idle timeout of its job.
+    Idle,
+    /// The process was stopped by the memory watchdog of its job, see
+    /// [`MemoryWatchdog`](crate::job_mgr::MemoryWatchdog).
+    MemoryWatchdog,
+    /// The process was stopped after being checkpointed, see
+    /// [`checkpoint`](crate::checkpoint).
+    Checkpointed,
+    /// The process was stopped for exceeding the quota of its log files,
+    /// see [`QuotaPolicy::StopProcess`]. It's never restarted, since it
+    /// would exceed the quota again.
+    LogQuota,
 }
 
 #[derive(Clone)]
@@ -34,6 +226,8 @@ pub struct Process {
 
 enum State {
     Running(oneshot::Sender<()>, watch::Receiver<Option<i32>>),
+    /// The process is stopped by `SIGSTOP` until it's resumed.
+    Paused(oneshot::Sender<()>, watch::Receiver<Option<i32>>),
     Terminating(watch::Receiver<Option<i32>>),
     Terminated(i32),
 
@@ -42,10 +236,34 @@ enum State {
     Invalid,
 }
 
+/// The environment variable carrying the spawn id of a process, which is
+/// inherited by its descendants, see [`Process::spawn_id`].
+pub const SPAWN_ID_ENV: &str = "PETRI_SPAWN_ID";
+
+/// The template of log file names if a process doesn't specify one.
+pub const DEFAULT_LOG_NAME: &str = "{program}-{pid}-{date}.log";
+
+/// The template of log file names if a process appends to its log files
+/// without specifying one, which is stable across restarts.
+pub const DEFAULT_APPEND_LOG_NAME: &str = "{program}-{date}.log";
+
+/// Variables that can be used in templates of log file names. `{job}` and
+/// `{instance}` are only available to jobs.
+pub const LOG_NAME_VARIABLES: [&str; 5] = ["job", "instance", "program", "pid", "date"];
+
 pub type OutputSubscriber = UnboundedSender<Arc<[u8]>>;
 
+/// Notes of operators about a process, like why it's running.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Annotations {
+    pub tags: BTreeMap<String, String>,
+    pub note: Option<String>,
+}
+
 struct Inner {
     id: u32,
+    spawn_id: u64,
+    argv: Vec<String>,
     cmd: String,
     started_at: Instant,
     local_started_at: DateTime<Local>,
@@ -54,40 +272,294 @@ struct Inner {
     manager_handle: ProcessManagerHandle,
 
     state: Mutex<State>,
+    /// Whether the process runs in a container, which is not paused by
+    /// stopping the engine CLI process.
+    containerized: bool,
+
+    /// Milliseconds since `started_at` when the process last wrote any
+    /// output.
+    last_output_at: AtomicU64,
+    /// Whether the process is being stopped for being idle.
+    idle_stop: AtomicBool,
+    /// Whether the process is being stopped by the memory watchdog.
+    memory_stop: AtomicBool,
+    /// Whether the process is being stopped by a checkpoint.
+    checkpointed: AtomicBool,
+    /// Whether the process is being stopped for exceeding its log quota.
+    log_quota_stop: AtomicBool,
+    /// Whether the process notified that it's ready.
+    ready: watch::Sender<bool>,
+    annotations: parking_lot::Mutex<Annotations>,
 
     output_buf: RwLock<LogBuffer>,
+    /// The size of the buffers that the pipes are read into at first.
+    read_buffer_size: usize,
     output_subscribers: SubscriberList<OutputSubscriber>,
-    output_file_writer: Option<Mutex<FileWriter>>,
+    output_file_writer: Option<LogWriter>,
+    /// The directory and the template to find the log files.
+    log_files: Option<(PathBuf, FileNameTemplate)>,
+    /// The usage of the log files, if they have a quota.
+    log_quota: Option<Arc<QuotaState>>,
 }
 
 impl StartInfo {
-    pub fn cmd(&self) -> String {
-        let mut cmd_string = self.program.clone();
-        if let Some(args) = &self.args {
-            for arg in args {
-                cmd_string.push(' ');
-                cmd_string.push_str(arg);
+    pub fn builder(program: impl Into<String>, cwd: impl Into<String>) -> StartInfoBuilder {
+        StartInfoBuilder {
+            info: StartInfo {
+                program: program.into(),
+                args: None,
+                cwd: cwd.into(),
+                env: HashMap::new(),
+                log: LogOptions::default(),
+                cpus: None,
+                sandbox: SandboxOptions::default(),
+                runtime: Runtime::default(),
+                petri_env: true,
+                read_buffer_size: None,
+            },
+        }
+    }
+
+    /// Checks what can't be spawned, which is also checked when it's
+    /// built. The fields may be changed after that (e.g. by overrides of
+    /// jobs), so it's checked again before spawning.
+    pub fn validate(&self) -> Result<(), InvalidStartInfo> {
+        if self.program.is_empty() {
+            return Err(InvalidStartInfo::EmptyProgram);
+        }
+        if !Path::new(&self.cwd).is_absolute() {
+            return Err(InvalidStartInfo::RelativeCwd(self.cwd.clone()));
+        }
+        for (name, value) in &self.env {
+            let name_bytes = name.as_bytes();
+            if name_bytes.is_empty() || name_bytes.contains(&b'=') || name_bytes.contains(&0) {
+                return Err(InvalidStartInfo::InvalidEnvName(
+                    name.to_string_lossy().into_owned(),
+                ));
+            }
+            if value.as_bytes().contains(&0) {
+                return Err(InvalidStartInfo::InvalidEnvValue(
+                    name.to_string_lossy().into_owned(),
+                ));
+            }
+        }
+        match self.read_buffer_size {
+            Some(0) => return Err(InvalidStartInfo::ZeroReadBufferSize),
+            Some(size) if size > MAX_READ_BUFFER_SIZE => {
+                return Err(InvalidStartInfo::ReadBufferSizeTooLarge);
             }
+            _ => {}
         }
-        cmd_string
+        Ok(())
+    }
+
+    /// Returns the program followed by its arguments.
+    pub fn argv(&self) -> Vec<String> {
+        let args = self.args.iter().flatten().cloned();
+        [self.program.clone()].into_iter().chain(args).collect()
+    }
+
+    /// Returns the command line quoted like a shell would need it, so the
+    /// arguments can be told apart. See [`argv`](Self::argv) for the exact
+    /// ones.
+    pub fn cmd(&self) -> String {
+        quote_argv(&self.argv())
+    }
+
+    /// Returns the template of the log file names, with the name of the
+    /// program filled in.
+    pub fn log_name_template(&self) -> FileNameTemplate {
+        let template = self.log.name.clone().unwrap_or_else(|| {
+            let default = if self.log.append {
+                DEFAULT_APPEND_LOG_NAME
+            } else {
+                DEFAULT_LOG_NAME
+            };
+            FileNameTemplate::parse(default).expect("the default should be valid")
+        });
+        let program = Path::new(&self.program)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(&self.program);
+        template.bind("program", program)
     }
 }
 
-impl Process {
-    pub(super) fn spawn(start_info: &StartInfo, mgr_handle: &ProcessManagerHandle) -> Result<Self> {
-        let mut command = Command::new(&start_info.program);
+impl StartInfoBuilder {
+    /// Appends the arguments.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let all_args = self.info.args.get_or_insert_with(Vec::new);
+        all_args.extend(args.into_iter().map(Into::into));
+        if all_args.is_empty() {
+            self.info.args = None;
+        }
+        self
+    }
+
+    /// Adds the environment variables, replacing the ones with the same
+    /// names.
+    pub fn envs<I, K, V>(mut self, env: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<OsString>,
+        V: Into<OsString>,
+    {
+        let env = env
+            .into_iter()
+            .map(|(name, value)| (name.into(), value.into()));
+        self.info.env.extend(env);
+        self
+    }
+
+    pub fn env(mut self, name: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        self.info.env.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn log(mut self, log: LogOptions) -> Self {
+        self.info.log = log;
+        self
+    }
+
+    pub fn cpus(mut self, cpus: impl Into<Option<CpuSet>>) -> Self {
+        self.info.cpus = cpus.into();
+        self
+    }
+
+    pub fn sandbox(mut self, sandbox: SandboxOptions) -> Self {
+        self.info.sandbox = sandbox;
+        self
+    }
+
+    pub fn runtime(mut self, runtime: Runtime) -> Self {
+        self.info.runtime = runtime;
+        self
+    }
+
+    pub fn petri_env(mut self, petri_env: bool) -> Self {
+        self.info.petri_env = petri_env;
+        self
+    }
 
-        if let Some(args) = &start_info.args {
-            command.args(args);
+    pub fn read_buffer_size(mut self, size: impl Into<Option<usize>>) -> Self {
+        self.info.read_buffer_size = size.into();
+        self
+    }
+
+    pub fn build(self) -> Result<StartInfo, InvalidStartInfo> {
+        self.info.validate()?;
+        Ok(self.info)
+    }
+}
+
+/// Joins the program and its arguments into a command line, quoting the
+/// ones with spaces or special characters for shells.
+pub fn quote_argv(argv: &[String]) -> String {
+    argv.iter()
+        .map(|arg| shlex::try_quote(arg).unwrap_or(Cow::Borrowed(arg)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Parses a template of log file names, and checks that it only uses the
+/// known variables.
+pub fn parse_log_name(s: &str) -> Result<FileNameTemplate> {
+    let template = FileNameTemplate::parse(s)?;
+    if let Some(name) = template
+        .variables()
+        .find(|name| !LOG_NAME_VARIABLES.contains(name))
+    {
+        return Err(anyhow!(
+            "unknown variable `{{{name}}}`, expected one of {}",
+            LOG_NAME_VARIABLES
+                .map(|name| format!("`{{{name}}}`"))
+                .join(", ")
+        ));
+    }
+    Ok(template)
+}
+
+impl ExitReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExitReason::Normal => "normal",
+            ExitReason::Oom => "OOM",
+            ExitReason::Stopped => "stopped",
+            ExitReason::Idle => "idle",
+            ExitReason::MemoryWatchdog => "memory-watchdog",
+            ExitReason::Checkpointed => "checkpointed",
+            ExitReason::LogQuota => "log-quota",
         }
+    }
+}
 
-        let mut child = command
+impl Process {
+    /// Spawns the process. The subscriber, if any, is subscribed to the
+    /// output before it's read, so it gets all the output.
+    pub(super) fn spawn(
+        start_info: &StartInfo,
+        mgr_handle: &ProcessManagerHandle,
+        subscriber: Option<OutputSubscriber>,
+    ) -> Result<(
+        Self,
+        Option<subscriber_list::CancellationToken<OutputSubscriber>>,
+    )> {
+        start_info.validate()?;
+
+        let (mut command, container) = match &start_info.runtime {
+            Runtime::Native => {
+                let mut command = Command::new(&start_info.program);
+                if let Some(args) = &start_info.args {
+                    command.args(args);
+                }
+                (command, None)
+            }
+            Runtime::Container(opts) => {
+                let container = Container::new(opts);
+                let command = container.command();
+                (command, Some(container))
+            }
+        };
+
+        static SPAWN_SEQ: AtomicU64 = AtomicU64::new(1);
+        let spawn_id = SPAWN_SEQ.fetch_add(1, AtomicOrdering::Relaxed);
+
+        command
             .current_dir(&start_info.cwd)
             .env_clear()
             .envs(&start_info.env)
+            .env(SPAWN_ID_ENV, spawn_id.to_string());
+        for hook in mgr_handle.spawn_hooks() {
+            hook.before_spawn(&mut command, start_info)?;
+        }
+        if let (Some(container), Runtime::Container(opts)) = (&container, &start_info.runtime) {
+            container.add_run_args(&mut command, opts, start_info);
+        }
+
+        // Applied after the hooks, so they are kept when the command is
+        // wrapped. Resource and sandbox settings of containers are applied
+        // by the container engine, not the engine CLI process.
+        let mut sandbox_guard = None;
+        if container.is_none() {
+            if let Some(cpus) = &start_info.cpus {
+                set_command_affinity(&mut command, cpus);
+            }
+            sandbox_guard = sandbox::prepare(&mut command, &start_info.sandbox, &start_info.cwd)?;
+        }
+        fds::restore_limit(&mut command);
+
+        let mut child = command
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
-            .spawn()?;
+            .spawn()
+            .map_err(|err| match sandbox_guard {
+                Some(guard) => guard.map_spawn_error(err),
+                None => err.into(),
+            })?;
 
         let started_at = Instant::now();
         let id = child.id().expect("cannot get pid before waiting");
@@ -99,10 +571,18 @@ impl Process {
             return Err(anyhow!("cannot get stderr pipe"));
         };
 
-        let mut log_file_writer = start_info.log_path.as_ref().and_then(|p| {
-            let builder =
-                FilePathBuilder::new(p, &format!("{}-{}", &start_info.program, id), "log");
-            match FileWriter::new(builder) {
+        let log_name_template = start_info.log_name_template().bind("pid", &id.to_string());
+        let mut log_file_writer = start_info.log.path.as_ref().and_then(|p| {
+            let file_writer = FilePathBuilder::with_template(p, log_name_template.clone())
+                .map(|builder| builder.rotation_policy(start_info.log.rotation().policy()))
+                .and_then(|builder| {
+                    if start_info.log.append {
+                        FileWriter::appending(builder)
+                    } else {
+                        FileWriter::new(builder)
+                    }
+                });
+            match file_writer {
                 Ok(file_writer) => Some(file_writer),
                 Err(err) => {
                     error!("failed to open file writer for process logging: {err:?}");
@@ -114,24 +594,75 @@ impl Process {
             if let Some(rotation_driver) = mgr_handle.logger_rotation_driver() {
                 writer.set_rotation_driver(rotation_driver);
             }
+            let mgr_handle = mgr_handle.clone();
+            writer.set_rotation_listener(move |path| mgr_handle.handle_log_rotated(id, path));
         }
+        // The quota counts the files of all the processes sharing the
+        // template, so it's not bound to this pid.
+        let log_quota = start_info
+            .log
+            .quota
+            .filter(|_| log_file_writer.is_some())
+            .map(|quota| Arc::new(QuotaState::new(quota)));
+        let quota_tracker =
+            start_info
+                .log
+                .path
+                .clone()
+                .zip(log_quota.clone())
+                .map(|(log_path, state)| {
+                    QuotaTracker::new(id, log_path, start_info.log_name_template(), state)
+                });
 
+        let runtime_config = mgr_handle.runtime_config().get();
+        let read_buffer_size = start_info
+            .read_buffer_size
+            .unwrap_or(runtime_config.read_buffer_size);
         let (kill_signal_tx, kill_signal_rx) = oneshot::channel();
         let (exit_code_tx, exit_code_rx) = watch::channel(None);
+        let output_subscribers = SubscriberList::default();
+        let subscription = subscriber.map(|subscriber| output_subscribers.subscribe(subscriber));
         let inner = Arc::new(Inner {
             id,
+            spawn_id,
+            argv: start_info.argv(),
             cmd: start_info.cmd(),
             started_at,
             local_started_at: Local::now(),
             state: Mutex::new(State::Running(kill_signal_tx, exit_code_rx)),
+            containerized: container.is_some(),
             manager_handle: mgr_handle.clone(),
-            output_buf: Default::default(),
-            output_subscribers: Default::default(),
-            output_file_writer: log_file_writer.map(Mutex::new),
+            last_output_at: AtomicU64::new(0),
+            idle_stop: AtomicBool::new(false),
+            memory_stop: AtomicBool::new(false),
+            checkpointed: AtomicBool::new(false),
+            log_quota_stop: AtomicBool::new(false),
+            ready: watch::Sender::new(false),
+            annotations: Default::default(),
+            output_buf: RwLock::new(LogBuffer::with_capacity(runtime_config.output_buffer_size)),
+            read_buffer_size,
+            output_subscribers,
+            output_file_writer: log_file_writer.map(|writer| {
+                LogWriter::spawn(
+                    writer,
+                    mgr_handle.clone(),
+                    quota_tracker,
+                    start_info.log.sampling,
+                )
+            }),
+            log_files: start_info.log.path.clone().map(|p| (p, log_name_template)),
+            log_quota,
         });
-        inner.monit_process(stdout, stderr, child, kill_signal_rx, exit_code_tx);
+        inner.monit_process(
+            stdout,
+            stderr,
+            child,
+            container,
+            kill_signal_rx,
+            exit_code_tx,
+        );
 
-        Ok(Self { inner })
+        Ok((Self { inner }, subscription))
     }
 
     #[inline]
@@ -139,11 +670,43 @@ impl Process {
         self.inner.id
     }
 
+    /// Returns the id tagged in the environment of the process, which is
+    /// unique in the server.
+    #[inline]
+    pub fn spawn_id(&self) -> u64 {
+        self.inner.spawn_id
+    }
+
+    /// Marks the process ready, as notified by itself (e.g. by running
+    /// `petri notify ready`).
+    pub fn mark_ready(&self) {
+        self.inner.ready.send_replace(true);
+    }
+
+    #[inline]
+    pub fn is_ready(&self) -> bool {
+        *self.inner.ready.borrow()
+    }
+
+    /// Waits until the process is marked ready, which never completes if
+    /// it never notifies.
+    pub async fn wait_ready(&self) {
+        let mut ready_rx = self.inner.ready.subscribe();
+        _ = ready_rx.wait_for(|ready| *ready).await;
+    }
+
+    /// Returns the command line for display, see [`StartInfo::cmd`].
     #[inline]
     pub fn cmd(&self) -> &str {
         &self.inner.cmd
     }
 
+    /// Returns the program followed by its arguments.
+    #[inline]
+    pub fn argv(&self) -> &[String] {
+        &self.inner.argv
+    }
+
     #[inline]
     pub fn started_at(&self) -> Instant {
         self.inner.started_at
@@ -154,6 +717,80 @@ impl Process {
         &self.inner.local_started_at
     }
 
+    /// Returns when the process last wrote any output, or when it started
+    /// if it hasn't written any yet.
+    pub fn last_output_at(&self) -> Instant {
+        let millis = self.inner.last_output_at.load(AtomicOrdering::Relaxed);
+        self.inner.started_at + Duration::from_millis(millis)
+    }
+
+    /// Marks that the process is going to be stopped for being idle, so it
+    /// exits with [`ExitReason::Idle`] once it's killed.
+    pub(crate) fn mark_idle(&self) {
+        self.inner.idle_stop.store(true, AtomicOrdering::Relaxed);
+    }
+
+    /// Marks that the process is going to be stopped by the memory
+    /// watchdog, so it exits with [`ExitReason::MemoryWatchdog`].
+    pub(crate) fn mark_memory_exceeded(&self) {
+        self.inner.memory_stop.store(true, AtomicOrdering::Relaxed);
+    }
+
+    /// Marks that the process is going to be stopped for exceeding its log
+    /// quota, so it exits with [`ExitReason::LogQuota`].
+    pub(crate) fn mark_log_quota_exceeded(&self) {
+        self.inner
+            .log_quota_stop
+            .store(true, AtomicOrdering::Relaxed);
+    }
+
+    /// Returns whether the process runs in a container, in which case it's
+    /// the engine CLI process that is managed.
+    #[inline]
+    pub fn is_containerized(&self) -> bool {
+        self.inner.containerized
+    }
+
+    /// Marks whether the process is going to be stopped by a checkpoint, so
+    /// it exits with [`ExitReason::Checkpointed`].
+    pub(crate) fn set_checkpointed(&self, checkpointed: bool) {
+        self.inner
+            .checkpointed
+            .store(checkpointed, AtomicOrdering::Relaxed);
+    }
+
+    /// Returns the directory of the log files, if the output is logged.
+    #[inline]
+    pub fn log_path(&self) -> Option<&Path> {
+        self.inner
+            .log_files
+            .as_ref()
+            .map(|(log_path, _)| log_path.as_path())
+    }
+
+    pub fn annotations(&self) -> Annotations {
+        self.inner.annotations.lock().clone()
+    }
+
+    /// Changes the annotations of the process, returning what `f` returns.
+    pub fn annotate<R>(&self, f: impl FnOnce(&mut Annotations) -> R) -> R {
+        f(&mut self.inner.annotations.lock())
+    }
+
+    /// Returns the usage of the log files, if they have a quota.
+    pub fn log_usage(&self) -> Option<LogUsage> {
+        self.inner.log_quota.as_ref().map(|state| state.usage())
+    }
+
+    /// Returns the log files of the process on the disk, including the
+    /// rotated ones, sorted by path.
+    pub fn log_files(&self) -> io::Result<Vec<PathBuf>> {
+        match &self.inner.log_files {
+            Some((log_path, template)) => template.existing_paths(log_path),
+            None => Ok(vec![]),
+        }
+    }
+
     pub async fn kill(&self) -> i32 {
         let mut state = self.inner.state.lock().await;
 
@@ -167,6 +804,13 @@ impl Process {
                 *state = State::Terminating(exit_code_rx.clone());
                 exit_code_rx
             }
+            State::Paused(kill_signal_tx, exit_code_rx) => {
+                _ = kill_signal_tx.send(());
+                // Otherwise `SIGTERM` is not handled until the kill timeout.
+                _ = send_signal(self.inner.id, libc::SIGCONT);
+                *state = State::Terminating(exit_code_rx.clone());
+                exit_code_rx
+            }
             State::Terminating(exit_code_rx) => {
                 *state = State::Terminating(exit_code_rx.clone());
                 exit_code_rx
@@ -194,6 +838,80 @@ impl Process {
         exit_code
     }
 
+    /// Stops the process with `SIGSTOP` until it's resumed. Its descendants
+    /// are not stopped.
+    pub async fn pause(&self) -> Result<()> {
+        if self.is_containerized() {
+            return Err(anyhow!(
+                "processes in containers cannot be paused, pause the container instead"
+            ));
+        }
+
+        let mut state = self.inner.state.lock().await;
+        match &*state {
+            State::Running(..) => {}
+            State::Paused(..) => return Err(anyhow!("the process is already paused")),
+            _ => return Err(anyhow!("the process is not running")),
+        }
+        send_signal(self.inner.id, libc::SIGSTOP)?;
+
+        let State::Running(kill_signal_tx, exit_code_rx) =
+            std::mem::replace(&mut *state, State::Invalid)
+        else {
+            unreachable!()
+        };
+        *state = State::Paused(kill_signal_tx, exit_code_rx);
+        Ok(())
+    }
+
+    /// Continues the process stopped by [`Process::pause`].
+    pub async fn resume(&self) -> Result<()> {
+        let mut state = self.inner.state.lock().await;
+        match &*state {
+            State::Paused(..) => {}
+            State::Running(..) => return Err(anyhow!("the process is not paused")),
+            _ => return Err(anyhow!("the process is not running")),
+        }
+        send_signal(self.inner.id, libc::SIGCONT)?;
+
+        let State::Paused(kill_signal_tx, exit_code_rx) =
+            std::mem::replace(&mut *state, State::Invalid)
+        else {
+            unreachable!()
+        };
+        *state = State::Running(kill_signal_tx, exit_code_rx);
+        Ok(())
+    }
+
+    pub async fn is_paused(&self) -> bool {
+        matches!(*self.inner.state.lock().await, State::Paused(..))
+    }
+
+    /// Waits for the process to exit without requesting it to terminate,
+    /// and returns the exit code.
+    pub async fn wait(&self) -> i32 {
+        let state = self.inner.state.lock().await;
+        let mut exit_code_rx = match &*state {
+            State::Running(_, exit_code_rx)
+            | State::Paused(_, exit_code_rx)
+            | State::Terminating(exit_code_rx) => exit_code_rx.clone(),
+            State::Terminated(exit_code) => return *exit_code,
+            State::Invalid => unreachable!(),
+        };
+        drop(state);
+
+        let exit_code = *exit_code_rx
+            .wait_for(Option::is_some)
+            .await
+            .expect("`exit_code` sender should not drop without sending values");
+        exit_code.expect("the sent value should not be empty")
+    }
+
+    #[inline]
+    pub(crate) fn output_subscriber_count(&self) -> usize {
+        self.inner.output_subscribers.len()
+    }
+
     pub async fn attach_output_channel(
         &self,
         sender: OutputSubscriber,
@@ -215,27 +933,107 @@ impl Process {
     }
 }
 
+/// Replaces the command with one that runs its program with the wrapper
+/// (e.g. `nice -n 10`), keeping its arguments, working directory and
+/// environment variables. Like the commands of processes, the wrapper
+/// inherits no other environment variables.
+///
+/// Settings that can't be read from the command, like the standard I/O,
+/// are not kept.
+pub fn wrap_command<I, S>(command: &mut Command, wrapper: impl AsRef<OsStr>, args: I)
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let inner = command.as_std();
+    let mut wrapped = Command::new(wrapper);
+    wrapped
+        .args(args)
+        .arg(inner.get_program())
+        .args(inner.get_args())
+        .env_clear();
+    if let Some(cwd) = inner.get_current_dir() {
+        wrapped.current_dir(cwd);
+    }
+    for (name, value) in inner.get_envs() {
+        match value {
+            Some(value) => wrapped.env(name, value),
+            None => wrapped.env_remove(name),
+        };
+    }
+    *command = wrapped;
+}
+
+#[cfg(target_os = "linux")]
+fn set_command_affinity(command: &mut Command, cpus: &CpuSet) {
+    let raw_set = cpus.to_raw();
+    unsafe {
+        command.pre_exec(move || crate::affinity::set_current_affinity(&raw_set));
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_command_affinity(_command: &mut Command, cpus: &CpuSet) {
+    warn!("cpu affinity is not supported on this platform, ignoring cpu set `{cpus}`");
+}
+
+fn send_signal(pid: u32, signal: libc::c_int) -> io::Result<()> {
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Sends `SIGTERM` to the child and kills it if it doesn't exit within
+/// the timeout. The child is killed immediately if the timeout is zero.
+async fn terminate_child(child: &mut Child, timeout: Duration) {
+    if !timeout.is_zero() {
+        if let Some(pid) = child.id() {
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+            if tokio::time::timeout(timeout, child.wait()).await.is_ok() {
+                return;
+            }
+            debug!("process {pid} didn't exit in {timeout:?}, killing it");
+        }
+    }
+    _ = child.start_kill();
+}
+
 impl Inner {
     fn monit_process(
         self: &Arc<Self>,
         stdout: ChildStdout,
         stderr: ChildStderr,
         mut child: Child,
+        container: Option<Container>,
         kill_signal: oneshot::Receiver<()>,
         exit_code_tx: watch::Sender<Option<i32>>,
     ) {
         self.read_stdio(stdout);
         self.read_stdio(stderr);
 
+        let oom_probe = OomProbe::new(self.id);
+
         let process_inner = Arc::clone(self);
         task::spawn(async move {
+            let mut kill_requested = false;
             let exit_status = tokio::select! {
                 exit_status = child.wait() => {
                     Some(exit_status.expect("failed to wait child"))
                 },
                 kill_signal = kill_signal => {
                     if kill_signal.is_ok() {
-                        _ = child.start_kill();
+                        kill_requested = true;
+                        let container_killed = match &container {
+                            Some(container) => container.kill().await,
+                            None => false,
+                        };
+                        if !container_killed {
+                            let kill_timeout =
+                                process_inner.manager_handle.runtime_config().get().kill_timeout;
+                            terminate_child(&mut child, kill_timeout).await;
+                        }
                     }
                     None
                 }
@@ -251,6 +1049,39 @@ impl Inner {
 
             // TODO: the exit code is simulated for processes that were killed by signals.
             let exit_code = exit_status.code().unwrap_or(1);
+
+            // The OOM killer always sends `SIGKILL`, and shells exit with
+            // 128 + signal number if their children are killed.
+            let killed = exit_status.signal() == Some(libc::SIGKILL)
+                || exit_status.code() == Some(128 + libc::SIGKILL);
+            let checkpointed = process_inner.checkpointed.load(AtomicOrdering::Relaxed);
+            let oom_killed = if killed && !kill_requested && !checkpointed {
+                // Reading the cgroup and the kernel log may block.
+                let pid = process_inner.id;
+                task::spawn_blocking(move || oom_probe.was_oom_killed(pid))
+                    .await
+                    .unwrap_or(false)
+            } else {
+                false
+            };
+            let exit_reason = if kill_requested {
+                if process_inner.idle_stop.load(AtomicOrdering::Relaxed) {
+                    ExitReason::Idle
+                } else if process_inner.memory_stop.load(AtomicOrdering::Relaxed) {
+                    ExitReason::MemoryWatchdog
+                } else if process_inner.log_quota_stop.load(AtomicOrdering::Relaxed) {
+                    ExitReason::LogQuota
+                } else {
+                    ExitReason::Stopped
+                }
+            } else if checkpointed {
+                ExitReason::Checkpointed
+            } else if oom_killed {
+                warn!("process {} was killed by the OOM killer", process_inner.id);
+                ExitReason::Oom
+            } else {
+                ExitReason::Normal
+            };
             _ = exit_code_tx.send(Some(exit_code));
 
             let mut state_guard = process_inner.state.lock().await;
@@ -259,24 +1090,25 @@ impl Inner {
 
             process_inner
                 .manager_handle
-                .handle_process_exit(process_inner.id, exit_code)
+                .handle_process_exit(process_inner.id, exit_code, exit_reason)
                 .await;
         });
     }
 
     fn read_stdio<R: AsyncRead + Send + Unpin + 'static>(self: &Arc<Self>, mut pipe: R) {
         let self_clone = Arc::clone(self);
+        let mut buf = ReadBuffer::new(self.read_buffer_size);
         task::spawn(async move {
-            let mut buf = vec![0; 1024];
             loop {
-                match pipe.read(&mut buf).await {
+                match pipe.read(buf.as_mut()).await {
                     Ok(cnt) => {
                         if cnt == 0 {
                             // No more data to read.
                             break;
                         }
 
-                        self_clone.write_output(&buf[0..cnt]).await;
+                        self_clone.write_output(&buf.as_mut()[0..cnt]).await;
+                        buf.resize_for_read(cnt);
                     }
                     Err(err) => {
                         if err.kind() != IoErrorKind::Interrupted {
@@ -285,23 +1117,28 @@ impl Inner {
                     }
                 }
             }
-
-            if !buf.is_empty() {
-                self_clone.write_output(&buf).await;
-            }
         });
     }
 
     async fn write_output(self: &Arc<Self>, buf: &[u8]) {
+        let elapsed = self.started_at.elapsed().as_millis() as u64;
+        self.last_output_at
+            .fetch_max(elapsed, AtomicOrdering::Relaxed);
+
+        let counters = self.manager_handle.counters();
+        counters
+            .output_bytes
+            .fetch_add(buf.len() as u64, AtomicOrdering::Relaxed);
+
+        let mut shared_buf = None;
         if let Some(file_writer) = self.output_file_writer.as_ref() {
-            let mut file_writer = file_writer.lock().await;
-            _ = file_writer.write_all(buf);
+            let shared_buf = shared_buf.get_or_insert_with(|| Arc::from(buf));
+            file_writer.write(Arc::clone(shared_buf)).await;
         }
 
         let mut output_buf = self.output_buf.write().await;
         output_buf.append(buf);
 
-        let mut shared_buf = None;
         self.output_subscribers.for_each(|sender| {
             let shared_buf = shared_buf.get_or_insert_with(|| Arc::from(buf));
             _ = sender.send(Arc::clone(shared_buf));
@@ -313,3 +1150,195 @@ impl Inner {
         drop(output_buf);
     }
 }
+
+/// The buffer that a pipe is read into, sized by the throughput: it grows
+/// while the reads fill it up, so chatty processes take fewer reads, and
+/// shrinks back while they use a small part of it.
+struct ReadBuffer {
+    buf: Vec<u8>,
+    min_len: usize,
+    max_len: usize,
+    /// How many reads in a row used less than a quarter of the buffer.
+    small_reads: u32,
+}
+
+/// The buffers don't grow beyond this, which is also the largest size
+/// they can start with.
+pub const MAX_READ_BUFFER_SIZE: usize = 256 * 1024;
+
+/// How many small reads in a row shrink the buffer.
+const SHRINK_AFTER_READS: u32 = 16;
+
+impl ReadBuffer {
+    fn new(len: usize) -> Self {
+        let len = len.max(1);
+        Self {
+            buf: vec![0; len],
+            min_len: len,
+            max_len: len.max(MAX_READ_BUFFER_SIZE),
+            small_reads: 0,
+        }
+    }
+
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+
+    /// Resizes the buffer for the next read, after `cnt` bytes are read.
+    fn resize_for_read(&mut self, cnt: usize) {
+        let len = self.buf.len();
+        if cnt == len {
+            self.small_reads = 0;
+            self.buf.resize((len * 2).min(self.max_len), 0);
+        } else if cnt < len / 4 && len > self.min_len {
+            self.small_reads += 1;
+            if self.small_reads >= SHRINK_AFTER_READS {
+                self.small_reads = 0;
+                self.buf.truncate((len / 2).max(self.min_len));
+                self.buf.shrink_to_fit();
+            }
+        } else {
+            self.small_reads = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::path::Path;
+
+    use tokio::process::Command;
+
+    use super::{
+        wrap_command, InvalidStartInfo, LogSampling, ReadBuffer, StartInfo, MAX_READ_BUFFER_SIZE,
+        SHRINK_AFTER_READS,
+    };
+
+    #[test]
+    fn test_start_info_builder() {
+        let info = StartInfo::builder("sleep", "/")
+            .args(["60"])
+            .env("A", "1")
+            .build()
+            .unwrap();
+        assert_eq!(info.args, Some(vec!["60".to_owned()]));
+        assert_eq!(info.cmd(), "sleep 60");
+
+        let quoted = StartInfo::builder("sh", "/")
+            .args(["-c", "echo 'hi there'", ""])
+            .build()
+            .unwrap();
+        assert_eq!(quoted.cmd(), r#"sh -c "echo 'hi there'" ''"#);
+
+        let no_args = StartInfo::builder("true", "/")
+            .args(Vec::<String>::new())
+            .build()
+            .unwrap();
+        assert_eq!(no_args.args, None);
+
+        let build = |program: &str, cwd: &str, name: &str, value: &str| {
+            StartInfo::builder(program, cwd)
+                .env(name, value)
+                .build()
+                .unwrap_err()
+        };
+        assert_eq!(build("", "/", "A", "1"), InvalidStartInfo::EmptyProgram);
+        assert_eq!(
+            build("true", "srv", "A", "1"),
+            InvalidStartInfo::RelativeCwd("srv".to_owned())
+        );
+        assert_eq!(
+            build("true", "/", "A=B", "1"),
+            InvalidStartInfo::InvalidEnvName("A=B".to_owned())
+        );
+        assert_eq!(
+            build("true", "/", "", "1"),
+            InvalidStartInfo::InvalidEnvName("".to_owned())
+        );
+        assert_eq!(
+            build("true", "/", "A", "1\0"),
+            InvalidStartInfo::InvalidEnvValue("A".to_owned())
+        );
+
+        let read_buffer = |size| {
+            StartInfo::builder("true", "/")
+                .read_buffer_size(size)
+                .build()
+                .map(drop)
+        };
+        assert_eq!(read_buffer(0), Err(InvalidStartInfo::ZeroReadBufferSize));
+        assert_eq!(read_buffer(MAX_READ_BUFFER_SIZE), Ok(()));
+        assert_eq!(
+            read_buffer(MAX_READ_BUFFER_SIZE + 1),
+            Err(InvalidStartInfo::ReadBufferSizeTooLarge)
+        );
+    }
+
+    #[test]
+    fn test_log_sampling() {
+        assert_eq!(
+            "1/10".parse::<LogSampling>().unwrap(),
+            LogSampling::EveryNth(10)
+        );
+        assert_eq!(
+            "100/s".parse::<LogSampling>().unwrap(),
+            LogSampling::PerSecond(100)
+        );
+        for sampling in ["1/10", "100/s"] {
+            assert_eq!(
+                sampling.parse::<LogSampling>().unwrap().to_string(),
+                sampling
+            );
+        }
+        for invalid in ["", "10", "2/10", "1/0", "0/s", "1/x", "x/s", "100/m"] {
+            assert!(invalid.parse::<LogSampling>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_wrap_command() {
+        let mut command = Command::new("sleep");
+        command
+            .arg("60")
+            .current_dir("/srv")
+            .env_clear()
+            .env("A", "1");
+        wrap_command(&mut command, "nice", ["-n", "10"]);
+
+        let command = command.as_std();
+        assert_eq!(command.get_program(), "nice");
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args, ["-n", "10", "sleep", "60"]);
+        assert_eq!(command.get_current_dir(), Some(Path::new("/srv")));
+        let envs: Vec<_> = command.get_envs().collect();
+        assert_eq!(envs, [(OsStr::new("A"), Some(OsStr::new("1")))]);
+    }
+
+    #[test]
+    fn test_read_buffer() {
+        let mut buf = ReadBuffer::new(8192);
+        // Full reads grow the buffer up to the limit.
+        while buf.as_mut().len() < MAX_READ_BUFFER_SIZE {
+            let len = buf.as_mut().len();
+            buf.resize_for_read(len);
+            assert!(buf.as_mut().len() > len);
+        }
+        buf.resize_for_read(MAX_READ_BUFFER_SIZE);
+        assert_eq!(buf.as_mut().len(), MAX_READ_BUFFER_SIZE);
+
+        // A few small reads don't shrink it, but a quiet process does.
+        for _ in 1..SHRINK_AFTER_READS {
+            buf.resize_for_read(100);
+        }
+        assert_eq!(buf.as_mut().len(), MAX_READ_BUFFER_SIZE);
+        buf.resize_for_read(100);
+        assert_eq!(buf.as_mut().len(), MAX_READ_BUFFER_SIZE / 2);
+
+        // It never gets smaller than at first.
+        for _ in 0..SHRINK_AFTER_READS * 16 {
+            buf.resize_for_read(100);
+        }
+        assert_eq!(buf.as_mut().len(), 8192);
+    }
+}
@@ -1,13 +1,35 @@
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 
 use anyhow::Result;
 use indexmap::IndexMap;
 use parking_lot::Mutex;
-use petri_logger::writers::file_writer::RotationDriver;
 use petri_utils::subscriber_list::{CancellationToken, SubscriberList};
+use thiserror::Error;
+use tokio::process::Command;
 use tokio::sync::RwLock;
+use tokio::task;
 
-use crate::process::{OutputSubscriber, Process, StartInfo};
+use crate::fds;
+use crate::process::{ExitReason, LogUsage, OutputSubscriber, Process, StartInfo};
+use crate::reaper::Reaper;
+use crate::runtime_config::SharedRuntimeConfig;
+use crate::spawn_queue::{SpawnPermit, SpawnQueue, SpawnTicket};
+
+pub use crate::reaper::Orphan;
+pub use petri_logger::writers::file_writer::RotationDriver;
+
+/// How long a started process holds its slot in the spawn queue, unless
+/// it exits earlier, so processes don't all initialize at once.
+const SPAWN_SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// How many file descriptors must be left to start a process: the few it
+/// takes, and room for the connections of clients and the rest of the
+/// server, which would fail confusingly without them.
+pub const SPAWN_FD_RESERVE: u64 = 64;
 
 pub struct ProcessManager {
     handle: Handle,
@@ -19,17 +41,106 @@ pub struct Handle {
 }
 
 pub trait EventHandler: Send + Sync {
-    fn handle_process_exit(&self, pid: u32, exit_code: i32) {
+    fn handle_process_start(&self, pid: u32) {
+        _ = pid;
+    }
+
+    fn handle_process_exit(&self, pid: u32, exit_code: i32, exit_reason: ExitReason) {
         _ = pid;
         _ = exit_code;
+        _ = exit_reason;
     }
+
+    /// Called when a log file of the process is rotated, with the path of
+    /// the previous file. It's called from the thread writing the file.
+    fn handle_log_rotated(&self, pid: u32, path: &Path) {
+        _ = pid;
+        _ = path;
+    }
+
+    /// Called when the log files of the process exceed their quota, after
+    /// its policy is applied.
+    fn handle_log_quota_exceeded(&self, pid: u32, usage: &LogUsage) {
+        _ = pid;
+        _ = usage;
+    }
+}
+
+/// Customizes the commands of processes before they're spawned, like
+/// adding environment variables or wrapping the program with
+/// [`wrap_command`]. The hooks run in the order they were added.
+///
+/// [`wrap_command`]: crate::process::wrap_command
+pub trait SpawnHook: Send + Sync {
+    /// Called with the command whose program, arguments, working directory
+    /// and environment are set. CPU affinity and sandbox settings are
+    /// applied after the hooks. An error vetoes the spawn, which fails
+    /// with the error.
+    fn before_spawn(&self, command: &mut Command, start_info: &StartInfo) -> Result<()>;
+}
+
+/// A cap on the number of managed processes was reached. The caps are
+/// set by the runtime config options named in the messages.
+#[derive(Error, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub enum LimitReached {
+    #[error("limit reached: at most {limit} processes can be managed (`max_processes`)")]
+    Processes { limit: usize },
+    #[error(
+        "limit reached: at most {limit} processes of job {} can run (`max_replicas`)",
+        &.group[..8.min(.group.len())]
+    )]
+    Replicas { group: String, limit: usize },
+    #[error(
+        "limit reached: {open} of {limit} file descriptors are open, fewer than {} are left to \
+        start a process (`limits.open_files`)",
+        SPAWN_FD_RESERVE
+    )]
+    FileDescriptors { open: usize, limit: u64 },
+}
+
+/// Aggregate numbers of the process manager since the server started.
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct Stats {
+    pub children_spawned: u64,
+    /// Bytes read from stdout and stderr of the processes.
+    pub output_bytes: u64,
+    /// Bytes written to the log files of the processes.
+    pub log_bytes_written: u64,
+    /// Times the log files of the processes exceeded their quotas.
+    pub log_quota_exceeded: u64,
+    /// Lines of the output left out of the log files by sampling.
+    pub log_lines_skipped: u64,
+    /// Number of the active output subscriptions (e.g. `petri log`).
+    pub output_subscribers: usize,
+    pub event_handlers: usize,
+    /// Spawns waiting for a slot in the spawn queue.
+    pub queued_spawns: usize,
 }
 
 #[derive(Default)]
+pub(crate) struct Counters {
+    pub(crate) children_spawned: AtomicU64,
+    pub(crate) output_bytes: AtomicU64,
+    pub(crate) log_bytes_written: AtomicU64,
+    pub(crate) log_quota_exceeded: AtomicU64,
+    pub(crate) log_lines_skipped: AtomicU64,
+}
+
 struct Inner {
     processes: RwLock<IndexMap<u32, Process>>,
+    /// The replica groups (usually job ids) of the processes, guarded by
+    /// the lock of `processes`.
+    replica_groups: Mutex<HashMap<u32, String>>,
     rotation_driver: Mutex<Option<Arc<dyn RotationDriver>>>,
+    spawn_hooks: Mutex<Vec<Arc<dyn SpawnHook>>>,
     event_handlers: SubscriberList<Box<dyn EventHandler>>,
+    reaper: Mutex<Reaper>,
+    counters: Counters,
+    runtime_config: SharedRuntimeConfig,
+    spawn_queue: SpawnQueue,
+    shutting_down: AtomicBool,
 }
 
 impl Default for ProcessManager {
@@ -40,9 +151,22 @@ impl Default for ProcessManager {
 
 impl ProcessManager {
     pub fn new() -> Self {
+        let runtime_config = SharedRuntimeConfig::default();
+        let inner = Inner {
+            processes: Default::default(),
+            replica_groups: Default::default(),
+            rotation_driver: Default::default(),
+            spawn_hooks: Default::default(),
+            event_handlers: Default::default(),
+            reaper: Default::default(),
+            counters: Default::default(),
+            spawn_queue: SpawnQueue::new(runtime_config.clone()),
+            runtime_config,
+            shutting_down: AtomicBool::new(false),
+        };
         Self {
             handle: Handle {
-                inner: Default::default(),
+                inner: Arc::new(inner),
             },
         }
     }
@@ -59,25 +183,222 @@ impl ProcessManager {
         *rotation_driver = Some(Arc::new(driver));
     }
 
+    /// Adds a hook called before spawning each process, after the ones
+    /// added earlier.
+    pub fn add_spawn_hook<H>(&self, hook: H)
+    where
+        H: SpawnHook + 'static,
+    {
+        self.handle.inner.spawn_hooks.lock().push(Arc::new(hook));
+    }
+
+    /// Makes the server the reaper of orphaned descendants of the managed
+    /// processes, and starts tracking them.
+    #[cfg(target_os = "linux")]
+    pub fn enable_child_subreaper(&self) -> std::io::Result<()> {
+        use std::time::Duration;
+
+        use tokio::signal::unix::{signal, SignalKind};
+
+        crate::reaper::set_child_subreaper()?;
+        let mut sigchld = signal(SignalKind::child())?;
+
+        let weak_inner = Arc::downgrade(&self.handle.inner);
+        tokio::task::spawn(async move {
+            // Scan periodically as well to record the lineage of processes
+            // before they are orphaned.
+            let mut ticker = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                tokio::select! {
+                    _ = sigchld.recv() => {},
+                    _ = ticker.tick() => {},
+                }
+                let Some(inner) = Weak::upgrade(&weak_inner) else {
+                    break;
+                };
+                (Handle { inner }).scan_orphans().await;
+            }
+        });
+        Ok(())
+    }
+
+    /// Rejects new processes from now on, without stopping the running
+    /// ones, so they can be stopped in order before [`shutdown`].
+    ///
+    /// [`shutdown`]: Self::shutdown
+    pub fn begin_shutdown(&self) {
+        self.handle
+            .inner
+            .shutting_down
+            .store(true, AtomicOrdering::Relaxed);
+    }
+
+    /// Rejects new processes and stops the running ones.
     pub async fn shutdown(&self) {
+        self.begin_shutdown();
         let processes = self.handle.inner.processes.read().await;
         for process in processes.values() {
             info!("killing process {}...", process.id());
+            // The descendants of a paused process would be left stopped.
+            if process.is_paused().await {
+                self.handle.signal_descendants(process.id(), libc::SIGCONT);
+            }
             process.kill().await;
         }
     }
 }
 
 impl Handle {
+    /// Joins the spawn queue, see [`add_process_with_permit`].
+    ///
+    /// [`add_process_with_permit`]: Self::add_process_with_permit
+    pub fn queue_spawn(&self) -> SpawnTicket {
+        self.inner.spawn_queue.enqueue()
+    }
+
+    /// Starts the process once there is a slot in the spawn queue.
     pub async fn add_process(&self, start_info: &StartInfo) -> Result<u32> {
-        let process = Process::spawn(&start_info, self)?;
+        let permit = self.queue_spawn().wait().await;
+        self.add_process_with_permit(start_info, permit).await
+    }
+
+    /// Starts the process with a slot granted by the spawn queue, which is
+    /// held until the process settles.
+    ///
+    /// Fails with [`LimitReached`] if there are `max_processes` processes,
+    /// or if the server is running out of file descriptors.
+    pub async fn add_process_with_permit(
+        &self,
+        start_info: &StartInfo,
+        permit: SpawnPermit,
+    ) -> Result<u32> {
+        let (process, _) = self.spawn_process(start_info, None, permit, None).await?;
+        Ok(process.id())
+    }
+
+    /// Starts the process like [`add_process_with_permit`] does, with the
+    /// subscriber subscribed to its output before any of it's read.
+    ///
+    /// [`add_process_with_permit`]: Self::add_process_with_permit
+    pub async fn add_attached_process_with_permit(
+        &self,
+        start_info: &StartInfo,
+        permit: SpawnPermit,
+        subscriber: OutputSubscriber,
+    ) -> Result<(Process, CancellationToken<OutputSubscriber>)> {
+        let (process, token) = self
+            .spawn_process(start_info, None, permit, Some(subscriber))
+            .await?;
+        Ok((process, token.expect("the subscriber should be subscribed")))
+    }
+
+    /// Starts the process as a replica of the group (like the processes of a
+    /// job and its instances), like [`add_process_with_permit`] does.
+    ///
+    /// Also fails with [`LimitReached`] if the group has `max_replicas`
+    /// processes.
+    ///
+    /// [`add_process_with_permit`]: Self::add_process_with_permit
+    pub async fn add_replica_with_permit(
+        &self,
+        start_info: &StartInfo,
+        group: &str,
+        permit: SpawnPermit,
+    ) -> Result<u32> {
+        let (process, _) = self
+            .spawn_process(start_info, Some(group), permit, None)
+            .await?;
+        Ok(process.id())
+    }
+
+    async fn spawn_process(
+        &self,
+        start_info: &StartInfo,
+        group: Option<&str>,
+        permit: SpawnPermit,
+        subscriber: Option<OutputSubscriber>,
+    ) -> Result<(Process, Option<CancellationToken<OutputSubscriber>>)> {
+        if self.is_shutting_down() {
+            return Err(anyhow!("the server is shutting down"));
+        }
+
+        // Listing the descriptors blocks, so it's done before taking the
+        // lock. Concurrent spawns may see the same count, which the reserve
+        // leaves room for.
+        let fd_usage = tokio::task::spawn_blocking(fds::Usage::current)
+            .await
+            .ok()
+            .flatten();
+
+        // Hold the lock while spawning, so concurrent spawns can't exceed
+        // the limits together.
+        let mut processes = self.inner.processes.write().await;
+        let config = self.inner.runtime_config.get();
+        if config.max_processes > 0 && processes.len() >= config.max_processes {
+            return Err(LimitReached::Processes {
+                limit: config.max_processes,
+            }
+            .into());
+        }
+        if let Some(usage) = fd_usage {
+            if usage.available() < SPAWN_FD_RESERVE {
+                return Err(LimitReached::FileDescriptors {
+                    open: usage.open,
+                    limit: usage.limit,
+                }
+                .into());
+            }
+        }
+        if let Some(group) = group.filter(|_| config.max_replicas > 0) {
+            let replicas = self
+                .inner
+                .replica_groups
+                .lock()
+                .values()
+                .filter(|g| *g == group)
+                .count();
+            if replicas >= config.max_replicas {
+                return Err(LimitReached::Replicas {
+                    group: group.to_owned(),
+                    limit: config.max_replicas,
+                }
+                .into());
+            }
+        }
+
+        let (process, token) = Process::spawn(start_info, self, subscriber)?;
+
+        let settling_process = process.clone();
+        tokio::task::spawn(async move {
+            tokio::select! {
+                _ = tokio::time::sleep(SPAWN_SETTLE_TIME) => {},
+                _ = settling_process.wait() => {},
+            }
+            drop(permit);
+        });
 
         let id = process.id();
-        self.inner.processes.write().await.insert(id, process);
+        self.inner
+            .counters
+            .children_spawned
+            .fetch_add(1, AtomicOrdering::Relaxed);
+        self.inner.reaper.lock().register(process.spawn_id(), id);
+        if let Some(group) = group {
+            self.inner
+                .replica_groups
+                .lock()
+                .insert(id, group.to_owned());
+        }
+        processes.insert(id, process.clone());
+        drop(processes);
 
         info!("process `{}` started (pid: {id})", start_info.program);
 
-        Ok(id)
+        self.inner.event_handlers.for_each(|handler| {
+            handler.handle_process_start(id);
+        });
+
+        Ok((process, token))
     }
 
     pub async fn stop_process(&self, id: u32) -> Result<i32> {
@@ -86,19 +407,106 @@ impl Handle {
             return Err(anyhow!("process with id `{id}` is not found"));
         };
 
+        // The descendants of a paused process would be left stopped.
+        if process.is_paused().await {
+            self.signal_descendants(id, libc::SIGCONT);
+        }
         Ok(process.kill().await)
     }
 
+    /// Stops the process and its descendants until they're resumed,
+    /// returning the number of the descendants.
+    pub async fn pause_process(&self, id: u32) -> Result<usize> {
+        let Some(process) = self.process_with_id(id).await else {
+            return Err(anyhow!("process with id `{id}` is not found"));
+        };
+
+        process.pause().await?;
+        Ok(self.signal_descendants(id, libc::SIGSTOP))
+    }
+
+    /// Continues the process stopped by [`Handle::pause_process`] and its
+    /// descendants, returning the number of the descendants.
+    pub async fn resume_process(&self, id: u32) -> Result<usize> {
+        let Some(process) = self.process_with_id(id).await else {
+            return Err(anyhow!("process with id `{id}` is not found"));
+        };
+
+        if !process.is_paused().await {
+            return Err(anyhow!("the process is not paused"));
+        }
+        // Resume the descendants first, so the process doesn't observe
+        // them stopped.
+        let count = self.signal_descendants(id, libc::SIGCONT);
+        process.resume().await?;
+        Ok(count)
+    }
+
+    fn signal_descendants(&self, id: u32, signal: libc::c_int) -> usize {
+        let tree = self.inner.reaper.lock().tree_of(id);
+        for pid in &tree {
+            unsafe { libc::kill(*pid as libc::pid_t, signal) };
+        }
+        tree.len()
+    }
+
+    /// Stops the process along with its descendants and the orphans it
+    /// left, returning the exit code of the process if it was running and
+    /// the number of the other processes that were killed.
+    pub async fn stop_process_tree(&self, id: u32) -> Result<(Option<i32>, usize)> {
+        let process = self.process_with_id(id).await;
+        let tree = self.inner.reaper.lock().tree_of(id);
+        if process.is_none() && tree.is_empty() {
+            return Err(anyhow!("process with id `{id}` is not found"));
+        }
+
+        // Kill the descendants first, so they don't get orphaned again.
+        for pid in &tree {
+            unsafe { libc::kill(*pid as libc::pid_t, libc::SIGKILL) };
+        }
+        let exit_code = match process {
+            Some(process) => Some(process.kill().await),
+            None => None,
+        };
+        self.scan_orphans().await;
+
+        Ok((exit_code, tree.len()))
+    }
+
+    pub async fn wait_process(&self, id: u32) -> Result<i32> {
+        let Some(process) = self.process_with_id(id).await else {
+            return Err(anyhow!("process with id `{id}` is not found"));
+        };
+
+        Ok(process.wait().await)
+    }
+
     pub async fn processes(&self) -> Vec<Process> {
         let processes = self.inner.processes.read().await;
         processes.values().cloned().collect()
     }
 
+    /// Returns the number of processes without waiting, or `None` if they
+    /// are being updated, for when the runtime may not make progress.
+    pub fn try_process_count(&self) -> Option<usize> {
+        let processes = self.inner.processes.try_read().ok()?;
+        Some(processes.len())
+    }
+
     pub async fn process_with_id(&self, id: u32) -> Option<Process> {
         let processes = self.inner.processes.read().await;
         processes.get(&id).cloned()
     }
 
+    /// Finds the process by its spawn id, see [`Process::spawn_id`].
+    pub async fn process_with_spawn_id(&self, spawn_id: u64) -> Option<Process> {
+        let processes = self.inner.processes.read().await;
+        processes
+            .values()
+            .find(|process| process.spawn_id() == spawn_id)
+            .cloned()
+    }
+
     pub async fn attach_output_channel(
         &self,
         id: u32,
@@ -111,6 +519,50 @@ impl Handle {
         Some(process.attach_output_channel(sender).await)
     }
 
+    /// Returns whether the server is shutting down, when no processes can
+    /// be started anymore.
+    #[inline]
+    pub fn is_shutting_down(&self) -> bool {
+        self.inner.shutting_down.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Returns the options that can be adjusted while the server is running.
+    pub fn runtime_config(&self) -> SharedRuntimeConfig {
+        self.inner.runtime_config.clone()
+    }
+
+    /// Returns the orphaned processes adopted by the server.
+    pub fn orphans(&self) -> Vec<Orphan> {
+        self.inner.reaper.lock().orphans()
+    }
+
+    async fn scan_orphans(&self) {
+        let managed: HashSet<_> = self.inner.processes.read().await.keys().cloned().collect();
+        // Walking the process table may block.
+        let inner = Arc::clone(&self.inner);
+        _ = task::spawn_blocking(move || inner.reaper.lock().scan(&managed)).await;
+    }
+
+    pub async fn stats(&self) -> Stats {
+        let output_subscribers = self
+            .processes()
+            .await
+            .iter()
+            .map(Process::output_subscriber_count)
+            .sum();
+        let counters = &self.inner.counters;
+        Stats {
+            children_spawned: counters.children_spawned.load(AtomicOrdering::Relaxed),
+            output_bytes: counters.output_bytes.load(AtomicOrdering::Relaxed),
+            log_bytes_written: counters.log_bytes_written.load(AtomicOrdering::Relaxed),
+            log_quota_exceeded: counters.log_quota_exceeded.load(AtomicOrdering::Relaxed),
+            log_lines_skipped: counters.log_lines_skipped.load(AtomicOrdering::Relaxed),
+            output_subscribers,
+            event_handlers: self.inner.event_handlers.len(),
+            queued_spawns: self.inner.spawn_queue.len(),
+        }
+    }
+
     pub fn add_event_handler<H: EventHandler + 'static>(
         &self,
         handler: H,
@@ -118,18 +570,46 @@ impl Handle {
         self.inner.event_handlers.subscribe(Box::new(handler))
     }
 
-    pub(crate) async fn handle_process_exit(&self, id: u32, exit_code: i32) {
+    pub(crate) async fn handle_process_exit(
+        &self,
+        id: u32,
+        exit_code: i32,
+        exit_reason: ExitReason,
+    ) {
         info!("process {id} exit with code {exit_code}");
 
         let mut processes = self.inner.processes.write().await;
-        processes.remove(&id);
+        processes.shift_remove(&id);
+        self.inner.replica_groups.lock().remove(&id);
         drop(processes);
 
         self.inner.event_handlers.for_each(|handler| {
-            handler.handle_process_exit(id, exit_code);
+            handler.handle_process_exit(id, exit_code, exit_reason);
+        });
+    }
+
+    pub(crate) fn handle_log_rotated(&self, id: u32, path: &Path) {
+        debug!("log file of process {id} rotated: {}", path.display());
+        self.inner.event_handlers.for_each(|handler| {
+            handler.handle_log_rotated(id, path);
         });
     }
 
+    pub(crate) fn handle_log_quota_exceeded(&self, id: u32, usage: &LogUsage) {
+        self.inner.event_handlers.for_each(|handler| {
+            handler.handle_log_quota_exceeded(id, usage);
+        });
+    }
+
+    #[inline]
+    pub(crate) fn counters(&self) -> &Counters {
+        &self.inner.counters
+    }
+
+    pub(crate) fn spawn_hooks(&self) -> Vec<Arc<dyn SpawnHook>> {
+        self.inner.spawn_hooks.lock().clone()
+    }
+
     #[rustfmt::skip]
     pub(crate) fn logger_rotation_driver(&self) -> Option<Arc<dyn RotationDriver>> {
         self.inner.rotation_driver.lock().as_ref().map(Arc::clone)
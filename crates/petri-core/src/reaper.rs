@@ -0,0 +1,208 @@
+//! Adoption of orphaned descendants of managed processes (Linux only).
+//!
+//! With `PR_SET_CHILD_SUBREAPER` set, processes that daemonize themselves
+//! by double-forking are re-parented to the server instead of init. The
+//! reaper tracks the descendants of managed processes, so that it can
+//! tell which managed process an adopted orphan came from, and reaps the
+//! orphans when they exit.
+//!
+//! Intermediate processes of a double fork usually exit before they can
+//! be seen, so every managed process is also tagged with a spawn id in
+//! its environment, which is inherited by its descendants.
+//!
+//! Orphans that exit before they can be seen can't be told apart from the
+//! children spawned by the server itself, so they are reaped once they
+//! are left as zombies for longer than the runtime would take to reap
+//! its own children.
+
+use std::collections::{HashMap, HashSet};
+#[cfg(target_os = "linux")]
+use std::time::{Duration, Instant};
+
+use indexmap::IndexMap;
+
+use crate::process::SPAWN_ID_ENV;
+
+#[cfg(target_os = "linux")]
+use crate::proc_table::{process_cmd, ProcStat, ProcTable};
+
+/// A process that was orphaned by a managed process and adopted by the
+/// server.
+#[derive(Clone, Debug)]
+pub struct Orphan {
+    pub pid: u32,
+    /// The managed process it descended from, if known.
+    pub owner_pid: Option<u32>,
+    pub cmd: String,
+}
+
+#[derive(Default)]
+pub(crate) struct Reaper {
+    /// Known descendants of managed processes, mapped to the managed
+    /// process they descended from.
+    lineage: HashMap<u32, u32>,
+    /// Spawn ids of managed processes, mapped to their pids.
+    spawns: HashMap<u64, u32>,
+    orphans: IndexMap<u32, Orphan>,
+    /// Zombie children that are not known to be orphans, mapped to when
+    /// they were first seen.
+    #[cfg(target_os = "linux")]
+    strays: HashMap<u32, Instant>,
+}
+
+/// How long a zombie child not known to be an orphan is left for the
+/// runtime to reap, before it's reaped as a stray orphan.
+#[cfg(target_os = "linux")]
+const STRAY_ZOMBIE_GRACE: Duration = Duration::from_secs(5);
+
+impl Reaper {
+    /// Records the spawn id of a newly spawned managed process.
+    #[inline]
+    pub(crate) fn register(&mut self, spawn_id: u64, pid: u32) {
+        self.spawns.insert(spawn_id, pid);
+    }
+
+    #[inline]
+    pub(crate) fn orphans(&self) -> Vec<Orphan> {
+        self.orphans.values().cloned().collect()
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn set_child_subreaper() -> std::io::Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+impl Reaper {
+    /// Updates the lineage with the current process table, adopts new
+    /// orphans and reaps the exited ones.
+    pub(crate) fn scan(&mut self, managed: &HashSet<u32>) {
+        let table = match ProcTable::snapshot() {
+            Ok(table) => table,
+            Err(err) => {
+                warn!("failed to read the process table: {err:?}");
+                return;
+            }
+        };
+        let self_pid = std::process::id();
+
+        // Record descendants of the managed processes, and also those of
+        // the orphans, which may fork again.
+        let roots = managed.iter().map(|pid| (*pid, *pid)).chain(
+            self.orphans
+                .values()
+                .filter_map(|orphan| Some((orphan.pid, orphan.owner_pid?))),
+        );
+        for (root, owner) in roots.collect::<Vec<_>>() {
+            for pid in table.descendants(root) {
+                self.lineage.insert(pid, owner);
+            }
+        }
+
+        // Any child that we didn't spawn is an orphan. Children in another
+        // session are adopted even without a known owner, because they have
+        // apparently daemonized.
+        let self_session = read_session(self_pid);
+        let mut strays = HashMap::new();
+        for &pid in table.children(self_pid) {
+            if managed.contains(&pid) || self.orphans.contains_key(&pid) {
+                continue;
+            }
+            let owner_pid = self.lineage.get(&pid).cloned().or_else(|| {
+                let spawn_id = read_spawn_id(pid)?;
+                self.spawns.get(&spawn_id).cloned()
+            });
+            if owner_pid.is_none() && read_session(pid) == self_session {
+                // It may be spawned by the server, or an orphan that exited
+                // before it could be seen, whose environment is gone.
+                if table.get(pid).is_some_and(|entry| entry.zombie) {
+                    let now = Instant::now();
+                    let seen_at = self.strays.get(&pid).cloned().unwrap_or(now);
+                    if now.duration_since(seen_at) < STRAY_ZOMBIE_GRACE {
+                        strays.insert(pid, seen_at);
+                    } else {
+                        unsafe {
+                            libc::waitpid(pid as libc::pid_t, std::ptr::null_mut(), libc::WNOHANG)
+                        };
+                        debug!("reaped stray process {pid}");
+                    }
+                }
+                continue;
+            }
+
+            match owner_pid {
+                Some(owner_pid) => info!("adopted process {pid} orphaned by {owner_pid}"),
+                None => info!("adopted orphaned process {pid}"),
+            }
+            self.orphans.insert(
+                pid,
+                Orphan {
+                    pid,
+                    owner_pid,
+                    cmd: process_cmd(pid).unwrap_or_default(),
+                },
+            );
+        }
+
+        self.orphans.retain(|pid, _| match table.get(*pid) {
+            Some(entry) if !entry.zombie => true,
+            _ => {
+                // Reap the zombie, the process can only be waited by us.
+                unsafe { libc::waitpid(*pid as libc::pid_t, std::ptr::null_mut(), libc::WNOHANG) };
+                debug!("reaped orphaned process {pid}");
+                false
+            }
+        });
+        self.lineage.retain(|pid, _| table.contains(*pid));
+        self.strays = strays;
+
+        // The exited managed processes will not get new orphans anymore,
+        // since the orphans must be adopted in the scan triggered by that.
+        self.spawns.retain(|_, pid| managed.contains(pid));
+    }
+
+    /// Returns the live descendants of the managed process, including the
+    /// orphans it left and their descendants.
+    pub(crate) fn tree_of(&self, pid: u32) -> Vec<u32> {
+        let Ok(table) = ProcTable::snapshot() else {
+            return vec![];
+        };
+
+        let mut tree = table.descendants(pid);
+        for orphan in self.orphans.values() {
+            if orphan.owner_pid == Some(pid) {
+                tree.push(orphan.pid);
+                tree.extend(table.descendants(orphan.pid));
+            }
+        }
+        tree
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Reaper {
+    pub(crate) fn scan(&mut self, _managed: &HashSet<u32>) {}
+
+    pub(crate) fn tree_of(&self, _pid: u32) -> Vec<u32> {
+        vec![]
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read_session(pid: u32) -> Option<u32> {
+    ProcStat::read(pid).ok().map(|stat| stat.session)
+}
+
+#[cfg(target_os = "linux")]
+fn read_spawn_id(pid: u32) -> Option<u64> {
+    let environ = std::fs::read(format!("/proc/{pid}/environ")).ok()?;
+    let prefix = format!("{SPAWN_ID_ENV}=");
+    environ
+        .split(|b| *b == 0)
+        .find_map(|var| var.strip_prefix(prefix.as_bytes()))
+        .and_then(|value| std::str::from_utf8(value).ok()?.parse().ok())
+}
@@ -0,0 +1,238 @@
+//! Server options that can be adjusted while the server is running.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::LevelFilter;
+use petri_utils::time::parse_duration;
+use tokio::sync::watch;
+
+use crate::process::MAX_READ_BUFFER_SIZE;
+
+/// Keys of the options that can be changed with [`SharedRuntimeConfig::set`].
+pub const KEYS: &[&str] = &[
+    "log_level",
+    "rotation_interval",
+    "kill_timeout",
+    "output_buffer_size",
+    "read_buffer_size",
+    "max_concurrent_spawns",
+    "max_processes",
+    "max_replicas",
+];
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RuntimeConfig {
+    pub log_level: LevelFilter,
+    /// How often the log files are checked for rotation.
+    pub rotation_interval: Duration,
+    /// How long to wait for processes to exit after `SIGTERM` before
+    /// killing them, zero means killing them immediately.
+    pub kill_timeout: Duration,
+    /// The size of the in-memory output buffer of new processes.
+    pub output_buffer_size: usize,
+    /// The size of the buffers that the output of new processes is read
+    /// into at first, which grow while the processes are chatty.
+    pub read_buffer_size: usize,
+    /// How many processes can be starting at the same time, the others
+    /// wait in a queue. Zero means no limit.
+    pub max_concurrent_spawns: usize,
+    /// How many processes can be managed at the same time, zero means no
+    /// limit.
+    pub max_processes: usize,
+    /// How many processes of a job, including the instances of a template,
+    /// can run at the same time. Zero means no limit.
+    pub max_replicas: usize,
+}
+
+/// A shared and watchable [`RuntimeConfig`].
+#[derive(Clone)]
+pub struct SharedRuntimeConfig {
+    tx: Arc<watch::Sender<RuntimeConfig>>,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        if cfg!(debug_assertions) {
+            Self {
+                log_level: LevelFilter::Trace,
+                rotation_interval: Duration::from_secs(5),
+                kill_timeout: Duration::ZERO,
+                output_buffer_size: 4096,
+                read_buffer_size: 8192,
+                max_concurrent_spawns: 0,
+                max_processes: 0,
+                max_replicas: 0,
+            }
+        } else {
+            Self {
+                log_level: LevelFilter::Info,
+                rotation_interval: Duration::from_secs(30),
+                kill_timeout: Duration::ZERO,
+                output_buffer_size: 4096,
+                read_buffer_size: 8192,
+                max_concurrent_spawns: 0,
+                max_processes: 0,
+                max_replicas: 0,
+            }
+        }
+    }
+}
+
+impl RuntimeConfig {
+    /// Returns the value of the option in its textual form.
+    pub fn get(&self, key: &str) -> Option<String> {
+        Some(match key {
+            "log_level" => self.log_level.as_str().to_lowercase(),
+            "rotation_interval" => format!("{}ms", self.rotation_interval.as_millis()),
+            "kill_timeout" => format!("{}ms", self.kill_timeout.as_millis()),
+            "output_buffer_size" => self.output_buffer_size.to_string(),
+            "read_buffer_size" => self.read_buffer_size.to_string(),
+            "max_concurrent_spawns" => self.max_concurrent_spawns.to_string(),
+            "max_processes" => self.max_processes.to_string(),
+            "max_replicas" => self.max_replicas.to_string(),
+            _ => return None,
+        })
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "log_level" => {
+                self.log_level = value
+                    .parse()
+                    .map_err(|_| anyhow!("invalid log level `{value}`"))?;
+            }
+            "rotation_interval" => {
+                let interval = parse_duration(value)?;
+                if interval.is_zero() {
+                    return Err(anyhow!("rotation interval must not be zero"));
+                }
+                self.rotation_interval = interval;
+            }
+            "kill_timeout" => {
+                self.kill_timeout = parse_duration(value)?;
+            }
+            "output_buffer_size" | "read_buffer_size" => {
+                let size: usize = value
+                    .parse()
+                    .map_err(|_| anyhow!("invalid buffer size `{value}`"))?;
+                if size == 0 {
+                    return Err(anyhow!("buffer size must not be zero"));
+                }
+                if key == "read_buffer_size" && size > MAX_READ_BUFFER_SIZE {
+                    return Err(anyhow!(
+                        "read buffer size must not exceed {MAX_READ_BUFFER_SIZE} bytes"
+                    ));
+                }
+                if key == "output_buffer_size" {
+                    self.output_buffer_size = size;
+                } else {
+                    self.read_buffer_size = size;
+                }
+            }
+            "max_concurrent_spawns" => {
+                self.max_concurrent_spawns = value
+                    .parse()
+                    .map_err(|_| anyhow!("invalid number of spawns `{value}`"))?;
+            }
+            "max_processes" | "max_replicas" => {
+                let limit = value
+                    .parse()
+                    .map_err(|_| anyhow!("invalid number of processes `{value}`"))?;
+                if key == "max_processes" {
+                    self.max_processes = limit;
+                } else {
+                    self.max_replicas = limit;
+                }
+            }
+            _ => {
+                return Err(anyhow!(
+                    "unknown option `{key}`, expected one of: {}",
+                    KEYS.join(", ")
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for SharedRuntimeConfig {
+    fn default() -> Self {
+        Self::new(RuntimeConfig::default())
+    }
+}
+
+impl SharedRuntimeConfig {
+    pub fn new(config: RuntimeConfig) -> Self {
+        let (tx, _) = watch::channel(config);
+        Self { tx: Arc::new(tx) }
+    }
+
+    /// Returns a copy of the current config.
+    pub fn get(&self) -> RuntimeConfig {
+        self.tx.borrow().clone()
+    }
+
+    /// Parses and sets the value of the option, and notifies the watchers
+    /// if it's changed.
+    pub fn set(&self, key: &str, value: &str) -> Result<()> {
+        let mut config = self.get();
+        config.set(key, value)?;
+        self.tx.send_if_modified(|current| {
+            if *current == config {
+                return false;
+            }
+            *current = config;
+            true
+        });
+        Ok(())
+    }
+
+    /// Returns a receiver that is notified when the config changes.
+    pub fn subscribe(&self) -> watch::Receiver<RuntimeConfig> {
+        self.tx.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use log::LevelFilter;
+
+    use super::SharedRuntimeConfig;
+
+    #[test]
+    fn test_set() {
+        let config = SharedRuntimeConfig::default();
+        config.set("log_level", "warn").unwrap();
+        config.set("kill_timeout", "5s").unwrap();
+        config.set("output_buffer_size", "65536").unwrap();
+        config.set("read_buffer_size", "16384").unwrap();
+        config.set("max_concurrent_spawns", "4").unwrap();
+        config.set("max_replicas", "2").unwrap();
+
+        let current = config.get();
+        assert_eq!(current.log_level, LevelFilter::Warn);
+        assert_eq!(current.kill_timeout, Duration::from_secs(5));
+        assert_eq!(current.output_buffer_size, 65536);
+        assert_eq!(current.read_buffer_size, 16384);
+        assert_eq!(current.max_concurrent_spawns, 4);
+        assert_eq!(current.max_replicas, 2);
+        assert_eq!(current.get("kill_timeout").unwrap(), "5000ms");
+    }
+
+    #[test]
+    fn test_set_invalid() {
+        let config = SharedRuntimeConfig::default();
+        assert!(config.set("log_level", "loud").is_err());
+        assert!(config.set("rotation_interval", "0s").is_err());
+        assert!(config.set("output_buffer_size", "0").is_err());
+        assert!(config.set("read_buffer_size", "0").is_err());
+        assert!(config.set("read_buffer_size", "262145").is_err());
+        assert!(config.set("max_concurrent_spawns", "-1").is_err());
+        assert!(config.set("unknown", "1").is_err());
+        assert_eq!(config.get(), Default::default());
+    }
+}
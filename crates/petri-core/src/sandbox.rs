@@ -0,0 +1,446 @@
+#[cfg(target_os = "linux")]
+use std::ffi::CStr;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::path::PathBuf;
+
+use thiserror::Error;
+use tokio::process::Command;
+
+/// Opt-in sandbox settings applied to a process when it's spawned.
+///
+/// All the settings are only supported on Linux, and some of them
+/// require either root privileges or unprivileged user namespaces.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct SandboxOptions {
+    /// Prevents the process from gaining privileges via `execve`.
+    pub no_new_privs: bool,
+    /// Mounts a private, empty tmpfs at `/tmp`.
+    pub private_tmp: bool,
+    /// Runs the process in a new network namespace with no interfaces
+    /// other than loopback.
+    pub isolate_network: bool,
+    /// Remounts the root mount as read-only.
+    pub read_only_root: bool,
+    /// Changes the root directory of the process to the given path.
+    pub rootfs: Option<PathBuf>,
+    /// Bind-mounts the cwd into the same path under `rootfs`, or in the
+    /// container of the process.
+    pub bind_cwd: bool,
+}
+
+/// A single step of setting up the sandbox, used to report precisely
+/// which one has failed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum SandboxStep {
+    NoNewPrivs = 1,
+    CreateNamespaces,
+    MapIds,
+    PrivateMounts,
+    ReadOnlyRoot,
+    PrivateTmp,
+    BindCwd,
+    Chroot,
+}
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum SandboxError {
+    #[error("sandboxing is not supported on this platform")]
+    Unsupported,
+    #[error("invalid rootfs: {0}")]
+    InvalidRootfs(String),
+    #[error("failed to {step} for the sandbox: {source} ({})", step.hint())]
+    Setup {
+        step: SandboxStep,
+        #[source]
+        source: io::Error,
+    },
+}
+
+impl SandboxOptions {
+    /// Returns `true` if any of the settings is turned on.
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.no_new_privs || self.needs_namespaces()
+    }
+
+    #[inline]
+    fn needs_namespaces(&self) -> bool {
+        self.needs_mount_namespace() || self.isolate_network || self.rootfs.is_some()
+    }
+
+    #[inline]
+    fn needs_mount_namespace(&self) -> bool {
+        self.private_tmp || self.read_only_root || self.bind_cwd
+    }
+}
+
+impl SandboxStep {
+    fn from_raw(raw: u8) -> Option<Self> {
+        Some(match raw {
+            1 => Self::NoNewPrivs,
+            2 => Self::CreateNamespaces,
+            3 => Self::MapIds,
+            4 => Self::PrivateMounts,
+            5 => Self::ReadOnlyRoot,
+            6 => Self::PrivateTmp,
+            7 => Self::BindCwd,
+            8 => Self::Chroot,
+            _ => return None,
+        })
+    }
+
+    fn hint(&self) -> &'static str {
+        match self {
+            Self::NoNewPrivs => "requires Linux 3.5 or later",
+            Self::CreateNamespaces | Self::MapIds => {
+                "requires root or unprivileged user namespaces to be enabled"
+            }
+            Self::PrivateMounts | Self::ReadOnlyRoot | Self::PrivateTmp | Self::BindCwd => {
+                "requires CAP_SYS_ADMIN in the sandbox namespace"
+            }
+            Self::Chroot => {
+                "requires root, CAP_SYS_CHROOT or unprivileged user namespaces to be enabled"
+            }
+        }
+    }
+}
+
+impl Display for SandboxStep {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NoNewPrivs => "set no_new_privs",
+            Self::CreateNamespaces => "create namespaces",
+            Self::MapIds => "map user and group ids",
+            Self::PrivateMounts => "make mounts private",
+            Self::ReadOnlyRoot => "remount the root as read-only",
+            Self::PrivateTmp => "mount a private /tmp",
+            Self::BindCwd => "bind the cwd into the rootfs",
+            Self::Chroot => "change the root directory",
+        })
+    }
+}
+
+/// Keeps the resources needed to figure out which step failed if the
+/// process could not be spawned.
+pub(crate) struct SpawnGuard {
+    #[cfg(target_os = "linux")]
+    status_rx: std::os::fd::OwnedFd,
+    #[cfg(target_os = "linux")]
+    status_tx: Option<std::os::fd::OwnedFd>,
+}
+
+impl SpawnGuard {
+    /// Converts the error returned by `spawn` into a [`SandboxError`]
+    /// if the failure happened while setting up the sandbox.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn map_spawn_error(mut self, err: io::Error) -> anyhow::Error {
+        use std::os::fd::AsRawFd;
+
+        // Close our write end so that reading won't block when the child
+        // didn't report anything.
+        drop(self.status_tx.take());
+
+        let mut raw_step = 0u8;
+        let res = unsafe {
+            libc::read(
+                self.status_rx.as_raw_fd(),
+                &mut raw_step as *mut u8 as *mut libc::c_void,
+                1,
+            )
+        };
+        match SandboxStep::from_raw(raw_step).filter(|_| res == 1) {
+            Some(step) => SandboxError::Setup { step, source: err }.into(),
+            None => err.into(),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn map_spawn_error(self, err: io::Error) -> anyhow::Error {
+        err.into()
+    }
+}
+
+/// Configures the command to apply the sandbox settings in the child
+/// process before executing the program.
+///
+/// Returns `None` if no sandbox setting is turned on.
+#[cfg(target_os = "linux")]
+pub(crate) fn prepare(
+    command: &mut Command,
+    opts: &SandboxOptions,
+    cwd: &str,
+) -> Result<Option<SpawnGuard>, SandboxError> {
+    use std::ffi::CString;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+
+    if !opts.is_enabled() {
+        return Ok(None);
+    }
+
+    validate_rootfs(opts, cwd)?;
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(SandboxError::Setup {
+            step: SandboxStep::CreateNamespaces,
+            source: io::Error::last_os_error(),
+        });
+    }
+    let (status_rx, status_tx) =
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+    // Everything that needs allocation is prepared here, since only
+    // async-signal-safe operations are allowed after forking.
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let to_c_string = |path: &std::path::Path| CString::new(path.as_os_str().as_bytes()).ok();
+    let ctx = ChildContext {
+        opts: opts.clone(),
+        use_user_ns: uid != 0,
+        uid_map: CString::new(format!("{uid} {uid} 1")).unwrap(),
+        gid_map: CString::new(format!("{gid} {gid} 1")).unwrap(),
+        rootfs: opts.rootfs.as_deref().and_then(to_c_string),
+        cwd: CString::new(cwd)
+            .map_err(|_| SandboxError::InvalidRootfs("the cwd contains a nul byte".to_owned()))?,
+        cwd_in_rootfs: opts
+            .rootfs
+            .as_deref()
+            .and_then(|rootfs| to_c_string(&rootfs.join(cwd.trim_start_matches('/')))),
+    };
+    let raw_status_tx = status_tx.as_raw_fd();
+
+    unsafe {
+        command.pre_exec(move || {
+            let res = setup_in_child(&ctx);
+            if let Err((step, err)) = res {
+                let raw_step = step as u8;
+                libc::write(
+                    raw_status_tx,
+                    &raw_step as *const u8 as *const libc::c_void,
+                    1,
+                );
+                return Err(err);
+            }
+            Ok(())
+        });
+    }
+
+    Ok(Some(SpawnGuard {
+        status_rx,
+        status_tx: Some(status_tx),
+    }))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn prepare(
+    _command: &mut Command,
+    opts: &SandboxOptions,
+    _cwd: &str,
+) -> Result<Option<SpawnGuard>, SandboxError> {
+    if opts.is_enabled() {
+        return Err(SandboxError::Unsupported);
+    }
+    Ok(None)
+}
+
+#[cfg(target_os = "linux")]
+fn validate_rootfs(opts: &SandboxOptions, cwd: &str) -> Result<(), SandboxError> {
+    let Some(rootfs) = &opts.rootfs else {
+        if opts.bind_cwd {
+            return Err(SandboxError::InvalidRootfs(
+                "binding the cwd requires a rootfs".to_owned(),
+            ));
+        }
+        return Ok(());
+    };
+
+    let display = rootfs.display();
+    if !rootfs.is_absolute() {
+        return Err(SandboxError::InvalidRootfs(format!(
+            "`{display}` is not an absolute path"
+        )));
+    }
+    if !rootfs.is_dir() {
+        return Err(SandboxError::InvalidRootfs(format!(
+            "`{display}` is not a directory"
+        )));
+    }
+    if rootfs.canonicalize().ok().as_deref() == Some(std::path::Path::new("/")) {
+        return Err(SandboxError::InvalidRootfs(
+            "the host root cannot be used as rootfs".to_owned(),
+        ));
+    }
+    if opts.bind_cwd && !rootfs.join(cwd.trim_start_matches('/')).is_dir() {
+        return Err(SandboxError::InvalidRootfs(format!(
+            "`{cwd}` must exist as a directory in `{display}` to bind the cwd"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+struct ChildContext {
+    opts: SandboxOptions,
+    use_user_ns: bool,
+    uid_map: std::ffi::CString,
+    gid_map: std::ffi::CString,
+    rootfs: Option<std::ffi::CString>,
+    cwd: std::ffi::CString,
+    cwd_in_rootfs: Option<std::ffi::CString>,
+}
+
+#[cfg(target_os = "linux")]
+fn setup_in_child(ctx: &ChildContext) -> Result<(), (SandboxStep, io::Error)> {
+    use std::ptr::null;
+
+    fn check(step: SandboxStep, res: libc::c_int) -> Result<(), (SandboxStep, io::Error)> {
+        if res != 0 {
+            return Err((step, io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    unsafe fn write_file(path: &CStr, contents: &[u8]) -> libc::c_int {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY);
+        if fd < 0 {
+            return -1;
+        }
+        let written = libc::write(fd, contents.as_ptr() as *const libc::c_void, contents.len());
+        libc::close(fd);
+        if written != contents.len() as isize {
+            return -1;
+        }
+        0
+    }
+
+    unsafe fn bind_mount(source: &CStr, target: &CStr) -> libc::c_int {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            null(),
+            libc::MS_BIND | libc::MS_REC,
+            null(),
+        )
+    }
+
+    let opts = &ctx.opts;
+    unsafe {
+        if opts.no_new_privs {
+            check(
+                SandboxStep::NoNewPrivs,
+                libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0),
+            )?;
+        }
+
+        if !opts.needs_namespaces() {
+            return Ok(());
+        }
+
+        let mut flags = 0;
+        if opts.needs_mount_namespace() {
+            flags |= libc::CLONE_NEWNS;
+        }
+        if opts.isolate_network {
+            flags |= libc::CLONE_NEWNET;
+        }
+        if ctx.use_user_ns {
+            flags |= libc::CLONE_NEWUSER;
+        }
+        if flags != 0 {
+            check(SandboxStep::CreateNamespaces, libc::unshare(flags))?;
+        }
+
+        if ctx.use_user_ns {
+            check(
+                SandboxStep::MapIds,
+                write_file(c"/proc/self/setgroups", b"deny"),
+            )?;
+            check(
+                SandboxStep::MapIds,
+                write_file(c"/proc/self/uid_map", ctx.uid_map.to_bytes()),
+            )?;
+            check(
+                SandboxStep::MapIds,
+                write_file(c"/proc/self/gid_map", ctx.gid_map.to_bytes()),
+            )?;
+        }
+
+        if flags & libc::CLONE_NEWNS != 0 {
+            // Stop mount events from propagating back to the parent namespace.
+            check(
+                SandboxStep::PrivateMounts,
+                libc::mount(
+                    null(),
+                    c"/".as_ptr(),
+                    null(),
+                    libc::MS_REC | libc::MS_PRIVATE,
+                    null(),
+                ),
+            )?;
+        }
+
+        if let Some(rootfs) = &ctx.rootfs {
+            if flags & libc::CLONE_NEWNS != 0 {
+                // Make the rootfs a mount point, so that it can be remounted
+                // after changing root.
+                check(SandboxStep::Chroot, bind_mount(rootfs, rootfs))?;
+            }
+            if let (true, Some(cwd_in_rootfs)) = (opts.bind_cwd, &ctx.cwd_in_rootfs) {
+                check(SandboxStep::BindCwd, bind_mount(&ctx.cwd, cwd_in_rootfs))?;
+            }
+            check(SandboxStep::Chroot, libc::chroot(rootfs.as_ptr()))?;
+
+            // The cwd was changed before running this function, and it's
+            // now outside of the new root. Try entering the same path in
+            // the new root, or use the root if it doesn't exist.
+            if libc::chdir(ctx.cwd.as_ptr()) != 0 {
+                check(SandboxStep::Chroot, libc::chdir(c"/".as_ptr()))?;
+            }
+        }
+
+        if opts.read_only_root {
+            // Flags that are locked by the kernel must be preserved when
+            // remounting inside a user namespace.
+            let mut stat: libc::statvfs = std::mem::zeroed();
+            check(
+                SandboxStep::ReadOnlyRoot,
+                libc::statvfs(c"/".as_ptr(), &mut stat),
+            )?;
+            let mut mount_flags = libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY;
+            for (st_flag, ms_flag) in [
+                (libc::ST_NOSUID, libc::MS_NOSUID),
+                (libc::ST_NODEV, libc::MS_NODEV),
+                (libc::ST_NOEXEC, libc::MS_NOEXEC),
+                (libc::ST_NOATIME, libc::MS_NOATIME),
+                (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+                (libc::ST_RELATIME, libc::MS_RELATIME),
+            ] {
+                if stat.f_flag & st_flag != 0 {
+                    mount_flags |= ms_flag;
+                }
+            }
+            check(
+                SandboxStep::ReadOnlyRoot,
+                libc::mount(null(), c"/".as_ptr(), null(), mount_flags, null()),
+            )?;
+        }
+
+        if opts.private_tmp {
+            check(
+                SandboxStep::PrivateTmp,
+                libc::mount(
+                    c"tmpfs".as_ptr(),
+                    c"/tmp".as_ptr(),
+                    c"tmpfs".as_ptr(),
+                    libc::MS_NOSUID | libc::MS_NODEV,
+                    c"mode=1777".as_ptr() as *const libc::c_void,
+                ),
+            )?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,247 @@
+//! A first-come-first-served queue that limits how many processes are
+//! starting at the same time.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tokio::sync::watch;
+
+use crate::runtime_config::SharedRuntimeConfig;
+
+/// The queue of spawns, limited by `max_concurrent_spawns` in the runtime
+/// config.
+#[derive(Clone)]
+pub(crate) struct SpawnQueue {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    runtime_config: SharedRuntimeConfig,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// The number of permits that are not released yet.
+    running: usize,
+    waiters: VecDeque<Waiter>,
+    next_ticket: u64,
+}
+
+struct Waiter {
+    ticket: u64,
+    /// The 1-based position in the queue, zero once a slot is granted.
+    position_tx: watch::Sender<usize>,
+}
+
+/// A place in the spawn queue. Dropping it leaves the queue.
+pub struct SpawnTicket {
+    queue: SpawnQueue,
+    ticket: u64,
+    position_rx: watch::Receiver<usize>,
+    /// Whether the slot is handed over to a [`SpawnPermit`].
+    converted: bool,
+}
+
+/// A slot to spawn a process, which is given back to the queue when it's
+/// dropped.
+pub struct SpawnPermit {
+    queue: SpawnQueue,
+}
+
+impl SpawnQueue {
+    pub(crate) fn new(runtime_config: SharedRuntimeConfig) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                runtime_config,
+                state: Default::default(),
+            }),
+        }
+    }
+
+    /// Joins the end of the queue, the slot may be granted immediately.
+    pub(crate) fn enqueue(&self) -> SpawnTicket {
+        let mut state = self.inner.state.lock();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        let (position_tx, position_rx) = watch::channel(state.waiters.len() + 1);
+        state.waiters.push_back(Waiter {
+            ticket,
+            position_tx,
+        });
+        self.inner.dispatch(&mut state);
+
+        SpawnTicket {
+            queue: self.clone(),
+            ticket,
+            position_rx,
+            converted: false,
+        }
+    }
+
+    /// Returns the number of spawns waiting for a slot.
+    pub(crate) fn len(&self) -> usize {
+        self.inner.state.lock().waiters.len()
+    }
+}
+
+impl Inner {
+    /// Grants slots to the waiters at the front while the limit allows,
+    /// and tells the rest their new positions.
+    fn dispatch(&self, state: &mut State) {
+        let limit = self.runtime_config.get().max_concurrent_spawns;
+        let mut granted = false;
+        while limit == 0 || state.running < limit {
+            let Some(waiter) = state.waiters.pop_front() else {
+                break;
+            };
+            state.running += 1;
+            _ = waiter.position_tx.send(0);
+            granted = true;
+        }
+        if granted {
+            Self::renumber(state);
+        }
+    }
+
+    fn renumber(state: &State) {
+        for (idx, waiter) in state.waiters.iter().enumerate() {
+            waiter.position_tx.send_if_modified(|position| {
+                let changed = *position != idx + 1;
+                *position = idx + 1;
+                changed
+            });
+        }
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock();
+        state.running -= 1;
+        self.dispatch(&mut state);
+    }
+
+    /// Leaves the queue, returning `false` if the slot is already granted,
+    /// which must then be released.
+    fn cancel(&self, ticket: u64) -> bool {
+        let mut state = self.state.lock();
+        let Some(idx) = state.waiters.iter().position(|w| w.ticket == ticket) else {
+            return false;
+        };
+        state.waiters.remove(idx);
+        Self::renumber(&state);
+        true
+    }
+}
+
+impl SpawnTicket {
+    /// Returns the 1-based position in the queue, or `None` if the slot
+    /// is granted.
+    pub fn position(&self) -> Option<usize> {
+        let position = *self.position_rx.borrow();
+        (position > 0).then_some(position)
+    }
+
+    /// Waits until the position changes or the slot is granted.
+    pub async fn changed(&mut self) {
+        // The sender is only dropped after the slot is granted.
+        _ = self.position_rx.changed().await;
+    }
+
+    /// Waits for the slot to be granted.
+    pub async fn wait(mut self) -> SpawnPermit {
+        while self.position().is_some() {
+            self.changed().await;
+        }
+        self.converted = true;
+        SpawnPermit {
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl Drop for SpawnTicket {
+    fn drop(&mut self) {
+        if self.converted {
+            return;
+        }
+        // The slot may be granted after the position is read, so only the
+        // queue can tell whether the ticket is still waiting.
+        if !self.queue.inner.cancel(self.ticket) {
+            self.queue.inner.release();
+        }
+    }
+}
+
+impl Drop for SpawnPermit {
+    fn drop(&mut self) {
+        self.queue.inner.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+    use std::thread;
+
+    use super::SpawnQueue;
+    use crate::runtime_config::SharedRuntimeConfig;
+
+    #[tokio::test]
+    async fn test_spawn_queue() {
+        let runtime_config = SharedRuntimeConfig::default();
+        runtime_config.set("max_concurrent_spawns", "1").unwrap();
+        let queue = SpawnQueue::new(runtime_config);
+
+        let first = queue.enqueue();
+        let second = queue.enqueue();
+        let third = queue.enqueue();
+        assert_eq!(first.position(), None);
+        assert_eq!(second.position(), Some(1));
+        assert_eq!(third.position(), Some(2));
+
+        // Leaving the queue moves the ones behind forward.
+        drop(second);
+        assert_eq!(third.position(), Some(1));
+        assert_eq!(queue.len(), 1);
+
+        let permit = first.wait().await;
+        assert_eq!(third.position(), Some(1));
+        drop(permit);
+        assert_eq!(third.position(), None);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn test_drop_ticket_while_granted() {
+        let runtime_config = SharedRuntimeConfig::default();
+        runtime_config.set("max_concurrent_spawns", "1").unwrap();
+        let queue = SpawnQueue::new(runtime_config);
+
+        // A ticket granted after its position is read can't be cancelled.
+        let permit = queue.enqueue();
+        let ticket = queue.enqueue();
+        assert_eq!(ticket.position(), Some(1));
+        drop(permit);
+        assert!(!queue.inner.cancel(ticket.ticket));
+        drop(ticket);
+        assert_eq!(queue.inner.state.lock().running, 0);
+
+        for _ in 0..1000 {
+            let permit = queue.enqueue();
+            let ticket = queue.enqueue();
+            let barrier = Barrier::new(2);
+            thread::scope(|scope| {
+                scope.spawn(|| {
+                    barrier.wait();
+                    // Grants the slot to the ticket.
+                    drop(permit);
+                });
+                barrier.wait();
+                drop(ticket);
+            });
+            // Every slot is given back, whichever drop came first.
+            assert_eq!(queue.inner.state.lock().running, 0);
+        }
+    }
+}
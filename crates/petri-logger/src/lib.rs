@@ -1,13 +1,18 @@
+pub mod reader;
 mod sink_thread;
 pub mod writers;
 
 use std::env;
+use std::io::Write;
 use std::path::Path;
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{mpsc, Arc};
+
+use petri_utils::time::{in_zone, ClockWatch};
 
 use sink_thread::{BoxedWriter, SinkThread};
 use writers::file_writer::*;
-use writers::StdWriter;
+use writers::{MemoryWriter, StdWriter};
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct NoFileWriter;
@@ -16,8 +21,15 @@ pub struct NoFileWriter;
 pub struct LoggerBuilder {
     file_writer: Option<FileWriter>,
     std_writer: Option<StdWriter>,
+    memory_writer: Option<MemoryWriter>,
+    custom_writers: Vec<BoxedWriter>,
+    context: Option<LogContext>,
 }
 
+/// Returns the context of the current log line (e.g. the id of the
+/// request being handled), which is written before the message.
+pub type LogContext = fn() -> Option<String>;
+
 impl LoggerBuilder {
     pub fn new() -> Self {
         Self::default()
@@ -54,6 +66,25 @@ impl LoggerBuilder {
         self
     }
 
+    /// Tees the logs into the memory writer, so they can be read back
+    /// while the logger is running.
+    pub fn enable_memory(mut self, writer: MemoryWriter) -> Self {
+        self.memory_writer = Some(writer);
+        self
+    }
+
+    /// Adds a custom sink (e.g. a network forwarder) that all the logs
+    /// are written to.
+    pub fn add_writer(mut self, writer: Box<dyn Write + Send>) -> Self {
+        self.custom_writers.push(writer);
+        self
+    }
+
+    pub fn context(mut self, context: LogContext) -> Self {
+        self.context = Some(context);
+        self
+    }
+
     pub fn build(self) -> Logger {
         let mut writers: Vec<BoxedWriter> = vec![];
 
@@ -67,6 +98,21 @@ impl LoggerBuilder {
             writers.push(std_writer);
         }
 
+        if let Some(memory_writer) = self.memory_writer {
+            writers.push(Box::new(memory_writer));
+        }
+
+        writers.extend(self.custom_writers);
+
+        // Writers of the builder can't be removed, they just take the ids
+        // before the ones of `SinkHandle::add`.
+        let writers: Vec<_> = writers
+            .into_iter()
+            .enumerate()
+            .map(|(idx, writer)| (SinkId(idx as u64), writer))
+            .collect();
+        let next_sink_id = Arc::new(AtomicU64::new(writers.len() as u64));
+
         let (tx, rx) = mpsc::channel();
 
         SinkThread::new(writers, rx).start();
@@ -83,8 +129,11 @@ impl LoggerBuilder {
 
         Logger {
             tx,
+            next_sink_id,
+            context: self.context,
             exec_name,
             pid: std::process::id(),
+            clock: ClockWatch::new(),
         }
     }
 }
@@ -92,12 +141,61 @@ impl LoggerBuilder {
 enum LoggerOp {
     Write(String),
     SyncFlush(mpsc::SyncSender<()>),
+    AddSink(SinkId, BoxedWriter),
+    RemoveSink(SinkId, mpsc::SyncSender<bool>),
 }
 
+/// The id of a sink added with [`SinkHandle::add`].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct SinkId(u64);
+
 pub struct Logger {
     tx: mpsc::Sender<LoggerOp>,
+    next_sink_id: Arc<AtomicU64>,
+    context: Option<LogContext>,
     exec_name: String,
     pid: u32,
+    /// Jumps of the clock are logged, since the times of the lines around
+    /// them are not comparable.
+    clock: ClockWatch,
+}
+
+impl Logger {
+    /// Returns a handle to add and remove sinks while the logger is
+    /// running. Keep it before the logger is installed.
+    pub fn sink_handle(&self) -> SinkHandle {
+        SinkHandle {
+            tx: self.tx.clone(),
+            next_sink_id: Arc::clone(&self.next_sink_id),
+        }
+    }
+}
+
+/// Adds and removes sinks of a running [`Logger`]. The sinks are owned
+/// by the sink thread, so they never race with the writes.
+#[derive(Clone)]
+pub struct SinkHandle {
+    tx: mpsc::Sender<LoggerOp>,
+    next_sink_id: Arc<AtomicU64>,
+}
+
+impl SinkHandle {
+    /// Adds the sink, the logs written after this call are written to it.
+    pub fn add(&self, writer: Box<dyn Write + Send>) -> SinkId {
+        let id = SinkId(self.next_sink_id.fetch_add(1, AtomicOrdering::Relaxed));
+        _ = self.tx.send(LoggerOp::AddSink(id, writer));
+        id
+    }
+
+    /// Flushes and drops the sink, returns `false` if there is no such
+    /// sink. The logs written before this call are all written to it.
+    pub fn remove(&self, id: SinkId) -> bool {
+        let (tx, rx) = mpsc::sync_channel(1);
+        if self.tx.send(LoggerOp::RemoveSink(id, tx)).is_err() {
+            return false;
+        }
+        rx.recv().unwrap_or(false)
+    }
 }
 
 impl log::Log for Logger {
@@ -107,15 +205,24 @@ impl log::Log for Logger {
     }
 
     fn log(&self, record: &log::Record) {
-        let now = chrono::Local::now();
-        let formatted_now = now.format("%Y-%m-%d %T%:::z");
+        let (now, jump) = self.clock.read();
+        let formatted_now = in_zone(&now).format("%Y-%m-%d %T%:::z");
         let exec_name = &self.exec_name;
         let pid = self.pid;
 
+        if let Some(jump) = jump {
+            let message = format!("{formatted_now} {exec_name}[{pid}] W: {jump}\n");
+            _ = self.tx.send(LoggerOp::Write(message));
+        }
+
         // Format and indent the args string.
         let mut args = format!("{}", record.args());
         args = args.replace('\n', "\n\t");
 
+        if let Some(context) = self.context.and_then(|context| context()) {
+            args = format!("[{context}] {args}");
+        }
+
         let message = format!(
             "{formatted_now} {exec_name}[{pid}] {}: {}:{}: {args}\n",
             &record.level().as_str()[0..1],
@@ -135,3 +242,54 @@ impl log::Log for Logger {
         rx.recv().expect("expected a response");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    use log::Log;
+
+    use super::LoggerBuilder;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_runtime_sinks() {
+        let builtin = SharedBuf::default();
+        let logger = LoggerBuilder::new()
+            .add_writer(Box::new(builtin.clone()))
+            .build();
+        let sinks = logger.sink_handle();
+        let log = |msg: &str| {
+            logger.log(&log::Record::builder().args(format_args!("{msg}")).build());
+            logger.flush();
+        };
+
+        log("first");
+        let added = SharedBuf::default();
+        let id = sinks.add(Box::new(added.clone()));
+        log("second");
+        assert!(sinks.remove(id));
+        assert!(!sinks.remove(id));
+        log("third");
+
+        let builtin = String::from_utf8(builtin.0.lock().unwrap().clone()).unwrap();
+        let added = String::from_utf8(added.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(builtin.lines().count(), 3);
+        assert_eq!(added.lines().count(), 1);
+        assert!(added.ends_with(": second\n"));
+    }
+}
@@ -0,0 +1,493 @@
+//! Reading log files that may be too large to load, like the rotated files
+//! of a chatty process.
+//!
+//! Files are read with `pread` into bounded buffers, so the memory stays
+//! flat however large they are. They're not memory-mapped: the files may
+//! be truncated from outside (e.g. by `copytruncate` of logrotate), and
+//! reading a mapping past the new end raises SIGBUS, which would kill the
+//! server. A file that shrinks is read as if it ended there instead.
+
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::time::SystemTime;
+
+use chrono::{DateTime, Local, NaiveDateTime};
+
+/// Lines are only searched for a timestamp in this many bytes, which is
+/// enough for the longest format with nanoseconds and an offset.
+const TIMESTAMP_MAX_LEN: usize = 40;
+
+/// The length of the date and the time without fractions of a second,
+/// like `2024-05-01T18:30:15`.
+const DATE_TIME_LEN: usize = 19;
+
+/// Files are considered to have timestamps if a line in this many bytes
+/// at the beginning starts with one.
+const TIMESTAMP_PROBE_LEN: u64 = 64 * 1024;
+
+/// How many bytes are read at a time when searching for lines.
+const BLOCK_LEN: u64 = 64 * 1024;
+
+/// Contents that are read by offsets, like a file.
+trait Contents {
+    /// Returns the length that the contents are read up to.
+    fn size(&self) -> u64;
+
+    /// Reads from the offset, returning how many bytes are read, which is
+    /// fewer than the buffer only at the end.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+}
+
+/// A log file with the length it has when it's opened.
+struct LogFile {
+    file: File,
+    len: u64,
+}
+
+impl LogFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Self { file, len })
+    }
+}
+
+impl Contents for LogFile {
+    #[inline]
+    fn size(&self) -> u64 {
+        self.len
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let max_len = self.len.saturating_sub(offset).min(buf.len() as u64) as usize;
+        let mut read = 0;
+        while read < max_len {
+            match self
+                .file
+                .read_at(&mut buf[read..max_len], offset + read as u64)
+            {
+                // The file is truncated.
+                Ok(0) => break,
+                Ok(len) => read += len,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl Contents for [u8] {
+    #[inline]
+    fn size(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let rest = self.get(offset as usize..).unwrap_or_default();
+        let len = rest.len().min(buf.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        Ok(len)
+    }
+}
+
+/// Reads the log files of a process as one, from the oldest file.
+pub struct HistoryReader {
+    files: Vec<LogFile>,
+    /// The file and the offset in it to read from.
+    file: usize,
+    offset: u64,
+    /// The chunk handed out last.
+    buf: Vec<u8>,
+}
+
+impl HistoryReader {
+    /// Opens the files, which are read in the order they were modified.
+    pub fn open(paths: &[PathBuf]) -> io::Result<Self> {
+        let mut paths: Vec<_> = paths
+            .iter()
+            .map(|path| {
+                let modified = fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .unwrap_or(SystemTime::UNIX_EPOCH);
+                (modified, path)
+            })
+            .collect();
+        paths.sort();
+
+        let mut files = Vec::with_capacity(paths.len());
+        for (_, path) in paths {
+            match LogFile::open(path) {
+                Ok(file) => files.push(file),
+                // Old files may be removed in the meantime.
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(Self {
+            files,
+            file: 0,
+            offset: 0,
+            buf: vec![],
+        })
+    }
+
+    /// Moves to the start of the last `lines` lines, or the beginning if
+    /// there are fewer.
+    pub fn seek_tail(&mut self, lines: usize) -> io::Result<()> {
+        (self.file, self.offset) = (0, 0);
+        let mut remaining = lines;
+        for (idx, file) in self.files.iter().enumerate().rev() {
+            let (offset, found) = tail_start(file, remaining)?;
+            remaining -= found;
+            if remaining == 0 {
+                (self.file, self.offset) = (idx, offset);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves to the first line with a timestamp (as a Unix timestamp in
+    /// seconds) at or after `since`. Returns `false` without moving if the
+    /// lines don't start with timestamps.
+    pub fn seek_time(&mut self, since: i64) -> io::Result<bool> {
+        let mut has_any = false;
+        for file in &self.files {
+            if has_timestamps(file)? {
+                has_any = true;
+                break;
+            }
+        }
+        if !has_any {
+            return Ok(false);
+        }
+
+        // The files are in order, so the line is in the first file that
+        // has one.
+        for (idx, file) in self.files.iter().enumerate() {
+            let offset = seek_time(file, since)?;
+            if offset < file.len {
+                (self.file, self.offset) = (idx, offset);
+                return Ok(true);
+            }
+        }
+        (self.file, self.offset) = (self.files.len(), 0);
+        Ok(true)
+    }
+
+    /// Returns the next chunk of at most `max_len` bytes, which ends at
+    /// the end of a line unless the line is longer, or `None` at the end.
+    pub fn next_chunk(&mut self, max_len: usize) -> io::Result<Option<&[u8]>> {
+        loop {
+            let Some(file) = self.files.get(self.file) else {
+                return Ok(None);
+            };
+            let remaining = file.len.saturating_sub(self.offset);
+            if remaining > 0 {
+                self.buf.resize(remaining.min(max_len as u64) as usize, 0);
+                let read = file.read_at(&mut self.buf, self.offset)?;
+                if read > 0 {
+                    let mut len = read;
+                    if (len as u64) < remaining {
+                        if let Some(newline) = self.buf[..len].iter().rposition(|b| *b == b'\n') {
+                            len = newline + 1;
+                        }
+                    }
+                    self.offset += len as u64;
+                    return Ok(Some(&self.buf[..len]));
+                }
+                // The file is truncated, so it ends here.
+            }
+            (self.file, self.offset) = (self.file + 1, 0);
+        }
+    }
+}
+
+/// Returns the offset of the last `lines` lines, and how many lines
+/// there are from it, which is fewer if the contents don't have enough.
+fn tail_start<C: Contents + ?Sized>(contents: &C, lines: usize) -> io::Result<(u64, usize)> {
+    let len = contents.size();
+    if lines == 0 || len == 0 {
+        return Ok((len, 0));
+    }
+
+    let mut buf = vec![0; BLOCK_LEN as usize];
+    let mut found = 0;
+    // The newline that ends the last line doesn't start another one.
+    let mut end = len - 1;
+    while end > 0 {
+        let start = end.saturating_sub(BLOCK_LEN);
+        let read = contents.read_at(&mut buf[..(end - start) as usize], start)?;
+        for (idx, b) in buf[..read].iter().enumerate().rev() {
+            if *b == b'\n' {
+                found += 1;
+                if found == lines {
+                    return Ok((start + idx as u64 + 1, lines));
+                }
+            }
+        }
+        end = start;
+    }
+    Ok((0, found + 1))
+}
+
+/// Returns the offset of the first line with a timestamp at or after
+/// `since`, or the length if there is none. Lines without a timestamp are
+/// taken as a part of the line before, like the lines of a backtrace.
+///
+/// The timestamps are binary-searched, so they must be in order.
+fn seek_time<C: Contents + ?Sized>(contents: &C, since: i64) -> io::Result<u64> {
+    let (mut lo, mut hi) = (0, contents.size());
+    let mut found = contents.size();
+    while lo < hi {
+        let mid = line_start(contents, lo + (hi - lo) / 2)?;
+        match next_timestamp(contents, mid, hi)? {
+            Some((offset, timestamp)) if timestamp >= since => {
+                found = offset;
+                hi = mid;
+            }
+            Some((offset, _)) => lo = line_end(contents, offset)?,
+            // The lines from `mid` belong to the line before.
+            None => hi = mid,
+        }
+    }
+    Ok(found)
+}
+
+/// Parses the timestamp that the line starts with, like
+/// `2024-05-01T18:30:15.123Z` (RFC 3339) or `2024-05-01 18:30:15+08`
+/// (the logs of the server), as a Unix timestamp in seconds. Times
+/// without an offset are local.
+pub fn line_timestamp(line: &[u8]) -> Option<i64> {
+    if !line.first()?.is_ascii_digit() {
+        return None;
+    }
+    let head = &line[..line.len().min(TIMESTAMP_MAX_LEN)];
+    let head = match str::from_utf8(head) {
+        Ok(head) => head,
+        Err(err) => str::from_utf8(&head[..err.valid_up_to()]).ok()?,
+    };
+
+    // The date and the time may be separated by a space, so the timestamp
+    // ends at the first space after the time.
+    let end = head
+        .get(DATE_TIME_LEN..)
+        .and_then(|rest| rest.find(char::is_whitespace))
+        .map_or(head.len(), |pos| DATE_TIME_LEN + pos);
+    let head = head.get(..end)?;
+
+    for fmt in ["%Y-%m-%dT%H:%M:%S%.f%#z", "%Y-%m-%d %H:%M:%S%.f%#z"] {
+        if let Ok(time) = DateTime::parse_from_str(head, fmt) {
+            return Some(time.timestamp());
+        }
+    }
+    for fmt in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(head, fmt) {
+            return Some(time.and_local_timezone(Local).earliest()?.timestamp());
+        }
+    }
+    None
+}
+
+fn has_timestamps<C: Contents + ?Sized>(contents: &C) -> io::Result<bool> {
+    let probe_len = contents.size().min(TIMESTAMP_PROBE_LEN);
+    Ok(next_timestamp(contents, 0, probe_len)?.is_some())
+}
+
+/// Returns the first line starting in `from..to` with a timestamp.
+fn next_timestamp<C: Contents + ?Sized>(
+    contents: &C,
+    mut from: u64,
+    to: u64,
+) -> io::Result<Option<(u64, i64)>> {
+    let mut head = [0; TIMESTAMP_MAX_LEN];
+    while from < to {
+        let read = contents.read_at(&mut head, from)?;
+        let line = &head[..read];
+        let line = match line.iter().position(|b| *b == b'\n') {
+            Some(newline) => &line[..newline],
+            None => line,
+        };
+        if let Some(timestamp) = line_timestamp(line) {
+            return Ok(Some((from, timestamp)));
+        }
+        from = line_end(contents, from)?;
+    }
+    Ok(None)
+}
+
+/// Returns the start of the line that the offset is in.
+fn line_start<C: Contents + ?Sized>(contents: &C, offset: u64) -> io::Result<u64> {
+    let mut buf = vec![0; BLOCK_LEN.min(offset) as usize];
+    let mut end = offset;
+    while end > 0 {
+        let start = end.saturating_sub(BLOCK_LEN);
+        let read = contents.read_at(&mut buf[..(end - start) as usize], start)?;
+        if let Some(newline) = buf[..read].iter().rposition(|b| *b == b'\n') {
+            return Ok(start + newline as u64 + 1);
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+/// Returns the start of the line after the one at the offset, or the
+/// length if it's the last one.
+fn line_end<C: Contents + ?Sized>(contents: &C, offset: u64) -> io::Result<u64> {
+    let mut buf = vec![0; BLOCK_LEN.min(contents.size().saturating_sub(offset)) as usize];
+    let mut start = offset;
+    while start < contents.size() {
+        let read = contents.read_at(&mut buf, start)?;
+        if let Some(newline) = buf[..read].iter().position(|b| *b == b'\n') {
+            return Ok(start + newline as u64 + 1);
+        }
+        if read < buf.len() {
+            // The contents are truncated.
+            break;
+        }
+        start += read as u64;
+    }
+    Ok(contents.size())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::time::{Duration, SystemTime};
+    use std::{env, process};
+
+    use chrono::DateTime;
+
+    use super::{line_timestamp, seek_time, tail_start, HistoryReader};
+
+    fn timestamp(s: &str) -> i64 {
+        DateTime::parse_from_rfc3339(s).unwrap().timestamp()
+    }
+
+    #[test]
+    fn test_tail_start() {
+        let tail = |bytes: &[u8], lines| tail_start(bytes, lines).unwrap();
+        assert_eq!(tail(b"a\nbb\nc\n", 2), (2, 2));
+        assert_eq!(tail(b"a\nbb\nc\n", 5), (0, 3));
+        assert_eq!(tail(b"a\nbb\nc\n", 0), (7, 0));
+        assert_eq!(tail(b"a\nbb\n\nc", 2), (5, 2));
+        assert_eq!(tail(b"a\n", 1), (0, 1));
+        assert_eq!(tail(b"", 1), (0, 0));
+    }
+
+    #[test]
+    fn test_line_timestamp() {
+        let at = timestamp("2024-05-01T18:30:15Z");
+        assert_eq!(line_timestamp(b"2024-05-01T18:30:15Z started"), Some(at));
+        assert_eq!(line_timestamp(b"2024-05-01T18:30:15.123Z"), Some(at));
+        assert_eq!(
+            line_timestamp(b"2024-05-01T20:30:15.123456+02:00 x"),
+            Some(at)
+        );
+        assert_eq!(
+            line_timestamp(b"2024-05-02 02:30:15+08 petri[1] I: started"),
+            Some(at)
+        );
+        assert!(line_timestamp(b"2024-05-01 18:30:15 local").is_some());
+        assert_eq!(line_timestamp(b"  at main.rs:1"), None);
+        assert_eq!(line_timestamp(b"42 requests"), None);
+        assert_eq!(line_timestamp(b""), None);
+    }
+
+    #[test]
+    fn test_seek_time() {
+        let contents = "\
+            2024-05-01T10:00:00Z a\n\
+            2024-05-01T11:00:00Z b\n\
+            \x20 continued\n\
+            2024-05-01T12:00:00Z c\n\
+            2024-05-01T12:00:00Z d\n\
+            2024-05-01T13:00:00Z e\n";
+        let bytes = contents.as_bytes();
+        let seek = |time: &str| &contents[seek_time(bytes, timestamp(time)).unwrap() as usize..];
+
+        assert_eq!(seek("2024-05-01T09:00:00Z"), contents);
+        assert!(seek("2024-05-01T11:00:00Z").starts_with("2024-05-01T11:00:00Z b"));
+        assert!(seek("2024-05-01T11:30:00Z").starts_with("2024-05-01T12:00:00Z c"));
+        assert!(seek("2024-05-01T12:00:00Z").starts_with("2024-05-01T12:00:00Z c"));
+        assert_eq!(seek("2024-05-01T13:00:00Z"), "2024-05-01T13:00:00Z e\n");
+        assert_eq!(seek("2024-05-01T14:00:00Z"), "");
+        assert_eq!(seek_time(&b"no\ntimestamps\n"[..], 0).unwrap(), 14);
+    }
+
+    #[test]
+    fn test_history_reader() {
+        let dir = env::temp_dir().join(format!("petri-reader-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let old = dir.join("b.log");
+        let new = dir.join("a.log");
+        fs::write(&old, "2024-05-01T10:00:00Z a\n2024-05-01T11:00:00Z b\n").unwrap();
+        fs::write(&new, "2024-05-01T12:00:00Z c\n2024-05-01T13:00:00Z d\n").unwrap();
+        // Ordered by the time the files are modified, not by names.
+        let modified = SystemTime::now() - Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(&old)
+            .and_then(|file| file.set_modified(modified))
+            .unwrap();
+        let empty = dir.join("c.log");
+        fs::write(&empty, "").unwrap();
+        let paths = [new.clone(), old.clone(), empty];
+
+        let read_all = |reader: &mut HistoryReader, max_len| {
+            let mut chunks = vec![];
+            while let Some(chunk) = reader.next_chunk(max_len).unwrap() {
+                chunks.push(String::from_utf8(chunk.to_vec()).unwrap());
+            }
+            chunks
+        };
+
+        let mut reader = HistoryReader::open(&paths).unwrap();
+        let chunks = read_all(&mut reader, 30);
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks[0].starts_with("2024-05-01T10:00:00Z a"));
+        assert_eq!(chunks.concat().lines().count(), 4);
+
+        // Chunks are cut within long lines only if they must.
+        let mut reader = HistoryReader::open(&paths).unwrap();
+        let chunks = read_all(&mut reader, 10);
+        assert_eq!(chunks[0], "2024-05-01");
+        assert_eq!(chunks.concat().lines().count(), 4);
+
+        let mut reader = HistoryReader::open(&paths).unwrap();
+        reader.seek_tail(3).unwrap();
+        let lines = read_all(&mut reader, 1024).concat();
+        assert!(lines.starts_with("2024-05-01T11:00:00Z b"));
+
+        let mut reader = HistoryReader::open(&paths).unwrap();
+        assert!(reader.seek_time(timestamp("2024-05-01T11:30:00Z")).unwrap());
+        let lines = read_all(&mut reader, 1024).concat();
+        assert!(lines.starts_with("2024-05-01T12:00:00Z c"));
+        let mut reader = HistoryReader::open(&paths).unwrap();
+        assert!(reader.seek_time(timestamp("2024-05-02T00:00:00Z")).unwrap());
+        assert!(reader.next_chunk(1024).unwrap().is_none());
+
+        fs::write(&old, "no timestamps\n").unwrap();
+        fs::write(&new, "at all\n").unwrap();
+        let mut reader = HistoryReader::open(&paths).unwrap();
+        assert!(!reader.seek_time(0).unwrap());
+
+        // Files truncated after they are opened end where they are cut.
+        fs::write(&old, "a\nb\nc\n").unwrap();
+        fs::write(&new, "").unwrap();
+        let mut reader = HistoryReader::open(&paths).unwrap();
+        File::options()
+            .write(true)
+            .open(&old)
+            .and_then(|file| file.set_len(2))
+            .unwrap();
+        reader.seek_tail(2).unwrap();
+        assert_eq!(read_all(&mut reader, 1024), ["a\n"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,17 +2,17 @@ use std::io::Write;
 use std::sync::mpsc;
 use std::thread::{self, JoinHandle};
 
-use super::LoggerOp;
+use super::{LoggerOp, SinkId};
 
 pub type BoxedWriter = Box<dyn Write + Send + 'static>;
 
 pub struct SinkThread {
-    writers: Vec<BoxedWriter>,
+    writers: Vec<(SinkId, BoxedWriter)>,
     rx: mpsc::Receiver<LoggerOp>,
 }
 
 impl SinkThread {
-    pub fn new(writers: Vec<BoxedWriter>, rx: mpsc::Receiver<LoggerOp>) -> Self {
+    pub fn new(writers: Vec<(SinkId, BoxedWriter)>, rx: mpsc::Receiver<LoggerOp>) -> Self {
         Self { writers, rx }
     }
 
@@ -28,18 +28,30 @@ impl SinkThread {
     fn execute_op(&mut self, op: LoggerOp) {
         match op {
             LoggerOp::Write(message) => {
-                for writer in self.writers.iter_mut() {
+                for (_, writer) in self.writers.iter_mut() {
                     // TODO: handle the write error. Maybe we should remove
                     // the bad writer if it failed too many times.
                     _ = writer.write_all(message.as_bytes());
                 }
             }
             LoggerOp::SyncFlush(tx) => {
-                for writer in self.writers.iter_mut() {
+                for (_, writer) in self.writers.iter_mut() {
                     _ = writer.flush();
                 }
                 tx.send(()).expect("waiter released too early");
             }
+            LoggerOp::AddSink(id, writer) => {
+                self.writers.push((id, writer));
+            }
+            LoggerOp::RemoveSink(id, tx) => {
+                let idx = self.writers.iter().position(|(sink_id, _)| *sink_id == id);
+                if let Some(idx) = idx {
+                    let (_, mut writer) = self.writers.remove(idx);
+                    _ = writer.flush();
+                }
+                // The waiter may have given up.
+                _ = tx.send(idx.is_some());
+            }
         }
     }
 }
@@ -1,34 +1,70 @@
+mod rotation;
+mod template;
+
 use std::fs::{self, File};
 use std::io::{self, BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 use std::sync::Arc;
 
-use chrono::{DateTime, Datelike, Local};
+use petri_utils::time::{in_zone, Clock, ClockWatch, SystemClock};
 use thiserror::Error;
 
+pub use rotation::{
+    Composite, Daily, Hourly, RotationCadence, RotationOptions, RotationPolicy, RotationState,
+    SizeBased, Weekly,
+};
+pub use template::{FileNameTemplate, TemplateError, DATE_VAR};
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("the file is not rotated")]
     NotRotated,
     #[error("failed to create file")]
     FailedToCreateFile(io::Error),
+    #[error("variable `{0}` of the file name template is not filled in")]
+    UnboundVariable(String),
 }
 
+type RotationListener = Box<dyn FnMut(&Path) + Send>;
+
 pub struct FileWriter {
     file_path_builder: FilePathBuilder,
+    /// Whether to append to the existing file instead of creating a new
+    /// one, see [`FileWriter::appending`].
+    append: bool,
     active_file: Option<BufWriter<File>>,
+    active_path: Option<PathBuf>,
+    /// The size of the active file.
+    written: u64,
     needs_rotation: Arc<AtomicBool>,
     rotation_driver: Option<Box<dyn RotationDriver>>,
+    rotation_listener: Option<RotationListener>,
 }
 
 impl FileWriter {
     pub fn new(file_path_builder: FilePathBuilder) -> Result<Self, Error> {
+        Self::with_mode(file_path_builder, false)
+    }
+
+    /// Creates a writer that appends to the file at the path if it exists,
+    /// so the path stays the same for writers created one after another
+    /// (e.g. across restarts of a process). The path still changes when
+    /// the file is rotated.
+    pub fn appending(file_path_builder: FilePathBuilder) -> Result<Self, Error> {
+        Self::with_mode(file_path_builder, true)
+    }
+
+    fn with_mode(file_path_builder: FilePathBuilder, append: bool) -> Result<Self, Error> {
         let mut this = Self {
             file_path_builder,
+            append,
             active_file: None,
+            active_path: None,
+            written: 0,
             needs_rotation: Arc::new(AtomicBool::new(false)),
             rotation_driver: None,
+            rotation_listener: None,
         };
         this.try_rotate()?;
         Ok(this)
@@ -45,28 +81,75 @@ impl FileWriter {
         self.rotation_driver = Some(Box::new(driver));
     }
 
+    /// Sets the callback invoked with the path of the previous file after
+    /// the writer rotates away from it, once the file is flushed.
+    pub fn set_rotation_listener<F>(&mut self, listener: F)
+    where
+        F: FnMut(&Path) + Send + 'static,
+    {
+        self.rotation_listener = Some(Box::new(listener));
+    }
+
+    /// Returns the path of the file being written.
+    #[inline]
+    pub fn active_path(&self) -> Option<&Path> {
+        self.active_path.as_deref()
+    }
+
     #[cold]
     pub fn try_rotate(&mut self) -> Result<(), Error> {
         // If there is already an active file, we need to rotate the file
         // path first. Otherwise we can create the file directly.
-        if self.active_file.is_some() && !self.file_path_builder.rotate_if_needed() {
+        if self.active_file.is_some() && !self.file_path_builder.rotate_if_needed(self.written) {
             return Err(Error::NotRotated);
         }
+        self.open_next()
+    }
+
+    /// Moves on to a new file regardless of the rotation policy, like when
+    /// the old files are to be removed.
+    #[cold]
+    pub fn rotate(&mut self) -> Result<(), Error> {
+        self.open_next()
+    }
 
+    fn open_next(&mut self) -> Result<(), Error> {
         let mut last_io_error = None;
         // A heuristic approach to avoid infinite failure loop.
         for _ in 0..100 {
             let path = self.file_path_builder.make_path();
-            match fs::OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(path)
-            {
-                Ok(file) => {
+            if let Some(parent) = path.parent() {
+                if let Err(err) = fs::create_dir_all(parent) {
+                    last_io_error = Some(err);
+                    continue;
+                }
+            }
+
+            // Appending writers reuse the first file that is not full.
+            let mut options = fs::OpenOptions::new();
+            if self.append {
+                options.append(true).create(true);
+            } else {
+                options.write(true).create_new(true);
+            }
+            let opened = options.open(&path).and_then(|file| {
+                let len = file.metadata()?.len();
+                Ok((file, len))
+            });
+            match opened {
+                Ok((_, len)) if self.file_path_builder.should_rotate(len) => {}
+                Ok((file, len)) => {
                     let writer = BufWriter::new(file);
                     if let Some(mut old_file) = self.active_file.replace(writer) {
                         _ = old_file.flush();
                     }
+                    let old_path = self.active_path.replace(path);
+                    if let (Some(old_path), Some(listener)) =
+                        (old_path, self.rotation_listener.as_mut())
+                    {
+                        listener(&old_path);
+                    }
+                    self.written = len;
                     return Ok(());
                 }
                 Err(err) => {
@@ -75,7 +158,9 @@ impl FileWriter {
             };
         }
 
-        Err(Error::FailedToCreateFile(last_io_error.unwrap()))
+        Err(Error::FailedToCreateFile(last_io_error.unwrap_or_else(
+            || io::Error::other("all the files are full"),
+        )))
     }
 }
 
@@ -95,16 +180,20 @@ impl Write for FileWriter {
         if self
             .needs_rotation
             .fetch_and(false, AtomicOrdering::Relaxed)
+            || self.file_path_builder.should_rotate(self.written)
         {
             // We can just ignore the error since it keeps the
             // active file unchanged if an error occurred.
             _ = self.try_rotate();
         }
 
-        self.active_file
+        let len = self
+            .active_file
             .as_mut()
             .expect("expected an active file")
-            .write(buf)
+            .write(buf)?;
+        self.written += len as u64;
+        Ok(len)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -117,9 +206,11 @@ impl Write for FileWriter {
 
 pub struct FilePathBuilder {
     base_path: PathBuf,
-    prefix: String,
-    ext: String,
-    last_date: DateTime<Local>,
+    template: FileNameTemplate,
+    policy: Box<dyn RotationPolicy>,
+    clock: ClockWatch<Box<dyn Clock>>,
+    /// The rendered `{date}` of the current period.
+    period: String,
     conflict_counter: u64,
 }
 
@@ -128,45 +219,95 @@ impl FilePathBuilder {
     where
         P: AsRef<Path>,
     {
+        Self::with_parts(base_path, FileNameTemplate::with_prefix(prefix, ext))
+    }
+
+    /// Creates the builder with a template of paths relative to the base
+    /// path, whose variables other than `{date}` are all filled in.
+    pub fn with_template<P>(base_path: P, template: FileNameTemplate) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        if let Some(name) = template.variables().find(|name| *name != DATE_VAR) {
+            return Err(Error::UnboundVariable(name.to_owned()));
+        }
+        Ok(Self::with_parts(base_path, template))
+    }
+
+    fn with_parts<P>(base_path: P, template: FileNameTemplate) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        let clock = ClockWatch::with_clock(Box::new(SystemClock) as Box<dyn Clock>);
         Self {
             base_path: base_path.as_ref().to_owned(),
-            prefix: prefix.to_owned(),
-            ext: ext.to_owned(),
-            last_date: Local::now(),
+            template,
+            period: Daily.period(&in_zone(&clock.now())),
+            policy: Box::new(Daily),
+            clock,
             conflict_counter: 0,
         }
     }
 
+    /// Sets the clock that the periods are read from, which is the system
+    /// clock by default.
+    pub fn clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = ClockWatch::with_clock(Box::new(clock));
+        self.period = self.policy.period(&in_zone(&self.clock.now()));
+        self
+    }
+
+    /// Sets when the files are rotated, which is daily by default.
+    pub fn rotation_policy<P>(mut self, policy: P) -> Self
+    where
+        P: RotationPolicy + 'static,
+    {
+        self.period = policy.period(&in_zone(&self.clock.now()));
+        self.policy = Box::new(policy);
+        self
+    }
+
     fn make_path(&mut self) -> PathBuf {
-        let date_string = self.last_date.format("%Y%m%d");
-        let suffix = if self.conflict_counter == 0 {
-            format!("-{date_string}")
-        } else {
+        let mut relative_path = self.template.render(&self.period);
+        if self.conflict_counter > 0 {
             let discriminator = self.conflict_counter + 1;
-            format!("-{date_string}-{discriminator}")
-        };
+            relative_path = template::with_discriminator(&relative_path, discriminator);
+        }
         self.conflict_counter += 1;
 
-        let file_name = format!("{}{}.{}", self.prefix, suffix, self.ext);
-
         let mut path = self.base_path.to_owned();
-        path.push(file_name);
+        path.push(relative_path);
 
         path
     }
 
-    fn rotate_if_needed(&mut self) -> bool {
-        let now = Local::now();
-        if self.last_date.day() == now.day()
-            && self.last_date.month() == now.month()
-            && self.last_date.year() == now.year()
-        {
+    /// Returns whether the active file of the size should be rotated.
+    fn should_rotate(&self, size: u64) -> bool {
+        let state = RotationState {
+            period: &self.period,
+            size,
+        };
+        self.policy
+            .should_rotate(&in_zone(&self.clock.now()), &state)
+    }
+
+    /// Moves on to the next path if the active file of the size should be
+    /// rotated. The discriminator is reset when the period changes.
+    fn rotate_if_needed(&mut self, size: u64) -> bool {
+        if !self.should_rotate(size) {
             return false;
         }
 
-        self.last_date = now;
-        self.conflict_counter = 0;
-
+        // Keep the period if the clock is set back, the files are rotated
+        // by size then.
+        let period = self.policy.period(&in_zone(&self.clock.now()));
+        if period > self.period {
+            self.period = period;
+            self.conflict_counter = 0;
+        }
         true
     }
 }
@@ -201,7 +342,13 @@ impl RotationDriver for Arc<dyn RotationDriver> {
 
 #[cfg(test)]
 mod tests {
-    use super::FilePathBuilder;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use chrono::{Local, TimeZone};
+    use petri_utils::time::ManualClock;
+
+    use super::{Composite, FilePathBuilder, Hourly, SizeBased};
 
     #[test]
     fn test_file_path_builder() {
@@ -217,5 +364,48 @@ mod tests {
 
         let path2 = builder.make_path();
         assert_ne!(path1, path2);
+
+        for path in [path1, path2] {
+            let name = path.file_name().unwrap().to_str().unwrap();
+            assert!(builder.template.matches(name));
+        }
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        let mut builder = FilePathBuilder::new("/tmp", "hello", "log")
+            .rotation_policy(Composite::new().with(Hourly).with(SizeBased(1024)));
+        assert!(!builder.rotate_if_needed(1023));
+        assert!(builder.rotate_if_needed(1024));
+
+        let path = builder.make_path();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(builder.template.matches(name));
+    }
+
+    #[test]
+    fn test_rotate_on_clock_jumps() {
+        let start = Local.with_ymd_and_hms(2024, 5, 1, 14, 30, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let mut builder = FilePathBuilder::new("/tmp", "hello", "log")
+            .clock(Arc::clone(&clock))
+            .rotation_policy(Composite::new().with(Hourly).with(SizeBased(1024)));
+        assert_eq!(builder.period, "20240501-14");
+
+        clock.advance(Duration::from_secs(3600));
+        assert!(builder.rotate_if_needed(0));
+        assert_eq!(builder.period, "20240501-15");
+
+        // Setting the clock back doesn't rotate back to the earlier period.
+        clock.set_wall(start);
+        assert!(!builder.rotate_if_needed(0));
+        assert!(builder.rotate_if_needed(1024));
+        assert_eq!(builder.period, "20240501-15");
+
+        // Jumping forward rotates once, skipping the periods in between.
+        clock.set_wall(start + chrono::Duration::hours(5));
+        assert!(builder.rotate_if_needed(0));
+        assert_eq!(builder.period, "20240501-19");
+        assert!(!builder.rotate_if_needed(0));
     }
 }
@@ -0,0 +1,204 @@
+use chrono::{DateTime, FixedOffset};
+
+/// The state of the active file that policies decide on.
+#[derive(Clone, Copy, Debug)]
+pub struct RotationState<'a> {
+    /// The period that the file was created in, see
+    /// [`RotationPolicy::period`].
+    pub period: &'a str,
+    /// The size of the file in bytes.
+    pub size: u64,
+}
+
+/// Decides when the files of a [`FileWriter`] are rotated.
+///
+/// [`FileWriter`]: super::FileWriter
+pub trait RotationPolicy: Send {
+    /// Returns the period that the time is in, which is also filled in
+    /// the `{date}` of file names. Files are named by day by default.
+    fn period(&self, now: &DateTime<FixedOffset>) -> String {
+        now.format(Daily::FORMAT).to_string()
+    }
+
+    /// Returns whether the active file should be rotated.
+    fn should_rotate(&self, now: &DateTime<FixedOffset>, state: &RotationState) -> bool;
+}
+
+impl RotationPolicy for Box<dyn RotationPolicy> {
+    fn period(&self, now: &DateTime<FixedOffset>) -> String {
+        (**self).period(now)
+    }
+
+    fn should_rotate(&self, now: &DateTime<FixedOffset>, state: &RotationState) -> bool {
+        (**self).should_rotate(now, state)
+    }
+}
+
+/// Rotates the files when the period formatted with `FORMAT` moves on.
+/// Periods sort by time, so the files are never rotated back to an earlier
+/// period when the clock is set back.
+macro_rules! periodic_policy {
+    ($(#[$meta:meta])* $name:ident, $format:literal) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Default, Debug)]
+        pub struct $name;
+
+        impl $name {
+            const FORMAT: &'static str = $format;
+        }
+
+        impl RotationPolicy for $name {
+            fn period(&self, now: &DateTime<FixedOffset>) -> String {
+                now.format(Self::FORMAT).to_string()
+            }
+
+            fn should_rotate(&self, now: &DateTime<FixedOffset>, state: &RotationState) -> bool {
+                self.period(now).as_str() > state.period
+            }
+        }
+    };
+}
+
+periodic_policy!(
+    /// Starts a new file every hour, named like `20240501-14`.
+    Hourly,
+    "%Y%m%d-%H"
+);
+periodic_policy!(
+    /// Starts a new file every day, named like `20240501`.
+    Daily,
+    "%Y%m%d"
+);
+periodic_policy!(
+    /// Starts a new file every ISO week, named like `2024W18`.
+    Weekly,
+    "%GW%V"
+);
+
+/// Starts a new file once the active one grows to the size in bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct SizeBased(pub u64);
+
+impl RotationPolicy for SizeBased {
+    fn should_rotate(&self, _now: &DateTime<FixedOffset>, state: &RotationState) -> bool {
+        state.size >= self.0
+    }
+}
+
+/// Rotates the files when any of the policies says so, and names them by
+/// the period of the first one.
+#[derive(Default)]
+pub struct Composite(Vec<Box<dyn RotationPolicy>>);
+
+impl Composite {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with<P>(mut self, policy: P) -> Self
+    where
+        P: RotationPolicy + 'static,
+    {
+        self.0.push(Box::new(policy));
+        self
+    }
+}
+
+impl RotationPolicy for Composite {
+    fn period(&self, now: &DateTime<FixedOffset>) -> String {
+        match self.0.first() {
+            Some(policy) => policy.period(now),
+            None => Daily.period(now),
+        }
+    }
+
+    fn should_rotate(&self, now: &DateTime<FixedOffset>, state: &RotationState) -> bool {
+        self.0.iter().any(|policy| policy.should_rotate(now, state))
+    }
+}
+
+/// How often the files are rotated.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum RotationCadence {
+    Hourly,
+    #[default]
+    Daily,
+    Weekly,
+}
+
+/// When the files are rotated, by time and optionally by size.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct RotationOptions {
+    pub cadence: RotationCadence,
+    /// Rotate the file once it grows to the size in bytes, in addition to
+    /// the cadence.
+    pub max_size: Option<u64>,
+}
+
+impl RotationOptions {
+    /// Creates the policy of the options.
+    pub fn policy(&self) -> Box<dyn RotationPolicy> {
+        let policy = match self.cadence {
+            RotationCadence::Hourly => Composite::new().with(Hourly),
+            RotationCadence::Daily => Composite::new().with(Daily),
+            RotationCadence::Weekly => Composite::new().with(Weekly),
+        };
+        match self.max_size {
+            Some(max_size) => Box::new(policy.with(SizeBased(max_size))),
+            None => Box::new(policy),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Local, TimeZone};
+
+    use super::{Composite, Daily, Hourly, RotationPolicy, RotationState, SizeBased, Weekly};
+
+    #[test]
+    fn test_periodic_policies() {
+        let time = Local
+            .with_ymd_and_hms(2024, 5, 1, 14, 30, 0)
+            .unwrap()
+            .fixed_offset();
+        let later = Local
+            .with_ymd_and_hms(2024, 5, 1, 15, 0, 0)
+            .unwrap()
+            .fixed_offset();
+        let earlier = Local
+            .with_ymd_and_hms(2024, 4, 1, 12, 0, 0)
+            .unwrap()
+            .fixed_offset();
+        let policies: [(&dyn RotationPolicy, &str, bool); 3] = [
+            (&Hourly, "20240501-14", true),
+            (&Daily, "20240501", false),
+            (&Weekly, "2024W18", false),
+        ];
+        for (policy, period, rotates) in policies {
+            assert_eq!(policy.period(&time), period);
+            let state = RotationState { period, size: 0 };
+            assert!(!policy.should_rotate(&time, &state));
+            assert_eq!(policy.should_rotate(&later, &state), rotates);
+            assert!(!policy.should_rotate(&earlier, &state));
+        }
+    }
+
+    #[test]
+    fn test_composite_policy() {
+        let time = Local
+            .with_ymd_and_hms(2024, 5, 1, 14, 30, 0)
+            .unwrap()
+            .fixed_offset();
+        let policy = Composite::new().with(Hourly).with(SizeBased(1024));
+        assert_eq!(policy.period(&time), "20240501-14");
+
+        let state = |size| RotationState {
+            period: "20240501-14",
+            size,
+        };
+        assert!(!policy.should_rotate(&time, &state(1023)));
+        assert!(policy.should_rotate(&time, &state(1024)));
+        assert_eq!(SizeBased(1024).period(&time), "20240501");
+    }
+}
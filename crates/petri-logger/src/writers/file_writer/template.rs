@@ -0,0 +1,341 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+/// The variable that is filled in with the date when the file is created.
+pub const DATE_VAR: &str = "date";
+
+#[derive(Error, PartialEq, Eq, Debug)]
+pub enum TemplateError {
+    #[error("the template is empty")]
+    Empty,
+    #[error("the template must be a relative path without `.` or `..`")]
+    InvalidPath,
+    #[error("`{{` is not closed")]
+    UnclosedBrace,
+    #[error("unexpected `}}`")]
+    UnexpectedBrace,
+    #[error("invalid variable name `{0}`")]
+    InvalidVariable(String),
+}
+
+/// A template of log file paths relative to the log directory, like
+/// `{job}/{date}/{program}.log`.
+///
+/// Variables other than `{date}` are filled in with [`bind`] before the
+/// template is used to create files.
+///
+/// [`bind`]: FileNameTemplate::bind
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FileNameTemplate {
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Segment {
+    Literal(String),
+    Variable(String),
+}
+
+impl FileNameTemplate {
+    pub fn parse(s: &str) -> Result<Self, TemplateError> {
+        if s.is_empty() {
+            return Err(TemplateError::Empty);
+        }
+        if s.starts_with('/')
+            || s.split('/')
+                .any(|component| matches!(component, "" | "." | ".."))
+        {
+            return Err(TemplateError::InvalidPath);
+        }
+
+        let mut segments = vec![];
+        let mut rest = s;
+        while !rest.is_empty() {
+            match rest.find(['{', '}']) {
+                Some(idx) if rest.as_bytes()[idx] == b'}' => {
+                    return Err(TemplateError::UnexpectedBrace);
+                }
+                Some(idx) => {
+                    if idx > 0 {
+                        segments.push(Segment::Literal(rest[..idx].to_owned()));
+                    }
+                    let Some(len) = rest[idx..].find('}') else {
+                        return Err(TemplateError::UnclosedBrace);
+                    };
+                    let name = &rest[idx + 1..idx + len];
+                    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_lowercase() || b == b'_')
+                    {
+                        return Err(TemplateError::InvalidVariable(name.to_owned()));
+                    }
+                    segments.push(Segment::Variable(name.to_owned()));
+                    rest = &rest[idx + len + 1..];
+                }
+                None => {
+                    segments.push(Segment::Literal(rest.to_owned()));
+                    break;
+                }
+            }
+        }
+
+        Ok(Self { segments })
+    }
+
+    /// Creates the template of `{prefix}-{date}.{ext}`.
+    pub(super) fn with_prefix(prefix: &str, ext: &str) -> Self {
+        Self {
+            segments: vec![
+                Segment::Literal(format!("{prefix}-")),
+                Segment::Variable(DATE_VAR.to_owned()),
+                Segment::Literal(format!(".{ext}")),
+            ],
+        }
+    }
+
+    /// Returns the names of the variables that are not filled in yet.
+    pub fn variables(&self) -> impl Iterator<Item = &str> {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Variable(name) => Some(name.as_str()),
+            Segment::Literal(_) => None,
+        })
+    }
+
+    /// Fills in the variable with the value. Slashes in the value are
+    /// replaced, so it can't add or escape directories.
+    pub fn bind(mut self, name: &str, value: &str) -> Self {
+        let mut value = value.replace('/', "_");
+        if value.trim_matches('.').is_empty() {
+            value = "_".repeat(value.len().max(1));
+        }
+        for segment in &mut self.segments {
+            if matches!(segment, Segment::Variable(n) if n == name) {
+                *segment = Segment::Literal(value.clone());
+            }
+        }
+        self
+    }
+
+    /// Renders the path with the date, all the other variables must be
+    /// filled in.
+    pub(super) fn render(&self, date: &str) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(s) => s.as_str(),
+                Segment::Variable(_) => date,
+            })
+            .collect()
+    }
+
+    /// Returns the existing files in the directory whose paths match the
+    /// template, sorted by path. See [`matches`] for how they match.
+    ///
+    /// [`matches`]: FileNameTemplate::matches
+    pub fn existing_paths(&self, base_path: &Path) -> io::Result<Vec<PathBuf>> {
+        let depth = self.render("").matches('/').count();
+        let mut paths = vec![];
+        self.collect_paths(base_path, base_path, depth, &mut paths)?;
+        paths.sort();
+        Ok(paths)
+    }
+
+    fn collect_paths(
+        &self,
+        base_path: &Path,
+        dir: &Path,
+        depth: usize,
+        paths: &mut Vec<PathBuf>,
+    ) -> io::Result<()> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            // Directories of other variables may not be created yet.
+            Err(err) if err.kind() == io::ErrorKind::NotFound && dir != base_path => {
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let path = entry.path();
+            if depth > 0 && file_type.is_dir() {
+                self.collect_paths(base_path, &path, depth - 1, paths)?;
+            } else if depth == 0 && file_type.is_file() {
+                let relative_path = path.strip_prefix(base_path).expect("should be under base");
+                if relative_path.to_str().is_some_and(|p| self.matches(p)) {
+                    paths.push(path);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether the relative path is one that a writer with the
+    /// template creates. Variables that are not filled in match any
+    /// value, and `{date}` matches a date.
+    ///
+    /// Paths with a discriminator (like `app-20240501-2.log`) also match,
+    /// so do the ones with another extension appended (e.g. `.log.gz`
+    /// after the file is compressed).
+    pub fn matches(&self, path: &str) -> bool {
+        let mut candidates = vec![path];
+        if let Some((stripped, ext)) = path.rsplit_once('.') {
+            if !ext.contains('/') {
+                candidates.push(stripped);
+            }
+        }
+
+        candidates.into_iter().any(|candidate| {
+            if self.matches_exactly(candidate) {
+                return true;
+            }
+            let Some(without_discriminator) = strip_discriminator(candidate) else {
+                return false;
+            };
+            self.matches_exactly(&without_discriminator)
+        })
+    }
+
+    fn matches_exactly(&self, path: &str) -> bool {
+        fn match_segments(segments: &[Segment], s: &str) -> bool {
+            let Some((segment, rest)) = segments.split_first() else {
+                return s.is_empty();
+            };
+            match segment {
+                Segment::Literal(literal) => s
+                    .strip_prefix(literal.as_str())
+                    .is_some_and(|s| match_segments(rest, s)),
+                Segment::Variable(name) if name == DATE_VAR => {
+                    // Dates of daily, hourly and weekly files.
+                    ["########", "########-##", "####W##"]
+                        .into_iter()
+                        .any(|pattern| {
+                            let matched = s.len() >= pattern.len()
+                                && s.bytes().zip(pattern.bytes()).all(|(b, p)| match p {
+                                    b'#' => b.is_ascii_digit(),
+                                    _ => b == p,
+                                });
+                            matched && match_segments(rest, &s[pattern.len()..])
+                        })
+                }
+                Segment::Variable(_) => {
+                    // Any non-empty value without slashes.
+                    let max_len = s.find('/').unwrap_or(s.len());
+                    (1..=max_len)
+                        .filter(|len| s.is_char_boundary(*len))
+                        .any(|len| match_segments(rest, &s[len..]))
+                }
+            }
+        }
+
+        match_segments(&self.segments, path)
+    }
+}
+
+/// Inserts the discriminator before the extension of the file name.
+pub(super) fn with_discriminator(path: &str, discriminator: u64) -> String {
+    let name_start = path.rfind('/').map_or(0, |idx| idx + 1);
+    match path[name_start..].rfind('.') {
+        Some(idx) => {
+            let (stem, ext) = path.split_at(name_start + idx);
+            format!("{stem}-{discriminator}{ext}")
+        }
+        None => format!("{path}-{discriminator}"),
+    }
+}
+
+/// The reverse of [`with_discriminator`], returns `None` if there is no
+/// discriminator.
+fn strip_discriminator(path: &str) -> Option<String> {
+    let name_start = path.rfind('/').map_or(0, |idx| idx + 1);
+    let (stem, ext) = match path[name_start..].rfind('.') {
+        Some(idx) => path.split_at(name_start + idx),
+        None => (path, ""),
+    };
+    let (stem, discriminator) = stem.rsplit_once('-')?;
+    if discriminator.is_empty() || !discriminator.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(format!("{stem}{ext}"))
+}
+
+impl Display for FileNameTemplate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(s) => f.write_str(s)?,
+                Segment::Variable(name) => write!(f, "{{{name}}}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{with_discriminator, FileNameTemplate, TemplateError};
+
+    #[test]
+    fn test_parse() {
+        let template = FileNameTemplate::parse("{job}/{date}/{program}-{instance}.log").unwrap();
+        assert_eq!(
+            template.variables().collect::<Vec<_>>(),
+            ["job", "date", "program", "instance"]
+        );
+        assert_eq!(
+            template.to_string(),
+            "{job}/{date}/{program}-{instance}.log"
+        );
+
+        let parse = |s| FileNameTemplate::parse(s).unwrap_err();
+        assert_eq!(parse(""), TemplateError::Empty);
+        assert_eq!(parse("/var/{job}.log"), TemplateError::InvalidPath);
+        assert_eq!(parse("../{job}.log"), TemplateError::InvalidPath);
+        assert_eq!(parse("{job.log"), TemplateError::UnclosedBrace);
+        assert_eq!(parse("job}.log"), TemplateError::UnexpectedBrace);
+        assert_eq!(
+            parse("{Job}.log"),
+            TemplateError::InvalidVariable("Job".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_bind_and_render() {
+        let template = FileNameTemplate::parse("{job}/{program}-{date}.log")
+            .unwrap()
+            .bind("job", "../web")
+            .bind("program", "..");
+        assert_eq!(template.render("20240501"), ".._web/__-20240501.log");
+        assert_eq!(
+            with_discriminator(&template.render("20240501"), 2),
+            ".._web/__-20240501-2.log"
+        );
+    }
+
+    #[test]
+    fn test_matches() {
+        let template = FileNameTemplate::parse("{job}/{program}-{pid}-{date}.log")
+            .unwrap()
+            .bind("job", "web");
+        assert!(template.matches("web/app-42-20240501.log"));
+        assert!(template.matches("web/app-42-20240501-3.log"));
+        assert!(template.matches("web/app-42-20240501.log.gz"));
+        assert!(template.matches("web/my-app-42-20240501-3.log.zst"));
+        assert!(template.matches("web/app-42-20240501-14.log"));
+        assert!(template.matches("web/app-42-2024W18-2.log"));
+        assert!(!template.matches("api/app-42-20240501.log"));
+        assert!(!template.matches("web/app-42-2024050.log"));
+        assert!(!template.matches("web/app-42-20240501.txt"));
+        assert!(!template.matches("web/sub/app-42-20240501.log"));
+
+        let template = FileNameTemplate::parse("{program}-{pid}.log")
+            .unwrap()
+            .bind("pid", "42");
+        assert!(template.matches("app-42.log"));
+        assert!(template.matches("app-42-2.log"));
+        assert!(!template.matches("app-43.log"));
+    }
+}
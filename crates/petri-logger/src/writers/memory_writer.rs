@@ -0,0 +1,83 @@
+use std::io::{Result as IoResult, Write};
+use std::sync::{Arc, Mutex};
+
+use petri_utils::subscriber_list::{CancellationToken, SubscriberList};
+use petri_utils::LogBuffer;
+
+pub type MemorySubscriber = Box<dyn Fn(&[u8]) + Send + Sync>;
+
+/// A writer that keeps the most recent logs in a ring buffer, and
+/// forwards new logs to the subscribers.
+///
+/// The writer is cheap to clone, all the clones share the same buffer.
+#[derive(Clone)]
+pub struct MemoryWriter {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    buf: Mutex<LogBuffer>,
+    subscribers: SubscriberList<MemorySubscriber>,
+}
+
+impl MemoryWriter {
+    pub fn with_capacity(cap: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                buf: Mutex::new(LogBuffer::with_capacity(cap)),
+                subscribers: SubscriberList::new(),
+            }),
+        }
+    }
+
+    /// Returns the buffered logs.
+    pub fn contents(&self) -> Vec<u8> {
+        let buf = self.inner.buf.lock().unwrap();
+        let mut contents = Vec::with_capacity(buf.len());
+        buf.with_buffers(|slice| contents.extend(slice));
+        contents
+    }
+
+    /// Returns the buffered logs, and subscribes to the logs written
+    /// afterward.
+    ///
+    /// The subscriber is called on the logger thread, so it should not
+    /// block.
+    pub fn subscribe(
+        &self,
+        subscriber: MemorySubscriber,
+    ) -> (Vec<u8>, CancellationToken<MemorySubscriber>) {
+        // Hold the buffer lock, so no logs will be lost or duplicated
+        // between the snapshot and the subscription.
+        let buf = self.inner.buf.lock().unwrap();
+        let mut contents = Vec::with_capacity(buf.len());
+        buf.with_buffers(|slice| contents.extend(slice));
+        let token = self.inner.subscribers.subscribe(subscriber);
+        drop(buf);
+
+        (contents, token)
+    }
+}
+
+impl Default for MemoryWriter {
+    fn default() -> Self {
+        Self::with_capacity(64 * 1024)
+    }
+}
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let mut log_buf = self.inner.buf.lock().unwrap();
+        log_buf.append(buf);
+        self.inner
+            .subscribers
+            .for_each(|subscriber| subscriber(buf));
+        drop(log_buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
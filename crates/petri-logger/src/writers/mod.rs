@@ -1,4 +1,6 @@
 pub mod file_writer;
+mod memory_writer;
 mod std_writer;
 
+pub use memory_writer::{MemorySubscriber, MemoryWriter};
 pub use std_writer::StdWriter;
@@ -0,0 +1,187 @@
+//! Uploads the rotated log files of jobs to S3-compatible storage, as
+//! objects keyed `{prefix}{jid}/{file name}`.
+//!
+//! Files are uploaded one at a time in the order they're rotated, so the
+//! bandwidth limit applies to all of them. Failed uploads are retried with
+//! exponential backoff.
+
+mod s3;
+
+use std::env;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use aws_credential_types::Credentials;
+use petri_control::env::matches_pattern;
+use petri_control::{ArchivedLog, LogArchive};
+use petri_core::job_mgr::{EventHandler, Handle as JobManagerHandle};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task;
+use tokio::time;
+
+use self::s3::{Bucket, Throttled};
+use crate::config::ArchiveConfig;
+
+/// The longest wait before retrying an upload.
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+pub(crate) struct Archive {
+    bucket: Arc<Bucket>,
+    prefix: String,
+    /// Patterns of the names or ids of the jobs to archive, all if empty.
+    jobs: Vec<String>,
+    bandwidth: Option<u64>,
+    max_retries: u32,
+}
+
+impl Archive {
+    /// Creates the archive of the config, returning `None` if archiving is
+    /// not configured.
+    pub(crate) fn new(config: &ArchiveConfig) -> Result<Option<Self>> {
+        let Some(endpoint) = &config.endpoint else {
+            return Ok(None);
+        };
+        let credential = |value: &Option<String>, var: &str| {
+            value
+                .clone()
+                .or_else(|| env::var(var).ok())
+                .ok_or_else(|| anyhow!("`{var}` is not set"))
+        };
+        let credentials = Credentials::new(
+            credential(&config.access_key_id, "AWS_ACCESS_KEY_ID")?,
+            credential(&config.secret_access_key, "AWS_SECRET_ACCESS_KEY")?,
+            None,
+            None,
+            "petri",
+        );
+        let bucket = Bucket::new(endpoint, &config.region, &config.bucket, credentials)?;
+        Ok(Some(Self {
+            bucket: Arc::new(bucket),
+            prefix: config.prefix.clone(),
+            jobs: config.jobs.clone(),
+            bandwidth: config.bandwidth,
+            max_retries: config.max_retries,
+        }))
+    }
+
+    /// Starts uploading the files rotated by the processes of the selected
+    /// jobs. The uploads stop once the returned handler is dropped.
+    pub(crate) fn spawn_uploader(self: &Arc<Self>, job_mgr_handle: JobManagerHandle) -> Uploader {
+        let (tx, rx) = mpsc::unbounded_channel();
+        task::spawn(Arc::clone(self).upload(rx, job_mgr_handle));
+        Uploader { tx }
+    }
+
+    async fn upload(
+        self: Arc<Self>,
+        mut rx: UnboundedReceiver<(String, PathBuf)>,
+        job_mgr_handle: JobManagerHandle,
+    ) {
+        while let Some((jid, path)) = rx.recv().await {
+            if !self.is_selected(&job_mgr_handle, &jid).await {
+                continue;
+            }
+            let Some(key) = self.object_key(&jid, &path) else {
+                continue;
+            };
+
+            let mut backoff = Duration::from_secs(1);
+            let mut attempt = 0;
+            loop {
+                let upload = {
+                    let this = Arc::clone(&self);
+                    let (path, key) = (path.clone(), key.clone());
+                    move || this.put_file(&path, &key)
+                };
+                let result = task::spawn_blocking(upload).await;
+                match result {
+                    Ok(Ok(())) => {
+                        info!("archived `{}` as `{key}`", path.display());
+                        break;
+                    }
+                    Ok(Err(err)) if attempt < self.max_retries => {
+                        warn!(
+                            "failed to archive `{}`, retrying in {backoff:?}: {err:?}",
+                            path.display()
+                        );
+                    }
+                    Ok(Err(err)) => {
+                        error!("failed to archive `{}`: {err:?}", path.display());
+                        break;
+                    }
+                    Err(err) => {
+                        error!("failed to archive `{}`: {err}", path.display());
+                        break;
+                    }
+                }
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                attempt += 1;
+            }
+        }
+    }
+
+    async fn is_selected(&self, job_mgr_handle: &JobManagerHandle, jid: &str) -> bool {
+        if self.jobs.is_empty() {
+            return true;
+        }
+        let name = job_mgr_handle
+            .job_with_id(jid)
+            .await
+            .map(|job| job.display_name().to_owned());
+        self.jobs.iter().any(|pattern| {
+            matches_pattern(pattern, jid)
+                || name
+                    .as_deref()
+                    .is_some_and(|name| matches_pattern(pattern, name))
+        })
+    }
+
+    fn object_key(&self, jid: &str, path: &Path) -> Option<String> {
+        let name = path.file_name()?.to_str()?;
+        Some(format!("{}{jid}/{name}", self.prefix))
+    }
+
+    fn put_file(&self, path: &Path, key: &str) -> Result<()> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        match self.bandwidth {
+            Some(rate) => self.bucket.put_object(key, Throttled::new(file, rate), len),
+            None => self.bucket.put_object(key, file, len),
+        }
+    }
+}
+
+#[async_trait]
+impl LogArchive for Archive {
+    async fn list(&self, jid: &str) -> Result<Vec<ArchivedLog>> {
+        let prefix = format!("{}{jid}/", self.prefix);
+        let bucket = Arc::clone(&self.bucket);
+        let objects = task::spawn_blocking(move || bucket.list_objects(&prefix)).await??;
+        Ok(objects
+            .into_iter()
+            .map(|object| ArchivedLog {
+                key: object.key,
+                size: object.size,
+                last_modified: object.last_modified,
+            })
+            .collect())
+    }
+}
+
+/// Queues the rotated log files of jobs for uploading.
+pub(crate) struct Uploader {
+    tx: UnboundedSender<(String, PathBuf)>,
+}
+
+impl EventHandler for Uploader {
+    fn handle_log_rotated(&self, _pid: u32, jid: Option<&str>, path: &Path) {
+        if let Some(jid) = jid {
+            _ = self.tx.send((jid.to_owned(), path.to_owned()));
+        }
+    }
+}
@@ -0,0 +1,536 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind as IoErrorKind;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Result;
+use log::LevelFilter;
+use petri_control::env::{EnvPolicy, ListenAddress};
+use petri_core::job_history;
+use petri_utils::id;
+use petri_utils::parse_bytes;
+use petri_utils::time::{parse_duration, parse_nonzero_duration, Zone};
+use serde::{Deserialize, Deserializer};
+
+/// Configuration of the server, loaded from a TOML file.
+#[derive(Deserialize, Default, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// The file the config is loaded from.
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    /// The directory to keep the pidfile of the server in, no pidfile is
+    /// written if it's not set.
+    pub state_dir: Option<PathBuf>,
+    /// The directory to keep the checkpoints of processes in (experimental,
+    /// requires CRIU), checkpoints are disabled if it's not set.
+    pub checkpoint_dir: Option<PathBuf>,
+    /// Logging of the server itself.
+    pub log: LogConfig,
+    /// Shell commands to run on process events.
+    pub hooks: HooksConfig,
+    /// Rhai scripts to run on process events.
+    pub scripts: ScriptsConfig,
+    /// Uploading of rotated log files of jobs to object storage.
+    pub archive: ArchiveConfig,
+    /// Sampling of process resource usage.
+    pub metrics: MetricsConfig,
+    /// Authorization of clients.
+    pub auth: AuthConfig,
+    /// The history of commands run by clients.
+    pub history: HistoryConfig,
+    /// The starts and exits of jobs.
+    pub job_history: JobHistoryConfig,
+    /// The address to listen on and limits of the commands run by clients.
+    pub control: ControlConfig,
+    /// Garbage collection of exited jobs.
+    pub gc: GcConfig,
+    /// Caps on the number of managed processes.
+    pub limits: LimitsConfig,
+    /// Timeouts of the phases of shutting down the server.
+    pub shutdown: ShutdownConfig,
+    /// Detecting when the server stops responding.
+    pub watchdog: WatchdogConfig,
+    /// Which variables of the client environment the processes get.
+    pub env: EnvConfig,
+    /// How ids of jobs are shown to users.
+    pub id_format: IdFormat,
+}
+
+/// How ids of jobs are shown to users. Clients can use either the shown
+/// form or the full digest.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum IdFormat {
+    /// 8 characters of Crockford's base32, like `04hmasw9`.
+    #[default]
+    Base32,
+    /// An adjective and a noun with a short suffix, like `amber-ferret-6h`.
+    Words,
+}
+
+impl IdFormat {
+    /// Makes the format used by all ids of the process.
+    pub fn install(self) {
+        let installed = match self {
+            IdFormat::Base32 => id::set_format(id::Base32Format),
+            IdFormat::Words => id::set_format(id::WordsFormat),
+        };
+        if !installed {
+            warn!("the format of ids is already set, `id_format` is ignored");
+        }
+    }
+}
+
+/// Logging of the server itself.
+#[derive(Deserialize, Default, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// The maximum log level, the build default is used if it's not set.
+    #[serde(deserialize_with = "deserialize_log_level")]
+    pub level: Option<LevelFilter>,
+    /// The zone of the times in logs and names of log files (including the
+    /// ones of processes) and in responses to clients: `local`, `utc` or an
+    /// offset like `+08:00`. Start the server with `TZ` set for named zones.
+    #[serde(deserialize_with = "deserialize_zone")]
+    pub timezone: Zone,
+}
+
+/// Which variables of the client environment the processes get. Patterns
+/// match the names of variables, with `*` matching any characters.
+#[derive(Deserialize, Default, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnvConfig {
+    /// Patterns of the variables accepted from clients (e.g. `LC_*`), all
+    /// are accepted if it's empty.
+    pub allow: Vec<String>,
+    /// Patterns of the variables never accepted from clients (e.g.
+    /// `*_TOKEN`).
+    pub deny: Vec<String>,
+    /// Variables set if the client doesn't set them. `PATH` and `HOME`
+    /// default to the ones of the server.
+    pub defaults: BTreeMap<String, String>,
+}
+
+/// Caps on the number of managed processes, starting more processes
+/// fails beyond them. They are the defaults of the runtime options with
+/// the same names, except `open_files`.
+#[derive(Deserialize, Default, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// How many processes can be managed at the same time.
+    pub max_processes: Option<usize>,
+    /// How many processes of a job, including the instances of a template,
+    /// can run at the same time.
+    pub max_replicas: Option<usize>,
+    /// The limit of open files (`RLIMIT_NOFILE`) the server raises itself
+    /// to, which can't exceed the hard limit. Defaults to the hard limit.
+    /// Starting processes fails when few files are left.
+    pub open_files: Option<u64>,
+}
+
+/// Authorization of clients.
+#[derive(Deserialize, Default, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// The file to persist the tokens, tokens are kept in memory only if
+    /// it's not set.
+    pub tokens_file: Option<PathBuf>,
+    /// Uids of the users that can run read-only commands without a token.
+    pub observers: Vec<u32>,
+}
+
+/// The history of commands run by clients.
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// The file to persist the history, the history is kept in memory only
+    /// if it's not set.
+    pub file: Option<PathBuf>,
+    /// The maximum number of commands to keep.
+    pub capacity: usize,
+}
+
+/// The starts and exits of jobs. The recent events of each job are kept
+/// in memory, and older ones are spilled to a file of the job.
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobHistoryConfig {
+    /// The directory to spill the events to, older events are dropped if
+    /// it's not set.
+    pub dir: Option<PathBuf>,
+    /// The number of events of each job kept in memory.
+    pub capacity: usize,
+    /// Spilled events older than this are removed by the garbage
+    /// collection, and when the server starts.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub retention: Duration,
+}
+
+/// The address to listen on and limits of the commands run by clients.
+/// Commands that stream until the client stops them (e.g. `log -f` and
+/// `wait`) are not limited.
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    /// Where to listen for clients, like `/tmp/petri.sock`, `@petri` (an
+    /// abstract socket on Linux) or `tcp:[::]:7070`. Clients connect to it
+    /// with `PETRI_ADDRESS`. `PETRI_ADDRESS` of the server or the default
+    /// socket is used if it's not set.
+    #[serde(deserialize_with = "deserialize_address")]
+    pub address: Option<ListenAddress>,
+    /// Whether clients run by the processes start a new server if this one
+    /// is not running. Turn it off if the server is run by a supervisor,
+    /// which restarts it instead.
+    pub client_autostart: bool,
+    /// Commands running longer than this are cancelled.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub timeout: Duration,
+    /// A warning is logged for commands running longer than this.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub slow_threshold: Duration,
+    pub lanes: LanesConfig,
+}
+
+/// How many commands of each lane are run at once, the others wait. The
+/// lanes have separate budgets, so read-only commands like `ps` are served
+/// while clients run many others.
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LanesConfig {
+    /// Read-only commands that respond right away, and `stop-server`.
+    pub quick: NonZeroUsize,
+    /// Commands that stream until they are stopped, like `log` and `wait`.
+    pub streaming: NonZeroUsize,
+    /// The other commands, like `run` and `apply`.
+    pub batch: NonZeroUsize,
+}
+
+/// Garbage collection of exited jobs and their log files, also run on
+/// demand by `petri gc`.
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct GcConfig {
+    /// Whether to collect periodically.
+    pub enabled: bool,
+    #[serde(deserialize_with = "deserialize_nonzero_duration")]
+    pub interval: Duration,
+    /// Jobs that exited longer ago than this are removed, except templates
+    /// and autostart jobs.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub job_retention: Duration,
+}
+
+/// Timeouts of the phases of shutting down the server. A phase that
+/// doesn't finish in time is left behind, and the next one starts.
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Whether `SIGTERM` and `SIGINT` shut down the server like
+    /// `stop-server`, instead of killing it. It's set by `--foreground`.
+    pub on_signals: bool,
+    /// Stopping the jobs, dependents first and then by stop priorities.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub jobs_timeout: Duration,
+    /// Stopping the processes that are left, including the ones not
+    /// stopped in time as jobs.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub processes_timeout: Duration,
+    /// Flushing the logs of the server.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub logs_timeout: Duration,
+    /// Waiting for the `stop-server` clients to receive the progress
+    /// before disconnecting them.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub control_timeout: Duration,
+}
+
+/// Detecting when the server stops responding, because something blocks
+/// its runtime. A thread outside of the runtime checks its heartbeats, and
+/// logs what the server was doing when they stop.
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchdogConfig {
+    pub enabled: bool,
+    /// The runtime is considered stalled without heartbeats for this long.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub stall_timeout: Duration,
+}
+
+/// Shell commands to run on process events.
+///
+/// The commands are run with `sh -c`, and the event fields are exposed
+/// as environment variables: `PETRI_EVENT`, `PETRI_PID`, `PETRI_JID`
+/// (empty if the process doesn't belong to a job), `PETRI_EXIT_CODE` and
+/// `PETRI_EXIT_REASON` (`normal`, `OOM`, `stopped` or `idle`, exit
+/// events only).
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksConfig {
+    pub on_process_start: Option<String>,
+    pub on_process_exit: Option<String>,
+    /// Hooks running longer than this are killed.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub timeout: Duration,
+}
+
+/// Rhai scripts to run on process events, see the `scripts` module for
+/// the functions they can define and call. The scripts are loaded again
+/// when the config is reloaded.
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScriptsConfig {
+    /// The directory of the `*.rhai` scripts, no script is run if it's
+    /// not set.
+    pub dir: Option<PathBuf>,
+    /// Scripts handling an event for longer than this are terminated.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub timeout: Duration,
+}
+
+/// Uploading of rotated log files of jobs to S3-compatible storage, as
+/// objects keyed `{prefix}{jid}/{file name}`.
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArchiveConfig {
+    /// The endpoint of the storage, like `https://s3.us-east-1.amazonaws.com`.
+    /// Log files are not archived if it's not set.
+    pub endpoint: Option<String>,
+    pub region: String,
+    pub bucket: String,
+    pub prefix: String,
+    /// Defaults to `AWS_ACCESS_KEY_ID` of the server.
+    pub access_key_id: Option<String>,
+    /// Defaults to `AWS_SECRET_ACCESS_KEY` of the server.
+    pub secret_access_key: Option<String>,
+    /// Patterns of the names or ids of the jobs to archive, with `*`
+    /// matching any characters. All jobs are archived if it's empty.
+    pub jobs: Vec<String>,
+    /// The upload bandwidth in bytes per second (e.g. `1M`), unlimited if
+    /// it's not set. It must not be zero.
+    #[serde(deserialize_with = "deserialize_bandwidth")]
+    pub bandwidth: Option<u64>,
+    /// How many times failed uploads are retried.
+    pub max_retries: u32,
+}
+
+/// Sampling of process resource usage.
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    /// The directory to store the samples, metrics are not recorded if
+    /// it's not set.
+    pub dir: Option<PathBuf>,
+    #[serde(deserialize_with = "deserialize_nonzero_duration")]
+    pub interval: Duration,
+    /// Samples older than this are removed.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub retention: Duration,
+}
+
+impl ServerConfig {
+    /// Loads the config from the given path, or returns the default config
+    /// if the file doesn't exist.
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut config: Self = match fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents)?,
+            Err(err) if err.kind() == IoErrorKind::NotFound => Default::default(),
+            Err(err) => return Err(err.into()),
+        };
+        config.path = Some(path.to_owned());
+        Ok(config)
+    }
+}
+
+impl EnvConfig {
+    pub fn policy(&self) -> EnvPolicy {
+        EnvPolicy {
+            allow: self.allow.clone(),
+            deny: self.deny.clone(),
+            defaults: self.defaults.clone(),
+        }
+    }
+}
+
+impl HooksConfig {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.on_process_start.is_none() && self.on_process_exit.is_none()
+    }
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            on_process_start: None,
+            on_process_exit: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Default for ScriptsConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            region: "us-east-1".to_owned(),
+            bucket: String::new(),
+            prefix: String::new(),
+            access_key_id: None,
+            secret_access_key: None,
+            jobs: vec![],
+            bandwidth: None,
+            max_retries: 5,
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            dir: None,
+            interval: Duration::from_secs(10),
+            retention: Duration::from_secs(2 * 24 * 60 * 60),
+        }
+    }
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            address: None,
+            client_autostart: true,
+            timeout: Duration::from_secs(10 * 60),
+            slow_threshold: Duration::from_secs(30),
+            lanes: LanesConfig::default(),
+        }
+    }
+}
+
+impl Default for LanesConfig {
+    fn default() -> Self {
+        Self {
+            quick: NonZeroUsize::new(64).unwrap(),
+            streaming: NonZeroUsize::new(256).unwrap(),
+            batch: NonZeroUsize::new(32).unwrap(),
+        }
+    }
+}
+
+impl Default for JobHistoryConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            capacity: job_history::DEFAULT_CAPACITY,
+            retention: Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval: Duration::from_secs(60 * 60),
+            job_retention: Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            on_signals: false,
+            jobs_timeout: Duration::from_secs(30),
+            processes_timeout: Duration::from_secs(10),
+            logs_timeout: Duration::from_secs(5),
+            control_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            stall_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            capacity: 1000,
+        }
+    }
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_duration(&s).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_nonzero_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_nonzero_duration(&s).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_bandwidth<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match parse_bytes(&s).map_err(serde::de::Error::custom)? {
+        0 => Err(serde::de::Error::custom(
+            "bandwidth must not be zero, leave it unset for no limit",
+        )),
+        rate => Ok(Some(rate)),
+    }
+}
+
+fn deserialize_log_level<'de, D>(deserializer: D) -> Result<Option<LevelFilter>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse()
+        .map(Some)
+        .map_err(|_| serde::de::Error::custom(format!("invalid log level `{s}`")))
+}
+
+fn deserialize_zone<'de, D>(deserializer: D) -> Result<Zone, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+fn deserialize_address<'de, D>(deserializer: D) -> Result<Option<ListenAddress>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map(Some).map_err(serde::de::Error::custom)
+}
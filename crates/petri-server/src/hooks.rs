@@ -0,0 +1,91 @@
+use std::process::Stdio;
+use std::sync::Arc;
+
+use petri_core::job_mgr::EventHandler;
+use petri_core::process::ExitReason;
+use tokio::process::Command;
+use tokio::task;
+
+use crate::config::HooksConfig;
+
+/// Runs the configured hooks on job manager events.
+///
+/// Every hook runs in its own task, so slow hooks never block the event
+/// delivery, and they are killed once the configured timeout elapses.
+pub(crate) struct HookRunner {
+    config: Arc<HooksConfig>,
+}
+
+impl HookRunner {
+    pub(crate) fn new(config: HooksConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+
+    fn spawn_hook(&self, event: &'static str, cmd: &str, env: Vec<(&'static str, String)>) {
+        let mut command = Command::new("/bin/sh");
+        command
+            .arg("-c")
+            .arg(cmd)
+            .env("PETRI_EVENT", event)
+            .envs(env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true);
+
+        let timeout = self.config.timeout;
+        task::spawn(async move {
+            let mut child = match command.spawn() {
+                Ok(child) => child,
+                Err(err) => {
+                    warn!("failed to run `{event}` hook: {err:?}");
+                    return;
+                }
+            };
+
+            match tokio::time::timeout(timeout, child.wait()).await {
+                Ok(Ok(status)) if status.success() => {}
+                Ok(Ok(status)) => warn!("`{event}` hook exited with {status}"),
+                Ok(Err(err)) => warn!("failed to wait `{event}` hook: {err:?}"),
+                Err(_) => {
+                    warn!("`{event}` hook timed out after {timeout:?}, killing it");
+                    _ = child.kill().await;
+                }
+            }
+        });
+    }
+}
+
+impl EventHandler for HookRunner {
+    fn handle_process_start(&self, pid: u32, jid: Option<&str>) {
+        let Some(cmd) = &self.config.on_process_start else {
+            return;
+        };
+        let env = vec![
+            ("PETRI_PID", pid.to_string()),
+            ("PETRI_JID", jid.unwrap_or_default().to_owned()),
+        ];
+        self.spawn_hook("process_start", cmd, env);
+    }
+
+    fn handle_process_exit(
+        &self,
+        pid: u32,
+        jid: Option<&str>,
+        exit_code: i32,
+        exit_reason: ExitReason,
+    ) {
+        let Some(cmd) = &self.config.on_process_exit else {
+            return;
+        };
+        let env = vec![
+            ("PETRI_PID", pid.to_string()),
+            ("PETRI_JID", jid.unwrap_or_default().to_owned()),
+            ("PETRI_EXIT_CODE", exit_code.to_string()),
+            ("PETRI_EXIT_REASON", exit_reason.as_str().to_owned()),
+        ];
+        self.spawn_hook("process_exit", cmd, env);
+    }
+}
@@ -1,18 +1,55 @@
 #[macro_use]
 extern crate log;
 
+mod archive;
+mod config;
+mod hooks;
+mod reload;
+mod scripts;
+mod shutdown;
+mod state_dir;
+mod watchdog;
+
 use std::future::Future;
 use std::pin::{pin, Pin};
 use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Instant;
 
-use anyhow::Result;
-use parking_lot::Mutex;
-use petri_core::job_mgr::JobManager;
+use anyhow::{Context as _, Result};
+use archive::Archive;
+use parking_lot::{Mutex, RwLock};
+use petri_control::auth::TokenStore;
+use petri_control::checkpoint::CheckpointStore;
+use petri_control::env::{ListenAddress, ServerEnvHook};
+use petri_control::history::{ActiveCommands, CommandHistory};
+use petri_control::lanes::{LaneLimits, Lanes};
+use petri_control::{CommandLimits, ConfigReloader, LONG_VERSION};
+use petri_core::job_history::JobHistory;
+use petri_core::job_mgr::{self, JobManager};
+use petri_core::metrics::{self, MetricsStore};
 use petri_core::process_mgr::ProcessManager;
+use petri_core::runtime_config::RuntimeConfig;
+use petri_logger::writers::MemoryWriter;
 use pin_project_lite::pin_project;
-use tokio::sync::watch;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+
+pub use config::{
+    ArchiveConfig, AuthConfig, HistoryConfig, HooksConfig, LogConfig, MetricsConfig, ScriptsConfig,
+    ServerConfig,
+};
+pub use reload::ConfigLoader;
+use reload::ReloadCoordinator;
+use shutdown::Shutdown;
+use state_dir::ServerStateDir;
+use watchdog::Watchdog;
+
+/// How many shutdown events are kept for `stop-server` clients that fall
+/// behind.
+const SHUTDOWN_PROGRESS_CAPACITY: usize = 256;
 
 pin_project! {
     pub struct Server {
@@ -35,15 +72,189 @@ async fn wait_for_shutdown(mut shutdown_request_rx: watch::Receiver<bool>) {
     }
 }
 
+/// Waits for `SIGTERM` or `SIGINT`, or forever if they can't be listened
+/// to. Listening starts before the returned future is polled.
+fn wait_for_stop_signal() -> impl Future<Output = ()> {
+    let signals = signal(SignalKind::terminate()).and_then(|sigterm| {
+        let sigint = signal(SignalKind::interrupt())?;
+        Ok((sigterm, sigint))
+    });
+    async move {
+        let (mut sigterm, mut sigint) = match signals {
+            Ok(signals) => signals,
+            Err(err) => {
+                warn!("failed to listen to SIGTERM and SIGINT: {err:?}");
+                return std::future::pending().await;
+            }
+        };
+        match biased_select(sigterm.recv(), sigint.recv()).await {
+            Select::First(_) => info!("received SIGTERM"),
+            Select::Second(_) => info!("received SIGINT"),
+        }
+    }
+}
+
+/// Applies the log level in the runtime config whenever it changes.
+fn watch_log_level(mut config_rx: watch::Receiver<RuntimeConfig>) -> JoinHandle<()> {
+    tokio::task::spawn(async move {
+        loop {
+            let log_level = config_rx.borrow_and_update().log_level;
+            log::set_max_level(log_level);
+            if config_rx.changed().await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+/// Reloads the config whenever the server receives `SIGHUP`.
+fn listen_sighup(reload_coordinator: Arc<ReloadCoordinator>) -> Option<JoinHandle<()>> {
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(err) => {
+            warn!("failed to listen to SIGHUP, reloading on signal is disabled: {err:?}");
+            return None;
+        }
+    };
+    Some(tokio::task::spawn(async move {
+        while sighup.recv().await.is_some() {
+            info!("received SIGHUP, reloading the config...");
+            if let Err(err) = reload_coordinator.reload().await {
+                error!("failed to reload the config: {err:?}");
+            }
+        }
+    }))
+}
+
 impl Server {
-    pub fn new() -> Result<Self> {
+    /// Creates the server with the config returned by `config_loader`,
+    /// which is also called when the config is reloaded. `server_logs` is
+    /// the in-memory copy of the server logs that clients can read.
+    pub fn new(config_loader: ConfigLoader, server_logs: Option<MemoryWriter>) -> Result<Self> {
+        let config = config_loader()?;
+        // Ids are shown in this format from now on, so set it before any
+        // job is created.
+        config.id_format.install();
+        let started_at = Instant::now();
         let (shutdown_request_tx, shutdown_request_rx) = watch::channel(false);
         let process_manager = ProcessManager::new();
         let proc_mgr_handle = process_manager.handle();
 
-        let job_manager = JobManager::new(proc_mgr_handle.clone());
+        let job_history = match &config.job_history.dir {
+            Some(dir) => {
+                let job_history = &config.job_history;
+                match JobHistory::with_dir(dir.clone(), job_history.capacity, job_history.retention)
+                {
+                    Ok(history) => history,
+                    Err(err) => {
+                        warn!("failed to open the job history dir, keeping it in memory: {err:?}");
+                        JobHistory::in_memory(job_history.capacity)
+                    }
+                }
+            }
+            None => JobHistory::in_memory(config.job_history.capacity),
+        };
+        // Files of jobs from the previous runs are left to the retention.
+        let pruned_history = job_history.clone();
+        tokio::task::spawn(async move {
+            if let Err(err) = pruned_history.prune().await {
+                warn!("failed to prune the job history: {err:?}");
+            }
+        });
+        let job_manager = JobManager::with_history(proc_mgr_handle.clone(), job_history.clone());
         let job_mgr_handle = job_manager.handle();
 
+        let log_archive = Archive::new(&config.archive)
+            .context("failed to configure the log archive")?
+            .map(Arc::new);
+        let archive_uploader = log_archive.as_ref().map(|archive| {
+            job_mgr_handle.add_event_handler(archive.spawn_uploader(job_mgr_handle.clone()))
+        });
+
+        let metrics_config = config.metrics.clone();
+        let metrics_store = match &metrics_config.dir {
+            Some(dir) if metrics_config.enabled => {
+                match MetricsStore::new(dir.clone(), metrics_config.retention) {
+                    Ok(store) => Some(store),
+                    Err(err) => {
+                        warn!("failed to open the metrics store, metrics are disabled: {err:?}");
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        let token_store = match &config.auth.tokens_file {
+            Some(path) => TokenStore::load(path.clone())
+                .with_context(|| format!("failed to load the tokens from `{}`", path.display()))?,
+            None => TokenStore::in_memory(),
+        };
+        let observer_uids = config.auth.observers.clone();
+        let command_limits = CommandLimits {
+            timeout: config.control.timeout,
+            slow_threshold: config.control.slow_threshold,
+        };
+        let lanes = &config.control.lanes;
+        let lanes = Lanes::new(LaneLimits {
+            quick: lanes.quick.get(),
+            streaming: lanes.streaming.get(),
+            batch: lanes.batch.get(),
+        });
+        let gc_config = config.gc.clone();
+        let shutdown_config = config.shutdown.clone();
+        let listen_address = match &config.control.address {
+            Some(address) => address.clone(),
+            None => ListenAddress::from_env()?,
+        };
+        let state_guard =
+            ServerStateDir::new(config.state_dir.as_deref(), &listen_address).claim()?;
+        process_manager.add_spawn_hook(
+            ServerEnvHook::new(&listen_address).no_autostart(!config.control.client_autostart),
+        );
+        let history = match &config.history.file {
+            Some(path) => match CommandHistory::load(path.clone(), config.history.capacity) {
+                Ok(history) => history,
+                Err(err) => {
+                    warn!("failed to load the command history, starting over: {err:?}");
+                    CommandHistory::in_memory(config.history.capacity)
+                }
+            },
+            None => CommandHistory::in_memory(config.history.capacity),
+        };
+        let history = Arc::new(history);
+        let active_commands = ActiveCommands::default();
+        let watchdog = Watchdog {
+            config: config.watchdog.clone(),
+            proc_mgr_handle: proc_mgr_handle.clone(),
+            active_commands: active_commands.clone(),
+            history: Arc::clone(&history),
+        };
+
+        let checkpoints = config.checkpoint_dir.clone().map(CheckpointStore::new);
+        let env_policy = Arc::new(RwLock::new(config.env.policy()));
+        let paths = [
+            ("config", &config.path),
+            ("state", &config.state_dir),
+            ("checkpoints", &config.checkpoint_dir),
+            ("scripts", &config.scripts.dir),
+            ("metrics", &config.metrics.dir),
+            ("tokens", &config.auth.tokens_file),
+            ("history", &config.history.file),
+            ("job history", &config.job_history.dir),
+        ]
+        .into_iter()
+        .filter_map(|(name, path)| Some((name, path.clone()?)))
+        .collect();
+        let reload_coordinator = Arc::new(ReloadCoordinator::new(
+            config_loader,
+            config,
+            job_mgr_handle.clone(),
+            proc_mgr_handle.runtime_config(),
+            metrics_store.clone(),
+            Arc::clone(&env_policy),
+        ));
+
         // Wrap the process manager into a shared container, because the caller
         // may configure it before the future actually takes it.
         let process_manager = Arc::new(Mutex::new(Some(process_manager)));
@@ -59,38 +270,130 @@ impl Server {
                 .take()
                 .expect("process manager should be present before polling");
 
-            info!("the server is started!");
+            info!("the server {LONG_VERSION} is started!");
+
+            let stop_signal = shutdown_config
+                .on_signals
+                .then(|| Box::pin(wait_for_stop_signal()) as Pin<Box<dyn Future<Output = ()>>>);
+
+            #[cfg(target_os = "linux")]
+            if let Err(err) = process_manager.enable_child_subreaper() {
+                warn!("failed to become the reaper of orphaned processes: {err:?}");
+            }
+
+            let watchdog_guard = watchdog.spawn();
+            let log_level_watcher = watch_log_level(proc_mgr_handle.runtime_config().subscribe());
+            let sighup_listener = listen_sighup(Arc::clone(&reload_coordinator));
+
+            let metrics_recorder = metrics_store.clone().map(|store| {
+                metrics::spawn_recorder(proc_mgr_handle.clone(), store, metrics_config.interval)
+            });
+            let collector = gc_config.enabled.then(|| {
+                job_mgr::spawn_collector(
+                    job_mgr_handle.clone(),
+                    gc_config.interval,
+                    gc_config.job_retention,
+                )
+            });
+
+            let (shutdown_progress, _) = broadcast::channel(SHUTDOWN_PROGRESS_CAPACITY);
+            let shutdown = Shutdown {
+                config: shutdown_config,
+                job_mgr_handle: job_mgr_handle.clone(),
+                process_manager: &process_manager,
+                progress: shutdown_progress.clone(),
+            };
 
             let control_ctx = petri_control::Context {
+                listen_address,
                 proc_mgr_handle,
                 job_mgr_handle,
+                metrics_store,
+                server_logs,
                 shutdown_request: shutdown_request_tx,
+                shutdown_progress,
+                started_at,
+                token_store,
+                observer_uids,
+                history: Arc::clone(&history),
+                active_commands,
+                command_limits,
+                lanes,
+                job_retention: gc_config.job_retention,
+                command_panics: Default::default(),
+                config_reloader: Some(Arc::clone(&reload_coordinator) as _),
+                log_archive: log_archive.map(|archive| archive as _),
+                env_policy,
+                run_locks: Default::default(),
+                checkpoints,
+                paths,
             };
 
             // Always poll the future `wait_for_shutdown` first, because we want
             // to shutdown the server ASAP when the controller requested.
-            let res = biased_select(
+            let mut control = Box::pin(petri_control::run_control_server(control_ctx));
+            let shutdown_requested = biased_select(
                 wait_for_shutdown(shutdown_request_rx),
-                petri_control::run_control_server(control_ctx),
-            )
-            .await;
-            match res {
-                Select::First(_) => {
+                stop_signal.unwrap_or_else(|| Box::pin(std::future::pending())),
+            );
+            let res = biased_select(shutdown_requested, control.as_mut()).await;
+            let control_running = match res {
+                Select::First(Select::First(_)) => {
                     info!("client requested to shutdown the server");
+                    true
                 }
+                Select::First(Select::Second(_)) => true,
                 Select::Second(Err(e)) => {
                     error!("error occurred while running control: {e:?}");
+                    false
                 }
                 _ => unreachable!("this function should not return"),
-            }
+            };
 
             info!("the server is shutting down...");
-            process_manager.shutdown().await;
+            log_level_watcher.abort();
+            if let Some(sighup_listener) = sighup_listener {
+                sighup_listener.abort();
+            }
+            if let Some(metrics_recorder) = metrics_recorder {
+                metrics_recorder.abort();
+            }
+            if let Some(collector) = collector {
+                collector.abort();
+            }
+
+            // Keep serving the clients while shutting down, so they can
+            // follow the progress. The control is stopped after the last
+            // phase.
+            {
+                let mut shutdown = pin!(shutdown.run());
+                if control_running {
+                    match biased_select(shutdown.as_mut(), control.as_mut()).await {
+                        Select::First(_) => {}
+                        Select::Second(Err(e)) => {
+                            error!("error occurred while running control: {e:?}");
+                            shutdown.await;
+                        }
+                        _ => unreachable!("this function should not return"),
+                    }
+                } else {
+                    shutdown.await;
+                }
+            }
+            drop(control);
+            drop(watchdog_guard);
+            // The commands are written to the history in the background.
+            history.flush().await;
 
             // Defer releasing the job manager the make sure that it can
             // handle all the remaining events from the process manager.
+            drop(archive_uploader);
             drop(job_manager);
             drop(process_manager);
+            drop(reload_coordinator);
+            drop(state_guard);
+            // The events are spilled in the background too.
+            job_history.flush().await;
 
             can_drop.store(true, AtomicOrdering::Relaxed);
 
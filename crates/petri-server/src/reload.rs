@@ -0,0 +1,276 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use parking_lot::RwLock;
+use petri_control::env::EnvPolicy;
+use petri_control::{ConfigReloader, ReloadSummary};
+use petri_core::fds;
+use petri_core::job_mgr::{EventHandler, Handle as JobManagerHandle};
+use petri_core::metrics::MetricsStore;
+use petri_core::runtime_config::{RuntimeConfig, SharedRuntimeConfig};
+use petri_utils::subscriber_list::CancellationToken;
+use petri_utils::time;
+use tokio::sync::Mutex;
+
+use crate::config::ServerConfig;
+use crate::hooks::HookRunner;
+use crate::scripts::ScriptRunner;
+
+pub type ConfigLoader = Box<dyn Fn() -> Result<ServerConfig> + Send + Sync>;
+
+/// Loads the config again and applies the changes that are safe to apply
+/// while the server is running.
+pub(crate) struct ReloadCoordinator {
+    loader: ConfigLoader,
+    job_mgr_handle: JobManagerHandle,
+    runtime_config: SharedRuntimeConfig,
+    metrics_store: Option<MetricsStore>,
+    env_policy: Arc<RwLock<EnvPolicy>>,
+    state: Mutex<State>,
+}
+
+struct State {
+    config: ServerConfig,
+    hooks_token: Option<CancellationToken<Box<dyn EventHandler>>>,
+    scripts_token: Option<CancellationToken<Box<dyn EventHandler>>>,
+}
+
+impl ReloadCoordinator {
+    /// Creates the coordinator and applies the initial config.
+    pub(crate) fn new(
+        loader: ConfigLoader,
+        config: ServerConfig,
+        job_mgr_handle: JobManagerHandle,
+        runtime_config: SharedRuntimeConfig,
+        metrics_store: Option<MetricsStore>,
+        env_policy: Arc<RwLock<EnvPolicy>>,
+    ) -> Self {
+        let mut this = Self {
+            loader,
+            job_mgr_handle,
+            runtime_config,
+            metrics_store,
+            env_policy,
+            state: Mutex::new(State {
+                config: Default::default(),
+                hooks_token: None,
+                scripts_token: None,
+            }),
+        };
+
+        let hooks_token = this.make_hooks(&config);
+        let scripts_token = this.make_scripts(&config);
+        this.apply_log_level(&config);
+        time::set_zone(config.log.timezone);
+        this.apply_limits(&config);
+        this.apply_env_policy(&config);
+        *this.state.get_mut() = State {
+            config,
+            hooks_token,
+            scripts_token,
+        };
+
+        this
+    }
+
+    fn make_hooks(
+        &self,
+        config: &ServerConfig,
+    ) -> Option<CancellationToken<Box<dyn EventHandler>>> {
+        (!config.hooks.is_empty()).then(|| {
+            self.job_mgr_handle
+                .add_event_handler(HookRunner::new(config.hooks.clone()))
+        })
+    }
+
+    fn make_scripts(
+        &self,
+        config: &ServerConfig,
+    ) -> Option<CancellationToken<Box<dyn EventHandler>>> {
+        let runner = ScriptRunner::load(&config.scripts, self.job_mgr_handle.clone())?;
+        Some(self.job_mgr_handle.add_event_handler(runner))
+    }
+
+    fn apply_log_level(&self, config: &ServerConfig) {
+        // Go back to the build default once the level is removed.
+        let level = config
+            .log
+            .level
+            .unwrap_or_else(|| RuntimeConfig::default().log_level);
+        let level = level.as_str().to_lowercase();
+        if let Err(err) = self.runtime_config.set("log_level", &level) {
+            warn!("failed to apply the log level: {err:?}");
+        }
+    }
+
+    fn apply_env_policy(&self, config: &ServerConfig) {
+        *self.env_policy.write() = config.env.policy();
+    }
+
+    fn apply_limits(&self, config: &ServerConfig) {
+        let limits = [
+            ("max_processes", config.limits.max_processes),
+            ("max_replicas", config.limits.max_replicas),
+        ];
+        for (key, limit) in limits {
+            let limit = limit.unwrap_or_default().to_string();
+            if let Err(err) = self.runtime_config.set(key, &limit) {
+                warn!("failed to apply `{key}`: {err:?}");
+            }
+        }
+        match fds::raise_limit(config.limits.open_files) {
+            Ok(limit) => debug!("the limit of open files is {limit}"),
+            Err(err) => warn!("failed to raise the limit of open files: {err:?}"),
+        }
+    }
+}
+
+#[async_trait]
+impl ConfigReloader for ReloadCoordinator {
+    async fn reload(&self) -> Result<ReloadSummary> {
+        let new_config = (self.loader)()?;
+
+        let mut state = self.state.lock().await;
+        let summary = diff(&state.config, &new_config);
+
+        if state.config.hooks != new_config.hooks {
+            // Subscribe the new hooks before dropping the old ones, so no
+            // events are missed in between.
+            let hooks_token = self.make_hooks(&new_config);
+            state.hooks_token = hooks_token;
+        }
+        // The scripts may have changed even if the config doesn't.
+        state.scripts_token = self.make_scripts(&new_config);
+        if state.config.metrics.retention != new_config.metrics.retention {
+            if let Some(store) = &self.metrics_store {
+                store.set_retention(new_config.metrics.retention);
+            }
+        }
+        if state.config.log.level != new_config.log.level {
+            self.apply_log_level(&new_config);
+        }
+        if state.config.log.timezone != new_config.log.timezone {
+            time::set_zone(new_config.log.timezone);
+        }
+        if state.config.limits != new_config.limits {
+            self.apply_limits(&new_config);
+        }
+        if state.config.env != new_config.env {
+            self.apply_env_policy(&new_config);
+        }
+
+        // Keep the settings that are not applied, so that they are still
+        // reported until the server restarts.
+        let mut config = new_config;
+        config.metrics.enabled = state.config.metrics.enabled;
+        config.metrics.dir = state.config.metrics.dir.take();
+        config.metrics.interval = state.config.metrics.interval;
+        config.auth = state.config.auth.clone();
+        config.history = state.config.history.clone();
+        config.job_history = state.config.job_history.clone();
+        config.archive = state.config.archive.clone();
+        config.control = state.config.control.clone();
+        config.gc = state.config.gc.clone();
+        config.shutdown = state.config.shutdown.clone();
+        config.watchdog = state.config.watchdog.clone();
+        config.state_dir = state.config.state_dir.take();
+        config.checkpoint_dir = state.config.checkpoint_dir.take();
+        config.id_format = state.config.id_format;
+        state.config = config;
+
+        info!(
+            "config reloaded, applied: {:?}, requires restart: {:?}",
+            summary.applied, summary.requires_restart
+        );
+        Ok(summary)
+    }
+}
+
+fn diff(old: &ServerConfig, new: &ServerConfig) -> ReloadSummary {
+    let mut summary = ReloadSummary::default();
+    let mut check = |changed: bool, name: &str, live: bool| {
+        if !changed {
+            return;
+        }
+        if live {
+            summary.applied.push(name.to_owned());
+        } else {
+            summary.requires_restart.push(name.to_owned());
+        }
+    };
+
+    check(old.state_dir != new.state_dir, "state_dir", false);
+    check(
+        old.checkpoint_dir != new.checkpoint_dir,
+        "checkpoint_dir",
+        false,
+    );
+    check(old.log.level != new.log.level, "log.level", true);
+    check(old.log.timezone != new.log.timezone, "log.timezone", true);
+    check(old.hooks != new.hooks, "hooks", true);
+    check(old.scripts != new.scripts, "scripts", true);
+    check(old.limits != new.limits, "limits", true);
+    check(old.env != new.env, "env", true);
+    check(
+        old.metrics.retention != new.metrics.retention,
+        "metrics.retention",
+        true,
+    );
+    check(
+        old.metrics.enabled != new.metrics.enabled,
+        "metrics.enabled",
+        false,
+    );
+    check(old.metrics.dir != new.metrics.dir, "metrics.dir", false);
+    check(
+        old.metrics.interval != new.metrics.interval,
+        "metrics.interval",
+        false,
+    );
+    check(
+        old.auth.tokens_file != new.auth.tokens_file,
+        "auth.tokens_file",
+        false,
+    );
+    check(
+        old.auth.observers != new.auth.observers,
+        "auth.observers",
+        false,
+    );
+    check(old.history != new.history, "history", false);
+    check(old.job_history != new.job_history, "job_history", false);
+    check(old.archive != new.archive, "archive", false);
+    check(old.control != new.control, "control", false);
+    check(old.gc != new.gc, "gc", false);
+    check(old.shutdown != new.shutdown, "shutdown", false);
+    check(old.watchdog != new.watchdog, "watchdog", false);
+    check(old.id_format != new.id_format, "id_format", false);
+
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use log::LevelFilter;
+
+    use super::diff;
+    use crate::config::ServerConfig;
+
+    #[test]
+    fn test_diff() {
+        let old = ServerConfig::default();
+        assert!(diff(&old, &old).applied.is_empty());
+
+        let mut new = old.clone();
+        new.log.level = Some(LevelFilter::Warn);
+        new.hooks.on_process_exit = Some("true".to_owned());
+        new.metrics.interval = Duration::from_secs(1);
+
+        let summary = diff(&old, &new);
+        assert_eq!(summary.applied, ["log.level", "hooks"]);
+        assert_eq!(summary.requires_restart, ["metrics.interval"]);
+    }
+}
@@ -0,0 +1,454 @@
+//! Rhai scripts that handle job manager events, loaded from the scripts
+//! dir (`~/.petri/scripts` by default).
+//!
+//! A script handles an event by defining a function named after it, which
+//! takes a map of the event fields:
+//!
+//! ```rhai
+//! fn on_process_exit(event) {
+//!     if event.jid != () && event.exit_reason == "OOM" {
+//!         notify(`job ${event.jid} ran out of memory, restarting it`);
+//!         restart_job(event.jid);
+//!     }
+//! }
+//! ```
+//!
+//! Scripts can only call the functions of the control API below and can't
+//! touch files or import modules: `restart_job(job)`, `notify(message)`
+//! and `log(message)`.
+
+use std::cell::Cell;
+use std::fs;
+use std::io::ErrorKind as IoErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use petri_core::job_mgr::{EventHandler, Handle as JobManagerHandle};
+use petri_core::process::{ExitReason, LogUsage};
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Map, NativeCallContext, Scope, AST};
+use tokio::process::Command;
+use tokio::runtime::Handle as RuntimeHandle;
+
+use crate::config::ScriptsConfig;
+
+/// The extension of script files.
+const SCRIPT_EXT: &str = "rhai";
+
+enum Event {
+    ProcessStart {
+        pid: u32,
+        jid: Option<String>,
+    },
+    ProcessExit {
+        pid: u32,
+        jid: Option<String>,
+        exit_code: i32,
+        exit_reason: ExitReason,
+    },
+    LogQuotaExceeded {
+        pid: u32,
+        jid: Option<String>,
+        usage: LogUsage,
+    },
+}
+
+/// Runs the scripts on job manager events.
+///
+/// The scripts run one event at a time in a dedicated thread, so slow
+/// scripts never block the event delivery. The thread exits once the
+/// runner is dropped.
+pub(crate) struct ScriptRunner {
+    tx: mpsc::Sender<Event>,
+}
+
+impl ScriptRunner {
+    /// Loads the scripts in the dir, returning `None` if there is none.
+    pub(crate) fn load(config: &ScriptsConfig, job_mgr_handle: JobManagerHandle) -> Option<Self> {
+        let dir = config.dir.as_ref()?;
+        let paths = match script_paths(dir) {
+            Ok(paths) if paths.is_empty() => return None,
+            Ok(paths) => paths,
+            Err(err) => {
+                warn!("failed to read the scripts dir: {err:?}");
+                return None;
+            }
+        };
+
+        let api = Api {
+            job_mgr_handle,
+            runtime: RuntimeHandle::current(),
+        };
+        let timeout = config.timeout;
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("petri-scripts".to_owned())
+            .spawn(move || {
+                let scripts = Scripts::new(api, timeout);
+                let scripts = scripts.load(paths);
+                while let Ok(event) = rx.recv() {
+                    scripts.dispatch(&event);
+                }
+            })
+            .map_err(|err| warn!("failed to start the scripts thread: {err:?}"))
+            .ok()?;
+
+        Some(Self { tx })
+    }
+}
+
+impl EventHandler for ScriptRunner {
+    fn handle_process_start(&self, pid: u32, jid: Option<&str>) {
+        let jid = jid.map(ToOwned::to_owned);
+        _ = self.tx.send(Event::ProcessStart { pid, jid });
+    }
+
+    fn handle_process_exit(
+        &self,
+        pid: u32,
+        jid: Option<&str>,
+        exit_code: i32,
+        exit_reason: ExitReason,
+    ) {
+        let jid = jid.map(ToOwned::to_owned);
+        _ = self.tx.send(Event::ProcessExit {
+            pid,
+            jid,
+            exit_code,
+            exit_reason,
+        });
+    }
+
+    fn handle_log_quota_exceeded(&self, pid: u32, jid: Option<&str>, usage: &LogUsage) {
+        let jid = jid.map(ToOwned::to_owned);
+        _ = self.tx.send(Event::LogQuotaExceeded {
+            pid,
+            jid,
+            usage: *usage,
+        });
+    }
+}
+
+fn script_paths(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == IoErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+    let mut paths = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == SCRIPT_EXT) && path.is_file() {
+            paths.push(path);
+        }
+    }
+    // Scripts handle each event in the order of their names.
+    paths.sort();
+    Ok(paths)
+}
+
+/// The control API that scripts can call.
+struct Api {
+    job_mgr_handle: JobManagerHandle,
+    runtime: RuntimeHandle,
+}
+
+impl Api {
+    fn restart_job(&self, query: String) {
+        let job_mgr_handle = self.job_mgr_handle.clone();
+        self.runtime.spawn(async move {
+            let result = async {
+                let jid = job_mgr_handle.resolve_job_id(&query).await?;
+                job_mgr_handle.stop_job(&jid).await?;
+                job_mgr_handle.start_job(&jid).await
+            };
+            match result.await {
+                Ok(pid) => info!("script restarted job `{query}` (pid: {pid})"),
+                Err(err) => warn!("script failed to restart job `{query}`: {err:?}"),
+            }
+        });
+    }
+
+    fn notify(&self, message: String) {
+        let mut command = notification_command(&message);
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true);
+        self.runtime.spawn(async move {
+            match command.status().await {
+                Ok(status) if status.success() => {}
+                Ok(status) => warn!("script notification exited with {status}"),
+                Err(err) => warn!("failed to send script notification: {err:?}"),
+            }
+        });
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn notification_command(message: &str) -> Command {
+    let mut command = Command::new("osascript");
+    // The message is passed as an argument, so it needs no quoting.
+    command
+        .args(["-e", "on run argv"])
+        .args([
+            "-e",
+            "display notification (item 1 of argv) with title \"petri\"",
+        ])
+        .args(["-e", "end run", message]);
+    command
+}
+
+#[cfg(not(target_os = "macos"))]
+fn notification_command(message: &str) -> Command {
+    let mut command = Command::new("notify-send");
+    command.args(["petri", message]);
+    command
+}
+
+struct Script {
+    name: String,
+    ast: AST,
+}
+
+/// The engine and the compiled scripts, which live in the scripts thread.
+struct Scripts {
+    engine: Engine,
+    scripts: Vec<Script>,
+    /// When the running script is terminated.
+    deadline: Rc<Cell<Option<Instant>>>,
+    timeout: Duration,
+}
+
+impl Scripts {
+    fn new(api: Api, timeout: Duration) -> Self {
+        let deadline = Rc::new(Cell::new(None::<Instant>));
+        let mut engine = Engine::new();
+        engine.set_module_resolver(DummyModuleResolver);
+
+        let progress_deadline = Rc::clone(&deadline);
+        engine.on_progress(move |_| {
+            let deadline = progress_deadline.get()?;
+            (Instant::now() >= deadline).then(|| "timed out".into())
+        });
+        engine.on_print(|text| info!("script: {text}"));
+        engine
+            .on_debug(|text, source, _| debug!("script `{}`: {text}", source.unwrap_or_default()));
+
+        let api = Rc::new(api);
+        let restart_api = Rc::clone(&api);
+        engine.register_fn("restart_job", move |job: &str| {
+            restart_api.restart_job(job.to_owned())
+        });
+        engine.register_fn("notify", move |message: &str| {
+            api.notify(message.to_owned())
+        });
+        engine.register_fn("log", |ctx: NativeCallContext, message: &str| {
+            info!(
+                "script `{}`: {message}",
+                ctx.call_source().unwrap_or_default()
+            )
+        });
+
+        Self {
+            engine,
+            scripts: vec![],
+            deadline,
+            timeout,
+        }
+    }
+
+    /// Compiles the scripts and runs their top-level statements, skipping
+    /// the ones that fail.
+    fn load(mut self, paths: Vec<PathBuf>) -> Self {
+        for path in paths {
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let source = match fs::read_to_string(&path) {
+                Ok(source) => source,
+                Err(err) => {
+                    warn!("failed to read script `{name}`: {err:?}");
+                    continue;
+                }
+            };
+            if let Err(err) = self.add(name.clone(), &source) {
+                warn!("failed to load script `{name}`: {err}");
+                continue;
+            }
+            info!("loaded script `{name}`");
+        }
+        self
+    }
+
+    fn add(&mut self, name: String, source: &str) -> Result<(), Box<EvalAltResult>> {
+        let mut ast = self.engine.compile(source)?;
+        ast.set_source(name.as_str());
+        self.deadline.set(Some(Instant::now() + self.timeout));
+        let result = self.engine.run_ast(&ast);
+        self.deadline.set(None);
+        result?;
+        self.scripts.push(Script { name, ast });
+        Ok(())
+    }
+
+    fn dispatch(&self, event: &Event) {
+        let (name, fields) = event_fields(event);
+        for script in &self.scripts {
+            if let Err(err) = self.call(script, name, fields.clone()) {
+                warn!("script `{}` failed to handle `{name}`: {err}", script.name);
+            }
+        }
+    }
+
+    /// Calls the handler of the event in the script, returning `None` if
+    /// the script doesn't handle it.
+    fn call(
+        &self,
+        script: &Script,
+        event: &str,
+        fields: Map,
+    ) -> Result<Option<Dynamic>, Box<EvalAltResult>> {
+        let handler = format!("on_{event}");
+        let handles = script
+            .ast
+            .iter_functions()
+            .any(|f| f.name == handler && f.params.len() == 1);
+        if !handles {
+            return Ok(None);
+        }
+
+        // The top-level statements only run once when the script is loaded.
+        let options = CallFnOptions::new().eval_ast(false);
+        self.deadline.set(Some(Instant::now() + self.timeout));
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut Scope::new(),
+            &script.ast,
+            handler,
+            (fields,),
+        );
+        self.deadline.set(None);
+        Ok(Some(result?))
+    }
+}
+
+fn event_fields(event: &Event) -> (&'static str, Map) {
+    let mut fields = Map::new();
+    let jid = |jid: &Option<String>| jid.clone().map_or(Dynamic::UNIT, Dynamic::from);
+    let name = match event {
+        Event::ProcessStart { pid, jid: job } => {
+            fields.insert("pid".into(), (*pid as i64).into());
+            fields.insert("jid".into(), jid(job));
+            "process_start"
+        }
+        Event::ProcessExit {
+            pid,
+            jid: job,
+            exit_code,
+            exit_reason,
+        } => {
+            fields.insert("pid".into(), (*pid as i64).into());
+            fields.insert("jid".into(), jid(job));
+            fields.insert("exit_code".into(), (*exit_code as i64).into());
+            fields.insert("exit_reason".into(), exit_reason.as_str().into());
+            "process_exit"
+        }
+        Event::LogQuotaExceeded {
+            pid,
+            jid: job,
+            usage,
+        } => {
+            fields.insert("pid".into(), (*pid as i64).into());
+            fields.insert("jid".into(), jid(job));
+            fields.insert("bytes".into(), (usage.bytes as i64).into());
+            fields.insert("quota".into(), (usage.quota.max_bytes as i64).into());
+            fields.insert("policy".into(), usage.quota.policy.as_str().into());
+            "log_quota_exceeded"
+        }
+    };
+    fields.insert("event".into(), name.into());
+    (name, fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use petri_core::job_mgr::JobManager;
+    use petri_core::process::ExitReason;
+    use petri_core::process_mgr::ProcessManager;
+    use rhai::EvalAltResult;
+    use tokio::runtime::{Builder as RuntimeBuilder, Runtime};
+
+    use super::{event_fields, Api, Event, Scripts};
+
+    fn make_scripts(runtime: &Runtime, timeout: Duration) -> Scripts {
+        let _guard = runtime.enter();
+        let proc_mgr = ProcessManager::new();
+        let api = Api {
+            job_mgr_handle: JobManager::new(proc_mgr.handle()).handle(),
+            runtime: runtime.handle().clone(),
+        };
+        Scripts::new(api, timeout)
+    }
+
+    #[test]
+    fn test_call_handlers() {
+        let runtime = RuntimeBuilder::new_current_thread().build().unwrap();
+        let mut scripts = make_scripts(&runtime, Duration::from_secs(10));
+        let source = r#"
+            fn on_process_exit(event) {
+                if event.jid == () && event.exit_reason == "OOM" {
+                    event.exit_code + 1
+                }
+            }
+        "#;
+        scripts.add("exit.rhai".to_owned(), source).unwrap();
+
+        let event = Event::ProcessExit {
+            pid: 42,
+            jid: None,
+            exit_code: 1,
+            exit_reason: ExitReason::Oom,
+        };
+        let (name, fields) = event_fields(&event);
+        let result = scripts.call(&scripts.scripts[0], name, fields).unwrap();
+        assert_eq!(result.unwrap().as_int(), Ok(2));
+
+        let event = Event::ProcessStart {
+            pid: 42,
+            jid: Some("abc".to_owned()),
+        };
+        let (name, fields) = event_fields(&event);
+        let result = scripts.call(&scripts.scripts[0], name, fields).unwrap();
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_restrict_scripts() {
+        let runtime = RuntimeBuilder::new_current_thread().build().unwrap();
+        let mut scripts = make_scripts(&runtime, Duration::from_millis(50));
+
+        let err = scripts
+            .add("import.rhai".to_owned(), r#"import "other" as other;"#)
+            .unwrap_err();
+        assert!(matches!(*err, EvalAltResult::ErrorModuleNotFound(..)));
+
+        // Slow handlers are terminated, and the script is kept.
+        let source = "fn on_process_start(event) { loop {} }";
+        scripts.add("loop.rhai".to_owned(), source).unwrap();
+        let event = Event::ProcessStart { pid: 42, jid: None };
+        let (name, fields) = event_fields(&event);
+        let err = scripts.call(&scripts.scripts[0], name, fields).unwrap_err();
+        assert!(matches!(*err, EvalAltResult::ErrorTerminated(..)));
+        assert_eq!(scripts.scripts.len(), 1);
+    }
+}
@@ -0,0 +1,112 @@
+//! Shutting down the server in phases, while the `stop-server` clients
+//! follow the progress.
+
+use std::future::Future;
+use std::time::Duration;
+
+use petri_control::{ShutdownEvent, ShutdownPhase};
+use petri_core::job_mgr::Handle as JobManagerHandle;
+use petri_core::process_mgr::ProcessManager;
+use tokio::sync::broadcast;
+use tokio::task::{self, JoinSet};
+
+use crate::config::ShutdownConfig;
+
+/// How often to check whether the `stop-server` clients are gone.
+const CLIENTS_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+pub(crate) struct Shutdown<'a> {
+    pub(crate) config: ShutdownConfig,
+    pub(crate) job_mgr_handle: JobManagerHandle,
+    pub(crate) process_manager: &'a ProcessManager,
+    pub(crate) progress: broadcast::Sender<ShutdownEvent>,
+}
+
+impl Shutdown<'_> {
+    /// Runs all the phases. The control server should be kept running
+    /// until this returns, and stopped after that.
+    pub(crate) async fn run(&self) {
+        // No processes can be started from now on, including the restarts
+        // of the jobs being stopped.
+        self.process_manager.begin_shutdown();
+
+        let config = &self.config;
+        self.phase(
+            ShutdownPhase::StopJobs,
+            config.jobs_timeout,
+            self.stop_jobs(),
+        )
+        .await;
+        self.phase(
+            ShutdownPhase::StopProcesses,
+            config.processes_timeout,
+            self.process_manager.shutdown(),
+        )
+        .await;
+        self.phase(ShutdownPhase::FlushLogs, config.logs_timeout, flush_logs())
+            .await;
+        self.phase(
+            ShutdownPhase::StopControl,
+            config.control_timeout,
+            self.wait_for_clients(),
+        )
+        .await;
+    }
+
+    async fn phase<F>(&self, phase: ShutdownPhase, timeout: Duration, fut: F)
+    where
+        F: Future<Output = ()>,
+    {
+        info!("shutdown: {phase}...");
+        self.report(ShutdownEvent::PhaseStarted(phase));
+        if tokio::time::timeout(timeout, fut).await.is_err() {
+            warn!("shutdown: {phase} didn't finish in {timeout:?}, moving on");
+            self.report(ShutdownEvent::PhaseTimedOut(phase));
+        }
+    }
+
+    /// Stops the jobs wave by wave, and the jobs of a wave concurrently.
+    async fn stop_jobs(&self) {
+        for wave in self.job_mgr_handle.shutdown_waves().await {
+            let mut stops = JoinSet::new();
+            for jid in wave {
+                let handle = self.job_mgr_handle.clone();
+                stops.spawn(async move {
+                    let name = match handle.job_with_id(&jid).await {
+                        Some(job) => job.display_name().to_owned(),
+                        None => jid.clone(),
+                    };
+                    let result = handle
+                        .stop_job(&jid)
+                        .await
+                        .map_err(|err| format!("{err:#}"));
+                    ShutdownEvent::JobStopped { name, result }
+                });
+            }
+            while let Some(res) = stops.join_next().await {
+                match res {
+                    Ok(event) => self.report(event),
+                    Err(err) => error!("failed to stop a job: {err:?}"),
+                }
+            }
+        }
+    }
+
+    /// Waits for the `stop-server` clients to receive the last event.
+    async fn wait_for_clients(&self) {
+        while self.progress.receiver_count() > 0 {
+            tokio::time::sleep(CLIENTS_CHECK_INTERVAL).await;
+        }
+    }
+
+    fn report(&self, event: ShutdownEvent) {
+        // Nobody may be following the progress.
+        _ = self.progress.send(event);
+    }
+}
+
+async fn flush_logs() {
+    if let Err(err) = task::spawn_blocking(|| log::logger().flush()).await {
+        error!("failed to flush the logs: {err:?}");
+    }
+}
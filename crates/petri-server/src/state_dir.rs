@@ -0,0 +1,289 @@
+//! The files that the running server leaves on disk outside of its
+//! config: the pidfile and the control socket.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind as IoErrorKind, Seek, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, Once};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use petri_control::env::ListenAddress;
+
+/// How long to wait for a previous server to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The files to remove if the server exits without dropping its guard.
+static CLEANUP_PATHS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+/// The pidfile to remove if the server exits without dropping its guard,
+/// if it's still the one this process wrote.
+static CLEANUP_PID_FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// The state dir of the server, keeping a pidfile for each address that
+/// a server listens on.
+///
+/// The server claims its files when it starts, reclaiming the ones left
+/// by a crashed server. They're removed when the guard is dropped, which
+/// panics do as they unwind, or through `atexit` when the process exits
+/// without dropping it (see [`cleanup_at_exit`]). Aborts skip both, so
+/// the next server reclaims the files. The pidfile is locked while the
+/// server runs, so servers starting at the same time can't both claim the
+/// files.
+pub(crate) struct ServerStateDir {
+    pid_file: Option<PathBuf>,
+    socket: Option<PathBuf>,
+    address: ListenAddress,
+}
+
+/// The files claimed by the running server, removed when it's dropped.
+pub(crate) struct StateGuard {
+    /// The pidfile, open to hold its lock.
+    pid_file: Option<(PathBuf, File)>,
+}
+
+impl ServerStateDir {
+    /// Pidfiles are not written if `dir` is `None`, but the socket is
+    /// still reclaimed and cleaned up.
+    pub(crate) fn new(dir: Option<&Path>, address: &ListenAddress) -> Self {
+        let socket = match address {
+            ListenAddress::Path(path) => Some(path.clone()),
+            _ => None,
+        };
+        Self {
+            pid_file: dir.map(|dir| dir.join(pid_file_name(address))),
+            socket,
+            address: address.clone(),
+        }
+    }
+
+    /// Claims the files for this process. Fails if another server is
+    /// running at the address.
+    pub(crate) fn claim(self) -> Result<StateGuard> {
+        let previous = self.pid_file.as_deref().and_then(read_pid_file);
+        let running = || match previous {
+            Some(pid) => anyhow!("another server (pid {pid}) is running at {}", self.address),
+            None => anyhow!("another server is running at {}", self.address),
+        };
+        // The lock is held by a server that runs or is still starting.
+        let locked_pid_file = match &self.pid_file {
+            Some(pid_file) => Some(lock_pid_file(pid_file).map_err(|err| {
+                if err.kind() == IoErrorKind::WouldBlock {
+                    return running();
+                }
+                let context = format!("failed to lock the pidfile `{}`", pid_file.display());
+                anyhow::Error::new(err).context(context)
+            })?),
+            None => None,
+        };
+        if is_listening(&self.address) {
+            return Err(running());
+        }
+
+        // Nothing is listening, so the files are left by a server that is
+        // gone. Without a pidfile to lock, it may also be still starting,
+        // which fails to bind then.
+        if let Some(pid) = previous {
+            warn!("reclaiming the files left by a previous server (pid {pid})");
+        }
+        if let Some(socket) = &self.socket {
+            match fs::remove_file(socket) {
+                Ok(_) => warn!("removed the stale socket `{}`", socket.display()),
+                Err(err) if err.kind() == IoErrorKind::NotFound => {}
+                Err(err) => return Err(err).context("failed to remove the stale socket"),
+            }
+        }
+
+        let pid_file = match (self.pid_file, locked_pid_file) {
+            (Some(path), Some(mut file)) => {
+                write_pid(&mut file)
+                    .with_context(|| format!("failed to write the pidfile `{}`", path.display()))?;
+                Some((path, file))
+            }
+            _ => None,
+        };
+
+        register_cleanup(pid_file.as_ref().map(|(path, _)| path.clone()), self.socket);
+        Ok(StateGuard { pid_file })
+    }
+}
+
+impl Drop for StateGuard {
+    fn drop(&mut self) {
+        // The socket is removed by the control server itself.
+        if let Some((pid_file, _)) = &self.pid_file {
+            if let Err(err) = remove_own_pid_file(pid_file) {
+                error!("failed to remove the pidfile: {err:?}");
+            }
+        }
+        if let Ok(mut paths) = CLEANUP_PATHS.lock() {
+            paths.clear();
+        }
+        if let Ok(mut pid_file) = CLEANUP_PID_FILE.lock() {
+            pid_file.take();
+        }
+    }
+}
+
+/// Removes the pidfile and the socket of the server, best effort, when the
+/// process exits without dropping the guard, like by `exit` after a fatal
+/// error. Registered with `atexit` when the files are claimed.
+extern "C" fn cleanup_at_exit() {
+    // The lock may be held by a thread that is still running.
+    let Ok(mut paths) = CLEANUP_PATHS.try_lock() else {
+        return;
+    };
+    for path in paths.drain(..) {
+        _ = fs::remove_file(path);
+    }
+    if let Some(pid_file) = CLEANUP_PID_FILE.try_lock().ok().and_then(|mut p| p.take()) {
+        _ = remove_own_pid_file(&pid_file);
+    }
+}
+
+fn register_cleanup(pid_file: Option<PathBuf>, socket: Option<PathBuf>) {
+    static AT_EXIT: Once = Once::new();
+
+    if let Ok(mut cleanup_paths) = CLEANUP_PATHS.lock() {
+        cleanup_paths.extend(socket);
+    }
+    if let Ok(mut cleanup_pid_file) = CLEANUP_PID_FILE.lock() {
+        *cleanup_pid_file = pid_file;
+    }
+    AT_EXIT.call_once(|| unsafe {
+        libc::atexit(cleanup_at_exit);
+    });
+}
+
+/// Opens the pidfile and takes an exclusive lock on it, failing with
+/// `WouldBlock` if another process holds it. The contents are kept, so
+/// the pid of a previous server can still be read.
+fn lock_pid_file(path: &Path) -> io::Result<File> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+fn write_pid(file: &mut File) -> io::Result<()> {
+    file.set_len(0)?;
+    file.rewind()?;
+    writeln!(file, "{}", std::process::id())
+}
+
+/// Removes the pidfile unless another process has written it since, like
+/// a server that started after this one removed its pidfile.
+fn remove_own_pid_file(path: &Path) -> io::Result<()> {
+    if read_pid_file(path) != Some(std::process::id()) {
+        return Ok(());
+    }
+    fs::remove_file(path)
+}
+
+/// Returns the pid in the pidfile, if there is a valid one.
+fn read_pid_file(path: &Path) -> Option<u32> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == IoErrorKind::NotFound => return None,
+        Err(err) => {
+            warn!("failed to read the pidfile: {err:?}");
+            return None;
+        }
+    };
+    contents.trim().parse().ok()
+}
+
+/// Names the pidfile after the address, like `tmp-petri.sock.pid` for
+/// `/tmp/petri.sock`, so servers at different addresses don't conflict.
+fn pid_file_name(address: &ListenAddress) -> String {
+    let mut name = String::new();
+    for c in address.to_string().chars() {
+        if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+            name.push(c);
+        } else if !name.is_empty() && !name.ends_with('-') {
+            name.push('-');
+        }
+    }
+    let name = name.trim_end_matches('-');
+    format!("{name}.pid")
+}
+
+fn is_listening(address: &ListenAddress) -> bool {
+    let res = match address {
+        ListenAddress::Path(path) => UnixStream::connect(path).map(drop),
+        ListenAddress::Abstract(name) => connect_abstract(name),
+        ListenAddress::Tcp(addr) => {
+            std::net::TcpStream::connect_timeout(addr, CONNECT_TIMEOUT).map(drop)
+        }
+    };
+    res.is_ok()
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn connect_abstract(name: &str) -> io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    let addr = SocketAddr::from_abstract_name(name)?;
+    UnixStream::connect_addr(&addr).map(drop)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn connect_abstract(_name: &str) -> io::Result<()> {
+    Err(io::Error::from(IoErrorKind::Unsupported))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::ErrorKind as IoErrorKind;
+    use std::process;
+
+    use super::{lock_pid_file, pid_file_name, read_pid_file, remove_own_pid_file, write_pid};
+
+    #[test]
+    fn test_lock_pid_file() {
+        let dir = std::env::temp_dir().join(format!("petri-state-dir-{}", process::id()));
+        let path = dir.join("test.pid");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "42\n").unwrap();
+
+        let mut file = lock_pid_file(&path).unwrap();
+        // The pid of the previous server is kept until the new one is
+        // written.
+        assert_eq!(read_pid_file(&path), Some(42));
+        let err = lock_pid_file(&path).unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::WouldBlock);
+        write_pid(&mut file).unwrap();
+        assert_eq!(read_pid_file(&path), Some(process::id()));
+        drop(file);
+
+        // A pidfile written by another process is kept.
+        fs::write(&path, "42\n").unwrap();
+        remove_own_pid_file(&path).unwrap();
+        assert!(path.exists());
+        fs::write(&path, format!("{}\n", process::id())).unwrap();
+        remove_own_pid_file(&path).unwrap();
+        assert!(!path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pid_file_name() {
+        let name = |address: &str| pid_file_name(&address.parse().unwrap());
+        assert_eq!(name("/tmp/petri.sock"), "tmp-petri.sock.pid");
+        assert_eq!(name("tcp:[::]:7070"), "tcp-7070.pid");
+        assert_eq!(name("tcp:127.0.0.1:7070"), "tcp-127.0.0.1-7070.pid");
+    }
+}
@@ -0,0 +1,249 @@
+//! Detecting when the runtime of the server stops making progress, e.g.
+//! a blocking call that never returns, which leaves all clients hanging.
+//!
+//! A task on the runtime beats periodically, and a thread outside of it
+//! checks the beats. When they stop for the stall timeout, the thread
+//! logs what the server was doing. The server is not restarted, since the
+//! pipes of the processes and the jobs only live in this process, so a
+//! supervisor can decide what to do with the report.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use petri_control::history::{ActiveCommands, CommandHistory};
+use petri_core::process_mgr::Handle as ProcessManagerHandle;
+use petri_utils::time::FormattedUptime;
+use tokio::task::JoinHandle;
+
+use crate::config::WatchdogConfig;
+
+/// How often the runtime beats, and the thread checks the beats.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many of the latest commands are logged when the runtime stalls.
+const LAST_COMMANDS: usize = 5;
+
+pub(crate) struct Watchdog {
+    pub(crate) config: WatchdogConfig,
+    pub(crate) proc_mgr_handle: ProcessManagerHandle,
+    pub(crate) active_commands: ActiveCommands,
+    pub(crate) history: Arc<CommandHistory>,
+}
+
+/// Stops the watchdog when it's dropped.
+pub(crate) struct WatchdogGuard {
+    heartbeat: JoinHandle<()>,
+    stopped: Arc<AtomicBool>,
+}
+
+/// The time of the last beat, in milliseconds since the watchdog started.
+struct Heartbeat {
+    started_at: Instant,
+    last_beat_ms: AtomicU64,
+}
+
+/// Tells when the runtime stalls and recovers, so each stall is reported
+/// once.
+#[derive(Default)]
+struct StallTracker {
+    /// How long the runtime has stalled, `None` if it's not stalled.
+    stalled_for: Option<Duration>,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum StallEvent {
+    Stalled,
+    /// The runtime made progress again after stalling for about this long.
+    Recovered(Duration),
+}
+
+impl Watchdog {
+    /// Starts beating on the current runtime and checking the beats, or
+    /// returns `None` if the watchdog is disabled.
+    pub(crate) fn spawn(self) -> Option<WatchdogGuard> {
+        if !self.config.enabled {
+            return None;
+        }
+
+        let heartbeat = Arc::new(Heartbeat {
+            started_at: Instant::now(),
+            last_beat_ms: AtomicU64::new(0),
+        });
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let beating = Arc::clone(&heartbeat);
+        let heartbeat_task = tokio::task::spawn(async move {
+            let mut ticker = tokio::time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                ticker.tick().await;
+                beating.beat();
+            }
+        });
+
+        let thread_stopped = Arc::clone(&stopped);
+        let res = thread::Builder::new()
+            .name("petri-watchdog".to_owned())
+            .spawn(move || self.run(&heartbeat, &thread_stopped));
+        if let Err(err) = res {
+            warn!("failed to start the watchdog: {err:?}");
+            heartbeat_task.abort();
+            return None;
+        }
+
+        Some(WatchdogGuard {
+            heartbeat: heartbeat_task,
+            stopped,
+        })
+    }
+
+    fn run(self, heartbeat: &Heartbeat, stopped: &AtomicBool) {
+        let mut tracker = StallTracker::default();
+        loop {
+            thread::sleep(HEARTBEAT_INTERVAL);
+            if stopped.load(AtomicOrdering::Relaxed) {
+                break;
+            }
+
+            // `Instant` doesn't count the time that the machine sleeps, so
+            // waking up doesn't look like a stall.
+            let since_beat = heartbeat.since_last_beat();
+            match tracker.check(since_beat, self.config.stall_timeout) {
+                Some(StallEvent::Stalled) => error!("{}", self.diagnostics(since_beat)),
+                Some(StallEvent::Recovered(stalled_for)) => {
+                    let stalled_for = FormattedUptime::new(stalled_for);
+                    info!("the runtime recovered after stalling for about {stalled_for}");
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Describes what the server was doing when it stalled. Nothing here
+    /// may wait for the runtime.
+    fn diagnostics(&self, since_beat: Duration) -> String {
+        let missed = since_beat.as_secs() / HEARTBEAT_INTERVAL.as_secs();
+        let mut report = format!(
+            "the runtime hasn't made progress for {} ({missed} heartbeats missed), \
+            the stacks can be sampled with `sample {}`",
+            FormattedUptime::new(since_beat),
+            std::process::id()
+        );
+
+        match self.proc_mgr_handle.try_process_count() {
+            Some(count) => _ = write!(report, "\nprocesses: {count}"),
+            None => report.push_str("\nprocesses: unknown (locked)"),
+        }
+
+        let now = Instant::now();
+        match self.active_commands.try_snapshot() {
+            Some(commands) if commands.is_empty() => report.push_str("\ncommands running: none"),
+            Some(commands) => {
+                report.push_str("\ncommands running:");
+                for command in commands {
+                    let running_for = FormattedUptime::new(now - command.started_at);
+                    _ = write!(report, "\n  `{}` for {running_for}", command.cmd_line);
+                }
+            }
+            None => report.push_str("\ncommands running: unknown (locked)"),
+        }
+
+        match self.history.try_entries() {
+            Some(entries) => {
+                report.push_str("\nlast commands:");
+                let skipped = entries.len().saturating_sub(LAST_COMMANDS);
+                for entry in &entries[skipped..] {
+                    _ = write!(
+                        report,
+                        "\n  {} `{}` {}",
+                        entry.time,
+                        entry.args.join(" "),
+                        entry.result.as_str()
+                    );
+                }
+            }
+            None => report.push_str("\nlast commands: unknown (locked)"),
+        }
+
+        report
+    }
+}
+
+impl Heartbeat {
+    fn beat(&self) {
+        let elapsed = self.started_at.elapsed().as_millis() as u64;
+        self.last_beat_ms.store(elapsed, AtomicOrdering::Relaxed);
+    }
+
+    fn since_last_beat(&self) -> Duration {
+        let last_beat = Duration::from_millis(self.last_beat_ms.load(AtomicOrdering::Relaxed));
+        self.started_at.elapsed().saturating_sub(last_beat)
+    }
+}
+
+impl StallTracker {
+    /// Checks the time since the last beat, returning the event if the
+    /// runtime has just stalled or recovered.
+    fn check(&mut self, since_beat: Duration, stall_timeout: Duration) -> Option<StallEvent> {
+        if since_beat < stall_timeout {
+            return self.stalled_for.take().map(StallEvent::Recovered);
+        }
+        let stalled = self.stalled_for.is_none();
+        self.stalled_for = Some(since_beat);
+        stalled.then_some(StallEvent::Stalled)
+    }
+}
+
+impl Drop for WatchdogGuard {
+    fn drop(&mut self) {
+        self.heartbeat.abort();
+        self.stopped.store(true, AtomicOrdering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering as AtomicOrdering;
+    use std::time::{Duration, Instant};
+
+    use super::{Heartbeat, StallEvent, StallTracker};
+
+    #[test]
+    fn test_heartbeat() {
+        let heartbeat = Heartbeat {
+            started_at: Instant::now() - Duration::from_secs(10),
+            last_beat_ms: Default::default(),
+        };
+        assert!(heartbeat.since_last_beat() >= Duration::from_secs(10));
+        heartbeat.beat();
+        assert!(heartbeat.since_last_beat() < Duration::from_secs(1));
+
+        // A beat recorded after the clock is read doesn't underflow.
+        heartbeat
+            .last_beat_ms
+            .store(u64::MAX / 2, AtomicOrdering::Relaxed);
+        assert_eq!(heartbeat.since_last_beat(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_stall_tracker() {
+        let timeout = Duration::from_secs(30);
+        let secs = Duration::from_secs;
+        let mut tracker = StallTracker::default();
+        assert_eq!(tracker.check(secs(1), timeout), None);
+
+        // A stall is reported once, however long it lasts.
+        assert_eq!(tracker.check(secs(30), timeout), Some(StallEvent::Stalled));
+        assert_eq!(tracker.check(secs(31), timeout), None);
+        assert_eq!(tracker.check(secs(45), timeout), None);
+        assert_eq!(
+            tracker.check(secs(1), timeout),
+            Some(StallEvent::Recovered(secs(45)))
+        );
+        assert_eq!(tracker.check(secs(1), timeout), None);
+
+        assert_eq!(tracker.check(secs(60), timeout), Some(StallEvent::Stalled));
+    }
+}
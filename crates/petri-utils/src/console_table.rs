@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use std::fmt::Display;
 
 /// Options for customizing a table column.
@@ -58,10 +59,45 @@ impl CoreBuilder {
 
     fn push_row(&mut self, column_idx: usize, field: String) {
         let column = &mut self.columns[column_idx];
-        column.max_width = column.max_width.max(field.len());
+        // Fields are padded by characters.
+        column.max_width = column.max_width.max(field.chars().count());
         column.rows.push(field);
     }
 
+    fn fit_column(&mut self, column_idx: usize, width: usize) {
+        let last_column_idx = self.columns.len() - 1;
+        // Columns but the last are padded to their widths and followed by
+        // their spacing.
+        let used: usize = self
+            .columns
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| *idx != last_column_idx)
+            .map(|(idx, column)| {
+                let spacing = column.options.spacing as usize;
+                if idx == column_idx {
+                    spacing
+                } else {
+                    column.max_width + spacing
+                }
+            })
+            .sum::<usize>()
+            + match column_idx == last_column_idx {
+                true => 0,
+                false => self.columns[last_column_idx].max_width,
+            };
+
+        let column = &mut self.columns[column_idx];
+        let available = width.saturating_sub(used).max(column.options.title.len());
+        column.max_width = column.options.title.len();
+        for field in &mut column.rows {
+            if let Cow::Owned(truncated) = truncate(field, available) {
+                *field = truncated;
+            }
+            column.max_width = column.max_width.max(field.chars().count());
+        }
+    }
+
     fn build(&self) -> String {
         let mut table = String::new();
         if self.columns.is_empty() {
@@ -115,6 +151,77 @@ impl CoreBuilder {
     }
 }
 
+/// Shortens the text to the number of characters with an ellipsis, for
+/// the long fields of tables (e.g. commands) to fit in a terminal.
+pub fn truncate(text: &str, width: usize) -> Cow<'_, str> {
+    match text.char_indices().nth(width) {
+        Some(_) if width == 0 => Cow::Borrowed(""),
+        Some(_) => {
+            let end = text
+                .char_indices()
+                .nth(width - 1)
+                .map_or(text.len(), |(idx, _)| idx);
+            Cow::Owned(format!("{}…", &text[..end]))
+        }
+        None => Cow::Borrowed(text),
+    }
+}
+
+/// A table builder with columns chosen at runtime, like by users.
+#[derive(Clone)]
+pub struct DynamicBuilder {
+    core: CoreBuilder,
+}
+
+impl DynamicBuilder {
+    pub fn new(columns: Vec<ColumnOptions>) -> Self {
+        let columns = columns
+            .into_iter()
+            .map(|options| Column {
+                max_width: options.title.len(),
+                options,
+                rows: vec![],
+            })
+            .collect();
+        Self {
+            core: CoreBuilder::new(columns),
+        }
+    }
+
+    /// Appends a row with a field for each column.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of fields doesn't match the columns.
+    pub fn push_row(&mut self, fields: Vec<String>) {
+        assert_eq!(
+            fields.len(),
+            self.core.columns.len(),
+            "the row should have a field for each column"
+        );
+        for (idx, field) in fields.into_iter().enumerate() {
+            self.core.push_row(idx, field);
+        }
+    }
+
+    /// Shortens the fields of the column, so the rows fit in `width` along
+    /// with the other columns. Call it after all the rows are pushed.
+    pub fn fit_column(&mut self, column_idx: usize, width: usize) {
+        self.core.fit_column(column_idx, width);
+    }
+
+    /// Builds the table and returns it as a `String` value.
+    pub fn build(&self) -> String {
+        self.core.build()
+    }
+}
+
+impl Display for DynamicBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.build())
+    }
+}
+
 /// A collection of column definitions, which can be used to make
 /// builders for creating tables with those columns.
 pub trait ColumnCollection {
@@ -155,6 +262,13 @@ macro_rules! impl_column_collection {
                     )*
                 }
 
+                /// Shortens the fields of the column, so the rows fit in
+                /// `width` along with the other columns. Call it after all
+                /// the rows are pushed.
+                pub fn fit_column(&mut self, column_idx: usize, width: usize) {
+                    self.core.fit_column(column_idx, width);
+                }
+
                 /// Builds the table and returns it as a `String` value.
                 pub fn build(&self) -> String {
                     self.core.build()
@@ -205,7 +319,7 @@ impl_column_collection!(Builder8, 0, 1, 2, 3, 4, 5, 6, 7);
 mod tests {
     use indoc::indoc;
 
-    use super::{Alignment, ColumnCollection, ColumnOptions};
+    use super::{truncate, Alignment, ColumnCollection, ColumnOptions, DynamicBuilder};
 
     #[test]
     fn test_simple_table() {
@@ -236,4 +350,63 @@ mod tests {
                 second  b       This is another note"}
         )
     }
+
+    #[test]
+    fn test_dynamic_table() {
+        let mut builder = DynamicBuilder::new(vec![
+            ColumnOptions::new("PID").alignment(Alignment::Right),
+            ColumnOptions::new("NOTE"),
+        ]);
+        builder.push_row(vec!["42".to_string(), "bisecting".to_string()]);
+        builder.push_row(vec!["1234".to_string(), "-".to_string()]);
+
+        assert_eq!(
+            builder.build(),
+            indoc! {"
+                 PID NOTE
+                  42 bisecting
+                1234 -"}
+        )
+    }
+
+    #[test]
+    fn test_fit_column() {
+        let mut builder = DynamicBuilder::new(vec![
+            ColumnOptions::new("PID").alignment(Alignment::Right),
+            ColumnOptions::new("CMD").spacing(2),
+            ColumnOptions::new("NOTE"),
+        ]);
+        builder.push_row(vec![
+            "42".to_string(),
+            "sleep 1000".to_string(),
+            "bisecting".to_string(),
+        ]);
+        builder.push_row(vec!["7".to_string(), "true".to_string(), "-".to_string()]);
+        // The columns after it are kept in the width too.
+        builder.fit_column(1, 20);
+
+        assert_eq!(
+            builder.build(),
+            indoc! {"
+                PID CMD    NOTE
+                 42 slee…  bisecting
+                  7 true   -"}
+        );
+        assert!(builder
+            .build()
+            .lines()
+            .all(|line| line.chars().count() <= 20));
+
+        // The title is never cut.
+        builder.fit_column(1, 0);
+        assert!(builder.build().starts_with("PID CMD  NOTE"));
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("sleep 10", 8), "sleep 10");
+        assert_eq!(truncate("sleep 100", 8), "sleep 1…");
+        assert_eq!(truncate("échoé", 3), "éc…");
+        assert_eq!(truncate("sleep", 0), "");
+    }
 }
@@ -1,6 +1,50 @@
+//! Ids of jobs, which are SHA-1 digests in hex internally. They are long
+//! and hard to tell apart, so users see them in a short form (see
+//! [`IdFormat`]) instead.
+
 use std::borrow::Borrow;
 use std::ops::Deref;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+
+/// The alphabet of Crockford's base32, without the letters that look like
+/// digits.
+const BASE32_ALPHABET: &[u8; 32] = b"0123456789abcdefghjkmnpqrstvwxyz";
+
+const ADJECTIVES: [&str; 64] = [
+    "amber", "bold", "brave", "brisk", "calm", "clever", "cosmic", "crisp", "daring", "eager",
+    "early", "fancy", "fast", "fierce", "fluffy", "gentle", "giant", "glad", "golden", "grand",
+    "happy", "hidden", "humble", "icy", "jolly", "keen", "kind", "lively", "lucky", "merry",
+    "mighty", "misty", "noble", "odd", "olive", "plain", "polite", "proud", "quick", "quiet",
+    "rapid", "rosy", "royal", "rusty", "shiny", "silent", "silver", "sleek", "smart", "snowy",
+    "solar", "spicy", "steady", "stormy", "sunny", "swift", "tidy", "tiny", "vivid", "warm",
+    "wild", "windy", "witty", "young",
+];
+
+const NOUNS: [&str; 64] = [
+    "ant", "badger", "bear", "bee", "bison", "cat", "cobra", "crab", "crane", "crow", "deer",
+    "dingo", "dove", "duck", "eagle", "eel", "elk", "falcon", "ferret", "finch", "fox", "frog",
+    "gecko", "goat", "goose", "hare", "hawk", "heron", "horse", "ibis", "jay", "koala", "lark",
+    "lemur", "lion", "lynx", "mole", "moose", "moth", "mouse", "newt", "otter", "owl", "panda",
+    "parrot", "pony", "puma", "quail", "rabbit", "raven", "robin", "seal", "shark", "sheep",
+    "snail", "swan", "tiger", "toad", "trout", "viper", "whale", "wolf", "wren", "yak",
+];
+
+static FORMAT: OnceLock<Box<dyn IdFormat>> = OnceLock::new();
+
+/// How the short form of ids is made from their digests.
+pub trait IdFormat: Send + Sync {
+    /// Returns the short form of the digest, which is given as the leading
+    /// 64 bits.
+    fn short(&self, digest: u64) -> String;
+}
+
+/// The leading 40 bits of the digest in base32, like `3k9d0x2f`. This is
+/// the default format.
+pub struct Base32Format;
+
+/// An adjective and a noun, with 2 base32 characters from the leading 22
+/// bits of the digest, like `brave-otter-k3`.
+pub struct WordsFormat;
 
 #[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
 pub struct Id(Arc<str>);
@@ -9,6 +53,50 @@ impl Id {
     pub fn new(s: &str) -> Self {
         Self(Arc::from(s))
     }
+
+    /// Returns the form of the id shown to users, see [`short_id`].
+    pub fn short(&self) -> String {
+        short_id(&self.0)
+    }
+}
+
+impl IdFormat for Base32Format {
+    fn short(&self, digest: u64) -> String {
+        base32(digest, 8)
+    }
+}
+
+impl IdFormat for WordsFormat {
+    fn short(&self, digest: u64) -> String {
+        let adjective = ADJECTIVES[(digest >> 58) as usize];
+        let noun = NOUNS[(digest >> 52 & 0x3f) as usize];
+        format!("{adjective}-{noun}-{}", base32(digest << 12, 2))
+    }
+}
+
+/// Sets the format of short ids in this process, which can only be set
+/// once. Returns `false` if it's set, or a short id has been made already.
+pub fn set_format(format: impl IdFormat + 'static) -> bool {
+    FORMAT.set(Box::new(format)).is_ok()
+}
+
+/// Returns the short form of the id. Ids that are not hex digests (which
+/// are not made by petri) are returned as they are.
+pub fn short_id(id: &str) -> String {
+    let digest = id
+        .get(..16)
+        .and_then(|prefix| u64::from_str_radix(prefix, 16).ok());
+    match digest {
+        Some(digest) => FORMAT.get_or_init(|| Box::new(Base32Format)).short(digest),
+        None => id.to_owned(),
+    }
+}
+
+/// Encodes the leading bits of the value in `len` base32 characters.
+fn base32(value: u64, len: usize) -> String {
+    (0..len)
+        .map(|i| BASE32_ALPHABET[(value >> (59 - i * 5) & 0x1f) as usize] as char)
+        .collect()
 }
 
 impl From<&str> for Id {
@@ -48,3 +136,17 @@ impl Deref for Id {
         &*self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Base32Format, IdFormat, WordsFormat};
+
+    #[test]
+    fn test_id_formats() {
+        let digest = u64::from_str_radix("0123456789abcdef", 16).unwrap();
+        assert_eq!(Base32Format.short(digest), "04hmasw9");
+        assert_eq!(Base32Format.short(u64::MAX), "zzzzzzzz");
+        assert_eq!(WordsFormat.short(digest), "amber-ferret-6h");
+        assert_eq!(WordsFormat.short(u64::MAX), "young-yak-zz");
+    }
+}
@@ -1,8 +1,11 @@
 pub mod console_table;
-mod id;
+pub mod id;
 mod log_buf;
+mod size;
+pub mod sparkline;
 pub mod subscriber_list;
 pub mod time;
 
 pub use id::Id;
 pub use log_buf::LogBuffer;
+pub use size::{parse_bytes, FormattedBytes, ParseBytesError};
@@ -0,0 +1,112 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+/// An error returned when parsing a size string failed.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ParseBytesError(String);
+
+impl Display for ParseBytesError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for ParseBytesError {}
+
+/// A lazy human-readable string that represents a size in bytes, using
+/// binary units (like `12.3 MiB`).
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct FormattedBytes(u64);
+
+impl FormattedBytes {
+    /// Constructs a new `FormattedBytes` with the specified byte count.
+    #[inline]
+    pub fn new(bytes: u64) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<u64> for FormattedBytes {
+    fn from(value: u64) -> Self {
+        Self::new(value)
+    }
+}
+
+impl Display for FormattedBytes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+        if self.0 < 1024 {
+            return write!(f, "{} B", self.0);
+        }
+
+        let mut value = self.0 as f64 / 1024.0;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        write!(f, "{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// Parses a human-friendly size string like `512`, `64K` or `1.5GiB`.
+///
+/// Units are binary, `K`, `M`, `G` and `T` are the same as `KiB`, `MiB`,
+/// `GiB` and `TiB`. A bare number is interpreted as bytes.
+pub fn parse_bytes(s: &str) -> Result<u64, ParseBytesError> {
+    let s = s.trim();
+    let number_len = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(number_len);
+    let Ok(value) = number.parse::<f64>() else {
+        return Err(ParseBytesError(format!("expected a number in size `{s}`")));
+    };
+
+    let exponent = match unit.trim_start() {
+        "" | "B" => 0,
+        "K" | "KB" | "KiB" => 1,
+        "M" | "MB" | "MiB" => 2,
+        "G" | "GB" | "GiB" => 3,
+        "T" | "TB" | "TiB" => 4,
+        unit => {
+            return Err(ParseBytesError(format!(
+                "unknown unit `{unit}` in size `{s}`"
+            )))
+        }
+    };
+    let bytes = value * 1024f64.powi(exponent);
+    if bytes >= u64::MAX as f64 {
+        return Err(ParseBytesError(format!("size `{s}` is too large")));
+    }
+    Ok(bytes.round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_bytes, FormattedBytes};
+
+    #[test]
+    fn test_format() {
+        assert_eq!(FormattedBytes::new(0).to_string(), "0 B");
+        assert_eq!(FormattedBytes::new(1023).to_string(), "1023 B");
+        assert_eq!(FormattedBytes::new(1024).to_string(), "1.0 KiB");
+        assert_eq!(FormattedBytes::new(1536 * 1024).to_string(), "1.5 MiB");
+        assert_eq!(
+            FormattedBytes::new(3 * 1024 * 1024 * 1024).to_string(),
+            "3.0 GiB"
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_bytes("512"), Ok(512));
+        assert_eq!(parse_bytes("64K"), Ok(64 * 1024));
+        assert_eq!(parse_bytes("1.5GiB"), Ok(1536 * 1024 * 1024));
+        assert_eq!(parse_bytes("10 MB"), Ok(10 * 1024 * 1024));
+        assert!(parse_bytes("").is_err());
+        assert!(parse_bytes("MiB").is_err());
+        assert!(parse_bytes("10X").is_err());
+    }
+}
@@ -0,0 +1,48 @@
+//! Renders series of values as compact unicode sparklines.
+
+const TICKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Renders the values into a sparkline of at most `width` characters.
+///
+/// If there are more values than `width`, adjacent values are bucketed
+/// and the maximum of each bucket is rendered, so that spikes are never
+/// hidden. The scale starts from zero and ends at the maximum value.
+pub fn render(values: &[f64], width: usize) -> String {
+    if values.is_empty() || width == 0 {
+        return String::new();
+    }
+
+    let buckets = values.len().min(width);
+    let bucketed: Vec<f64> = (0..buckets)
+        .map(|idx| {
+            let start = idx * values.len() / buckets;
+            let end = (idx + 1) * values.len() / buckets;
+            values[start..end].iter().cloned().fold(0.0, f64::max)
+        })
+        .collect();
+
+    let max = bucketed.iter().cloned().fold(0.0, f64::max);
+    bucketed
+        .iter()
+        .map(|value| {
+            if max <= 0.0 {
+                return TICKS[0];
+            }
+            let level = (value / max * (TICKS.len() - 1) as f64).round() as usize;
+            TICKS[level.min(TICKS.len() - 1)]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+
+    #[test]
+    fn test_render() {
+        assert_eq!(render(&[], 10), "");
+        assert_eq!(render(&[0.0, 0.0], 10), "▁▁");
+        assert_eq!(render(&[0.0, 1.0, 2.0, 7.0], 10), "▁▂▃█");
+        assert_eq!(render(&[1.0, 7.0, 0.0, 0.0], 2), "█▁");
+    }
+}
@@ -46,6 +46,15 @@ impl<T: Send + Sync> SubscriberList<T> {
         }
     }
 
+    /// Returns the number of active subscribers.
+    pub fn len(&self) -> usize {
+        self.inner.map.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     #[allow(dead_code)]
     pub fn close(&self) {
         self.inner.map.write().unwrap().clear();
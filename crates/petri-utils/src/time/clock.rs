@@ -0,0 +1,204 @@
+use std::fmt::{self, Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+
+/// A source of time, with a monotonic clock for measuring durations and a
+/// wall clock for showing and naming things, which can be changed (e.g.
+/// by NTP) while the server is running.
+pub trait Clock: Send + Sync {
+    fn monotonic(&self) -> Instant;
+
+    fn wall(&self) -> DateTime<Local>;
+}
+
+/// The clocks of the system.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SystemClock;
+
+/// A clock that only moves when told to, for simulating clock changes in
+/// tests.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<(Instant, DateTime<Local>)>,
+}
+
+/// A change of the wall clock that is not explained by the time passed
+/// on the monotonic clock.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ClockJump {
+    /// How far the wall clock moved beyond the time passed, negative if
+    /// it was set back.
+    pub offset: chrono::Duration,
+}
+
+/// Reads the wall clock, detecting jumps since the last reading.
+pub struct ClockWatch<C = SystemClock> {
+    clock: C,
+    /// Differences of the clocks up to this are not treated as jumps.
+    tolerance: chrono::Duration,
+    last: Mutex<Option<(Instant, DateTime<Local>)>>,
+}
+
+/// The default tolerance of [`ClockWatch`].
+const JUMP_TOLERANCE: Duration = Duration::from_secs(1);
+
+impl Clock for SystemClock {
+    fn monotonic(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn monotonic(&self) -> Instant {
+        (**self).monotonic()
+    }
+
+    fn wall(&self) -> DateTime<Local> {
+        (**self).wall()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Box<C> {
+    fn monotonic(&self) -> Instant {
+        (**self).monotonic()
+    }
+
+    fn wall(&self) -> DateTime<Local> {
+        (**self).wall()
+    }
+}
+
+impl ManualClock {
+    pub fn new(wall: DateTime<Local>) -> Self {
+        Self {
+            now: Mutex::new((Instant::now(), wall)),
+        }
+    }
+
+    /// Lets the time pass on both clocks.
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().expect("clock should not be poisoned");
+        now.0 += duration;
+        now.1 += duration;
+    }
+
+    /// Sets the wall clock, like NTP stepping the system clock.
+    pub fn set_wall(&self, wall: DateTime<Local>) {
+        self.now.lock().expect("clock should not be poisoned").1 = wall;
+    }
+}
+
+impl Clock for ManualClock {
+    fn monotonic(&self) -> Instant {
+        self.now.lock().expect("clock should not be poisoned").0
+    }
+
+    fn wall(&self) -> DateTime<Local> {
+        self.now.lock().expect("clock should not be poisoned").1
+    }
+}
+
+impl Display for ClockJump {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let direction = if self.offset < chrono::Duration::zero() {
+            "back"
+        } else {
+            "forward"
+        };
+        let millis = self.offset.num_milliseconds().unsigned_abs();
+        let secs = millis as f64 / 1000.0;
+        write!(f, "the wall clock jumped {direction} by {secs:.3}s")
+    }
+}
+
+impl ClockWatch {
+    pub fn new() -> Self {
+        Self::with_clock(SystemClock)
+    }
+}
+
+impl Default for ClockWatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Clock> ClockWatch<C> {
+    pub fn with_clock(clock: C) -> Self {
+        Self {
+            clock,
+            tolerance: chrono::Duration::from_std(JUMP_TOLERANCE)
+                .expect("tolerance should be in range"),
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Returns the wall time, and the jump of the wall clock since the
+    /// last reading if any.
+    pub fn read(&self) -> (DateTime<Local>, Option<ClockJump>) {
+        let monotonic = self.clock.monotonic();
+        let wall = self.clock.wall();
+        let mut last = self.last.lock().expect("clock should not be poisoned");
+        let jump = last.and_then(|(last_monotonic, last_wall)| {
+            let elapsed = chrono::Duration::from_std(monotonic - last_monotonic).ok()?;
+            let offset = (wall - last_wall) - elapsed;
+            (offset.abs() > self.tolerance).then_some(ClockJump { offset })
+        });
+        *last = Some((monotonic, wall));
+        (wall, jump)
+    }
+
+    /// Returns the wall time, see [`ClockWatch::read`].
+    pub fn now(&self) -> DateTime<Local> {
+        self.read().0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{Local, TimeZone};
+
+    use super::{Clock, ClockJump, ClockWatch, ManualClock};
+
+    #[test]
+    fn test_clock_watch() {
+        let start = Local.with_ymd_and_hms(2024, 5, 1, 14, 0, 0).unwrap();
+        let watch = ClockWatch::with_clock(ManualClock::new(start));
+        assert_eq!(watch.read(), (start, None));
+
+        watch.clock.advance(Duration::from_secs(60));
+        assert_eq!(watch.read(), (start + chrono::Duration::minutes(1), None));
+
+        // Set back by an hour while a minute passes.
+        watch.clock.advance(Duration::from_secs(60));
+        watch
+            .clock
+            .set_wall(start + chrono::Duration::minutes(2) - chrono::Duration::hours(1));
+        let (_, jump) = watch.read();
+        assert_eq!(
+            jump,
+            Some(ClockJump {
+                offset: -chrono::Duration::hours(1)
+            })
+        );
+        assert_eq!(
+            jump.unwrap().to_string(),
+            "the wall clock jumped back by 3600.000s"
+        );
+
+        // Small corrections are not jumps.
+        let now = watch.clock.wall();
+        watch
+            .clock
+            .set_wall(now + chrono::Duration::milliseconds(200));
+        assert_eq!(watch.read().1, None);
+    }
+}
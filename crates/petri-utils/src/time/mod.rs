@@ -1,5 +1,11 @@
+mod clock;
 mod delay;
 mod formatter;
+mod parser;
+mod zone;
 
+pub use clock::{Clock, ClockJump, ClockWatch, ManualClock, SystemClock};
 pub use delay::DelayedTask;
 pub use formatter::FormattedUptime;
+pub use parser::{parse_duration, parse_nonzero_duration, ParseDurationError};
+pub use zone::{in_zone, set_zone, zone, ParseZoneError, Zone};
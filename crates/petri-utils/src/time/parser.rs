@@ -0,0 +1,123 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+/// An error returned when parsing a duration string failed.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ParseDurationError(String);
+
+impl Display for ParseDurationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for ParseDurationError {}
+
+/// Parses a human-friendly duration string like `30s`, `1h30m` or `500ms`.
+///
+/// Supported units are `ms`, `s`, `m`, `h` and `d`. A bare number is
+/// interpreted as seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, ParseDurationError> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err(ParseDurationError("empty duration".to_owned()));
+    }
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits_len = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits_len == 0 {
+            return Err(ParseDurationError(format!(
+                "expected a number in duration `{s}`"
+            )));
+        }
+        let value: u64 = rest[..digits_len]
+            .parse()
+            .map_err(|_| ParseDurationError(format!("number is too large in duration `{s}`")))?;
+        rest = &rest[digits_len..];
+
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let unit = match &rest[..unit_len] {
+            "ms" => Duration::from_millis(1),
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(60 * 60),
+            "d" => Duration::from_secs(24 * 60 * 60),
+            "" => {
+                return Err(ParseDurationError(format!(
+                    "missing unit in duration `{s}`"
+                )))
+            }
+            unit => {
+                return Err(ParseDurationError(format!(
+                    "unknown unit `{unit}` in duration `{s}`"
+                )))
+            }
+        };
+        rest = &rest[unit_len..];
+
+        let value = u32::try_from(value)
+            .ok()
+            .and_then(|value| unit.checked_mul(value))
+            .ok_or_else(|| ParseDurationError(format!("duration `{s}` is too long")))?;
+        total = total
+            .checked_add(value)
+            .ok_or_else(|| ParseDurationError(format!("duration `{s}` is too long")))?;
+    }
+
+    Ok(total)
+}
+
+/// Parses a duration like [`parse_duration`], rejecting zero, for the
+/// intervals of periodic work.
+pub fn parse_nonzero_duration(s: &str) -> Result<Duration, ParseDurationError> {
+    let duration = parse_duration(s)?;
+    if duration.is_zero() {
+        return Err(ParseDurationError(
+            "the duration must not be zero".to_owned(),
+        ));
+    }
+    Ok(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{parse_duration, parse_nonzero_duration};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("2d").unwrap(), Duration::from_secs(172_800));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("10x").is_err());
+        assert!(parse_duration("1h30").is_err());
+        assert!(parse_duration("-5s").is_err());
+
+        assert!(parse_nonzero_duration("0").is_err());
+        assert!(parse_nonzero_duration("0s0ms").is_err());
+        assert_eq!(
+            parse_nonzero_duration("1ms").unwrap(),
+            Duration::from_millis(1)
+        );
+    }
+}
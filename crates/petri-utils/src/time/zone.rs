@@ -0,0 +1,136 @@
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use chrono::{DateTime, FixedOffset, Local, TimeZone, Utc};
+
+/// The time zone that times are shown in, like the timestamps of logs and
+/// the dates in names of log files. Named zones (e.g. `Asia/Tokyo`) are
+/// used by starting the server with `TZ` set, and choosing [`Zone::Local`].
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Zone {
+    #[default]
+    Local,
+    Utc,
+    Fixed(FixedOffset),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ParseZoneError(String);
+
+static ZONE: RwLock<Zone> = RwLock::new(Zone::Local);
+
+/// Sets the zone of the process, which applies to the times shown from
+/// now on.
+pub fn set_zone(zone: Zone) {
+    *ZONE.write().expect("zone should not be poisoned") = zone;
+}
+
+/// Returns the zone of the process, which is [`Zone::Local`] unless set by
+/// [`set_zone`].
+pub fn zone() -> Zone {
+    *ZONE.read().expect("zone should not be poisoned")
+}
+
+/// Returns the time in the zone of the process.
+pub fn in_zone<Tz: TimeZone>(time: &DateTime<Tz>) -> DateTime<FixedOffset> {
+    zone().convert(time)
+}
+
+impl Zone {
+    /// Returns the same instant in the zone.
+    pub fn convert<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> DateTime<FixedOffset> {
+        match self {
+            Zone::Local => time.with_timezone(&Local).fixed_offset(),
+            Zone::Utc => time.with_timezone(&Utc).fixed_offset(),
+            Zone::Fixed(offset) => time.with_timezone(offset),
+        }
+    }
+}
+
+impl FromStr for Zone {
+    type Err = ParseZoneError;
+
+    /// Parses `local`, `utc` or an offset like `+08:00`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "local" => return Ok(Zone::Local),
+            "utc" | "z" => return Ok(Zone::Utc),
+            _ => {}
+        }
+        let invalid = || ParseZoneError(s.to_owned());
+        let (sign, rest) = match s.as_bytes().first() {
+            Some(b'+') => (1, &s[1..]),
+            Some(b'-') => (-1, &s[1..]),
+            _ => return Err(invalid()),
+        };
+        let (hours, minutes) = rest.split_once(':').unwrap_or((rest, "0"));
+        let hours: i32 = hours.parse().map_err(|_| invalid())?;
+        let minutes: i32 = minutes.parse().map_err(|_| invalid())?;
+        if minutes >= 60 {
+            return Err(invalid());
+        }
+        FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+            .map(Zone::Fixed)
+            .ok_or_else(invalid)
+    }
+}
+
+impl Display for Zone {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Zone::Local => f.write_str("local"),
+            Zone::Utc => f.write_str("utc"),
+            Zone::Fixed(offset) => write!(f, "{offset}"),
+        }
+    }
+}
+
+impl Display for ParseZoneError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid time zone `{}`, expected `local`, `utc` or an offset like `+08:00`",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseZoneError {}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{FixedOffset, TimeZone, Utc};
+
+    use super::Zone;
+
+    #[test]
+    fn test_parse_zone() {
+        assert_eq!("local".parse(), Ok(Zone::Local));
+        assert_eq!("UTC".parse(), Ok(Zone::Utc));
+        let east = FixedOffset::east_opt(8 * 3600).unwrap();
+        assert_eq!("+08:00".parse(), Ok(Zone::Fixed(east)));
+        assert_eq!("+8".parse(), Ok(Zone::Fixed(east)));
+        let west = FixedOffset::west_opt(5 * 3600 + 30 * 60).unwrap();
+        assert_eq!("-05:30".parse(), Ok(Zone::Fixed(west)));
+        assert_eq!(Zone::Fixed(west).to_string(), "-05:30");
+
+        for invalid in ["Asia/Tokyo", "+08:60", "+25:00", "08:00", ""] {
+            assert!(invalid.parse::<Zone>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_convert() {
+        let time = Utc.with_ymd_and_hms(2024, 5, 1, 22, 30, 0).unwrap();
+        let east = Zone::Fixed(FixedOffset::east_opt(8 * 3600).unwrap());
+        assert_eq!(
+            east.convert(&time).format("%Y%m%d-%H %:z").to_string(),
+            "20240502-06 +08:00"
+        );
+        assert_eq!(
+            Zone::Utc.convert(&time).format("%Y%m%d-%H %:z").to_string(),
+            "20240501-22 +00:00"
+        );
+    }
+}
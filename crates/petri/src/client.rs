@@ -1,25 +1,60 @@
 use std::collections::HashMap;
 use std::env;
 use std::error::Error as StdError;
-use std::ffi::OsStr;
 use std::io::{self, ErrorKind as IoErrorKind, Write};
-use std::os::unix::prelude::OsStrExt;
-use std::process::{self, Stdio};
+use std::process;
 
 use anyhow::Error;
 use clap::Parser;
-use petri_control::cli::{IpcRequestPacket, OwnedIpcMessagePacket};
+use petri_control::auth::TOKEN_ENV;
+use petri_control::cli::bytes::{self, Base64Bytes};
+use petri_control::cli::mux::{MuxClient, MuxStream};
+use petri_control::cli::{Compression, Encoding, IpcRequestPacket, RenderHints};
 use petri_control::command::CommandClient;
-use petri_control::env::socket_path;
-use petri_control::Command;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::UnixStream;
+use petri_control::env::{ListenAddress, NO_AUTOSTART_ENV};
+use petri_control::transport::IpcStream;
+use petri_control::{Command, LONG_VERSION, VERSION};
+use petri_utils::time::FormattedUptime;
+
+use crate::daemon;
+
+/// The environment variable to request compressing the packets.
+const COMPRESSION_ENV: &str = "PETRI_COMPRESSION";
 
 enum ConnectError {
     ServerNotStarted,
     OtherError(Error),
 }
 
+/// What the requests of a run share, except the command.
+struct RequestTemplate {
+    cwd: String,
+    env: HashMap<String, String>,
+    env_bytes: Vec<(Base64Bytes, Base64Bytes)>,
+    token: Option<String>,
+    args: Vec<String>,
+    compression: Compression,
+    render: RenderHints,
+}
+
+impl RequestTemplate {
+    fn request<'c>(&self, cmd: &'c Command) -> IpcRequestPacket<'c> {
+        IpcRequestPacket {
+            cmd,
+            cwd: self.cwd.clone(),
+            env: self.env.clone(),
+            env_bytes: self.env_bytes.clone(),
+            token: self.token.clone(),
+            args: self.args.clone(),
+            encodings: Encoding::PREFERRED.to_vec(),
+            compression: self.compression,
+            version: VERSION,
+            multiplex: true,
+            render: self.render,
+        }
+    }
+}
+
 impl<E> From<E> for ConnectError
 where
     E: StdError + Send + Sync + 'static,
@@ -38,45 +73,112 @@ pub async fn run_client(args: Vec<String>) {
         println!("current dir is invalid");
         return;
     };
-    let env_vars: HashMap<_, _> = env::vars_os()
-        .filter_map(|entry| {
-            let Some(key) = entry.0.to_str() else {
-                return None;
-            };
-            let Some(value) = entry.1.to_str() else {
-                return None;
-            };
-            Some((key.to_string(), value.to_string()))
-        })
-        .collect();
+    let (mut env_vars, env_bytes) = bytes::split_env(env::vars_os());
+
+    // Send the token separately, so it's not leaked to the processes
+    // that inherit the client environment.
+    let token = env_vars.remove(TOKEN_ENV);
+
+    // Compression only pays off when the socket is forwarded from a remote
+    // host (e.g. over SSH), so it's opt-in.
+    let compression = match env_vars.get(COMPRESSION_ENV).map(String::as_str) {
+        Some("zstd") => Compression::Zstd,
+        Some("none") | None => Compression::None,
+        Some(other) => {
+            println!("unknown compression `{other}` in `{COMPRESSION_ENV}`");
+            return;
+        }
+    };
+
+    let address = match ListenAddress::for_client() {
+        Ok(address) => address,
+        Err(err) => {
+            println!("{err}");
+            return;
+        }
+    };
 
     // Parse and serialize the command.
-    let cmd = Command::parse_from(args);
-    let mut cmd_string = serde_json::to_string(&IpcRequestPacket {
-        cmd: &cmd,
+    let mut cmd = Command::parse_from(&args);
+    if let Err(err) = cmd.prepare() {
+        println!("{err:#}");
+        process::exit(1);
+    }
+    let template = RequestTemplate {
         cwd,
         env: env_vars,
-    })
-    .expect("failed to serialize the command");
-    cmd_string.push('\n');
+        env_bytes,
+        token,
+        args: args[1..].to_vec(),
+        compression,
+        render: RenderHints::detect(),
+    };
 
+    // Commands that take more than one request share the connection, if the
+    // server supports it.
+    let mut client = None;
     let mut server_started_by_us = false;
     let mut retry_count = 0;
     loop {
-        match try_talking_to_server(&cmd_string, &cmd).await {
-            Ok(_) => {
-                return;
+        let talk = try_talking_to_server(&address, &mut client, template.request(&cmd), &cmd);
+        let res = match cmd.client_timeout() {
+            Some(timeout) => match tokio::time::timeout(timeout, talk).await {
+                Ok(res) => res,
+                Err(_) => {
+                    let timeout = FormattedUptime::new(timeout);
+                    println!("the server did not respond within {timeout}");
+                    process::exit(1);
+                }
+            },
+            None => talk.await,
+        };
+        match res {
+            Ok((exit_status, follow_up)) => {
+                if exit_status != 0 {
+                    process::exit(exit_status);
+                }
+                // Commands that take more than one request go on with the
+                // next one.
+                let Some(mut next_cmd) = follow_up else {
+                    return;
+                };
+                if let Err(err) = next_cmd.prepare() {
+                    println!("{err:#}");
+                    process::exit(1);
+                }
+                cmd = next_cmd;
+                retry_count = 0;
+                continue;
             }
             Err(ConnectError::OtherError(err)) => {
                 println!("error occurred while connecting to server: {}", err);
+                // The connection may be broken, so retries reconnect.
+                client = None;
+                // Probes report what they see instead of waiting it out.
+                if cmd.is_probe() {
+                    process::exit(1);
+                }
+            }
+            Err(ConnectError::ServerNotStarted) if cmd.is_probe() => {
+                println!("the server is not running");
+                process::exit(1);
             }
             Err(ConnectError::ServerNotStarted) => {
                 // If the server is not started by us yet, let's try starting
                 // it and wait for it to get ready. After that, we only retry
                 // connecting to the server.
                 if !server_started_by_us {
+                    if env::var_os(NO_AUTOSTART_ENV).is_some_and(|value| !value.is_empty()) {
+                        println!(
+                            "the server is not running (not started for `{NO_AUTOSTART_ENV}`)"
+                        );
+                        process::exit(1);
+                    }
                     println!("starting the server as daemon...");
-                    start_server_as_daemon();
+                    if let Err(err) = daemon::start_server() {
+                        println!("failed to start the server: {err}");
+                        process::exit(1);
+                    }
                     server_started_by_us = true;
                 }
             }
@@ -92,79 +194,136 @@ pub async fn run_client(args: Vec<String>) {
     }
 }
 
-async fn try_talking_to_server(payload: &str, cmd: &dyn CommandClient) -> Result<(), ConnectError> {
-    let mut stream = match UnixStream::connect(socket_path()?).await {
+async fn try_talking_to_server(
+    address: &ListenAddress,
+    client: &mut Option<MuxClient>,
+    request: IpcRequestPacket<'_>,
+    cmd: &dyn CommandClient,
+) -> Result<(i32, Option<Command>), ConnectError> {
+    if let Some(mux) = client {
+        let stream = mux.send(request).await?;
+        return receive(stream, cmd).await;
+    }
+
+    let stream = match IpcStream::connect(address).await {
         Ok(stream) => stream,
         Err(err) => {
-            if err.kind() == IoErrorKind::NotFound {
+            // Servers behind TCP may be on other hosts, so they are never
+            // started by the client. A refused socket file is left by a
+            // crashed server, which the new server reclaims.
+            let not_started = match address {
+                ListenAddress::Path(_) => matches!(
+                    err.kind(),
+                    IoErrorKind::NotFound | IoErrorKind::ConnectionRefused
+                ),
+                ListenAddress::Abstract(_) => err.kind() == IoErrorKind::ConnectionRefused,
+                ListenAddress::Tcp(_) => false,
+            };
+            if not_started {
                 return Err(ConnectError::ServerNotStarted);
             }
             return Err(ConnectError::OtherError(err.into()));
         }
     };
 
-    // Send the command to server.
-    stream.write_all(payload.as_bytes()).await?;
-
-    // Create a buffer reader that can read the stream line by line,
-    // since messages are delimited by newlines in our protocol.
-    let stream_buf_read = BufReader::new(stream);
-    let mut stream_lines = stream_buf_read.lines();
+    let (mux, stream) = MuxClient::with_stream(address.clone(), stream, request).await?;
+    *client = Some(mux);
+    receive(stream, cmd).await
+}
 
-    // Receive all the contents from server until EOF.
+/// Receives all the contents of the command until it's done.
+async fn receive(
+    mut stream: MuxStream,
+    cmd: &dyn CommandClient,
+) -> Result<(i32, Option<Command>), ConnectError> {
+    let mut handler = cmd.handler();
     let mut stdout = io::stdout();
-    while let Some(line) = stream_lines.next_line().await? {
-        let pkt: OwnedIpcMessagePacket<serde_json::Value> = serde_json::from_str(&line)?;
+    let mut exit_status = 0;
+    loop {
+        let Some(pkt) = stream.next().await? else {
+            if handler
+                .as_ref()
+                .is_some_and(|handler| handler.expects_more())
+            {
+                eprintln!("the server closed the connection unexpectedly");
+                exit_status = 1;
+            }
+            break;
+        };
         if let Some(output) = pkt.to_output() {
             stdout.write_all(output.as_bytes())?;
             stdout.flush()?;
+        } else if let Some(eos) = pkt.to_end_of_stream() {
+            eprintln!("stream ended because {}", eos.reason);
+            break;
+        } else if let Some(denied) = pkt.to_permission_denied() {
+            eprintln!("permission denied: {}", denied.reason);
+            exit_status = 1;
+            break;
+        } else if let Some(unknown) = pkt.to_unknown_command() {
+            eprintln!(
+                "the server (version {}) doesn't support `{}`",
+                unknown.server_version, unknown.name
+            );
+            if unknown.server_version != VERSION {
+                eprintln!(
+                    "the client is version {VERSION}, upgrade the server by restarting it \
+                    with `petri stop-server`"
+                );
+            }
+            exit_status = 1;
+            break;
+        } else if let Some(bad_request) = pkt.to_bad_request() {
+            eprintln!("bad request: {}", bad_request.reason);
+            if let Some(help) = &bad_request.help {
+                eprintln!("\nthe server supports:\n{help}");
+            }
+            exit_status = 1;
+            break;
+        } else if let Some(err) = pkt.to_internal_error() {
+            eprintln!("internal server error: {}", err.message);
+            exit_status = 1;
+            break;
+        } else if pkt.to_failed().is_some() {
+            // The command has told why in its output.
+            exit_status = 1;
+            break;
         } else {
-            if let Some(mut handler) = cmd.handler() {
-                handler
-                    .handle_response(pkt)
-                    .await
-                    .map_err(ConnectError::OtherError)?;
+            if let Some(handler) = handler.as_mut() {
+                if let Err(err) = handler.handle_response(pkt).await {
+                    print_request_id(stream.request_id());
+                    return Err(ConnectError::OtherError(err));
+                }
+                exit_status = handler.exit_status();
+                if handler.expects_more() {
+                    continue;
+                }
             }
-            // End the program once we received the response packet.
+            // End the program once we received the final response packet.
             break;
         }
     }
 
-    Ok(())
-}
-
-fn start_server_as_daemon() {
-    let current_exe = env::current_exe().expect("failed to get current executable path");
-
-    let pid = unsafe { libc::fork() };
-    if pid != 0 {
-        // The calling process can wait for the server to start and
-        // retry its previous operation.
-        return;
+    if exit_status != 0 {
+        print_request_id(stream.request_id());
+        print_version_skew(stream.server_version());
     }
+    let follow_up = handler.as_mut().and_then(|handler| handler.follow_up());
+    Ok((exit_status, follow_up))
+}
 
-    // Create a new session.
-    let sid = unsafe { libc::setsid() };
-    if sid == -1 {
-        return;
+/// Prints the id of the failed request, to find its logs on the server.
+fn print_request_id(request_id: Option<&str>) {
+    if let Some(request_id) = request_id {
+        eprintln!("request id: {request_id}");
     }
+}
 
-    unsafe {
-        let root_dir_path = OsStr::new("/");
-        libc::chdir(root_dir_path.as_bytes() as *const _ as *const i8);
-
-        libc::umask(0);
+/// Prints the versions if the server is not built like the client, which
+/// may be why the request failed.
+fn print_version_skew(server_version: Option<&str>) {
+    if let Some(server_version) = server_version.filter(|version| *version != LONG_VERSION) {
+        eprintln!("server version: {server_version}");
+        eprintln!("client version: {LONG_VERSION}");
     }
-
-    // Daemonization is done. Now we can execute the program in server
-    // mode, and exit the current process.
-    process::Command::new(current_exe)
-        .arg("--server")
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()
-        .unwrap();
-
-    process::exit(0);
 }
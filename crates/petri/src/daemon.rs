@@ -0,0 +1,225 @@
+//! Starts the server as a daemon, detached from the client that started it.
+//!
+//! Forking is only safe in a process with a single thread, since the child
+//! gets a copy of the calling thread only, and may deadlock on a lock (like
+//! the one of `malloc`) held by another thread. macOS also refuses to use
+//! most of its system frameworks after forking. The client forks itself
+//! only when it's safe; otherwise, it spawns `petri --server --daemonize`,
+//! which daemonizes itself before it starts any thread.
+
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, ErrorKind as IoErrorKind};
+use std::mem;
+use std::os::unix::prelude::OsStrExt;
+use std::process::{self, Stdio};
+use std::ptr;
+
+use libc::c_int;
+
+/// The argument of the server to daemonize itself.
+pub const DAEMONIZE_ARG: &str = "--daemonize";
+
+/// The highest file descriptor closed in the daemon, in case the limit is
+/// unbounded.
+const MAX_INHERITED_FD: c_int = 65536;
+
+enum Forked {
+    /// The calling process, once the daemon is detached.
+    Parent,
+    /// The daemon, which reports the errors of its setup to the calling
+    /// process.
+    Daemon(Reporter),
+}
+
+/// The write end of the pipe the daemon reports errors through. The
+/// calling process takes EOF for success, which is either when the
+/// reporter is dropped or when the daemon executes another program.
+struct Reporter(c_int);
+
+impl Reporter {
+    /// Reports the last OS error, and exits the daemon.
+    fn fail(self) -> ! {
+        let errno = io::Error::last_os_error().raw_os_error().unwrap_or(0);
+        let bytes = errno.to_ne_bytes();
+        unsafe {
+            libc::write(self.0, bytes.as_ptr().cast(), bytes.len());
+            libc::_exit(1);
+        }
+    }
+}
+
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// Starts the server in the background, returning once it's detached.
+pub fn start_server() -> io::Result<()> {
+    let current_exe = env::current_exe()?;
+
+    if !is_fork_safe() {
+        // Spawned with `posix_spawn` by the standard library, which is
+        // safe in processes with threads.
+        let output = process::Command::new(&current_exe)
+            .args(["--server", DAEMONIZE_ARG])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(io::Error::other(format!(
+                "the server exited with {}: {}",
+                output.status,
+                stderr.trim()
+            )));
+        }
+        return Ok(());
+    }
+
+    // Allocated before forking, along with everything else the daemon
+    // needs to execute the server.
+    let program = CString::new(current_exe.as_os_str().as_bytes())?;
+    let server_arg = CString::new("--server").expect("the argument has no nul");
+    let argv = [program.as_ptr(), server_arg.as_ptr(), ptr::null()];
+    match fork_daemon()? {
+        Forked::Parent => Ok(()),
+        Forked::Daemon(reporter) => unsafe {
+            // Returns only if the server fails to execute.
+            libc::execv(program.as_ptr(), argv.as_ptr());
+            reporter.fail()
+        },
+    }
+}
+
+/// Daemonizes the server, which must not have started any thread. Returns
+/// in the daemon, while the calling process exits once it's detached.
+pub fn daemonize() -> io::Result<()> {
+    match fork_daemon()? {
+        Forked::Parent => process::exit(0),
+        Forked::Daemon(_) => Ok(()),
+    }
+}
+
+fn is_fork_safe() -> bool {
+    cfg!(target_os = "linux") && thread_count() == Some(1)
+}
+
+fn thread_count() -> Option<usize> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("Threads:"))?;
+    line["Threads:".len()..].trim().parse().ok()
+}
+
+/// Forks a daemon by forking twice with a new session in between, so the
+/// daemon is not a session leader and never gets a controlling terminal.
+/// The daemon runs in `/` with its standard streams on `/dev/null` and no
+/// other inherited file descriptors.
+///
+/// The calling process waits until the daemon reports its setup, and
+/// returns the error if it fails.
+fn fork_daemon() -> io::Result<Forked> {
+    let max_fd = max_fd();
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let [read_fd, write_fd] = fds;
+    unsafe {
+        libc::fcntl(read_fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        libc::fcntl(write_fd, libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+
+    let pid = unsafe { libc::fork() };
+    if pid == -1 {
+        let err = io::Error::last_os_error();
+        unsafe {
+            libc::close(read_fd);
+            libc::close(write_fd);
+        }
+        return Err(err);
+    }
+    if pid != 0 {
+        unsafe { libc::close(write_fd) };
+        return wait_daemon(pid, read_fd).map(|_| Forked::Parent);
+    }
+
+    unsafe { libc::close(read_fd) };
+    let reporter = Reporter(write_fd);
+    unsafe {
+        if libc::setsid() == -1 {
+            reporter.fail();
+        }
+        match libc::fork() {
+            -1 => reporter.fail(),
+            0 => {}
+            // The intermediate process only exists to leave the session.
+            _ => libc::_exit(0),
+        }
+
+        if libc::chdir(c"/".as_ptr()) == -1 {
+            reporter.fail();
+        }
+        // Jobs inherit the mask, so their files must not become writable
+        // by everyone.
+        libc::umask(0o022);
+
+        let null_fd = libc::open(c"/dev/null".as_ptr(), libc::O_RDWR);
+        if null_fd == -1 {
+            reporter.fail();
+        }
+        for fd in 0..3 {
+            if libc::dup2(null_fd, fd) == -1 {
+                reporter.fail();
+            }
+        }
+        for fd in 3..max_fd {
+            if fd != reporter.0 {
+                libc::close(fd);
+            }
+        }
+    }
+    Ok(Forked::Daemon(reporter))
+}
+
+/// Reaps the intermediate process and reads what the daemon reports.
+fn wait_daemon(pid: libc::pid_t, read_fd: c_int) -> io::Result<()> {
+    let mut status = 0;
+    while unsafe { libc::waitpid(pid, &mut status, 0) } == -1 {
+        let err = io::Error::last_os_error();
+        if err.kind() != IoErrorKind::Interrupted {
+            unsafe { libc::close(read_fd) };
+            return Err(err);
+        }
+    }
+
+    let mut bytes = [0u8; mem::size_of::<c_int>()];
+    let mut len = 0;
+    while len < bytes.len() {
+        let read =
+            unsafe { libc::read(read_fd, bytes[len..].as_mut_ptr().cast(), bytes.len() - len) };
+        match read {
+            0 => break,
+            -1 if io::Error::last_os_error().kind() == IoErrorKind::Interrupted => continue,
+            -1 => break,
+            read => len += read as usize,
+        }
+    }
+    unsafe { libc::close(read_fd) };
+
+    if len == bytes.len() {
+        return Err(io::Error::from_raw_os_error(c_int::from_ne_bytes(bytes)));
+    }
+    Ok(())
+}
+
+fn max_fd() -> c_int {
+    let mut limit = unsafe { mem::zeroed::<libc::rlimit>() };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == -1 {
+        return MAX_INHERITED_FD;
+    }
+    c_int::try_from(limit.rlim_cur).map_or(MAX_INHERITED_FD, |max| max.min(MAX_INHERITED_FD))
+}
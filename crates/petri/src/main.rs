@@ -1,16 +1,47 @@
 #[macro_use]
 extern crate log;
 
+use clap::Parser;
+use petri_control::command::{run_bench_child, BENCH_CHILD_ARG};
+
+use crate::server::ServerArgs;
+
 mod client;
+mod daemon;
 mod logging;
 mod server;
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
+fn main() {
     let args: Vec<_> = std::env::args().collect();
 
-    if args.len() == 2 && args[1] == "--server" {
-        server::run_server().await;
+    if args.get(1).is_some_and(|arg| arg == "--server") {
+        let server_args = ServerArgs::parse_from(&args[2..]);
+        // Forked before the runtime starts any thread.
+        if server_args.daemonize {
+            if let Err(err) = daemon::daemonize() {
+                eprintln!("failed to daemonize the server: {err}");
+                std::process::exit(1);
+            }
+        }
+        run_server(server_args);
+        return;
+    }
+
+    run(args);
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn run_server(args: ServerArgs) {
+    server::run_server(args).await;
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn run(args: Vec<String>) {
+    if args.len() >= 2 && args[1] == BENCH_CHILD_ARG {
+        if let Err(err) = run_bench_child(&args[2..]) {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
         return;
     }
 
@@ -1,17 +1,57 @@
 use std::fs;
-use std::time::Duration;
-
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use log::LevelFilter;
+use petri_control::cli;
+use petri_control::env::ListenAddress;
+use petri_logger::writers::MemoryWriter;
 use petri_logger::LoggerBuilder;
-use petri_server::Server;
+use petri_server::{Server, ServerConfig};
 use tokio::task as tokio_task;
 
 use crate::logging;
 
-pub async fn run_server() {
-    configure_logger();
+/// Runs the server, which is otherwise started by the first client.
+#[derive(Parser, Debug)]
+#[command(name = "petri --server", no_binary_name = true)]
+pub struct ServerArgs {
+    /// Stay attached to the terminal, and shut down gracefully on `SIGTERM`
+    /// or `SIGINT` like `petri stop-server`, for supervisors like systemd.
+    #[arg(long, conflicts_with = "daemonize")]
+    foreground: bool,
+    /// Detach from the terminal, like when a client starts the server.
+    #[arg(long)]
+    pub daemonize: bool,
+    /// Load the config from the file instead of `~/.petri/config.toml`.
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Listen on the socket, or any address that `PETRI_ADDRESS` takes,
+    /// instead of the one of the config.
+    #[arg(long, value_name = "PATH")]
+    socket: Option<ListenAddress>,
+    /// Write the logs of the server to the directory instead of
+    /// `~/.petri/logs`.
+    #[arg(long, value_name = "PATH")]
+    log_dir: Option<PathBuf>,
+    /// The maximum log level (`off`, `error`, `warn`, `info`, `debug` or
+    /// `trace`) instead of the one of the config.
+    #[arg(long, value_name = "LEVEL")]
+    log_level: Option<LevelFilter>,
+    /// Keep the clients run by the processes from starting another server
+    /// while this one is not running.
+    #[arg(long)]
+    no_autostart: bool,
+}
+
+pub async fn run_server(args: ServerArgs) {
+    let server_logs = MemoryWriter::default();
+    configure_logger(server_logs.clone(), &args);
     configure_panic_handler();
 
-    let server = match Server::new() {
+    let config_loader = Box::new(move || load_config(&args));
+    let server = match Server::new(config_loader, Some(server_logs)) {
         Ok(server) => server,
         Err(err) => panic!("failed to start the server:\n{err:?}"),
     };
@@ -19,6 +59,15 @@ pub async fn run_server() {
     server.with_process_manager(|proc_mgr| {
         let driver = logging::rotation_callback_registry().make_driver();
         proc_mgr.set_logger_rotation_driver(driver);
+
+        // Start a timer to drive the log rotation checks.
+        let runtime_config = proc_mgr.handle().runtime_config();
+        tokio_task::spawn(async move {
+            loop {
+                tokio::time::sleep(runtime_config.get().rotation_interval).await;
+                logging::rotation_callback_registry().notify_all();
+            }
+        });
     });
 
     if let Err(err) = server.await {
@@ -30,14 +79,89 @@ pub async fn run_server() {
     ensure_logs_flushed();
 }
 
+fn load_config(args: &ServerArgs) -> Result<ServerConfig> {
+    let petri_dir = home::home_dir().map(|home_dir| home_dir.join(".petri"));
+    let mut config = match (&args.config, &petri_dir) {
+        (Some(config_path), _) => load_config_file(config_path, true)?,
+        (None, Some(petri_dir)) => load_config_file(&petri_dir.join("config.toml"), false)?,
+        (None, None) => ServerConfig::default(),
+    };
+    apply_args(&mut config, args);
+    let Some(petri_dir) = petri_dir else {
+        return Ok(config);
+    };
+
+    config
+        .state_dir
+        .get_or_insert_with(|| petri_dir.join("run"));
+    config
+        .checkpoint_dir
+        .get_or_insert_with(|| petri_dir.join("checkpoints"));
+    config
+        .scripts
+        .dir
+        .get_or_insert_with(|| petri_dir.join("scripts"));
+    config
+        .metrics
+        .dir
+        .get_or_insert_with(|| petri_dir.join("metrics"));
+    config
+        .auth
+        .tokens_file
+        .get_or_insert_with(|| petri_dir.join("tokens.json"));
+    config
+        .history
+        .file
+        .get_or_insert_with(|| petri_dir.join("history.jsonl"));
+    config
+        .job_history
+        .dir
+        .get_or_insert_with(|| petri_dir.join("job-history"));
+    Ok(config)
+}
+
+/// Loads the config file, which may be missing unless it's `required`.
+fn load_config_file(config_path: &Path, required: bool) -> Result<ServerConfig> {
+    if required && !config_path.exists() {
+        return Err(anyhow!(
+            "the config file `{}` is not found",
+            config_path.display()
+        ));
+    }
+    ServerConfig::load(config_path)
+        .with_context(|| format!("failed to load the config from `{}`", config_path.display()))
+}
+
+/// Overrides the config with the arguments of the server, which also
+/// applies to reloaded configs.
+fn apply_args(config: &mut ServerConfig, args: &ServerArgs) {
+    if let Some(address) = &args.socket {
+        config.control.address = Some(address.clone());
+    }
+    if let Some(level) = args.log_level {
+        config.log.level = Some(level);
+    }
+    if args.no_autostart {
+        config.control.client_autostart = false;
+    }
+    if args.foreground {
+        config.shutdown.on_signals = true;
+    }
+}
+
 #[inline(always)]
-fn configure_logger() {
-    let mut logger = LoggerBuilder::new();
+fn configure_logger(server_logs: MemoryWriter, args: &ServerArgs) {
+    let mut logger = LoggerBuilder::new()
+        .enable_memory(server_logs)
+        .context(cli::log_context);
 
-    if let Some(home_dir) = home::home_dir() {
-        let mut logs_dir = home_dir;
+    let logs_dir = args.log_dir.clone().or_else(|| {
+        let mut logs_dir = home::home_dir()?;
         logs_dir.push(".petri");
         logs_dir.push("logs");
+        Some(logs_dir)
+    });
+    if let Some(logs_dir) = logs_dir {
         if logs_dir.exists() || fs::create_dir_all(&logs_dir).is_ok() {
             let registry = logging::rotation_callback_registry();
             let driver = registry.make_driver();
@@ -50,26 +174,15 @@ fn configure_logger() {
 
     logger = logger.enable_stderr();
 
-    if cfg!(debug_assertions) {
+    if let Some(level) = args.log_level {
+        log::set_max_level(level);
+    } else if cfg!(debug_assertions) {
         log::set_max_level(log::LevelFilter::Trace);
     } else {
         log::set_max_level(log::LevelFilter::Info);
     }
     let boxed_logger = Box::new(logger.build());
     log::set_boxed_logger(boxed_logger).expect("failed to init logger");
-
-    // Start a timer to drive the log rotation checks.
-    tokio_task::spawn(async {
-        loop {
-            if cfg!(debug_assertions) {
-                tokio::time::sleep(Duration::from_secs(5)).await;
-            } else {
-                tokio::time::sleep(Duration::from_secs(30)).await;
-            }
-
-            logging::rotation_callback_registry().notify_all();
-        }
-    });
 }
 
 fn ensure_logs_flushed() {